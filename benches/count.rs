@@ -0,0 +1,121 @@
+// Compares counting by collecting the matching events (as COUNT used to) with counting
+// from the count index (chorus::count), on a store of a few hundred thousand events.
+//
+// Run with `cargo bench --bench count` (set CHORUS_BENCH_EVENTS to change the size)
+
+use chorus::config::Config;
+use chorus::filter::ChorusFilter;
+use chorus::globals::GLOBALS;
+use pocket_db::ScreenResult;
+use pocket_types::Event;
+use secp256k1::{Keypair, Message, SECP256K1};
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+fn make_event(keypair: &Keypair, kind: u16, created_at: u64, n: usize) -> Vec<u8> {
+    let pubkey = hex::encode(keypair.x_only_public_key().0.serialize());
+    let unsigned = format!(
+        r#"{{"pubkey":"{pubkey}","created_at":{created_at},"kind":{kind},"tags":[],"content":"note {n}"}}"#
+    );
+    let id = chorus::nostr::compute_event_id(unsigned.as_bytes()).unwrap();
+    let sig = SECP256K1.sign_schnorr_no_aux_rand(&Message::from_digest(id), keypair);
+    let json = format!(
+        r#"{{"id":"{}","pubkey":"{pubkey}","created_at":{created_at},"kind":{kind},"tags":[],"content":"note {n}","sig":"{}"}}"#,
+        hex::encode(id),
+        hex::encode(sig.serialize())
+    );
+    let mut buffer = vec![0_u8; 4096];
+    let _ = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    buffer
+}
+
+fn filter(json: &str) -> ChorusFilter {
+    let mut buffer = vec![0_u8; 4096];
+    let (_, _, filter) = ChorusFilter::from_json(json.as_bytes(), &mut buffer).unwrap();
+    filter
+}
+
+// The old way: collect every match, sort, dedup, and count what is left
+fn count_collecting(filters: &[ChorusFilter]) -> usize {
+    let mut events: Vec<&Event> = Vec::new();
+    for filter in filters.iter() {
+        let screen = |event: &Event| -> ScreenResult {
+            let event_flags = chorus::nostr::event_flags(event, &None);
+            chorus::nostr::screen_outgoing_event(event, &event_flags, false)
+        };
+        let (found, _) = chorus::nostr::find_events(filter, screen).unwrap();
+        events.extend(found);
+    }
+    events.sort_by_key(|e| std::cmp::Reverse(e.created_at()));
+    events.dedup();
+    events.len()
+}
+
+fn time<F: Fn() -> usize>(f: F) -> (usize, Duration) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        count = f();
+        best = best.min(start.elapsed());
+    }
+    (count, best)
+}
+
+fn main() {
+    let num_events: usize = std::env::var("CHORUS_BENCH_EVENTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(300_000);
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        open_relay: true,
+        allow_scraping: true,
+        ..Default::default()
+    };
+    let store = chorus::setup_store_and_return(&config).unwrap();
+    *GLOBALS.config.write() = config;
+    let _ = GLOBALS.store.set(store);
+    chorus::count::migrate(GLOBALS.store.get().unwrap()).unwrap();
+
+    // Mostly notes, some reactions and contact lists, from a thousand authors
+    let keypairs: Vec<Keypair> = (1..=1000_u16)
+        .map(|i| {
+            let mut secret = [0x01_u8; 32];
+            secret[..2].copy_from_slice(&i.to_be_bytes());
+            Keypair::from_seckey_slice(SECP256K1, &secret).unwrap()
+        })
+        .collect();
+    let start = Instant::now();
+    for n in 0..num_events {
+        let kind = match n % 10 {
+            0..=6 => 1,
+            7 | 8 => 7,
+            _ => 3,
+        };
+        let bytes = make_event(
+            &keypairs[n % keypairs.len()],
+            kind,
+            1_700_000_000 + n as u64,
+            n,
+        );
+        let event = unsafe { Event::delineate(&bytes).unwrap() };
+        let _ = chorus::store_event(event).unwrap();
+    }
+    println!("stored {num_events} events in {:?}", start.elapsed());
+
+    for json in [
+        r#"{"kinds":[3],"limit":1000000}"#,
+        r#"{"kinds":[1],"limit":1000000}"#,
+        r#"{"kinds":[1,7],"since":1700100000,"limit":1000000}"#,
+    ] {
+        let filters = vec![filter(json)];
+        let (old_count, old) = time(|| count_collecting(&filters));
+        let (new_count, new) = time(|| chorus::count::count(&filters, None).unwrap().0);
+        assert_eq!(old_count, new_count);
+        println!("{json}: {new_count} events, collecting {old:?}, counting {new:?}");
+    }
+}
@@ -0,0 +1,94 @@
+// Measures what keeping the first-seen index costs ingestion: stores events as chorus does
+// (with every index), a batch at a time, then times recording when each batch was received
+// again on its own, and gives that as a share of the time storing took.
+//
+// Run with `cargo bench --bench ingest` (set CHORUS_BENCH_EVENTS to change how many
+// events are stored)
+
+use chorus::config::Config;
+use chorus::globals::GLOBALS;
+use pocket_types::Event;
+use secp256k1::{Keypair, Message, SECP256K1};
+use std::time::{Duration, Instant};
+
+const BATCH: usize = 5_000;
+
+fn make_event(keypair: &Keypair, created_at: u64, n: usize) -> Vec<u8> {
+    let pubkey = hex::encode(keypair.x_only_public_key().0.serialize());
+    let unsigned = format!(
+        r#"{{"pubkey":"{pubkey}","created_at":{created_at},"kind":1,"tags":[],"content":"note {n}"}}"#
+    );
+    let id = chorus::nostr::compute_event_id(unsigned.as_bytes()).unwrap();
+    let sig = SECP256K1.sign_schnorr_no_aux_rand(&Message::from_digest(id), keypair);
+    let json = format!(
+        r#"{{"id":"{}","pubkey":"{pubkey}","created_at":{created_at},"kind":1,"tags":[],"content":"note {n}","sig":"{}"}}"#,
+        hex::encode(id),
+        hex::encode(sig.serialize())
+    );
+    let mut buffer = vec![0_u8; 4096];
+    let _ = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    buffer
+}
+
+fn store(events: &[Vec<u8>]) -> Duration {
+    let start = Instant::now();
+    for bytes in events.iter() {
+        let event = unsafe { Event::delineate(bytes).unwrap() };
+        let _ = chorus::store_event(event).unwrap();
+    }
+    start.elapsed()
+}
+
+// Forget when the events were received, then time recording it again
+fn record_again(events: &[Vec<u8>]) -> Duration {
+    let mut elapsed = Duration::ZERO;
+    for bytes in events.iter() {
+        let event = unsafe { Event::delineate(bytes).unwrap() };
+        chorus::first_seen::forget(event.id()).unwrap();
+        let start = Instant::now();
+        chorus::first_seen::record(event).unwrap();
+        elapsed += start.elapsed();
+    }
+    elapsed
+}
+
+fn main() {
+    let num_events: usize = std::env::var("CHORUS_BENCH_EVENTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(200_000);
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        open_relay: true,
+        ..Default::default()
+    };
+    let store = chorus::setup_store_and_return(&config).unwrap();
+    *GLOBALS.config.write() = config;
+    let _ = GLOBALS.store.set(store);
+
+    // Made up front, so only storing them is timed
+    let keypairs: Vec<Keypair> = (1..=100_u8)
+        .map(|i| Keypair::from_seckey_slice(SECP256K1, &[i; 32]).unwrap())
+        .collect();
+    let events: Vec<Vec<u8>> = (0..num_events)
+        .map(|n| make_event(&keypairs[n % keypairs.len()], 1_700_000_000 + n as u64, n))
+        .collect();
+
+    let (mut count, mut storing, mut recording) = (0, Duration::ZERO, Duration::ZERO);
+    for batch in events.chunks(BATCH) {
+        count += batch.len();
+        storing += store(batch);
+        recording += record_again(batch);
+    }
+
+    println!(
+        "stored {count} events in {storing:?}, {:.0} events/s",
+        count as f64 / storing.as_secs_f64()
+    );
+    println!(
+        "of which recording when they were received took {recording:?}, {:.1}%",
+        100.0 * recording.as_secs_f64() / storing.as_secs_f64()
+    );
+}
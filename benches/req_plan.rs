@@ -0,0 +1,137 @@
+// Compares running each filter of a REQ on its own (as REQs used to) with the planned
+// scans of chorus::planner, for 10 filters sharing an author list, on a store of half a
+// million events.
+//
+// Run with `cargo bench --bench req_plan` (set CHORUS_BENCH_EVENTS to change the size)
+
+use chorus::config::Config;
+use chorus::filter::ChorusFilter;
+use chorus::globals::GLOBALS;
+use pocket_db::ScreenResult;
+use pocket_types::Event;
+use secp256k1::{Keypair, Message, SECP256K1};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+// Regular kinds (none replaceable, addressable or ephemeral)
+const KINDS: [u16; 10] = [1, 6, 7, 16, 20, 1063, 1068, 1111, 1311, 9735];
+
+fn make_event(keypair: &Keypair, kind: u16, created_at: u64, n: usize) -> Vec<u8> {
+    let pubkey = hex::encode(keypair.x_only_public_key().0.serialize());
+    let unsigned = format!(
+        r#"{{"pubkey":"{pubkey}","created_at":{created_at},"kind":{kind},"tags":[],"content":"note {n}"}}"#
+    );
+    let id = chorus::nostr::compute_event_id(unsigned.as_bytes()).unwrap();
+    let sig = SECP256K1.sign_schnorr_no_aux_rand(&Message::from_digest(id), keypair);
+    let json = format!(
+        r#"{{"id":"{}","pubkey":"{pubkey}","created_at":{created_at},"kind":{kind},"tags":[],"content":"note {n}","sig":"{}"}}"#,
+        hex::encode(id),
+        hex::encode(sig.serialize())
+    );
+    let mut buffer = vec![0_u8; 4096];
+    let _ = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    buffer
+}
+
+fn filter(json: &str) -> ChorusFilter {
+    let mut buffer = vec![0_u8; 65536];
+    let (_, _, filter) = ChorusFilter::from_json(json.as_bytes(), &mut buffer).unwrap();
+    filter
+}
+
+fn screen(event: &Event) -> ScreenResult {
+    let event_flags = chorus::nostr::event_flags(event, &None);
+    chorus::nostr::screen_outgoing_event(event, &event_flags, false)
+}
+
+// The old way: each filter on its own
+fn each_filter(filters: &[ChorusFilter]) -> usize {
+    let mut ids = HashSet::new();
+    for filter in filters.iter() {
+        let (found, _) = chorus::nostr::find_events(filter, screen).unwrap();
+        ids.extend(found.iter().map(|e| e.id()));
+    }
+    ids.len()
+}
+
+fn planned(filters: &[ChorusFilter]) -> usize {
+    let plan = chorus::planner::plan(filters).unwrap();
+    let (found, _) = chorus::planner::find_events(filters, &plan, screen).unwrap();
+    found.len()
+}
+
+fn time<F: Fn() -> usize>(f: F) -> (usize, Duration) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        count = f();
+        best = best.min(start.elapsed());
+    }
+    (count, best)
+}
+
+fn main() {
+    let num_events: usize = std::env::var("CHORUS_BENCH_EVENTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(500_000);
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        open_relay: true,
+        allow_scraping: true,
+        ..Default::default()
+    };
+    let store = chorus::setup_store_and_return(&config).unwrap();
+    *GLOBALS.config.write() = config;
+    let _ = GLOBALS.store.set(store);
+
+    // A thousand authors, each posting all ten kinds
+    let keypairs: Vec<Keypair> = (1..=1000_u16)
+        .map(|i| {
+            let mut secret = [0x01_u8; 32];
+            secret[..2].copy_from_slice(&i.to_be_bytes());
+            Keypair::from_seckey_slice(SECP256K1, &secret).unwrap()
+        })
+        .collect();
+    let start = Instant::now();
+    for n in 0..num_events {
+        let bytes = make_event(
+            &keypairs[n % keypairs.len()],
+            KINDS[(n / keypairs.len()) % KINDS.len()],
+            1_700_000_000 + n as u64,
+            n,
+        );
+        let event = unsafe { Event::delineate(&bytes).unwrap() };
+        let _ = chorus::store_event(event).unwrap();
+    }
+    println!("stored {num_events} events in {:?}", start.elapsed());
+
+    // Follows: 50 authors
+    let authors = keypairs[..50]
+        .iter()
+        .map(|k| format!("\"{}\"", hex::encode(k.x_only_public_key().0.serialize())))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    for limit in [None, Some(100)] {
+        let filters: Vec<ChorusFilter> = KINDS
+            .iter()
+            .map(|kind| match limit {
+                Some(limit) => filter(&format!(
+                    r#"{{"authors":[{authors}],"kinds":[{kind}],"limit":{limit}}}"#
+                )),
+                None => filter(&format!(r#"{{"authors":[{authors}],"kinds":[{kind}]}}"#)),
+            })
+            .collect();
+        println!("{}", chorus::planner::plan(&filters).unwrap());
+        let (old_count, old) = time(|| each_filter(&filters));
+        let (new_count, new) = time(|| planned(&filters));
+        assert_eq!(old_count, new_count);
+        println!("limit {limit:?}: {new_count} events, each filter {old:?}, planned {new:?}");
+    }
+}
@@ -0,0 +1,104 @@
+// Compares verifying incoming events inline on the connection tasks (as EVENT used to)
+// with verifying them on the worker pool (chorus::verify), in accepted events per second,
+// for a few numbers of busy connections each sending events one after another. Each run
+// also times a task that should be able to run alongside, to show how much the
+// verification holds up everything else.
+//
+// Run with `cargo bench --bench verify` on a multi-core machine (set
+// CHORUS_BENCH_EVENTS to change how many events each connection sends)
+
+use secp256k1::{Keypair, Message, SECP256K1};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn make_event(keypair: &Keypair, n: usize) -> Vec<u8> {
+    let pubkey = hex::encode(keypair.x_only_public_key().0.serialize());
+    let created_at = 1_700_000_000 + n as u64;
+    let unsigned = format!(
+        r#"{{"pubkey":"{pubkey}","created_at":{created_at},"kind":1,"tags":[],"content":"note {n}"}}"#
+    );
+    let id = chorus::nostr::compute_event_id(unsigned.as_bytes()).unwrap();
+    let sig = SECP256K1.sign_schnorr_no_aux_rand(&Message::from_digest(id), keypair);
+    format!(
+        r#"{{"id":"{}","pubkey":"{pubkey}","created_at":{created_at},"kind":1,"tags":[],"content":"note {n}","sig":"{}"}}"#,
+        hex::encode(id),
+        hex::encode(sig.serialize())
+    )
+    .into_bytes()
+}
+
+// Each connection verifies its events in turn. Returns events per second, and the
+// longest a timer task that ticks every millisecond was held up by.
+async fn run(connections: usize, events: Arc<Vec<Vec<u8>>>, pool: bool) -> (f64, Duration) {
+    let worst = Arc::new(AtomicU64::new(0));
+    let ticker = {
+        let worst = worst.clone();
+        tokio::spawn(async move {
+            loop {
+                let start = Instant::now();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                let late = start.elapsed().saturating_sub(Duration::from_millis(1));
+                let _ = worst.fetch_max(late.as_micros() as u64, Ordering::Relaxed);
+            }
+        })
+    };
+    // (Let it start)
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for _ in 0..connections {
+        let events = events.clone();
+        tasks.push(tokio::spawn(async move {
+            for json in events.iter() {
+                if pool {
+                    chorus::verify::verify(json.clone()).await.unwrap();
+                } else {
+                    chorus::verify::verify_json(json).unwrap();
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    ticker.abort();
+    let worst = Duration::from_micros(worst.load(Ordering::Relaxed));
+    let rate = (connections * events.len()) as f64 / elapsed.as_secs_f64();
+    (rate, worst)
+}
+
+fn main() {
+    let num_events: usize = std::env::var("CHORUS_BENCH_EVENTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(5_000);
+
+    let keypair = Keypair::from_seckey_slice(SECP256K1, &[0x01_u8; 32]).unwrap();
+    let events: Arc<Vec<Vec<u8>>> =
+        Arc::new((0..num_events).map(|n| make_event(&keypair, n)).collect());
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    println!(
+        "{} runtime threads, {} verify workers",
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        chorus::verify::num_workers()
+    );
+
+    for connections in [1, 4, 16, 64] {
+        let (inline, inline_stall) = runtime.block_on(run(connections, events.clone(), false));
+        let (pool, pool_stall) = runtime.block_on(run(connections, events.clone(), true));
+        println!(
+            "{connections} connections: inline {inline:.0} events/s (timer held up {inline_stall:?}), \
+             pool {pool:.0} events/s (timer held up {pool_stall:?})"
+        );
+    }
+}
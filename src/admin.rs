@@ -0,0 +1,105 @@
+//! Management commands carried in nostr events
+//!
+//! Instead of POSTing to the NIP-86 endpoint, a moderator (or an admin) can send an
+//! `ADMIN_COMMAND_KIND` event over the websocket. Its content is the same JSON-RPC request
+//! (`{"method": ..., "params": [...]}`), handled by the same code, and the JSON-RPC
+//! response comes back as the message of the OK. These events are never stored.
+//!
+//! The event must be signed by the sender (it is verified even if `verify_events` is off),
+//! be no more than a minute old, and carry a `relay` tag naming this relay, so that it
+//! cannot be replayed later or elsewhere. Within that minute, the ids of commands already
+//! applied are remembered, so that none can be replayed then either.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::reply::NostrReplyPrefix;
+use dashmap::mapref::entry::Entry;
+use hyper::StatusCode;
+use pocket_types::{Event, Time};
+use serde_json::Value;
+
+/// The kind of management command events (ephemeral, so no relay keeps them)
+pub const ADMIN_COMMAND_KIND: u16 = 28686;
+
+// How far created_at may be from now
+const MAX_SKEW_SECONDS: u64 = 60;
+
+async fn check(event: &Event) -> Result<Value, Error> {
+    let fail = |s: &str| -> Result<Value, Error> {
+        Err(ChorusError::ManagementAuthFailure(s.to_owned()).into())
+    };
+
+    let pubkey = event.pubkey();
+    if !crate::is_admin(pubkey) && !crate::is_moderator(pubkey) {
+        return fail("not an admin or moderator");
+    }
+
+    let now = Time::now().as_u64();
+    let created_at = event.created_at().as_u64();
+    if created_at > now + MAX_SKEW_SECONDS || created_at + MAX_SKEW_SECONDS < now {
+        return fail("command is too old or too far in the future");
+    }
+
+    if !crate::nostr::verify_relay_tag(event, false)? {
+        return fail("command is not addressed to this relay");
+    }
+
+    // On a worker thread, see verify.rs
+    crate::verify::verify(event.as_json()?).await?;
+
+    // Once only
+    GLOBALS
+        .admin_commands
+        .retain(|_, created_at| *created_at + MAX_SKEW_SECONDS >= now);
+    match GLOBALS.admin_commands.entry(event.id()) {
+        Entry::Occupied(_) => return fail("command was already applied"),
+        Entry::Vacant(entry) => {
+            let _ = entry.insert(created_at);
+        }
+    }
+
+    serde_json::from_slice(event.content())
+        .map_err(|_| ChorusError::BadRequest("Command is not JSON").into())
+}
+
+/// Handle a management command event, returning what to say in the OK: whether it
+/// succeeded, and the JSON-RPC response (or why it was refused)
+pub async fn handle(event: &Event) -> (bool, NostrReplyPrefix, String) {
+    // The store belongs to the new process while we hand over
+    if crate::handover::is_handing_over() {
+        return (
+            false,
+            NostrReplyPrefix::Error,
+            "relay is restarting".to_owned(),
+        );
+    }
+
+    let command = match check(event).await {
+        Ok(command) => command,
+        Err(e) => {
+            let prefix = match e.inner {
+                ChorusError::ManagementAuthFailure(_) => NostrReplyPrefix::Restricted,
+                ChorusError::ServerBusy => NostrReplyPrefix::RateLimited,
+                _ => NostrReplyPrefix::Invalid,
+            };
+            return (false, prefix, format!("{}", e.inner));
+        }
+    };
+
+    log::info!(
+        target: "Client",
+        "Management command from {}: {}",
+        event.pubkey().as_hex_string(),
+        command.get("method").and_then(|m| m.as_str()).unwrap_or("?")
+    );
+
+    let (response, status) = crate::web::management::response(
+        crate::web::management::handle_inner(event.pubkey(), command),
+    );
+    let prefix = match status {
+        StatusCode::OK => NostrReplyPrefix::None,
+        StatusCode::BAD_REQUEST | StatusCode::NOT_IMPLEMENTED => NostrReplyPrefix::Invalid,
+        _ => NostrReplyPrefix::Error,
+    };
+    (status == StatusCode::OK, prefix, response.to_string())
+}
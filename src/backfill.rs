@@ -0,0 +1,41 @@
+//! Generic backfill driver for our own indexes
+//!
+//! When an index is introduced (or needs rebuilding) it must be filled in for the events
+//! already in the store. This walks every stored event that `screen` matches a chunk at a
+//! time (see `crate::walk`) and hands each one to `index`, committing a write transaction
+//! per chunk, so that neither the transaction nor the events found at once grow with the
+//! store. Callers record an index as built only once its backfill returns, so one that was
+//! stopped partway is built again (from scratch) on the next start.
+
+use crate::error::Error;
+use crate::walk::Walk;
+use pocket_db::heed::RwTxn;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::Event;
+
+/// Feed every stored event that `screen` matches to `index`, a chunk of events (and a
+/// write transaction) at a time. `name` is only for logging.
+///
+/// Returns the number of events indexed.
+pub fn backfill<S, I>(store: &Store, name: &str, screen: S, mut index: I) -> Result<usize, Error>
+where
+    S: Fn(&Event) -> ScreenResult,
+    I: FnMut(&mut RwTxn, &Event) -> Result<(), Error>,
+{
+    log::info!(target: "Server", "Building {name}...");
+
+    let mut walk = Walk::all();
+    let mut indexed: usize = 0;
+    while let Some(events) = walk.next_chunk_in(store, &screen)? {
+        let _reading = crate::map_size::reading();
+        let mut txn = store.write_txn()?;
+        for event in events.iter() {
+            index(&mut txn, event)?;
+        }
+        txn.commit()?;
+        indexed += events.len();
+    }
+
+    log::info!(target: "Server", "Built {name} over {indexed} events");
+    Ok(indexed)
+}
@@ -1,14 +1,10 @@
-use chorus::config::{Config, FriendlyConfig};
-use chorus::counting_stream::CountingStream;
-use chorus::error::Error;
+use chorus::error::{ChorusError, Error};
 use chorus::globals::GLOBALS;
-use chorus::ip::HashedPeer;
+use chorus::listener::Listener;
 use std::env;
-use std::fs::OpenOptions;
-use std::io::Read;
+use std::os::fd::RawFd;
 use std::sync::atomic::Ordering;
 use std::time::Duration;
-use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
@@ -28,33 +24,171 @@ async fn main() -> Result<(), Error> {
     // Log host name
     log::info!(target: "Server", "HOSTNAME = {}", config.hostname);
 
+    // Keep maintenance tools off the store while we have it open
+    chorus::data_lock::share(&config)?;
+
     chorus::setup_store(&config)?;
 
+    // Build the multi-letter tag index if the indexed tag names changed
+    chorus::tag_index::migrate(GLOBALS.store.get().unwrap(), &config)?;
+
+    // Index when events stored before we kept the first-seen index were received
+    chorus::first_seen::migrate(GLOBALS.store.get().unwrap())?;
+
+    // Build or drop the search index if enable_search changed
+    chorus::search_index::migrate(GLOBALS.store.get().unwrap(), &config)?;
+
+    // Index expiring events stored before we kept the expiration index
+    chorus::expiration::migrate(GLOBALS.store.get().unwrap())?;
+
+    // Index events for counting stored before we kept the count index
+    chorus::count::migrate(GLOBALS.store.get().unwrap())?;
+
+    // Carry out `a` tag deletions stored before we handled them
+    chorus::deletion::migrate(GLOBALS.store.get().unwrap())?;
+
+    // Remove superseded versions of replaceable events stored before we kept only the latest
+    chorus::replaceable::migrate(GLOBALS.store.get().unwrap())?;
+
+    // Pick up any undelivered events for the event sink
+    chorus::sink::init()?;
+
+    // And any not yet forwarded to peer relays
+    chorus::forward::init()?;
+
     if let Some(ref blossom_directory) = config.blossom_directory {
-        let filestore = chorus::filestore::FileStore::new(blossom_directory).await?;
+        let filestore =
+            chorus::filestore::FileStore::new(blossom_directory, config.blossom_shard_depth)
+                .await?;
         let _ = GLOBALS.filestore.set(filestore);
     }
 
     // TLS setup
-    let maybe_tls_acceptor = if config.use_tls {
+    if config.tls_in_use() {
         log::info!(target: "Server", "Using TLS");
-        Some(chorus::tls::tls_acceptor(&config)?)
+        chorus::tls::load(&config)?;
     } else {
         log::info!(target: "Server", "Not using TLS");
-        None
-    };
+    }
 
-    // Bind listener to port
-    let listener = TcpListener::bind((&*config.ip_address, config.port)).await?;
-    log::info!(target: "Server", "Running on {}:{}", config.ip_address, config.port);
+    // Bind the listeners, or adopt the ones handed over to us
+    let handover_state = chorus::handover::inherited_state()?;
+    let mut listeners: Vec<Listener> = Vec::with_capacity(config.listeners.len());
+    match handover_state {
+        Some(ref state) => {
+            let fds = chorus::handover::take_listen_fds(state)?;
+            if fds.len() != config.listeners.len() {
+                return Err(ChorusError::Handover(format!(
+                    "handed {} listeners, but {} are configured",
+                    fds.len(),
+                    config.listeners.len()
+                ))
+                .into());
+            }
+            for (spec, fd) in config.listeners.iter().zip(fds) {
+                listeners.push(Listener::adopt(spec, fd)?);
+            }
+        }
+        None => {
+            for spec in config.listeners.iter() {
+                listeners.push(Listener::bind(spec).await?);
+            }
+        }
+    }
+    for listener in listeners.iter() {
+        log::info!(target: "Server", "Running on {}", listener.spec);
+    }
 
     // Store config into GLOBALS
     *GLOBALS.config.write() = config;
 
+    // Watch live event delivery lag
+    tokio::spawn(chorus::lag::monitor());
+
+    // Deliver accepted events to the event sink, if configured
+    tokio::spawn(chorus::sink::run());
+
+    // Forward accepted events to peer relays, if configured
+    tokio::spawn(chorus::forward::run());
+
+    // Pick up renewed TLS certificates
+    if GLOBALS.config.read().tls_in_use() {
+        tokio::spawn(chorus::tls::watch());
+    }
+
+    // Run the write policy plugin, if configured
+    if GLOBALS.config.read().write_policy_plugin.is_some() {
+        tokio::spawn(chorus::write_policy::run());
+    }
+
+    // Remove events as they expire (NIP-40)
+    tokio::spawn(chorus::expiration::run());
+
+    // Forget since_last cursors as they expire
+    tokio::spawn(chorus::resume::run());
+
+    // Remove events past their retention, if configured
+    tokio::spawn(chorus::retention::run());
+
+    // Remove ephemeral events that older versions stored, if configured
+    if GLOBALS.config.read().sweep_ephemeral_on_startup {
+        tokio::spawn(async {
+            match chorus::ephemeral::sweep().await {
+                Ok(n) => log::info!(target: "Server", "Removed {n} stored ephemeral events"),
+                Err(e) => log::error!(target: "Server", "Ephemeral event sweep failed: {e}"),
+            }
+        });
+    }
+
+    // Move Blossom blobs to where they now belong, if they are not there yet, then
+    // record the MIME types of those stored before we did
+    if let Some(filestore) = GLOBALS.filestore.get() {
+        tokio::spawn(async {
+            match filestore.migrate_layout().await {
+                Ok(0) => {}
+                Ok(n) => log::info!(target: "Server", "Filestore: moved {n} blobs into place"),
+                Err(e) => log::error!(target: "Server", "Filestore: moving blobs failed: {e}"),
+            }
+            match chorus::filestore::metadata::backfill_mime_types(filestore).await {
+                Ok(0) => {}
+                Ok(n) => log::info!(target: "Server", "Filestore: recorded {n} MIME types"),
+                Err(e) => {
+                    log::error!(target: "Server", "Filestore: recording MIME types failed: {e}")
+                }
+            }
+        });
+    }
+
+    // Garbage collect Blossom blobs, if configured
+    if GLOBALS.filestore.get().is_some() {
+        tokio::spawn(chorus::filestore::gc::run());
+    }
+
+    // Forget idle rate limit buckets
+    tokio::spawn(chorus::rate_limit::run());
+
+    // Keep the NIP-11 document (and the status in it) fresh
+    tokio::spawn(chorus::web::nip11::refresh_rid());
+
+    // Accept connections on each listener
+    let listen_fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    let accepting: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(listener.run()))
+        .collect();
+
+    // If we are taking over from an old process, let it know we are ready
+    if let Some(ref state) = handover_state {
+        chorus::handover::signal_ready(state)?;
+    }
+
     let mut interrupt_signal = signal(SignalKind::interrupt())?;
     let mut quit_signal = signal(SignalKind::quit())?;
     let mut terminate_signal = signal(SignalKind::terminate())?;
     let mut hup_signal = signal(SignalKind::hangup())?;
+    let mut usr2_signal = signal(SignalKind::user_defined2())?;
+
+    let mut handed_over = false;
 
     loop {
         tokio::select! {
@@ -76,109 +210,149 @@ async fn main() -> Result<(), Error> {
             v = hup_signal.recv() => if v.is_some() {
                 log::info!(target: "Server", "SIGHUP: Reloading configuration");
 
-                // Reload the config file
-                let mut file = OpenOptions::new().read(true).open(config_path.clone())?;
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)?;
-                let friendly_config: FriendlyConfig = toml::from_str(&contents)?;
-                let config: Config = friendly_config.into_config()?;
+                // Reload the config file, keeping the running one if the new one is bad
+                let mut config = match chorus::load_config(&config_path) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        log::error!(target: "Server", "SIGHUP: Keeping the running configuration: {e}");
+                        continue;
+                    }
+                };
+
+                let changed = config.keep_startup_settings(&GLOBALS.config.read());
+                if !changed.is_empty() {
+                    log::warn!(
+                        target: "Server",
+                        "SIGHUP: Changes to {} require a restart and were not applied",
+                        changed.join(", ")
+                    );
+                }
+
+                // Pick up renewed certificates
+                if config.tls_in_use() {
+                    match chorus::tls::load(&config) {
+                        Ok(()) => {
+                            log::info!(target: "Server", "SIGHUP: Reloaded TLS certificates");
+                        }
+                        Err(e) => {
+                            log::error!(target: "Server", "SIGHUP: Keeping the running configuration, TLS failed: {e}");
+                            continue;
+                        }
+                    }
+                }
+
+                let new_mode = config.mode;
+                let old_mode = std::mem::replace(&mut *GLOBALS.config.write(), config).mode;
+                if new_mode != old_mode {
+                    log::warn!(target: "Server", "SIGHUP: Switched from {old_mode} to {new_mode} mode");
+                }
 
-                *GLOBALS.config.write() = config;
+                // The relay information document has the name, description, limits, etc.
+                let _ = chorus::web::nip11::rebuild_rid();
 
                 chorus::print_stats();
             },
 
-            // Accepts network connections and spawn a task to serve each one
-            v = listener.accept() => {
-                let (tcp_stream, hashed_peer) = {
-                    let (tcp_stream, peer_addr) = v?;
-                    let hashed_peer = HashedPeer::new(peer_addr);
-                    (tcp_stream, hashed_peer)
-                };
-
-                // Possibly IP block early
-                if ! GLOBALS.config.read().chorus_is_behind_a_proxy
-                    && GLOBALS.config.read().enable_ip_blocking
-                {
-                    let ip_data = chorus::get_ip_data(hashed_peer.ip())?;
-                    if ip_data.is_banned() {
-                        log::debug!(target: "Client",
-                                    "{}: Blocking reconnection until {}",
-                                    hashed_peer.ip(),
-                                    ip_data.ban_until);
-                        // note: no need to shutdown() which only drops the write half.
-                        // the whole thing gets dropped when we continue.
-                        continue;
+            // Hand the listeners over to a new process on USR2
+            v = usr2_signal.recv() => if v.is_some() {
+                log::info!(target: "Server", "SIGUSR2: Handing over to a new process");
+                match chorus::handover::hand_over(&listen_fds, &config_path).await {
+                    Ok(pid) => {
+                        log::info!(target: "Server", "Handed over to pid {}", pid);
+                        handed_over = true;
+                        break;
+                    }
+                    Err(e) => {
+                        log::error!(target: "Server", "{}", e);
                     }
                 }
-
-                let counting_stream = CountingStream(tcp_stream);
-
-                let maybe_tls_acceptor_clone = maybe_tls_acceptor.clone();
-                tokio::spawn(async move {
-                    match maybe_tls_acceptor_clone {
-                        Some(tls_acceptor) => {
-                            match tls_acceptor.accept(counting_stream).await {
-                                Ok(stream) => {
-                                    let io = hyper_util::rt::TokioIo::new(stream);
-                                    chorus::serve(io, hashed_peer).await;
-                                },
-                                Err(e) => {
-                                    log::error!(
-                                        target: "Client",
-                                        "{}: TLS accept: {}", hashed_peer, e
-                                    );
-                                }
-                            }
-                        },
-                        None => {
-                            let io = hyper_util::rt::TokioIo::new(counting_stream);
-                            chorus::serve(io, hashed_peer).await;
-                        }
-                    };
-                });
-            }
+            },
         };
     }
 
-    // Pre-sync in case something below hangs up
-    let _ = GLOBALS.store.get().unwrap().sync();
-
-    // Set the shutting down signal
-    let _ = GLOBALS.shutting_down.send(true);
+    // Stop accepting; after a handover the new process keeps the sockets open
+    for task in accepting.iter() {
+        task.abort();
+    }
 
-    // Wait for active websockets to shutdown gracefully
-    let mut num_connections = GLOBALS.num_connections.load(Ordering::Relaxed);
-    if num_connections != 0 {
-        log::info!(target: "Server", "Waiting for {num_connections} websockets to shutdown...");
+    // After a handover, let existing connections finish on their own for a while
+    if handed_over {
+        let grace = GLOBALS.config.read().handover_grace_seconds;
+        log::info!(target: "Server", "Draining connections for up to {grace} seconds...");
 
-        // We will check if all clients have shutdown every 50ms
-        let interval = tokio::time::interval(Duration::from_millis(50));
+        let interval = tokio::time::interval(Duration::from_millis(250));
         tokio::pin!(interval);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(grace);
 
-        let mut ms = 0;
-
-        while num_connections != 0 {
-            // If we get another shutdown signal, stop waiting for websockets
+        while GLOBALS.num_connections.load(Ordering::Relaxed) != 0 {
             tokio::select! {
                 v = interrupt_signal.recv() => if v.is_some() {
                     break;
                 },
-                v = quit_signal.recv() => if v.is_some() {
-                    break;
-                },
                 v = terminate_signal.recv() => if v.is_some() {
                     break;
                 },
-                _instant = interval.tick() => {
-                    ms += 50;
-                    if ms > 5_000 {
-                        log::info!(target: "Server", "Some connections were hung.");
+                instant = interval.tick() => {
+                    if instant >= deadline {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Pre-sync in case something below hangs up
+    let _ = GLOBALS.store.get().unwrap().sync();
+
+    // Set the shutting down signal
+    let _ = GLOBALS.shutting_down.send(true);
+
+    // Wait for active websockets to close (they were just told to) and in-flight HTTP
+    // requests to finish, for up to shutdown_grace_seconds
+    let in_flight = || {
+        (
+            GLOBALS.num_connections.load(Ordering::Relaxed),
+            GLOBALS.num_http_requests.load(Ordering::Relaxed),
+        )
+    };
+    let (num_connections, num_http_requests) = in_flight();
+    if num_connections != 0 || num_http_requests != 0 {
+        let grace = GLOBALS.config.read().shutdown_grace_seconds;
+        log::info!(
+            target: "Server",
+            "Waiting up to {grace} seconds for {num_connections} websockets and {num_http_requests} HTTP requests to finish..."
+        );
+
+        // We will check if everything has finished every 50ms
+        let interval = tokio::time::interval(Duration::from_millis(50));
+        tokio::pin!(interval);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(grace);
+
+        loop {
+            // If we get another shutdown signal, exit immediately
+            let forced = tokio::select! {
+                v = interrupt_signal.recv() => v.is_some(),
+                v = quit_signal.recv() => v.is_some(),
+                v = terminate_signal.recv() => v.is_some(),
+                instant = interval.tick() => {
+                    if in_flight() == (0, 0) {
+                        break;
+                    }
+                    if instant >= deadline {
+                        let (c, h) = in_flight();
+                        log::info!(
+                            target: "Server",
+                            "Aborting {c} websockets and {h} HTTP requests that did not finish in time."
+                        );
                         break;
                     }
-                    num_connections = GLOBALS.num_connections.load(Ordering::Relaxed);
-                    continue;
+                    false
                 }
+            };
+            if forced {
+                log::info!(target: "Server", "Second signal, exiting immediately.");
+                let _ = GLOBALS.store.get().unwrap().sync();
+                std::process::exit(1);
             }
         }
     }
@@ -35,7 +35,7 @@ fn main() -> Result<(), Error> {
                 .next()
                 .ok_or::<Error>(ChorusError::General("ID argument missing".to_owned()).into())?;
             let id: Id = Id::read_hex(idstr.as_bytes())?;
-            GLOBALS.store.get().unwrap().remove_event(id)?;
+            chorus::remove_event(id)?;
             println!("Done.");
         }
         "delete_by_pubkey" => {
@@ -56,7 +56,7 @@ fn main() -> Result<(), Error> {
                     .unwrap()
                     .find_events(filter, true, 0, 0, |_| ScreenResult::Match)?;
             for event in events.iter() {
-                GLOBALS.store.get().unwrap().remove_event(event.id())?;
+                chorus::remove_event(event.id())?;
             }
             println!("Done.");
         }
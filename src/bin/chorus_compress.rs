@@ -1,7 +1,12 @@
 use chorus::error::Error;
 use std::env;
-use std::io::BufRead;
-fn main() -> Result<(), Error> {
+use std::process::ExitCode;
+
+// Exit codes
+const DONE: u8 = 0;
+const FAILED: u8 = 3;
+
+fn main() -> ExitCode {
     // Get args (config path)
     let mut args = env::args();
     if args.len() <= 1 {
@@ -10,14 +15,22 @@ fn main() -> Result<(), Error> {
     let _ = args.next(); // ignore program name
     let config_path = args.next().unwrap();
 
+    match compress(config_path) {
+        Ok(()) => ExitCode::from(DONE),
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(FAILED)
+        }
+    }
+}
+
+fn compress(config_path: String) -> Result<(), Error> {
     let config = chorus::load_config(config_path)?;
 
     chorus::setup_logging(&config);
 
-    println!("Chorus must NOT be running when you do this.");
-    println!("Proceed? (break out with ^C, or press <ENTER> to proceed)");
-    let stdin = std::io::stdin();
-    let _ = stdin.lock().lines().next().unwrap().unwrap();
+    // Chorus must not be running
+    chorus::data_lock::exclusive(&config)?;
 
     let store = chorus::setup_store_and_return(&config)?;
 
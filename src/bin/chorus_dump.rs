@@ -1,43 +1,38 @@
 use chorus::error::Error;
 use chorus::globals::GLOBALS;
-use pocket_db::ScreenResult;
-use pocket_types::{Event, Filter};
 use std::env;
+use std::fs::File;
+use std::io::BufWriter;
 
 fn main() -> Result<(), Error> {
-    // Get args (config path)
-    let mut args = env::args();
+    // Get args (optional --seen, config path, optional filter, optional output file)
+    let mut args = env::args().peekable();
     if args.len() <= 1 {
-        panic!("USAGE: chorus_dump <chorus_config_path>");
+        panic!("USAGE: chorus_dump [--seen] <chorus_config_path> [<filter_json> [<output_path>]]");
     }
     let _ = args.next(); // ignore program name
+    let seen = args.next_if(|arg| arg == "--seen").is_some();
     let config_path = args.next().unwrap();
+    let filter = args.next().unwrap_or("{}".to_owned());
+    let output_path = args.next();
 
-    let mut config = chorus::load_config(config_path)?;
-
-    // Force allow of scraping (this program is a scraper)
-    config.allow_scraping = true;
+    let config = chorus::load_config(config_path)?;
 
     chorus::setup_logging(&config);
     chorus::setup_store(&config)?;
-
-    let mut buffer: [u8; 128] = [0; 128];
-    let (_incount, _outcount, filter) = Filter::from_json(b"{}", &mut buffer)?;
-    let screen = |_: &Event| -> ScreenResult { ScreenResult::Match };
-
-    let (mut events, _redacted) = GLOBALS.store.get().unwrap().find_events(
-        filter,
-        config.allow_scraping,
-        config.allow_scrape_if_limited_to,
-        config.allow_scrape_if_max_seconds,
-        screen,
-    )?;
-
-    for event in events.drain(..) {
-        let bytes = event.as_json()?;
-        let s = unsafe { std::str::from_utf8_unchecked(&bytes) };
-        println!("{s}");
-    }
+    *GLOBALS.config.write() = config;
+
+    let count = match output_path {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            chorus::jsonl::export(filter.as_bytes(), seen, &mut out)?
+        }
+        None => {
+            let mut out = BufWriter::new(std::io::stdout().lock());
+            chorus::jsonl::export(filter.as_bytes(), seen, &mut out)?
+        }
+    };
+    log::info!(target: "Server", "Exported {count} events");
 
     Ok(())
 }
@@ -0,0 +1,40 @@
+use chorus::error::Error;
+use chorus::globals::GLOBALS;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+
+fn main() -> Result<(), Error> {
+    // Get args (config path, optional input file)
+    let mut args = env::args();
+    if args.len() <= 1 {
+        panic!("USAGE: chorus_import <chorus_config_path> [<input_path>]");
+    }
+    let _ = args.next(); // ignore program name
+    let config_path = args.next().unwrap();
+    let input_path = args.next();
+
+    let config = chorus::load_config(config_path)?;
+
+    chorus::setup_logging(&config);
+    chorus::data_lock::share(&config)?;
+    chorus::setup_store(&config)?;
+
+    // Our own indexes are brought up to date by the relay at startup (each in one
+    // transaction over the whole store), not here where a live relay would wait on them.
+    // Events we import are indexed as they are stored either way.
+
+    *GLOBALS.config.write() = config;
+
+    let report = match input_path {
+        Some(path) => chorus::jsonl::import(BufReader::new(File::open(path)?))?,
+        None => chorus::jsonl::import(std::io::stdin().lock())?,
+    };
+
+    println!(
+        "accepted: {}, duplicate: {}, invalid: {}, rejected: {}",
+        report.accepted, report.duplicate, report.invalid, report.rejected
+    );
+
+    Ok(())
+}
@@ -80,7 +80,7 @@ fn main() -> Result<(), Error> {
 
         // Delete if pubkey marked banned
         if matches!(chorus::get_pubkey_approval(event.pubkey()), Ok(Some(false))) {
-            GLOBALS.store.get().unwrap().remove_event(event.id())?;
+            chorus::remove_event(event.id())?;
             continue;
         }
 
@@ -109,7 +109,7 @@ fn main() -> Result<(), Error> {
                 }
                 b'P' => {
                     chorus::mark_pubkey_approval(event.pubkey(), false)?;
-                    GLOBALS.store.get().unwrap().remove_event(event.id())?;
+                    chorus::remove_event(event.id())?;
                     println!("User banned.");
                     break;
                 }
@@ -120,7 +120,7 @@ fn main() -> Result<(), Error> {
                 }
                 b'I' => {
                     chorus::mark_event_approval(event.id(), false)?;
-                    GLOBALS.store.get().unwrap().remove_event(event.id())?;
+                    chorus::remove_event(event.id())?;
                     println!("Event banned.");
                     break;
                 }
@@ -0,0 +1,86 @@
+use chorus::error::Error;
+use chorus::globals::GLOBALS;
+use std::env;
+use std::process::ExitCode;
+
+// Exit codes
+const CLEAN: u8 = 0;
+const REPAIRED: u8 = 1;
+const PROBLEMS_REMAIN: u8 = 2;
+const FAILED: u8 = 3;
+
+fn main() -> ExitCode {
+    // Get args (config path, optional --repair)
+    let mut args = env::args();
+    if args.len() <= 1 {
+        panic!("USAGE: chorus_verify <config_path> [--repair]");
+    }
+    let _ = args.next(); // ignore program name
+    let config_path = args.next().unwrap();
+    let repair = args.next().as_deref() == Some("--repair");
+
+    match verify(config_path, repair) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(FAILED)
+        }
+    }
+}
+
+fn verify(config_path: String, repair: bool) -> Result<u8, Error> {
+    let config = chorus::load_config(config_path)?;
+
+    chorus::setup_logging(&config);
+
+    // Chorus must not be running
+    chorus::data_lock::exclusive(&config)?;
+
+    chorus::setup_store(&config)?;
+    *GLOBALS.config.write() = config;
+
+    let report = chorus::verify_store()?;
+    for problem in report.problems.iter() {
+        println!("{problem}");
+    }
+    println!(
+        "events: {}, damaged: {}, unindexed: {}, mismatched index entries: {}, stale tag index entries: {}",
+        report.events,
+        report.damaged.len(),
+        report.unindexed.len(),
+        report.mismatched_entries,
+        report.stale_index_entries
+    );
+
+    let needs_repair =
+        !report.damaged.is_empty() || !report.unindexed.is_empty() || report.mismatched_entries > 0;
+    if !needs_repair {
+        return Ok(CLEAN);
+    }
+    if !repair {
+        println!("Run again with --repair to repair what can be repaired");
+        return Ok(PROBLEMS_REMAIN);
+    }
+
+    let repairs = chorus::integrity::repair(&report)?;
+    for failure in repairs.failed.iter() {
+        println!("{failure}");
+    }
+    println!(
+        "removed: {}, reindexed: {}, stale tag index entries removed: {}",
+        repairs.removed, repairs.reindexed, repairs.stale_removed
+    );
+
+    // See what is left
+    let report = chorus::verify_store()?;
+    if report.damaged.is_empty() && report.unindexed.is_empty() && report.mismatched_entries == 0 {
+        Ok(REPAIRED)
+    } else {
+        if report.mismatched_entries > 0 {
+            println!(
+                "Index entries lead to the wrong events: rebuild the store with chorus_compress"
+            );
+        }
+        Ok(PROBLEMS_REMAIN)
+    }
+}
@@ -0,0 +1,86 @@
+//! The NIPs we implement, and the configuration under which we implement them
+//!
+//! NIP-11 `supported_nips` is computed from this registry, so when a feature (or a config
+//! switch for it) is added, register its NIPs here rather than advertising them directly.
+
+use crate::config::Config;
+
+/// A feature and the NIPs it implements
+pub struct Capability {
+    /// Name of the feature (for humans)
+    pub feature: &'static str,
+
+    /// NIPs implemented by the feature
+    pub nips: &'static [u16],
+
+    /// Whether the feature is enabled under a config
+    pub enabled: fn(&Config) -> bool,
+}
+
+fn always(_config: &Config) -> bool {
+    true
+}
+
+/// Every feature that implements a NIP
+pub const CAPABILITIES: &[Capability] = &[
+    Capability {
+        feature: "nostr protocol (nostr.rs)",
+        nips: &[
+            1,  // nostr
+            9,  // Event Deletion
+            40, // Expiration Timestamp
+            65, // Relay List Metadata
+        ],
+        enabled: always,
+    },
+    Capability {
+        feature: "relay information document (web/nip11.rs)",
+        nips: &[11],
+        enabled: always,
+    },
+    Capability {
+        feature: "direct messages",
+        nips: &[
+            4,  // DMs
+            59, // GiftWrap
+        ],
+        enabled: always,
+    },
+    Capability {
+        feature: "AUTH",
+        nips: &[42],
+        enabled: always,
+    },
+    Capability {
+        feature: "COUNT",
+        nips: &[45],
+        enabled: always,
+    },
+    Capability {
+        feature: "search (search_index.rs)",
+        nips: &[50],
+        enabled: |config| config.enable_search,
+    },
+    Capability {
+        feature: "protected events",
+        nips: &[70],
+        enabled: always,
+    },
+    Capability {
+        feature: "negentropy sync",
+        nips: &[77],
+        enabled: |config| config.enable_negentropy,
+    },
+];
+
+/// The NIPs we implement under this config, in order
+pub fn supported_nips(config: &Config) -> Vec<u16> {
+    let mut nips: Vec<u16> = CAPABILITIES
+        .iter()
+        .filter(|c| (c.enabled)(config))
+        .flat_map(|c| c.nips.iter().copied())
+        .collect();
+    nips.sort();
+    nips.dedup();
+    nips
+}
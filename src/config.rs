@@ -1,7 +1,16 @@
-use crate::error::Error;
+use crate::error::{ChorusError, Error};
+use crate::filestore::layout::MAX_SHARD_DEPTH;
+use crate::filestore::media::MediaFormat;
+use crate::ip::HashedIp;
+use crate::listener::ListenerSpec;
+use crate::mode::Mode;
+use crate::proxy::Cidr;
+use crate::retention::RetentionRule;
+use crate::web::nip11::Fees;
 use hyper::http::uri::{Authority, Scheme, Uri};
 use pocket_types::Pubkey;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use std::str::FromStr;
 use url::Host;
 
@@ -47,6 +56,106 @@ pub struct FriendlyConfig {
     pub throttling_burst: usize,
     pub blossom_directory: Option<String>,
     pub enable_negentropy: bool,
+    pub lag_warn_ms: u64,
+    pub lag_warn_seconds: u64,
+    pub keep_rejected_events: bool,
+    pub rejected_events_max_count: usize,
+    pub rejected_events_max_bytes: usize,
+    pub rejected_events_max_seconds: u64,
+    pub handover_grace_seconds: u64,
+    pub indexed_tag_names: Vec<String>,
+    pub event_sink_url: Option<String>,
+    pub event_sink_include_ephemeral: bool,
+    pub event_sink_batch_size: usize,
+    pub event_sink_max_outbox: u64,
+    pub event_sink_overflow_drop_oldest: bool,
+    pub enable_since_seen: bool,
+    pub blossom_mirror_max_bytes: u64,
+    pub blossom_mirror_timeout_seconds: u64,
+    pub enable_search: bool,
+    pub search_max_scanned: usize,
+    pub blossom_max_upload_bytes: u64,
+    pub blossom_allowed_mime_types: Vec<String>,
+    pub auth_required_for_read: bool,
+    pub auth_required_for_write: bool,
+    pub expiration_sweep_seconds: u64,
+    pub shutdown_grace_seconds: u64,
+    pub enable_metrics: bool,
+    pub metrics_bearer_token: Option<String>,
+    pub metrics_allowed_ips: Vec<String>,
+    pub max_events_per_minute: u32,
+    pub max_reqs_per_minute: u32,
+    pub max_blossom_uploads_per_minute: u32,
+    pub rate_limit_ban_after: u32,
+    pub rate_limit_ban_seconds: u64,
+    pub rate_limit_exempt_pubkeys: Vec<String>,
+    pub blossom_gc_interval_seconds: u64,
+    pub blossom_gc_unaccessed_days: u64,
+    pub blossom_max_total_bytes: u64,
+    pub blossom_gc_grace_seconds: u64,
+    pub negentropy_max_items: usize,
+    pub protected_events_author_only: bool,
+    pub trusted_proxies: Vec<String>,
+    pub proxy_protocol: bool,
+    pub lmdb_map_size: u64,
+    pub lmdb_max_map_size: u64,
+    pub retention_sweep_seconds: u64,
+    pub retention: Vec<RetentionRule>,
+    pub max_inflight_bytes: usize,
+    pub user_hex_keys: Vec<String>,
+    pub users_from_relay_lists: bool,
+    pub enable_http2: bool,
+    pub tls_reload_seconds: u64,
+    pub json_logs: bool,
+    pub write_policy_plugin: Option<String>,
+    pub write_policy_timeout_ms: u64,
+    pub write_policy_fail_open: bool,
+    pub write_policy_allow_pubkeys: Vec<String>,
+    pub write_policy_deny_pubkeys: Vec<String>,
+    pub websocket_compression: bool,
+    pub websocket_compression_level: u8,
+    pub websocket_compression_min_bytes: usize,
+    pub mode: String,
+    pub blossom_allowed_pubkeys: Vec<String>,
+    pub blossom_relay_users: bool,
+    pub stats_bearer_token: Option<String>,
+    pub verify_workers: usize,
+    pub verify_queue: usize,
+    pub extra_ephemeral_kinds: Vec<u16>,
+    pub sweep_ephemeral_on_startup: bool,
+    pub forward_relays: Vec<String>,
+    pub forward_kinds: Vec<u16>,
+    pub forward_authors: Vec<String>,
+    pub relay_countries: Vec<String>,
+    pub language_tags: Vec<String>,
+    pub tags: Vec<String>,
+    pub posting_policy: Option<String>,
+    pub payments_url: Option<String>,
+    pub fees: Option<Fees>,
+    pub max_filters: usize,
+    pub created_at_upper_drift: u64,
+    pub created_at_lower_limit: i64,
+    pub created_at_lower_limit_exempt_kinds: Vec<u16>,
+    pub max_event_bytes: usize,
+    pub max_event_tags: usize,
+    pub default_limit: u32,
+    pub max_limit: u32,
+    pub ip_reputation_half_life_seconds: u64,
+    pub ip_ban_threshold: u32,
+    pub listeners: Vec<ListenerSpec>,
+    pub private_dms: bool,
+    pub blossom_shard_depth: usize,
+    pub max_message_length: usize,
+    pub max_filter_values: usize,
+    pub enable_since_last: bool,
+    pub since_last_expiry_days: u64,
+    pub max_since_last_cursors: usize,
+    pub blossom_media_max_dimension: u32,
+    pub blossom_media_format: String,
+    pub blossom_media_quality: u8,
+    pub blossom_media_keep_original: bool,
+    pub blossom_media_max_transcodes: usize,
+    pub blossom_media_max_pixels: u64,
 }
 
 impl Default for FriendlyConfig {
@@ -90,6 +199,106 @@ impl Default for FriendlyConfig {
             throttling_burst: 1024 * 1024 * 16,
             blossom_directory: None,
             enable_negentropy: false,
+            lag_warn_ms: 1000,
+            lag_warn_seconds: 30,
+            keep_rejected_events: false,
+            rejected_events_max_count: 1000,
+            rejected_events_max_bytes: 4 * 1024 * 1024,
+            rejected_events_max_seconds: 86400,
+            handover_grace_seconds: 30,
+            indexed_tag_names: vec![],
+            event_sink_url: None,
+            event_sink_include_ephemeral: false,
+            event_sink_batch_size: 100,
+            event_sink_max_outbox: 100_000,
+            event_sink_overflow_drop_oldest: false,
+            enable_since_seen: false,
+            blossom_mirror_max_bytes: 104857600,
+            blossom_mirror_timeout_seconds: 60,
+            enable_search: false,
+            search_max_scanned: 10000,
+            blossom_max_upload_bytes: 104857600,
+            blossom_allowed_mime_types: vec![],
+            auth_required_for_read: false,
+            auth_required_for_write: false,
+            expiration_sweep_seconds: 300,
+            shutdown_grace_seconds: 10,
+            enable_metrics: false,
+            metrics_bearer_token: None,
+            metrics_allowed_ips: vec![],
+            max_events_per_minute: 120,
+            max_reqs_per_minute: 120,
+            max_blossom_uploads_per_minute: 30,
+            rate_limit_ban_after: 20,
+            rate_limit_ban_seconds: 600,
+            rate_limit_exempt_pubkeys: vec![],
+            blossom_gc_interval_seconds: 3600,
+            blossom_gc_unaccessed_days: 0,
+            blossom_max_total_bytes: 0,
+            blossom_gc_grace_seconds: 86400,
+            negentropy_max_items: 500000,
+            protected_events_author_only: false,
+            trusted_proxies: vec![],
+            proxy_protocol: false,
+            lmdb_map_size: 0,
+            lmdb_max_map_size: 68719476736,
+            retention_sweep_seconds: 3600,
+            retention: vec![],
+            max_inflight_bytes: 1048576,
+            user_hex_keys: vec![],
+            users_from_relay_lists: false,
+            enable_http2: true,
+            tls_reload_seconds: 60,
+            json_logs: false,
+            write_policy_plugin: None,
+            write_policy_timeout_ms: 1000,
+            write_policy_fail_open: false,
+            write_policy_allow_pubkeys: vec![],
+            write_policy_deny_pubkeys: vec![],
+            websocket_compression: false,
+            websocket_compression_level: 6,
+            websocket_compression_min_bytes: 256,
+            mode: "normal".to_owned(),
+            blossom_allowed_pubkeys: vec![],
+            blossom_relay_users: false,
+            stats_bearer_token: None,
+            verify_workers: 0,
+            verify_queue: 1024,
+            extra_ephemeral_kinds: vec![],
+            sweep_ephemeral_on_startup: false,
+            forward_relays: vec![],
+            forward_kinds: vec![],
+            forward_authors: vec![],
+            relay_countries: vec!["*".to_owned()],
+            language_tags: vec!["*".to_owned()],
+            tags: vec![],
+            posting_policy: None,
+            payments_url: None,
+            fees: None,
+            max_filters: 20,
+            created_at_upper_drift: 900,
+            created_at_lower_limit: 0,
+            created_at_lower_limit_exempt_kinds: vec![0, 3],
+            max_event_bytes: 524288,
+            max_event_tags: 5000,
+            default_limit: 500,
+            max_limit: 5000,
+            ip_reputation_half_life_seconds: 3600,
+            ip_ban_threshold: 10,
+            listeners: vec![],
+            private_dms: true,
+            blossom_shard_depth: 2,
+            max_message_length: 1048576,
+            max_filter_values: 1000,
+            enable_since_last: false,
+            since_last_expiry_days: 30,
+            max_since_last_cursors: 100,
+            blossom_media_max_dimension: 2048,
+            blossom_media_format: "jpeg".to_owned(),
+            blossom_media_quality: 85,
+            blossom_media_keep_original: false,
+            blossom_media_max_transcodes: 2,
+            blossom_media_max_pixels: 40000000,
         }
     }
 }
@@ -135,6 +344,106 @@ impl FriendlyConfig {
             throttling_burst,
             blossom_directory,
             enable_negentropy,
+            lag_warn_ms,
+            lag_warn_seconds,
+            keep_rejected_events,
+            rejected_events_max_count,
+            rejected_events_max_bytes,
+            rejected_events_max_seconds,
+            handover_grace_seconds,
+            indexed_tag_names,
+            event_sink_url,
+            event_sink_include_ephemeral,
+            event_sink_batch_size,
+            event_sink_max_outbox,
+            event_sink_overflow_drop_oldest,
+            enable_since_seen,
+            blossom_mirror_max_bytes,
+            blossom_mirror_timeout_seconds,
+            enable_search,
+            search_max_scanned,
+            blossom_max_upload_bytes,
+            blossom_allowed_mime_types,
+            auth_required_for_read,
+            auth_required_for_write,
+            expiration_sweep_seconds,
+            shutdown_grace_seconds,
+            enable_metrics,
+            metrics_bearer_token,
+            metrics_allowed_ips,
+            max_events_per_minute,
+            max_reqs_per_minute,
+            max_blossom_uploads_per_minute,
+            rate_limit_ban_after,
+            rate_limit_ban_seconds,
+            rate_limit_exempt_pubkeys,
+            blossom_gc_interval_seconds,
+            blossom_gc_unaccessed_days,
+            blossom_max_total_bytes,
+            blossom_gc_grace_seconds,
+            negentropy_max_items,
+            protected_events_author_only,
+            trusted_proxies,
+            proxy_protocol,
+            lmdb_map_size,
+            lmdb_max_map_size,
+            retention_sweep_seconds,
+            retention,
+            max_inflight_bytes,
+            user_hex_keys,
+            users_from_relay_lists,
+            enable_http2,
+            tls_reload_seconds,
+            json_logs,
+            write_policy_plugin,
+            write_policy_timeout_ms,
+            write_policy_fail_open,
+            write_policy_allow_pubkeys,
+            write_policy_deny_pubkeys,
+            websocket_compression,
+            websocket_compression_level,
+            websocket_compression_min_bytes,
+            mode,
+            blossom_allowed_pubkeys,
+            blossom_relay_users,
+            stats_bearer_token,
+            verify_workers,
+            verify_queue,
+            extra_ephemeral_kinds,
+            sweep_ephemeral_on_startup,
+            forward_relays,
+            forward_kinds,
+            forward_authors,
+            relay_countries,
+            language_tags,
+            tags,
+            posting_policy,
+            payments_url,
+            fees,
+            max_filters,
+            created_at_upper_drift,
+            created_at_lower_limit,
+            created_at_lower_limit_exempt_kinds,
+            max_event_bytes,
+            max_event_tags,
+            default_limit,
+            max_limit,
+            ip_reputation_half_life_seconds,
+            ip_ban_threshold,
+            listeners,
+            private_dms,
+            blossom_shard_depth,
+            max_message_length,
+            max_filter_values,
+            enable_since_last,
+            since_last_expiry_days,
+            max_since_last_cursors,
+            blossom_media_max_dimension,
+            blossom_media_format,
+            blossom_media_quality,
+            blossom_media_keep_original,
+            blossom_media_max_transcodes,
+            blossom_media_max_pixels,
         } = self;
 
         let mut contact_public_key: Option<Pubkey> = None;
@@ -147,8 +456,40 @@ impl FriendlyConfig {
             admin_keys.push(Pubkey::read_hex(pkh.as_bytes())?);
         }
 
+        let user_keys = user_hex_keys
+            .iter()
+            .map(|pkh| Pubkey::read_hex(pkh.as_bytes()))
+            .collect::<Result<Vec<Pubkey>, _>>()?;
+
+        let rate_limit_exempt_pubkeys = rate_limit_exempt_pubkeys
+            .iter()
+            .map(|pkh| Pubkey::read_hex(pkh.as_bytes()))
+            .collect::<Result<Vec<Pubkey>, _>>()?;
+
+        let write_policy_allow_pubkeys = write_policy_allow_pubkeys
+            .iter()
+            .map(|pkh| Pubkey::read_hex(pkh.as_bytes()))
+            .collect::<Result<Vec<Pubkey>, _>>()?;
+
+        let write_policy_deny_pubkeys = write_policy_deny_pubkeys
+            .iter()
+            .map(|pkh| Pubkey::read_hex(pkh.as_bytes()))
+            .collect::<Result<Vec<Pubkey>, _>>()?;
+
+        let blossom_allowed_pubkeys = blossom_allowed_pubkeys
+            .iter()
+            .map(|pkh| Pubkey::read_hex(pkh.as_bytes()))
+            .collect::<Result<Vec<Pubkey>, _>>()?;
+
+        let forward_authors = forward_authors
+            .iter()
+            .map(|pkh| Pubkey::read_hex(pkh.as_bytes()))
+            .collect::<Result<Vec<Pubkey>, _>>()?;
+
         let hostname = Host::parse(&hostname)?;
 
+        let mode = Mode::from_str(&mode)?;
+
         let server_log_level =
             log::LevelFilter::from_str(&server_log_level).unwrap_or(log::LevelFilter::Info);
         let library_log_level =
@@ -156,6 +497,52 @@ impl FriendlyConfig {
         let client_log_level =
             log::LevelFilter::from_str(&client_log_level).unwrap_or(log::LevelFilter::Info);
 
+        let metrics_allowed_ips = metrics_allowed_ips
+            .iter()
+            .map(|ip| {
+                ip.parse::<IpAddr>().map(HashedIp::new).map_err(|e| {
+                    ChorusError::General(format!("metrics_allowed_ips {ip}: {e}")).into()
+                })
+            })
+            .collect::<Result<Vec<HashedIp>, Error>>()?;
+
+        let trusted_proxies = trusted_proxies
+            .iter()
+            .map(|cidr| cidr.parse::<Cidr>())
+            .collect::<Result<Vec<Cidr>, Error>>()?;
+
+        // Otherwise anybody could claim to be anybody
+        if proxy_protocol && trusted_proxies.is_empty() {
+            return Err(ChorusError::General(
+                "proxy_protocol needs trusted_proxies (the proxies that send it)".to_owned(),
+            )
+            .into());
+        }
+
+        if blossom_shard_depth > MAX_SHARD_DEPTH {
+            return Err(ChorusError::General(format!(
+                "blossom_shard_depth {blossom_shard_depth} is more than {MAX_SHARD_DEPTH}"
+            ))
+            .into());
+        }
+
+        let blossom_media_format = MediaFormat::from_str(&blossom_media_format)?;
+        if !(1..=100).contains(&blossom_media_quality) {
+            return Err(ChorusError::General(format!(
+                "blossom_media_quality {blossom_media_quality} is not from 1 to 100"
+            ))
+            .into());
+        }
+
+        let listeners = if listeners.is_empty() {
+            vec![ListenerSpec::tcp(&ip_address, port, use_tls)]
+        } else {
+            for listener in listeners.iter() {
+                listener.check()?;
+            }
+            listeners
+        };
+
         Ok(Config {
             data_directory,
             ip_address,
@@ -196,6 +583,107 @@ impl FriendlyConfig {
             throttling_burst,
             blossom_directory,
             enable_negentropy,
+            lag_warn_ms,
+            lag_warn_seconds,
+            keep_rejected_events,
+            rejected_events_max_count,
+            rejected_events_max_bytes,
+            rejected_events_max_seconds,
+            handover_grace_seconds,
+            indexed_tag_names,
+            event_sink_url,
+            event_sink_include_ephemeral,
+            event_sink_batch_size,
+            event_sink_max_outbox,
+            event_sink_overflow_drop_oldest,
+            enable_since_seen,
+            blossom_mirror_max_bytes,
+            blossom_mirror_timeout_seconds,
+            enable_search,
+            search_max_scanned,
+            blossom_max_upload_bytes,
+            blossom_allowed_mime_types,
+            auth_required_for_read,
+            auth_required_for_write,
+            expiration_sweep_seconds,
+            shutdown_grace_seconds,
+            enable_metrics,
+            metrics_bearer_token,
+            metrics_allowed_ips,
+            max_events_per_minute,
+            max_reqs_per_minute,
+            max_blossom_uploads_per_minute,
+            rate_limit_ban_after,
+            rate_limit_ban_seconds,
+            rate_limit_exempt_pubkeys,
+            blossom_gc_interval_seconds,
+            blossom_gc_unaccessed_days,
+            blossom_max_total_bytes,
+            blossom_gc_grace_seconds,
+            negentropy_max_items,
+            protected_events_author_only,
+            trusted_proxies,
+            proxy_protocol,
+            lmdb_map_size,
+            lmdb_max_map_size,
+            retention_sweep_seconds,
+            retention,
+            max_inflight_bytes,
+            user_keys,
+            user_hex_keys,
+            users_from_relay_lists,
+            enable_http2,
+            tls_reload_seconds,
+            json_logs,
+            write_policy_plugin,
+            write_policy_timeout_ms,
+            write_policy_fail_open,
+            write_policy_allow_pubkeys,
+            write_policy_deny_pubkeys,
+            websocket_compression,
+            websocket_compression_level,
+            websocket_compression_min_bytes,
+            mode,
+            blossom_allowed_pubkeys,
+            blossom_relay_users,
+            stats_bearer_token,
+            verify_workers,
+            verify_queue,
+            extra_ephemeral_kinds,
+            sweep_ephemeral_on_startup,
+            forward_relays,
+            forward_kinds,
+            forward_authors,
+            relay_countries,
+            language_tags,
+            tags,
+            posting_policy,
+            payments_url,
+            fees,
+            max_filters,
+            created_at_upper_drift,
+            created_at_lower_limit,
+            created_at_lower_limit_exempt_kinds,
+            max_event_bytes,
+            max_event_tags,
+            default_limit,
+            max_limit,
+            ip_reputation_half_life_seconds,
+            ip_ban_threshold,
+            listeners,
+            private_dms,
+            blossom_shard_depth,
+            max_message_length,
+            max_filter_values,
+            enable_since_last,
+            since_last_expiry_days,
+            max_since_last_cursors,
+            blossom_media_max_dimension,
+            blossom_media_format,
+            blossom_media_quality,
+            blossom_media_keep_original,
+            blossom_media_max_transcodes,
+            blossom_media_max_pixels,
         })
     }
 }
@@ -241,6 +729,107 @@ pub struct Config {
     pub throttling_burst: usize,
     pub blossom_directory: Option<String>,
     pub enable_negentropy: bool,
+    pub lag_warn_ms: u64,
+    pub lag_warn_seconds: u64,
+    pub keep_rejected_events: bool,
+    pub rejected_events_max_count: usize,
+    pub rejected_events_max_bytes: usize,
+    pub rejected_events_max_seconds: u64,
+    pub handover_grace_seconds: u64,
+    pub indexed_tag_names: Vec<String>,
+    pub event_sink_url: Option<String>,
+    pub event_sink_include_ephemeral: bool,
+    pub event_sink_batch_size: usize,
+    pub event_sink_max_outbox: u64,
+    pub event_sink_overflow_drop_oldest: bool,
+    pub enable_since_seen: bool,
+    pub blossom_mirror_max_bytes: u64,
+    pub blossom_mirror_timeout_seconds: u64,
+    pub enable_search: bool,
+    pub search_max_scanned: usize,
+    pub blossom_max_upload_bytes: u64,
+    pub blossom_allowed_mime_types: Vec<String>,
+    pub auth_required_for_read: bool,
+    pub auth_required_for_write: bool,
+    pub expiration_sweep_seconds: u64,
+    pub shutdown_grace_seconds: u64,
+    pub enable_metrics: bool,
+    pub metrics_bearer_token: Option<String>,
+    pub metrics_allowed_ips: Vec<HashedIp>,
+    pub max_events_per_minute: u32,
+    pub max_reqs_per_minute: u32,
+    pub max_blossom_uploads_per_minute: u32,
+    pub rate_limit_ban_after: u32,
+    pub rate_limit_ban_seconds: u64,
+    pub rate_limit_exempt_pubkeys: Vec<Pubkey>,
+    pub blossom_gc_interval_seconds: u64,
+    pub blossom_gc_unaccessed_days: u64,
+    pub blossom_max_total_bytes: u64,
+    pub blossom_gc_grace_seconds: u64,
+    pub negentropy_max_items: usize,
+    pub protected_events_author_only: bool,
+    pub trusted_proxies: Vec<Cidr>,
+    pub proxy_protocol: bool,
+    pub lmdb_map_size: u64,
+    pub lmdb_max_map_size: u64,
+    pub retention_sweep_seconds: u64,
+    pub retention: Vec<RetentionRule>,
+    pub max_inflight_bytes: usize,
+    pub user_keys: Vec<Pubkey>,
+    pub user_hex_keys: Vec<String>,
+    pub users_from_relay_lists: bool,
+    pub enable_http2: bool,
+    pub tls_reload_seconds: u64,
+    pub json_logs: bool,
+    pub write_policy_plugin: Option<String>,
+    pub write_policy_timeout_ms: u64,
+    pub write_policy_fail_open: bool,
+    pub write_policy_allow_pubkeys: Vec<Pubkey>,
+    pub write_policy_deny_pubkeys: Vec<Pubkey>,
+    pub websocket_compression: bool,
+    pub websocket_compression_level: u8,
+    pub websocket_compression_min_bytes: usize,
+    pub mode: Mode,
+    pub blossom_allowed_pubkeys: Vec<Pubkey>,
+    pub blossom_relay_users: bool,
+    pub stats_bearer_token: Option<String>,
+    pub verify_workers: usize,
+    pub verify_queue: usize,
+    pub extra_ephemeral_kinds: Vec<u16>,
+    pub sweep_ephemeral_on_startup: bool,
+    pub forward_relays: Vec<String>,
+    pub forward_kinds: Vec<u16>,
+    pub forward_authors: Vec<Pubkey>,
+    pub relay_countries: Vec<String>,
+    pub language_tags: Vec<String>,
+    pub tags: Vec<String>,
+    pub posting_policy: Option<String>,
+    pub payments_url: Option<String>,
+    pub fees: Option<Fees>,
+    pub max_filters: usize,
+    pub created_at_upper_drift: u64,
+    pub created_at_lower_limit: i64,
+    pub created_at_lower_limit_exempt_kinds: Vec<u16>,
+    pub max_event_bytes: usize,
+    pub max_event_tags: usize,
+    pub default_limit: u32,
+    pub max_limit: u32,
+    pub ip_reputation_half_life_seconds: u64,
+    pub ip_ban_threshold: u32,
+    pub listeners: Vec<ListenerSpec>,
+    pub private_dms: bool,
+    pub blossom_shard_depth: usize,
+    pub max_message_length: usize,
+    pub max_filter_values: usize,
+    pub enable_since_last: bool,
+    pub since_last_expiry_days: u64,
+    pub max_since_last_cursors: usize,
+    pub blossom_media_max_dimension: u32,
+    pub blossom_media_format: MediaFormat,
+    pub blossom_media_quality: u8,
+    pub blossom_media_keep_original: bool,
+    pub blossom_media_max_transcodes: usize,
+    pub blossom_media_max_pixels: u64,
 }
 
 impl Default for Config {
@@ -278,4 +867,54 @@ impl Config {
 
         Ok(uri_parts)
     }
+
+    /// Settings that only take effect at startup (listening sockets, storage, index
+    /// building, logging). When reloading, these are put back to their `running` values
+    /// and the names of any that differed are returned, so they can be logged as needing
+    /// a restart.
+    pub fn keep_startup_settings(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut changed: Vec<&'static str> = Vec::new();
+
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != running.$field {
+                        self.$field = running.$field.clone();
+                        changed.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+
+        keep!(
+            data_directory,
+            ip_address,
+            port,
+            use_tls,
+            server_log_level,
+            library_log_level,
+            client_log_level,
+            blossom_directory,
+            indexed_tag_names,
+            event_sink_url,
+            enable_search,
+            json_logs,
+            write_policy_plugin,
+            verify_workers,
+            sweep_ephemeral_on_startup,
+            forward_relays,
+            listeners,
+            blossom_shard_depth,
+            blossom_media_max_transcodes,
+            lmdb_map_size,
+            enable_http2
+        );
+
+        changed
+    }
+
+    /// Whether any listener uses TLS, so that the certificate and key are needed
+    pub fn tls_in_use(&self) -> bool {
+        self.listeners.iter().any(|listener| listener.tls)
+    }
 }
@@ -0,0 +1,144 @@
+//! Per-connection protocol statistics, logged as one summary when the connection closes
+
+use pocket_types::Pubkey;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
+
+#[derive(Debug)]
+pub struct ConnStats {
+    pub started: Instant,
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub events_accepted: u64,
+
+    /// Events we already had (answered OK, but not accepted again)
+    pub events_duplicate: u64,
+
+    pub events_rejected: u64,
+    pub subscriptions_opened: u64,
+    pub max_subscriptions: usize,
+
+    /// Rejections by reason (the machine-readable prefix of the OK message)
+    pub rejection_reasons: HashMap<String, u64>,
+
+    /// Close code and reason, and whether we closed it
+    pub close: Option<(u16, String, bool)>,
+}
+
+impl Default for ConnStats {
+    fn default() -> ConnStats {
+        ConnStats {
+            started: Instant::now(),
+            frames_in: 0,
+            frames_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            events_accepted: 0,
+            events_duplicate: 0,
+            events_rejected: 0,
+            subscriptions_opened: 0,
+            max_subscriptions: 0,
+            rejection_reasons: HashMap::new(),
+            close: None,
+        }
+    }
+}
+
+impl ConnStats {
+    pub fn frame_in(&mut self, len: usize) {
+        self.frames_in += 1;
+        self.bytes_in += len as u64;
+    }
+
+    pub fn frame_out(&mut self, len: usize) {
+        self.frames_out += 1;
+        self.bytes_out += len as u64;
+    }
+
+    pub fn event_accepted(&mut self) {
+        self.events_accepted += 1;
+    }
+
+    pub fn event_duplicate(&mut self) {
+        self.events_duplicate += 1;
+    }
+
+    pub fn event_rejected(&mut self, reason: &str) {
+        self.events_rejected += 1;
+        *self.rejection_reasons.entry(reason.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn subscription_opened(&mut self, now_open: usize) {
+        self.subscriptions_opened += 1;
+        self.max_subscriptions = self.max_subscriptions.max(now_open);
+    }
+
+    pub fn closed(&mut self, code: u16, reason: &str, by_us: bool) {
+        // Keep the first one: if we closed, their close is just the acknowledgement
+        if self.close.is_none() {
+            self.close = Some((code, reason.to_owned(), by_us));
+        }
+    }
+
+    /// The most common rejection reason and its count
+    pub fn top_rejection(&self) -> Option<(&str, u64)> {
+        self.rejection_reasons
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(reason, count)| (reason.as_str(), *count))
+    }
+
+    /// A summary for the close log
+    pub fn summary<'a>(&'a self, user: Option<&'a Pubkey>) -> Summary<'a> {
+        Summary { stats: self, user }
+    }
+}
+
+pub struct Summary<'a> {
+    stats: &'a ConnStats,
+    user: Option<&'a Pubkey>,
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = self.stats;
+        write!(
+            f,
+            "duration={}s frames_in={} frames_out={} bytes_in={} bytes_out={} \
+             events_accepted={} events_duplicate={} events_rejected={}",
+            s.started.elapsed().as_secs(),
+            s.frames_in,
+            s.frames_out,
+            s.bytes_in,
+            s.bytes_out,
+            s.events_accepted,
+            s.events_duplicate,
+            s.events_rejected,
+        )?;
+        if let Some((reason, count)) = s.top_rejection() {
+            write!(f, " top_rejection=\"{reason}\"x{count}")?;
+        }
+        write!(
+            f,
+            " subs_opened={} max_subs={}",
+            s.subscriptions_opened, s.max_subscriptions
+        )?;
+        match self.user {
+            Some(pubkey) => write!(f, " auth={}", pubkey.as_hex_string())?,
+            None => write!(f, " auth=none")?,
+        }
+        match &s.close {
+            Some((code, reason, by_us)) => write!(
+                f,
+                " close_code={} close_reason=\"{}\" closed_by={}",
+                code,
+                reason,
+                if *by_us { "relay" } else { "client" }
+            ),
+            None => write!(f, " close_code=none"),
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! NIP-45 COUNT
+//!
+//! Filters on nothing but kinds, authors, since and until are counted from our own index
+//! of events by kind and by author. Beside each key it keeps what screening the event
+//! needs (its author, expiration and whether it is protected or tags anybody), so such a
+//! count reads index entries only. An event is only loaded when nothing but its tags can
+//! decide whether the user may see it (DMs, GiftWraps and their inbox).
+//!
+//! Other filters walk the store's indexes without collecting the matching events. The
+//! screen handed down counts each visible match (adding its author to the HyperLogLog,
+//! if the filter qualifies) and then reports it as a mismatch, so nothing accumulates and
+//! the filter's limit never cuts the walk short.
+
+use crate::error::{ChorusError, Error};
+use crate::filter::ChorusFilter;
+use crate::globals::GLOBALS;
+use crate::nostr::{event_flags, is_protected, screen_outgoing_event};
+use pocket_db::heed::types::Bytes;
+use pocket_db::heed::{Database, RwTxn};
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Hll8, Id, Kind, Pubkey, Time};
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+// Count entry flags
+const PROTECTED: u8 = 1;
+const TAGS_PUBKEYS: u8 = 2;
+
+// A count entry: the author, the expiration (0 if none) and the flags
+const ENTRY_LEN: usize = 32 + 8 + 1;
+
+// Every key ends in kind (u16 BE) ++ created_at (u64 BE) ++ id
+const KEY_TAIL_LEN: usize = 2 + 8 + 32;
+
+fn table(store: &Store, name: &'static str) -> Result<Database<Bytes, Bytes>, Error> {
+    store
+        .extra_table(name)
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(name)))
+}
+
+fn entry_of(event: &Event) -> Vec<u8> {
+    let mut flags = 0;
+    if is_protected(event) {
+        flags |= PROTECTED;
+    }
+    if let Ok(tags) = event.tags() {
+        if tags.iter().any(|mut tag| tag.next() == Some(b"p")) {
+            flags |= TAGS_PUBKEYS;
+        }
+    }
+    let expiration = crate::expiration::expiration_of(event).unwrap_or(0);
+
+    let mut entry = event.pubkey().as_slice().to_vec();
+    entry.extend_from_slice(&expiration.to_be_bytes());
+    entry.push(flags);
+    entry
+}
+
+fn key_tail(kind: u16, created_at: u64, id: &[u8]) -> Vec<u8> {
+    let mut tail = kind.to_be_bytes().to_vec();
+    tail.extend_from_slice(&created_at.to_be_bytes());
+    tail.extend_from_slice(id);
+    tail
+}
+
+fn record_into(store: &Store, txn: &mut RwTxn, event: &Event) -> Result<(), Error> {
+    let kind = event.kind().as_u16();
+    let created_at = event.created_at().as_u64();
+    let tail = key_tail(kind, created_at, event.id().as_slice());
+    let entry = entry_of(event);
+
+    table(store, "count_kinds")?.put(txn, &tail, &entry)?;
+    let mut key = event.pubkey().as_slice().to_vec();
+    key.extend_from_slice(&tail);
+    table(store, "count_authors")?.put(txn, &key, &entry)?;
+
+    let mut place = tail[..2 + 8].to_vec();
+    place.extend_from_slice(event.pubkey().as_slice());
+    table(store, "count_ids")?.put(txn, event.id().as_slice(), &place)?;
+    Ok(())
+}
+
+fn forget_in(store: &Store, txn: &mut RwTxn, id: Id) -> Result<(), Error> {
+    let by_id = table(store, "count_ids")?;
+    let place: Option<Vec<u8>> = by_id.get(txn, id.as_slice())?.map(|v| v.to_vec());
+    let Some(place) = place else {
+        return Ok(());
+    };
+    if place.len() == 2 + 8 + 32 {
+        let mut tail = place[..2 + 8].to_vec();
+        tail.extend_from_slice(id.as_slice());
+        let _ = table(store, "count_kinds")?.delete(txn, &tail)?;
+        let mut key = place[2 + 8..].to_vec();
+        key.extend_from_slice(&tail);
+        let _ = table(store, "count_authors")?.delete(txn, &key)?;
+    }
+    let _ = by_id.delete(txn, id.as_slice())?;
+    Ok(())
+}
+
+/// Index a newly stored event. A deletion request also forgets the events pocket removed
+/// for it.
+pub fn record(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    record_into(store, &mut txn, event)?;
+    if event.kind().as_u16() == 5 {
+        for mut tag in event.tags()?.iter() {
+            if tag.next() != Some(b"e") {
+                continue;
+            }
+            let Some(id) = tag.next().and_then(|value| Id::read_hex(value).ok()) else {
+                continue;
+            };
+            if store.get_event_by_id(id)?.is_none() {
+                forget_in(store, &mut txn, id)?;
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Remove the index entries of a removed event
+pub fn forget(id: Id) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    forget_in(store, &mut txn, id)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Build the index (once) for events stored before it existed
+pub fn migrate(store: &Store) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let meta = table(store, "count_meta")?;
+
+    {
+        let txn = store.read_txn()?;
+        if meta.get(&txn, b"built")?.is_some() {
+            return Ok(());
+        }
+    }
+
+    let _ = crate::backfill::backfill(
+        store,
+        "count index",
+        |_| ScreenResult::Match,
+        |txn, event| record_into(store, txn, event),
+    )?;
+    let mut txn = store.write_txn()?;
+    meta.put(&mut txn, b"built", b"")?;
+    txn.commit()?;
+    Ok(())
+}
+
+fn is_built(store: &Store) -> Result<bool, Error> {
+    let meta = table(store, "count_meta")?;
+    let txn = store.read_txn()?;
+    Ok(meta.get(&txn, b"built")?.is_some())
+}
+
+// A filter the index can count: only kinds, authors, since and until
+struct Countable {
+    authors: Vec<Pubkey>,
+    kinds: Vec<u16>,
+    since: u64,
+    until: u64,
+}
+
+fn countable(filter: &ChorusFilter) -> Option<Countable> {
+    if !filter.is_plain() {
+        return None;
+    }
+    if !filter.json.keys().all(|k| {
+        matches!(
+            k.as_str(),
+            "authors" | "kinds" | "since" | "until" | "limit"
+        )
+    }) {
+        return None;
+    }
+
+    // Empty lists (and anything odd) are left to the store
+    let list = |name: &str| -> Option<Vec<Value>> {
+        match filter.json.get(name) {
+            None => Some(vec![]),
+            Some(Value::Array(values)) if !values.is_empty() => Some(values.clone()),
+            Some(_) => None,
+        }
+    };
+    let mut authors: Vec<Pubkey> = list("authors")?
+        .iter()
+        .map(|a| Pubkey::read_hex(a.as_str()?.as_bytes()).ok())
+        .collect::<Option<_>>()?;
+    authors.sort_by(|a, b| a.as_slice().cmp(b.as_slice()));
+    authors.dedup_by(|a, b| a.as_slice() == b.as_slice());
+    let mut kinds: Vec<u16> = list("kinds")?
+        .iter()
+        .map(|k| u16::try_from(k.as_u64()?).ok())
+        .collect::<Option<_>>()?;
+    kinds.sort();
+    kinds.dedup();
+
+    // Whether a scrape is allowed is the store's to say, unless it always is
+    if authors.is_empty() && (kinds.is_empty() || !GLOBALS.config.read().allow_scraping) {
+        return None;
+    }
+
+    let time = |name: &str| -> Option<Option<u64>> {
+        match filter.json.get(name) {
+            None => Some(None),
+            Some(value) => value.as_u64().map(Some),
+        }
+    };
+    Some(Countable {
+        authors,
+        kinds,
+        since: time("since")?.unwrap_or(0),
+        until: time("until")?.unwrap_or(u64::MAX),
+    })
+}
+
+// Screens count entries as `screen_outgoing_event` screens events (keep the two in step)
+struct EntryScreen {
+    user: Option<Pubkey>,
+    authorized_user: bool,
+    now: u64,
+    private_dms: bool,
+    protected_events_author_only: bool,
+    open_relay: bool,
+    serve_relay_lists: bool,
+    serve_ephemeral: bool,
+
+    // Each author's approval, and whether they are one of our users
+    authors: HashMap<[u8; 32], (Option<bool>, bool)>,
+}
+
+impl EntryScreen {
+    fn new(user: Option<Pubkey>, authorized_user: bool) -> EntryScreen {
+        let config = GLOBALS.config.read();
+        EntryScreen {
+            user,
+            authorized_user,
+            now: Time::now().as_u64(),
+            private_dms: config.private_dms,
+            protected_events_author_only: config.protected_events_author_only,
+            open_relay: config.open_relay,
+            serve_relay_lists: config.serve_relay_lists,
+            serve_ephemeral: config.serve_ephemeral,
+            authors: HashMap::new(),
+        }
+    }
+
+    // Whether the user may see the event. None when only the event itself can tell.
+    fn visible(&mut self, kind: u16, id: Id, entry: &[u8]) -> Option<bool> {
+        if entry.len() != ENTRY_LEN || kind == 1059 || (kind == 4 && self.private_dms) {
+            return None;
+        }
+        let author: [u8; 32] = entry[..32].try_into().unwrap();
+        let expiration = u64::from_be_bytes(entry[32..40].try_into().unwrap());
+        let flags = entry[40];
+
+        let author_is_current_user = self
+            .user
+            .is_some_and(|user| user.as_slice() == author.as_slice());
+        if !author_is_current_user && self.protected_events_author_only && flags & PROTECTED != 0 {
+            return Some(false);
+        }
+
+        // (the sweeper removes it)
+        if expiration != 0 && expiration < self.now {
+            return Some(false);
+        }
+
+        let (pubkey_approval, relay_user) = *self.authors.entry(author).or_insert_with(|| {
+            let pubkey = Pubkey::from_bytes(author);
+            (
+                crate::get_pubkey_approval(pubkey).ok().flatten(),
+                crate::is_relay_user(pubkey),
+            )
+        });
+        let event_approval = crate::get_event_approval(id).ok().flatten();
+        if event_approval == Some(false) || pubkey_approval == Some(false) {
+            return Some(false);
+        }
+        if event_approval == Some(true) || pubkey_approval == Some(true) {
+            return Some(true);
+        }
+
+        if self.open_relay
+            || (self.serve_relay_lists && (kind == 10002 || kind == 10050))
+            || (self.serve_ephemeral && Kind::from(kind).is_ephemeral())
+            || self.authorized_user
+            || relay_user
+        {
+            return Some(true);
+        }
+
+        // Whether it tags whoever is asking (their inbox)
+        if self.user.is_some() && flags & TAGS_PUBKEYS != 0 {
+            return None;
+        }
+
+        Some(false)
+    }
+}
+
+// Count from the index, handing each visible match to `tally`
+fn count_by_index<T>(
+    countable: &Countable,
+    entry_screen: &mut EntryScreen,
+    mut tally: T,
+) -> Result<(), Error>
+where
+    T: FnMut(Id, Pubkey),
+{
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let txn = store.read_txn()?;
+
+    // The ranges to walk, by prefix and the tail to start from and end at
+    let mut ranges: Vec<(&'static str, Vec<u8>, Vec<u8>, Vec<u8>)> = Vec::new();
+    let (since, until) = (countable.since, countable.until);
+    if countable.authors.is_empty() {
+        for kind in countable.kinds.iter() {
+            ranges.push((
+                "count_kinds",
+                vec![],
+                key_tail(*kind, since, &[0; 32]),
+                key_tail(*kind, until, &[0xff; 32]),
+            ));
+        }
+    } else {
+        for author in countable.authors.iter() {
+            let prefix = author.as_slice().to_vec();
+            if countable.kinds.is_empty() {
+                ranges.push((
+                    "count_authors",
+                    prefix,
+                    key_tail(0, 0, &[0; 32]),
+                    key_tail(u16::MAX, u64::MAX, &[0xff; 32]),
+                ));
+            } else {
+                for kind in countable.kinds.iter() {
+                    ranges.push((
+                        "count_authors",
+                        prefix.clone(),
+                        key_tail(*kind, since, &[0; 32]),
+                        key_tail(*kind, until, &[0xff; 32]),
+                    ));
+                }
+            }
+        }
+    }
+
+    for (name, prefix, start, end) in ranges {
+        let index = table(store, name)?;
+        let start = [prefix.as_slice(), start.as_slice()].concat();
+        let end = [prefix.as_slice(), end.as_slice()].concat();
+        let range = (
+            Bound::Included(start.as_slice()),
+            Bound::Included(end.as_slice()),
+        );
+        for i in index.range(&txn, &range)? {
+            let (key, entry) = i?;
+            if key.len() != prefix.len() + KEY_TAIL_LEN {
+                continue;
+            }
+            let tail = &key[prefix.len()..];
+            let kind = u16::from_be_bytes(tail[..2].try_into().unwrap());
+            let created_at = u64::from_be_bytes(tail[2..10].try_into().unwrap());
+            let id = Id::from_bytes(tail[10..].try_into().unwrap());
+            if created_at < since || created_at > until {
+                continue;
+            }
+
+            let visible = match entry_screen.visible(kind, id, entry) {
+                Some(visible) => visible,
+                None => match store.get_event_by_id(id)? {
+                    Some(event) => {
+                        let event_flags = event_flags(event, &entry_screen.user);
+                        screen_outgoing_event(event, &event_flags, entry_screen.authorized_user)
+                            == ScreenResult::Match
+                    }
+                    None => false,
+                },
+            };
+            if visible {
+                tally(id, Pubkey::from_bytes(entry[..32].try_into().unwrap()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Count the distinct events matching any of the filters that `user` may see, along
+/// with the HyperLogLog registers when there is a single filter that qualifies for one
+/// (per NIP-45).
+pub fn count(
+    filters: &[ChorusFilter],
+    user: Option<Pubkey>,
+) -> Result<(usize, Option<Hll8>), Error> {
+    let authorized_user = user.map(crate::is_authorized_user).unwrap_or(false);
+
+    let hll_offset = match filters {
+        [filter] => filter.filter.hyperloglog_offset().ok().flatten(),
+        _ => None,
+    };
+    let hll: RefCell<Option<Hll8>> = RefCell::new(hll_offset.map(|_| Hll8::new()));
+
+    // An event can match more than one filter, but should only be counted once
+    let dedup = filters.len() > 1;
+    let seen: RefCell<BTreeSet<Id>> = RefCell::new(BTreeSet::new());
+
+    let count: Cell<usize> = Cell::new(0);
+
+    let tally = |id: Id, author: Pubkey| {
+        if dedup && !seen.borrow_mut().insert(id) {
+            return;
+        }
+        count.set(count.get() + 1);
+        if let (Some(offset), Some(hll)) = (hll_offset, hll.borrow_mut().as_mut()) {
+            // This only fails for a bad offset, and hyperloglog_offset() gave it to us
+            let _ = hll.add_element(author.as_bytes(), offset);
+        }
+    };
+
+    let built = {
+        let _reading = crate::map_size::reading();
+        is_built(GLOBALS.store.get().unwrap())?
+    };
+    let mut entry_screen = EntryScreen::new(user, authorized_user);
+
+    for filter in filters.iter() {
+        if let Some(countable) = countable(filter).filter(|_| built) {
+            count_by_index(&countable, &mut entry_screen, tally)?;
+            continue;
+        }
+
+        let screen = |event: &Event| -> ScreenResult {
+            let event_flags = event_flags(event, &user);
+            if screen_outgoing_event(event, &event_flags, authorized_user) != ScreenResult::Match {
+                return ScreenResult::Mismatch;
+            }
+            // Unindexed multi-letter tag conditions are not applied by the store
+            if !matches!(filter.long_tags_match(event), Ok(true)) {
+                return ScreenResult::Mismatch;
+            }
+            tally(event.id(), event.pubkey());
+            ScreenResult::Mismatch
+        };
+        let _ = crate::nostr::find_events(filter, screen)?;
+    }
+
+    Ok((count.get(), hll.into_inner()))
+}
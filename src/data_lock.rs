@@ -0,0 +1,59 @@
+//! A lock on the data directory, so that offline maintenance (compacting, verifying and
+//! repairing) never runs on a store that chorus has open.
+//!
+//! Chorus holds it shared, as does a process it hands over to, so they can overlap. The
+//! maintenance tools need it exclusively, and give up at once rather than wait.
+
+use crate::config::Config;
+use crate::error::{ChorusError, Error};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+use std::sync::OnceLock;
+
+// Held for as long as the process runs
+static HELD: OnceLock<File> = OnceLock::new();
+
+fn open(config: &Config) -> Result<File, Error> {
+    std::fs::create_dir_all(&config.data_directory)?;
+    let path = Path::new(&config.data_directory).join("chorus.lock");
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?)
+}
+
+/// Hold the lock shared, as the relay does, failing if a maintenance tool has it
+pub fn share(config: &Config) -> Result<(), Error> {
+    let file = open(config)?;
+    match file.try_lock_shared() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(ChorusError::DataDirectoryLocked(
+                "a maintenance tool is using it".to_owned(),
+            )
+            .into())
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    let _ = HELD.set(file);
+    Ok(())
+}
+
+/// Hold the lock exclusively, as the maintenance tools do, failing if chorus (or another
+/// tool) has it
+pub fn exclusive(config: &Config) -> Result<(), Error> {
+    let file = open(config)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(ChorusError::DataDirectoryLocked(
+                "stop chorus (and any other tool using it) first".to_owned(),
+            )
+            .into())
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    let _ = HELD.set(file);
+    Ok(())
+}
@@ -0,0 +1,381 @@
+//! Websocket compression (permessage-deflate, RFC 7692)
+//!
+//! tungstenite does not implement any websocket extensions, so we do it around it. If
+//! `websocket_compression` is set and the client offers permessage-deflate, we agree to it
+//! without context takeover in either direction: every message is compressed on its own,
+//! so no compression state is kept between messages and memory per connection stays
+//! small and predictable.
+//!
+//! Outgoing text messages of at least `websocket_compression_min_bytes` are compressed and
+//! sent as raw frames with RSV1 set. Incoming compressed messages are inflated by
+//! `Inflating`, which sits between the connection and tungstenite and rewrites them as
+//! the uncompressed frames tungstenite understands, so tungstenite's message size limit
+//! (the NIP-11 `max_message_length`) applies to the inflated size.
+
+use hyper::body::Bytes;
+use hyper_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+use hyper_tungstenite::tungstenite::protocol::frame::Frame;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// What we answer in Sec-WebSocket-Extensions when we agree to compression
+pub const RESPONSE: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Compression settings for a connection
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    pub level: u8,
+    pub min_bytes: usize,
+}
+
+/// Whether any of the client's Sec-WebSocket-Extensions offers is permessage-deflate with
+/// parameters we can honor
+pub fn accept_offer(offers: &str) -> bool {
+    'offers: for offer in offers.split(',') {
+        let mut params = offer.split(';').map(|p| p.trim());
+        if params.next() != Some("permessage-deflate") {
+            continue;
+        }
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => {}
+                // (we inflate with a full window, so any is fine)
+                ("client_max_window_bits", _) => {}
+                // (we always deflate with a full window)
+                ("server_max_window_bits", Some("15")) => {}
+                _ => continue 'offers,
+            }
+        }
+        return true;
+    }
+    false
+}
+
+/// A text message as a compressed frame
+pub fn compressed_frame(text: &[u8], level: u8) -> Frame {
+    let mut payload = miniz_oxide::deflate::compress_to_vec(text, level.min(9));
+
+    // We finish with a final block rather than an empty stored block, so what remains of
+    // the empty stored block after removing its last 4 bytes is a single 0 (RFC 7692
+    // 7.2.1 and 7.2.3.3)
+    payload.push(0);
+
+    let mut frame = Frame::message(Bytes::from(payload), OpCode::Data(Data::Text), true);
+    frame.header_mut().rsv1 = true;
+    frame
+}
+
+// Inflate a compressed message payload, to no more than `max` bytes
+fn inflate(payload: &[u8], max: usize) -> io::Result<Vec<u8>> {
+    let mut input = Vec::with_capacity(payload.len() + 6);
+    input.extend_from_slice(payload);
+    // The 4 bytes the sender removed, and then an empty final block so that the stream
+    // is complete
+    input.extend_from_slice(&[0x00, 0x00, 0xff, 0xff, 0x03, 0x00]);
+    miniz_oxide::inflate::decompress_to_vec_with_limit(&input, max).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("permessage-deflate: {:?}", e.status),
+        )
+    })
+}
+
+// The parts of a frame from the client
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: [u8; 4],
+    header_len: usize,
+    payload_len: usize,
+}
+
+// Parse a frame header from the start of `buf`, if all of it is there
+fn parse_header(buf: &[u8]) -> Option<FrameHeader> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let (mut header_len, payload_len) = match buf[1] & 0x7f {
+        126 => (
+            4,
+            u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as usize,
+        ),
+        127 => (
+            10,
+            u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?) as usize,
+        ),
+        n => (2, n as usize),
+    };
+    let mut mask = [0; 4];
+    if buf[1] & 0x80 != 0 {
+        mask.copy_from_slice(buf.get(header_len..header_len + 4)?);
+        header_len += 4;
+    }
+    Some(FrameHeader {
+        fin: buf[0] & 0x80 != 0,
+        rsv1: buf[0] & 0x40 != 0,
+        opcode: buf[0] & 0x0f,
+        mask,
+        header_len,
+        payload_len,
+    })
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+// A compressed message being received
+struct Compressed {
+    opcode: u8,
+    mask: [u8; 4],
+    payload: Vec<u8>,
+}
+
+/// The client's side of a websocket connection, with compressed messages inflated
+pub struct Inflating<S> {
+    inner: S,
+    enabled: bool,
+    max_message_size: usize,
+
+    // Bytes from the client not yet looked at
+    raw: Vec<u8>,
+
+    // Bytes ready for tungstenite
+    ready: Vec<u8>,
+    ready_pos: usize,
+
+    message: Option<Compressed>,
+}
+
+impl<S> Inflating<S> {
+    /// Wrap a connection. Unless `enabled`, everything passes through untouched.
+    pub fn new(inner: S, enabled: bool, max_message_size: usize) -> Inflating<S> {
+        Inflating {
+            inner,
+            enabled,
+            max_message_size,
+            raw: Vec::new(),
+            ready: Vec::new(),
+            ready_pos: 0,
+            message: None,
+        }
+    }
+
+    // Look at the next whole frame in `raw`, if there is one. Returns whether there was.
+    fn process_frame(&mut self) -> io::Result<bool> {
+        let Some(header) = parse_header(&self.raw) else {
+            return Ok(false);
+        };
+        if header.payload_len > self.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "websocket frame too large",
+            ));
+        }
+        let frame_len = header.header_len + header.payload_len;
+        if self.raw.len() < frame_len {
+            return Ok(false);
+        }
+
+        let is_control = header.opcode >= 8;
+        let starts_compressed = header.rsv1 && (header.opcode == 1 || header.opcode == 2);
+        let continues_compressed = self.message.is_some() && header.opcode == 0;
+        if is_control || !(starts_compressed || continues_compressed) {
+            if self.message.is_some() && !is_control {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "websocket message interrupted",
+                ));
+            }
+            self.ready.extend(self.raw.drain(..frame_len));
+            return Ok(true);
+        }
+
+        let mut payload: Vec<u8> = self.raw[header.header_len..frame_len].to_vec();
+        let _ = self.raw.drain(..frame_len);
+        apply_mask(&mut payload, header.mask);
+        let message = self.message.get_or_insert_with(|| Compressed {
+            opcode: header.opcode,
+            mask: header.mask,
+            payload: Vec::new(),
+        });
+        message.payload.extend_from_slice(&payload);
+        if message.payload.len() > self.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "websocket message too large",
+            ));
+        }
+        if !header.fin {
+            return Ok(true);
+        }
+
+        // The whole message is here: hand it on as one uncompressed (masked) frame
+        let message = self.message.take().unwrap();
+        let mut data = inflate(&message.payload, self.max_message_size)?;
+        self.ready.push(0x80 | message.opcode);
+        match data.len() {
+            n if n < 126 => self.ready.push(0x80 | n as u8),
+            n if n <= u16::MAX as usize => {
+                self.ready.push(0x80 | 126);
+                self.ready.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                self.ready.push(0x80 | 127);
+                self.ready.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        self.ready.extend_from_slice(&message.mask);
+        apply_mask(&mut data, message.mask);
+        self.ready.extend_from_slice(&data);
+        Ok(true)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inflating<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        loop {
+            // Hand over what is ready
+            if this.ready_pos < this.ready.len() {
+                let n = buf.remaining().min(this.ready.len() - this.ready_pos);
+                buf.put_slice(&this.ready[this.ready_pos..this.ready_pos + n]);
+                this.ready_pos += n;
+                if this.ready_pos == this.ready.len() {
+                    this.ready.clear();
+                    this.ready_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.process_frame()? {
+                continue;
+            }
+
+            // We need more from the client
+            let mut chunk = [0_u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) if chunk_buf.filled().is_empty() => {
+                    // At the end, pass on whatever is left for tungstenite to complain about
+                    if this.raw.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                    this.ready.append(&mut this.raw);
+                }
+                Poll::Ready(Ok(())) => this.raw.extend_from_slice(chunk_buf.filled()),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inflating<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_accept_offer() {
+        assert!(accept_offer("permessage-deflate"));
+        assert!(accept_offer(
+            "permessage-deflate; client_max_window_bits, x-webkit-deflate-frame"
+        ));
+        assert!(accept_offer(
+            "permessage-deflate; server_max_window_bits=10, permessage-deflate"
+        ));
+        assert!(!accept_offer(
+            "permessage-deflate; server_max_window_bits=10"
+        ));
+        assert!(!accept_offer("permessage-deflate; mystery"));
+        assert!(!accept_offer("x-webkit-deflate-frame"));
+        assert!(!accept_offer(""));
+    }
+
+    #[tokio::test]
+    async fn test_inflating() {
+        let text = r#"["EVENT","sub",{"content":"hello hello hello hello hello"}]"#;
+        let mask = [1, 2, 3, 4];
+
+        // A compressed text message from a client, split over two (masked) frames, with a
+        // ping in between
+        let frame = compressed_frame(text.as_bytes(), 6);
+        let mut compressed = frame.payload().to_vec();
+        apply_mask(&mut compressed, mask);
+        let (first, second) = compressed.split_at(5);
+        let mut wire = vec![0x40 | 0x01, 0x80 | first.len() as u8];
+        wire.extend_from_slice(&mask);
+        wire.extend_from_slice(first);
+        wire.extend_from_slice(&[0x89, 0x80]);
+        wire.extend_from_slice(&mask);
+        wire.extend_from_slice(&[0x80, 0x80 | second.len() as u8]);
+        wire.extend_from_slice(&mask);
+        wire.extend_from_slice(second);
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(&wire).await.unwrap();
+        drop(client);
+        let mut inflating = Inflating::new(server, true, 1 << 20);
+        let mut out = Vec::new();
+        inflating.read_to_end(&mut out).await.unwrap();
+
+        // The ping, then the message as a single uncompressed frame
+        assert_eq!(&out[..6], &[0x89, 0x80, 1, 2, 3, 4]);
+        let out = &out[6..];
+        assert_eq!(out[0], 0x81);
+        assert_eq!((out[1] & 0x7f) as usize, text.len());
+        assert_eq!(&out[2..6], &mask);
+        let mut payload = out[6..].to_vec();
+        apply_mask(&mut payload, mask);
+        assert_eq!(payload, text.as_bytes());
+    }
+}
@@ -0,0 +1,288 @@
+//! NIP-09 deletion of replaceable and addressable events by `a` tag
+//!
+//! pocket handles the `e` tags of a deletion request itself. An `a` tag
+//! (`kind:pubkey:d-tag`) deletes every version of that address created at or before the
+//! deletion request. We remember the address (with the deletion's created_at) so that
+//! older versions submitted later are refused as well, which matters when the deletion
+//! request reaches us before the event it deletes.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use pocket_db::heed::RwTxn;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Filter, Id, Kind, Pubkey};
+
+const DELETION_KIND: u16 = 5;
+
+fn is_addressable(kind: u16) -> bool {
+    (30000..40000).contains(&kind)
+}
+
+fn is_replaceable(kind: u16) -> bool {
+    kind == 0 || kind == 3 || (10000..20000).contains(&kind)
+}
+
+/// A replaceable or addressable event's address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub kind: u16,
+    pub pubkey: Pubkey,
+    pub d_tag: Vec<u8>,
+}
+
+impl Address {
+    /// Parse the value of an `a` tag. Only addresses of replaceable (with an empty d-tag)
+    /// and addressable kinds are accepted.
+    pub fn parse(value: &[u8]) -> Option<Address> {
+        let mut parts = value.splitn(3, |b| *b == b':');
+        let kind: u16 = std::str::from_utf8(parts.next()?).ok()?.parse().ok()?;
+        let pubkey = Pubkey::read_hex(parts.next()?).ok()?;
+        let d_tag = parts.next()?.to_vec();
+        if is_addressable(kind) || (is_replaceable(kind) && d_tag.is_empty()) {
+            Some(Address {
+                kind,
+                pubkey,
+                d_tag,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The address of an event, if it is replaceable or addressable
+    pub fn of(event: &Event) -> Option<Address> {
+        let kind = event.kind().as_u16();
+        let d_tag = if is_addressable(kind) {
+            let mut d_tag = Vec::new();
+            for mut tag in event.tags().ok()?.iter() {
+                if tag.next() == Some(b"d") {
+                    d_tag = tag.next().unwrap_or(b"").to_vec();
+                    break;
+                }
+            }
+            d_tag
+        } else if is_replaceable(kind) {
+            Vec::new()
+        } else {
+            return None;
+        };
+        Some(Address {
+            kind,
+            pubkey: event.pubkey(),
+            d_tag,
+        })
+    }
+
+    /// The address as bytes (as kept in the deleted_addresses table)
+    pub(crate) fn key(&self) -> Vec<u8> {
+        let mut key = self.kind.to_be_bytes().to_vec();
+        key.extend_from_slice(self.pubkey.as_slice());
+        key.extend_from_slice(&self.d_tag);
+        key
+    }
+}
+
+/// The addresses a deletion request deletes. `a` tags naming somebody else's events are
+/// ignored, since only the author may delete them.
+pub fn addresses_deleted_by(event: &Event) -> Vec<Address> {
+    if event.kind().as_u16() != DELETION_KIND {
+        return vec![];
+    }
+    let Ok(tags) = event.tags() else {
+        return vec![];
+    };
+    let mut addresses = Vec::new();
+    for mut tag in tags.iter() {
+        if tag.next() != Some(b"a") {
+            continue;
+        }
+        if let Some(address) = tag.next().and_then(Address::parse) {
+            if address.pubkey == event.pubkey() {
+                addresses.push(address);
+            }
+        }
+    }
+    addresses
+}
+
+// Record that `address` was deleted as of `deleted_at`, keeping the latest
+fn record_into(
+    store: &Store,
+    txn: &mut RwTxn,
+    address: &Address,
+    deleted_at: u64,
+) -> Result<(), Error> {
+    let table = store
+        .extra_table("deleted_addresses")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "deleted_addresses",
+        )))?;
+    let key = address.key();
+    if let Some(existing) = table.get(txn, &key)? {
+        if existing.len() == 8 && u64::from_be_bytes(existing.try_into().unwrap()) >= deleted_at {
+            return Ok(());
+        }
+    }
+    table.put(txn, &key, &deleted_at.to_be_bytes())?;
+    Ok(())
+}
+
+/// When `address` was deleted (the latest deletion request's created_at), if ever
+pub fn deleted_at(address: &Address) -> Result<Option<u64>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let table = store
+        .extra_table("deleted_addresses")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "deleted_addresses",
+        )))?;
+    let txn = store.read_txn()?;
+    Ok(table
+        .get(&txn, &address.key())?
+        .and_then(|v| v.try_into().ok())
+        .map(u64::from_be_bytes))
+}
+
+/// Refuse an event whose address was deleted at or after it was created
+pub fn check(event: &Event) -> Result<(), Error> {
+    let Some(address) = Address::of(event) else {
+        return Ok(());
+    };
+    match deleted_at(&address)? {
+        Some(at) if event.created_at().as_u64() <= at => Err(ChorusError::DeletedAddress.into()),
+        _ => Ok(()),
+    }
+}
+
+// Remove the stored versions of `address` created at or before `deleted_at`. Returns how
+// many were removed.
+fn purge(store: &Store, address: &Address, deleted_at: u64) -> Result<usize, Error> {
+    let json = format!(
+        r#"{{"kinds":[{}],"authors":["{}"]}}"#,
+        address.kind,
+        address.pubkey.as_hex_string()
+    );
+    let mut buffer: [u8; 256] = [0; 256];
+    let (_incount, _outcount, filter) = Filter::from_json(json.as_bytes(), &mut buffer)?;
+    let screen = |e: &Event| -> ScreenResult {
+        if e.created_at().as_u64() <= deleted_at && Address::of(e).as_ref() == Some(address) {
+            ScreenResult::Match
+        } else {
+            ScreenResult::Mismatch
+        }
+    };
+    let ids: Vec<Id> = {
+        let _reading = crate::map_size::reading();
+        let (events, _redacted) = store.find_events(filter, true, 0, 0, screen)?;
+        events.iter().map(|e| e.id()).collect()
+    };
+    for id in ids.iter() {
+        crate::remove_event(*id)?;
+    }
+    Ok(ids.len())
+}
+
+/// Carry out the `a` tags of a newly stored deletion request
+pub fn record(event: &Event) -> Result<(), Error> {
+    let addresses = addresses_deleted_by(event);
+    if addresses.is_empty() {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let deleted_at = event.created_at().as_u64();
+
+    {
+        let _reading = crate::map_size::reading();
+        let mut txn = store.write_txn()?;
+        for address in addresses.iter() {
+            record_into(store, &mut txn, address, deleted_at)?;
+        }
+        txn.commit()?;
+    }
+
+    for address in addresses.iter() {
+        let removed = purge(store, address, deleted_at)?;
+        if removed > 0 {
+            log::debug!(
+                target: "Server",
+                "Deleted {removed} versions of {}:{}:{}",
+                address.kind,
+                address.pubkey.as_hex_string(),
+                String::from_utf8_lossy(&address.d_tag)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Carry out the `a` tags of deletion requests stored before we handled them (once). This
+/// is only recorded as done once every deleted version is purged, so if we are stopped
+/// partway it is all done again (which is harmless) on the next start.
+pub fn migrate(store: &Store) -> Result<(), Error> {
+    let meta = store
+        .extra_table("deleted_addresses_meta")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "deleted_addresses_meta",
+        )))?;
+
+    {
+        let _reading = crate::map_size::reading();
+        let txn = store.read_txn()?;
+        if meta.get(&txn, b"built")?.is_some() {
+            return Ok(());
+        }
+    }
+
+    let mut deleted: Vec<(Address, u64)> = Vec::new();
+    let _reading = crate::map_size::reading();
+    let screen = |e: &Event| -> ScreenResult {
+        if e.kind() == Kind::from(DELETION_KIND) {
+            ScreenResult::Match
+        } else {
+            ScreenResult::Mismatch
+        }
+    };
+    let _ = crate::backfill::backfill(store, "deleted addresses", screen, |txn, event| {
+        for address in addresses_deleted_by(event) {
+            record_into(store, txn, &address, event.created_at().as_u64())?;
+            deleted.push((address, event.created_at().as_u64()));
+        }
+        Ok(())
+    })?;
+    drop(_reading);
+
+    for (address, deleted_at) in deleted.iter() {
+        let _ = purge(store, address, *deleted_at)?;
+    }
+
+    let _reading = crate::map_size::reading();
+    let mut txn = store.write_txn()?;
+    meta.put(&mut txn, b"built", b"")?;
+    txn.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        let pk = "ee11a5dff40c19a555f41fe42b48f00e618c91225622ae37b6c2bb67b76c4e49";
+
+        let address = Address::parse(format!("30023:{pk}:my:article").as_bytes()).unwrap();
+        assert_eq!(address.kind, 30023);
+        assert_eq!(address.pubkey.as_hex_string(), pk);
+        assert_eq!(address.d_tag, b"my:article");
+
+        let address = Address::parse(format!("10002:{pk}:").as_bytes()).unwrap();
+        assert_eq!(address.kind, 10002);
+        assert!(address.d_tag.is_empty());
+
+        // Regular kinds have no address, replaceable ones have no d-tag
+        assert!(Address::parse(format!("1:{pk}:").as_bytes()).is_none());
+        assert!(Address::parse(format!("10002:{pk}:x").as_bytes()).is_none());
+        assert!(Address::parse(format!("30023:{pk}").as_bytes()).is_none());
+        assert!(Address::parse(b"30023:nothex:x").is_none());
+    }
+}
@@ -0,0 +1,85 @@
+//! Ephemeral events: relayed to open subscriptions, never stored
+//!
+//! Kinds 20000-29999 are ephemeral (NIP-01), as are any in `extra_ephemeral_kinds`. Once
+//! accepted, such an event is advertised to every connection with a copy of the event
+//! itself rather than its offset in the store, and is gone once they have seen it.
+//!
+//! Older versions of chorus stored them like any other event. `sweep()` removes those.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::lag::{NewEvent, Source};
+use crate::walk::Walk;
+use pocket_db::ScreenResult;
+use pocket_types::{Event, Kind};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Is this kind ephemeral (and so never stored)?
+pub fn is_ephemeral(kind: Kind) -> bool {
+    kind.is_ephemeral()
+        || GLOBALS
+            .config
+            .read()
+            .extra_ephemeral_kinds
+            .contains(&kind.as_u16())
+}
+
+/// Send an ephemeral event to the connections, to deliver to matching subscriptions
+pub fn advertise(event: &Event, ingested: Instant) -> Result<(), Error> {
+    // A copy of its own, since the event lives in the session buffer
+    let json = event.as_json()?;
+    let mut buffer = vec![0_u8; json.len() + 256];
+    let _ = Event::from_json(&json, &mut buffer)?;
+
+    // (Nobody may be listening)
+    let _ = GLOBALS.new_events.send(NewEvent {
+        source: Source::Ephemeral(Arc::new(buffer)),
+        ingested,
+    });
+    Ok(())
+}
+
+// Walk the next chunk of the store, removing ephemeral events. Returns how many were
+// removed, or None once the whole store was walked.
+fn sweep_chunk(walk: &mut Walk) -> Result<Option<usize>, Error> {
+    let screen = |e: &Event| -> ScreenResult {
+        if is_ephemeral(e.kind()) {
+            ScreenResult::Match
+        } else {
+            ScreenResult::Mismatch
+        }
+    };
+    let Some(events) = walk.next_chunk(screen)? else {
+        return Ok(None);
+    };
+    for event in events.iter() {
+        crate::remove_event(event.id())?;
+    }
+    Ok(Some(events.len()))
+}
+
+/// Remove ephemeral events from the store, walking it a chunk at a time on a blocking
+/// thread (see `crate::walk`). Returns how many were removed.
+pub async fn sweep() -> Result<usize, Error> {
+    let mut walk = Walk::all();
+    let mut removed: usize = 0;
+    loop {
+        // Leave the store alone while we hand it over, and in read-only and maintenance modes
+        if !crate::mode::background_writes() {
+            break;
+        }
+        let (returned, result) = tokio::task::spawn_blocking(move || {
+            let result = sweep_chunk(&mut walk);
+            (walk, result)
+        })
+        .await
+        .map_err(|e| ChorusError::General(format!("{e}")).into_err())?;
+        walk = returned;
+        match result? {
+            Some(n) => removed += n,
+            None => break,
+        }
+    }
+    Ok(removed)
+}
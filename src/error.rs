@@ -53,11 +53,26 @@ pub enum ChorusError {
     // Blossom Authorization failure
     BlossomAuthFailure(String),
 
+    // Blob does not hash to what was authorized
+    BlossomHashMismatch,
+
+    // Blob MIME type is not allowed
+    BlossomMimeType(String),
+
+    // Fetching a blob to mirror failed
+    BlossomMirror(String),
+
+    // URL of a blob to mirror is not acceptable
+    BlossomMirrorUrl(String),
+
+    // Blob is larger than allowed (the limit)
+    BlossomTooLarge(u64),
+
     // Channel Recv
     ChannelRecv(tokio::sync::broadcast::error::RecvError),
 
     // Channel Send
-    ChannelSend(tokio::sync::broadcast::error::SendError<u64>),
+    ChannelSend(tokio::sync::broadcast::error::SendError<crate::lag::NewEvent>),
 
     // Config
     Config(toml::de::Error),
@@ -65,12 +80,24 @@ pub enum ChorusError {
     // Crypto
     Crypto(secp256k1::Error),
 
+    // The data directory is locked by another process
+    DataDirectoryLocked(String),
+
+    // The event's address was deleted (NIP-09 `a` tag) at or after its created_at
+    DeletedAddress,
+
     // Closing on error(s)
     ErrorClose,
 
+    // Event id does not match the hash of its serialized commitment (computed, received)
+    EventIdMismatch(String, String),
+
     // Event is Invalid
     EventIsInvalid(String),
 
+    // Event sink
+    EventSink(String),
+
     // From hex
     FromHex(hex::FromHexError),
 
@@ -80,6 +107,9 @@ pub enum ChorusError {
     // General
     General(String),
 
+    // Handing over to a new process failed
+    Handover(String),
+
     // Http
     Http(hyper::http::Error),
 
@@ -89,6 +119,9 @@ pub enum ChorusError {
     // Infallible
     Infallible,
 
+    // Filter is not valid
+    InvalidFilter(String),
+
     // Invalid URI
     InvalidUri(hyper::http::uri::InvalidUri),
 
@@ -122,6 +155,9 @@ pub enum ChorusError {
     // Protected Event
     ProtectedEvent,
 
+    // Bad or missing PROXY protocol preamble
+    ProxyProtocol(String),
+
     // Pocket Db Error
     PocketDb(pocket_db::Error),
 
@@ -134,9 +170,36 @@ pub enum ChorusError {
     // Rate limit exceeded
     RateLimitExceeded,
 
+    // Too many of something (events, subscriptions, uploads) too quickly
+    RateLimited(&'static str),
+
     // X-Real-Ip header is missing
     RealIpHeaderMissing,
 
+    // A newer version of the replaceable event is stored
+    Superseded,
+
+    // We already have the event
+    Duplicate,
+
+    // Too many events are waiting to be verified
+    ServerBusy,
+
+    // The write policy rejected the event
+    WritePolicyRejected(String),
+
+    // The write policy plugin did not answer (and we fail closed)
+    WritePolicyUnavailable,
+
+    // Event is neither by nor for one of our users
+    NotAcceptedHere,
+
+    // Writes are refused in read-only and maintenance modes
+    ReadOnly,
+
+    // New connections are refused in maintenance mode
+    Maintenance,
+
     // Restricted
     Restricted,
 
@@ -152,8 +215,8 @@ pub enum ChorusError {
     // Shutting Down
     ShuttingDown,
 
-    // Signal - Not Blossom Request
-    SignalNotBlossom,
+    // The LMDB map is full and may not grow any further
+    StorageFull,
 
     // Speedy
     Speedy(speedy::Error),
@@ -164,6 +227,30 @@ pub enum ChorusError {
     // Too many subscriptions
     TooManySubscriptions,
 
+    // Too many filters in one REQ
+    TooManyFilters,
+
+    // Event created_at is before created_at_lower_limit
+    CreatedAtTooOld,
+
+    // Event created_at is after created_at_upper_drift
+    CreatedAtTooNew,
+
+    // Event is larger than max_event_bytes
+    EventTooLarge,
+
+    // Event has more than max_event_tags tags
+    TooManyTags,
+
+    // Message is longer than max_message_length
+    MessageTooLong(usize),
+
+    // Filter field has more than max_filter_values values
+    TooManyFilterValues(String, usize),
+
+    // Filter field values are not event ids or pubkeys
+    FilterValueNotHex(String),
+
     // Tungstenite
     Tungstenite(hyper_tungstenite::tungstenite::error::Error),
 
@@ -195,18 +282,33 @@ impl std::fmt::Display for ChorusError {
             ChorusError::Base64Decode(e) => write!(f, "{e}"),
             ChorusError::BlockedIp => write!(f, "IP is temporarily blocked"),
             ChorusError::BlossomAuthFailure(s) => write!(f, "Authorization failure: {s}"),
+            ChorusError::BlossomHashMismatch => {
+                write!(f, "File hash does not match authorized hash")
+            }
+            ChorusError::BlossomMimeType(s) => write!(f, "MIME type {s} is not allowed"),
+            ChorusError::BlossomMirror(s) => write!(f, "Mirror failed: {s}"),
+            ChorusError::BlossomMirrorUrl(s) => write!(f, "Mirror URL rejected: {s}"),
+            ChorusError::BlossomTooLarge(max) => write!(f, "File is larger than {max} bytes"),
             ChorusError::ChannelRecv(e) => write!(f, "{e}"),
             ChorusError::ChannelSend(e) => write!(f, "{e}"),
             ChorusError::Config(e) => write!(f, "{e}"),
             ChorusError::Crypto(e) => write!(f, "{e}"),
+            ChorusError::DataDirectoryLocked(s) => write!(f, "Data directory is locked: {s}"),
+            ChorusError::DeletedAddress => write!(f, "That address is deleted"),
             ChorusError::ErrorClose => write!(f, "Closing due to error(s)"),
+            ChorusError::EventIdMismatch(c, g) => {
+                write!(f, "Event id mismatch, computed {c} got {g}")
+            }
             ChorusError::EventIsInvalid(s) => write!(f, "Event is invalid: {s}"),
+            ChorusError::EventSink(s) => write!(f, "Event sink: {s}"),
             ChorusError::FromHex(e) => write!(f, "{e}"),
             ChorusError::FromUtf8(e) => write!(f, "{e}"),
             ChorusError::General(s) => write!(f, "{s}"),
+            ChorusError::Handover(s) => write!(f, "Handover failed: {s}"),
             ChorusError::Http(e) => write!(f, "{e}"),
             ChorusError::Hyper(e) => write!(f, "{e}"),
             ChorusError::Infallible => panic!("INFALLIBLE"),
+            ChorusError::InvalidFilter(s) => write!(f, "Filter is invalid: {s}"),
             ChorusError::InvalidUri(e) => write!(f, "{e}"),
             ChorusError::InvalidUriParts(e) => write!(f, "{e}"),
             ChorusError::Io(e) => write!(f, "{e}"),
@@ -223,17 +325,39 @@ impl std::fmt::Display for ChorusError {
             ChorusError::PocketDbHeed(e) => write!(f, "{e}"),
             ChorusError::PocketType(e) => write!(f, "{e}"),
             ChorusError::RateLimitExceeded => write!(f, "Rate limit exceeded"),
+            ChorusError::RateLimited(what) => write!(f, "Too many {what}, slow down"),
             ChorusError::ProtectedEvent => write!(f, "Protected event"),
+            ChorusError::ProxyProtocol(s) => write!(f, "PROXY protocol: {s}"),
             ChorusError::RealIpHeaderMissing => write!(f, "X-Real-Ip header is missing"),
+            ChorusError::WritePolicyRejected(s) => write!(f, "Rejected by write policy: {s}"),
+            ChorusError::WritePolicyUnavailable => write!(f, "Write policy unavailable"),
+            ChorusError::NotAcceptedHere => write!(f, "Not accepted here"),
+            ChorusError::ReadOnly => write!(f, "Relay is in read-only mode"),
+            ChorusError::Maintenance => write!(f, "Relay is down for maintenance"),
+            ChorusError::Superseded => write!(f, "A newer version is stored"),
+            ChorusError::Duplicate => write!(f, "Duplicate event"),
+            ChorusError::ServerBusy => write!(f, "Server busy"),
             ChorusError::Restricted => write!(f, "Restricted"),
             ChorusError::Rustls(e) => write!(f, "{e}"),
             ChorusError::Scraper => write!(f, "Filter is underspecified. Scrapers are not allowed"),
             ChorusError::SerdeJson(e) => write!(f, "{e}"),
             ChorusError::ShuttingDown => write!(f, "Shutting down"),
-            ChorusError::SignalNotBlossom => write!(f, "internal-signal-not-blossom"),
+            ChorusError::StorageFull => write!(f, "storage full"),
             ChorusError::Speedy(e) => write!(f, "{e}"),
             ChorusError::TimedOut => write!(f, "Timed out"),
             ChorusError::TooManySubscriptions => write!(f, "Too many subscriptions"),
+            ChorusError::TooManyFilters => write!(f, "Too many filters"),
+            ChorusError::CreatedAtTooOld => write!(f, "created_at too far in the past"),
+            ChorusError::CreatedAtTooNew => write!(f, "created_at too far in the future"),
+            ChorusError::EventTooLarge => write!(f, "event too large"),
+            ChorusError::TooManyTags => write!(f, "too many tags"),
+            ChorusError::MessageTooLong(max) => write!(f, "message longer than {max} bytes"),
+            ChorusError::TooManyFilterValues(field, max) => {
+                write!(f, "{field} has more than {max} values")
+            }
+            ChorusError::FilterValueNotHex(field) => {
+                write!(f, "{field} values must be 64 lowercase hex characters")
+            }
             ChorusError::Tungstenite(e) => write!(f, "{e}"),
             ChorusError::UrlParse(e) => write!(f, "{e}"),
             ChorusError::Utf8(e) => write!(f, "{e}"),
@@ -295,18 +419,29 @@ impl ChorusError {
             ChorusError::Base64Decode(_) => 0.0,
             ChorusError::BlockedIp => 0.0,
             ChorusError::BlossomAuthFailure(_) => 0.0,
+            ChorusError::BlossomHashMismatch => 0.0,
+            ChorusError::BlossomMimeType(_) => 0.0,
+            ChorusError::BlossomMirror(_) => 0.0,
+            ChorusError::BlossomMirrorUrl(_) => 0.0,
+            ChorusError::BlossomTooLarge(_) => 0.0,
             ChorusError::ChannelRecv(_) => 0.0,
             ChorusError::ChannelSend(_) => 0.0,
             ChorusError::Config(_) => 0.0,
             ChorusError::Crypto(_) => 0.1,
+            ChorusError::DataDirectoryLocked(_) => 0.0,
+            ChorusError::DeletedAddress => 0.0,
             ChorusError::ErrorClose => 1.0,
+            ChorusError::EventIdMismatch(_, _) => 0.2,
             ChorusError::EventIsInvalid(_) => 0.2,
+            ChorusError::EventSink(_) => 0.0,
             ChorusError::FromHex(_) => 0.2,
             ChorusError::FromUtf8(_) => 0.2,
             ChorusError::General(_) => 0.0,
+            ChorusError::Handover(_) => 0.0,
             ChorusError::Http(_) => 0.0,
             ChorusError::Hyper(_) => 0.0,
             ChorusError::Infallible => panic!("INFALLIBLE"),
+            ChorusError::InvalidFilter(_) => 0.2,
             ChorusError::InvalidUri(_) => 0.0,
             ChorusError::InvalidUriParts(_) => 0.0,
             ChorusError::Io(_) => 0.0,
@@ -321,17 +456,35 @@ impl ChorusError {
             ChorusError::PocketDbHeed(_) => 0.0,
             ChorusError::PocketType(_) => 0.25,
             ChorusError::RateLimitExceeded => 1.0,
+            ChorusError::RateLimited(_) => 0.0,
             ChorusError::ProtectedEvent => 0.35,
+            ChorusError::ProxyProtocol(_) => 0.0,
             ChorusError::RealIpHeaderMissing => 0.0,
+            ChorusError::WritePolicyRejected(_) => 0.1,
+            ChorusError::WritePolicyUnavailable => 0.0,
+            ChorusError::NotAcceptedHere => 0.1,
+            ChorusError::ReadOnly => 0.0,
+            ChorusError::Maintenance => 0.0,
+            ChorusError::Superseded => 0.0,
+            ChorusError::Duplicate => 0.0,
+            ChorusError::ServerBusy => 0.0,
             ChorusError::Restricted => 0.1,
             ChorusError::Rustls(_) => 0.0,
             ChorusError::Scraper => 0.4,
             ChorusError::SerdeJson(_) => 0.0,
             ChorusError::ShuttingDown => 0.0,
-            ChorusError::SignalNotBlossom => 0.0,
+            ChorusError::StorageFull => 0.0,
             ChorusError::Speedy(_) => 0.0,
             ChorusError::TimedOut => 0.1,
             ChorusError::TooManySubscriptions => 0.1,
+            ChorusError::TooManyFilters => 0.1,
+            ChorusError::CreatedAtTooOld => 0.1,
+            ChorusError::CreatedAtTooNew => 0.1,
+            ChorusError::EventTooLarge => 0.2,
+            ChorusError::TooManyTags => 0.2,
+            ChorusError::MessageTooLong(_) => 0.2,
+            ChorusError::TooManyFilterValues(_, _) => 0.1,
+            ChorusError::FilterValueNotHex(_) => 0.2,
             ChorusError::Tungstenite(_) => 0.0,
             ChorusError::UrlParse(_) => 0.1,
             ChorusError::Utf8(_) => 0.1,
@@ -368,9 +521,9 @@ impl From<tokio::sync::broadcast::error::RecvError> for Error {
     }
 }
 
-impl From<tokio::sync::broadcast::error::SendError<u64>> for Error {
+impl From<tokio::sync::broadcast::error::SendError<crate::lag::NewEvent>> for Error {
     #[track_caller]
-    fn from(err: tokio::sync::broadcast::error::SendError<u64>) -> Self {
+    fn from(err: tokio::sync::broadcast::error::SendError<crate::lag::NewEvent>) -> Self {
         Error {
             inner: ChorusError::ChannelSend(err),
             location: std::panic::Location::caller(),
@@ -441,9 +594,13 @@ impl From<hyper::http::uri::InvalidUriParts> for Error {
 impl From<std::io::Error> for Error {
     #[track_caller]
     fn from(err: std::io::Error) -> Self {
-        Error {
-            inner: ChorusError::Io(err),
-            location: std::panic::Location::caller(),
+        // Our own errors come back wrapped when they pass through I/O adapters
+        match err.downcast::<Error>() {
+            Ok(e) => e,
+            Err(err) => Error {
+                inner: ChorusError::Io(err),
+                location: std::panic::Location::caller(),
+            },
         }
     }
 }
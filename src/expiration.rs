@@ -0,0 +1,185 @@
+//! NIP-40 expiration: an index of events by their `expiration` tag, swept periodically
+//!
+//! Expired events are never served (see `nostr::screen_outgoing_event`), but without the
+//! sweeper they would only be removed if somebody happened to query them. The sweeper
+//! removes them through `crate::remove_event`, so all of our indexes stay consistent.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use pocket_db::heed::RwTxn;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Id, Time};
+use std::ops::Bound;
+use std::time::Duration;
+
+/// The `expiration` tag of an event, if it has a valid one
+pub fn expiration_of(event: &Event) -> Option<u64> {
+    for mut tag in event.tags().ok()?.iter() {
+        if tag.next() == Some(b"expiration") {
+            return std::str::from_utf8(tag.next()?).ok()?.parse::<u64>().ok();
+        }
+    }
+    None
+}
+
+fn key(expiration: u64, id: Id) -> Vec<u8> {
+    let mut key = expiration.to_be_bytes().to_vec();
+    key.extend_from_slice(id.as_slice());
+    key
+}
+
+fn record_into(store: &Store, txn: &mut RwTxn, event: &Event) -> Result<(), Error> {
+    let Some(expiration) = expiration_of(event) else {
+        return Ok(());
+    };
+    let table = store
+        .extra_table("expiration_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "expiration_index",
+        )))?;
+    table.put(txn, &key(expiration, event.id()), b"")?;
+    Ok(())
+}
+
+/// Index a newly stored event (if it expires)
+pub fn record(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    if expiration_of(event).is_none() {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    record_into(store, &mut txn, event)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// The index key of an event that is about to be removed, to pass to `forget()` once it
+/// is gone
+pub fn key_for_removal(event: &Event) -> Option<Vec<u8>> {
+    expiration_of(event).map(|expiration| key(expiration, event.id()))
+}
+
+/// Remove the index entry of a removed event
+pub fn forget(key: Option<Vec<u8>>) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let Some(key) = key else {
+        return Ok(());
+    };
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("expiration_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "expiration_index",
+        )))?;
+    let mut txn = store.write_txn()?;
+    let _ = table.delete(&mut txn, &key)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Build the index (once) for events stored before it existed
+pub fn migrate(store: &Store) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let meta = store
+        .extra_table("expiration_index_meta")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "expiration_index_meta",
+        )))?;
+
+    {
+        let txn = store.read_txn()?;
+        if meta.get(&txn, b"built")?.is_some() {
+            return Ok(());
+        }
+    }
+
+    let screen = |e: &Event| -> ScreenResult {
+        if expiration_of(e).is_some() {
+            ScreenResult::Match
+        } else {
+            ScreenResult::Mismatch
+        }
+    };
+    let _ = crate::backfill::backfill(store, "expiration index", screen, |txn, event| {
+        record_into(store, txn, event)
+    })?;
+    let mut txn = store.write_txn()?;
+    meta.put(&mut txn, b"built", b"")?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Remove every event that has expired. Returns how many were removed.
+pub fn sweep() -> Result<usize, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("expiration_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "expiration_index",
+        )))?;
+
+    // Expired means the expiration is in the past
+    let ids: Vec<(Vec<u8>, Id)> = {
+        let _reading = crate::map_size::reading();
+        let txn = store.read_txn()?;
+        let end = Time::now().as_u64().to_be_bytes();
+        let range = (Bound::Unbounded, Bound::Excluded(end.as_slice()));
+        let mut ids = Vec::new();
+        for i in table.range(&txn, &range)? {
+            let (key, _) = i?;
+            if key.len() == 8 + 32 {
+                ids.push((key.to_vec(), Id::from_bytes(key[8..].try_into().unwrap())));
+            }
+        }
+        ids
+    };
+
+    let mut removed: usize = 0;
+    for (key, id) in ids {
+        let stored = {
+            let _reading = crate::map_size::reading();
+            store.get_event_by_id(id)?.is_some()
+        };
+        if stored {
+            // This also forgets the index entry
+            crate::remove_event(id)?;
+            removed += 1;
+        } else {
+            // Already gone some other way
+            forget(Some(key))?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Sweep expired events every `expiration_sweep_seconds`, until shutdown
+pub async fn run() {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+
+    loop {
+        let seconds = GLOBALS.config.read().expiration_sweep_seconds;
+        if seconds == 0 {
+            return;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(seconds)) => {},
+            _ = shutting_down.changed() => {},
+        }
+        if *shutting_down.borrow() {
+            return;
+        }
+
+        // Leave the store alone while we hand it over, and in read-only and maintenance modes
+        if !crate::mode::background_writes() {
+            continue;
+        }
+
+        match sweep() {
+            Ok(0) => {}
+            Ok(n) => log::info!(target: "Server", "Removed {n} expired events"),
+            Err(e) => log::error!(target: "Server", "Expiration sweep failed: {e}"),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Fault injection at the store write boundaries, for testing.
+//!
+//! Call sites use `failpoints::hit("name")?`. Without the `failpoints` feature this is a
+//! no-op that always succeeds. With it, tests can arm named failpoints to make these calls
+//! fail deterministically.
+//!
+//! Failpoints:
+//!
+//! * `store_event` - before an event is appended to the events file and indexed by pocket
+//! * `store_event.after_append` - after pocket has stored the event, before our own
+//!   (multi-letter tag) index is written
+//! * `store_event.between_indexes` - after our tag and first-seen indexes are written,
+//!   before the rest of our own indexes are
+//! * `store_event.rollback` - before an event that could not be indexed is removed again,
+//!   leaving it stored but not fully indexed (as if we had crashed)
+//! * `tag_index.put` - before each of our own index entries is written (inside the write
+//!   transaction)
+//! * `remove_event` - before an event is removed
+//! * `remove_event.after_remove` - after pocket removed the event, before our own index
+//!   entries are removed
+//! * `sink.next_batch` - before the event sink reads its next batch from the outbox
+//! * `sink.remove_delivered` - before the event sink removes a delivered batch from the
+//!   outbox
+
+use crate::error::Error;
+
+#[cfg(not(feature = "failpoints"))]
+#[inline(always)]
+pub fn hit(_name: &'static str) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(feature = "failpoints")]
+pub use inner::*;
+
+#[cfg(feature = "failpoints")]
+mod inner {
+    use crate::error::{ChorusError, Error};
+    use lazy_static::lazy_static;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+
+    /// When an armed failpoint fails
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum FailAction {
+        /// Every time it is hit
+        Always,
+
+        /// The next time it is hit, then never again
+        Once,
+
+        /// Every nth time it is hit
+        EveryNth(u64),
+
+        /// Pseudo-randomly, about one in `one_in` hits, reproducibly from `seed`
+        Random { seed: u64, one_in: u64 },
+    }
+
+    struct State {
+        action: FailAction,
+        hits: u64,
+        rng: u64,
+        fired: u64,
+    }
+
+    lazy_static! {
+        static ref FAILPOINTS: Mutex<HashMap<&'static str, State>> = Mutex::new(HashMap::new());
+    }
+
+    /// Arm a failpoint (replacing any previous arming)
+    pub fn arm(name: &'static str, action: FailAction) {
+        let rng = match action {
+            FailAction::Random { seed, .. } => seed,
+            _ => 0,
+        };
+        let _ = FAILPOINTS.lock().insert(
+            name,
+            State {
+                action,
+                hits: 0,
+                rng,
+                fired: 0,
+            },
+        );
+    }
+
+    /// Disarm a failpoint
+    pub fn disarm(name: &'static str) {
+        let _ = FAILPOINTS.lock().remove(name);
+    }
+
+    /// Disarm all failpoints
+    pub fn disarm_all() {
+        FAILPOINTS.lock().clear();
+    }
+
+    /// How many times an armed failpoint has fired
+    pub fn fired(name: &'static str) -> u64 {
+        FAILPOINTS.lock().get(name).map(|s| s.fired).unwrap_or(0)
+    }
+
+    pub fn hit(name: &'static str) -> Result<(), Error> {
+        let mut failpoints = FAILPOINTS.lock();
+        let Some(state) = failpoints.get_mut(name) else {
+            return Ok(());
+        };
+        state.hits += 1;
+
+        let fail = match state.action {
+            FailAction::Always => true,
+            FailAction::Once => state.fired == 0,
+            FailAction::EveryNth(n) => n != 0 && state.hits % n == 0,
+            FailAction::Random { one_in, .. } => {
+                // xorshift64
+                let mut x = state.rng.max(1);
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                state.rng = x;
+                one_in != 0 && x % one_in == 0
+            }
+        };
+
+        if fail {
+            state.fired += 1;
+            Err(ChorusError::General(format!("failpoint {name}")).into())
+        } else {
+            Ok(())
+        }
+    }
+}
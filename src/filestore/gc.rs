@@ -0,0 +1,198 @@
+//! Garbage collection of Blossom blobs
+//!
+//! Blobs can be deleted for not having been downloaded in `blossom_gc_unaccessed_days`,
+//! and then, while the filestore holds more than `blossom_max_total_bytes`, the least
+//! recently used blobs are evicted. Blobs uploaded within `blossom_gc_grace_seconds`,
+//! those we don't know the upload time of, and pinned blobs, are never touched.
+//!
+//! This works from the blob metadata in the store (which survives restarts), so files we
+//! have no metadata for are neither counted nor deleted. A blob that is being uploaded
+//! again, or whose metadata changed after it was chosen, is left for the next sweep to
+//! reconsider.
+
+use super::metadata::{self, BlobMetadata};
+use super::HashOutput;
+use crate::error::Error;
+use crate::globals::GLOBALS;
+use pocket_types::Time;
+use std::collections::HashSet;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+struct Policy {
+    now: u64,
+    max_unused_seconds: Option<u64>,
+    max_total_bytes: Option<u64>,
+    grace_seconds: u64,
+}
+
+/// Choose which blobs to delete, oldest use first
+fn choose(
+    mut blobs: Vec<(HashOutput, BlobMetadata)>,
+    pins: &HashSet<HashOutput>,
+    policy: Policy,
+) -> Vec<(HashOutput, BlobMetadata)> {
+    let mut total: u64 = blobs.iter().map(|(_, m)| m.size).sum();
+    blobs.sort_by_key(|(_, m)| m.last_used());
+
+    let mut chosen = Vec::new();
+    for (hash, metadata) in blobs {
+        if pins.contains(&hash)
+            || metadata.uploaded == 0
+            || metadata.uploaded + policy.grace_seconds > policy.now
+        {
+            continue;
+        }
+        let unused = policy
+            .max_unused_seconds
+            .is_some_and(|max| metadata.last_used() + max < policy.now);
+        let over = policy.max_total_bytes.is_some_and(|max| total > max);
+        if !unused && !over {
+            // Everything after this was used more recently
+            break;
+        }
+        total -= metadata.size;
+        chosen.push((hash, metadata));
+    }
+    chosen
+}
+
+/// Delete blobs per the configured policies. Returns how many were deleted and how many
+/// bytes that freed.
+pub async fn sweep() -> Result<(usize, u64), Error> {
+    let policy = {
+        let config = GLOBALS.config.read();
+        Policy {
+            now: Time::now().as_u64(),
+            max_unused_seconds: match config.blossom_gc_unaccessed_days {
+                0 => None,
+                days => Some(days * 86400),
+            },
+            max_total_bytes: match config.blossom_max_total_bytes {
+                0 => None,
+                max => Some(max),
+            },
+            grace_seconds: config.blossom_gc_grace_seconds,
+        }
+    };
+    if policy.max_unused_seconds.is_none() && policy.max_total_bytes.is_none() {
+        return Ok((0, 0));
+    }
+
+    let Some(filestore) = GLOBALS.filestore.get() else {
+        return Ok((0, 0));
+    };
+
+    let pins: HashSet<HashOutput> = metadata::list_pins()?.into_iter().collect();
+
+    let mut deleted: usize = 0;
+    let mut freed: u64 = 0;
+    for (hash, metadata) in choose(metadata::all_blobs()?, &pins, policy) {
+        match filestore.delete_unchanged(hash, &metadata).await {
+            Ok(true) => {}
+            // Uploaded or served since we chose it
+            Ok(false) => continue,
+            Err(e) => {
+                log::error!(target: "Server", "Could not delete blob {hash}: {e}");
+                continue;
+            }
+        }
+        log::info!(
+            target: "Server",
+            "Garbage collected blob {} ({} bytes, last used {})",
+            hash,
+            metadata.size,
+            metadata.last_used()
+        );
+        deleted += 1;
+        freed += metadata.size;
+    }
+    Ok((deleted, freed))
+}
+
+/// Garbage collect blobs every `blossom_gc_interval_seconds`, until shutdown
+pub async fn run() {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+
+    loop {
+        let seconds = GLOBALS.config.read().blossom_gc_interval_seconds;
+        if seconds == 0 {
+            return;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(seconds)) => {},
+            _ = shutting_down.changed() => {},
+        }
+        if *shutting_down.borrow() {
+            return;
+        }
+
+        // Leave the store alone while we hand it over, and in read-only and maintenance modes
+        if !crate::mode::background_writes() {
+            continue;
+        }
+
+        match sweep().await {
+            Ok((0, _)) => {}
+            Ok((n, bytes)) => {
+                log::info!(target: "Server", "Garbage collected {n} blobs ({bytes} bytes)")
+            }
+            Err(e) => log::error!(target: "Server", "Blob garbage collection failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn blob(n: u8, size: u64, uploaded: u64, last_accessed: u64) -> (HashOutput, BlobMetadata) {
+        (
+            HashOutput::from_bytes([n; 32]),
+            BlobMetadata {
+                size,
+                uploaded,
+                last_accessed,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_choose() {
+        let blobs = vec![
+            blob(1, 100, 1000, 9000), // recently used
+            blob(2, 100, 1000, 2000), // unused
+            blob(3, 100, 1000, 0),    // never downloaded
+            blob(4, 100, 9500, 0),    // within grace
+            blob(5, 100, 1000, 0),    // pinned
+            blob(6, 100, 0, 0),       // uploaded we don't know when
+        ];
+        let pins: HashSet<HashOutput> = [HashOutput::from_bytes([5; 32])].into_iter().collect();
+        let policy = Policy {
+            now: 10000,
+            max_unused_seconds: Some(5000),
+            max_total_bytes: None,
+            grace_seconds: 1000,
+        };
+
+        let chosen: Vec<u8> = choose(blobs.clone(), &pins, policy)
+            .iter()
+            .map(|(h, _)| h.as_bytes()[0])
+            .collect();
+        assert_eq!(chosen, vec![3, 2]);
+
+        // Capping the size evicts the least recently used until under
+        let policy = Policy {
+            max_unused_seconds: None,
+            max_total_bytes: Some(250),
+            ..policy
+        };
+        let chosen: Vec<u8> = choose(blobs, &pins, policy)
+            .iter()
+            .map(|(h, _)| h.as_bytes()[0])
+            .collect();
+        assert_eq!(chosen, vec![3, 2, 1]);
+    }
+}
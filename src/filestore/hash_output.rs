@@ -29,13 +29,20 @@ impl HashOutput {
         HashOutput(bytes)
     }
 
-    pub fn to_pathbuf<P: AsRef<Path>>(&self, base: P) -> PathBuf {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Where the blob with this hash is kept under `base`: `depth` directories down, each
+    /// named for the next two hex digits of the hash, in a file named for the whole hash
+    pub fn to_pathbuf<P: AsRef<Path>>(&self, base: P, depth: usize) -> PathBuf {
         let s = hex::encode(self.0);
         let mut output: PathBuf = PathBuf::new();
         output.push(base);
-        output.push(&s[0..=1]);
-        output.push(&s[2..=3]);
-        output.push(&s[4..]);
+        for level in 0..depth {
+            output.push(&s[level * 2..level * 2 + 2]);
+        }
+        output.push(&s);
         output
     }
 }
@@ -58,8 +65,12 @@ mod test {
         .unwrap();
 
         assert_eq!(
-            &format!("{}", hash.to_pathbuf("/tmp").display()),
-            "/tmp/e3/b0/c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+            &format!("{}", hash.to_pathbuf("/tmp", 2).display()),
+            "/tmp/e3/b0/e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            &format!("{}", hash.to_pathbuf("/tmp", 0).display()),
+            "/tmp/e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
         );
     }
 }
@@ -0,0 +1,132 @@
+//! Image dimensions, from the image's header alone
+//!
+//! PNG, GIF, JPEG and WebP are understood. Nothing is decoded: we only look for the
+//! width and height where each format records them, so anything else (or anything
+//! truncated or malformed) just has no dimensions.
+
+/// How much of the start of a file to read for `dimensions()`. JPEG dimensions come after
+/// any EXIF data, which is no more than 64 KiB per segment.
+pub const HEADER_BYTES: usize = 256 * 1024;
+
+/// The width and height of the image that `header` is the start of, if we can tell
+pub fn dimensions(header: &[u8]) -> Option<(u32, u32)> {
+    if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png(header)
+    } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        gif(header)
+    } else if header.starts_with(b"\xff\xd8") {
+        jpeg(header)
+    } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WEBP") {
+        webp(header)
+    } else {
+        None
+    }
+}
+
+fn u16_be(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn u16_le(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn u24_le(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 3)?;
+    Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+}
+
+// The IHDR chunk comes first, right after the signature
+fn png(header: &[u8]) -> Option<(u32, u32)> {
+    if header.get(12..16) != Some(b"IHDR") {
+        return None;
+    }
+    let width = u32::from_be_bytes(header.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(header.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+// The logical screen size
+fn gif(header: &[u8]) -> Option<(u32, u32)> {
+    Some((u16_le(header, 6)?, u16_le(header, 8)?))
+}
+
+// Walk the segments up to the first start of frame
+fn jpeg(header: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    loop {
+        if *header.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *header.get(at + 1)?;
+        match marker {
+            // Fill
+            0xff => at += 1,
+            // Markers without a length
+            0x01 | 0xd0..=0xd7 => at += 2,
+            // Start of frame (other than DHT, JPG and DAC, which share the range)
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                let height = u16_be(header, at + 5)?;
+                let width = u16_be(header, at + 7)?;
+                return Some((width, height));
+            }
+            // Start of scan or end of image, without a frame
+            0xda | 0xd9 => return None,
+            _ => at += 2 + u16_be(header, at + 2)? as usize,
+        }
+    }
+}
+
+// Lossy, lossless and extended WebP each keep it differently
+fn webp(header: &[u8]) -> Option<(u32, u32)> {
+    match header.get(12..16)? {
+        b"VP8 " => {
+            if header.get(23..26) != Some(b"\x9d\x01\x2a") {
+                return None;
+            }
+            Some((u16_le(header, 26)? & 0x3fff, u16_le(header, 28)? & 0x3fff))
+        }
+        b"VP8L" => {
+            if *header.get(20)? != 0x2f {
+                return None;
+            }
+            let bits = u32::from_le_bytes(header.get(21..25)?.try_into().ok()?);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((u24_le(header, 24)? + 1, u24_le(header, 27)? + 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&640_u32.to_be_bytes());
+        png.extend_from_slice(&480_u32.to_be_bytes());
+        assert_eq!(dimensions(&png), Some((640, 480)));
+
+        let gif = b"GIF89a\x20\x03\x58\x02";
+        assert_eq!(dimensions(gif), Some((800, 600)));
+
+        // SOI, an APP0 segment, then SOF0
+        let jpeg = b"\xff\xd8\xff\xe0\x00\x04\x00\x00\xff\xc0\x00\x11\x08\x01\xe0\x02\x80";
+        assert_eq!(dimensions(jpeg), Some((640, 480)));
+        assert_eq!(dimensions(&jpeg[..12]), None);
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0".to_vec();
+        webp.extend_from_slice(&[0x7f, 0x02, 0x00, 0xdf, 0x01, 0x00]);
+        assert_eq!(dimensions(&webp), Some((640, 480)));
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8L\0\0\0\0\x2f".to_vec();
+        let bits: u32 = 639 | (479 << 14);
+        webp.extend_from_slice(&bits.to_le_bytes());
+        assert_eq!(dimensions(&webp), Some((640, 480)));
+
+        assert_eq!(dimensions(b"%PDF-1.7"), None);
+        assert_eq!(dimensions(b""), None);
+    }
+}
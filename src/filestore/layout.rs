@@ -0,0 +1,145 @@
+//! How blobs are laid out in the filestore directory
+//!
+//! Each blob is a file named for its (hex) hash, `blossom_shard_depth` directories down,
+//! each directory named for the next two hex digits of the hash: `e3/b0/e3b0c442...` at
+//! depth 2. The `layout` file records the depth the filestore is laid out at.
+//!
+//! If it is missing (older versions kept blobs as `e3/b0/c442...`, and the oldest flat in
+//! the directory) or records another depth, the blobs are moved at startup, in the
+//! background. Each move is a rename, and the `layout` file is only written once all of
+//! them are done, so if we are interrupted the next start carries on where we left off.
+//! Until then, blobs not yet where they belong are looked for wherever they might be.
+
+use super::{FileStore, HashOutput};
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use tokio::fs;
+
+/// The deepest the filestore may be sharded
+pub const MAX_SHARD_DEPTH: usize = 4;
+
+const LAYOUT_FILE: &str = "layout";
+
+/// The depth the filestore at `base` is laid out at, if it is known
+pub(super) fn read_depth(base: &Path) -> Option<usize> {
+    let contents = std::fs::read_to_string(base.join(LAYOUT_FILE)).ok()?;
+    contents.trim().strip_prefix("shard_depth=")?.parse().ok()
+}
+
+fn write_depth(base: &Path, depth: usize) -> Result<(), Error> {
+    std::fs::write(base.join(LAYOUT_FILE), format!("shard_depth={depth}\n"))?;
+    Ok(())
+}
+
+/// Everywhere else a blob might be, while the filestore is not yet laid out
+pub(super) fn former_paths(hash: HashOutput, base: &Path) -> Vec<PathBuf> {
+    let hex = hash.to_string();
+    let mut paths = vec![base.join(&hex[0..2]).join(&hex[2..4]).join(&hex[4..])];
+    for depth in 0..=MAX_SHARD_DEPTH {
+        paths.push(hash.to_pathbuf(base, depth));
+    }
+    paths
+}
+
+// The hash of the blob at `path`, from its name (and, in the old layout, its directories)
+fn hash_of(path: &Path) -> Option<HashOutput> {
+    let name = path.file_name()?.to_str()?;
+    if !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    match name.len() {
+        64 => HashOutput::from_hex(name).ok(),
+        60 => {
+            let second = path.parent()?;
+            let first = second.parent()?;
+            let hex = format!(
+                "{}{}{name}",
+                first.file_name()?.to_str()?,
+                second.file_name()?.to_str()?
+            );
+            HashOutput::from_hex(&hex).ok()
+        }
+        _ => None,
+    }
+}
+
+impl FileStore {
+    /// Move the blobs to where `shard_depth` says they belong, if they are not there
+    /// already (see the module docs). Returns how many were moved.
+    pub async fn migrate_layout(&self) -> Result<usize, Error> {
+        if self.laid_out.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+
+        let (files, mut directories) = self.entries().await?;
+
+        let mut moved: usize = 0;
+        for path in files.iter() {
+            let Some(hash) = hash_of(path) else {
+                log::warn!(target: "Server", "Filestore: leaving {}, which is not a blob", path.display());
+                continue;
+            };
+            let target = hash.to_pathbuf(&self.base, self.shard_depth);
+            if *path == target {
+                continue;
+            }
+            let result = if fs::try_exists(&target).await? {
+                // It was uploaded again in the meantime
+                fs::remove_file(path).await
+            } else {
+                fs::create_dir_all(target.parent().unwrap()).await?;
+                fs::rename(path, &target).await
+            };
+            match result {
+                Ok(()) => {}
+                // It was deleted in the meantime
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+            moved += 1;
+            if moved % 10_000 == 0 {
+                log::info!(target: "Server", "Filestore: moved {moved} blobs so far");
+            }
+        }
+
+        // Remove the directories left empty, deepest first (those still in use fail)
+        directories.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+        for directory in directories.iter() {
+            let _ = fs::remove_dir(directory).await;
+        }
+
+        write_depth(&self.base, self.shard_depth)?;
+        self.laid_out.store(true, Ordering::Relaxed);
+        Ok(moved)
+    }
+
+    /// The hashes of every stored blob, wherever it is
+    pub async fn hashes(&self) -> Result<Vec<HashOutput>, Error> {
+        let (files, _directories) = self.entries().await?;
+        Ok(files.iter().filter_map(|path| hash_of(path)).collect())
+    }
+
+    // Every file and directory but our own
+    async fn entries(&self) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Error> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        let mut directories: Vec<PathBuf> = Vec::new();
+        let mut pending: Vec<PathBuf> = vec![self.base.clone()];
+        while let Some(directory) = pending.pop() {
+            let mut entries = fs::read_dir(&directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path == self.temp || path == self.base.join(LAYOUT_FILE) {
+                    continue;
+                }
+                if entry.file_type().await?.is_dir() {
+                    directories.push(path.clone());
+                    pending.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        Ok((files, directories))
+    }
+}
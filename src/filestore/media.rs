@@ -0,0 +1,288 @@
+//! BUD-05 media optimization: re-encoding images uploaded to `/media`
+//!
+//! JPEG, PNG and WebP images are decoded, turned the way their EXIF orientation says,
+//! scaled down to fit within `blossom_media_max_dimension`, and encoded as
+//! `blossom_media_format` (or lossless WebP if they have transparency), which drops their
+//! metadata. Anything else, including animated GIFs, is left as it is.
+//!
+//! Decoding is both the expensive part and the dangerous one. It runs on blocking threads,
+//! no more than `blossom_media_max_transcodes` at a time, and only for images whose header
+//! says they have no more than `blossom_media_max_pixels` (the decoder is held to that
+//! size too, in case the header lies).
+
+use super::{FileStore, HashOutput};
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use ::image::codecs::jpeg::JpegEncoder;
+use ::image::codecs::webp::WebPEncoder;
+use ::image::imageops::FilterType;
+use ::image::{DynamicImage, ImageDecoder, ImageReader, ImageResult, Limits};
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Semaphore;
+
+static TRANSCODERS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+// The types of image we re-encode
+const OPTIMIZABLE: [&str; 3] = ["image/jpeg", "image/png", "image/webp"];
+
+/// What images are re-encoded as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MediaFormat {
+    #[default]
+    Jpeg,
+    Webp,
+}
+
+impl MediaFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            MediaFormat::Jpeg => "image/jpeg",
+            MediaFormat::Webp => "image/webp",
+        }
+    }
+}
+
+impl fmt::Display for MediaFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaFormat::Jpeg => write!(f, "jpeg"),
+            MediaFormat::Webp => write!(f, "webp"),
+        }
+    }
+}
+
+impl FromStr for MediaFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<MediaFormat, Error> {
+        match &*s.trim().to_lowercase() {
+            "jpeg" | "jpg" => Ok(MediaFormat::Jpeg),
+            "webp" => Ok(MediaFormat::Webp),
+            _ => Err(ChorusError::General(format!("Unknown blossom_media_format: {s}")).into()),
+        }
+    }
+}
+
+/// How images are re-encoded (from the config)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub max_dimension: u32,
+    pub format: MediaFormat,
+    pub quality: u8,
+    pub max_pixels: u64,
+}
+
+impl Settings {
+    pub fn from_config() -> Settings {
+        let config = GLOBALS.config.read();
+        Settings {
+            max_dimension: config.blossom_media_max_dimension,
+            format: config.blossom_media_format,
+            quality: config.blossom_media_quality,
+            max_pixels: config.blossom_media_max_pixels,
+        }
+    }
+}
+
+/// A re-encoded image
+#[derive(Debug, Clone)]
+pub struct Optimized {
+    pub bytes: Vec<u8>,
+    pub mime_type: &'static str,
+}
+
+/// Whether content of this MIME type is an image we re-encode
+pub fn is_optimizable(mime_type: &str) -> bool {
+    let essence = mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    OPTIMIZABLE.contains(&essence.as_str())
+}
+
+/// Re-encode a stored image on a blocking thread, once one of
+/// `blossom_media_max_transcodes` is free (it is only read into memory then). None if that
+/// is not worthwhile (see `transcode`).
+pub async fn optimize(filestore: &FileStore, hash: HashOutput) -> Result<Option<Optimized>, Error> {
+    let settings = Settings::from_config();
+    let transcoders = TRANSCODERS.get_or_init(|| {
+        let max = GLOBALS.config.read().blossom_media_max_transcodes;
+        Arc::new(Semaphore::new(max.max(1)))
+    });
+    let permit = transcoders
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|e| ChorusError::General(format!("{e}")).into_err())?;
+    let original = filestore.read(hash).await?;
+
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        transcode(&original, &settings)
+    })
+    .await
+    .map_err(|e| ChorusError::General(format!("{e}")).into_err())?
+}
+
+/// Re-encode an image, inline. None if that is not worthwhile: it is too big to decode
+/// (or its header does not say how big), it cannot be decoded, or it was not scaled down
+/// and the result is no smaller.
+pub fn transcode(original: &[u8], settings: &Settings) -> Result<Option<Optimized>, Error> {
+    let header = &original[..original.len().min(super::image::HEADER_BYTES)];
+    let Some((width, height)) = super::image::dimensions(header) else {
+        return Ok(None);
+    };
+    if width as u64 * height as u64 > settings.max_pixels {
+        log::debug!(target: "Server", "Blossom: not decoding a {width}x{height} image");
+        return Ok(None);
+    }
+
+    let image = match decode(original, width, height) {
+        Ok(image) => image,
+        Err(e) => {
+            log::debug!(target: "Server", "Blossom: could not decode an image: {e}");
+            return Ok(None);
+        }
+    };
+
+    let max = settings.max_dimension;
+    let scaled = image.width() > max || image.height() > max;
+    let image = if scaled {
+        image.resize(max, max, FilterType::Lanczos3)
+    } else {
+        image
+    };
+
+    let optimized =
+        encode(&image, settings).map_err(|e| ChorusError::General(format!("{e}")).into_err())?;
+    if !scaled && optimized.bytes.len() >= original.len() {
+        return Ok(None);
+    }
+    Ok(Some(optimized))
+}
+
+// Decode an image its header says is `width` by `height`, and no bigger
+fn decode(original: &[u8], width: u32, height: u32) -> ImageResult<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(width);
+    limits.max_image_height = Some(height);
+
+    let mut reader = ImageReader::new(Cursor::new(original)).with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn encode(image: &DynamicImage, settings: &Settings) -> ImageResult<Optimized> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let alpha = image.color().has_alpha();
+    let format = if alpha {
+        MediaFormat::Webp
+    } else {
+        settings.format
+    };
+    match format {
+        MediaFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut bytes, settings.quality);
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        MediaFormat::Webp if alpha => {
+            let encoder = WebPEncoder::new_lossless(&mut bytes);
+            DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder)?;
+        }
+        MediaFormat::Webp => {
+            let encoder = WebPEncoder::new_lossless(&mut bytes);
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+    }
+    Ok(Optimized {
+        bytes,
+        mime_type: format.mime_type(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::image::codecs::png::PngEncoder;
+    use ::image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    const SETTINGS: Settings = Settings {
+        max_dimension: 1000,
+        format: MediaFormat::Jpeg,
+        quality: 85,
+        max_pixels: 10_000_000,
+    };
+
+    fn png(image: DynamicImage) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        image
+            .write_with_encoder(PngEncoder::new(&mut bytes))
+            .unwrap();
+        bytes
+    }
+
+    fn photo(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_transcode() {
+        // Scaled down to fit, keeping the aspect ratio
+        let original = png(photo(3000, 1500));
+        let optimized = transcode(&original, &SETTINGS).unwrap().unwrap();
+        assert_eq!(optimized.mime_type, "image/jpeg");
+        assert_eq!(
+            crate::filestore::image::dimensions(&optimized.bytes),
+            Some((1000, 500))
+        );
+
+        // Transparency needs WebP
+        let logo = DynamicImage::ImageRgba8(RgbaImage::from_fn(200, 100, |x, _| {
+            Rgba([255, 0, 0, (x % 256) as u8])
+        }));
+        let optimized = transcode(&png(logo), &SETTINGS).unwrap().unwrap();
+        assert_eq!(optimized.mime_type, "image/webp");
+        assert_eq!(
+            crate::filestore::image::dimensions(&optimized.bytes),
+            Some((200, 100))
+        );
+
+        // Too many pixels to decode
+        let settings = Settings {
+            max_pixels: 3000 * 1500 - 1,
+            ..SETTINGS
+        };
+        assert!(transcode(&original, &settings).unwrap().is_none());
+
+        // No smaller, and no need to scale
+        let mut small: Vec<u8> = Vec::new();
+        let encoder = JpegEncoder::new_with_quality(&mut small, 10);
+        photo(100, 100).write_with_encoder(encoder).unwrap();
+        assert!(transcode(&small, &SETTINGS).unwrap().is_none());
+
+        // Not an image, or not really
+        assert!(transcode(b"%PDF-1.7", &SETTINGS).unwrap().is_none());
+        let mut truncated = original.clone();
+        truncated.truncate(100);
+        assert!(transcode(&truncated, &SETTINGS).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_is_optimizable() {
+        assert!(is_optimizable("image/jpeg"));
+        assert!(is_optimizable("Image/PNG; charset=binary"));
+        assert!(!is_optimizable("image/gif"));
+        assert!(!is_optimizable("video/mp4"));
+    }
+}
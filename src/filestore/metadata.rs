@@ -0,0 +1,389 @@
+//! Blob metadata and ownership, kept in the store
+//!
+//! The files themselves live in the filestore. For each blob we keep its metadata (as
+//! JSON, keyed by hash) including the pubkeys that uploaded it, and for each uploader the
+//! blobs they uploaded (so they can be listed per BUD-02). Pinned blobs are kept in a
+//! set of their own, and are never garbage collected.
+//!
+//! Blobs stored before we recorded MIME types (or any metadata) have theirs sniffed once,
+//! at startup, in the background (see `backfill_mime_types`), rather than as they are
+//! served. Those we had no metadata for at all get some, with an unknown upload time.
+
+use super::{FileStore, HashOutput};
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use pocket_types::Pubkey;
+use serde::{Deserialize, Serialize};
+
+/// What we know about a stored blob
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobMetadata {
+    /// Size in bytes
+    pub size: u64,
+
+    /// MIME type (as given by the uploader, or sniffed)
+    pub mime_type: Option<String>,
+
+    /// Width and height ("WxH"), for images whose header told us
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dim: Option<String>,
+
+    /// When it was first uploaded, or zero if we don't know (it was stored before we kept
+    /// metadata)
+    pub uploaded: u64,
+
+    /// Who uploaded it (hex pubkeys)
+    pub owners: Vec<String>,
+
+    /// When it was last served (roughly: this is only updated once per `TOUCH_INTERVAL`).
+    /// Zero if it never has been since we started keeping track.
+    #[serde(default)]
+    pub last_accessed: u64,
+}
+
+impl BlobMetadata {
+    /// When it was last used, for garbage collection: the last access, or else the upload
+    pub fn last_used(&self) -> u64 {
+        self.last_accessed.max(self.uploaded)
+    }
+}
+
+// How stale `last_accessed` may get before a download updates it. This spares us a write
+// transaction per download, and is far finer than garbage collection needs.
+const TOUCH_INTERVAL: u64 = 3600;
+
+/// Get the metadata of a blob, if we have any
+pub fn get_blob(hash: HashOutput) -> Result<Option<BlobMetadata>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let txn = store.read_txn()?;
+    match blobs.get(&txn, hash.as_bytes())? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Record that `owner` uploaded a blob at time `uploaded`
+pub fn add_blob(
+    hash: HashOutput,
+    size: u64,
+    mime_type: Option<String>,
+    dim: Option<String>,
+    owner: Pubkey,
+    uploaded: u64,
+) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let blob_owners =
+        store
+            .extra_table("blob_owners")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blob_owners",
+            )))?;
+    let mut txn = store.write_txn()?;
+
+    let mut metadata: BlobMetadata = match blobs.get(&txn, hash.as_bytes())? {
+        Some(bytes) => serde_json::from_slice(bytes)?,
+        None => BlobMetadata {
+            size,
+            mime_type,
+            dim: None,
+            uploaded,
+            owners: vec![],
+            last_accessed: 0,
+        },
+    };
+    if metadata.dim.is_none() {
+        metadata.dim = dim;
+    }
+    let owner_hex = owner.as_hex_string();
+    if !metadata.owners.contains(&owner_hex) {
+        metadata.owners.push(owner_hex);
+    }
+    blobs.put(&mut txn, hash.as_bytes(), &serde_json::to_vec(&metadata)?)?;
+
+    let key = owner_key(owner, hash);
+    if blob_owners.get(&txn, &key)?.is_none() {
+        blob_owners.put(&mut txn, &key, &uploaded.to_be_bytes())?;
+    }
+
+    txn.commit()?;
+    Ok(())
+}
+
+/// Record the MIME type of a blob, creating its metadata (with an unknown upload time) if
+/// it predates our keeping any
+pub fn set_mime_type(hash: HashOutput, size: u64, mime_type: Option<String>) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let mut txn = store.write_txn()?;
+
+    let mut metadata: BlobMetadata = match blobs.get(&txn, hash.as_bytes())? {
+        Some(bytes) => serde_json::from_slice(bytes)?,
+        None => BlobMetadata {
+            size,
+            ..Default::default()
+        },
+    };
+    metadata.mime_type = mime_type;
+    blobs.put(&mut txn, hash.as_bytes(), &serde_json::to_vec(&metadata)?)?;
+
+    txn.commit()?;
+    Ok(())
+}
+
+/// Sniff and record the MIME type of every stored blob that we have none for, once (see
+/// the module docs). Stops without finishing if the store stops taking our writes, to
+/// carry on at the next start. Returns how many were recorded.
+pub async fn backfill_mime_types(filestore: &FileStore) -> Result<usize, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let meta = store
+        .extra_table("blobs_meta")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs_meta")))?;
+    {
+        let _reading = crate::map_size::reading();
+        let txn = store.read_txn()?;
+        if meta.get(&txn, b"mime_types")?.is_some() {
+            return Ok(0);
+        }
+    }
+
+    let mut recorded: usize = 0;
+    for hash in filestore.hashes().await? {
+        if get_blob(hash)?.is_some_and(|m| m.mime_type.is_some()) {
+            continue;
+        }
+        let (size, mime_type) = match filestore.metadata(hash).await {
+            Ok(m) => (m.len(), filestore.sniff_mime_type(hash).await?),
+            // It was deleted in the meantime
+            Err(_) => continue,
+        };
+        // Leave the store alone while we hand it over, and in read-only and maintenance modes
+        if !crate::mode::background_writes() {
+            return Ok(recorded);
+        }
+        set_mime_type(
+            hash,
+            size,
+            Some(mime_type.unwrap_or("application/octet-stream".to_owned())),
+        )?;
+        recorded += 1;
+    }
+
+    let _reading = crate::map_size::reading();
+    let mut txn = store.write_txn()?;
+    meta.put(&mut txn, b"mime_types", b"")?;
+    txn.commit()?;
+    Ok(recorded)
+}
+
+/// Remove `owner`'s claim on a blob. Returns true if nobody else owns it (so the file
+/// itself can go too).
+pub fn remove_owner(hash: HashOutput, owner: Pubkey) -> Result<bool, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let blob_owners =
+        store
+            .extra_table("blob_owners")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blob_owners",
+            )))?;
+    let mut txn = store.write_txn()?;
+
+    let _ = blob_owners.delete(&mut txn, &owner_key(owner, hash))?;
+
+    let maybe_metadata: Option<BlobMetadata> = match blobs.get(&txn, hash.as_bytes())? {
+        Some(bytes) => Some(serde_json::from_slice(bytes)?),
+        None => None,
+    };
+    let orphaned = match maybe_metadata {
+        Some(mut metadata) => {
+            let owner_hex = owner.as_hex_string();
+            metadata.owners.retain(|o| *o != owner_hex);
+            if metadata.owners.is_empty() {
+                let _ = blobs.delete(&mut txn, hash.as_bytes())?;
+                true
+            } else {
+                blobs.put(&mut txn, hash.as_bytes(), &serde_json::to_vec(&metadata)?)?;
+                false
+            }
+        }
+        // Uploaded before we tracked owners, so not theirs to remove
+        None => false,
+    };
+
+    txn.commit()?;
+    Ok(orphaned)
+}
+
+/// Blobs uploaded by `owner`, with when they uploaded each, optionally limited to those
+/// uploaded within `since..=until`
+pub fn list_blobs(
+    owner: Pubkey,
+    since: Option<u64>,
+    until: Option<u64>,
+) -> Result<Vec<(HashOutput, BlobMetadata, u64)>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let blob_owners =
+        store
+            .extra_table("blob_owners")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blob_owners",
+            )))?;
+    let txn = store.read_txn()?;
+
+    let mut output: Vec<(HashOutput, BlobMetadata, u64)> = Vec::new();
+    for i in blob_owners.prefix_iter(&txn, owner.as_slice())? {
+        let (key, val) = i?;
+        let uploaded = u64::from_be_bytes(val[..8].try_into().unwrap());
+        if since.is_some_and(|s| uploaded < s) || until.is_some_and(|u| uploaded > u) {
+            continue;
+        }
+        let hash = HashOutput::from_bytes(key[32..64].try_into().unwrap());
+        if let Some(bytes) = blobs.get(&txn, hash.as_bytes())? {
+            output.push((hash, serde_json::from_slice(bytes)?, uploaded));
+        }
+    }
+    Ok(output)
+}
+
+/// Record that a blob was served at time `now`
+pub fn touch(hash: HashOutput, now: u64) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    match get_blob(hash)? {
+        Some(metadata) if metadata.last_used() + TOUCH_INTERVAL <= now => {}
+        _ => return Ok(()),
+    }
+
+    let store = GLOBALS.store.get().unwrap();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let mut txn = store.write_txn()?;
+    if let Some(bytes) = blobs.get(&txn, hash.as_bytes())? {
+        let mut metadata: BlobMetadata = serde_json::from_slice(bytes)?;
+        metadata.last_accessed = now;
+        blobs.put(&mut txn, hash.as_bytes(), &serde_json::to_vec(&metadata)?)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Every blob we have metadata for
+pub fn all_blobs() -> Result<Vec<(HashOutput, BlobMetadata)>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let txn = store.read_txn()?;
+
+    let mut output: Vec<(HashOutput, BlobMetadata)> = Vec::new();
+    for i in blobs.iter(&txn)? {
+        let (key, val) = i?;
+        if let Ok(bytes) = <[u8; 32]>::try_from(key) {
+            output.push((HashOutput::from_bytes(bytes), serde_json::from_slice(val)?));
+        }
+    }
+    Ok(output)
+}
+
+/// Forget a blob entirely (metadata and every owner's claim), once the file is gone
+pub fn forget_blob(hash: HashOutput) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blobs = store
+        .extra_table("blobs")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blobs")))?;
+    let blob_owners =
+        store
+            .extra_table("blob_owners")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blob_owners",
+            )))?;
+    let mut txn = store.write_txn()?;
+
+    let maybe_metadata: Option<BlobMetadata> = match blobs.get(&txn, hash.as_bytes())? {
+        Some(bytes) => Some(serde_json::from_slice(bytes)?),
+        None => None,
+    };
+    if let Some(metadata) = maybe_metadata {
+        for owner_hex in metadata.owners.iter() {
+            if let Ok(owner) = Pubkey::read_hex(owner_hex.as_bytes()) {
+                let _ = blob_owners.delete(&mut txn, &owner_key(owner, hash))?;
+            }
+        }
+        let _ = blobs.delete(&mut txn, hash.as_bytes())?;
+    }
+
+    txn.commit()?;
+    Ok(())
+}
+
+/// Pin a blob, so that garbage collection never removes it
+pub fn pin(hash: HashOutput) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blob_pins = store
+        .extra_table("blob_pins")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blob_pins")))?;
+    let mut txn = store.write_txn()?;
+    blob_pins.put(&mut txn, hash.as_bytes(), b"")?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Unpin a blob
+pub fn unpin(hash: HashOutput) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blob_pins = store
+        .extra_table("blob_pins")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blob_pins")))?;
+    let mut txn = store.write_txn()?;
+    let _ = blob_pins.delete(&mut txn, hash.as_bytes())?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Pinned blobs
+pub fn list_pins() -> Result<Vec<HashOutput>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let blob_pins = store
+        .extra_table("blob_pins")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("blob_pins")))?;
+    let txn = store.read_txn()?;
+
+    let mut output: Vec<HashOutput> = Vec::new();
+    for i in blob_pins.iter(&txn)? {
+        let (key, _) = i?;
+        if let Ok(bytes) = <[u8; 32]>::try_from(key) {
+            output.push(HashOutput::from_bytes(bytes));
+        }
+    }
+    Ok(output)
+}
+
+fn owner_key(owner: Pubkey, hash: HashOutput) -> Vec<u8> {
+    let mut key = Vec::with_capacity(64);
+    key.extend_from_slice(owner.as_slice());
+    key.extend_from_slice(hash.as_bytes());
+    key
+}
@@ -3,8 +3,13 @@ use futures::TryStreamExt;
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyDataStream, BodyExt, StreamBody};
 use hyper::body::{Bytes, Frame};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::fs::Metadata;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use tokio::fs;
 use tokio::fs::File;
 use tokio_util::io::{InspectReader, ReaderStream, StreamReader};
@@ -12,13 +17,55 @@ use tokio_util::io::{InspectReader, ReaderStream, StreamReader};
 mod hash_output;
 pub use hash_output::HashOutput;
 
+pub mod gc;
+pub mod image;
+pub mod layout;
+pub mod media;
+pub mod metadata;
+
+// Temporary files older than this are left over from uploads that never finished. Newer
+// ones may belong to uploads still in progress (in the process we took over from).
+const STALE_TEMP_AGE: Duration = Duration::from_secs(3600);
+
+lazy_static! {
+    // The blobs being stored, and by how many uploads at once (see `Uploading`)
+    static ref UPLOADING: Mutex<HashMap<HashOutput, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Marks a blob as being stored, from when its hash is known (before its file is in
+/// place) until this is dropped, once the caller has recorded its metadata. Until then
+/// `delete_unreferenced` leaves it alone.
+pub struct Uploading(HashOutput);
+
+impl Uploading {
+    fn new(hash: HashOutput) -> Uploading {
+        *UPLOADING.lock().entry(hash).or_insert(0) += 1;
+        Uploading(hash)
+    }
+}
+
+impl Drop for Uploading {
+    fn drop(&mut self) {
+        let mut uploading = UPLOADING.lock();
+        if let Some(count) = uploading.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                let _ = uploading.remove(&self.0);
+            }
+        }
+    }
+}
+
 pub struct FileStore {
     pub base: PathBuf,
     pub temp: PathBuf,
+    shard_depth: usize,
+    // Whether every blob is where `shard_depth` says (see `layout`)
+    laid_out: AtomicBool,
 }
 
 impl FileStore {
-    pub async fn new<P: AsRef<Path>>(base: P) -> Result<FileStore, Error> {
+    pub async fn new<P: AsRef<Path>>(base: P, shard_depth: usize) -> Result<FileStore, Error> {
         let base = base.as_ref().to_owned();
 
         let temp = {
@@ -31,7 +78,64 @@ impl FileStore {
             fs::create_dir_all(&temp).await?;
         }
 
-        Ok(FileStore { base, temp })
+        let laid_out = AtomicBool::new(layout::read_depth(&base) == Some(shard_depth));
+        let filestore = FileStore {
+            base,
+            temp,
+            shard_depth,
+            laid_out,
+        };
+        filestore.sweep_temp(STALE_TEMP_AGE).await?;
+        Ok(filestore)
+    }
+
+    /// Remove temporary files older than `age`, left behind by uploads that never
+    /// finished (e.g. if we crashed)
+    pub async fn sweep_temp(&self, age: Duration) -> Result<usize, Error> {
+        let mut removed: usize = 0;
+        let mut entries = fs::read_dir(&self.temp).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let stale = metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.elapsed().ok())
+                .is_some_and(|elapsed| elapsed > age);
+            if metadata.is_file() && stale {
+                fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            log::info!(target: "Server", "Removed {removed} stale temporary upload files");
+        }
+        Ok(removed)
+    }
+
+    /// Check that we can write to the filestore, by writing (and removing) a temporary file
+    pub async fn check_writable(&self) -> Result<(), Error> {
+        let temp = TempFile(self.tmpfile());
+        fs::write(&temp.0, b"").await?;
+        Ok(())
+    }
+
+    // Where a blob is, or would be. Until the filestore is laid out, a blob may still be
+    // where it used to be, or be moved from there as we look, so look where it belongs
+    // both before and after.
+    async fn locate(&self, hash: HashOutput) -> PathBuf {
+        let pathbuf = hash.to_pathbuf(&self.base, self.shard_depth);
+        if self.laid_out.load(Ordering::Relaxed) {
+            return pathbuf;
+        }
+        let mut candidates = vec![pathbuf.clone()];
+        candidates.extend(layout::former_paths(hash, &self.base));
+        candidates.push(pathbuf.clone());
+        for candidate in candidates {
+            if fs::try_exists(&candidate).await.unwrap_or(false) {
+                return candidate;
+            }
+        }
+        pathbuf
     }
 
     fn tmpfile(&self) -> PathBuf {
@@ -43,22 +147,25 @@ impl FileStore {
 
     /// Store a file in storage, streamed from a hyper BoxBody
     ///
-    /// Returns it's HashOutput by which it can be later retrieved or deleted.
+    /// Returns it's HashOutput by which it can be later retrieved or deleted, and marks it
+    /// as being stored until the caller has recorded its metadata (see `Uploading`).
     pub async fn store(
         &self,
         data: BoxBody<Bytes, Error>,
         expected_hash: Option<HashOutput>,
-    ) -> Result<(u64, HashOutput, Option<String>), Error> {
+    ) -> Result<(u64, HashOutput, Option<String>, Uploading), Error> {
         use bitcoin_hashes::sha256;
         use std::io::Write; // for hash_engine.write_all()
 
-        // We will download into a temporary file (as we don't know the hash yet)
-        let temppathbuf = self.tmpfile();
+        // We will download into a temporary file (as we don't know the hash yet). The
+        // guard removes it however we leave, including if this future is dropped because
+        // the client went away.
+        let temp = TempFile(self.tmpfile());
         let mut tempfile = File::options()
             .create(true)
             .truncate(true)
             .write(true)
-            .open(&temppathbuf)
+            .open(&temp.0)
             .await?;
 
         // Convert the BoxBody into a Data Stream
@@ -91,51 +198,39 @@ impl FileStore {
         // Verify the expected hash matches
         if let Some(expected) = expected_hash {
             if hash != expected {
-                // Remove the temporary file
-                fs::remove_file(&temppathbuf).await?;
-
-                // And complain
-                return Err(ChorusError::BlossomAuthFailure(
-                    "File hash does not match authorized hash".to_string(),
-                )
-                .into());
+                return Err(ChorusError::BlossomHashMismatch.into());
             }
         }
 
         // Sniff the mime-type
-        let maybe_mime_string = {
-            use mime_sniffer::MimeTypeSniffer;
-            use tokio::io::AsyncReadExt;
-            let mut readtempfile = File::open(&temppathbuf).await?;
-            let mut buffer: Vec<u8> = vec![0; 128];
-            let _ = readtempfile.read(&mut buffer).await?;
-            buffer.sniff_mime_type().map(|s| s.to_string())
-        };
-
-        // Compute the proper path
-        let pathbuf = hash.to_pathbuf(&self.base);
+        let maybe_mime_string = sniff(&temp.0).await?;
 
-        // If it already exists, trust the existing copy
-        if fs::try_exists(&pathbuf).await? {
-            // Just clean up
-            fs::remove_file(&temppathbuf).await?;
+        // From here on, the file is not removed from under us
+        let uploading = Uploading::new(hash);
 
-            return Ok((size, hash, maybe_mime_string));
+        // If it already exists, trust the existing copy (the guard cleans up)
+        if fs::try_exists(&self.locate(hash).await).await? {
+            return Ok((size, hash, maybe_mime_string, uploading));
         }
 
+        // Compute the proper path
+        let pathbuf = hash.to_pathbuf(&self.base, self.shard_depth);
+
         // Make the parent directory
         fs::create_dir_all(pathbuf.parent().unwrap()).await?;
 
-        // Move the file
-        fs::rename(&temppathbuf, &pathbuf).await?;
+        // Move the file into place. This is atomic (the temp directory is within the
+        // filestore, so on the same filesystem): readers see all of it or nothing.
+        fs::rename(&temp.0, &pathbuf).await?;
+        temp.keep();
 
-        Ok((size, hash, maybe_mime_string))
+        Ok((size, hash, maybe_mime_string, uploading))
     }
 
     /// Retrieve a file from storage by its HashOutput, streamed to a hyper BoxBoxy
     pub async fn retrieve(&self, hash: HashOutput) -> Result<BoxBody<Bytes, Error>, Error> {
         // Compute the path
-        let pathbuf = hash.to_pathbuf(&self.base);
+        let pathbuf = self.locate(hash).await;
 
         // Open the file
         let file = File::open(&pathbuf).await?;
@@ -152,18 +247,118 @@ impl FileStore {
         Ok(boxed_body)
     }
 
+    /// Retrieve `len` bytes of a file starting at `start`, streamed to a hyper BoxBody
+    pub async fn retrieve_range(
+        &self,
+        hash: HashOutput,
+        start: u64,
+        len: u64,
+    ) -> Result<BoxBody<Bytes, Error>, Error> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        // Compute the path
+        let pathbuf = self.locate(hash).await;
+
+        // Open the file and seek to the start of the window
+        let mut file = File::open(&pathbuf).await?;
+        let _ = file.seek(std::io::SeekFrom::Start(start)).await?;
+
+        // Convert the window of the file into a Stream
+        let reader_stream = ReaderStream::new(file.take(len));
+
+        // Convert the Stream into a Body
+        let stream_body = StreamBody::new(reader_stream.map_ok(Frame::data));
+
+        // Box the body, mapping the error
+        let boxed_body = BodyExt::map_err(stream_body, |e| e.into()).boxed();
+
+        Ok(boxed_body)
+    }
+
+    /// Read the whole of a file from storage
+    pub async fn read(&self, hash: HashOutput) -> Result<Vec<u8>, Error> {
+        Ok(fs::read(self.locate(hash).await).await?)
+    }
+
+    /// Sniff the mime-type of a stored file from its first bytes
+    pub async fn sniff_mime_type(&self, hash: HashOutput) -> Result<Option<String>, Error> {
+        sniff(&self.locate(hash).await).await
+    }
+
+    /// The width and height of a stored image, if we can tell from its header
+    pub async fn dimensions(&self, hash: HashOutput) -> Result<Option<(u32, u32)>, Error> {
+        use tokio::io::AsyncReadExt;
+        let file = File::open(self.locate(hash).await).await?;
+        let mut header: Vec<u8> = Vec::new();
+        let _ = file
+            .take(image::HEADER_BYTES as u64)
+            .read_to_end(&mut header)
+            .await?;
+        Ok(image::dimensions(&header))
+    }
+
     /// Check if a file exists and provide it's metadata (including .len())
     pub async fn metadata(&self, hash: HashOutput) -> Result<Metadata, Error> {
         // Compute the path
-        let pathbuf = hash.to_pathbuf(&self.base);
+        let pathbuf = self.locate(hash).await;
 
         Ok(tokio::fs::metadata(&pathbuf).await?)
     }
 
+    /// Delete a file from storage if nothing refers to it: we have no metadata for it, and
+    /// nobody is storing it (but whoever holds `ours`). Returns whether it was deleted.
+    pub async fn delete_unreferenced(
+        &self,
+        hash: HashOutput,
+        ours: Option<&Uploading>,
+    ) -> Result<bool, Error> {
+        let pathbuf = self.locate(hash).await;
+
+        // Checked and deleted together, so a new upload of it either finds it gone (and
+        // puts it back) or keeps it
+        let uploading = UPLOADING.lock();
+        let storing = uploading.get(&hash).copied().unwrap_or(0)
+            - usize::from(ours.is_some_and(|u| u.0 == hash));
+        if storing > 0 || metadata::get_blob(hash)?.is_some() {
+            return Ok(false);
+        }
+        match std::fs::remove_file(&pathbuf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Delete a blob chosen for garbage collection, and forget its metadata, unless it
+    /// has changed since it was chosen: somebody is storing it again, or its metadata is
+    /// no longer `seen` (it was uploaded or served meanwhile). Returns whether it was
+    /// deleted.
+    pub async fn delete_unchanged(
+        &self,
+        hash: HashOutput,
+        seen: &metadata::BlobMetadata,
+    ) -> Result<bool, Error> {
+        let pathbuf = self.locate(hash).await;
+
+        // Checked and deleted together, as in `delete_unreferenced`
+        let uploading = UPLOADING.lock();
+        if uploading.contains_key(&hash) || metadata::get_blob(hash)?.as_ref() != Some(seen) {
+            return Ok(false);
+        }
+        match std::fs::remove_file(&pathbuf) {
+            Ok(()) => {}
+            // Gone already is fine, we still forget it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        metadata::forget_blob(hash)?;
+        Ok(true)
+    }
+
     /// Delete a file from storage by its HashOutput
     pub async fn delete(&self, hash: HashOutput) -> Result<(), Error> {
         // Compute the path
-        let pathbuf = hash.to_pathbuf(&self.base);
+        let pathbuf = self.locate(hash).await;
 
         // Delete the file
         tokio::fs::remove_file(&pathbuf).await?;
@@ -171,3 +366,165 @@ impl FileStore {
         Ok(())
     }
 }
+
+// A temporary file that is removed when dropped, unless kept
+struct TempFile(PathBuf);
+
+impl TempFile {
+    // It has been moved into place
+    fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // Synchronous, as we may be dropped outside of the runtime
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+async fn sniff(path: &Path) -> Result<Option<String>, Error> {
+    use mime_sniffer::MimeTypeSniffer;
+    use tokio::io::AsyncReadExt;
+    let mut file = File::open(path).await?;
+    let mut buffer: Vec<u8> = vec![0; 128];
+    let _ = file.read(&mut buffer).await?;
+    Ok(buffer.sniff_mime_type().map(|s| s.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+
+    fn count_files(dir: &Path) -> usize {
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                count += count_files(&entry.path());
+            } else {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn body(chunks: Vec<Result<&'static [u8], Error>>) -> BoxBody<Bytes, Error> {
+        let stream = futures::stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| chunk.map(|bytes| Frame::data(Bytes::from_static(bytes)))),
+        );
+        BodyExt::boxed(StreamBody::new(stream))
+    }
+
+    #[tokio::test]
+    async fn test_store_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let filestore = FileStore::new(dir.path(), 2).await.unwrap();
+
+        // The body fails midway (as when the client goes away)
+        let aborted = body(vec![
+            Ok(b"some of the data"),
+            Err(ChorusError::General("client went away".to_owned()).into()),
+        ]);
+        assert!(filestore.store(aborted, None).await.is_err());
+        assert_eq!(count_files(dir.path()), 0);
+
+        // The hash does not match
+        let wrong = HashOutput::from_bytes([0; 32]);
+        let mismatched = body(vec![Ok(b"all of the data")]);
+        assert!(filestore.store(mismatched, Some(wrong)).await.is_err());
+        assert_eq!(count_files(dir.path()), 0);
+
+        // The store future is dropped partway
+        let stalled =
+            futures::stream::iter(vec![Ok(Frame::data(Bytes::from_static(b"the start")))])
+                .chain(futures::stream::pending::<Result<Frame<Bytes>, Error>>());
+        let pending = BodyExt::boxed(StreamBody::new(stalled));
+        let store = filestore.store(pending, None);
+        let timeout = tokio::time::timeout(Duration::from_millis(100), store).await;
+        assert!(timeout.is_err());
+        assert_eq!(count_files(dir.path()), 0);
+
+        // And the good case leaves just the file
+        let good = body(vec![Ok(b"all of "), Ok(b"the data")]);
+        let (size, hash, _, _) = filestore.store(good, None).await.unwrap();
+        assert_eq!(size, 15);
+        assert_eq!(count_files(dir.path()), 1);
+        assert!(filestore.metadata(hash).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_temp() {
+        let dir = tempfile::tempdir().unwrap();
+        let filestore = FileStore::new(dir.path(), 2).await.unwrap();
+        std::fs::write(filestore.tmpfile(), b"leftover").unwrap();
+
+        assert_eq!(filestore.sweep_temp(STALE_TEMP_AGE).await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            filestore
+                .sweep_temp(Duration::from_millis(10))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(count_files(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let blob = |n: u8| -> (HashOutput, Vec<u8>) {
+            use bitcoin_hashes::sha256;
+            use std::io::Write;
+            let data = vec![n; 100];
+            let mut engine = sha256::HashEngine::default();
+            engine.write_all(&data).unwrap();
+            (HashOutput::from_engine(engine), data)
+        };
+
+        // One blob as older versions kept them, one flat, and one already in place
+        let (old, old_data) = blob(1);
+        let hex = old.to_string();
+        let old_path = base.join(&hex[0..2]).join(&hex[2..4]).join(&hex[4..]);
+        std::fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+        std::fs::write(&old_path, &old_data).unwrap();
+        let (flat, flat_data) = blob(2);
+        std::fs::write(base.join(flat.to_string()), &flat_data).unwrap();
+        let (placed, placed_data) = blob(3);
+        let placed_path = placed.to_pathbuf(base, 2);
+        std::fs::create_dir_all(placed_path.parent().unwrap()).unwrap();
+        std::fs::write(&placed_path, &placed_data).unwrap();
+
+        // They are all found before they are moved, and after
+        let filestore = FileStore::new(base, 2).await.unwrap();
+        for (hash, _) in [blob(1), blob(2), blob(3)] {
+            assert_eq!(filestore.metadata(hash).await.unwrap().len(), 100);
+        }
+        assert_eq!(filestore.migrate_layout().await.unwrap(), 2);
+        assert_eq!(filestore.migrate_layout().await.unwrap(), 0);
+        for (hash, data) in [blob(1), blob(2), blob(3)] {
+            assert_eq!(std::fs::read(hash.to_pathbuf(base, 2)).unwrap(), data);
+            assert_eq!(filestore.metadata(hash).await.unwrap().len(), 100);
+        }
+        assert!(!old_path.exists());
+        assert_eq!(count_files(base), 4); // and the layout file
+
+        // Laid out, a restart moves nothing, and a new depth moves everything
+        let filestore = FileStore::new(base, 2).await.unwrap();
+        assert!(filestore.laid_out.load(Ordering::Relaxed));
+        let filestore = FileStore::new(base, 1).await.unwrap();
+        assert_eq!(filestore.metadata(flat).await.unwrap().len(), 100);
+        assert_eq!(filestore.migrate_layout().await.unwrap(), 3);
+        for (hash, data) in [blob(1), blob(2), blob(3)] {
+            assert_eq!(std::fs::read(hash.to_pathbuf(base, 1)).unwrap(), data);
+        }
+        assert_eq!(count_files(base), 4);
+        assert_eq!(layout::read_depth(base), Some(1));
+    }
+}
@@ -0,0 +1,327 @@
+//! Filters as chorus understands them: a standard filter plus conditions that pocket
+//! does not know about, namely tag conditions on multi-letter tag names (e.g. `#title`),
+//! the `since_seen`, `until_seen`, `_received_since`, `_received_until` and `since_last`
+//! extensions, and NIP-50 `search`.
+//!
+//! Such conditions are stripped out before the rest of the filter is handed to pocket,
+//! and applied by us afterwards (see `crate::tag_index` for the tags we can index,
+//! `crate::first_seen` for `since_seen`, `until_seen` and the `_received_` fields,
+//! `crate::resume` for `since_last` and `crate::search_index` for `search`).
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use pocket_types::{Event, Filter, OwnedFilter};
+use serde_json::{Map, Value};
+
+/// A tag condition on a multi-letter tag name
+#[derive(Debug, Clone, PartialEq)]
+pub struct LongTagCondition {
+    /// The tag name (without the leading '#')
+    pub name: String,
+
+    /// The event matches if it has a tag with this name and any of these values
+    pub values: Vec<String>,
+}
+
+impl LongTagCondition {
+    pub fn event_matches(&self, event: &Event) -> Result<bool, Error> {
+        for mut tag in event.tags()?.iter() {
+            if tag.next() != Some(self.name.as_bytes()) {
+                continue;
+            }
+            if let Some(value) = tag.next() {
+                if self.values.iter().any(|v| v.as_bytes() == value) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Remove a field holding a timestamp (such as `since_seen`), returning it if `enabled`
+fn take_time(
+    map: &mut Map<String, Value>,
+    field: &str,
+    enabled: bool,
+) -> Result<Option<u64>, Error> {
+    match map.remove(field) {
+        Some(value) if enabled => match value.as_u64() {
+            Some(time) => Ok(Some(time)),
+            None => Err(ChorusError::InvalidFilter(format!("{field} must be a timestamp")).into()),
+        },
+        _ => Ok(None),
+    }
+}
+
+pub struct ChorusFilter {
+    pub filter: OwnedFilter,
+
+    /// The filter as JSON, less the conditions pocket does not know about (for planning,
+    /// see `crate::planner`)
+    pub json: Map<String, Value>,
+
+    pub long_tags: Vec<LongTagCondition>,
+
+    /// Only events we first received at or after this time (by `since_seen` if
+    /// `enable_since_seen`, or `_received_since`). Set to 0 when the filter only has an
+    /// `until_seen` (or `_received_until`).
+    pub since_seen: Option<u64>,
+
+    /// Only events we first received at or before this time (by `until_seen` if
+    /// `enable_since_seen`, or `_received_until`)
+    pub until_seen: Option<u64>,
+
+    /// Whether it has `_received_since` or `_received_until`, which only the relay's users
+    /// and admins may send (see `nostr.rs`)
+    pub received: bool,
+
+    /// Whether to resume from where the same subscription left off (if
+    /// `enable_since_last`, see `crate::resume`)
+    pub since_last: bool,
+
+    /// Terms that must all appear in the content (NIP-50, if `enable_search`)
+    pub search: Option<Vec<String>>,
+}
+
+impl ChorusFilter {
+    /// Parse a filter a client sent from the start of `input`, using `buffer` for the
+    /// pocket filter. Its limit becomes the one it is served with: `default_limit` if it
+    /// has none, and no more than `max_limit`.
+    ///
+    /// Returns the number of input bytes consumed, the number of buffer bytes used, and
+    /// the filter.
+    pub fn from_json(
+        input: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(usize, usize, ChorusFilter), Error> {
+        Self::parse(input, buffer, true)
+    }
+
+    /// As `from_json`, but keeping the limit as it is (for filters we make ourselves)
+    pub fn from_json_as_is(
+        input: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(usize, usize, ChorusFilter), Error> {
+        Self::parse(input, buffer, false)
+    }
+
+    fn parse(
+        input: &[u8],
+        buffer: &mut [u8],
+        apply_limits: bool,
+    ) -> Result<(usize, usize, ChorusFilter), Error> {
+        let mut stream =
+            serde_json::Deserializer::from_slice(input).into_iter::<Map<String, Value>>();
+        let mut map = match stream.next() {
+            Some(result) => result?,
+            None => return Err(ChorusError::InvalidFilter("missing filter".to_owned()).into()),
+        };
+        let consumed = stream.byte_offset();
+
+        if apply_limits {
+            crate::validation::check_filter(&map)?;
+        }
+
+        // A limit that is not a number is left for pocket to reject
+        let mut limit_changed = false;
+        if apply_limits && map.get("limit").is_none_or(|v| v.is_u64()) {
+            let config = GLOBALS.config.read();
+            let asked = map.get("limit").and_then(|v| v.as_u64());
+            let limit = asked
+                .unwrap_or(config.default_limit as u64)
+                .min(config.max_limit as u64);
+            if asked != Some(limit) {
+                let _ = map.insert("limit".to_owned(), limit.into());
+                limit_changed = true;
+            }
+        }
+
+        let long_names: Vec<String> = map
+            .keys()
+            .filter(|k| k.starts_with('#') && k.len() > 2)
+            .cloned()
+            .collect();
+
+        // The common case: nothing pocket does not understand
+        if long_names.is_empty()
+            && !limit_changed
+            && !map.contains_key("since_seen")
+            && !map.contains_key("until_seen")
+            && !map.contains_key("_received_since")
+            && !map.contains_key("_received_until")
+            && !map.contains_key("since_last")
+            && !map.contains_key("search")
+        {
+            let (incount, outcount, filter) = Filter::from_json(input, buffer)?;
+            return Ok((
+                incount,
+                outcount,
+                ChorusFilter {
+                    filter: filter.to_owned(),
+                    json: map,
+                    long_tags: vec![],
+                    since_seen: None,
+                    until_seen: None,
+                    received: false,
+                    since_last: false,
+                    search: None,
+                },
+            ));
+        }
+
+        let mut long_tags: Vec<LongTagCondition> = Vec::with_capacity(long_names.len());
+        for key in long_names {
+            let values = match map.remove(&key) {
+                Some(Value::Array(a)) => a,
+                _ => {
+                    return Err(ChorusError::InvalidFilter(format!("{key} is not an array")).into())
+                }
+            };
+            let mut strings: Vec<String> = Vec::with_capacity(values.len());
+            for v in values {
+                match v {
+                    Value::String(s) => strings.push(s),
+                    _ => {
+                        return Err(ChorusError::InvalidFilter(format!(
+                            "{key} values must be strings"
+                        ))
+                        .into())
+                    }
+                }
+            }
+            long_tags.push(LongTagCondition {
+                name: key[1..].to_owned(),
+                values: strings,
+            });
+        }
+
+        // since_seen and until_seen are ignored (whatever their values) unless enabled, but
+        // the _received_ fields are not (as we always know when we received events)
+        let enabled = GLOBALS.config.read().enable_since_seen;
+        let since_seen = take_time(&mut map, "since_seen", enabled)?;
+        let until_seen = take_time(&mut map, "until_seen", enabled)?;
+        let received_since = take_time(&mut map, "_received_since", true)?;
+        let received_until = take_time(&mut map, "_received_until", true)?;
+        let received = received_since.is_some() || received_until.is_some();
+        let until_seen = match (until_seen, received_until) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let since_seen = since_seen.max(received_since).or(until_seen.map(|_| 0));
+
+        // since_last is ignored (whatever its value) unless enabled
+        let since_last = match map.remove("since_last") {
+            Some(value) if GLOBALS.config.read().enable_since_last => match value.as_bool() {
+                Some(since_last) => since_last,
+                None => {
+                    return Err(ChorusError::InvalidFilter(
+                        "since_last must be true or false".to_owned(),
+                    )
+                    .into())
+                }
+            },
+            _ => false,
+        };
+
+        // search is left to pocket (as before) unless enabled
+        let search = if GLOBALS.config.read().enable_search {
+            match map.remove("search") {
+                Some(Value::String(s)) => {
+                    let terms = crate::search_index::terms(&s);
+                    if terms.is_empty() {
+                        None
+                    } else {
+                        Some(terms)
+                    }
+                }
+                Some(_) => {
+                    return Err(
+                        ChorusError::InvalidFilter("search must be a string".to_owned()).into(),
+                    )
+                }
+                None => None,
+            }
+        } else {
+            None
+        };
+
+        let rest = serde_json::to_vec(&map)?;
+        let (_incount, outcount, filter) = Filter::from_json(&rest, buffer)?;
+        Ok((
+            consumed,
+            outcount,
+            ChorusFilter {
+                filter: filter.to_owned(),
+                json: map,
+                long_tags,
+                since_seen,
+                until_seen,
+                received,
+                since_last,
+                search,
+            },
+        ))
+    }
+
+    /// Serve only events created at or after `since`, if that is later than the filter's
+    /// own `since` (resuming it, see `crate::resume`). `buffer` is used for the pocket
+    /// filter.
+    pub fn resume_from(&mut self, since: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        if self.json.get("since").and_then(|v| v.as_u64()) >= Some(since) {
+            return Ok(());
+        }
+        let _ = self.json.insert("since".to_owned(), since.into());
+        let rest = serde_json::to_vec(&self.json)?;
+        let (_incount, _outcount, filter) = Filter::from_json(&rest, buffer)?;
+        self.filter = filter.to_owned();
+        Ok(())
+    }
+
+    /// This filter with `json` in place of its own (for serving it a page at a time, see
+    /// `crate::history`)
+    pub fn with_json(&self, json: Map<String, Value>) -> Result<ChorusFilter, Error> {
+        let rest = serde_json::to_vec(&json)?;
+        let mut buffer = vec![0_u8; rest.len() * 2 + 256];
+        let (_incount, _outcount, filter) = Filter::from_json(&rest, &mut buffer)?;
+        Ok(ChorusFilter {
+            filter: filter.to_owned(),
+            json,
+            long_tags: self.long_tags.clone(),
+            since_seen: self.since_seen,
+            until_seen: self.until_seen,
+            received: self.received,
+            since_last: self.since_last,
+            search: self.search.clone(),
+        })
+    }
+
+    /// Whether the multi-letter tag conditions match (not the rest of the filter)
+    pub fn long_tags_match(&self, event: &Event) -> Result<bool, Error> {
+        for condition in self.long_tags.iter() {
+            if !condition.event_matches(event)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether the search terms match (not the rest of the filter)
+    pub fn search_matches(&self, event: &Event) -> bool {
+        match &self.search {
+            Some(terms) => crate::search_index::event_matches(event, terms),
+            None => true,
+        }
+    }
+
+    /// Whether this is a plain filter, with nothing pocket does not know about
+    pub fn is_plain(&self) -> bool {
+        self.long_tags.is_empty() && self.since_seen.is_none() && self.search.is_none()
+    }
+
+    pub fn event_matches(&self, event: &Event) -> Result<bool, Error> {
+        Ok(self.filter.event_matches(event)?
+            && self.long_tags_match(event)?
+            && self.search_matches(event))
+    }
+}
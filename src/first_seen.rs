@@ -0,0 +1,194 @@
+//! Index of when we first received each event
+//!
+//! Pollers using `since` miss events whose author backdated `created_at`. With this index
+//! a REQ filter may instead carry a non-standard `since_seen` field (and `until_seen`, with
+//! `enable_since_seen`), matching events by when we received them and serving them in
+//! that order. The relay's users and admins may do the same with `_received_since` and
+//! `_received_until` whatever the config (see `nostr.rs`).
+//!
+//! It is kept whatever the config, so that when each event was received is known for
+//! exports too. Events that were already stored when the index was built are recorded as
+//! seen when it was built, since we cannot know when we really received them.
+
+use crate::error::{ChorusError, Error};
+use crate::filter::ChorusFilter;
+use crate::globals::GLOBALS;
+use pocket_db::heed::RwTxn;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Id, Time};
+use std::ops::Bound;
+
+fn record_into(store: &Store, txn: &mut RwTxn, event: &Event, seen: u64) -> Result<(), Error> {
+    let by_seen = store
+        .extra_table("first_seen")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("first_seen")))?;
+    let by_id = store
+        .extra_table("first_seen_ids")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "first_seen_ids",
+        )))?;
+
+    if by_id.get(txn, event.id().as_slice())?.is_some() {
+        return Ok(());
+    }
+
+    let mut key = seen.to_be_bytes().to_vec();
+    key.extend_from_slice(event.id().as_slice());
+    by_seen.put(txn, &key, b"")?;
+    by_id.put(txn, event.id().as_slice(), &seen.to_be_bytes())?;
+    Ok(())
+}
+
+/// Record that we received an event now
+pub fn record(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    record_into(store, &mut txn, event, Time::now().as_u64())?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Record that we received an event at `seen`, as when an event is stored again and must
+/// keep the time it was first received
+pub fn record_at(event: &Event, seen: u64) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    record_into(store, &mut txn, event, seen)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Forget a removed event
+pub fn forget(id: Id) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let by_seen = store
+        .extra_table("first_seen")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("first_seen")))?;
+    let by_id = store
+        .extra_table("first_seen_ids")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "first_seen_ids",
+        )))?;
+
+    let mut txn = store.write_txn()?;
+    let seen: Option<[u8; 8]> = by_id
+        .get(&txn, id.as_slice())?
+        .map(|v| v[..8].try_into().unwrap());
+    if let Some(seen) = seen {
+        let mut key = seen.to_vec();
+        key.extend_from_slice(id.as_slice());
+        let _ = by_seen.delete(&mut txn, &key)?;
+        let _ = by_id.delete(&mut txn, id.as_slice())?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// When we first received an event, if known
+pub fn seen_at(id: Id) -> Result<Option<u64>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let by_id = store
+        .extra_table("first_seen_ids")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "first_seen_ids",
+        )))?;
+    let txn = store.read_txn()?;
+    Ok(by_id
+        .get(&txn, id.as_slice())?
+        .map(|v| u64::from_be_bytes(v[..8].try_into().unwrap())))
+}
+
+/// Build the index (once) for events stored before it existed. Events already in it (as
+/// those received since) keep when they were received.
+pub fn migrate(store: &Store) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let meta = store
+        .extra_table("first_seen_meta")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "first_seen_meta",
+        )))?;
+
+    {
+        let txn = store.read_txn()?;
+        if meta.get(&txn, b"built")?.is_some() {
+            return Ok(());
+        }
+    }
+
+    let now = Time::now().as_u64();
+    let _ = crate::backfill::backfill(
+        store,
+        "first-seen index",
+        |_| ScreenResult::Match,
+        |txn, event| record_into(store, txn, event, now),
+    )?;
+    let mut txn = store.write_txn()?;
+    meta.put(&mut txn, b"built", b"")?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Find events matching a filter that we first received at or after `since_seen` (and at
+/// or before its `until_seen`, if it has one).
+///
+/// Returns the matching events in the order we received them (limited by the filter's
+/// limit) and whether any were redacted by the screen.
+pub fn find_events<F>(
+    filter: &ChorusFilter,
+    since_seen: u64,
+    screen: F,
+) -> Result<(Vec<&'static Event>, bool), Error>
+where
+    F: Fn(&Event) -> ScreenResult,
+{
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let by_seen = store
+        .extra_table("first_seen")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("first_seen")))?;
+    let txn = store.read_txn()?;
+
+    let limit = filter.filter.limit() as usize;
+    let start = since_seen.to_be_bytes();
+    let end = filter
+        .until_seen
+        .and_then(|until_seen| until_seen.checked_add(1))
+        .map(|after| after.to_be_bytes());
+    let range = (
+        Bound::Included(start.as_slice()),
+        match &end {
+            Some(after) => Bound::Excluded(after.as_slice()),
+            None => Bound::Unbounded,
+        },
+    );
+
+    let mut redacted = false;
+    let mut events: Vec<&'static Event> = Vec::new();
+    for i in by_seen.range(&txn, &range)? {
+        if events.len() >= limit {
+            break;
+        }
+        let (key, _) = i?;
+        if key.len() != 8 + 32 {
+            continue;
+        }
+        let id = Id::from_bytes(key[8..].try_into().unwrap());
+        let Some(event) = store.get_event_by_id(id)? else {
+            continue;
+        };
+        if !filter.event_matches(event)? {
+            continue;
+        }
+        match screen(event) {
+            ScreenResult::Match => events.push(event),
+            ScreenResult::Redacted => redacted = true,
+            ScreenResult::Mismatch => {}
+        }
+    }
+
+    Ok((events, redacted))
+}
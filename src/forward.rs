@@ -0,0 +1,450 @@
+//! Forwarding: sending the events we accept on to peer relays
+//!
+//! Accepted events matching `forward_kinds` and `forward_authors` are appended (by id) to
+//! a queue table at ingest. A task per peer in `forward_relays` keeps a websocket open to
+//! it and sends it the queued events in order, one at a time, waiting for each OK. The
+//! last sequence number each peer has answered for is recorded in the database, so a
+//! restart picks up where it left off. Queue entries every peer has been sent are trimmed.
+//!
+//! Ingest never waits on a peer. A peer that is down just falls behind, and catches up
+//! once it is back.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use futures::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use pocket_types::{Event, Id};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_rustls::{rustls, TlsConnector};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use url::Url;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const OK_TIMEOUT: Duration = Duration::from_secs(30);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// Sequence number of the next event queued, unless the queue has a later one (it may have
+// been trimmed empty, but numbers are never reused)
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
+// The sequence number of the last event queued, for peers to wait on
+static QUEUED: OnceLock<watch::Sender<u64>> = OnceLock::new();
+
+static STATUS: Mutex<BTreeMap<String, PeerStatus>> = Mutex::new(BTreeMap::new());
+
+/// How forwarding to a peer relay is going
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerStatus {
+    pub url: String,
+
+    /// Whether we have a connection to it
+    pub connected: bool,
+
+    /// Events waiting to be sent to it
+    pub behind: u64,
+
+    /// Events it accepted since startup
+    pub forwarded: u64,
+
+    /// Events it refused (with OK false) since startup
+    pub refused: u64,
+
+    /// Why the last connection failed
+    pub last_error: Option<String>,
+}
+
+fn update_status<F: FnOnce(&mut PeerStatus)>(url: &str, f: F) {
+    let mut status = STATUS.lock();
+    let entry = status.entry(url.to_owned()).or_insert_with(|| PeerStatus {
+        url: url.to_owned(),
+        ..Default::default()
+    });
+    f(entry);
+}
+
+/// Status of forwarding to each peer
+pub fn stats() -> Vec<PeerStatus> {
+    STATUS.lock().values().cloned().collect()
+}
+
+/// Pick up where we left off: continue the queue's sequence
+pub fn init() -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let queue = store
+        .extra_table("forward_queue")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "forward_queue",
+        )))?;
+    let txn = store.read_txn()?;
+    let last = match queue.last(&txn)? {
+        Some((key, _)) => u64::from_be_bytes(key[..8].try_into().unwrap()),
+        None => 0,
+    };
+    NEXT_SEQ.store(last + 1, Ordering::Relaxed);
+    let _ = QUEUED.set(watch::Sender::new(last));
+    Ok(())
+}
+
+/// Queue an accepted event for the peers (if any are configured and it matches)
+pub fn enqueue(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    {
+        let config = GLOBALS.config.read();
+        if config.forward_relays.is_empty() {
+            return Ok(());
+        }
+        if !config.forward_kinds.is_empty()
+            && !config.forward_kinds.contains(&event.kind().as_u16())
+        {
+            return Ok(());
+        }
+        if !config.forward_authors.is_empty() && !config.forward_authors.contains(&event.pubkey()) {
+            return Ok(());
+        }
+    }
+
+    let store = GLOBALS.store.get().unwrap();
+    let queue = store
+        .extra_table("forward_queue")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "forward_queue",
+        )))?;
+
+    // Numbered within the write transaction, so events are committed in sequence order
+    // (a peer reading one can never later find an earlier one)
+    let mut txn = store.write_txn()?;
+    let after = match queue.last(&txn)? {
+        Some((key, _)) => u64::from_be_bytes(key[..8].try_into().unwrap()),
+        None => 0,
+    };
+    let seq = (after + 1).max(NEXT_SEQ.load(Ordering::Relaxed));
+    queue.put(&mut txn, &seq.to_be_bytes(), event.id().as_slice())?;
+    let _ = NEXT_SEQ.fetch_max(seq + 1, Ordering::Relaxed);
+    txn.commit()?;
+
+    if let Some(queued) = QUEUED.get() {
+        queued.send_if_modified(|last| {
+            let newer = seq > *last;
+            if newer {
+                *last = seq;
+            }
+            newer
+        });
+    }
+    Ok(())
+}
+
+// The last sequence number `url` has been sent
+fn position(url: &str) -> Result<u64, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let positions = store
+        .extra_table("forward_positions")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "forward_positions",
+        )))?;
+    let txn = store.read_txn()?;
+    Ok(match positions.get(&txn, url.as_bytes())? {
+        Some(bytes) => u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+        None => 0,
+    })
+}
+
+// Record that the peer has answered for `seq`, unless we are leaving the store alone (see
+// `mode::background_writes`), in which case it is sent again later
+fn set_position(url: &str, seq: u64) -> Result<(), Error> {
+    if !crate::mode::background_writes() {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let positions = store
+        .extra_table("forward_positions")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "forward_positions",
+        )))?;
+    let mut txn = store.write_txn()?;
+    positions.put(&mut txn, url.as_bytes(), &seq.to_be_bytes())?;
+    txn.commit()?;
+    Ok(())
+}
+
+// The first queued event after `seq`
+fn next_after(seq: u64) -> Result<Option<(u64, Id)>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let queue = store
+        .extra_table("forward_queue")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "forward_queue",
+        )))?;
+    let txn = store.read_txn()?;
+    let start = seq.to_be_bytes();
+    let range = (Bound::Excluded(start.as_slice()), Bound::Unbounded);
+    let next = match queue.range(&txn, &range)?.next() {
+        Some(i) => {
+            let (key, value) = i?;
+            Some((
+                u64::from_be_bytes(key[..8].try_into().unwrap()),
+                Id::from_bytes(value[..32].try_into().unwrap()),
+            ))
+        }
+        None => None,
+    };
+    Ok(next)
+}
+
+// Remove queued events that every peer has been sent
+fn trim() -> Result<(), Error> {
+    if !crate::mode::background_writes() {
+        return Ok(());
+    }
+    let _reading = crate::map_size::reading();
+    let urls = GLOBALS.config.read().forward_relays.clone();
+    let mut oldest = u64::MAX;
+    for url in urls.iter() {
+        oldest = oldest.min(position(url)?);
+    }
+
+    let store = GLOBALS.store.get().unwrap();
+    let queue = store
+        .extra_table("forward_queue")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "forward_queue",
+        )))?;
+    let mut txn = store.write_txn()?;
+    let end = oldest.to_be_bytes();
+    let range = (Bound::Unbounded, Bound::Included(end.as_slice()));
+    let keys: Vec<Vec<u8>> = queue
+        .range(&txn, &range)?
+        .map(|i| i.map(|(key, _)| key.to_owned()))
+        .collect::<Result<_, _>>()?;
+    for key in keys.iter() {
+        let _ = queue.delete(&mut txn, key)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Forward events to each peer in `forward_relays`, until shutdown
+pub async fn run() {
+    let urls = GLOBALS.config.read().forward_relays.clone();
+    for url in urls {
+        update_status(&url, |_| {});
+        tokio::spawn(peer(url));
+    }
+}
+
+// Keep forwarding to one peer, reconnecting when the connection drops
+async fn peer(url: String) {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        // Paused while we leave the store alone (while we hand over the new process
+        // forwards instead)
+        if !crate::mode::background_writes() {
+            tokio::select! {
+                _ = crate::mode::wait_for_writes() => {},
+                _ = shutting_down.changed() => {},
+            }
+            if *shutting_down.borrow() {
+                return;
+            }
+            continue;
+        }
+
+        let started = Instant::now();
+        let result = tokio::select! {
+            result = forward(&url) => result,
+            _ = shutting_down.changed() => Ok(()),
+        };
+        update_status(&url, |s| s.connected = false);
+        if *shutting_down.borrow() {
+            return;
+        }
+        if let Err(e) = result {
+            log::warn!(target: "Server", "Forwarding to {url} failed: {e}");
+            update_status(&url, |s| s.last_error = Some(format!("{}", e.inner)));
+        }
+
+        // Back off from a peer that keeps failing, but not from one that was up a while
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        log::info!(target: "Server", "Reconnecting to {url} in {}s", backoff.as_secs());
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Connect to the peer and send it queued events until something goes wrong
+async fn forward(url: &str) -> Result<(), Error> {
+    let parsed = Url::parse(url)?;
+    let host = parsed
+        .host_str()
+        .ok_or(ChorusError::General("URL has no host".to_owned()).into_err())?
+        .to_owned();
+    let port = parsed
+        .port_or_known_default()
+        .ok_or(ChorusError::General("URL has no port".to_owned()).into_err())?;
+
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), port)))
+        .await
+        .map_err(|_| ChorusError::TimedOut.into_err())??;
+    match parsed.scheme() {
+        "ws" => serve(url, handshake(url, stream).await?).await,
+        "wss" => {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let tls_config = rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let server_name = rustls_pki_types::ServerName::try_from(host)
+                .map_err(|e| ChorusError::General(format!("{e}")).into_err())?;
+            let stream = TlsConnector::from(Arc::new(tls_config))
+                .connect(server_name, stream)
+                .await?;
+            serve(url, handshake(url, stream).await?).await
+        }
+        scheme => Err(ChorusError::General(format!("Unsupported scheme {scheme}")).into()),
+    }
+}
+
+async fn handshake<S>(url: &str, stream: S) -> Result<WebSocketStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (websocket, _response) = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        tokio_tungstenite::client_async(url, stream),
+    )
+    .await
+    .map_err(|_| ChorusError::TimedOut.into_err())??;
+    Ok(websocket)
+}
+
+async fn serve<S>(url: &str, mut websocket: WebSocketStream<S>) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    log::info!(target: "Server", "Forwarding events to {url}");
+    update_status(url, |s| {
+        s.connected = true;
+        s.last_error = None;
+    });
+    let mut queued = match QUEUED.get() {
+        Some(queued) => queued.subscribe(),
+        None => {
+            return Err(ChorusError::General("forwarding is not initialized".to_owned()).into())
+        }
+    };
+
+    loop {
+        // Paused while we leave the store alone (see `peer`)
+        if !crate::mode::background_writes() {
+            return Ok(());
+        }
+
+        let position = position(url)?;
+        let Some((seq, id)) = next_after(position)? else {
+            update_status(url, |s| s.behind = 0);
+            trim()?;
+
+            // Wait for more, answering pings meanwhile
+            tokio::select! {
+                changed = queued.changed() => {
+                    if changed.is_err() {
+                        return Ok(());
+                    }
+                }
+                message = websocket.next() => match message {
+                    Some(message) => {
+                        let _ = message?;
+                    }
+                    None => return Err(ChorusError::General("connection closed".to_owned()).into()),
+                },
+            }
+            continue;
+        };
+        update_status(url, |s| {
+            s.behind = NEXT_SEQ.load(Ordering::Relaxed).saturating_sub(seq)
+        });
+
+        // It may have been removed since
+        let json = {
+            let _reading = crate::map_size::reading();
+            GLOBALS
+                .store
+                .get()
+                .unwrap()
+                .get_event_by_id(id)?
+                .map(|event| event.as_json())
+                .transpose()?
+        };
+        let Some(json) = json else {
+            set_position(url, seq)?;
+            continue;
+        };
+
+        let mut message = b"[\"EVENT\",".to_vec();
+        message.extend_from_slice(&json);
+        message.push(b']');
+        websocket
+            .send(Message::text(String::from_utf8(message)?))
+            .await?;
+
+        let accepted = tokio::time::timeout(OK_TIMEOUT, await_ok(&mut websocket, id))
+            .await
+            .map_err(|_| ChorusError::TimedOut.into_err())??;
+        if accepted {
+            update_status(url, |s| s.forwarded += 1);
+        } else {
+            update_status(url, |s| s.refused += 1);
+        }
+        set_position(url, seq)?;
+    }
+}
+
+// Wait for the peer's OK for the event with `id`. Returns whether it accepted it.
+async fn await_ok<S>(websocket: &mut WebSocketStream<S>, id: Id) -> Result<bool, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let id = id.as_hex_string();
+    loop {
+        let Some(message) = websocket.next().await else {
+            return Err(ChorusError::General("connection closed".to_owned()).into());
+        };
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        let Ok(serde_json::Value::Array(reply)) = serde_json::from_str(text.as_str()) else {
+            continue;
+        };
+        if reply.first().and_then(|v| v.as_str()) == Some("OK")
+            && reply.get(1).and_then(|v| v.as_str()) == Some(&*id)
+        {
+            let accepted = reply.get(2).and_then(|v| v.as_bool()).unwrap_or(false);
+            if !accepted {
+                log::debug!(
+                    target: "Server",
+                    "Peer refused {id}: {}",
+                    reply.get(3).and_then(|v| v.as_str()).unwrap_or("")
+                );
+            }
+            return Ok(accepted);
+        }
+    }
+}
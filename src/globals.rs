@@ -1,18 +1,28 @@
 use crate::config::Config;
 use crate::filestore::FileStore;
 use crate::ip::HashedIp;
+use crate::lag::{Connection, ConnectionLag, NewEvent};
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimits;
+use crate::rejected::RejectedEvents;
+use crate::sink::SinkState;
 use dashmap::DashMap;
-use hyper::server::conn::http1;
-use hyper_util::rt::tokio::TokioTimer;
+use hyper::server::conn::{http1, http2};
+use hyper_util::rt::tokio::{TokioExecutor, TokioTimer};
 use lazy_static::lazy_static;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use pocket_db::Store;
-use std::sync::atomic::{AtomicU64, AtomicUsize};
+use pocket_types::Id;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::OnceLock;
 use std::time::{Duration, Instant};
 use tokio::sync::broadcast::Sender as BroadcastSender;
 use tokio::sync::watch::Sender as WatchSender;
 
+/// How long a client has to send a request's headers, and how long an HTTP connection may
+/// sit idle between requests
+pub const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Globals {
     pub start_time: Instant,
     pub bytes_inbound: AtomicU64,
@@ -21,17 +31,59 @@ pub struct Globals {
     pub store: OnceLock<Store>,
     pub filestore: OnceLock<FileStore>,
     pub http1builder: http1::Builder,
-    pub rid: OnceLock<String>,
 
-    /// This is a broadcast channel where new incoming events are advertised by their offset.
-    /// Every handler needs to listen to it and check if the incoming event matches any
-    /// subscribed fitlers for their client, and if so, send the event to their client under
-    /// that subscription.
-    pub new_events: BroadcastSender<u64>,
+    /// For connections that negotiated HTTP/2 by ALPN (see tls.rs)
+    pub http2builder: http2::Builder<TokioExecutor>,
+
+    /// The NIP-11 relay information document, rebuilt periodically (see web/nip11.rs)
+    pub rid: RwLock<Option<String>>,
+
+    /// This is a broadcast channel where new incoming events are advertised by their offset
+    /// (along with when they were ingested). Every handler needs to listen to it and check if
+    /// the incoming event matches any subscribed fitlers for their client, and if so, send the
+    /// event to their client under that subscription.
+    pub new_events: BroadcastSender<NewEvent>,
+
+    /// Live event delivery lag for each connection
+    pub delivery_lag: DashMap<Connection, ConnectionLag>,
+
+    /// Recently rejected events (only if keep_rejected_events is set)
+    pub rejected_events: Mutex<RejectedEvents>,
+
+    /// Event sink (event_sink_url) delivery state
+    pub event_sink: SinkState,
 
     pub num_connections: AtomicUsize,
+
+    /// The id given to the next websocket connection (see `lag::Connection`)
+    pub next_connection_id: AtomicU64,
+
     pub num_connections_per_ip: DashMap<HashedIp, usize>,
+
+    /// HTTP requests (other than websocket upgrades) being handled
+    pub num_http_requests: AtomicUsize,
+
+    /// Counters for the /metrics endpoint
+    pub metrics: Metrics,
+
+    /// Token buckets for EVENT, REQ and Blossom upload rate limits
+    pub rate_limits: RateLimits,
+
+    /// How many events with an id not matching their content each peer has submitted, and
+    /// when the last one was (forgotten after `nostr::EVENT_ID_MISMATCH_MEMORY`)
+    pub event_id_mismatches: DashMap<HashedIp, (u64, Instant)>,
+
+    /// Management command events already applied, by id, with their `created_at`
+    /// (forgotten once too old to be accepted anyway, see admin.rs)
+    pub admin_commands: DashMap<Id, u64>,
+
     pub shutting_down: WatchSender<bool>,
+
+    /// Set while handing over to a new process; writes are refused
+    pub handing_over: AtomicBool,
+
+    /// Set while delivery lag has been over lag_warn_ms for lag_warn_seconds
+    pub overloaded: AtomicBool,
 }
 
 lazy_static! {
@@ -43,7 +95,18 @@ lazy_static! {
         http1builder.ignore_invalid_headers(true);
         http1builder.keep_alive(true);
         http1builder.timer(TokioTimer::new());
-        http1builder.header_read_timeout(Duration::from_secs(5));
+        http1builder.header_read_timeout(HEADER_READ_TIMEOUT);
+
+        // Bounds like HTTP/1.1's on what one connection may ask of us at once (hyper has
+        // no header read timeout for HTTP/2, see `crate::serve_http2`)
+        let mut http2builder = http2::Builder::new(TokioExecutor::new());
+        http2builder.timer(TokioTimer::new());
+        http2builder.keep_alive_interval(Duration::from_secs(60));
+        http2builder.keep_alive_timeout(Duration::from_secs(20));
+        http2builder.max_concurrent_streams(100);
+        http2builder.max_header_list_size(64 * 1024);
+        http2builder.max_pending_accept_reset_streams(20);
+        http2builder.max_local_error_reset_streams(Some(256));
 
         Globals {
             start_time: Instant::now(),
@@ -53,11 +116,23 @@ lazy_static! {
             store: OnceLock::new(),
             filestore: OnceLock::new(),
             http1builder,
-            rid: OnceLock::new(),
+            http2builder,
+            rid: RwLock::new(None),
             new_events,
+            delivery_lag: DashMap::new(),
+            rejected_events: Mutex::new(RejectedEvents::default()),
+            event_sink: SinkState::default(),
             num_connections: AtomicUsize::new(0),
+            next_connection_id: AtomicU64::new(0),
             num_connections_per_ip: DashMap::new(),
+            num_http_requests: AtomicUsize::new(0),
+            metrics: Metrics::default(),
+            rate_limits: RateLimits::default(),
+            event_id_mismatches: DashMap::new(),
+            admin_commands: DashMap::new(),
             shutting_down,
+            handing_over: AtomicBool::new(false),
+            overloaded: AtomicBool::new(false),
         }
     };
 }
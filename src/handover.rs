@@ -0,0 +1,225 @@
+//! Zero-downtime restarts by handing the listening sockets over to a new process
+//!
+//! Sequence (on SIGUSR2 in the old process):
+//!
+//! 1. The old process stops accepting writes (EVENTs, management commands and Blossom
+//!    uploads and deletes are refused) and syncs the store. From here on only the new
+//!    process writes to the store: our background tasks that write (sweeps, the event
+//!    sink and forwarding) pause, and what we would otherwise record (IP data, since_last
+//!    cursors, blob access times) is not.
+//! 2. The old process re-executes its binary (as found at argv\[0\], so an upgraded binary
+//!    is picked up) with the listening sockets and a readiness pipe inherited, describing
+//!    them (along with the config path and a digest of the active ban list) in the
+//!    `CHORUS_HANDOVER` environment variable.
+//! 3. The new process opens the store (running any migrations), adopts the listening
+//!    sockets, and writes a byte to the readiness pipe.
+//! 4. Once ready, the old process stops accepting connections, lets its existing
+//!    connections drain for `handover_grace_seconds`, then closes any that remain and exits.
+//!
+//! Failure modes:
+//!
+//! * If the new process exits, or does not become ready within `HANDOVER_READY_TIMEOUT`,
+//!   the old process kills it, resumes accepting writes and keeps serving as before.
+//! * Until the old process exits, its remaining connections do not see live events
+//!   submitted to the new process (new event notifications are per-process).
+//! * If the ban list digests differ, the new process logs a warning but carries on; the
+//!   ban list lives in the shared store so this only happens if it changed mid-handover.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The environment variable carrying the serialized `HandoverState`
+pub const HANDOVER_VAR: &str = "CHORUS_HANDOVER";
+
+/// How long the old process waits for the new process to become ready
+pub const HANDOVER_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Minimal state passed from the old process to the new one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandoverState {
+    pub old_pid: u32,
+    pub config_path: String,
+    pub listen_fds: Vec<RawFd>,
+    pub ready_fd: RawFd,
+    pub ban_list_digest: String,
+}
+
+/// If we were started by a handover, get the state passed to us
+pub fn inherited_state() -> Result<Option<HandoverState>, Error> {
+    let json = match std::env::var(HANDOVER_VAR) {
+        Ok(json) => json,
+        Err(_) => return Ok(None),
+    };
+
+    // Don't pass it on to anything we might spawn
+    std::env::remove_var(HANDOVER_VAR);
+
+    Ok(Some(serde_json::from_str(&json)?))
+}
+
+/// Take the listening sockets passed to us, in the order of the configured listeners
+/// (see `crate::listener::Listener::adopt`)
+pub fn take_listen_fds(state: &HandoverState) -> Result<Vec<RawFd>, Error> {
+    for fd in state.listen_fds.iter() {
+        set_cloexec(*fd, true)?;
+    }
+    Ok(state.listen_fds.clone())
+}
+
+/// Tell the old process we are ready to take over
+pub fn signal_ready(state: &HandoverState) -> Result<(), Error> {
+    let digest = ban_list_digest()?;
+    if digest != state.ban_list_digest {
+        log::warn!(
+            target: "Server",
+            "Handover: ban list changed during handover (theirs {}, ours {})",
+            state.ban_list_digest,
+            digest
+        );
+    }
+
+    let mut pipe = unsafe { std::fs::File::from_raw_fd(state.ready_fd) };
+    pipe.write_all(&[1])?;
+    log::info!(target: "Server", "Handover: took over from pid {}", state.old_pid);
+    Ok(())
+}
+
+/// A digest of the currently active (banned) entries of the IP ban list
+pub fn ban_list_digest() -> Result<String, Error> {
+    use secp256k1::hashes::{sha256, Hash, HashEngine};
+
+    let mut banned: Vec<_> = crate::dump_ip_data()?
+        .into_iter()
+        .filter(|(_, data)| data.is_banned())
+        .collect();
+    banned.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+
+    let mut engine = sha256::Hash::engine();
+    for (ip, data) in banned.iter() {
+        engine.input(&ip.0);
+        engine.input(&data.ban_until.to_be_bytes());
+    }
+    let hash = sha256::Hash::from_engine(engine);
+    Ok(hex::encode(hash.to_byte_array()))
+}
+
+/// Whether writes are currently refused because we are handing over
+pub fn is_handing_over() -> bool {
+    GLOBALS.handing_over.load(Ordering::Relaxed)
+}
+
+/// Hand the listeners over to a freshly executed copy of our binary.
+///
+/// On success returns the new process's pid; the caller must then stop accepting on the
+/// listeners and drain. On failure writes are accepted again and the caller should carry on.
+pub async fn hand_over(listen_fds: &[RawFd], config_path: &str) -> Result<u32, Error> {
+    GLOBALS.handing_over.store(true, Ordering::SeqCst);
+
+    match hand_over_inner(listen_fds, config_path).await {
+        Ok(pid) => Ok(pid),
+        Err(e) => {
+            GLOBALS.handing_over.store(false, Ordering::SeqCst);
+            Err(e)
+        }
+    }
+}
+
+async fn hand_over_inner(listen_fds: &[RawFd], config_path: &str) -> Result<u32, Error> {
+    // Make sure everything we wrote is on disk before the new process opens the store
+    GLOBALS.store.get().unwrap().sync()?;
+
+    let (ready_read, ready_write) = pipe()?;
+
+    let state = HandoverState {
+        old_pid: std::process::id(),
+        config_path: config_path.to_owned(),
+        listen_fds: listen_fds.to_vec(),
+        ready_fd: ready_write.as_raw_fd(),
+        ban_list_digest: ban_list_digest()?,
+    };
+
+    let exe = match std::env::args_os().next() {
+        Some(arg0) => std::path::PathBuf::from(arg0),
+        None => std::env::current_exe()?,
+    };
+
+    // Let the child inherit the listeners and the write end of the pipe, only while spawning
+    for fd in listen_fds.iter() {
+        set_cloexec(*fd, false)?;
+    }
+    set_cloexec(ready_write.as_raw_fd(), false)?;
+    let spawned = std::process::Command::new(&exe)
+        .arg(config_path)
+        .env(HANDOVER_VAR, serde_json::to_string(&state)?)
+        .spawn();
+    for fd in listen_fds.iter() {
+        set_cloexec(*fd, true)?;
+    }
+    drop(ready_write);
+    let mut child = spawned?;
+    let pid = child.id();
+
+    log::info!(target: "Server", "Handover: started {} as pid {}", exe.display(), pid);
+
+    // Wait for the child to say it is ready. If it exits first the pipe closes and we
+    // read nothing.
+    let mut ready_read = ready_read;
+    let wait = tokio::task::spawn_blocking(move || -> std::io::Result<usize> {
+        let mut byte = [0_u8; 1];
+        ready_read.read(&mut byte)
+    });
+    let failure = match tokio::time::timeout(HANDOVER_READY_TIMEOUT, wait).await {
+        Ok(Ok(Ok(1))) => None,
+        Ok(Ok(Ok(_))) => Some("new process exited before it was ready".to_owned()),
+        Ok(Ok(Err(e))) => Some(format!("{e}")),
+        Ok(Err(e)) => Some(format!("{e}")),
+        Err(_) => Some("new process did not become ready in time".to_owned()),
+    };
+
+    if let Some(why) = failure {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(ChorusError::Handover(why).into());
+    }
+
+    // Reap the child when it eventually exits (after we do, it will be reparented anyway)
+    std::thread::spawn(move || {
+        let _ = child.wait();
+    });
+
+    Ok(pid)
+}
+
+fn pipe() -> Result<(std::fs::File, std::fs::File), Error> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    unsafe {
+        Ok((
+            std::fs::File::from_raw_fd(fds[0]),
+            std::fs::File::from_raw_fd(fds[1]),
+        ))
+    }
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> Result<(), Error> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let flags = if cloexec {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
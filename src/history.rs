@@ -0,0 +1,192 @@
+//! Reading the stored events a REQ asks for a page at a time
+//!
+//! Rather than finding every matching event at once, each page asks the store for a
+//! bounded number of events per filter, from where the last page left off. The next page
+//! is only read once the client has taken in the last one, so a slow reader holds back
+//! the reads (and a gone one stops them).
+//!
+//! Events are served newest first, or for filters with `since_seen` in the order we
+//! received them. A page is cut where the first of its filters ran out of events it was
+//! allowed to read, since there may be more of its events past that point. Everything up
+//! to there is served (in order), and the next page starts from there, skipping the events
+//! at that very point that were served already.
+//!
+//! A filter's `limit` still applies to that filter alone: it is served its newest (or
+//! first received) `limit` matches, whether they come from its own scan or another's.
+
+use crate::error::Error;
+use crate::filter::ChorusFilter;
+use crate::globals::GLOBALS;
+use pocket_db::ScreenResult;
+use pocket_types::{Event, Id};
+use std::collections::HashSet;
+
+/// Events read per filter per page (at least)
+pub const PAGE_EVENTS: usize = 500;
+
+/// The stored events matching some of a REQ's filters, a page at a time. Filters with
+/// `since_seen` must be paged apart from the others.
+pub struct History<'a> {
+    filters: Vec<&'a ChorusFilter>,
+
+    // How many more events each filter is served (what is left of its limit)
+    remaining: Vec<usize>,
+
+    // Whether events are served in the order we received them, not newest first
+    by_seen: bool,
+
+    // Where the next page starts in serving order (see `position`), and the events
+    // there that were already served
+    from: u64,
+    served_at_from: HashSet<Id>,
+
+    done: bool,
+
+    /// Whether any matching events were redacted by the screen so far
+    pub redacted: bool,
+}
+
+impl<'a> History<'a> {
+    pub fn new(filters: Vec<&'a ChorusFilter>) -> History<'a> {
+        let remaining = filters.iter().map(|f| f.filter.limit() as usize).collect();
+        let by_seen = filters.iter().any(|f| f.since_seen.is_some());
+        History {
+            filters,
+            remaining,
+            by_seen,
+            from: 0,
+            served_at_from: HashSet::new(),
+            done: false,
+            redacted: false,
+        }
+    }
+
+    // Where an event comes in serving order, lowest first
+    fn position(&self, event: &Event) -> Result<u64, Error> {
+        if self.by_seen {
+            Ok(crate::first_seen::seen_at(event.id())?.unwrap_or(u64::MAX))
+        } else {
+            Ok(u64::MAX - event.created_at().as_u64())
+        }
+    }
+
+    // A filter limited to `limit` events from `from` on
+    fn paged(&self, filter: &ChorusFilter, limit: usize) -> Result<ChorusFilter, Error> {
+        let mut json = filter.json.clone();
+        let _ = json.insert("limit".to_owned(), limit.into());
+        if !self.by_seen && self.from > 0 {
+            let until = u64::MAX - self.from;
+            if json
+                .get("until")
+                .and_then(|v| v.as_u64())
+                .is_none_or(|u| u > until)
+            {
+                let _ = json.insert("until".to_owned(), until.into());
+            }
+        }
+        let mut paged = filter.with_json(json)?;
+        if self.by_seen {
+            paged.since_seen = paged.since_seen.max(Some(self.from));
+        }
+        Ok(paged)
+    }
+
+    /// The next page of events, in serving order, or None once all are served
+    pub fn next_page<F>(&mut self, screen: F) -> Result<Option<Vec<&'static Event>>, Error>
+    where
+        F: Fn(&Event) -> ScreenResult,
+    {
+        if self.done {
+            return Ok(None);
+        }
+
+        // A page never asks for fewer than `allow_scrape_if_limited_to`, so a filter that
+        // is only allowed by its limit still is, and one that is not still is not
+        let page_events =
+            PAGE_EVENTS.max(GLOBALS.config.read().allow_scrape_if_limited_to as usize + 1);
+
+        // What each filter may read this page, and enough to get past what was served
+        let mut members: Vec<usize> = Vec::new();
+        let mut asked: Vec<usize> = Vec::new();
+        let mut paged: Vec<ChorusFilter> = Vec::new();
+        for (i, filter) in self.filters.iter().enumerate() {
+            if self.remaining[i] == 0 {
+                continue;
+            }
+            let limit = self.remaining[i].min(page_events) + self.served_at_from.len();
+            members.push(i);
+            asked.push(limit);
+            paged.push(self.paged(filter, limit)?);
+        }
+        if paged.is_empty() {
+            self.done = true;
+            return Ok(None);
+        }
+
+        let plan = crate::planner::plan(&paged)?;
+        let (found, redacted) = crate::planner::find_events(&paged, &plan, &screen)?;
+        self.redacted |= redacted;
+
+        let mut found: Vec<(u64, &'static Event)> = found
+            .into_iter()
+            .map(|e| Ok((self.position(e)?, e)))
+            .collect::<Result<_, Error>>()?;
+        found.sort_by(|(a, x), (b, y)| a.cmp(b).then(x.id().cmp(&y.id())));
+
+        // Cut the page where the first filter may have more events than it read. The
+        // ones it read are the first in serving order of all its matches, and all
+        // of them were found (by its own scan if by no other).
+        let mut cut: Option<u64> = None;
+        for (paged, asked) in paged.iter().zip(asked.iter()) {
+            let mut matched = 0;
+            for (position, event) in found.iter() {
+                if paged.event_matches(event)? {
+                    matched += 1;
+                    if matched == *asked {
+                        cut = Some(cut.map_or(*position, |c| c.min(*position)));
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Each filter is served its next matches, up to its limit
+        let mut page: Vec<(u64, &'static Event)> = Vec::new();
+        let mut wanted: HashSet<Id> = HashSet::new();
+        for (paged, i) in paged.iter().zip(members.iter()) {
+            for (position, event) in found.iter() {
+                if self.remaining[*i] == 0 || cut.is_some_and(|c| *position > c) {
+                    break;
+                }
+                if *position == self.from && self.served_at_from.contains(&event.id()) {
+                    continue;
+                }
+                if paged.event_matches(event)? {
+                    self.remaining[*i] -= 1;
+                    if wanted.insert(event.id()) {
+                        page.push((*position, event));
+                    }
+                }
+            }
+        }
+        page.sort_by(|(a, x), (b, y)| a.cmp(b).then(x.id().cmp(&y.id())));
+
+        match (cut, page.last()) {
+            (Some(cut), Some(_)) => {
+                if cut != self.from {
+                    self.from = cut;
+                    self.served_at_from.clear();
+                }
+                for (position, event) in page.iter() {
+                    if *position == cut {
+                        let _ = self.served_at_from.insert(event.id());
+                    }
+                }
+            }
+            // Every filter read all it had
+            _ => self.done = true,
+        }
+
+        Ok(Some(page.into_iter().map(|(_, e)| e).collect()))
+    }
+}
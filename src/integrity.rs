@@ -0,0 +1,304 @@
+//! Checking the store's indexes, and repairing what can be repaired
+//!
+//! pocket keeps events in an append-only file, with LMDB indexes of their offsets by id
+//! (`i`), by created_at (`ci`), by author (`ac`) and by tag (`tc`), and the ids of deleted
+//! events (`deleted_ids`). We check them two ways. We query through each of them: every
+//! event must be found by the queries that use them, and every event they return must
+//! match the query. And we walk every entry of each: it must lead to a stored event that
+//! its key describes, and no deleted id may still be stored. An event an index misses is
+//! stored again, which indexes it afresh. Entries that lead nowhere or to the wrong event
+//! can only be fixed by rebuilding the store (`chorus_compress`).
+//!
+//! Repairs copy every event they touch to `repaired-events.jsonl` in the data directory
+//! first, so nothing is lost even if it cannot be stored again.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::VerifyReport;
+use pocket_db::heed::types::Bytes;
+use pocket_db::heed::{Database, RoTxn};
+use pocket_db::ScreenResult;
+use pocket_types::{Event, Filter, Id};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+/// What `repair()` did
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Damaged events removed
+    pub removed: usize,
+
+    /// Events stored again, to index them
+    pub reindexed: usize,
+
+    /// Stale tag index entries removed
+    pub stale_removed: usize,
+
+    /// Events that could not be stored again (they are in `repaired-events.jsonl`)
+    pub failed: Vec<String>,
+}
+
+// The filters each event must be found by, one for each index
+fn queries(event: &Event) -> Result<Vec<Value>, Error> {
+    let created_at = event.created_at().as_u64();
+    let author = event.pubkey().as_hex_string();
+    let kind = event.kind().as_u16();
+
+    let mut queries = vec![
+        json!({ "since": created_at, "until": created_at }),
+        json!({ "authors": [author], "since": created_at, "until": created_at }),
+        json!({ "kinds": [kind], "since": created_at, "until": created_at }),
+        json!({ "authors": [author], "kinds": [kind], "since": created_at, "until": created_at }),
+    ];
+    for mut tag in event.tags()?.iter() {
+        let Some(name) = tag.next() else {
+            continue;
+        };
+        if name.len() != 1 || !name[0].is_ascii_alphabetic() {
+            continue;
+        }
+        let Some(value) = tag.next() else {
+            continue;
+        };
+        let (Ok(name), Ok(value)) = (std::str::from_utf8(name), std::str::from_utf8(value)) else {
+            continue;
+        };
+        let mut query = json!({ "since": created_at, "until": created_at });
+        query[format!("#{name}")] = json!([value]);
+        queries.push(query);
+    }
+    Ok(queries)
+}
+
+/// Query through each of pocket's indexes for each event (see the module docs), adding
+/// what is wrong to `report`
+pub fn check_indexes(events: &[&Event], report: &mut VerifyReport) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+
+    for event in events.iter() {
+        let id = event.id();
+        let mut found_everywhere = true;
+
+        if store.get_event_by_id(id)?.is_none() {
+            report.problems.push(format!(
+                "Event {} cannot be found by id",
+                id.as_hex_string()
+            ));
+            found_everywhere = false;
+        }
+
+        for query in queries(event)? {
+            let json = query.to_string();
+            let mut buffer = vec![0_u8; json.len() * 2 + 256];
+            let (_incount, _outcount, filter) = Filter::from_json(json.as_bytes(), &mut buffer)?;
+            let (found, _redacted) =
+                store.find_events(filter, true, 0, 0, |_| ScreenResult::Match)?;
+
+            if !found.iter().any(|e| e.id() == id) {
+                report.problems.push(format!(
+                    "Event {} is not found by {json}",
+                    id.as_hex_string()
+                ));
+                found_everywhere = false;
+            }
+            for other in found.iter() {
+                if !filter.event_matches(other)? {
+                    report.problems.push(format!(
+                        "{json} leads to event {}, which does not match it",
+                        other.id().as_hex_string()
+                    ));
+                    report.mismatched_entries += 1;
+                }
+            }
+        }
+
+        if !found_everywhere && !report.damaged.contains(&id) && !report.unindexed.contains(&id) {
+            report.unindexed.push(id);
+        }
+    }
+
+    Ok(())
+}
+
+// pocket's indexes of event offsets, other than by id
+const INDEXES: [&str; 3] = ["ci", "ac", "tc"];
+
+fn open_index(txn: &RoTxn, name: &'static str) -> Result<Database<Bytes, Bytes>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    store
+        .env()
+        .open_database::<Bytes, Bytes>(txn, Some(name))?
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(name)))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+// Whether an index entry's key describes the event it leads to. Keys hold created_at
+// (ascending or descending), after the author or tag they index by.
+fn key_describes(index: &str, key: &[u8], event: &Event) -> Result<bool, Error> {
+    let created_at = event.created_at().as_u64();
+    let when = contains(key, &created_at.to_be_bytes())
+        || contains(key, &(u64::MAX - created_at).to_be_bytes());
+    Ok(match index {
+        "ci" => when,
+        "ac" => when && key.starts_with(event.pubkey().as_slice()),
+        _ => {
+            let mut letters: Vec<u8> = Vec::new();
+            for mut tag in event.tags()?.iter() {
+                if let Some(name) = tag.next() {
+                    if name.len() == 1 {
+                        letters.push(name[0]);
+                    }
+                }
+            }
+            when && key.first().is_some_and(|letter| letters.contains(letter))
+        }
+    })
+}
+
+/// Walk every entry of pocket's indexes (see the module docs), adding what is wrong to
+/// `report`
+pub fn walk_indexes(report: &mut VerifyReport) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let txn = store.read_txn()?;
+
+    // Where each stored event is, by the id index, which must agree with the event there
+    let ids = open_index(&txn, "i")?;
+    let mut stored: HashMap<Vec<u8>, Id> = HashMap::new();
+    for entry in ids.iter(&txn)? {
+        let (key, offset) = entry?;
+        let event = match <[u8; 32]>::try_from(key) {
+            Ok(id) => store.get_event_by_id(Id::from_bytes(id))?,
+            Err(_) => None,
+        };
+        match event {
+            Some(event) if event.id().as_slice() == key => {
+                let _ = stored.insert(offset.to_vec(), event.id());
+            }
+            _ => {
+                report.problems.push(format!(
+                    "Id index entry {} does not lead to that event",
+                    hex::encode(key)
+                ));
+                report.mismatched_entries += 1;
+            }
+        }
+    }
+
+    for index in INDEXES {
+        for entry in open_index(&txn, index)?.iter(&txn)? {
+            let (key, offset) = entry?;
+            let event = match stored.get(offset) {
+                Some(id) => store.get_event_by_id(*id)?,
+                None => None,
+            };
+            let Some(event) = event else {
+                report.problems.push(format!(
+                    "{index} index entry {} leads to no stored event",
+                    hex::encode(key)
+                ));
+                report.mismatched_entries += 1;
+                continue;
+            };
+            if !key_describes(index, key, event)? {
+                report.problems.push(format!(
+                    "{index} index entry {} leads to event {}, which it does not describe",
+                    hex::encode(key),
+                    event.id().as_hex_string()
+                ));
+                report.mismatched_entries += 1;
+            }
+        }
+    }
+
+    for entry in open_index(&txn, "deleted_ids")?.iter(&txn)? {
+        let (key, _) = entry?;
+        if ids.get(&txn, key)?.is_some() {
+            report.problems.push(format!(
+                "Deleted event {} is still stored",
+                hex::encode(key)
+            ));
+            report.mismatched_entries += 1;
+        }
+    }
+
+    Ok(())
+}
+
+// Keep a copy of an event we are about to touch
+fn save_copy(event: &Event) -> Result<(), Error> {
+    let path = Path::new(&GLOBALS.config.read().data_directory).join("repaired-events.jsonl");
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&event.as_json()?)?;
+    file.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Repair what `report` found: remove damaged events, store unindexed ones again and
+/// remove stale tag index entries. Index entries leading to the wrong event are left
+/// for rebuilding the store.
+pub fn repair(report: &VerifyReport) -> Result<RepairReport, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let max_map_size = GLOBALS.config.read().lmdb_max_map_size;
+    let mut repairs: RepairReport = Default::default();
+
+    for id in report.damaged.iter() {
+        {
+            let _reading = crate::map_size::reading();
+            if let Some(event) = store.get_event_by_id(*id)? {
+                save_copy(event)?;
+            }
+        }
+        crate::remove_event(*id)?;
+        repairs.removed += 1;
+    }
+
+    for id in report.unindexed.iter() {
+        // A copy of its own, since removing it lets its space go
+        let json = {
+            let _reading = crate::map_size::reading();
+            match store.get_event_by_id(*id)? {
+                Some(event) => {
+                    save_copy(event)?;
+                    event.as_json()?
+                }
+                None => continue,
+            }
+        };
+        let mut buffer = vec![0_u8; json.len() + 256];
+        let (_size, event) = Event::from_json(&json, &mut buffer)?;
+
+        // It keeps when we first received it. Storing it again is not receiving it, so
+        // it is not passed on again either (that only happens as events arrive).
+        let seen = crate::first_seen::seen_at(*id)?;
+        crate::remove_event(*id)?;
+        if let Some(seen) = seen {
+            crate::map_size::write(store, max_map_size, || {
+                crate::first_seen::record_at(event, seen)
+            })?;
+        }
+        match crate::store_event(event) {
+            Ok(_) => repairs.reindexed += 1,
+            Err(e) => {
+                crate::first_seen::forget(*id)?;
+                repairs.failed.push(format!(
+                    "Event {} could not be stored again: {e}",
+                    id.as_hex_string()
+                ))
+            }
+        }
+    }
+
+    if report.stale_index_entries > 0 {
+        repairs.stale_removed = crate::tag_index::remove_stale()?;
+    }
+
+    Ok(repairs)
+}
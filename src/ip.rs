@@ -1,9 +1,17 @@
+use crate::error::ChorusError;
+use crate::globals::GLOBALS;
 use pocket_types::Time;
 use speedy::{Readable, Writable};
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
+/// Where connections on a unix domain socket come from, as far as we are concerned. No TCP
+/// peer has port 0, so `HashedPeer::new` can tell them apart.
+pub const UNIX_SOCKET_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+// (the hashed address, whether it is a loopback address, whether it stands for every
+// peer on a unix domain socket)
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
-pub struct HashedIp(pub [u8; 20], bool);
+pub struct HashedIp(pub [u8; 20], bool, bool);
 
 impl std::fmt::Display for HashedIp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -21,19 +29,26 @@ impl HashedIp {
         HashedIp(
             tag.as_bytes()[..20].try_into().unwrap(),
             ip_addr.is_loopback(),
+            false,
         )
     }
 
     pub fn from_bytes(bytes: &[u8]) -> HashedIp {
-        HashedIp(bytes[0..20].try_into().unwrap(), false)
+        HashedIp(bytes[0..20].try_into().unwrap(), false, false)
     }
 
     pub fn is_loopback(&self) -> bool {
         self.1
     }
+
+    /// Whether this stands for the peers on a unix domain socket, which have no address of
+    /// their own. They share it, so they are never banned by it.
+    pub fn is_unix_socket(&self) -> bool {
+        self.2
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct HashedPeer(pub HashedIp, pub u16);
 
 impl std::fmt::Display for HashedPeer {
@@ -44,7 +59,8 @@ impl std::fmt::Display for HashedPeer {
 
 impl HashedPeer {
     pub fn new(peer_addr: SocketAddr) -> HashedPeer {
-        let hashed_ip = HashedIp::new(peer_addr.ip());
+        let mut hashed_ip = HashedIp::new(peer_addr.ip());
+        hashed_ip.2 = peer_addr == UNIX_SOCKET_PEER;
         HashedPeer(hashed_ip, peer_addr.port())
     }
 
@@ -78,83 +94,199 @@ pub enum SessionExit {
     Timeout,
 }
 
+/// How long the escalating bans last, in seconds: a minute, ten minutes, an hour, a day
+pub const BAN_STEPS: [u64; 4] = [60, 600, 3600, 86400];
+
+/// Something an IP address did that counts against its reputation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    // An event with a bad id or signature
+    InvalidSignature,
+
+    // A failed AUTH
+    AuthFailure,
+
+    // Hitting a rate limit
+    RateLimit,
+
+    // Something that is not valid nostr (or HTTP, or WebSocket)
+    ProtocolError,
+}
+
+impl Violation {
+    /// The violation an error represents, if any
+    pub fn of(error: &ChorusError) -> Option<Violation> {
+        match error {
+            ChorusError::EventIdMismatch(_, _) | ChorusError::EventIsInvalid(_) => {
+                Some(Violation::InvalidSignature)
+            }
+            ChorusError::AuthFailure(_)
+            | ChorusError::BlossomAuthFailure(_)
+            | ChorusError::ManagementAuthFailure(_) => Some(Violation::AuthFailure),
+            ChorusError::RateLimited(_) => Some(Violation::RateLimit),
+            // (counted when the ban was made, see `rate_limit::check`)
+            ChorusError::RateLimitExceeded => None,
+            ChorusError::BadRequest(_)
+            | ChorusError::Crypto(_)
+            | ChorusError::FromHex(_)
+            | ChorusError::FromUtf8(_)
+            | ChorusError::FilterValueNotHex(_)
+            | ChorusError::InvalidFilter(_)
+            | ChorusError::PocketType(_)
+            | ChorusError::Utf8(_)
+            | ChorusError::Utf8Error
+            | ChorusError::WebsocketProtocol(_) => Some(Violation::ProtocolError),
+            _ => None,
+        }
+    }
+
+    /// How much it adds to the score
+    pub fn points(&self) -> f32 {
+        match *self {
+            Violation::InvalidSignature => 2.0,
+            Violation::AuthFailure => 1.0,
+            Violation::RateLimit => 0.5,
+            Violation::ProtocolError => 1.0,
+        }
+    }
+}
+
 // Long term reputation of an IP address
-// The values are running totals, updating with (9/10) of old and (1/10) of new.
 //
-// Used to determine ban time multiplier from short-term violations
+// Violations add to its score, which halves every `ip_reputation_half_life_seconds`.
+// Each time the score reaches `ip_ban_threshold` it is banned for the next of BAN_STEPS
+// and the score starts over. A step is forgiven for each day without such a ban.
 #[derive(Debug, Clone, Default, Readable, Writable)]
 pub struct IpReputation {
-    pub good: f32,
-    pub errored: f32,
-    pub too_many_errors: f32,
-    pub timed_out: f32,
+    // The score as of `updated`
+    pub score: f32,
+
+    // When the score was last brought up to date (unix seconds)
+    pub updated: u64,
+
+    // How many of BAN_STEPS it has been through (less those forgiven)
+    pub bans: u8,
+
+    // When it was last banned for its score (unix seconds)
+    pub last_ban: u64,
 }
 
 impl IpReputation {
-    pub fn update(&mut self, session_exit: SessionExit) {
-        // Lessen the running totals
-        self.good *= 9.0 / 10.0;
-        self.errored *= 9.0 / 10.0;
-        self.too_many_errors *= 9.0 / 10.0;
-        self.timed_out *= 9.0 / 10.0;
-
-        match session_exit {
-            SessionExit::Ok => self.good += 1.0,
-            SessionExit::ErrorExit => self.errored += 1.0,
-            SessionExit::TooManyErrors => self.too_many_errors += 1.0,
-            SessionExit::Timeout => self.timed_out += 1.0,
-        };
+    /// The score at `now`
+    pub fn score_at(&self, now: u64, half_life: u64) -> f32 {
+        if half_life == 0 {
+            return 0.0;
+        }
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        (self.score as f64 * 0.5_f64.powf(elapsed / half_life as f64)) as f32
     }
 
-    pub fn ban_multiplier(&self) -> f32 {
-        let good_endings = 1.0 + self.good + (self.errored / 2.0);
+    /// How many of BAN_STEPS it has been through at `now`, after forgiveness
+    pub fn bans_at(&self, now: u64) -> u8 {
+        let forgiven = now.saturating_sub(self.last_ban) / BAN_STEPS[BAN_STEPS.len() - 1];
+        self.bans.saturating_sub(forgiven.min(u8::MAX as u64) as u8)
+    }
 
-        let bad_endings = 1.0 + self.timed_out + self.too_many_errors + (self.errored / 2.0);
+    /// Add `points` at `now`. Returns how long to ban for, if that reached the threshold.
+    pub fn add(&mut self, points: f32, now: u64, half_life: u64, threshold: u32) -> Option<u64> {
+        let day = BAN_STEPS[BAN_STEPS.len() - 1];
+        let forgiven = now.saturating_sub(self.last_ban) / day;
+        if self.bans > 0 && forgiven > 0 {
+            self.bans = self.bans_at(now);
+            self.last_ban += forgiven * day;
+        }
+        self.score = self.score_at(now, half_life) + points;
+        self.updated = now;
 
-        bad_endings / good_endings
+        if threshold == 0 || self.score < threshold as f32 {
+            return None;
+        }
+        let seconds = BAN_STEPS[(self.bans as usize).min(BAN_STEPS.len() - 1)];
+        self.bans = (self.bans + 1).min(BAN_STEPS.len() as u8);
+        self.last_ban = now;
+        self.score = 0.0;
+        Some(seconds)
     }
 }
 
-// Memory-only short-term record of IP handling
+// Short-term record of IP handling, and its long term reputation
 #[derive(Debug, Clone, Default, Readable, Writable)]
 pub struct IpData {
     pub ban_until: u64,
     pub reputation: IpReputation,
 }
 
+// As IpData was stored before reputations were scores (only its ban is kept)
+#[derive(Readable)]
+struct LegacyIpData {
+    ban_until: u64,
+    _good: f32,
+    _errored: f32,
+    _too_many_errors: f32,
+    _timed_out: f32,
+}
+
+const LEGACY_LEN: usize = 8 + 4 * 4;
+
 impl IpData {
+    /// Read IpData as stored (in either layout)
+    pub fn from_bytes(bytes: &[u8]) -> Result<IpData, speedy::Error> {
+        if bytes.len() == LEGACY_LEN {
+            let legacy = LegacyIpData::read_from_buffer(bytes)?;
+            return Ok(IpData {
+                ban_until: legacy.ban_until,
+                reputation: Default::default(),
+            });
+        }
+        IpData::read_from_buffer(bytes)
+    }
+
+    /// Account for a session that has closed, having earned `points` of violations.
+    /// Returns how long it is banned for from now.
     pub fn update_on_session_close(
         &mut self,
         session_exit: SessionExit,
+        points: f32,
         minimum_ban_seconds: u64,
     ) -> u64 {
-        // Update reputation
-        self.reputation.update(session_exit);
+        // Errors the session was closed for were counted as they happened
+        let points = match session_exit {
+            SessionExit::ErrorExit => points + Violation::ProtocolError.points(),
+            _ => points,
+        };
+        self.add_violations(points, minimum_ban_seconds)
+    }
+
+    /// Add `points` of violations now, banning it for at least `minimum_ban_seconds` (longer
+    /// if that earns it a ban). Returns how long it is banned for from now.
+    pub fn add_violations(&mut self, points: f32, minimum_ban_seconds: u64) -> u64 {
+        let (half_life, threshold) = {
+            let config = GLOBALS.config.read();
+            (
+                config.ip_reputation_half_life_seconds,
+                config.ip_ban_threshold,
+            )
+        };
+        let now = Time::now().as_u64();
 
-        // Compute ban_until
-        let mut until = Time::now();
-        let seconds = self.ban_seconds(session_exit, minimum_ban_seconds);
-        until = until + seconds;
+        let mut seconds = minimum_ban_seconds;
+        if let Some(ban) = self.reputation.add(points, now, half_life, threshold) {
+            seconds = seconds.max(ban);
+        }
+        self.ban_until = self.ban_until.max(now + seconds);
 
-        self.ban_until = self.ban_until.max(until.as_u64());
+        self.ban_until - now
+    }
 
-        seconds
+    /// Lift any ban and reset the score, but remember how far bans have escalated
+    pub fn pardon(&mut self) {
+        self.ban_until = 0;
+        self.reputation.score = 0.0;
     }
 
     pub fn is_banned(&self) -> bool {
         Time::from_u64(self.ban_until) > Time::now()
     }
-
-    fn ban_seconds(&self, session_exit: SessionExit, minimum_ban_seconds: u64) -> u64 {
-        let multiplier = self.reputation.ban_multiplier();
-
-        match session_exit {
-            SessionExit::Ok => minimum_ban_seconds,
-            SessionExit::Timeout => minimum_ban_seconds,
-            SessionExit::ErrorExit => minimum_ban_seconds + (2.0 * multiplier) as u64,
-            SessionExit::TooManyErrors => minimum_ban_seconds + (5.0 * multiplier) as u64,
-        }
-    }
 }
 
 #[cfg(test)]
@@ -168,5 +300,85 @@ mod test {
 
         let socketaddr = std::net::SocketAddr::new(ipaddr, 80);
         println!("HashedPEER={}", HashedPeer::new(socketaddr));
+
+        // Unix domain socket peers are told apart from TCP ones on the same address
+        assert!(!HashedPeer::new(socketaddr).ip().is_unix_socket());
+        let unix = HashedPeer::new(UNIX_SOCKET_PEER).ip();
+        assert!(unix.is_unix_socket());
+        assert_ne!(unix, HashedPeer::new(socketaddr).ip());
+    }
+
+    const NOW: u64 = 1_700_000_000;
+    const DAY: u64 = 86400;
+
+    #[test]
+    fn test_decay() {
+        let mut reputation = IpReputation::default();
+        assert_eq!(reputation.add(8.0, NOW, 3600, 10), None);
+        assert_eq!(reputation.score_at(NOW, 3600), 8.0);
+        assert_eq!(reputation.score_at(NOW + 3600, 3600), 4.0);
+        assert_eq!(reputation.score_at(NOW + 7200, 3600), 2.0);
+        assert!((reputation.score_at(NOW + 1800, 3600) - 8.0 / 2.0_f32.sqrt()).abs() < 0.001);
+
+        // Decayed before the next violation is added
+        assert_eq!(reputation.add(1.0, NOW + 3600, 3600, 10), None);
+        assert_eq!(reputation.score, 5.0);
+
+        // With no half life nothing is remembered
+        assert_eq!(reputation.score_at(NOW + 3600, 0), 0.0);
+    }
+
+    #[test]
+    fn test_escalation() {
+        let mut reputation = IpReputation::default();
+
+        // Just below the threshold, then reaching it
+        assert_eq!(reputation.add(9.5, NOW, 3600, 10), None);
+        assert_eq!(reputation.add(0.5, NOW, 3600, 10), Some(60));
+        assert_eq!(reputation.score, 0.0);
+
+        // Each ban is longer, up to a day
+        assert_eq!(reputation.add(10.0, NOW + 1, 3600, 10), Some(600));
+        assert_eq!(reputation.add(10.0, NOW + 2, 3600, 10), Some(3600));
+        assert_eq!(reputation.add(10.0, NOW + 3, 3600, 10), Some(DAY));
+        assert_eq!(reputation.add(10.0, NOW + 4, 3600, 10), Some(DAY));
+        assert_eq!(reputation.bans, 4);
+
+        // A step is forgiven for each full day without a ban
+        assert_eq!(reputation.bans_at(NOW + 4 + DAY - 1), 4);
+        assert_eq!(reputation.bans_at(NOW + 4 + DAY), 3);
+        assert_eq!(reputation.bans_at(NOW + 4 + 2 * DAY), 2);
+        assert_eq!(
+            reputation.add(10.0, NOW + 4 + 2 * DAY, 3600, 10),
+            Some(3600)
+        );
+        assert_eq!(reputation.bans_at(NOW + 4 + 10 * DAY), 0);
+        assert_eq!(reputation.add(10.0, NOW + 4 + 10 * DAY, 3600, 10), Some(60));
+
+        // A threshold of 0 never bans
+        let mut reputation = IpReputation::default();
+        assert_eq!(reputation.add(1000.0, NOW, 3600, 0), None);
+    }
+
+    #[test]
+    fn test_legacy_ip_data() {
+        let mut bytes = 1_700_000_600_u64.to_le_bytes().to_vec();
+        for value in [1.0_f32, 2.0, 3.0, 4.0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        let ip_data = IpData::from_bytes(&bytes).unwrap();
+        assert_eq!(ip_data.ban_until, 1_700_000_600);
+        assert_eq!(ip_data.reputation.score, 0.0);
+
+        let mut ip_data = IpData {
+            ban_until: NOW,
+            reputation: Default::default(),
+        };
+        let _ = ip_data.reputation.add(9.0, NOW, 3600, 10);
+        let bytes = ip_data.write_to_vec().unwrap();
+        let read = IpData::from_bytes(&bytes).unwrap();
+        assert_eq!(read.ban_until, NOW);
+        assert_eq!(read.reputation.score, 9.0);
+        assert_eq!(read.reputation.updated, NOW);
     }
 }
@@ -0,0 +1,168 @@
+//! Export and import of events as line-delimited JSON (one event per line)
+//!
+//! An export may instead give each event with when we first received it, as
+//! `{"seen_at":<timestamp or null>,"event":<event>}`. Import takes
+//! either form, but the events it stores are received when it stores them.
+//!
+//! Export only reads, so it can run against the store of a running relay. Import goes
+//! through `crate::store_event` like any event a client sends, so every index is built,
+//! and stores each event in its own transactions, so it never holds the store for long.
+//! Events we already have are skipped by id before anything else is done with them, so
+//! an interrupted import can simply be run again.
+
+use crate::error::{ChorusError, Error};
+use crate::filter::ChorusFilter;
+use crate::globals::GLOBALS;
+use crate::history::History;
+use crate::walk::Walk;
+use pocket_db::ScreenResult;
+use pocket_types::Event;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::io::{BufRead, Write};
+
+// Write one exported line
+fn write_line<W: Write>(event: &Event, seen: bool, out: &mut W) -> Result<(), Error> {
+    if seen {
+        let seen_at = crate::first_seen::seen_at(event.id())?;
+        out.write_all(format!(r#"{{"seen_at":{},"event":"#, Value::from(seen_at)).as_bytes())?;
+        out.write_all(&event.as_json()?)?;
+        out.write_all(b"}\n")?;
+    } else {
+        out.write_all(&event.as_json()?)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Write every stored event matching `filter_json` (a nostr filter; its `kinds`, `authors`,
+/// `since`, `until` and so on apply, as do `_received_since` and `_received_until`, and
+/// `since_seen` and `until_seen` with `enable_since_seen`) to `out`, one per line, with
+/// when we first received them if `seen`. Returns how many were written.
+///
+/// Events are read a chunk at a time (see `crate::walk` and `crate::history`) and written
+/// as they are read, newest first, or in the order we received them by `since_seen`.
+pub fn export<W: Write>(filter_json: &[u8], seen: bool, out: &mut W) -> Result<usize, Error> {
+    let mut buffer = vec![0_u8; filter_json.len().max(128) * 2];
+    let screen = |_: &Event| -> ScreenResult { ScreenResult::Match };
+    let (_incount, _outcount, chorus_filter) =
+        ChorusFilter::from_json_as_is(filter_json, &mut buffer)?;
+
+    let mut count: usize = 0;
+    if chorus_filter.since_seen.is_some() {
+        let mut history = History::new(vec![&chorus_filter]);
+        while let Some(page) = history.next_page(screen)? {
+            for event in page {
+                write_line(event, seen, out)?;
+                count += 1;
+            }
+        }
+    } else {
+        let mut walk = Walk::new(filter_json)?;
+        while let Some(chunk) = walk.next_chunk(screen)? {
+            for event in chunk {
+                write_line(event, seen, out)?;
+                count += 1;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+// The event of an exported line, which may have when we first received it too
+fn event_json(line: &str) -> Cow<'_, str> {
+    if !line.starts_with(r#"{"seen_at""#) {
+        return Cow::Borrowed(line);
+    }
+    match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(mut map)) => match map.remove("event") {
+            Some(event) => Cow::Owned(event.to_string()),
+            None => Cow::Borrowed(line),
+        },
+        _ => Cow::Borrowed(line),
+    }
+}
+
+/// What happened to the lines of an import
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportReport {
+    /// Events stored
+    pub accepted: usize,
+
+    /// Events we already had
+    pub duplicate: usize,
+
+    /// Lines that are not a valid, correctly signed event
+    pub invalid: usize,
+
+    /// Valid events the store refused (such as deleted ones)
+    pub rejected: usize,
+}
+
+// Parse and verify an event, as an EVENT message would be
+fn verified(line: &[u8], buffer: &mut Vec<u8>) -> Option<()> {
+    buffer.clear();
+    buffer.resize(line.len() + 256, 0);
+    let (_size, event) = Event::from_json(line, buffer).ok()?;
+    let computed = crate::nostr::compute_event_id(&event.as_json().ok()?).ok()?;
+    if computed.as_slice() != event.id().as_slice() || event.verify().is_err() {
+        return None;
+    }
+    Some(())
+}
+
+/// Import events from line-delimited JSON, stopping only for errors of the store itself
+/// (like it being full)
+pub fn import<R: BufRead>(input: R) -> Result<ImportReport, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let mut report: ImportReport = Default::default();
+    let mut buffer: Vec<u8> = Vec::new();
+
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line = event_json(line);
+
+        if verified(line.as_bytes(), &mut buffer).is_none() {
+            log::debug!(target: "Server", "Line {}: not a valid event", n + 1);
+            report.invalid += 1;
+            continue;
+        }
+        let event = unsafe { Event::delineate(&buffer)? };
+
+        let stored = {
+            let _reading = crate::map_size::reading();
+            store.get_event_by_id(event.id())?.is_some()
+        };
+        if stored {
+            report.duplicate += 1;
+            continue;
+        }
+
+        match crate::store_event(event) {
+            Ok(_) => report.accepted += 1,
+            Err(e) => match e.inner {
+                ChorusError::PocketDb(ref pe)
+                    if matches!(pe.inner, pocket_db::InnerError::Duplicate) =>
+                {
+                    report.duplicate += 1
+                }
+                ChorusError::Superseded => report.duplicate += 1,
+                ChorusError::StorageFull
+                | ChorusError::Io(_)
+                | ChorusError::PocketDbHeed(_)
+                | ChorusError::MissingTable(_) => return Err(e),
+                _ => {
+                    log::debug!(target: "Server", "Line {}: {}", n + 1, e);
+                    report.rejected += 1;
+                }
+            },
+        }
+    }
+
+    Ok(report)
+}
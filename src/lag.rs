@@ -0,0 +1,369 @@
+//! Live event delivery lag
+//!
+//! Every accepted event is advertised to connections together with the instant it was
+//! ingested. When a connection forwards it to a subscriber we record how old the event
+//! was when its frame was queued on the websocket and when it was flushed to the socket.
+//! A large queued age means fan-out (matching and screening) is behind; a large gap
+//! between queued and flushed means the socket is backpressured.
+
+use crate::globals::GLOBALS;
+use crate::ip::HashedPeer;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A newly accepted event as advertised to every connection
+#[derive(Debug, Clone)]
+pub struct NewEvent {
+    pub source: Source,
+    pub ingested: Instant,
+}
+
+/// Where to find a newly accepted event
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// Its offset in the store
+    Stored(u64),
+
+    /// The event itself, since ephemeral events are not stored (see ephemeral.rs)
+    Ephemeral(Arc<Vec<u8>>),
+}
+
+// Samples older than this do not count towards the gauges
+const WINDOW: Duration = Duration::from_secs(10);
+
+// Per-connection sample cap, so a busy connection cannot grow without bound
+const MAX_SAMPLES: usize = 1024;
+
+/// Delivery lag percentiles (in milliseconds) over the recent window
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct LagGauges {
+    pub samples: usize,
+    pub queued_p50_ms: f64,
+    pub queued_p99_ms: f64,
+    pub flushed_p50_ms: f64,
+    pub flushed_p99_ms: f64,
+}
+
+// Lags are counted in bins, each `GROWTH` times as wide as the last, the first holding up to
+// `MIN_MS` and the last everything over what the one before holds (about 2.8 hours). A
+// percentile read from the counts is at most `GROWTH` times the true one (and at least it).
+const GROWTH: f64 = 1.05;
+const MIN_MS: f64 = 0.1;
+const BINS: usize = 400;
+
+/// Counts of lags, which (unlike percentiles) can be added up across connections
+#[derive(Debug, Clone, Default)]
+pub struct Sketch {
+    // Empty until something is counted
+    counts: Vec<u32>,
+}
+
+impl Sketch {
+    fn bin(ms: f64) -> usize {
+        if ms <= MIN_MS {
+            0
+        } else {
+            ((ms / MIN_MS).ln() / GROWTH.ln())
+                .ceil()
+                .min((BINS - 1) as f64) as usize
+        }
+    }
+
+    fn add(&mut self, ms: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; BINS];
+        }
+        self.counts[Self::bin(ms)] += 1;
+    }
+
+    fn merge(&mut self, other: &Sketch) {
+        if other.counts.is_empty() {
+            return;
+        }
+        if self.counts.is_empty() {
+            self.counts = vec![0; BINS];
+        }
+        for (count, more) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *more;
+        }
+    }
+
+    // Nearest-rank percentile, as the upper bound of the bin it falls in
+    fn percentile(&self, p: usize) -> f64 {
+        let total: u64 = self.counts.iter().map(|c| *c as u64).sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let rank = (p as u64 * total).div_ceil(100).max(1);
+        let mut cumulative: u64 = 0;
+        for (bin, count) in self.counts.iter().enumerate() {
+            cumulative += *count as u64;
+            if cumulative >= rank {
+                return MIN_MS * GROWTH.powi(bin as i32);
+            }
+        }
+        MIN_MS * GROWTH.powi((BINS - 1) as i32)
+    }
+}
+
+/// Whom a published lag is for. Connections on a unix socket all share one peer, so each
+/// connection also has an id of its own.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Connection {
+    pub id: u64,
+    pub peer: HashedPeer,
+}
+
+impl std::fmt::Display for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} #{}", self.peer, self.id)
+    }
+}
+
+/// A connection's delivery lag over the recent window, as published
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLag {
+    pub gauges: LagGauges,
+    queued: Sketch,
+    flushed: Sketch,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    queued: Duration,
+    flushed: Duration,
+}
+
+/// Recent lag samples for a single connection (across all of its subscriptions)
+#[derive(Debug, Default)]
+pub struct LagTracker {
+    samples: VecDeque<Sample>,
+}
+
+impl LagTracker {
+    pub fn record(&mut self, ingested: Instant, queued: Instant, flushed: Instant) {
+        if self.samples.len() >= MAX_SAMPLES {
+            let _ = self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            at: flushed,
+            queued: queued.saturating_duration_since(ingested),
+            flushed: flushed.saturating_duration_since(ingested),
+        });
+    }
+
+    /// Drop expired samples and compute the gauges (and the sketches they are aggregated
+    /// across connections with)
+    pub fn lag(&mut self) -> ConnectionLag {
+        let now = Instant::now();
+        while let Some(s) = self.samples.front() {
+            if now.saturating_duration_since(s.at) > WINDOW {
+                let _ = self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.samples.is_empty() {
+            return ConnectionLag::default();
+        }
+
+        let mut lag = ConnectionLag::default();
+        let mut queued: Vec<f64> = Vec::with_capacity(self.samples.len());
+        let mut flushed: Vec<f64> = Vec::with_capacity(self.samples.len());
+        for sample in self.samples.iter() {
+            let queued_ms = sample.queued.as_secs_f64() * 1000.0;
+            let flushed_ms = sample.flushed.as_secs_f64() * 1000.0;
+            lag.queued.add(queued_ms);
+            lag.flushed.add(flushed_ms);
+            queued.push(queued_ms);
+            flushed.push(flushed_ms);
+        }
+        queued.sort_by(|a, b| a.total_cmp(b));
+        flushed.sort_by(|a, b| a.total_cmp(b));
+
+        lag.gauges = LagGauges {
+            samples: self.samples.len(),
+            queued_p50_ms: percentile(&queued, 50),
+            queued_p99_ms: percentile(&queued, 99),
+            flushed_p50_ms: percentile(&flushed, 50),
+            flushed_p99_ms: percentile(&flushed, 99),
+        };
+        lag
+    }
+}
+
+// Nearest-rank percentile of already sorted data
+fn percentile(sorted: &[f64], p: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Publish a connection's lag so admin introspection and metrics can see it
+pub fn publish(connection: Connection, lag: ConnectionLag) {
+    let _ = GLOBALS.delivery_lag.insert(connection, lag);
+}
+
+/// Remove a connection's lag (when it closes)
+pub fn unpublish(connection: Connection) {
+    let _ = GLOBALS.delivery_lag.remove(&connection);
+}
+
+/// Aggregate lag across all connections, as percentiles of all of their samples together
+pub fn aggregate() -> LagGauges {
+    combine(GLOBALS.delivery_lag.iter())
+}
+
+fn combine<L>(connections: impl Iterator<Item = L>) -> LagGauges
+where
+    L: Deref<Target = ConnectionLag>,
+{
+    let mut samples: usize = 0;
+    let mut queued = Sketch::default();
+    let mut flushed = Sketch::default();
+    for lag in connections {
+        samples += lag.gauges.samples;
+        queued.merge(&lag.queued);
+        flushed.merge(&lag.flushed);
+    }
+    LagGauges {
+        samples,
+        queued_p50_ms: queued.percentile(50),
+        queued_p99_ms: queued.percentile(99),
+        flushed_p50_ms: flushed.percentile(50),
+        flushed_p99_ms: flushed.percentile(99),
+    }
+}
+
+/// Periodically check aggregate lag and warn if it stays above the configured threshold
+pub async fn monitor() {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut over_since: Option<Instant> = None;
+    let mut warned = false;
+
+    loop {
+        let _ = interval.tick().await;
+
+        let (warn_ms, warn_seconds) = {
+            let config = GLOBALS.config.read();
+            (config.lag_warn_ms, config.lag_warn_seconds)
+        };
+        if warn_ms == 0 {
+            over_since = None;
+            GLOBALS.overloaded.store(false, Ordering::Relaxed);
+            continue;
+        }
+
+        let agg = aggregate();
+        if agg.flushed_p99_ms <= warn_ms as f64 {
+            if warned {
+                log::info!(target: "Server", "Delivery lag recovered (p99 {:.0}ms)", agg.flushed_p99_ms);
+            }
+            over_since = None;
+            warned = false;
+            GLOBALS.overloaded.store(false, Ordering::Relaxed);
+            continue;
+        }
+
+        let since = *over_since.get_or_insert_with(Instant::now);
+        if !warned && since.elapsed() >= Duration::from_secs(warn_seconds) {
+            let culprit = if agg.queued_p99_ms > warn_ms as f64 {
+                "fan-out is behind (events are old before they are even queued)"
+            } else {
+                "socket backpressure (events wait between queueing and flushing)"
+            };
+            log::warn!(
+                target: "Server",
+                "Delivery lag p99 {:.0}ms (queued p99 {:.0}ms) has exceeded {}ms for {}s: {}",
+                agg.flushed_p99_ms,
+                agg.queued_p99_ms,
+                warn_ms,
+                warn_seconds,
+                culprit
+            );
+            warned = true;
+            GLOBALS.overloaded.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tracker(lags_ms: &[u64]) -> LagTracker {
+        let mut tracker = LagTracker::default();
+        let now = Instant::now();
+        for ms in lags_ms {
+            let flushed = now + Duration::from_millis(*ms);
+            tracker.record(now, flushed, flushed);
+        }
+        tracker
+    }
+
+    #[test]
+    fn test_sketch_percentiles() {
+        let lags: Vec<u64> = (1..=1000).map(|n| n * n % 7919).collect();
+        let mut sorted: Vec<f64> = lags.iter().map(|ms| *ms as f64).collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let lag = tracker(&lags).lag();
+        for p in [1, 50, 90, 99, 100] {
+            let exact = percentile(&sorted, p);
+            let sketched = lag.flushed.percentile(p);
+            assert!(
+                sketched >= exact && sketched <= exact * GROWTH,
+                "p{p}: {sketched} for {exact}"
+            );
+        }
+        assert_eq!(lag.gauges.flushed_p99_ms, percentile(&sorted, 99));
+    }
+
+    #[test]
+    fn test_connections_sharing_a_peer() {
+        // Like two clients on a unix socket
+        let peer = HashedPeer::new(crate::ip::UNIX_SOCKET_PEER);
+        let first = Connection {
+            id: u64::MAX - 1,
+            peer,
+        };
+        let second = Connection { id: u64::MAX, peer };
+        publish(first, tracker(&[10; 10]).lag());
+        publish(second, tracker(&[20; 20]).lag());
+        unpublish(first);
+        assert!(GLOBALS.delivery_lag.get(&first).is_none());
+        assert_eq!(
+            GLOBALS.delivery_lag.get(&second).unwrap().gauges.samples,
+            20
+        );
+        unpublish(second);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        // Most connections are quick, one is far behind with a tenth of the samples
+        let mut connections: Vec<ConnectionLag> =
+            (0..9).map(|_| tracker(&[10; 100]).lag()).collect();
+        connections.push(tracker(&[1000; 100]).lag());
+        let gauges = combine(connections.iter());
+        assert_eq!(gauges.samples, 1000);
+        assert!(gauges.flushed_p50_ms >= 10.0 && gauges.flushed_p50_ms <= 10.0 * GROWTH);
+        assert!(gauges.flushed_p99_ms >= 1000.0 && gauges.flushed_p99_ms <= 1000.0 * GROWTH);
+
+        assert_eq!(
+            combine(std::iter::empty::<&ConnectionLag>()).flushed_p99_ms,
+            0.0
+        );
+        assert_eq!(
+            combine(std::iter::once(&ConnectionLag::default())).samples,
+            0
+        );
+    }
+}
@@ -1,21 +1,66 @@
+pub mod admin;
+pub mod backfill;
+pub mod capabilities;
 pub mod config;
+pub mod conn_stats;
+pub mod count;
 pub mod counting_stream;
+pub mod data_lock;
+pub mod deflate;
+pub mod deletion;
+pub mod ephemeral;
 pub mod error;
+pub mod expiration;
+pub mod failpoints;
 pub mod filestore;
+pub mod filter;
+pub mod first_seen;
+pub mod forward;
 pub mod globals;
+pub mod handover;
+pub mod history;
+pub mod integrity;
 pub mod ip;
+pub mod jsonl;
+pub mod lag;
+pub mod limits;
+pub mod listener;
+pub mod map_size;
+pub mod metrics;
+pub mod mode;
 mod neg_storage;
 pub mod nostr;
+pub mod planner;
+pub mod proxy;
+pub mod rate_limit;
+pub mod rejected;
+pub mod relay_list;
+pub mod replaceable;
 pub mod reply;
+pub mod resume;
+pub mod retention;
+pub mod search_index;
+pub mod sink;
+pub mod tag_index;
 pub mod tls;
+pub mod trace;
+pub mod validation;
+pub mod verify;
+pub mod walk;
 pub mod web;
+pub mod write_policy;
 
 use crate::config::{Config, FriendlyConfig};
+use crate::conn_stats::ConnStats;
 use crate::error::{ChorusError, Error};
+use crate::filter::ChorusFilter;
 use crate::globals::GLOBALS;
-use crate::ip::{HashedIp, HashedPeer, IpData, SessionExit};
-use crate::reply::NostrReply;
-use futures::{sink::SinkExt, stream::StreamExt};
+use crate::ip::{HashedIp, HashedPeer, IpData, SessionExit, Violation};
+use crate::lag::{LagTracker, NewEvent, Source};
+use crate::listener::ListenerSpec;
+use crate::metrics::Handler;
+use crate::reply::{NostrReply, NostrReplyPrefix};
+use futures::{sink::SinkExt, stream::StreamExt, FutureExt};
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty};
 use hyper::body::{Bytes, Incoming};
@@ -24,36 +69,45 @@ use hyper::upgrade::Upgraded;
 use hyper::StatusCode;
 use hyper::{Request, Response};
 use hyper_tungstenite::tungstenite;
-use hyper_tungstenite::{HyperWebsocket, WebSocketStream};
+use hyper_tungstenite::WebSocketStream;
 use hyper_util::rt::TokioIo;
 use neg_storage::NegentropyStorageVector;
+use parking_lot::Mutex;
 use pocket_db::{ScreenResult, Store};
-use pocket_types::{Id, OwnedFilter, Pubkey};
-use speedy::{Readable, Writable};
+use pocket_types::{Event, Filter, Id, Pubkey};
+use speedy::Writable;
 use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fs::OpenOptions;
 use std::future::Future;
 use std::io::Read;
-use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::pin::Pin;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
 use textnonce::TextNonce;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::time::Instant;
 use tungstenite::protocol::frame::Utf8Bytes;
 use tungstenite::protocol::WebSocketConfig;
 use tungstenite::Message;
 
-/// Serve a single network connection
-pub async fn serve<T>(stream: TokioIo<T>, peer: HashedPeer)
+/// Serve a single network connection. `peer_addr` is the client's address (as given by a
+/// PROXY protocol preamble, if we read one).
+pub async fn serve<T>(stream: TokioIo<T>, peer_addr: SocketAddr, listener: Arc<ListenerSpec>)
 where
     T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     // Serve the network stream with our http server and our ChorusService
-    let service = ChorusService { peer };
+    let peer = HashedPeer::new(peer_addr);
+    let service = ChorusService {
+        peer_addr,
+        peer,
+        listener,
+    };
 
     let http1builder = GLOBALS.http1builder.clone();
     let connection = http1builder
@@ -61,25 +115,101 @@ where
         .with_upgrades();
 
     // If our service exits with an error, log the error
-    if let Err(he) = connection.await {
-        if let Some(src) = he.source() {
-            if &*format!("{}", src) == "Transport endpoint is not connected (os error 107)" {
-                // do nothing
-            } else {
-                // Print in detail
-                log::error!(target: "Client", "{}: {:?}", peer, src);
+    crate::trace::scope(crate::trace::Context::new(peer), async move {
+        if let Err(he) = connection.await {
+            log_connection_error(peer, he);
+        }
+    })
+    .await;
+}
+
+/// Serve a single network connection that negotiated HTTP/2 (by ALPN). There are no
+/// websocket upgrades over HTTP/2, only plain HTTP requests.
+pub async fn serve_http2<T>(stream: TokioIo<T>, peer_addr: SocketAddr, listener: Arc<ListenerSpec>)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let peer = HashedPeer::new(peer_addr);
+    let last_request = Arc::new(Mutex::new(Instant::now()));
+    let service = Http2Service {
+        inner: ChorusService {
+            peer_addr,
+            peer,
+            listener,
+        },
+        last_request: last_request.clone(),
+    };
+
+    let http2builder = GLOBALS.http2builder.clone();
+    crate::trace::scope(crate::trace::Context::new(peer), async move {
+        let connection = http2builder.serve_connection(stream, service);
+        tokio::pin!(connection);
+
+        // As HTTP/1.1 connections are closed when they take too long to send a request,
+        // one that has not started a request for as long is told to go away (streams it
+        // already opened still finish)
+        let mut going_away = false;
+        loop {
+            let deadline = *last_request.lock() + crate::globals::HEADER_READ_TIMEOUT;
+            tokio::select! {
+                result = connection.as_mut() => {
+                    if let Err(he) = result {
+                        log_connection_error(peer, he);
+                    }
+                    break;
+                }
+                _ = tokio::time::sleep_until(deadline), if !going_away => {
+                    if last_request.lock().elapsed() >= crate::globals::HEADER_READ_TIMEOUT {
+                        connection.as_mut().graceful_shutdown();
+                        going_away = true;
+                    }
+                }
             }
+        }
+    })
+    .await;
+}
+
+// Log why serving a connection failed (unless the client just went away)
+fn log_connection_error(peer: HashedPeer, he: hyper::Error) {
+    if let Some(src) = he.source() {
+        if &*format!("{}", src) == "Transport endpoint is not connected (os error 107)" {
+            // do nothing
         } else {
-            // Print in less detail
-            let e: Error = he.into();
-            log::error!(target: "Client", "{}: {}", peer, e);
+            // Print in detail
+            log::error!(target: "Client", "{}: {:?}", peer, src);
         }
+    } else {
+        // Print in less detail
+        let e: Error = he.into();
+        log::error!(target: "Client", "{}: {}", peer, e);
+    }
+}
+
+// Our per-connection HTTP service for HTTP/2, noting when each request starts
+struct Http2Service {
+    inner: ChorusService,
+    last_request: Arc<Mutex<Instant>>,
+}
+
+impl Service<Request<Incoming>> for Http2Service {
+    type Response = <ChorusService as Service<Request<Incoming>>>::Response;
+    type Error = Error;
+    type Future = <ChorusService as Service<Request<Incoming>>>::Future;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        *self.last_request.lock() = Instant::now();
+        self.inner.call(req)
     }
 }
 
 // This is our per-connection HTTP service
 struct ChorusService {
+    peer_addr: SocketAddr,
     peer: HashedPeer,
+
+    // The listener the connection arrived on
+    listener: Arc<ListenerSpec>,
 }
 
 impl Service<Request<Incoming>> for ChorusService {
@@ -95,25 +225,35 @@ impl Service<Request<Incoming>> for ChorusService {
         let failvalue =
             |c: ChorusError| -> Self::Future { Box::pin(futures::future::ready(Err(c.into()))) };
 
-        if GLOBALS.config.read().chorus_is_behind_a_proxy {
-            // If chorus is behind a proxy that sets an "X-Real-Ip" header, we use
-            // that ip address instead (otherwise their log file will just give the proxy IP
-            // for every peer)
-            //
-            // This header must be found and be valid for us to proceed
-            if let Some(rip) = req.headers().get("x-real-ip") {
-                if let Ok(ripstr) = rip.to_str() {
-                    if let Ok(ipaddr) = ripstr.parse::<IpAddr>() {
-                        let hashed_ip = HashedIp::new(ipaddr);
-                        hashed_peer = HashedPeer::from_parts(hashed_ip, hashed_peer.port());
-                    } else {
-                        return failvalue(ChorusError::BadRealIpHeader(ripstr.to_owned()));
-                    }
-                } else {
-                    return failvalue(ChorusError::BadRealIpHeaderCharacters);
+        // If chorus is behind a proxy, it tells us the client's IP address in a header, and
+        // we use that instead (otherwise we would log, rate limit and ban the proxy IP for
+        // every peer). We only believe that header from a proxy we trust, or from anybody
+        // if `chorus_is_behind_a_proxy` (in which case the header must be there).
+        let (behind_a_proxy, trusted) = {
+            let config = GLOBALS.config.read();
+            let behind_a_proxy = config.chorus_is_behind_a_proxy;
+            let trusted = behind_a_proxy
+                || config
+                    .trusted_proxies
+                    .iter()
+                    .any(|cidr| cidr.contains(self.peer_addr.ip()));
+            (behind_a_proxy, trusted)
+        };
+        if trusted {
+            let forwarded = {
+                let config = GLOBALS.config.read();
+                crate::proxy::forwarded_ip(req.headers(), &config.trusted_proxies)
+            };
+            match forwarded {
+                Ok(Some(ipaddr)) => {
+                    let hashed_ip = HashedIp::new(ipaddr);
+                    hashed_peer = HashedPeer::from_parts(hashed_ip, hashed_peer.port());
                 }
-            } else {
-                return failvalue(ChorusError::RealIpHeaderMissing);
+                Ok(None) if behind_a_proxy => {
+                    return failvalue(ChorusError::RealIpHeaderMissing);
+                }
+                Ok(None) => {}
+                Err(e) => return failvalue(e.inner),
             }
 
             // Possibly IP block late (if behind a proxy)
@@ -130,12 +270,18 @@ impl Service<Request<Incoming>> for ChorusService {
             }
         }
 
-        Box::pin(async move { handle_http_request(hashed_peer, req).await })
+        // (Requests may be handled in a task of their own, and may have come through a proxy)
+        let context = crate::trace::Context::inherit(hashed_peer);
+        let listener = self.listener.clone();
+        Box::pin(crate::trace::scope(context, async move {
+            handle_http_request(hashed_peer, listener, req).await
+        }))
     }
 }
 
 async fn handle_http_request(
     peer: HashedPeer,
+    listener: Arc<ListenerSpec>,
     mut request: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
     let ua = match request.headers().get("user-agent") {
@@ -148,6 +294,48 @@ async fn handle_http_request(
         None => "(no origin)".to_owned(),
     };
 
+    // Don't start anything new while shutting down
+    if *GLOBALS.shutting_down.borrow() {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Connection", "close")
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
+    }
+
+    // Only what this listener serves
+    if let Some(service) = crate::listener::service_for(&request) {
+        if !listener.serves(service) {
+            log::debug!(
+                target: "Client",
+                "{}: {:?} is not served on {}",
+                peer,
+                service,
+                listener
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Empty::new().map_err(|e| e.into()).boxed())?);
+        }
+    }
+
+    // Metrics, health checks and stats come first so that they can be had even from a busy
+    // address, and without the NIP-11 Accept header
+    match web::router::classify(request.uri().path()) {
+        web::router::Route::Metrics => {
+            let _guard = HttpRequestGuard::new();
+            return web::metrics::serve_metrics(peer, request).await;
+        }
+        web::router::Route::Health => {
+            let _guard = HttpRequestGuard::new();
+            return web::health::serve_health(peer).await;
+        }
+        web::router::Route::Stats => {
+            let _guard = HttpRequestGuard::new();
+            return web::health::serve_stats(peer, request).await;
+        }
+        _ => {}
+    }
+
     let max_conn = GLOBALS.config.read().max_connections_per_ip;
     if let Some(cur) = GLOBALS.num_connections_per_ip.get(&peer.ip()) {
         if *cur.value() >= max_conn {
@@ -181,11 +369,33 @@ async fn handle_http_request(
 
         let mut web_socket_config = WebSocketConfig::default();
         web_socket_config.max_write_buffer_size = 1024 * 1024; // 1 MB
-        web_socket_config.max_message_size = Some(1024 * 1024); // 1 MB
-        web_socket_config.max_frame_size = Some(1024 * 1024); // 1 MB
 
-        let (mut response, websocket) =
-            hyper_tungstenite::upgrade(&mut request, Some(web_socket_config))?;
+        // Messages up to twice max_message_length get through to be refused with a NOTICE
+        // (see `crate::validation`); longer ones close the connection
+        let max_message_size = GLOBALS.config.read().max_message_length.saturating_mul(2);
+        web_socket_config.max_message_size = Some(max_message_size);
+        web_socket_config.max_frame_size = Some(max_message_size);
+
+        // Agree to compression if configured and the client offers it
+        let compression = {
+            let config = GLOBALS.config.read();
+            let offered = request
+                .headers()
+                .get_all(http::header::SEC_WEBSOCKET_EXTENSIONS)
+                .iter()
+                .filter_map(|hv| hv.to_str().ok())
+                .any(deflate::accept_offer);
+            if config.websocket_compression && offered {
+                Some(deflate::Compression {
+                    level: config.websocket_compression_level,
+                    min_bytes: config.websocket_compression_min_bytes,
+                })
+            } else {
+                None
+            }
+        };
+
+        let (mut response, websocket) = upgrade(&mut request, compression.is_some())?;
 
         // If the client asked for Sec-Websocket-Protocol, then we already checked it must
         // have asked for 'nostr', so send that as a response header
@@ -196,34 +406,135 @@ async fn handle_http_request(
             );
         }
 
+        crate::trace::begin("UPGRADE");
+        log::debug!(target: "Client", "{}: Upgrading to a websocket", peer);
+
         // Start the websocket thread
-        tokio::spawn(async move { websocket_thread(peer, websocket, origin, ua).await });
+        let context = crate::trace::Context::inherit(peer);
+        tokio::spawn(crate::trace::scope(context, async move {
+            websocket_thread(peer, websocket, web_socket_config, compression, origin, ua).await
+        }));
 
-        Ok(response.map(|body| body.map_err(|e| e.into()).boxed()))
+        Ok(response)
     } else {
-        web::serve_http(peer, request).await
+        let _guard = HttpRequestGuard::new();
+        crate::trace::begin("HTTP");
+        let method = request.method().clone();
+        let path = request.uri().path().to_owned();
+        let started = Instant::now();
+        let response = web::serve_http(peer, request).await;
+        GLOBALS.metrics.observe(Handler::Http, started.elapsed());
+        if let Ok(ref response) = response {
+            log::debug!(target: "Client", "{}: {} {} {}", peer, method, path, response.status());
+        }
+        response
+    }
+}
+
+// Counts an HTTP request as in flight for as long as it lives, so shutdown can wait for
+// it (e.g. for a Blossom upload to finish)
+struct HttpRequestGuard;
+
+impl HttpRequestGuard {
+    fn new() -> HttpRequestGuard {
+        let _ = GLOBALS.num_http_requests.fetch_add(1, Ordering::SeqCst);
+        HttpRequestGuard
     }
 }
 
-async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: String, ua: String) {
+impl Drop for HttpRequestGuard {
+    fn drop(&mut self) {
+        let _ = GLOBALS.num_http_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Answer a websocket upgrade request (like `hyper_tungstenite::upgrade`, which cannot
+// agree to extensions or let us see the client's frames before tungstenite does)
+fn upgrade(
+    request: &mut Request<Incoming>,
+    compression: bool,
+) -> Result<(Response<BoxBody<Bytes, Error>>, hyper::upgrade::OnUpgrade), Error> {
+    use tungstenite::error::ProtocolError;
+
+    let key = request
+        .headers()
+        .get(http::header::SEC_WEBSOCKET_KEY)
+        .ok_or(ProtocolError::MissingSecWebSocketKey)?;
+    if request
+        .headers()
+        .get(http::header::SEC_WEBSOCKET_VERSION)
+        .map(|v| v.as_bytes())
+        != Some(b"13")
+    {
+        return Err(ProtocolError::MissingSecWebSocketVersionHeader.into());
+    }
+
+    let mut builder = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(http::header::CONNECTION, "upgrade")
+        .header(http::header::UPGRADE, "websocket")
+        .header(
+            http::header::SEC_WEBSOCKET_ACCEPT,
+            tungstenite::handshake::derive_accept_key(key.as_bytes()),
+        );
+    if compression {
+        builder = builder.header(http::header::SEC_WEBSOCKET_EXTENSIONS, deflate::RESPONSE);
+    }
+    let response = builder.body(Empty::new().map_err(|e| e.into()).boxed())?;
+
+    Ok((response, hyper::upgrade::on(request)))
+}
+
+async fn websocket_thread(
+    peer: HashedPeer,
+    websocket: hyper::upgrade::OnUpgrade,
+    web_socket_config: WebSocketConfig,
+    compression: Option<deflate::Compression>,
+    origin: String,
+    ua: String,
+) {
     // Await the websocket upgrade process
     match websocket.await {
-        Ok(websocket) => {
+        Ok(upgraded) => {
+            // Compressed messages from the client are inflated before tungstenite sees them
+            let max_message_size = web_socket_config.max_message_size.unwrap_or(usize::MAX);
+            let stream = deflate::Inflating::new(
+                TokioIo::new(upgraded),
+                compression.is_some(),
+                max_message_size,
+            );
+            let websocket = WebSocketStream::from_raw_socket(
+                TokioIo::new(stream),
+                tungstenite::protocol::Role::Server,
+                Some(web_socket_config),
+            )
+            .await;
+
+            let connected = std::time::Instant::now();
+
             // Build a websocket service
             let mut ws_service = WebSocketService {
+                id: GLOBALS.next_connection_id.fetch_add(1, Ordering::Relaxed),
                 peer,
                 subscriptions: HashMap::new(),
+                cursors: HashMap::new(),
                 neg_subscriptions: HashMap::new(),
                 // We start with a 1-page buffer, and grow it if needed.
                 buffer: vec![0; 4096],
                 websocket,
+                compression,
                 last_message: Instant::now(),
                 burst_tokens: GLOBALS.config.read().throttling_burst,
                 challenge: TextNonce::new().into_string(),
                 user: None,
                 error_punishment: 0.0,
+                violation_points: 0.0,
                 replied: false,
                 negentropy_sub: None,
+                lag: LagTracker::default(),
+                stats: ConnStats::default(),
+                unflushed: 0,
+                unflushed_lag: Vec::new(),
             };
 
             // Increment connection count
@@ -238,6 +549,7 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
 
             // we cheat somewhat and log these websocket open and close messages
             // as server messages
+            crate::trace::begin("CONNECT");
             log::info!(
                 target: "Server",
                 "{}: TOTAL={}, New Connection: {}, {}",
@@ -251,8 +563,12 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
             let mut session_exit: SessionExit = SessionExit::Ok;
             let mut msg = "Closed";
 
+            crate::trace::end();
+
             // Handle the websocket
-            if let Err(e) = ws_service.handle_websocket_stream().await {
+            let result = ws_service.handle_websocket_stream().await;
+            crate::trace::begin_at("DISCONNECT", connected);
+            if let Err(e) = result {
                 match e.inner {
                     ChorusError::Tungstenite(tungstenite::error::Error::Protocol(
                         tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
@@ -288,6 +604,9 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
                         session_exit = SessionExit::Timeout;
                         msg = "Timed Out (with no subscriptions)";
                     }
+                    ChorusError::Maintenance => {
+                        msg = "Turned away (maintenance)";
+                    }
                     ChorusError::Io(_) => {
                         // Usually "Connection reset by peer" but any I/O error
                         // isn't a big deal.
@@ -301,6 +620,21 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
                 }
             }
 
+            // Remember where their subscriptions left off
+            for cursor in ws_service.cursors.values() {
+                if let Err(e) = resume::save(cursor) {
+                    log::error!(target: "Client", "{}: {}", peer, e);
+                }
+            }
+
+            // Stop reporting delivery lag for this connection
+            lag::unpublish(ws_service.connection());
+
+            // Their subscriptions are gone
+            GLOBALS
+                .metrics
+                .subscriptions_changed(ws_service.subscriptions.len(), 0);
+
             // Decrement connection count
             let old_num_websockets = GLOBALS.num_connections.fetch_sub(1, Ordering::SeqCst);
 
@@ -318,27 +652,32 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
 
             // Update ip data (including ban time)
             let minimum_ban_seconds = GLOBALS.config.read().minimum_ban_seconds;
-            let ban_seconds = if GLOBALS.config.read().enable_ip_blocking {
-                let mut ban_seconds = 0;
-                if let Ok(mut ip_data) = get_ip_data(peer.ip()) {
-                    ban_seconds =
-                        ip_data.update_on_session_close(session_exit, minimum_ban_seconds);
-                    let _ = update_ip_data(peer.ip(), &ip_data);
-                }
-                ban_seconds
-            } else {
-                minimum_ban_seconds
-            };
+            let ban_seconds =
+                if GLOBALS.config.read().enable_ip_blocking && !peer.ip().is_unix_socket() {
+                    let mut ban_seconds = 0;
+                    if let Ok(mut ip_data) = get_ip_data(peer.ip()) {
+                        ban_seconds = ip_data.update_on_session_close(
+                            session_exit,
+                            ws_service.violation_points,
+                            minimum_ban_seconds,
+                        );
+                        let _ = update_ip_data(peer.ip(), &ip_data);
+                    }
+                    ban_seconds
+                } else {
+                    minimum_ban_seconds
+                };
 
             // we cheat somewhat and log these websocket open and close messages
             // as server messages
             log::info!(
                 target: "Server",
-                "{}: TOTAL={}, {}, ban={}s",
+                "{}: TOTAL={}, {}, ban={}s, {}",
                 peer,
                 old_num_websockets - 1,
                 msg,
-                ban_seconds
+                ban_seconds,
+                ws_service.stats.summary(ws_service.user.as_ref())
             );
         }
         Err(e) => {
@@ -348,22 +687,63 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
 }
 
 struct WebSocketService {
+    // Tells this connection apart from others from the same peer (see `lag::Connection`)
+    pub id: u64,
+
     pub peer: HashedPeer,
-    pub subscriptions: HashMap<String, Vec<OwnedFilter>>,
+    pub subscriptions: HashMap<String, Vec<ChorusFilter>>,
+
+    // Where the subscriptions that asked for `since_last` are up to (see `resume`)
+    pub cursors: HashMap<String, resume::Cursor>,
+
     pub neg_subscriptions: HashMap<String, NegentropyStorageVector>,
     pub buffer: Vec<u8>,
-    pub websocket: WebSocketStream<TokioIo<Upgraded>>,
+    pub websocket: WebSocketStream<TokioIo<deflate::Inflating<TokioIo<Upgraded>>>>,
+    pub compression: Option<deflate::Compression>,
     pub last_message: Instant,
     pub burst_tokens: usize,
     pub challenge: String,
     pub user: Option<Pubkey>,
     pub error_punishment: f32,
+
+    // Violations counted against the IP's reputation when the session closes
+    pub violation_points: f32,
+
     pub replied: bool,
     pub negentropy_sub: Option<String>,
+    pub lag: LagTracker,
+    pub stats: ConnStats,
+
+    // Bytes queued on the websocket but not yet flushed to the client
+    pub unflushed: usize,
+
+    // When the live events among them were ingested and queued, for lag tracking
+    pub unflushed_lag: Vec<(std::time::Instant, std::time::Instant)>,
 }
 
 impl WebSocketService {
+    fn connection(&self) -> lag::Connection {
+        lag::Connection {
+            id: self.id,
+            peer: self.peer,
+        }
+    }
+
+    // Count an error against the session, and against the IP's reputation
+    fn punish(&mut self, error: &ChorusError) {
+        self.error_punishment += error.punishment();
+        if let Some(violation) = Violation::of(error) {
+            self.violation_points += violation.points();
+        }
+    }
+
     async fn send(&mut self, m: Message) -> Result<(), Error> {
+        self.feed(m).await?;
+        self.flush().await
+    }
+
+    // Queue a message without waiting for it to be sent
+    async fn feed(&mut self, m: Message) -> Result<(), Error> {
         log::trace!(target: "Client", "{}: {}", self.peer, m);
 
         // Throttling: we consume burst tokens, but we do not throttle on output
@@ -372,14 +752,95 @@ impl WebSocketService {
             let reply = NostrReply::Notice("Rate limit exceeded.".into());
             self.websocket.send(Message::text(reply.as_json()?)).await?;
             let error = ChorusError::RateLimitExceeded;
-            self.error_punishment += error.punishment();
+            self.punish(&error);
             return Err(error.into());
         } else {
             self.burst_tokens -= m.len();
         }
 
         self.replied = true;
-        Ok(self.websocket.send(m).await?)
+        self.stats.frame_out(m.len());
+        self.unflushed += m.len();
+        let m = match (m, self.compression) {
+            (Message::Text(text), Some(compression)) if text.len() >= compression.min_bytes => {
+                Message::Frame(deflate::compressed_frame(
+                    text.as_bytes(),
+                    compression.level,
+                ))
+            }
+            (m, _) => m,
+        };
+        Ok(self.websocket.feed(m).await?)
+    }
+
+    // Wait until everything queued has been sent
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.websocket.flush().await?;
+        self.flushed();
+        Ok(())
+    }
+
+    // Send what is queued as far as it goes without waiting, returning whether it all went
+    fn try_flush(&mut self) -> Result<bool, Error> {
+        match self.websocket.flush().now_or_never() {
+            Some(result) => {
+                result?;
+                self.flushed();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Queue a live event's message unless that means waiting on the client: because it
+    // has not taken in what came before, or more than `max_inflight_bytes` would be in
+    // flight. Returns whether it was queued.
+    fn try_feed(&mut self, message: Message, max_inflight_bytes: usize) -> Result<bool, Error> {
+        if self.unflushed + message.len() > max_inflight_bytes && !self.try_flush()? {
+            return Ok(false);
+        }
+        let len = message.len();
+        match self.websocket.feed(message).now_or_never() {
+            Some(result) => {
+                result?;
+                // note, this is not currently counted in throttling
+                self.stats.frame_out(len);
+                self.unflushed += len;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn flushed(&mut self) {
+        let now = std::time::Instant::now();
+        for (ingested, queued) in self.unflushed_lag.drain(..) {
+            self.lag.record(ingested, queued, now);
+        }
+        self.unflushed = 0;
+    }
+
+    // Close subscriptions because the client is not keeping up with them
+    async fn close_slow_subscriptions(&mut self, subids: Vec<String>) -> Result<(), Error> {
+        let before = self.subscriptions.len();
+        for subid in subids.iter() {
+            if self.subscriptions.remove(subid).is_some() {
+                // Events were missed, so this must not move its cursor
+                let _ = self.cursors.remove(subid);
+                log::info!(target: "Client", "{}: Closed subscription {} of a slow reader", self.peer, subid);
+                let reply =
+                    NostrReply::Closed(subid, NostrReplyPrefix::Error, "slow reader".to_owned());
+                // Queued, to arrive after what they have not yet read
+                let message = Message::text(reply.as_json()?);
+                self.stats.frame_out(message.len());
+                self.unflushed += message.len();
+                self.websocket.feed(message).await?;
+            }
+        }
+        GLOBALS
+            .metrics
+            .subscriptions_changed(before, self.subscriptions.len());
+        Ok(())
     }
 
     async fn wsclose(&mut self, error: Error) -> Result<(), Error> {
@@ -389,12 +850,14 @@ impl WebSocketService {
         let (code, reason) = match &error.inner {
             ChorusError::TimedOut => (CloseCode::Policy, Utf8Bytes::from_static("timed out")),
             ChorusError::ShuttingDown => (CloseCode::Restart, Utf8Bytes::from_static("restarting")),
+            ChorusError::Maintenance => (CloseCode::Again, Utf8Bytes::from_static("maintenance")),
             ChorusError::BannedUser | ChorusError::BlockedIp => {
                 (CloseCode::Policy, Utf8Bytes::from_static("banned"))
             }
             e => (CloseCode::Error, format!("{}", e).into()),
         };
 
+        self.stats.closed(u16::from(code), &reason, true);
         let close_frame = CloseFrame { code, reason };
 
         // NOTE: This is the same as sending Message::Close(..)
@@ -413,6 +876,14 @@ impl WebSocketService {
         // Subscribe to the new_events broadcast channel
         let mut new_events = GLOBALS.new_events.subscribe();
 
+        // In maintenance mode we only say why we are closing
+        if crate::mode::current() == crate::mode::Mode::Maintenance {
+            let reply =
+                NostrReply::Notice("error: relay is down for maintenance, try again later".into());
+            self.send(Message::text(reply.as_json()?)).await?;
+            return self.wsclose(ChorusError::Maintenance.into()).await;
+        }
+
         // Offer AUTH to clients right off the bat
         let reply = NostrReply::Auth(self.challenge.clone());
         self.send(Message::text(reply.as_json()?)).await?;
@@ -428,6 +899,9 @@ impl WebSocketService {
         loop {
             tokio::select! {
                 instant = interval.tick() => {
+                    // Report delivery lag
+                    lag::publish(self.connection(), self.lag.lag());
+
                     // Drop them if they have no subscriptions
                     if self.subscriptions.is_empty() && self.neg_subscriptions.is_empty() {
                         // And they are idle for timeout_seconds with no subscriptions
@@ -448,9 +922,20 @@ impl WebSocketService {
                         None => break, // the websocket is closed
                     }
                 },
-                offset_result = new_events.recv() => {
-                    let offset = offset_result?;
-                    self.handle_new_event(offset).await?;
+                new_event_result = new_events.recv() => {
+                    match new_event_result {
+                        Ok(new_event) => self.handle_new_event(new_event).await?,
+                        Err(RecvError::Lagged(_)) => {
+                            // We fell behind (waiting on the client), and missed events
+                            let subids = self.subscriptions.keys().cloned().collect();
+                            self.close_slow_subscriptions(subids).await?;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                    // Send what is queued once we have caught up
+                    if self.unflushed > 0 && new_events.is_empty() {
+                        self.flush().await?;
+                    }
                 },
                 _r = shutting_down.changed() => {
                     self.wsclose(ChorusError::ShuttingDown.into()).await?;
@@ -462,20 +947,20 @@ impl WebSocketService {
     }
 
     // If the event matches a subscription they have open, send them the event
-    async fn handle_new_event(&mut self, new_event_offset: u64) -> Result<(), Error> {
+    async fn handle_new_event(&mut self, new_event: NewEvent) -> Result<(), Error> {
         if self.subscriptions.is_empty() {
             return Ok(());
         }
 
-        let event = GLOBALS
-            .store
-            .get()
-            .unwrap()
-            .get_event_by_offset(new_event_offset)?;
+        let event = match &new_event.source {
+            Source::Stored(offset) => GLOBALS.store.get().unwrap().get_event_by_offset(*offset)?,
+            Source::Ephemeral(bytes) => unsafe { Event::delineate(bytes)? },
+        };
 
         let event_flags = nostr::event_flags(event, &self.user);
         let authorized_user = self.user.map(is_authorized_user).unwrap_or(false);
 
+        let mut matched: Vec<String> = Vec::new();
         'subs: for (subid, filters) in self.subscriptions.iter() {
             for filter in filters.iter() {
                 if filter.event_matches(event)? {
@@ -486,21 +971,38 @@ impl WebSocketService {
                         //       let them know there were redactions from
                         //       the post-EOSE data
                     } else if screen_result == ScreenResult::Match {
-                        let message = NostrReply::Event(subid, event);
-                        // note, this is not currently counted in throttling
-                        self.websocket
-                            .send(Message::text(message.as_json()?))
-                            .await?;
+                        matched.push(subid.clone());
                         continue 'subs;
                     }
                 }
             }
         }
 
+        // Queue the event for each, but never wait on a client that is not reading
+        let max_inflight_bytes = GLOBALS.config.read().max_inflight_bytes;
+        let mut slow: Vec<String> = Vec::new();
+        for subid in matched.iter() {
+            let message = Message::text(NostrReply::Event(subid, event).as_json()?);
+            if !self.try_feed(message, max_inflight_bytes)? {
+                slow.push(subid.clone());
+                continue;
+            }
+            self.unflushed_lag
+                .push((new_event.ingested, std::time::Instant::now()));
+            if let Some(cursor) = self.cursors.get_mut(subid) {
+                cursor.delivered(event);
+            }
+        }
+        if !slow.is_empty() {
+            self.close_slow_subscriptions(slow).await?;
+        }
+
         Ok(())
     }
 
     async fn handle_websocket_message(&mut self, message: Message) -> Result<(), Error> {
+        self.stats.frame_in(message.len());
+
         // Throttling
         {
             let (throttling_burst, throttling_bytes_per_second) = {
@@ -527,7 +1029,7 @@ impl WebSocketService {
                 let reply = NostrReply::Notice("Rate limit exceeded.".into());
                 self.websocket.send(Message::text(reply.as_json()?)).await?;
                 let error = ChorusError::RateLimitExceeded;
-                self.error_punishment += error.punishment();
+                self.punish(&error);
                 return Err(error.into());
             } else {
                 self.burst_tokens -= message.len();
@@ -539,10 +1041,23 @@ impl WebSocketService {
                 log::trace!(target: "Client", "{}: <= {}", self.peer, msg);
                 self.replied = false;
                 // This is defined in nostr.rs
-                if let Err(e) = self.handle_nostr_message(&msg).await {
-                    self.error_punishment += e.inner.punishment();
-                    log::error!(target: "Client", "{}: {e}", self.peer);
-                    if !matches!(e.inner, ChorusError::AuthRequired) {
+                let subscriptions = self.subscriptions.len();
+                let result = self.handle_nostr_message(&msg).await;
+                GLOBALS
+                    .metrics
+                    .subscriptions_changed(subscriptions, self.subscriptions.len());
+                if let Err(e) = result {
+                    self.punish(&e.inner);
+                    if matches!(e.inner, ChorusError::RateLimited(_)) {
+                        // Not worth a log line per message while they keep it up
+                        log::debug!(target: "Client", "{}: {e}", self.peer);
+                    } else {
+                        log::error!(target: "Client", "{}: {e}", self.peer);
+                    }
+                    if !matches!(
+                        e.inner,
+                        ChorusError::AuthRequired | ChorusError::RateLimited(_)
+                    ) {
                         if msg.len() < 2048 {
                             log::warn!(target: "Client", "{}:   msg was {}", self.peer, msg);
                         } else {
@@ -564,6 +1079,7 @@ impl WebSocketService {
                         return Err(ChorusError::ErrorClose.into());
                     }
                 }
+                crate::trace::end();
             }
             Message::Binary(msg) => {
                 let reply = NostrReply::Notice(
@@ -586,6 +1102,7 @@ impl WebSocketService {
             Message::Close(msg) => {
                 // No need to send a reply: tungstenite takes care of this for you.
                 if let Some(msg) = &msg {
+                    self.stats.closed(u16::from(msg.code), &msg.reason, false);
                     log::debug!(target: "Client",
                         "{}: Received websocket close message with code {} and message: {}",
                         self.peer,
@@ -631,7 +1148,11 @@ pub fn print_stats() {
         GLOBALS.bytes_outbound.load(Ordering::Relaxed),
         (GLOBALS.bytes_outbound.load(Ordering::Relaxed) as f32) / (runtime as f32)
     );
-    if let Ok(status) = GLOBALS.store.get().unwrap().stats() {
+    let stats = {
+        let _reading = crate::map_size::reading();
+        GLOBALS.store.get().unwrap().stats()
+    };
+    if let Ok(status) = stats {
         log::info!(
             target: "Server",
             "Store: {} event bytes in {} events, {} bytes for the indexes",
@@ -655,14 +1176,18 @@ pub fn load_config<P: AsRef<Path>>(config_path: P) -> Result<Config, Error> {
 
 /// Setup logging
 pub fn setup_logging(config: &Config) {
-    env_logger::Builder::new()
+    let mut builder = env_logger::Builder::new();
+    builder
         .filter_level(config.library_log_level)
         .filter(Some("Server"), config.server_log_level)
         .filter(Some("Client"), config.client_log_level)
         .format_target(true)
         .format_module_path(false)
-        .format_timestamp_millis()
-        .init();
+        .format_timestamp_millis();
+    if config.json_logs {
+        builder.format(crate::trace::format_json);
+    }
+    builder.init();
 
     log::debug!(target: "Server", "Loaded config file.");
 }
@@ -679,18 +1204,281 @@ pub fn setup_store_and_return(config: &Config) -> Result<Store, Error> {
     let store = Store::new(
         &config.data_directory,
         vec![
-            "approved-events",  // id.as_slice() -> u8(bool)
-            "approved-pubkeys", // pubkey.as_slice() -> u8(bool)
-            "ip_data",          // HashedIp.0 -> IpData
-            "users",            // pubkey.as_slice() -> u8(bool) true if moderator
+            "approved-events",        // id.as_slice() -> u8(bool)
+            "approved-pubkeys",       // pubkey.as_slice() -> u8(bool)
+            "ip_data",                // HashedIp.0 -> IpData
+            "users",                  // pubkey.as_slice() -> u8(bool) true if moderator
+            "long_tag_index",         // name 0 value 0 id.as_slice() -> created_at (u64 BE)
+            "long_tag_index_meta",    // "names" -> JSON list of indexed tag names
+            "event_sink_outbox",      // u64 sequence (BE) -> enqueued at ms (u64 BE) ++ event json
+            "first_seen",             // first seen (u64 BE) ++ id.as_slice() -> ()
+            "first_seen_ids",         // id.as_slice() -> first seen (u64 BE)
+            "first_seen_meta",        // "built" -> () if the first_seen index is built
+            "blobs",                  // HashOutput -> BlobMetadata (JSON)
+            "blob_owners",            // pubkey.as_slice() ++ HashOutput -> uploaded (u64 BE)
+            "blob_pins",              // HashOutput -> () if pinned against garbage collection
+            "blobs_meta",             // "mime_types" -> () once every blob has one recorded
+            "search_index",           // term 0 (u64::MAX - created_at) (u64 BE) id.as_slice() -> ()
+            "search_index_meta",      // "built" -> () if the search index is built
+            "expiration_index",       // expiration (u64 BE) ++ id.as_slice() -> ()
+            "expiration_index_meta",  // "built" -> () once the expiration index is built
+            "deleted_addresses",      // kind (u16 BE) ++ pubkey ++ d-tag -> deleted at (u64 BE)
+            "deleted_addresses_meta", // "built" -> () once `a` tag deletions are recorded
+            "relay_list_users",       // pubkey.as_slice() -> relay list created_at (u64 BE)
+            "latest_addresses",       // kind (u16 BE) ++ pubkey ++ sha256(d-tag) -> id.as_slice()
+            "latest_addresses_meta",  // "built" -> () once superseded versions are removed
+            "forward_queue",          // u64 sequence (BE) -> id.as_slice() of an event to forward
+            "forward_positions",      // peer relay url -> last sequence forwarded to it (u64 BE)
+            "since_last_cursors",     // pubkey ++ fingerprint -> newest (u64 BE) ++ saved (u64 BE)
+            "count_kinds",            // kind (u16 BE) ++ created_at (u64 BE) ++ id -> count entry
+            "count_authors", // pubkey ++ kind (u16 BE) ++ created_at (u64 BE) ++ id -> count entry
+            "count_ids",     // id.as_slice() -> kind (u16 BE) ++ created_at (u64 BE) ++ pubkey
+            "count_meta",    // "built" -> () once the count index is built
         ],
     )?;
+    if config.lmdb_map_size > crate::map_size::map_size(&store) {
+        crate::map_size::grow_to(&store, config.lmdb_map_size)?;
+    }
     Ok(store)
 }
 
-/// Get IpData from storage about this remote HashedIp
+/// Store an event and index it.
+///
+/// If indexing fails the event is removed again, so it is never served without being
+/// fully indexed. Returns the event's offset. Writes that find the LMDB map full grow it
+/// and are retried (see `map_size`), except for storing the event itself, which may have
+/// been appended to the events file already and so is refused instead.
+pub fn store_event(event: &Event) -> Result<u64, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let max_map_size = GLOBALS.config.read().lmdb_max_map_size;
+
+    crate::failpoints::hit("store_event")?;
+    crate::deletion::check(event)?;
+    crate::replaceable::check(event)?;
+    let offset =
+        crate::map_size::write_once(store, max_map_size, || Ok(store.store_event(event)?))?;
+
+    let indexed = crate::failpoints::hit("store_event.after_append").and_then(|_| {
+        crate::map_size::write(store, max_map_size, || {
+            crate::tag_index::index_event(event)
+                .and_then(|_| crate::first_seen::record(event))
+                .and_then(|_| crate::failpoints::hit("store_event.between_indexes"))
+                .and_then(|_| crate::search_index::index_event(event))
+                .and_then(|_| crate::expiration::record(event))
+                .and_then(|_| crate::count::record(event))
+        })
+    });
+    if let Err(e) = indexed {
+        log::error!(target: "Server", "Failed to index event {}, removing it: {}", event.id().as_hex_string(), e);
+        crate::failpoints::hit("store_event.rollback")?;
+        // (Along with whatever our own indexes already hold for it)
+        unstore(event.id())?.remove()?;
+        return Err(e);
+    }
+
+    // Only the latest version of a replaceable event is kept
+    if let Err(e) = crate::replaceable::record(event) {
+        log::error!(target: "Server", "Failed to replace older versions of {}: {}", event.id().as_hex_string(), e);
+    }
+
+    // Deletion requests may also delete addresses, which pocket leaves to us
+    if let Err(e) = crate::deletion::record(event) {
+        log::error!(target: "Server", "Failed to delete addresses for {}: {}", event.id().as_hex_string(), e);
+    }
+
+    // Relay lists may make (or unmake) their authors our users
+    if let Err(e) = crate::relay_list::record(event) {
+        log::error!(target: "Server", "Failed to record relay list {}: {}", event.id().as_hex_string(), e);
+    }
+
+    Ok(offset)
+}
+
+// What our own indexes hold for an event, to be removed along with it
+struct IndexEntries {
+    id: Id,
+    tags: Vec<Vec<u8>>,
+    search: Vec<Vec<u8>>,
+    expiration: Option<Vec<u8>>,
+    latest: Option<(Vec<u8>, Id)>,
+}
+
+impl IndexEntries {
+    fn of(event: &Event) -> Result<IndexEntries, Error> {
+        Ok(IndexEntries {
+            id: event.id(),
+            tags: crate::tag_index::keys_for_removal(event)?,
+            search: crate::search_index::keys_for_removal(event),
+            expiration: crate::expiration::key_for_removal(event),
+            latest: crate::replaceable::key_for_removal(event),
+        })
+    }
+
+    // Of an event we no longer have, all that can be found by its id
+    fn of_missing(id: Id) -> IndexEntries {
+        IndexEntries {
+            id,
+            tags: vec![],
+            search: vec![],
+            expiration: None,
+            latest: None,
+        }
+    }
+
+    // (If this fails partway, entries are left pointing nowhere, which lookups tolerate)
+    fn remove(self) -> Result<(), Error> {
+        crate::tag_index::unindex(&self.tags)?;
+        crate::first_seen::forget(self.id)?;
+        crate::search_index::unindex(&self.search)?;
+        crate::expiration::forget(self.expiration)?;
+        crate::count::forget(self.id)?;
+        crate::replaceable::forget(self.latest)?;
+        Ok(())
+    }
+}
+
+/// Remove an event, including from our own indexes
+pub fn remove_event(id: Id) -> Result<(), Error> {
+    crate::failpoints::hit("remove_event")?;
+    let entries = unstore(id)?;
+    crate::failpoints::hit("remove_event.after_remove")?;
+    entries.remove()
+}
+
+// Remove an event from pocket, returning what our own indexes hold for it
+fn unstore(id: Id) -> Result<IndexEntries, Error> {
+    let store = GLOBALS.store.get().unwrap();
+
+    let entries = {
+        let _reading = crate::map_size::reading();
+        match store.get_event_by_id(id)? {
+            Some(event) => IndexEntries::of(event)?,
+            None => IndexEntries::of_missing(id),
+        }
+    };
+
+    let max_map_size = GLOBALS.config.read().lmdb_max_map_size;
+    crate::map_size::write(store, max_map_size, || Ok(store.remove_event(id)?))?;
+    Ok(entries)
+}
+
+/// Carry out a NIP-62 request to vanish: erase the author's events (and giftwraps to
+/// them), including from our own indexes
+pub fn vanish(event: &Event) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let pubkey = event.pubkey().as_hex_string();
+
+    // Everything it may erase
+    let mut entries: Vec<IndexEntries> = Vec::new();
+    for json in [
+        format!(r#"{{"authors":["{pubkey}"]}}"#),
+        format!(r##"{{"kinds":[1059],"#p":["{pubkey}"]}}"##),
+    ] {
+        let mut buffer: [u8; 256] = [0; 256];
+        let (_incount, _outcount, filter) = Filter::from_json(json.as_bytes(), &mut buffer)?;
+        let _reading = crate::map_size::reading();
+        let (events, _redacted) = store.find_events(filter, true, 0, 0, |_| ScreenResult::Match)?;
+        for event in events.iter() {
+            entries.push(IndexEntries::of(event)?);
+        }
+    }
+
+    let max_map_size = GLOBALS.config.read().lmdb_max_map_size;
+    crate::map_size::write(store, max_map_size, || Ok(store.vanish(event)?))?;
+
+    // And what it did
+    for entries in entries {
+        let gone = {
+            let _reading = crate::map_size::reading();
+            store.get_event_by_id(entries.id)?.is_none()
+        };
+        if gone {
+            entries.remove()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The result of `verify_store()`
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of events checked
+    pub events: usize,
+
+    /// Tag index entries left behind by removed events (harmless)
+    pub stale_index_entries: usize,
+
+    /// Index entries that lead to the wrong event (only rebuilding the store fixes these)
+    pub mismatched_entries: usize,
+
+    /// Events whose id or signature is wrong (which can only be removed)
+    pub damaged: Vec<Id>,
+
+    /// Events that some index does not find (which storing them again fixes)
+    pub unindexed: Vec<Id>,
+
+    /// Inconsistencies found
+    pub problems: Vec<String>,
+}
+
+/// Check that every stored event is valid and fully indexed, and that our indexes only
+/// refer to events that exist
+pub fn verify_store() -> Result<VerifyReport, Error> {
+    let mut report: VerifyReport = Default::default();
+
+    // A chunk of events at a time (see `crate::walk`), rather than every event at once
+    let mut walk = crate::walk::Walk::all();
+    while let Some(events) = walk.next_chunk(|_| ScreenResult::Match)? {
+        let _reading = crate::map_size::reading();
+        report.events += events.len();
+
+        for event in events.iter() {
+            let computed = crate::nostr::compute_event_id(&event.as_json()?)?;
+            if computed.as_slice() != event.id().as_slice() {
+                report.problems.push(format!(
+                    "Event {} has a mismatched id",
+                    event.id().as_hex_string()
+                ));
+                report.damaged.push(event.id());
+            } else if event.verify().is_err() {
+                report
+                    .problems
+                    .push(format!("Event {} is invalid", event.id().as_hex_string()));
+                report.damaged.push(event.id());
+            }
+        }
+
+        // pocket's own indexes (by id, created_at, author, kind and tag)
+        crate::integrity::check_indexes(&events, &mut report)?;
+
+        let (problems, missing) = crate::tag_index::verify_events(&events)?;
+        report.problems.extend(problems);
+        for id in missing {
+            if !report.unindexed.contains(&id) {
+                report.unindexed.push(id);
+            }
+        }
+    }
+
+    // And every entry of those indexes, once
+    crate::integrity::walk_indexes(&mut report)?;
+
+    let (stale, problems) = crate::tag_index::verify_entries()?;
+    report.stale_index_entries = stale;
+    report.problems.extend(problems);
+
+    Ok(report)
+}
+
+/// Get IpData from storage about this remote HashedIp (none for unix domain socket peers,
+/// who are never banned)
 pub fn get_ip_data(ip: HashedIp) -> Result<IpData, Error> {
+    if ip.is_unix_socket() {
+        return Ok(Default::default());
+    }
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let ip_data = store
         .extra_table("ip_data")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable("ip_data")))?;
@@ -700,12 +1488,17 @@ pub fn get_ip_data(ip: HashedIp) -> Result<IpData, Error> {
         Some(b) => b,
         None => return Ok(Default::default()),
     };
-    Ok(IpData::read_from_buffer(bytes)?)
+    Ok(IpData::from_bytes(bytes)?)
 }
 
-/// Get IpData in storage about this remote HashedIp
+/// Get IpData in storage about this remote HashedIp. It is not saved while we hand the
+/// store over, or in read-only and maintenance modes, or for unix domain socket peers.
 pub fn update_ip_data(ip: HashedIp, data: &IpData) -> Result<(), Error> {
+    if !crate::mode::background_writes() || ip.is_unix_socket() {
+        return Ok(());
+    }
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let ip_data = store
         .extra_table("ip_data")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable("ip_data")))?;
@@ -717,9 +1510,24 @@ pub fn update_ip_data(ip: HashedIp, data: &IpData) -> Result<(), Error> {
     Ok(())
 }
 
+/// Count a violation against an IP address as it happens, outside of a websocket session
+/// (whose violations are counted when it closes), banning it for at least
+/// `minimum_ban_seconds`. Returns how long it is banned for from now.
+pub fn add_ip_violation(
+    ip: HashedIp,
+    violation: Violation,
+    minimum_ban_seconds: u64,
+) -> Result<u64, Error> {
+    let mut ip_data = get_ip_data(ip)?;
+    let seconds = ip_data.add_violations(violation.points(), minimum_ban_seconds);
+    update_ip_data(ip, &ip_data)?;
+    Ok(seconds)
+}
+
 /// Dump all IpData from storage
 pub fn dump_ip_data() -> Result<Vec<(HashedIp, IpData)>, Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let ip_data = store
         .extra_table("ip_data")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable("ip_data")))?;
@@ -728,7 +1536,7 @@ pub fn dump_ip_data() -> Result<Vec<(HashedIp, IpData)>, Error> {
     for i in ip_data.iter(&txn)? {
         let (key, val) = i?;
         let hashedip = HashedIp::from_bytes(key);
-        let data = IpData::read_from_buffer(val)?;
+        let data = IpData::from_bytes(val)?;
         output.push((hashedip, data));
     }
     Ok(output)
@@ -737,6 +1545,7 @@ pub fn dump_ip_data() -> Result<Vec<(HashedIp, IpData)>, Error> {
 /// Mark an event as approved or not
 pub fn mark_event_approval(id: Id, approval: bool) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let approved_events = store
         .extra_table("approved-events")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable(
@@ -751,6 +1560,7 @@ pub fn mark_event_approval(id: Id, approval: bool) -> Result<(), Error> {
 /// Clear an event approval status
 pub fn clear_event_approval(id: Id) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let approved_events = store
         .extra_table("approved-events")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable(
@@ -765,6 +1575,7 @@ pub fn clear_event_approval(id: Id) -> Result<(), Error> {
 /// Fetch an event approval status
 pub fn get_event_approval(id: Id) -> Result<Option<bool>, Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let approved_events = store
         .extra_table("approved-events")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable(
@@ -779,6 +1590,7 @@ pub fn get_event_approval(id: Id) -> Result<Option<bool>, Error> {
 /// Dump all event approval statuses
 pub fn dump_event_approvals() -> Result<Vec<(Id, bool)>, Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let mut output: Vec<(Id, bool)> = Vec::new();
     let approved_events = store
         .extra_table("approved-events")
@@ -798,6 +1610,7 @@ pub fn dump_event_approvals() -> Result<Vec<(Id, bool)>, Error> {
 /// Mark a pubkey as approved or not
 pub fn mark_pubkey_approval(pubkey: Pubkey, approval: bool) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let approved_pubkeys = store
         .extra_table("approved-pubkeys")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable(
@@ -812,6 +1625,7 @@ pub fn mark_pubkey_approval(pubkey: Pubkey, approval: bool) -> Result<(), Error>
 /// Clear a pubkey approval status
 pub fn clear_pubkey_approval(pubkey: Pubkey) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let approved_pubkeys = store
         .extra_table("approved-pubkeys")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable(
@@ -826,6 +1640,7 @@ pub fn clear_pubkey_approval(pubkey: Pubkey) -> Result<(), Error> {
 /// Fetch a pubkey approval status
 pub fn get_pubkey_approval(pubkey: Pubkey) -> Result<Option<bool>, Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let approved_pubkeys = store
         .extra_table("approved-pubkeys")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable(
@@ -840,6 +1655,7 @@ pub fn get_pubkey_approval(pubkey: Pubkey) -> Result<Option<bool>, Error> {
 /// Dump all pubkey approval statuses
 pub fn dump_pubkey_approvals() -> Result<Vec<(Pubkey, bool)>, Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let mut output: Vec<(Pubkey, bool)> = Vec::new();
     let approved_pubkeys = store
         .extra_table("approved-pubkeys")
@@ -859,6 +1675,7 @@ pub fn dump_pubkey_approvals() -> Result<Vec<(Pubkey, bool)>, Error> {
 /// Add authorized user (or change moderator flag)
 pub fn add_authorized_user(pubkey: Pubkey, moderator: bool) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let users = store
         .extra_table("users")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable("users")))?;
@@ -871,6 +1688,7 @@ pub fn add_authorized_user(pubkey: Pubkey, moderator: bool) -> Result<(), Error>
 /// Remove authorized user
 pub fn rm_authorized_user(pubkey: Pubkey) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let users = store
         .extra_table("users")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable("users")))?;
@@ -883,6 +1701,7 @@ pub fn rm_authorized_user(pubkey: Pubkey) -> Result<(), Error> {
 /// Get authorized user
 pub fn get_authorized_user(pubkey: Pubkey) -> Result<Option<bool>, Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let users = store
         .extra_table("users")
         .ok_or(Into::<Error>::into(ChorusError::MissingTable("users")))?;
@@ -895,6 +1714,7 @@ pub fn get_authorized_user(pubkey: Pubkey) -> Result<Option<bool>, Error> {
 /// Dump all authorized users
 pub fn dump_authorized_users() -> Result<Vec<(Pubkey, bool)>, Error> {
     let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
     let mut output: Vec<(Pubkey, bool)> = Vec::new();
     let users = store
         .extra_table("users")
@@ -918,6 +1738,15 @@ pub fn is_authorized_user(pubkey: Pubkey) -> bool {
     }
 }
 
+/// Is the pubkey one of the relay's users, whose outbox and inbox we keep? These are the
+/// authorized users, those configured in `user_hex_keys`, and (if so configured) those
+/// whose relay list names us.
+pub fn is_relay_user(pubkey: Pubkey) -> bool {
+    is_authorized_user(pubkey)
+        || GLOBALS.config.read().user_keys.contains(&pubkey)
+        || matches!(crate::relay_list::is_user(pubkey), Ok(true))
+}
+
 /// Is the pubkey a moderator?
 pub fn is_moderator(pubkey: Pubkey) -> bool {
     match get_authorized_user(pubkey) {
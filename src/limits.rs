@@ -0,0 +1,158 @@
+//! Sanity limits on incoming events
+//!
+//! Clients with broken clocks submit events dated decades away, which then sit at the ends
+//! of our time-ordered indexes forever, and some submit events far larger than anybody
+//! needs. Events are checked against `created_at_upper_drift`, `created_at_lower_limit`,
+//! `max_event_bytes` and `max_event_tags` before anything expensive is done with them. An
+//! event exactly at a limit passes.
+
+use crate::config::Config;
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use pocket_types::{Event, Time};
+
+/// Check an event (whose JSON is `json_len` bytes) against the configured limits
+pub fn check(event: &Event, json_len: usize) -> Result<(), Error> {
+    check_at(
+        &GLOBALS.config.read(),
+        event,
+        json_len,
+        Time::now().as_u64(),
+    )
+}
+
+/// The earliest `created_at` accepted at `now` (for kinds that are not exempt), if any
+pub fn lower_bound(config: &Config, now: u64) -> Option<u64> {
+    match config.created_at_lower_limit {
+        0 => None,
+        n if n > 0 => Some(n as u64),
+        n => Some(now.saturating_sub(n.unsigned_abs())),
+    }
+}
+
+fn check_at(config: &Config, event: &Event, json_len: usize, now: u64) -> Result<(), Error> {
+    if json_len > config.max_event_bytes {
+        return Err(ChorusError::EventTooLarge.into());
+    }
+
+    let created_at = event.created_at().as_u64();
+    if created_at > now.saturating_add(config.created_at_upper_drift) {
+        return Err(ChorusError::CreatedAtTooNew.into());
+    }
+    if let Some(bound) = lower_bound(config, now) {
+        if created_at < bound
+            && !config
+                .created_at_lower_limit_exempt_kinds
+                .contains(&event.kind().as_u16())
+        {
+            return Err(ChorusError::CreatedAtTooOld.into());
+        }
+    }
+
+    if event.tags()?.iter().count() > config.max_event_tags {
+        return Err(ChorusError::TooManyTags.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    // An event (unsigned, which is fine here)
+    fn json(created_at: u64, kind: u16, num_tags: usize) -> String {
+        let tags = vec![r#"["t","x"]"#; num_tags].join(",");
+        format!(
+            r#"{{"id":"{}","pubkey":"{}","created_at":{created_at},"kind":{kind},"tags":[{tags}],"content":"","sig":"{}"}}"#,
+            "0".repeat(64),
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "0".repeat(128)
+        )
+    }
+
+    // Why `json` is refused, if it is
+    fn refusal(config: &Config, json: &str) -> Option<String> {
+        let mut buffer = vec![0_u8; json.len() + 256];
+        let (_size, event) = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+        check_at(config, event, json.len(), NOW)
+            .err()
+            .map(|e| format!("{}", e.inner))
+    }
+
+    fn check(config: &Config, created_at: u64, kind: u16, num_tags: usize) -> Option<String> {
+        refusal(config, &json(created_at, kind, num_tags))
+    }
+
+    #[test]
+    fn test_upper_drift() {
+        let config = Config {
+            created_at_upper_drift: 900,
+            ..Default::default()
+        };
+        assert_eq!(check(&config, NOW, 1, 0), None);
+        assert_eq!(check(&config, NOW + 900, 1, 0), None);
+        assert_eq!(
+            check(&config, NOW + 901, 1, 0).as_deref(),
+            Some("created_at too far in the future")
+        );
+    }
+
+    #[test]
+    fn test_lower_limit() {
+        // No limit
+        let config = Config::default();
+        assert_eq!(check(&config, 0, 1, 0), None);
+
+        // Absolute
+        let config = Config {
+            created_at_lower_limit: 1_600_000_000,
+            ..Default::default()
+        };
+        assert_eq!(check(&config, 1_600_000_000, 1, 0), None);
+        assert_eq!(
+            check(&config, 1_599_999_999, 1, 0).as_deref(),
+            Some("created_at too far in the past")
+        );
+
+        // Relative
+        let config = Config {
+            created_at_lower_limit: -86400,
+            ..Default::default()
+        };
+        assert_eq!(check(&config, NOW - 86400, 1, 0), None);
+        assert_eq!(
+            check(&config, NOW - 86401, 1, 0).as_deref(),
+            Some("created_at too far in the past")
+        );
+
+        // Exempt kinds
+        assert_eq!(check(&config, 1_000_000_000, 0, 0), None);
+        assert_eq!(check(&config, 1_000_000_000, 3, 0), None);
+        assert!(check(&config, 1_000_000_000, 10002, 0).is_some());
+    }
+
+    #[test]
+    fn test_size_and_tags() {
+        let json = json(NOW, 1, 3);
+        let config = Config {
+            max_event_bytes: json.len(),
+            ..Default::default()
+        };
+        assert_eq!(refusal(&config, &json), None);
+        let config = Config {
+            max_event_bytes: json.len() - 1,
+            ..Default::default()
+        };
+        assert_eq!(refusal(&config, &json).as_deref(), Some("event too large"));
+
+        let config = Config {
+            max_event_tags: 3,
+            ..Default::default()
+        };
+        assert_eq!(check(&config, NOW, 1, 3), None);
+        assert_eq!(check(&config, NOW, 1, 4).as_deref(), Some("too many tags"));
+    }
+}
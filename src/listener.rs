@@ -0,0 +1,337 @@
+//! The sockets chorus listens on
+//!
+//! By default chorus listens on `ip_address`:`port`, with TLS if `use_tls`. Instead, any
+//! number of `[[listeners]]` may be configured, each a TCP address and port or a unix
+//! domain socket, with TLS or without, and serving all or some of the relay (websockets
+//! and NIP-86 management), Blossom, NIP-11 (with the icon and banner) and metrics (with
+//! the health checks and stats). Each listener has an accept loop of its own, and every
+//! request is checked against the services of the listener its connection arrived on.
+//!
+//! Connections on a unix domain socket count as coming from 127.0.0.1 (unless a PROXY
+//! protocol preamble says otherwise), but are never banned by that address, which they
+//! all share (see `HashedIp::is_unix_socket`).
+
+use crate::counting_stream::CountingStream;
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::ip::{HashedPeer, UNIX_SOCKET_PEER};
+use hyper::body::Incoming;
+use hyper::Request;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+/// What a listener serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerService {
+    /// Websockets, and NIP-86 management requests
+    Relay,
+
+    /// Blossom
+    Blossom,
+
+    /// The NIP-11 document, icon and banner
+    Nip11,
+
+    /// Metrics, health checks and stats
+    Metrics,
+}
+
+fn all_services() -> Vec<ListenerService> {
+    vec![
+        ListenerService::Relay,
+        ListenerService::Blossom,
+        ListenerService::Nip11,
+        ListenerService::Metrics,
+    ]
+}
+
+/// One place to listen, as configured
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenerSpec {
+    /// The address to listen on (with `port`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<String>,
+
+    /// The port to listen on (with `ip_address`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// The path of a unix domain socket to listen on (instead of an address and port)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix_path: Option<String>,
+
+    /// Whether connections use TLS
+    #[serde(default)]
+    pub tls: bool,
+
+    /// What is served (everything unless listed)
+    #[serde(default = "all_services")]
+    pub services: Vec<ListenerService>,
+}
+
+impl ListenerSpec {
+    /// The listener used when none are configured
+    pub fn tcp(ip_address: &str, port: u16, tls: bool) -> ListenerSpec {
+        ListenerSpec {
+            ip_address: Some(ip_address.to_owned()),
+            port: Some(port),
+            unix_path: None,
+            tls,
+            services: all_services(),
+        }
+    }
+
+    /// Check that it says where to listen, in just one way
+    pub fn check(&self) -> Result<(), Error> {
+        match (&self.ip_address, self.port, &self.unix_path) {
+            (Some(_), Some(_), None) | (None, None, Some(_)) => Ok(()),
+            _ => Err(ChorusError::General(format!(
+                "listener {self}: needs either ip_address and port, or unix_path"
+            ))
+            .into()),
+        }
+    }
+
+    pub fn serves(&self, service: ListenerService) -> bool {
+        self.services.contains(&service)
+    }
+}
+
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.unix_path, &self.ip_address, self.port) {
+            (Some(path), _, _) => write!(f, "unix:{path}")?,
+            (None, Some(ip), Some(port)) => write!(f, "{ip}:{port}")?,
+            (None, ip, port) => write!(
+                f,
+                "{}:{}",
+                ip.as_deref().unwrap_or("?"),
+                port.map(|p| p.to_string()).unwrap_or("?".to_owned())
+            )?,
+        }
+        if self.tls {
+            write!(f, " (TLS)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The service a request is for, if it is one that listeners may leave out
+pub fn service_for(request: &Request<Incoming>) -> Option<ListenerService> {
+    use crate::web::router::{classify, Route};
+
+    let header_is = |name: &str, value: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v == value)
+    };
+
+    let route = classify(request.uri().path());
+    if matches!(route, Route::Metrics | Route::Health | Route::Stats) {
+        Some(ListenerService::Metrics)
+    } else if hyper_tungstenite::is_upgrade_request(request)
+        || header_is("Content-Type", "application/nostr+json+rpc")
+    {
+        Some(ListenerService::Relay)
+    } else if header_is("Accept", "application/nostr+json")
+        || matches!(route, Route::Icon | Route::Banner)
+    {
+        Some(ListenerService::Nip11)
+    } else if route.is_blossom() {
+        Some(ListenerService::Blossom)
+    } else {
+        None
+    }
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A bound listener
+pub struct Listener {
+    pub spec: Arc<ListenerSpec>,
+    socket: Socket,
+}
+
+impl Listener {
+    /// Bind to where `spec` says, failing with an error that names it
+    pub async fn bind(spec: &ListenerSpec) -> Result<Listener, Error> {
+        spec.check()?;
+        let bound = match (&spec.unix_path, &spec.ip_address, spec.port) {
+            (Some(path), _, _) => {
+                // A socket left behind by a previous run would be in the way, but one that
+                // is still accepted on belongs to somebody else
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    use std::os::unix::fs::FileTypeExt;
+                    if metadata.file_type().is_socket() {
+                        match UnixStream::connect(path).await {
+                            Ok(_) => {
+                                return Err(ChorusError::General(format!(
+                                    "listener {spec}: cannot bind: {path} is in use"
+                                ))
+                                .into())
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                                let _ = std::fs::remove_file(path);
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                }
+                UnixListener::bind(path).map(Socket::Unix)
+            }
+            (None, Some(ip), Some(port)) => TcpListener::bind((&**ip, port)).await.map(Socket::Tcp),
+            _ => unreachable!("checked above"),
+        };
+        match bound {
+            Ok(socket) => Ok(Listener {
+                spec: Arc::new(spec.clone()),
+                socket,
+            }),
+            Err(e) => {
+                Err(ChorusError::General(format!("listener {spec}: cannot bind: {e}")).into())
+            }
+        }
+    }
+
+    /// Adopt a listening socket handed over to us (see `crate::handover`)
+    pub fn adopt(spec: &ListenerSpec, fd: RawFd) -> Result<Listener, Error> {
+        let socket = if spec.unix_path.is_some() {
+            let std_listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            std_listener.set_nonblocking(true)?;
+            Socket::Unix(UnixListener::from_std(std_listener)?)
+        } else {
+            let std_listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            std_listener.set_nonblocking(true)?;
+            Socket::Tcp(TcpListener::from_std(std_listener)?)
+        };
+        Ok(Listener {
+            spec: Arc::new(spec.clone()),
+            socket,
+        })
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.as_raw_fd(),
+            Socket::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+
+    /// Accept connections and spawn a task to serve each one, for as long as the task
+    /// running this lives
+    pub async fn run(self) {
+        loop {
+            let accepted = match &self.socket {
+                Socket::Tcp(listener) => listener.accept().await.map(|(stream, peer_addr)| {
+                    tokio::spawn(handle(stream, peer_addr, self.spec.clone()));
+                }),
+                Socket::Unix(listener) => listener.accept().await.map(|(stream, _)| {
+                    tokio::spawn(handle(stream, UNIX_SOCKET_PEER, self.spec.clone()));
+                }),
+            };
+            if let Err(e) = accepted {
+                // Such as running out of file descriptors, which may pass
+                log::error!(target: "Server", "Listener {}: accept: {}", self.spec, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// Serve one connection
+async fn handle<S>(mut stream: S, peer_addr: SocketAddr, spec: Arc<ListenerSpec>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Learn the client's address from the PROXY protocol preamble, if our proxy sends
+    // one (and we trust it to)
+    let peer_addr = {
+        let (proxy_protocol, trusted) = {
+            let config = GLOBALS.config.read();
+            let trusted = config
+                .trusted_proxies
+                .iter()
+                .any(|cidr| cidr.contains(peer_addr.ip()));
+            (config.proxy_protocol, trusted)
+        };
+        if proxy_protocol {
+            match crate::proxy::read_preamble(&mut stream, peer_addr).await {
+                Ok(addr) if trusted => addr,
+                Ok(_) => peer_addr,
+                Err(e) => {
+                    log::info!(
+                        target: "Client",
+                        "{}: {}", HashedPeer::new(peer_addr), e
+                    );
+                    return;
+                }
+            }
+        } else {
+            peer_addr
+        }
+    };
+    let hashed_peer = HashedPeer::new(peer_addr);
+
+    // Possibly IP block early (before any TLS work)
+    if !GLOBALS.config.read().chorus_is_behind_a_proxy && GLOBALS.config.read().enable_ip_blocking {
+        match crate::get_ip_data(hashed_peer.ip()) {
+            Ok(ip_data) if ip_data.is_banned() => {
+                log::debug!(target: "Client",
+                            "{}: Blocking reconnection until {}",
+                            hashed_peer.ip(),
+                            ip_data.ban_until);
+                // note: no need to shutdown() which only drops the write half.
+                // the whole thing gets dropped when we return.
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!(target: "Client", "{}: {}", hashed_peer, e);
+                return;
+            }
+        }
+    }
+
+    let counting_stream = CountingStream(stream);
+
+    let tls_acceptor = if spec.tls {
+        crate::tls::acceptor()
+    } else {
+        None
+    };
+    match tls_acceptor {
+        Some(tls_acceptor) => match tls_acceptor.accept(counting_stream).await {
+            Ok(stream) => {
+                let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                let io = hyper_util::rt::TokioIo::new(stream);
+                if http2 {
+                    crate::serve_http2(io, peer_addr, spec).await;
+                } else {
+                    crate::serve(io, peer_addr, spec).await;
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    target: "Client",
+                    "{}: TLS accept: {}", hashed_peer, e
+                );
+            }
+        },
+        None => {
+            let io = hyper_util::rt::TokioIo::new(counting_stream);
+            crate::serve(io, peer_addr, spec).await;
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Growing the LMDB map when it fills up
+//!
+//! LMDB refuses writes with `MDB_MAP_FULL` once its memory map is full. Writes that go
+//! through `write()` instead grow the map (doubling it, up to `lmdb_max_map_size`) and are
+//! retried, and the map is grown ahead of time once it is three quarters full.
+//!
+//! The map may only be resized while no transaction, read or write, is open in this
+//! process. So everything that opens one holds a `reading()` guard while it does, and
+//! the resize waits for them all to be dropped (and keeps new ones waiting meanwhile).
+
+use crate::error::{ChorusError, Error};
+use parking_lot::{RwLock, RwLockReadGuard};
+use pocket_db::heed::{self, MdbError};
+use pocket_db::Store;
+use std::cell::Cell;
+
+static RESIZE: RwLock<()> = RwLock::new(());
+
+thread_local! {
+    // How many `Reading` guards this thread holds
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Keeps the map from being resized while it is held. Take one before opening a
+/// transaction, and keep it until the transaction (and anything borrowed from it) is
+/// gone. Guards nest, and cannot be held across an `.await`.
+pub struct Reading {
+    _guard: RwLockReadGuard<'static, ()>,
+}
+
+impl Drop for Reading {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Hold off resizing the map (see `Reading`)
+pub fn reading() -> Reading {
+    let guard = if DEPTH.with(|depth| depth.get()) == 0 {
+        // Queues behind a waiting resize, so a stream of readers cannot starve it
+        RESIZE.read()
+    } else {
+        // We already hold it, and queueing behind a resize would wait on ourselves
+        RESIZE.read_recursive()
+    };
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    Reading { _guard: guard }
+}
+
+/// Whether an error is LMDB saying the map is full
+pub fn is_map_full(e: &Error) -> bool {
+    let heed_error = match &e.inner {
+        ChorusError::PocketDbHeed(he) => he,
+        ChorusError::PocketDb(pe) => match &pe.inner {
+            pocket_db::InnerError::Lmdb(he) => he,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    matches!(heed_error, heed::Error::Mdb(MdbError::MapFull))
+}
+
+/// The current size of the map, in bytes
+pub fn map_size(store: &Store) -> u64 {
+    store.env().info().map_size as u64
+}
+
+/// How many bytes of the map are in use
+fn used(store: &Store) -> u64 {
+    // Safety: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    (store.env().info().last_page_number as u64 + 1) * page_size
+}
+
+/// Grow the map to at least `size` bytes (it is never shrunk).
+///
+/// This waits for every open transaction to finish, so it must not be called while
+/// holding a `reading()` guard (doing so is an error, rather than a deadlock).
+pub fn grow_to(store: &Store, size: u64) -> Result<(), Error> {
+    if DEPTH.with(|depth| depth.get()) > 0 {
+        return Err(ChorusError::General(
+            "Cannot grow the LMDB map while a transaction is open".to_owned(),
+        )
+        .into());
+    }
+    let _guard = RESIZE.write();
+    if size <= map_size(store) {
+        return Ok(());
+    }
+    // Safety: we hold the resize lock exclusively, and every transaction is opened under
+    // a `reading()` guard, so none is open
+    unsafe { store.env().resize(size as usize)? };
+    Ok(())
+}
+
+// Double the map, up to `max_map_size`, unless it is there already
+fn grow(store: &Store, max_map_size: u64) -> Result<(), Error> {
+    let size = map_size(store);
+    if size >= max_map_size {
+        log::error!(target: "Server", "LMDB map is full at {size} bytes, refusing writes");
+        return Err(ChorusError::StorageFull.into());
+    }
+    let new_size = size.saturating_mul(2).min(max_map_size);
+    log::warn!(target: "Server", "LMDB map is filling up, growing it from {size} to {new_size} bytes");
+    grow_to(store, new_size)
+}
+
+// Grow the map ahead of a write once it is three quarters full, so that writes which
+// cannot be retried (see `write_once`) rarely find it full
+fn make_room(store: &Store, max_map_size: u64) -> Result<(), Error> {
+    let size = map_size(store);
+    if size < max_map_size && used(store) > size / 4 * 3 {
+        grow(store, max_map_size)?;
+    }
+    Ok(())
+}
+
+/// Run a write, growing the map and retrying the write whenever the map is full. Once
+/// the map is `max_map_size` bytes it grows no further and this returns
+/// `ChorusError::StorageFull`. The write must be safe to repeat after it failed.
+pub fn write<T, F>(store: &Store, max_map_size: u64, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    make_room(store, max_map_size)?;
+    loop {
+        let result = {
+            let _reading = reading();
+            f()
+        };
+        match result {
+            Err(e) if is_map_full(&e) => grow(store, max_map_size)?,
+            result => return result,
+        }
+    }
+}
+
+/// Run a write that must not be repeated (like pocket's append to its events file, which
+/// a failed write may already have done). The map is grown ahead of time as in `write`,
+/// and if the map is full anyway it is grown for the next write, but this one fails.
+pub fn write_once<T, F>(store: &Store, max_map_size: u64, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    make_room(store, max_map_size)?;
+    let result = {
+        let _reading = reading();
+        f()
+    };
+    match result {
+        Err(e) if is_map_full(&e) => {
+            grow(store, max_map_size)?;
+            Err(e)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fill(store: &Store, from: u32, count: u32) -> Result<(), Error> {
+        let table = store.extra_table("filler").unwrap();
+        let mut txn = store.write_txn()?;
+        for i in from..from + count {
+            table.put(&mut txn, &i.to_be_bytes(), &[0xAA; 1000])?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_on_map_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path().to_str().unwrap(), vec!["filler"]).unwrap();
+
+        // Deliberately tiny, so that filling it up does not take long
+        let _guard = RESIZE.write();
+        unsafe { store.env().resize(1 << 20).unwrap() };
+        drop(_guard);
+        let initial = map_size(&store);
+
+        // A write that cannot be retried fails when the map is full, but grows it
+        let mut once = 0;
+        let result = write_once(&store, 1 << 30, || fill(&store, 0, 2000));
+        assert!(result.is_err_and(|e| is_map_full(&e)));
+        assert_eq!(map_size(&store), 2 * initial);
+        write_once(&store, 1 << 30, || {
+            once += 1;
+            fill(&store, 0, 100)
+        })
+        .unwrap();
+        assert_eq!(once, 1);
+
+        // Growing the map while a transaction is open is refused
+        {
+            let _reading = reading();
+            let _txn = store.read_txn().unwrap();
+            assert!(grow_to(&store, 1 << 28).is_err());
+        }
+
+        // Writing several times the map's size grows it (more than once)
+        for batch in 0..8 {
+            write(&store, 1 << 30, || fill(&store, batch * 1000, 1000)).unwrap();
+        }
+        assert!(map_size(&store) >= 4 * initial);
+
+        // Readers on other threads hold it off until they are done
+        let size = map_size(&store);
+        let reader = std::thread::scope(|scope| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let reader = scope.spawn(|| {
+                let _reading = reading();
+                let _txn = store.read_txn().unwrap();
+                tx.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                map_size(&store)
+            });
+            rx.recv().unwrap();
+            grow_to(&store, 2 * size).unwrap();
+            reader.join().unwrap()
+        });
+        assert_eq!(reader, size);
+        assert_eq!(map_size(&store), 2 * size);
+
+        // Until the ceiling
+        let ceiling = map_size(&store);
+        let mut full = false;
+        for batch in 8..64 {
+            if let Err(e) = write(&store, ceiling, || fill(&store, batch * 1000, 1000)) {
+                assert!(matches!(e.inner, ChorusError::StorageFull));
+                full = true;
+                break;
+            }
+        }
+        assert!(full);
+        assert_eq!(map_size(&store), ceiling);
+    }
+}
@@ -0,0 +1,342 @@
+//! Server-wide counters for the Prometheus `/metrics` endpoint
+//!
+//! Everything here is an atomic that the hot paths bump with a relaxed increment; the
+//! text exposition is only assembled when somebody scrapes us (see `web/metrics.rs`).
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Reasons an event is rejected for, as the machine-readable prefix of the OK message
+pub const REJECTION_REASONS: [&str; 8] = [
+    "auth-required",
+    "pow",
+    "duplicate",
+    "blocked",
+    "rate-limited",
+    "restricted",
+    "invalid",
+    "error",
+];
+
+/// What we time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Req,
+    Count,
+    Event,
+    Close,
+    Auth,
+    NegOpen,
+    NegMsg,
+    NegClose,
+    Http,
+}
+
+impl Handler {
+    const ALL: [Handler; 9] = [
+        Handler::Req,
+        Handler::Count,
+        Handler::Event,
+        Handler::Close,
+        Handler::Auth,
+        Handler::NegOpen,
+        Handler::NegMsg,
+        Handler::NegClose,
+        Handler::Http,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Handler::Req => "REQ",
+            Handler::Count => "COUNT",
+            Handler::Event => "EVENT",
+            Handler::Close => "CLOSE",
+            Handler::Auth => "AUTH",
+            Handler::NegOpen => "NEG-OPEN",
+            Handler::NegMsg => "NEG-MSG",
+            Handler::NegClose => "NEG-CLOSE",
+            Handler::Http => "http",
+        }
+    }
+
+    // Nostr messages (as opposed to HTTP requests)
+    fn is_message(&self) -> bool {
+        *self != Handler::Http
+    }
+}
+
+/// Upper bounds (in seconds) of the latency histogram buckets
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// A latency histogram. Buckets are not cumulative until rendered, the last one is +Inf.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(BUCKETS.len());
+        let _ = self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let _ = self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative: u64 = 0;
+        for (i, le) in BUCKETS.iter().enumerate() {
+            cumulative += self.buckets[i].load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+        }
+        cumulative += self.buckets[BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {cumulative}");
+        let _ = writeln!(
+            out,
+            "{name}_sum{{{labels}}} {}",
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count());
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub events_accepted: AtomicU64,
+
+    /// Events we already had (answered OK, but not accepted again)
+    pub events_duplicate: AtomicU64,
+
+    events_rejected: [AtomicU64; REJECTION_REASONS.len()],
+    latency: [Histogram; Handler::ALL.len()],
+
+    /// Open REQ subscriptions across all connections
+    pub subscriptions: AtomicUsize,
+
+    pub blossom_uploads: AtomicU64,
+    pub blossom_upload_bytes: AtomicU64,
+    pub blossom_downloads: AtomicU64,
+    pub blossom_download_bytes: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            events_accepted: AtomicU64::new(0),
+            events_duplicate: AtomicU64::new(0),
+            events_rejected: std::array::from_fn(|_| AtomicU64::new(0)),
+            latency: std::array::from_fn(|_| Histogram::default()),
+            subscriptions: AtomicUsize::new(0),
+            blossom_uploads: AtomicU64::new(0),
+            blossom_upload_bytes: AtomicU64::new(0),
+            blossom_downloads: AtomicU64::new(0),
+            blossom_download_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn event_accepted(&self) {
+        let _ = self.events_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn event_duplicate(&self) {
+        let _ = self.events_duplicate.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a rejection. Unknown reasons are counted as "error".
+    pub fn event_rejected(&self, reason: &str) {
+        let i = REJECTION_REASONS
+            .iter()
+            .position(|r| *r == reason)
+            .unwrap_or(REJECTION_REASONS.len() - 1);
+        let _ = self.events_rejected[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a handled message or request, and how long it took
+    pub fn observe(&self, handler: Handler, elapsed: Duration) {
+        self.latency[handler as usize].observe(elapsed);
+    }
+
+    /// Adjust the open subscription gauge after a connection went from `before` to
+    /// `after` subscriptions
+    pub fn subscriptions_changed(&self, before: usize, after: usize) {
+        if after > before {
+            let _ = self
+                .subscriptions
+                .fetch_add(after - before, Ordering::Relaxed);
+        } else if before > after {
+            let _ = self
+                .subscriptions
+                .fetch_sub(before - after, Ordering::Relaxed);
+        }
+    }
+
+    pub fn blossom_upload(&self, bytes: u64) {
+        let _ = self.blossom_uploads.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .blossom_upload_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn blossom_download(&self, bytes: u64) {
+        let _ = self.blossom_downloads.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .blossom_download_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Render our counters in the Prometheus text exposition format
+    pub fn render(&self, out: &mut String) {
+        header(
+            out,
+            "chorus_events_accepted_total",
+            "counter",
+            "Events accepted",
+        );
+        let _ = writeln!(
+            out,
+            "chorus_events_accepted_total {}",
+            self.events_accepted.load(Ordering::Relaxed)
+        );
+
+        header(
+            out,
+            "chorus_events_duplicate_total",
+            "counter",
+            "Events that were already stored",
+        );
+        let _ = writeln!(
+            out,
+            "chorus_events_duplicate_total {}",
+            self.events_duplicate.load(Ordering::Relaxed)
+        );
+
+        header(
+            out,
+            "chorus_events_rejected_total",
+            "counter",
+            "Events rejected, by reason",
+        );
+        for (i, reason) in REJECTION_REASONS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "chorus_events_rejected_total{{reason=\"{reason}\"}} {}",
+                self.events_rejected[i].load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            out,
+            "chorus_messages_total",
+            "counter",
+            "Nostr messages received, by type",
+        );
+        for handler in Handler::ALL.iter().filter(|h| h.is_message()) {
+            let _ = writeln!(
+                out,
+                "chorus_messages_total{{type=\"{}\"}} {}",
+                handler.name(),
+                self.latency[*handler as usize].count()
+            );
+        }
+
+        header(
+            out,
+            "chorus_handler_seconds",
+            "histogram",
+            "Time taken to handle nostr messages and HTTP requests",
+        );
+        for handler in Handler::ALL.iter() {
+            self.latency[*handler as usize].render(
+                out,
+                "chorus_handler_seconds",
+                &format!("handler=\"{}\"", handler.name()),
+            );
+        }
+
+        header(out, "chorus_subscriptions", "gauge", "Open subscriptions");
+        let _ = writeln!(
+            out,
+            "chorus_subscriptions {}",
+            self.subscriptions.load(Ordering::Relaxed)
+        );
+
+        for (name, help, value) in [
+            (
+                "chorus_blossom_uploads_total",
+                "Blobs uploaded (including mirrored)",
+                &self.blossom_uploads,
+            ),
+            (
+                "chorus_blossom_upload_bytes_total",
+                "Bytes of blobs uploaded (including mirrored)",
+                &self.blossom_upload_bytes,
+            ),
+            (
+                "chorus_blossom_downloads_total",
+                "Blobs served",
+                &self.blossom_downloads,
+            ),
+            (
+                "chorus_blossom_download_bytes_total",
+                "Bytes of blobs served",
+                &self.blossom_download_bytes,
+            ),
+        ] {
+            header(out, name, "counter", help);
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Write the HELP and TYPE lines of a metric
+pub fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(2));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "h", "handler=\"x\"");
+        assert!(out.contains("h_bucket{handler=\"x\",le=\"0.0001\"} 1\n"));
+        assert!(out.contains("h_bucket{handler=\"x\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("h_bucket{handler=\"x\",le=\"1\"} 2\n"));
+        assert!(out.contains("h_bucket{handler=\"x\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_count{handler=\"x\"} 3\n"));
+    }
+}
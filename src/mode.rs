@@ -0,0 +1,117 @@
+//! Read-only and maintenance modes
+//!
+//! For compacting the store, moving to new hardware and the like, the relay can be kept
+//! up without taking writes:
+//!
+//! * read-only: REQ, CLOSE, COUNT etc work as usual (subscriptions stay open through the
+//!   switch), but EVENTs are refused and Blossom uploads, mirrors and deletes get a 503.
+//!   Our own writes pause too (sweeps, garbage collection, the event sink and
+//!   forwarding), and what we would record as we serve (IP data and bans, since_last
+//!   cursors, blob access times) is not, so the store is left as it is.
+//! * maintenance: as read-only, but new websocket connections are sent a NOTICE and
+//!   closed, and HTTP requests get a 503 page.
+//!
+//! The NIP-11 document and NIP-86 management requests are still served in either mode
+//! (though commands that would change the store are refused), so that clients can see why
+//! and an admin can switch back. The mode is set by `mode` in
+//! the config (so a SIGHUP switches it), or by the `setmode` management command (which
+//! lasts until the next SIGHUP or restart).
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What clients refused for the mode are told to wait, in seconds (HTTP Retry-After)
+pub const RETRY_AFTER_SECONDS: u64 = 300;
+
+/// Which requests the relay serves
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Normal,
+    ReadOnly,
+    Maintenance,
+}
+
+impl Mode {
+    /// Whether events and uploads are accepted
+    pub fn accepts_writes(&self) -> bool {
+        *self == Mode::Normal
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Normal => write!(f, "normal"),
+            Mode::ReadOnly => write!(f, "read-only"),
+            Mode::Maintenance => write!(f, "maintenance"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Mode, Error> {
+        match &*s.trim().to_lowercase() {
+            "normal" => Ok(Mode::Normal),
+            "read-only" | "readonly" => Ok(Mode::ReadOnly),
+            "maintenance" => Ok(Mode::Maintenance),
+            _ => Err(ChorusError::General(format!("Unknown mode: {s}")).into()),
+        }
+    }
+}
+
+/// The current mode
+pub fn current() -> Mode {
+    GLOBALS.config.read().mode
+}
+
+/// Refuse a write unless in normal mode
+pub fn check_write() -> Result<(), Error> {
+    if current().accepts_writes() {
+        Ok(())
+    } else {
+        Err(ChorusError::ReadOnly.into())
+    }
+}
+
+/// Whether our own writes to the store go ahead: sweeps, garbage collection, the event sink
+/// and forwarding, and what we record as we serve (IP data, since_last cursors and the
+/// like). Only in normal mode, and not while we hand the store over to a new process.
+pub fn background_writes() -> bool {
+    check_write().is_ok() && !crate::handover::is_handing_over()
+}
+
+/// Wait until `background_writes()`, for tasks that write as they go
+pub async fn wait_for_writes() {
+    while !background_writes() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Switch to `mode`, until the config is next reloaded
+pub fn set(mode: Mode) {
+    let old = std::mem::replace(&mut GLOBALS.config.write().mode, mode);
+    if old != mode {
+        log::warn!(target: "Server", "Switched from {old} to {mode} mode");
+        let _ = crate::web::nip11::rebuild_rid();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mode_names() {
+        for mode in [Mode::Normal, Mode::ReadOnly, Mode::Maintenance] {
+            assert_eq!(mode.to_string().parse::<Mode>().unwrap(), mode);
+        }
+        assert_eq!("ReadOnly".parse::<Mode>().unwrap(), Mode::ReadOnly);
+        assert!("closed".parse::<Mode>().is_err());
+    }
+}
@@ -121,3 +121,54 @@ impl NegentropyStorageVector {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use negentropy::Negentropy;
+
+    fn id(n: u8) -> Id {
+        Id::from_slice(&[n; 32]).unwrap()
+    }
+
+    // Our storage must reconcile with the upstream implementation (as used by other
+    // relays and clients) on the other side
+    #[test]
+    fn test_reconcile_with_upstream() {
+        // We have 0..150, they have 100..250
+        let mut ours = NegentropyStorageVector::new();
+        for n in 0..150u8 {
+            ours.insert(1000 + n as u64, id(n)).unwrap();
+        }
+        ours.seal().unwrap();
+
+        let mut theirs = negentropy::NegentropyStorageVector::new();
+        for n in 100..250u8 {
+            theirs.insert(1000 + n as u64, id(n)).unwrap();
+        }
+        theirs.seal().unwrap();
+
+        let mut client = Negentropy::owned(theirs, 0).unwrap();
+        let mut msg = client.initiate().unwrap();
+        assert_eq!(msg[0], 0x61);
+
+        let mut have: Vec<Id> = Vec::new();
+        let mut need: Vec<Id> = Vec::new();
+        loop {
+            let mut server = Negentropy::owned(&ours, 0).unwrap();
+            let response = server.reconcile(&msg).unwrap();
+            match client
+                .reconcile_with_ids(&response, &mut have, &mut need)
+                .unwrap()
+            {
+                Some(next) => msg = next,
+                None => break,
+            }
+        }
+
+        have.sort();
+        need.sort();
+        assert_eq!(have, (150..250u8).map(id).collect::<Vec<Id>>());
+        assert_eq!(need, (0..100u8).map(id).collect::<Vec<Id>>());
+    }
+}
@@ -1,17 +1,38 @@
 use crate::error::{ChorusError, Error};
+use crate::filter::ChorusFilter;
 use crate::globals::GLOBALS;
+use crate::history::History;
+use crate::metrics::Handler;
 use crate::neg_storage::NegentropyStorageVector;
+use crate::rate_limit::Action;
 use crate::reply::{NostrReply, NostrReplyPrefix};
+use crate::write_policy::Verdict;
 use crate::WebSocketService;
 use hyper_tungstenite::tungstenite::Message;
 use negentropy::Negentropy;
 use pocket_db::ScreenResult;
 use pocket_types::json::{eat_whitespace, json_unescape, verify_char};
-use pocket_types::{read_hex, Event, Filter, Hll8, Kind, OwnedFilter, Pubkey, Time};
+use pocket_types::{read_hex, Event, Filter, Id, Kind, Pubkey, Time};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 use url::Url;
 
+// Stored events matching a REQ are flushed to the client every this many bytes
+const HISTORY_BATCH_BYTES: usize = 65536;
+
+/// An IP's count of event id mismatches starts over once it has gone this long without
+/// one, and is forgotten (see `rate_limit::run`)
+pub const EVENT_ID_MISMATCH_MEMORY: Duration = Duration::from_secs(3600);
+
 impl WebSocketService {
     pub async fn handle_nostr_message(&mut self, msg: &str) -> Result<(), Error> {
+        // Refuse what is too long before parsing any of it
+        if let Err(e) = crate::validation::check_message(msg.len()) {
+            let reply = NostrReply::Notice(format!("invalid: {}", e.inner));
+            self.send(Message::text(reply.as_json()?)).await?;
+            return Err(e);
+        }
+
         // If the msg is large, grow the session buffer
         // (it will be freed when they disconnect)
         if msg.len() > 4096 {
@@ -24,30 +45,62 @@ impl WebSocketService {
         let input = msg.as_bytes();
         let mut inpos = 0;
         eat_whitespace(input, &mut inpos);
-        verify_char(input, b'[', &mut inpos)?;
-        eat_whitespace(input, &mut inpos);
-        verify_char(input, b'"', &mut inpos)?;
-        if &input[inpos..inpos + 4] == b"REQ\"" {
-            self.req(msg, inpos + 4, false).await?;
-        } else if &input[inpos..inpos + 6] == b"COUNT\"" {
-            self.req(msg, inpos + 6, true).await?;
-        } else if &input[inpos..inpos + 6] == b"EVENT\"" {
-            self.event(msg, inpos + 6).await?;
-        } else if &input[inpos..inpos + 6] == b"CLOSE\"" {
-            self.close(msg, inpos + 6).await?;
-        } else if &input[inpos..inpos + 5] == b"AUTH\"" {
-            self.auth(msg, inpos + 5).await?;
-        } else if &input[inpos..inpos + 9] == b"NEG-OPEN\"" {
-            self.neg_open(msg, inpos + 9).await?;
-        } else if &input[inpos..inpos + 8] == b"NEG-MSG\"" {
-            self.neg_msg(msg, inpos + 8).await?;
-        } else if &input[inpos..inpos + 10] == b"NEG-CLOSE\"" {
-            self.neg_close(msg, inpos + 10).await?;
+        let mut framed = false;
+        if input.get(inpos) == Some(&b'[') {
+            inpos += 1;
+            eat_whitespace(input, &mut inpos);
+            if input.get(inpos) == Some(&b'"') {
+                inpos += 1;
+                framed = true;
+            }
+        }
+        if !framed {
+            let reply =
+                NostrReply::Notice("invalid: not a JSON array starting with a command".to_owned());
+            self.send(Message::text(reply.as_json()?)).await?;
+            return Err(ChorusError::BadRequest("not a nostr message").into());
+        }
+        let command = &input[inpos..];
+        let handler = if command.starts_with(b"REQ\"") {
+            Handler::Req
+        } else if command.starts_with(b"COUNT\"") {
+            Handler::Count
+        } else if command.starts_with(b"EVENT\"") {
+            Handler::Event
+        } else if command.starts_with(b"CLOSE\"") {
+            Handler::Close
+        } else if command.starts_with(b"AUTH\"") {
+            Handler::Auth
+        } else if command.starts_with(b"NEG-OPEN\"") {
+            Handler::NegOpen
+        } else if command.starts_with(b"NEG-MSG\"") {
+            Handler::NegMsg
+        } else if command.starts_with(b"NEG-CLOSE\"") {
+            Handler::NegClose
         } else {
             log::warn!(target: "Client", "{}: Received unhandled text message: {}", self.peer, msg);
             let reply = NostrReply::Notice("Command unrecognized".to_owned());
             self.send(Message::text(reply.as_json()?)).await?;
-        }
+            return Ok(());
+        };
+        let inpos = inpos + handler.name().len() + 1;
+
+        crate::trace::begin(handler.name());
+        let started = std::time::Instant::now();
+        let result = match handler {
+            Handler::Req => self.req(msg, inpos, false).await,
+            Handler::Count => self.req(msg, inpos, true).await,
+            Handler::Event => self.event(msg, inpos).await,
+            Handler::Close => self.close(msg, inpos).await,
+            Handler::Auth => self.auth(msg, inpos).await,
+            Handler::NegOpen => self.neg_open(msg, inpos).await,
+            Handler::NegMsg => self.neg_msg(msg, inpos).await,
+            Handler::NegClose => self.neg_close(msg, inpos).await,
+            Handler::Http => unreachable!(),
+        };
+        GLOBALS.metrics.observe(handler, started.elapsed());
+        result?;
+        log::debug!(target: "Client", "{}: {} handled", self.peer, handler.name());
 
         Ok(())
     }
@@ -71,23 +124,30 @@ impl WebSocketService {
             unsafe { String::from_utf8_unchecked(self.buffer[outpos..outpos + outlen].to_owned()) };
         outpos += outlen;
         verify_char(input, b'"', &mut inpos)?; // FIXME: json_unescape should eat the closing quote
+        crate::trace::sub_id(&subid);
 
-        // Read the filter into the session buffer
-        let mut filters: Vec<OwnedFilter> = Vec::new();
-        loop {
-            eat_whitespace(input, &mut inpos);
-            if input[inpos] == b']' {
-                break;
+        // Read the filters into the session buffer, refusing the whole REQ if any of them
+        // is invalid
+        let filters_start = inpos;
+        let filters = match self.read_filters(input, &mut inpos, outpos) {
+            Ok(filters) => filters,
+            Err(e) => {
+                let reply =
+                    NostrReply::Closed(&subid, NostrReplyPrefix::Invalid, format!("{}", e.inner));
+                self.send(Message::text(reply.as_json()?)).await?;
+                return Err(e);
             }
-            verify_char(input, b',', &mut inpos)?;
-            // whitespace after the comma is handled within Filter::from_json
-            let (incount, outcount, filter) =
-                Filter::from_json(&input[inpos..], &mut self.buffer[outpos..])?;
-            inpos += incount;
-            outpos += outcount;
-
-            filters.push(filter.to_owned());
-        }
+        };
+        log::debug!(
+            target: "Client",
+            "{}: {} {} {}",
+            self.peer,
+            if count { "COUNT" } else { "REQ" },
+            subid,
+            crate::trace::summarize(
+                msg[filters_start..inpos].trim_start_matches(|c: char| c == ',' || c.is_whitespace())
+            )
+        );
 
         if let Err(e) = self.req_inner(&subid, filters, count).await {
             let reply = match e.inner {
@@ -104,6 +164,11 @@ impl WebSocketService {
                 ChorusError::Scraper => {
                     NostrReply::Closed(&subid, NostrReplyPrefix::Invalid, format!("{}", e.inner))
                 }
+                ChorusError::RateLimited(_) | ChorusError::RateLimitExceeded => NostrReply::Closed(
+                    &subid,
+                    NostrReplyPrefix::RateLimited,
+                    format!("{}", e.inner),
+                ),
                 _ => NostrReply::Closed(&subid, NostrReplyPrefix::Error, format!("{}", e.inner)),
             };
             self.send(Message::text(reply.as_json()?)).await?;
@@ -113,27 +178,75 @@ impl WebSocketService {
         }
     }
 
+    // Read the filters of a REQ (or COUNT), starting at `inpos` in `input` and at `outpos`
+    // in the session buffer, and validate them (see `crate::validation`)
+    fn read_filters(
+        &mut self,
+        input: &[u8],
+        inpos: &mut usize,
+        mut outpos: usize,
+    ) -> Result<Vec<ChorusFilter>, Error> {
+        let max_filters = GLOBALS.config.read().max_filters;
+        let mut filters: Vec<ChorusFilter> = Vec::new();
+        loop {
+            eat_whitespace(input, inpos);
+            match input.get(*inpos) {
+                Some(b']') => break,
+                Some(_) => {}
+                None => return Err(ChorusError::BadRequest("unterminated message").into()),
+            }
+            verify_char(input, b',', inpos)?;
+            if filters.len() >= max_filters {
+                return Err(ChorusError::TooManyFilters.into());
+            }
+            // whitespace after the comma is handled within ChorusFilter::from_json
+            let (incount, outcount, filter) =
+                ChorusFilter::from_json(&input[*inpos..], &mut self.buffer[outpos..])?;
+            *inpos += incount;
+            outpos += outcount;
+
+            filters.push(filter);
+        }
+        Ok(filters)
+    }
+
     async fn req_inner(
         &mut self,
         subid: &String,
-        filters: Vec<OwnedFilter>,
+        mut filters: Vec<ChorusFilter>,
         count: bool,
     ) -> Result<(), Error> {
+        // Negentropy sessions count too
         let max_subscriptions = GLOBALS.config.read().max_subscriptions;
-        if self.subscriptions.len() >= max_subscriptions {
+        if self.subscriptions.len() + self.neg_subscriptions.len() >= max_subscriptions {
             return Err(ChorusError::TooManySubscriptions.into());
         }
 
         let user = self.user;
         let authorized_user = self.user.map(crate::is_authorized_user).unwrap_or(false);
 
+        if user.is_none() && GLOBALS.config.read().auth_required_for_read {
+            let reply = NostrReply::Closed(
+                subid,
+                NostrReplyPrefix::AuthRequired,
+                "this relay requires AUTH to read".to_owned(),
+            );
+            self.send(Message::text(reply.as_json()?)).await?;
+            self.send_auth_challenge().await?;
+            return Ok(());
+        }
+
+        crate::rate_limit::check(Action::Req, self.peer.ip(), user)?;
+
         if user.is_none() {
+            let private_dms = GLOBALS.config.read().private_dms;
             for filter in filters.iter() {
                 // If any DM kinds were requested, complain.
                 // But if NO kinds were requested, we will just silently not return DMs (elsewhere)
                 if filter
+                    .filter
                     .kinds()
-                    .any(|k| k.as_u16() == 4 || k.as_u16() == 1059)
+                    .any(|k| k.as_u16() == 1059 || (k.as_u16() == 4 && private_dms))
                 {
                     // They need to AUTH first to request DMs
                     let reply = NostrReply::Closed(
@@ -142,12 +255,63 @@ impl WebSocketService {
                         "DM kinds were included in the filters".to_owned(),
                     );
                     self.send(Message::text(reply.as_json()?)).await?;
+                    self.send_auth_challenge().await?;
                     return Ok(());
                 }
             }
         }
 
-        let completes = filters.iter().all(|f| f.completes());
+        // Only the relay's own users (and admins) may ask by _received_since or
+        // _received_until (since_seen is for anybody, with enable_since_seen)
+        if filters.iter().any(|f| f.received)
+            && !(authorized_user || user.is_some_and(crate::is_admin))
+        {
+            let (prefix, message) = if user.is_none() {
+                (NostrReplyPrefix::AuthRequired, "_received_since needs AUTH")
+            } else {
+                (
+                    NostrReplyPrefix::Restricted,
+                    "_received_since is for this relay's users",
+                )
+            };
+            let reply = NostrReply::Closed(subid, prefix, message.to_owned());
+            self.send(Message::text(reply.as_json()?)).await?;
+            self.send_auth_challenge().await?;
+            return Ok(());
+        }
+
+        // Filters that asked for it are served from where the same subscription of theirs
+        // left off (see crate::resume)
+        let mut cursor = match user {
+            Some(user) if filters.iter().any(|f| f.since_last) => Some(crate::resume::Cursor {
+                user,
+                fingerprint: crate::resume::fingerprint(&filters)?,
+                newest: None,
+            }),
+            _ => None,
+        };
+        if let Some(cursor) = &cursor {
+            if let Some(since) = crate::resume::load(cursor)? {
+                for filter in filters.iter_mut().filter(|f| f.since_last) {
+                    filter.resume_from(since, &mut self.buffer)?;
+                }
+            }
+        }
+
+        // Counting walks the indexes without collecting the events
+        if count {
+            let (count, opthll) = crate::count::count(&filters, user)?;
+            let reply = NostrReply::Count(subid, count, opthll);
+            self.send(Message::text(reply.as_json()?)).await?;
+            return Ok(());
+        }
+
+        let completes = filters.iter().all(|f| f.filter.completes());
+
+        // This replaces any subscription of theirs by the same id
+        if let Some(replaced) = self.cursors.remove(subid) {
+            crate::resume::save(&replaced)?;
+        }
 
         let mut redacted: bool = false;
 
@@ -159,82 +323,84 @@ impl WebSocketService {
 
         // Serve events matching subscription
         {
-            let mut events: Vec<&Event> = Vec::new();
-
-            for filter in filters.iter() {
-                let screen = |event: &Event| -> ScreenResult {
-                    let event_flags = event_flags(event, &user);
-                    screen_outgoing_event(event, &event_flags, authorized_user)
-                };
-                let (filter_events, was_redacted) = {
-                    let config = &*GLOBALS.config.read();
-                    GLOBALS.store.get().unwrap().find_events(
-                        filter,
-                        config.allow_scraping,
-                        config.allow_scrape_if_limited_to,
-                        config.allow_scrape_if_max_seconds,
-                        screen,
-                    )?
-                };
-                events.extend(filter_events);
-                redacted = redacted || was_redacted;
-            }
-
-            // sort
-            events.sort_by_key(|e| std::cmp::Reverse(e.created_at()));
+            let plan = crate::planner::plan(&filters)?;
+            log::debug!(target: "Client", "{}: REQ {} plan: {}", self.peer, subid, plan);
 
-            // dedup
-            events.dedup();
+            let screen = |event: &Event| -> ScreenResult {
+                let event_flags = event_flags(event, &user);
+                screen_outgoing_event(event, &event_flags, authorized_user)
+            };
 
-            if count {
-                // HyperLogLog count
-                let mut opthll: Option<Hll8> = None;
-                if filters.len() == 1 {
-                    if let Ok(Some(offset)) = filters[0].hyperloglog_offset() {
-                        let mut hll8 = Hll8::new();
-                        for event in events.iter() {
-                            hll8.add_element(event.pubkey().as_bytes(), offset)?;
+            // Those asked by since_seen first, in the order we received them, then the
+            // rest newest first (see crate::history)
+            let (by_seen, by_time): (Vec<&ChorusFilter>, Vec<&ChorusFilter>) =
+                filters.iter().partition(|f| f.since_seen.is_some());
+            let both = !by_seen.is_empty() && !by_time.is_empty();
+            let mut sent: HashSet<Id> = HashSet::new();
+            for group in [by_seen, by_time] {
+                if group.is_empty() {
+                    continue;
+                }
+                let mut history = History::new(group);
+
+                // A page at a time, each read only once the client has taken in the last,
+                // so a slow reader holds back the reads rather than piling up messages
+                // (and a gone one stops them right away)
+                while let Some(page) = history.next_page(&screen)? {
+                    for event in page {
+                        if both && !sent.insert(event.id()) {
+                            continue;
+                        }
+                        let reply = NostrReply::Event(subid, event);
+                        self.feed(Message::text(reply.as_json()?)).await?;
+                        if let Some(cursor) = cursor.as_mut() {
+                            cursor.delivered(event);
+                        }
+                        if self.unflushed >= HISTORY_BATCH_BYTES {
+                            self.flush().await?;
                         }
-                        opthll = Some(hll8);
                     }
+                    self.flush().await?;
                 }
-                let reply = NostrReply::Count(subid, events.len(), opthll);
-                self.send(Message::text(reply.as_json()?)).await?;
-            } else {
-                for event in events.drain(..) {
-                    let reply = NostrReply::Event(subid, event);
-                    self.send(Message::text(reply.as_json()?)).await?;
-                }
+                redacted = redacted || history.redacted;
+            }
 
-                // New policy Feb 2025: Redactions trigger a "CLOSED: auth-required" because
-                // some clients will not AUTH otherwise.
-                // (But we also already sent partial results, which I think is good)
-                if redacted {
-                    // They need to AUTH first
-                    let reply = NostrReply::Closed(
-                        subid,
-                        NostrReplyPrefix::AuthRequired,
-                        "At least one matching event requires AUTH".to_owned(),
-                    );
-                    self.send(Message::text(reply.as_json()?)).await?;
-                    return Ok(());
-                }
+            // New policy Feb 2025: Redactions trigger a "CLOSED: auth-required" because
+            // some clients will not AUTH otherwise.
+            // (But we also already sent partial results, which I think is good)
+            if redacted {
+                // They need to AUTH first
+                let reply = NostrReply::Closed(
+                    subid,
+                    NostrReplyPrefix::AuthRequired,
+                    "At least one matching event requires AUTH".to_owned(),
+                );
+                self.send(Message::text(reply.as_json()?)).await?;
+                self.send_auth_challenge().await?;
+                return Ok(());
+            }
 
-                if completes {
-                    // Closed
-                    let reply = NostrReply::Closed(subid, NostrReplyPrefix::None, "".to_owned());
-                    self.send(Message::text(reply.as_json()?)).await?;
-                } else {
-                    // EOSE
-                    let reply = NostrReply::Eose(subid);
-                    self.send(Message::text(reply.as_json()?)).await?;
+            if completes {
+                // Closed, so this is where it leaves off
+                if let Some(cursor) = cursor.take() {
+                    crate::resume::save(&cursor)?;
                 }
+                let reply = NostrReply::Closed(subid, NostrReplyPrefix::None, "".to_owned());
+                self.send(Message::text(reply.as_json()?)).await?;
+            } else {
+                // EOSE
+                let reply = NostrReply::Eose(subid);
+                self.send(Message::text(reply.as_json()?)).await?;
             }
         }
 
-        if !count && !completes {
+        if !completes {
             // Store subscription
             self.subscriptions.insert(subid.to_owned(), filters);
+            if let Some(cursor) = cursor {
+                let _ = self.cursors.insert(subid.to_owned(), cursor);
+            }
+            self.stats.subscription_opened(self.subscriptions.len());
 
             log::debug!(
                 target: "Client",
@@ -260,18 +426,81 @@ impl WebSocketService {
         let (_incount, event) = Event::from_json(&input[inpos..], &mut self.buffer)?;
         let id = event.id();
 
+        // Management commands are answered here, and never stored
+        if event.kind() == Kind::from(crate::admin::ADMIN_COMMAND_KIND) {
+            let (ok, prefix, msg) =
+                match crate::rate_limit::check(Action::Event, self.peer.ip(), self.user) {
+                    Ok(()) => crate::admin::handle(event).await,
+                    Err(e) => (false, NostrReplyPrefix::RateLimited, format!("{}", e.inner)),
+                };
+            let reply = NostrReply::Ok(id, ok, prefix, msg);
+            self.send(Message::text(reply.as_json()?)).await?;
+            return Ok(());
+        }
+
         if let Err(e) = self.event_inner().await {
             let reply = match e.inner {
                 ChorusError::AuthRequired => NostrReply::Ok(
                     id,
                     false,
                     NostrReplyPrefix::AuthRequired,
-                    PERSONAL_MSG.to_owned(),
+                    if GLOBALS.config.read().auth_required_for_write {
+                        "this relay requires AUTH to write".to_owned()
+                    } else {
+                        PERSONAL_MSG.to_owned()
+                    },
+                ),
+                ChorusError::ProtectedEvent => NostrReply::Ok(
+                    id,
+                    false,
+                    NostrReplyPrefix::AuthRequired,
+                    "this event may only be published by its author".to_owned(),
+                ),
+                ChorusError::RateLimited(_) | ChorusError::RateLimitExceeded => NostrReply::Ok(
+                    id,
+                    false,
+                    NostrReplyPrefix::RateLimited,
+                    format!("{}", e.inner),
                 ),
                 ChorusError::EventIsInvalid(ref why) => {
                     log::error!(target: "Client", "{}: {}", self.peer, e);
                     NostrReply::Ok(id, false, NostrReplyPrefix::Invalid, why.to_string())
                 }
+                ChorusError::EventIdMismatch(ref computed, ref got) => {
+                    let mismatches = {
+                        let now = Instant::now();
+                        let mut entry = GLOBALS
+                            .event_id_mismatches
+                            .entry(self.peer.ip())
+                            .or_insert((0, now));
+                        if now.duration_since(entry.1) > EVENT_ID_MISMATCH_MEMORY {
+                            entry.0 = 0;
+                        }
+                        *entry = (entry.0 + 1, now);
+                        entry.0
+                    };
+
+                    // Repeat offenders get punished more heavily
+                    self.error_punishment += (0.05 * (mismatches - 1) as f32).min(1.0);
+
+                    log::error!(
+                        target: "Client",
+                        "{}: {} ({} mismatches from this IP)",
+                        self.peer,
+                        e,
+                        mismatches
+                    );
+                    NostrReply::Ok(
+                        id,
+                        false,
+                        NostrReplyPrefix::Invalid,
+                        format!(
+                            "event id mismatch, computed {}… got {}…",
+                            &computed[..8],
+                            &got[..8]
+                        ),
+                    )
+                }
                 ChorusError::Restricted => {
                     log::error!(target: "Client", "{}: {}", self.peer, e);
                     NostrReply::Ok(
@@ -293,6 +522,54 @@ impl WebSocketService {
                     NostrReplyPrefix::Blocked,
                     "Author has been banned".to_string(),
                 ),
+                ChorusError::WritePolicyRejected(ref msg) => {
+                    NostrReply::Ok(id, false, NostrReplyPrefix::Blocked, msg.to_owned())
+                }
+                ChorusError::WritePolicyUnavailable => NostrReply::Ok(
+                    id,
+                    false,
+                    NostrReplyPrefix::Error,
+                    "write policy unavailable, try again later".to_owned(),
+                ),
+                ChorusError::NotAcceptedHere => NostrReply::Ok(
+                    id,
+                    false,
+                    NostrReplyPrefix::Blocked,
+                    "not accepted here".to_owned(),
+                ),
+                ChorusError::ReadOnly => NostrReply::Ok(
+                    id,
+                    false,
+                    NostrReplyPrefix::Error,
+                    "relay is in read-only mode".to_owned(),
+                ),
+                ChorusError::Duplicate => {
+                    NostrReply::Ok(id, true, NostrReplyPrefix::Duplicate, "".to_string())
+                }
+                ChorusError::CreatedAtTooOld
+                | ChorusError::CreatedAtTooNew
+                | ChorusError::EventTooLarge
+                | ChorusError::TooManyTags => {
+                    NostrReply::Ok(id, false, NostrReplyPrefix::Invalid, format!("{}", e.inner))
+                }
+                ChorusError::ServerBusy => NostrReply::Ok(
+                    id,
+                    false,
+                    NostrReplyPrefix::RateLimited,
+                    "server busy".to_owned(),
+                ),
+                ChorusError::Superseded => NostrReply::Ok(
+                    id,
+                    true,
+                    NostrReplyPrefix::Duplicate,
+                    "a newer version is stored".to_owned(),
+                ),
+                ChorusError::DeletedAddress => NostrReply::Ok(
+                    id,
+                    false,
+                    NostrReplyPrefix::Blocked,
+                    "That event is deleted".to_string(),
+                ),
                 ChorusError::PocketDb(ref pe) => match pe.inner {
                     pocket_db::InnerError::Deleted => NostrReply::Ok(
                         id,
@@ -307,9 +584,37 @@ impl WebSocketService {
                 },
                 _ => NostrReply::Ok(id, false, NostrReplyPrefix::Error, format!("{}", e.inner)),
             };
+            if let NostrReply::Ok(_, false, prefix, ref msg) = reply {
+                let reason = format!("{prefix}");
+                let reason = reason.trim_end_matches(": ");
+                let reason = if reason.is_empty() { "error" } else { reason };
+                self.stats.event_rejected(reason);
+                GLOBALS.metrics.event_rejected(reason);
+
+                // Delineate the event back out of the session buffer
+                let event = unsafe { Event::delineate(&self.buffer)? };
+                crate::rejected::record(event, format!("{prefix}{msg}"), self.peer);
+            } else if let NostrReply::Ok(_, true, NostrReplyPrefix::Duplicate, _) = reply {
+                // We already had it, so it is not counted as accepted (again)
+                self.stats.event_duplicate();
+                GLOBALS.metrics.event_duplicate();
+            } else {
+                self.stats.event_accepted();
+                GLOBALS.metrics.event_accepted();
+            }
             self.send(Message::text(reply.as_json()?)).await?;
+            // (Authorized users may publish anything, once AUTHed)
+            if matches!(
+                e.inner,
+                ChorusError::AuthRequired | ChorusError::ProtectedEvent
+            ) || (matches!(e.inner, ChorusError::NotAcceptedHere) && self.user.is_none())
+            {
+                self.send_auth_challenge().await?;
+            }
             Err(e)
         } else {
+            self.stats.event_accepted();
+            GLOBALS.metrics.event_accepted();
             let reply = NostrReply::Ok(id, true, NostrReplyPrefix::None, "".to_string());
             self.send(Message::text(reply.as_json()?)).await?;
             Ok(())
@@ -317,26 +622,66 @@ impl WebSocketService {
     }
 
     async fn event_inner(&mut self) -> Result<(), Error> {
+        // Delivery lag is measured from here
+        let ingested = std::time::Instant::now();
+
+        // The store belongs to the new process while we hand over
+        if crate::handover::is_handing_over() {
+            return Err(ChorusError::ShuttingDown.into());
+        }
+
+        // Compacting, migrating, etc
+        crate::mode::check_write()?;
+
         let user = self.user;
         let authorized_user = self.user.map(crate::is_authorized_user).unwrap_or(false);
 
+        if user.is_none() && GLOBALS.config.read().auth_required_for_write {
+            return Err(ChorusError::AuthRequired.into());
+        }
+
+        // Before anything expensive (like verifying the signature)
+        crate::rate_limit::check(Action::Event, self.peer.ip(), user)?;
+
         // Delineate the event back out of the session buffer
         let event = unsafe { Event::delineate(&self.buffer)? };
 
+        // Cheap sanity checks (see limits.rs)
+        let json = event.as_json()?;
+        crate::limits::check(event, json.len())?;
+
         let event_flags = event_flags(event, &user);
 
+        // We need not verify what we already have
+        let stored = {
+            let _reading = crate::map_size::reading();
+            GLOBALS
+                .store
+                .get()
+                .unwrap()
+                .get_event_by_id(event.id())?
+                .is_some()
+        };
+        if stored {
+            return Err(ChorusError::Duplicate.into());
+        }
+
         if GLOBALS.config.read().verify_events {
-            // Verify the event is valid (id is hash, signature is valid)
-            if let Err(e) = event.verify() {
-                return Err(ChorusError::EventIsInvalid(format!("{}", e.inner)).into());
-            }
+            // On a worker thread, see verify.rs
+            crate::verify::verify(json).await?;
+        }
+
+        // NIP-70: a protected event is only accepted from its author, over a connection
+        // AUTHed as them (even authorized users may not publish somebody else's)
+        if is_protected(event) && !event_flags.author_is_current_user {
+            return Err(ChorusError::ProtectedEvent.into());
         }
 
         // Handle Request to Vanish events
         if event.kind() == Kind::from(62) {
             if let Ok(true) = verify_relay_tag(event, true) {
                 // Erase their events (and giftwraps to them)
-                GLOBALS.store.get().unwrap().vanish(event)?;
+                crate::vanish(event)?;
 
                 // Add their pubkey to the blocklist so their events cannot come back
                 crate::mark_pubkey_approval(event.pubkey(), false)?;
@@ -346,17 +691,44 @@ impl WebSocketService {
         }
 
         // Screen the event to see if we are willing to accept it
-        if !screen_incoming_event(event, event_flags, authorized_user).await? {
-            if self.user.is_some() {
-                return Err(ChorusError::Restricted.into());
-            } else {
-                return Err(ChorusError::AuthRequired.into());
+        if !screen_incoming_event(event, authorized_user).await? {
+            return Err(ChorusError::NotAcceptedHere.into());
+        }
+
+        // And see what the write policy says
+        match crate::write_policy::check(event, self.peer).await? {
+            Verdict::Accept => {}
+            Verdict::Reject(msg) => return Err(ChorusError::WritePolicyRejected(msg).into()),
+            Verdict::ShadowReject => return Ok(()),
+        }
+
+        // Ephemeral events only go to open subscriptions
+        if crate::ephemeral::is_ephemeral(event.kind()) {
+            if let Err(e) = crate::sink::enqueue(event) {
+                log::error!(target: "Server", "Event sink enqueue failed: {e}");
             }
+            crate::ephemeral::advertise(event, ingested)?;
+            return Ok(());
         }
 
         // Store and index the event
-        let offset = GLOBALS.store.get().unwrap().store_event(event)?;
-        GLOBALS.new_events.send(offset)?; // advertise the new event
+        let offset = crate::store_event(event)?;
+
+        // Queue it for the event sink (never waits on the sink itself)
+        if let Err(e) = crate::sink::enqueue(event) {
+            log::error!(target: "Server", "Event sink enqueue failed: {e}");
+        }
+
+        // And for the peer relays we forward to
+        if let Err(e) = crate::forward::enqueue(event) {
+            log::error!(target: "Server", "Forwarding enqueue failed: {e}");
+        }
+
+        // advertise the new event
+        GLOBALS.new_events.send(crate::lag::NewEvent {
+            source: crate::lag::Source::Stored(offset),
+            ingested,
+        })?;
 
         Ok(())
     }
@@ -376,11 +748,15 @@ impl WebSocketService {
 
         // consider as a &str
         let subid = unsafe { std::str::from_utf8_unchecked(&self.buffer[..outlen]) };
+        crate::trace::sub_id(subid);
 
         // If we have that subscription
         if self.subscriptions.contains_key(subid) {
-            // Remove it
+            // Remove it, remembering where it left off
             self.subscriptions.remove(subid);
+            if let Some(cursor) = self.cursors.remove(subid) {
+                crate::resume::save(&cursor)?;
+            }
 
             // Don't send a CLOSED because there is no valid prefix for this kind of
             // message, and clients just presume it was closed.
@@ -468,6 +844,16 @@ impl WebSocketService {
         Ok(())
     }
 
+    // Remind a client that has not authenticated of our challenge (after telling them
+    // auth-required)
+    async fn send_auth_challenge(&mut self) -> Result<(), Error> {
+        if self.user.is_none() {
+            let reply = NostrReply::Auth(self.challenge.clone());
+            self.send(Message::text(reply.as_json()?)).await?;
+        }
+        Ok(())
+    }
+
     pub async fn neg_open(&mut self, msg: &str, mut inpos: usize) -> Result<(), Error> {
         let input = msg.as_bytes();
 
@@ -501,16 +887,51 @@ impl WebSocketService {
             return Ok(());
         }
 
-        // Read the filter into the session buffer
+        if self.user.is_none() && GLOBALS.config.read().auth_required_for_read {
+            let reply = NostrReply::NegErr(
+                &subid,
+                "auth-required: this relay requires AUTH to read".to_owned(),
+            );
+            self.send(Message::text(reply.as_json()?)).await?;
+            self.send_auth_challenge().await?;
+            return Ok(());
+        }
+
+        // Sessions count against max_subscriptions, along with REQs (reopening one
+        // replaces it)
+        let max_subscriptions = GLOBALS.config.read().max_subscriptions;
+        if !self.neg_subscriptions.contains_key(&subid)
+            && self.subscriptions.len() + self.neg_subscriptions.len() >= max_subscriptions
+        {
+            let reply = NostrReply::NegErr(
+                &subid,
+                format!(
+                    "blocked: No more than {max_subscriptions} subscriptions are allowed at any one time"
+                ),
+            );
+            self.send(Message::text(reply.as_json()?)).await?;
+            return Ok(());
+        }
+
+        // Read the filter, limited to one more event than we are willing to hold for a
+        // session, so the store stops looking once there are too many
+        let max_items = GLOBALS.config.read().negentropy_max_items;
         let filter = {
             eat_whitespace(input, &mut inpos);
             verify_char(input, b',', &mut inpos)?;
             // whitespace after the comma is handled within Filter::from_json
             let (incount, outcount, filter) =
                 Filter::from_json(&input[inpos..], &mut self.buffer[outpos..])?;
+            let mut json: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_slice(&input[inpos..inpos + incount])?;
+            let limit = (filter.limit() as usize).min(max_items.saturating_add(1));
+            let _ = json.insert("limit".to_owned(), limit.into());
             inpos += incount;
             outpos += outcount;
-            filter.to_owned()
+            let json = serde_json::to_vec(&json)?;
+            let mut buffer = vec![0_u8; json.len() * 2 + 256];
+            let (_incount, _outcount, capped) = Filter::from_json(&json, &mut buffer)?;
+            capped.to_owned()
         };
 
         // Read the negentropy message
@@ -545,13 +966,24 @@ impl WebSocketService {
         let user = self.user;
         let authorized_user = self.user.map(crate::is_authorized_user).unwrap_or(false);
 
-        // Find all matching events
+        // Find all matching events, but no more than we are willing to hold for a session
+        let matched: std::cell::Cell<usize> = std::cell::Cell::new(0);
         let mut events: Vec<&Event> = Vec::new();
         let screen = |event: &Event| -> ScreenResult {
+            // Once there are too many, the rest are only counted towards the limit (of
+            // each index range the store scans), so that it stops
+            if matched.get() > max_items {
+                return ScreenResult::Match;
+            }
             let event_flags = event_flags(event, &user);
-            screen_outgoing_event(event, &event_flags, authorized_user)
+            let result = screen_outgoing_event(event, &event_flags, authorized_user);
+            if result == ScreenResult::Match {
+                matched.set(matched.get() + 1);
+            }
+            result
         };
         let (filter_events, _redacted) = {
+            let _reading = crate::map_size::reading();
             let config = &*GLOBALS.config.read();
             GLOBALS.store.get().unwrap().find_events(
                 &filter,
@@ -561,6 +993,17 @@ impl WebSocketService {
                 screen,
             )?
         };
+        if matched.get() > max_items {
+            self.neg_subscriptions.remove(&subid);
+            let reply = NostrReply::NegErr(
+                &subid,
+                format!(
+                    "blocked: this query is too big (over {max_items} events), narrow the filter"
+                ),
+            );
+            self.send(Message::text(reply.as_json()?)).await?;
+            return Ok(());
+        }
         events.extend(filter_events);
         events.sort_by(|a, b| {
             a.created_at()
@@ -717,11 +1160,7 @@ impl WebSocketService {
     }
 }
 
-async fn screen_incoming_event(
-    event: &Event,
-    event_flags: EventFlags,
-    authorized_user: bool,
-) -> Result<bool, Error> {
+async fn screen_incoming_event(event: &Event, authorized_user: bool) -> Result<bool, Error> {
     // Accept anything from authenticated authorized users
     // We do this before checking moderation since authorized overrides moderation
     if authorized_user {
@@ -738,17 +1177,6 @@ async fn screen_incoming_event(
         return Err(ChorusError::BannedUser.into());
     }
 
-    // If the event has a '-' tag, require the user to be AUTHed and match
-    // the event author
-    for mut tag in event.tags()?.iter() {
-        if tag.next() == Some(b"-") {
-            // The event is protected. Only accept if user is AUTHed as the event author
-            if !event_flags.author_is_current_user {
-                return Err(ChorusError::ProtectedEvent.into());
-            }
-        }
-    }
-
     // Accept if an open relay
     if GLOBALS.config.read().open_relay {
         return Ok(true);
@@ -761,22 +1189,28 @@ async fn screen_incoming_event(
         return Ok(true);
     }
 
+    // Accept relay lists that make their authors our users
+    if crate::relay_list::makes_user(event) {
+        return Ok(true);
+    }
+
     // Allow if event kind ephemeral
     if event.kind().is_ephemeral() && GLOBALS.config.read().serve_ephemeral {
         return Ok(true);
     }
 
-    // If the author is one of our users, always accept it
-    if crate::is_authorized_user(event.pubkey()) {
+    // If the author is one of our users, always accept it (their outbox)
+    if crate::is_relay_user(event.pubkey()) {
         return Ok(true);
     }
 
-    // If the event tags one of our users, always accept it
+    // If the event tags one of our users, always accept it (their inbox, which includes
+    // giftwraps addressed to them)
     for mut tag in event.tags()?.iter() {
         if tag.next() == Some(b"p") {
             if let Some(value) = tag.next() {
                 if let Ok(pk) = Pubkey::read_hex(value) {
-                    if crate::is_authorized_user(pk) {
+                    if crate::is_relay_user(pk) {
                         return Ok(true);
                     }
                 }
@@ -787,28 +1221,112 @@ async fn screen_incoming_event(
     Ok(false)
 }
 
+/// Find the events matching a filter that pass the screen, via whichever index suits the
+/// filter best. Returns its newest (at most the filter's limit of them, except by
+/// `since_seen` which is in the order we received them) and whether any were redacted by
+/// the screen.
+pub fn find_events<F>(
+    filter: &ChorusFilter,
+    screen: F,
+) -> Result<(Vec<&'static Event>, bool), Error>
+where
+    F: Fn(&Event) -> ScreenResult,
+{
+    let indexed_condition = filter
+        .long_tags
+        .iter()
+        .find(|c| crate::tag_index::is_indexed(&c.name));
+    let found = match (filter.since_seen, &filter.search, indexed_condition) {
+        // Serve by when we received them
+        (Some(since_seen), _, _) => crate::first_seen::find_events(filter, since_seen, screen)?,
+        // Search via the search index
+        (None, Some(terms), _) => crate::search_index::find_events(filter, terms, screen)?,
+        // Use our own index for multi-letter tags where we have one
+        (None, None, Some(condition)) => crate::tag_index::find_events(filter, condition, screen)?,
+        (None, None, None) => {
+            let _reading = crate::map_size::reading();
+            let config = &*GLOBALS.config.read();
+            let (mut filter_events, was_redacted) = GLOBALS.store.get().unwrap().find_events(
+                &filter.filter,
+                config.allow_scraping,
+                config.allow_scrape_if_limited_to,
+                config.allow_scrape_if_max_seconds,
+                screen,
+            )?;
+            // Unindexed multi-letter tag conditions are applied afterwards
+            if !filter.long_tags.is_empty() {
+                let mut kept = Vec::with_capacity(filter_events.len());
+                for event in filter_events.drain(..) {
+                    if filter.long_tags_match(event)? {
+                        kept.push(event);
+                    }
+                }
+                filter_events = kept;
+            }
+            // The store walks each index range newest first and stops at the limit, but
+            // with several ranges (authors, kinds, tag values) it may return more than the
+            // limit, and in no particular order. Ties go in id order, to be repeatable.
+            filter_events.sort_by(|a, b| {
+                b.created_at()
+                    .cmp(&a.created_at())
+                    .then(a.id().cmp(&b.id()))
+            });
+            filter_events.truncate(filter.filter.limit() as usize);
+            (filter_events, was_redacted)
+        }
+    };
+    Ok(found)
+}
+
 pub fn screen_outgoing_event(
     event: &Event,
     event_flags: &EventFlags,
     authorized_user: bool,
 ) -> ScreenResult {
-    // Deny if it is a private event (DM or GiftWrap) and they are neither the recipient
-    // nor the author
+    // Deny if it is a GiftWrap and they are not its one and only recipient. Not even its
+    // author, whose key is a throwaway one.
+    // (even for authorized users)
+    // Once they have AUTHed, AUTH cannot help, so they are not told about it.
+    if event.kind() == Kind::from(1059) {
+        if event_flags.tags_only_current_user {
+            return ScreenResult::Match;
+        } else if event_flags.authenticated {
+            return ScreenResult::Mismatch;
+        } else {
+            return ScreenResult::Redacted;
+        }
+    }
+
+    // Deny if it is a DM and they are neither a recipient nor the author, if so configured
     // (even for authorized users)
-    if event.kind() == Kind::from(4) || event.kind() == Kind::from(1059) {
+    if event.kind() == Kind::from(4) && GLOBALS.config.read().private_dms {
         if event_flags.tags_current_user || event_flags.author_is_current_user {
             // they are tagged, it is ok
             return ScreenResult::Match;
+        } else if event_flags.authenticated {
+            // they are not tagged, and AUTH will not change that
+            return ScreenResult::Mismatch;
         } else {
             // they are not tagged
             return ScreenResult::Redacted;
         }
     }
 
-    // Deny (and delete) if it has an expired expiration tag
+    // Deny protected events to all but their author, if so configured
     // (even for authorized users)
+    if !event_flags.author_is_current_user
+        && GLOBALS.config.read().protected_events_author_only
+        && is_protected(event)
+    {
+        return ScreenResult::Redacted;
+    }
+
+    // Deny (and delete) if it has an expired expiration tag
+    // (even for authorized users, and leaving the store alone when we may not write)
     if matches!(event.is_expired(), Ok(true)) {
-        let _ = GLOBALS.store.get().unwrap().remove_event(event.id());
+        if crate::mode::background_writes() {
+            let _ = crate::remove_event(event.id());
+        }
         return ScreenResult::Mismatch;
     }
 
@@ -855,24 +1373,42 @@ pub fn screen_outgoing_event(
         return ScreenResult::Match;
     }
 
-    // Allow if author is one of our authorized users
+    // Allow if author is one of our users (their outbox)
     if event_flags.author_is_an_authorized_user {
         return ScreenResult::Match;
     }
 
+    // Allow if it tags whoever is asking (their inbox)
+    if event_flags.tags_current_user {
+        return ScreenResult::Match;
+    }
+
     // Do not allow the rest
     ScreenResult::Redacted
 }
 
 pub struct EventFlags {
+    // (whether the current user has AUTHed at all)
+    pub authenticated: bool,
+    // (authorized users, plus the other relay users, see `crate::is_relay_user`)
     pub author_is_an_authorized_user: bool,
     pub author_is_current_user: bool,
     pub tags_an_authorized_user: bool,
     pub tags_current_user: bool,
+    // (the current user is the only pubkey tagged)
+    pub tags_only_current_user: bool,
+}
+
+/// Whether an event is protected (NIP-70), i.e. has a `-` tag
+pub fn is_protected(event: &Event) -> bool {
+    match event.tags() {
+        Ok(tags) => tags.iter().any(|mut tag| tag.next() == Some(b"-")),
+        Err(_) => false,
+    }
 }
 
 pub fn event_flags(event: &Event, user: &Option<Pubkey>) -> EventFlags {
-    let author_is_an_authorized_user = crate::is_authorized_user(event.pubkey());
+    let author_is_an_authorized_user = crate::is_relay_user(event.pubkey());
 
     let author_is_current_user = match user {
         None => false,
@@ -881,10 +1417,12 @@ pub fn event_flags(event: &Event, user: &Option<Pubkey>) -> EventFlags {
 
     let mut tags_an_authorized_user = false;
     let mut tags_current_user = false;
+    let mut p_tags: usize = 0;
 
     if let Ok(tags) = event.tags() {
         for mut tag in tags.iter() {
             if let Some(b"p") = tag.next() {
+                p_tags += 1;
                 if let Some(value) = tag.next() {
                     if let Ok(tagged_pk) = Pubkey::read_hex(value) {
                         if let Some(current_user) = user {
@@ -893,7 +1431,7 @@ pub fn event_flags(event: &Event, user: &Option<Pubkey>) -> EventFlags {
                             }
                         }
 
-                        if crate::is_authorized_user(tagged_pk) {
+                        if crate::is_relay_user(tagged_pk) {
                             tags_an_authorized_user = true;
                         }
                     }
@@ -903,14 +1441,16 @@ pub fn event_flags(event: &Event, user: &Option<Pubkey>) -> EventFlags {
     }
 
     EventFlags {
+        authenticated: user.is_some(),
         author_is_an_authorized_user,
         author_is_current_user,
         tags_an_authorized_user,
         tags_current_user,
+        tags_only_current_user: tags_current_user && p_tags == 1,
     }
 }
 
-fn verify_relay_tag(event: &Event, allow_all_relays: bool) -> Result<bool, Error> {
+pub(crate) fn verify_relay_tag(event: &Event, allow_all_relays: bool) -> Result<bool, Error> {
     for mut tag in event.tags()?.iter() {
         match tag.next() {
             Some(b"relay") => {
@@ -953,3 +1493,180 @@ fn verify_challenge_tag(event: &Event, challenge: &[u8]) -> Result<bool, Error>
 
     Ok(false)
 }
+
+/// Compute the NIP-01 event id from the JSON of an event.
+///
+/// This is the sha256 hash of the serialized commitment
+/// `[0,<pubkey>,<created_at>,<kind>,<tags>,<content>]`
+pub fn compute_event_id(event_json: &[u8]) -> Result<[u8; 32], Error> {
+    use secp256k1::hashes::{sha256, Hash};
+    use serde_json::Value;
+
+    let value: Value = serde_json::from_slice(event_json)?;
+
+    // serde_json escapes strings exactly as NIP-01 requires (\n, \", \\, \r, \t, \b, \f,
+    // other control characters as \u00XX, everything else verbatim UTF-8)
+    let mut commitment: Vec<Value> = vec![Value::from(0)];
+    for name in ["pubkey", "created_at", "kind", "tags", "content"] {
+        match value.get(name) {
+            Some(v) => commitment.push(v.clone()),
+            None => return Err(ChorusError::EventIsInvalid(format!("missing {name}")).into()),
+        }
+    }
+    let bytes = serde_json::to_vec(&commitment)?;
+
+    let hash: sha256::Hash = Hash::hash(&bytes);
+    Ok(hash.to_byte_array())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PUBKEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const SIG: &str = "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
+
+    // A literal reading of the NIP-01 serialization rules, independent of serde_json
+    fn nip01_escape(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                '\u{8}' => out.push_str("\\b"),
+                '\u{c}' => out.push_str("\\f"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    // Escape everything outside of printable ASCII as \uXXXX, as some clients do
+    fn ascii_escape(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                ' '..='~' => out.push(c),
+                c => {
+                    let mut units = [0u16; 2];
+                    for unit in c.encode_utf16(&mut units) {
+                        out.push_str(&format!("\\u{:04X}", unit));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn reference_id(created_at: u64, kind: u16, tags: &[Vec<String>], content: &str) -> String {
+        use secp256k1::hashes::{sha256, Hash};
+
+        let tags = tags
+            .iter()
+            .map(|tag| {
+                let parts: Vec<String> = tag
+                    .iter()
+                    .map(|p| format!("\"{}\"", nip01_escape(p)))
+                    .collect();
+                format!("[{}]", parts.join(","))
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        let commitment = format!(
+            "[0,\"{PUBKEY}\",{created_at},{kind},[{tags}],\"{}\"]",
+            nip01_escape(content)
+        );
+        let hash: sha256::Hash = Hash::hash(commitment.as_bytes());
+        hex::encode(hash.to_byte_array())
+    }
+
+    #[test]
+    fn test_compute_event_id_known_vector() {
+        let json = concat!(
+            r#"{"id":"fc9dedd2549deedc0e07a84325467e2f5a9b4d5892e767555899933741013dee","#,
+            r#""pubkey":"79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798","#,
+            r#""created_at":1700000000,"kind":1,"#,
+            r#""tags":[["e","5c83da77af1dec6d7289834998ad7aafbd9e2191396d75ec3cc27f5a77226f36","wss://relay.example"],["t","nostr"]],"#,
+            r#""content":"Hello \"world\"\n\ttab ☕ 日本語 \\ back\b\f end","#,
+            r#""sig":"00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"}"#
+        );
+        let id = compute_event_id(json.as_bytes()).unwrap();
+        assert_eq!(
+            hex::encode(id),
+            "fc9dedd2549deedc0e07a84325467e2f5a9b4d5892e767555899933741013dee"
+        );
+    }
+
+    #[test]
+    fn test_compute_event_id_fuzz() {
+        const ALPHABET: &[char] = &[
+            'a', 'Z', '0', ' ', '"', '\\', '/', '\n', '\r', '\t', '\u{0}', '\u{8}', '\u{c}',
+            '\u{1f}', '\u{7f}', 'é', 'ß', '☕', '日', '本', '\u{2028}', '\u{fe0f}', '🎉', '𝄞',
+        ];
+
+        // Deterministic pseudo-random generator so failures are reproducible
+        let mut seed: u64 = 0x5eed_1234_abcd_ef01;
+        let mut next = move |n: usize| -> usize {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((seed >> 33) as usize) % n
+        };
+
+        for _ in 0..500 {
+            let created_at = next(2_000_000_000) as u64;
+            let kind = next(65536) as u16;
+            let random_string = |next: &mut dyn FnMut(usize) -> usize| -> String {
+                let len = next(24);
+                (0..len).map(|_| ALPHABET[next(ALPHABET.len())]).collect()
+            };
+            let content = random_string(&mut next);
+            let tags: Vec<Vec<String>> = (0..next(4))
+                .map(|_| (0..1 + next(3)).map(|_| random_string(&mut next)).collect())
+                .collect();
+
+            let expected = reference_id(created_at, kind, &tags, &content);
+
+            // Alternate between the two ways clients escape strings
+            let escape: fn(&str) -> String = if next(2) == 0 {
+                nip01_escape
+            } else {
+                ascii_escape
+            };
+            let tags_json = tags
+                .iter()
+                .map(|tag| {
+                    let parts: Vec<String> =
+                        tag.iter().map(|p| format!("\"{}\"", escape(p))).collect();
+                    format!("[{}]", parts.join(","))
+                })
+                .collect::<Vec<String>>()
+                .join(",");
+
+            let mut fields = vec![
+                format!(r#""id":"{expected}""#),
+                format!(r#""pubkey":"{PUBKEY}""#),
+                format!(r#""created_at":{created_at}"#),
+                format!(r#""kind":{kind}"#),
+                format!(r#""tags":[{tags_json}]"#),
+                format!(r#""content":"{}""#, escape(&content)),
+                format!(r#""sig":"{SIG}""#),
+            ];
+
+            // Shuffle the field ordering
+            for i in (1..fields.len()).rev() {
+                fields.swap(i, next(i + 1));
+            }
+            let json = format!("{{{}}}", fields.join(","));
+
+            let id = compute_event_id(json.as_bytes()).unwrap();
+            assert_eq!(hex::encode(id), expected, "failed on {json}");
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! Planning the store scans for the filters of one REQ
+//!
+//! Clients often send several filters that differ in only one respect, such as the same
+//! authors with different kinds. Run one at a time, those walk the same index ranges over
+//! and over. Instead, plain filters (see `ChorusFilter::is_plain`) that are the same but
+//! for one of `ids`, `authors`, `kinds` or a single-letter tag are merged into one scan
+//! over the union of their values, and exact duplicates are scanned once.
+//!
+//! A filter's `limit` applies to that filter alone, so a merged scan is limited to the sum
+//! of its members' limits and each member then takes its own newest matches from what
+//! was found. If the merged scan was cut short by its limit and a member did not get all
+//! it asked for, the others may have crowded its events out, and that member is scanned
+//! again on its own.
+//!
+//! Events found by more than one scan are only returned once.
+
+use crate::error::Error;
+use crate::filter::ChorusFilter;
+use pocket_db::ScreenResult;
+use pocket_types::{Event, Id};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fmt;
+
+/// The index a scan is expected to walk. (The store picks the most selective it has for
+/// a filter, in this order.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Index {
+    Ids,
+    FirstSeen,
+    Search,
+    LongTag,
+    Tags,
+    AuthorTime,
+    KindTime,
+    Time,
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Index::Ids => write!(f, "ids"),
+            Index::FirstSeen => write!(f, "first seen"),
+            Index::Search => write!(f, "search"),
+            Index::LongTag => write!(f, "long tag"),
+            Index::Tags => write!(f, "tags"),
+            Index::AuthorTime => write!(f, "author+time"),
+            Index::KindTime => write!(f, "kind+time"),
+            Index::Time => write!(f, "time"),
+        }
+    }
+}
+
+/// One walk of the store, serving one or more of the filters
+pub struct Scan {
+    /// The filters it serves (by position in the REQ)
+    pub members: Vec<usize>,
+
+    /// The filter to scan with, if it is not just that of the first member
+    pub merged: Option<ChorusFilter>,
+
+    pub index: Index,
+
+    /// Roughly how many index ranges it walks
+    pub ranges: usize,
+}
+
+/// The scans for the filters of a REQ
+pub struct Plan {
+    pub num_filters: usize,
+    pub scans: Vec<Scan>,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} scans for {} filters:",
+            self.scans.len(),
+            self.num_filters
+        )?;
+        for scan in self.scans.iter() {
+            write!(
+                f,
+                " [{:?} {} index, {} ranges]",
+                scan.members, scan.index, scan.ranges
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The dimensions filters can be merged on
+fn is_dimension(key: &str) -> bool {
+    matches!(key, "ids" | "authors" | "kinds") || (key.starts_with('#') && key.len() == 2)
+}
+
+fn array_len(json: &Map<String, Value>, key: &str) -> usize {
+    json.get(key)
+        .and_then(|v| v.as_array())
+        .map(|a| a.len())
+        .unwrap_or(0)
+}
+
+fn choose_index(filter: &ChorusFilter, json: &Map<String, Value>) -> (Index, usize) {
+    let tags: Vec<usize> = json
+        .keys()
+        .filter(|k| k.starts_with('#') && k.len() == 2)
+        .map(|k| array_len(json, k))
+        .collect();
+    let authors = array_len(json, "authors");
+    let kinds = array_len(json, "kinds");
+    if json.contains_key("ids") {
+        (Index::Ids, array_len(json, "ids"))
+    } else if filter.since_seen.is_some() {
+        (Index::FirstSeen, 1)
+    } else if filter.search.is_some() {
+        (
+            Index::Search,
+            filter.search.as_ref().map(|t| t.len()).unwrap_or(1),
+        )
+    } else if filter
+        .long_tags
+        .iter()
+        .any(|c| crate::tag_index::is_indexed(&c.name))
+    {
+        (Index::LongTag, 1)
+    } else if let Some(fewest) = tags.iter().min() {
+        (Index::Tags, *fewest)
+    } else if authors > 0 {
+        (Index::AuthorTime, authors * kinds.max(1))
+    } else if kinds > 0 {
+        (Index::KindTime, kinds)
+    } else {
+        (Index::Time, 1)
+    }
+}
+
+// The one dimension two filters differ in, ignoring their limits. Some("") if they do
+// not differ at all.
+fn differing_dimension<'a>(a: &'a Map<String, Value>, b: &Map<String, Value>) -> Option<&'a str> {
+    let mut differing: Option<&str> = None;
+    for key in a.keys().chain(b.keys()) {
+        if key == "limit" || a.get(key) == b.get(key) {
+            continue;
+        }
+        if !is_dimension(key) || !a.contains_key(key) || !b.contains_key(key) {
+            return None;
+        }
+        match differing {
+            Some(k) if k != key => return None,
+            _ => differing = Some(key_in(a, key)),
+        }
+    }
+    Some(differing.unwrap_or(""))
+}
+
+// `key` as borrowed from `map` (which has it)
+fn key_in<'a>(map: &'a Map<String, Value>, key: &str) -> &'a str {
+    map.get_key_value(key)
+        .map(|(k, _)| k.as_str())
+        .unwrap_or("")
+}
+
+// Filters being merged
+struct Group {
+    members: Vec<usize>,
+    json: Map<String, Value>,
+    key: Option<String>,
+}
+
+/// Plan the scans for `filters`
+pub fn plan(filters: &[ChorusFilter]) -> Result<Plan, Error> {
+    let mut groups: Vec<Group> = Vec::new();
+    let mut alone: Vec<usize> = Vec::new();
+
+    'filters: for (i, filter) in filters.iter().enumerate() {
+        if !filter.is_plain() {
+            alone.push(i);
+            continue;
+        }
+        let limited = filter.json.contains_key("limit");
+        for group in groups.iter_mut() {
+            if group.json.contains_key("limit") != limited {
+                continue;
+            }
+            let Some(key) = differing_dimension(&group.json, &filter.json) else {
+                continue;
+            };
+            let key = key.to_owned();
+            match (&group.key, key.is_empty()) {
+                // The same filter again
+                (_, true) => {}
+                // The first to differ decides what the group merges on
+                (None, false) => group.key = Some(key.clone()),
+                (Some(k), false) if *k == key => {}
+                _ => continue,
+            }
+            if !key.is_empty() {
+                let values = group
+                    .json
+                    .get_mut(&key)
+                    .and_then(|v| v.as_array_mut())
+                    .unwrap();
+                for value in filter.json[&key].as_array().unwrap() {
+                    if !values.contains(value) {
+                        values.push(value.clone());
+                    }
+                }
+            }
+            let limit = match (group.json.get("limit"), filter.json.get("limit")) {
+                (Some(a), Some(b)) => Some(
+                    a.as_u64()
+                        .unwrap_or(0)
+                        .saturating_add(b.as_u64().unwrap_or(0)),
+                ),
+                _ => None,
+            };
+            if let Some(limit) = limit {
+                let _ = group.json.insert("limit".to_owned(), limit.into());
+            }
+            group.members.push(i);
+            continue 'filters;
+        }
+        groups.push(Group {
+            members: vec![i],
+            json: filter.json.clone(),
+            key: None,
+        });
+    }
+
+    let mut scans: Vec<Scan> = Vec::new();
+    for group in groups.drain(..) {
+        let first = &filters[group.members[0]];
+        let merged = if group.members.len() > 1 {
+            let json = serde_json::to_vec(&group.json)?;
+            let mut buffer = vec![0_u8; json.len() * 2 + 1024];
+            let (_, _, merged) = ChorusFilter::from_json_as_is(&json, &mut buffer)?;
+            Some(merged)
+        } else {
+            None
+        };
+        let (index, ranges) = choose_index(first, &group.json);
+        scans.push(Scan {
+            members: group.members,
+            merged,
+            index,
+            ranges,
+        });
+    }
+    for i in alone {
+        let (index, ranges) = choose_index(&filters[i], &filters[i].json);
+        scans.push(Scan {
+            members: vec![i],
+            merged: None,
+            index,
+            ranges,
+        });
+    }
+    scans.sort_by_key(|scan| scan.members[0]);
+
+    Ok(Plan {
+        num_filters: filters.len(),
+        scans,
+    })
+}
+
+/// Find the events matching any of `filters` that pass the screen, by way of `plan`.
+/// Returns each only once (in no particular order), and whether any were redacted.
+pub fn find_events<F>(
+    filters: &[ChorusFilter],
+    plan: &Plan,
+    screen: F,
+) -> Result<(Vec<&'static Event>, bool), Error>
+where
+    F: Fn(&Event) -> ScreenResult,
+{
+    let mut events: Vec<&'static Event> = Vec::new();
+    let mut seen: HashSet<Id> = HashSet::new();
+    let mut redacted = false;
+    let mut add = |found: Vec<&'static Event>, events: &mut Vec<&'static Event>| {
+        for event in found {
+            if seen.insert(event.id()) {
+                events.push(event);
+            }
+        }
+    };
+
+    for scan in plan.scans.iter() {
+        let Some(merged) = &scan.merged else {
+            let (found, was_redacted) =
+                crate::nostr::find_events(&filters[scan.members[0]], &screen)?;
+            redacted |= was_redacted;
+            add(found, &mut events);
+            continue;
+        };
+
+        let (mut found, was_redacted) = crate::nostr::find_events(merged, &screen)?;
+        redacted |= was_redacted;
+        let cut_short = found.len() >= merged.filter.limit() as usize;
+        found.sort_by_key(|e| std::cmp::Reverse(e.created_at()));
+
+        for &member in scan.members.iter() {
+            let filter = &filters[member];
+            let limit = filter.filter.limit() as usize;
+            let mut mine: Vec<&'static Event> = Vec::new();
+            for &event in found.iter() {
+                if mine.len() >= limit {
+                    break;
+                }
+                if filter.event_matches(event)? {
+                    mine.push(event);
+                }
+            }
+            if cut_short && mine.len() < limit {
+                // Its events may have been crowded out by the others'
+                let (alone, was_redacted) = crate::nostr::find_events(filter, &screen)?;
+                redacted |= was_redacted;
+                mine = alone;
+            }
+            add(mine, &mut events);
+        }
+    }
+
+    Ok((events, redacted))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn json(s: &str) -> Map<String, Value> {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_differing_dimension() {
+        let a = json(r#"{"authors":["aa"],"kinds":[1],"limit":10}"#);
+        let b = json(r#"{"authors":["aa"],"kinds":[7],"limit":20}"#);
+        assert_eq!(differing_dimension(&a, &b), Some("kinds"));
+        assert_eq!(differing_dimension(&a, &a), Some(""));
+
+        // Two dimensions, or something other than a dimension
+        let c = json(r#"{"authors":["bb"],"kinds":[7],"limit":10}"#);
+        assert_eq!(differing_dimension(&a, &c), None);
+        let d = json(r#"{"authors":["aa"],"kinds":[1],"since":5,"limit":10}"#);
+        assert_eq!(differing_dimension(&a, &d), None);
+
+        // Only in one of them
+        let e = json(r#"{"authors":["aa"],"limit":10}"#);
+        assert_eq!(differing_dimension(&a, &e), None);
+    }
+}
@@ -0,0 +1,287 @@
+//! Learning the real client address when we are behind a proxy
+//!
+//! Proxies tell us the client address either in an HTTP header (`X-Real-Ip` or
+//! `X-Forwarded-For`), which we only believe from peers within `trusted_proxies`, or in a
+//! PROXY protocol (v1 or v2) preamble at the start of the TCP stream, if
+//! `proxy_protocol` is set. Whatever we learn is what gets hashed into the `HashedPeer`, so
+//! banning, rate limiting and logging all apply to the client rather than the proxy.
+
+use crate::error::{ChorusError, Error};
+use http::HeaderMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// A range of IP addresses, as in `10.0.0.0/8` (a bare address is a range of one)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Compare IPv4-mapped IPv6 addresses as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr, Error> {
+        let bad = || Into::<Error>::into(ChorusError::General(format!("Bad CIDR: {s}")));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| bad())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().map_err(|_| bad())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(bad());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+fn is_trusted(ip: IpAddr, trusted: &[Cidr]) -> bool {
+    trusted.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// The client address given by a proxy's `X-Real-Ip` or `X-Forwarded-For` header, if
+/// either is present. Only call this for a peer that is a trusted proxy.
+///
+/// `X-Forwarded-For` is read from the right (the end our own proxy appended to), skipping
+/// any further trusted proxies, since anything to the left of that may be made up by the
+/// client.
+pub fn forwarded_ip(headers: &HeaderMap, trusted: &[Cidr]) -> Result<Option<IpAddr>, Error> {
+    if let Some(rip) = headers.get("x-real-ip") {
+        let ripstr = rip
+            .to_str()
+            .map_err(|_| Into::<Error>::into(ChorusError::BadRealIpHeaderCharacters))?;
+        return match ripstr.trim().parse::<IpAddr>() {
+            Ok(ip) => Ok(Some(ip)),
+            Err(_) => Err(ChorusError::BadRealIpHeader(ripstr.to_owned()).into()),
+        };
+    }
+
+    let mut hops: Vec<IpAddr> = Vec::new();
+    for value in headers.get_all("x-forwarded-for") {
+        let s = value
+            .to_str()
+            .map_err(|_| Into::<Error>::into(ChorusError::BadRealIpHeaderCharacters))?;
+        for part in s.split(',') {
+            match part.trim().parse::<IpAddr>() {
+                Ok(ip) => hops.push(ip),
+                Err(_) => return Err(ChorusError::BadRealIpHeader(s.to_owned()).into()),
+            }
+        }
+    }
+    Ok(hops
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(**ip, trusted))
+        .or(hops.first())
+        .copied())
+}
+
+// The PROXY protocol v2 signature
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// A v1 header is at most this long, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+// How long a proxy gets to send its preamble
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read the PROXY protocol (v1 or v2) preamble from the start of a stream, returning the
+/// client address in it. Nothing after the preamble is consumed. A preamble that does not
+/// name a client (v1 UNKNOWN, v2 LOCAL, or an address family we do not handle) yields
+/// `peer_addr`.
+pub async fn read_preamble<S>(stream: &mut S, peer_addr: SocketAddr) -> Result<SocketAddr, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    match tokio::time::timeout(PREAMBLE_TIMEOUT, read_preamble_inner(stream)).await {
+        Ok(Ok(Some(addr))) => Ok(addr),
+        Ok(Ok(None)) => Ok(peer_addr),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(ChorusError::ProxyProtocol("timed out".to_owned()).into()),
+    }
+}
+
+async fn read_preamble_inner<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both versions are at least this long
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(header[0], header[1], &body)
+    } else if start.starts_with(b"PROXY ") {
+        // Read up to the CRLF, a byte at a time so as to not read past it
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(ChorusError::ProxyProtocol("v1 header too long".to_owned()).into());
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        Err(ChorusError::ProxyProtocol("missing preamble".to_owned()).into())
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let bad = || Into::<Error>::into(ChorusError::ProxyProtocol("bad v1 header".to_owned()));
+    let line = std::str::from_utf8(line).map_err(|_| bad())?;
+    let mut parts = line.trim_end_matches("\r\n").split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(bad());
+    }
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(bad()),
+    }
+    let src: IpAddr = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+    let _dst: IpAddr = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+    let sport: u16 = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+    Ok(Some(SocketAddr::new(src, sport)))
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let bad = |s: &str| Into::<Error>::into(ChorusError::ProxyProtocol(s.to_owned()));
+    if ver_cmd >> 4 != 2 {
+        return Err(bad("bad v2 version"));
+    }
+    match ver_cmd & 0x0F {
+        0 => return Ok(None), // LOCAL (e.g. the proxy's own health checks)
+        1 => {}               // PROXY
+        _ => return Err(bad("bad v2 command")),
+    }
+    match family {
+        // TCP over IPv4: src addr, dst addr, src port, dst port
+        0x11 => {
+            if body.len() < 12 {
+                return Err(bad("short v2 IPv4 address"));
+            }
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[0..4]).unwrap());
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over IPv6
+        0x21 => {
+            if body.len() < 36 {
+                return Err(bad("short v2 IPv6 address"));
+            }
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+
+        let one: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(one.contains("127.0.0.1".parse().unwrap()));
+        assert!(!one.contains("127.0.0.2".parse().unwrap()));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains("fd12::1".parse().unwrap()));
+        assert!(!v6.contains("fe80::1".parse().unwrap()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nonsense".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_forwarded_ip() {
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_ip(&headers, &trusted).unwrap(), None);
+
+        // The client may have put anything on the left
+        headers.insert(
+            "x-forwarded-for",
+            "1.1.1.1, 192.0.2.7, 10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(
+            forwarded_ip(&headers, &trusted).unwrap(),
+            Some("192.0.2.7".parse().unwrap())
+        );
+
+        headers.insert("x-real-ip", "198.51.100.1".parse().unwrap());
+        assert_eq!(
+            forwarded_ip(&headers, &trusted).unwrap(),
+            Some("198.51.100.1".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preamble() {
+        let peer: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+
+        let mut v1: &[u8] = b"PROXY TCP4 192.0.2.7 10.0.0.1 5678 443\r\nGET /";
+        let addr = read_preamble(&mut v1, peer).await.unwrap();
+        assert_eq!(addr, "192.0.2.7:5678".parse().unwrap());
+        assert_eq!(v1, b"GET /");
+
+        let mut unknown: &[u8] = b"PROXY UNKNOWN\r\nGET /";
+        assert_eq!(read_preamble(&mut unknown, peer).await.unwrap(), peer);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[192, 0, 2, 7, 10, 0, 0, 1, 0x16, 0x2e, 0x01, 0xbb]);
+        v2.extend_from_slice(b"GET /");
+        let mut v2: &[u8] = &v2;
+        let addr = read_preamble(&mut v2, peer).await.unwrap();
+        assert_eq!(addr, "192.0.2.7:5678".parse().unwrap());
+        assert_eq!(v2, b"GET /");
+
+        let mut none: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_preamble(&mut none, peer).await.is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! Token bucket rate limits on EVENT submissions, REQ (and COUNT) creations and Blossom
+//! uploads
+//!
+//! Each action has a bucket per hashed IP, and another per pubkey once the client has
+//! authenticated (so a user cannot dodge the limit by spreading out over addresses).
+//! Buckets hold a minute's worth of the configured rate and refill continuously.
+//!
+//! Hitting a limit is answered with `rate-limited:` (or 429) and the request is not
+//! handled, but the connection stays up. An IP that keeps hitting limits is banned for a
+//! while, through its reputation (see `ip::IpReputation`), so that the ban holds across
+//! reconnects and counts towards longer ones.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::ip::{HashedIp, Violation};
+use dashmap::DashMap;
+use pocket_types::Pubkey;
+use std::time::{Duration, Instant};
+
+// Violations are counted over this window before escalating to a ban
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+
+// How often idle buckets are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// What is being limited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Event,
+    Req,
+    BlossomUpload,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::Event => "events",
+            Action::Req => "subscriptions",
+            Action::BlossomUpload => "uploads",
+        }
+    }
+
+    // The configured rate, per minute (0 for unlimited)
+    fn per_minute(&self) -> u32 {
+        let config = GLOBALS.config.read();
+        match *self {
+            Action::Event => config.max_events_per_minute,
+            Action::Req => config.max_reqs_per_minute,
+            Action::BlossomUpload => config.max_blossom_uploads_per_minute,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(HashedIp),
+    Pubkey([u8; 32]),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Take a token if there is one
+    fn take(&mut self, per_minute: u32) -> bool {
+        let capacity = per_minute as f64;
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * capacity / 60.0;
+        self.tokens = (self.tokens + refill).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    buckets: DashMap<(Key, Action), Bucket>,
+
+    // Recent violations by IP: how many, since when
+    violations: DashMap<HashedIp, (u32, Instant)>,
+}
+
+impl RateLimits {
+    fn take(&self, key: Key, action: Action, per_minute: u32) -> bool {
+        self.buckets
+            .entry((key, action))
+            .or_insert_with(|| Bucket {
+                tokens: per_minute as f64,
+                updated: Instant::now(),
+            })
+            .take(per_minute)
+    }
+
+    // Count a violation, returning true if the IP has now earned a ban
+    fn violated(&self, ip: HashedIp, ban_after: u32) -> bool {
+        let now = Instant::now();
+        let mut entry = self.violations.entry(ip).or_insert((0, now));
+        if now.duration_since(entry.1) > VIOLATION_WINDOW {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+        if ban_after > 0 && entry.0 >= ban_after {
+            *entry = (0, now);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Forget buckets that have been idle long enough to be full again
+    fn prune(&self) {
+        self.buckets
+            .retain(|_, bucket| bucket.updated.elapsed() < Duration::from_secs(60));
+        self.violations
+            .retain(|_, (_, since)| since.elapsed() < VIOLATION_WINDOW);
+    }
+}
+
+/// Take a token for `action` from the buckets of `ip` and (if authenticated) `pubkey`.
+///
+/// Returns `ChorusError::RateLimited` if either is empty, or
+/// `ChorusError::RateLimitExceeded` if that was one violation too many and the IP is now
+/// banned.
+pub fn check(action: Action, ip: HashedIp, pubkey: Option<Pubkey>) -> Result<(), Error> {
+    let per_minute = action.per_minute();
+    if per_minute == 0 {
+        return Ok(());
+    }
+
+    let (exempt, ban_after, ban_seconds) = {
+        let config = GLOBALS.config.read();
+        (
+            pubkey.is_some_and(|pk| config.rate_limit_exempt_pubkeys.contains(&pk)),
+            config.rate_limit_ban_after,
+            config.rate_limit_ban_seconds,
+        )
+    };
+    if exempt {
+        return Ok(());
+    }
+
+    let limits = &GLOBALS.rate_limits;
+    let ip_ok = limits.take(Key::Ip(ip), action, per_minute);
+    let pubkey_ok = match pubkey {
+        Some(pk) => {
+            let key = Key::Pubkey(pk.as_slice().try_into().unwrap());
+            limits.take(key, action, per_minute)
+        }
+        None => true,
+    };
+    if ip_ok && pubkey_ok {
+        return Ok(());
+    }
+
+    // (unix domain socket peers share an address, so are not banned by it)
+    if !ip.is_unix_socket() && limits.violated(ip, ban_after) {
+        let seconds = crate::add_ip_violation(ip, Violation::RateLimit, ban_seconds)?;
+        log::info!(target: "Client", "{}: Banned for {}s for exceeding rate limits", ip, seconds);
+        return Err(ChorusError::RateLimitExceeded.into());
+    }
+
+    Err(ChorusError::RateLimited(action.name()).into())
+}
+
+/// Forget idle buckets (and event id mismatches long past) every so often, until shutdown
+pub async fn run() {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(PRUNE_INTERVAL) => {},
+            _ = shutting_down.changed() => {},
+        }
+        if *shutting_down.borrow() {
+            return;
+        }
+
+        GLOBALS.rate_limits.prune();
+        GLOBALS
+            .event_id_mismatches
+            .retain(|_, (_, last)| last.elapsed() < crate::nostr::EVENT_ID_MISMATCH_MEMORY);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let mut bucket = Bucket {
+            tokens: 3.0,
+            updated: Instant::now(),
+        };
+        assert!(bucket.take(3));
+        assert!(bucket.take(3));
+        assert!(bucket.take(3));
+        assert!(!bucket.take(3));
+
+        // A minute later it is full again (but no fuller)
+        bucket.updated -= Duration::from_secs(120);
+        assert!(bucket.take(3));
+        assert!(bucket.take(3));
+        assert!(bucket.take(3));
+        assert!(!bucket.take(3));
+    }
+}
@@ -0,0 +1,138 @@
+//! Recently rejected events, kept in memory for debugging
+//!
+//! These are never written to the event store or its indexes.
+
+use crate::globals::GLOBALS;
+use crate::ip::HashedPeer;
+use pocket_types::{Event, Kind, Time};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// A record of a rejected event.
+///
+/// For sensitive kinds only the id, kind and reason are kept (plus when and from whom
+/// we received it), never the author or content.
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectedEvent {
+    pub id: String,
+    pub kind: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    pub reason: String,
+    pub rejected_at: u64,
+    pub peer: String,
+}
+
+impl RejectedEvent {
+    fn size(&self) -> usize {
+        self.id.len()
+            + self.pubkey.as_ref().map(|s| s.len()).unwrap_or(0)
+            + self.event.as_ref().map(|s| s.len()).unwrap_or(0)
+            + self.reason.len()
+            + self.peer.len()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RejectedEvents {
+    entries: VecDeque<RejectedEvent>,
+    bytes: usize,
+}
+
+impl RejectedEvents {
+    fn push(&mut self, entry: RejectedEvent, max_count: usize, max_bytes: usize) {
+        self.bytes += entry.size();
+        self.entries.push_back(entry);
+        while self.entries.len() > max_count || self.bytes > max_bytes {
+            match self.entries.pop_front() {
+                Some(old) => self.bytes -= old.size(),
+                None => break,
+            }
+        }
+    }
+
+    fn expire(&mut self, max_seconds: u64) {
+        let cutoff = Time::now().as_u64().saturating_sub(max_seconds);
+        while let Some(front) = self.entries.front() {
+            if front.rejected_at >= cutoff {
+                break;
+            }
+            if let Some(old) = self.entries.pop_front() {
+                self.bytes -= old.size();
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+// Kinds whose author and content we do not keep
+fn is_sensitive(kind: Kind) -> bool {
+    kind == Kind::from(4) || kind == Kind::from(1059)
+}
+
+/// Remember a rejected event, if so configured
+pub fn record(event: &Event, reason: String, peer: HashedPeer) {
+    let (keep, max_count, max_bytes, max_seconds) = {
+        let config = GLOBALS.config.read();
+        (
+            config.keep_rejected_events,
+            config.rejected_events_max_count,
+            config.rejected_events_max_bytes,
+            config.rejected_events_max_seconds,
+        )
+    };
+    if !keep {
+        return;
+    }
+
+    let sensitive = is_sensitive(event.kind());
+    let entry = RejectedEvent {
+        id: event.id().as_hex_string(),
+        kind: event.kind().as_u16(),
+        pubkey: if sensitive {
+            None
+        } else {
+            Some(event.pubkey().as_hex_string())
+        },
+        event: if sensitive {
+            None
+        } else {
+            Some(format!("{event}"))
+        },
+        reason,
+        rejected_at: Time::now().as_u64(),
+        peer: format!("{peer}"),
+    };
+
+    let mut rejected = GLOBALS.rejected_events.lock();
+    rejected.expire(max_seconds);
+    rejected.push(entry, max_count, max_bytes);
+}
+
+/// List remembered rejected events, optionally only those with the given id or author
+/// (hex), oldest first
+pub fn list(id_or_pubkey: Option<&str>) -> Vec<RejectedEvent> {
+    let max_seconds = GLOBALS.config.read().rejected_events_max_seconds;
+    let mut rejected = GLOBALS.rejected_events.lock();
+    rejected.expire(max_seconds);
+    rejected
+        .entries
+        .iter()
+        .filter(|r| match id_or_pubkey {
+            None => true,
+            Some(h) => r.id == h || r.pubkey.as_deref() == Some(h),
+        })
+        .cloned()
+        .collect()
+}
+
+/// Forget all remembered rejected events
+pub fn clear() {
+    GLOBALS.rejected_events.lock().clear();
+}
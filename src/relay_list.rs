@@ -0,0 +1,139 @@
+//! Relay users by NIP-65 relay list
+//!
+//! With `users_from_relay_lists`, anybody whose latest relay list (kind 10002) names this
+//! relay is treated as one of its users for the inbox/outbox policy: we accept the events
+//! they author, and events that tag them. They are remembered (with the relay list's
+//! created_at) as their relay lists arrive, and forgotten once a newer one leaves us out.
+//!
+//! Since every event is checked against them (its author, and everybody it tags), they
+//! are also kept in memory, read from the store the first time they are needed.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use parking_lot::RwLock;
+use pocket_types::{Event, Kind, Pubkey};
+use std::collections::HashSet;
+use url::Url;
+
+const RELAY_LIST_KIND: u16 = 10002;
+
+// The users by relay list, as in the store, once read from it
+static USERS: RwLock<Option<HashSet<[u8; 32]>>> = RwLock::new(None);
+
+fn key(pubkey: Pubkey) -> [u8; 32] {
+    pubkey.as_slice().try_into().unwrap()
+}
+
+/// Whether a relay list names this relay (in any `r` tag, read or write)
+pub fn names_us(event: &Event) -> bool {
+    let Ok(tags) = event.tags() else {
+        return false;
+    };
+    let hostname = GLOBALS.config.read().hostname.clone();
+    for mut tag in tags.iter() {
+        if tag.next() != Some(b"r") {
+            continue;
+        }
+        let Some(url) = tag
+            .next()
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| Url::parse(v).ok())
+        else {
+            continue;
+        };
+        // (compared normalized, puny-encoded IDNA, etc)
+        if url.host().is_some_and(|h| h == hostname) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether this is a relay list that makes its author one of our users
+pub fn makes_user(event: &Event) -> bool {
+    event.kind() == Kind::from(RELAY_LIST_KIND)
+        && GLOBALS.config.read().users_from_relay_lists
+        && names_us(event)
+}
+
+/// Remember or forget the author of a newly stored relay list
+pub fn record(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    if event.kind() != Kind::from(RELAY_LIST_KIND) || !GLOBALS.config.read().users_from_relay_lists
+    {
+        return Ok(());
+    }
+
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("relay_list_users")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "relay_list_users",
+        )))?;
+    let created_at = event.created_at().as_u64();
+
+    let mut txn = store.write_txn()?;
+    let newer = match table.get(&txn, event.pubkey().as_slice())? {
+        Some(v) if v.len() == 8 => created_at > u64::from_be_bytes(v.try_into().unwrap()),
+        _ => true,
+    };
+    let user = names_us(event);
+    if newer {
+        if user {
+            table.put(
+                &mut txn,
+                event.pubkey().as_slice(),
+                &created_at.to_be_bytes(),
+            )?;
+        } else {
+            let _ = table.delete(&mut txn, event.pubkey().as_slice())?;
+        }
+    }
+    txn.commit()?;
+
+    if newer {
+        if let Some(users) = USERS.write().as_mut() {
+            if user {
+                let _ = users.insert(key(event.pubkey()));
+            } else {
+                let _ = users.remove(&key(event.pubkey()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a pubkey is one of our users by their relay list
+pub fn is_user(pubkey: Pubkey) -> Result<bool, Error> {
+    if !GLOBALS.config.read().users_from_relay_lists {
+        return Ok(false);
+    }
+    if let Some(users) = USERS.read().as_ref() {
+        return Ok(users.contains(&key(pubkey)));
+    }
+
+    // Read them in (under the write lock, so that a relay list recorded meanwhile is not
+    // missed)
+    let mut cached = USERS.write();
+    if cached.is_none() {
+        let _reading = crate::map_size::reading();
+        let store = GLOBALS.store.get().unwrap();
+        let table = store
+            .extra_table("relay_list_users")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "relay_list_users",
+            )))?;
+        let txn = store.read_txn()?;
+        let mut users: HashSet<[u8; 32]> = HashSet::new();
+        for entry in table.iter(&txn)? {
+            let (k, _v) = entry?;
+            if let Ok(k) = k.try_into() {
+                let _ = users.insert(k);
+            }
+        }
+        *cached = Some(users);
+    }
+    Ok(cached
+        .as_ref()
+        .is_some_and(|users| users.contains(&key(pubkey))))
+}
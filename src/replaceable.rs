@@ -0,0 +1,247 @@
+//! Replaceable (kinds 0, 3 and 10000-19999) and addressable (kinds 30000-39999) events
+//!
+//! Only the latest version of an address (kind, pubkey and, for addressable events, d-tag)
+//! is kept: the newest, or of those created at the same time, the one with the lowest id.
+//! The `latest_addresses` table points from each address to its latest version, so that an
+//! older version arriving later can be turned away without a search, and so that the
+//! version it replaces can be found and removed.
+
+use crate::deletion::Address;
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use bitcoin_hashes::{sha256, Hash};
+use parking_lot::Mutex;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Id};
+use std::collections::HashMap;
+
+// Held while deciding which version is the latest, so that two versions stored at once
+// cannot both win
+static RECORD: Mutex<()> = Mutex::new(());
+
+// The table key of an address. The d-tag is hashed, as it may be longer than LMDB allows
+// a key to be.
+fn key_of(address: &Address) -> Vec<u8> {
+    let mut key = address.kind.to_be_bytes().to_vec();
+    key.extend_from_slice(address.pubkey.as_slice());
+    let hash = sha256::Hash::hash(&address.d_tag);
+    key.extend_from_slice(<sha256::Hash as AsRef<[u8]>>::as_ref(&hash));
+    key
+}
+
+/// Whether a version (created at `a_created_at`, with id `a_id`) wins over another
+pub fn wins(a_created_at: u64, a_id: &[u8], b_created_at: u64, b_id: &[u8]) -> bool {
+    a_created_at > b_created_at || (a_created_at == b_created_at && a_id < b_id)
+}
+
+// The latest stored version of `key`'s address, if any
+fn latest(store: &Store, key: &[u8]) -> Result<Option<(u64, Id)>, Error> {
+    let _reading = crate::map_size::reading();
+    let table = store
+        .extra_table("latest_addresses")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "latest_addresses",
+        )))?;
+    let id = {
+        let txn = store.read_txn()?;
+        match table.get(&txn, key)?.and_then(|v| v.try_into().ok()) {
+            Some(bytes) => Id::from_bytes(bytes),
+            None => return Ok(None),
+        }
+    };
+    // (It may have been removed in the meantime)
+    Ok(store
+        .get_event_by_id(id)?
+        .map(|e| (e.created_at().as_u64(), id)))
+}
+
+/// Refuse a version that is older than the one we have
+pub fn check(event: &Event) -> Result<(), Error> {
+    let Some(address) = Address::of(event) else {
+        return Ok(());
+    };
+    let store = GLOBALS.store.get().unwrap();
+    match latest(store, &key_of(&address))? {
+        Some((created_at, id))
+            if id.as_slice() != event.id().as_slice()
+                && wins(
+                    created_at,
+                    id.as_slice(),
+                    event.created_at().as_u64(),
+                    event.id().as_slice(),
+                ) =>
+        {
+            Err(ChorusError::Superseded.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Make a newly stored version the latest, removing the one it replaces. If another
+/// version won in the meantime, this one is removed instead.
+pub fn record(event: &Event) -> Result<(), Error> {
+    let Some(address) = Address::of(event) else {
+        return Ok(());
+    };
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("latest_addresses")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "latest_addresses",
+        )))?;
+    let key = key_of(&address);
+
+    let loser = {
+        let _guard = RECORD.lock();
+        let _reading = crate::map_size::reading();
+        let current = latest(store, &key)?;
+        let mut txn = store.write_txn()?;
+        let loser = match current {
+            Some((_, id)) if id.as_slice() == event.id().as_slice() => None,
+            Some((created_at, id))
+                if wins(
+                    created_at,
+                    id.as_slice(),
+                    event.created_at().as_u64(),
+                    event.id().as_slice(),
+                ) =>
+            {
+                Some(event.id())
+            }
+            current => {
+                table.put(&mut txn, &key, event.id().as_slice())?;
+                current.map(|(_, id)| id)
+            }
+        };
+        txn.commit()?;
+        loser
+    };
+
+    if let Some(id) = loser {
+        crate::remove_event(id)?;
+    }
+    Ok(())
+}
+
+/// The table key for removing `event`, if it is replaceable or addressable
+pub fn key_for_removal(event: &Event) -> Option<(Vec<u8>, Id)> {
+    Address::of(event).map(|address| (key_of(&address), event.id()))
+}
+
+/// Forget a removed event, if it was the latest version of its address
+pub fn forget(key: Option<(Vec<u8>, Id)>) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let Some((key, id)) = key else {
+        return Ok(());
+    };
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("latest_addresses")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "latest_addresses",
+        )))?;
+    let mut txn = store.write_txn()?;
+    if table.get(&txn, &key)? == Some(id.as_slice()) {
+        let _ = table.delete(&mut txn, &key)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Build the `latest_addresses` table, removing every superseded version already stored
+/// (once). This is only recorded as done once they are all removed, so if we are stopped
+/// partway it is all done again (which is harmless) on the next start.
+pub fn migrate(store: &Store) -> Result<(), Error> {
+    let table = store
+        .extra_table("latest_addresses")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "latest_addresses",
+        )))?;
+    let meta = store
+        .extra_table("latest_addresses_meta")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "latest_addresses_meta",
+        )))?;
+
+    {
+        let _reading = crate::map_size::reading();
+        let txn = store.read_txn()?;
+        if meta.get(&txn, b"built")?.is_some() {
+            return Ok(());
+        }
+    }
+
+    let mut latest: HashMap<Vec<u8>, (u64, Id)> = HashMap::new();
+    let mut superseded: Vec<Id> = Vec::new();
+    let _reading = crate::map_size::reading();
+    let screen = |e: &Event| -> ScreenResult {
+        if Address::of(e).is_some() {
+            ScreenResult::Match
+        } else {
+            ScreenResult::Mismatch
+        }
+    };
+    let _ = crate::backfill::backfill(store, "latest addresses", screen, |_txn, event| {
+        let Some(address) = Address::of(event) else {
+            return Ok(());
+        };
+        let version = (event.created_at().as_u64(), event.id());
+        match latest.get_mut(&key_of(&address)) {
+            Some(current) => {
+                if wins(
+                    version.0,
+                    version.1.as_slice(),
+                    current.0,
+                    current.1.as_slice(),
+                ) {
+                    superseded.push(current.1);
+                    *current = version;
+                } else {
+                    superseded.push(version.1);
+                }
+            }
+            None => {
+                let _ = latest.insert(key_of(&address), version);
+            }
+        }
+        Ok(())
+    })?;
+    let mut txn = store.write_txn()?;
+    for (key, (_, id)) in latest.iter() {
+        table.put(&mut txn, key, id.as_slice())?;
+    }
+    txn.commit()?;
+    drop(_reading);
+
+    if !superseded.is_empty() {
+        log::info!(target: "Server", "Removing {} superseded versions of replaceable events", superseded.len());
+    }
+    for id in superseded.iter() {
+        crate::remove_event(*id)?;
+    }
+
+    let _reading = crate::map_size::reading();
+    let mut txn = store.write_txn()?;
+    meta.put(&mut txn, b"built", b"")?;
+    txn.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wins() {
+        let low = [1_u8; 32];
+        let high = [2_u8; 32];
+
+        // Newer wins
+        assert!(wins(20, &high, 10, &low));
+        assert!(!wins(10, &low, 20, &high));
+
+        // Ties go to the lowest id
+        assert!(wins(10, &low, 10, &high));
+        assert!(!wins(10, &high, 10, &low));
+    }
+}
@@ -0,0 +1,279 @@
+//! Resumption cursors for the `since_last` extension (`enable_since_last`)
+//!
+//! A subscription is known, for each user, by a fingerprint of its filters (leaving out
+//! `since`, `until` and `limit`). When one whose filters asked for `since_last` is closed,
+//! replaced, or its connection ends, the `created_at` of the newest event we delivered to
+//! it is saved as the cursor for that user and fingerprint, and those filters of the next
+//! such subscription are served from there instead of from their own `since`.
+//!
+//! Subscriptions with the same fingerprint (on several connections, say) share a cursor,
+//! which only ever moves forward. A subscription closed because the client was not
+//! reading it may have missed events, so it does not move the cursor at all.
+//!
+//! Cursors expire `since_last_expiry_days` after they were last saved, and a user keeps
+//! no more than `max_since_last_cursors` of them (those saved longest ago go first).
+
+use crate::error::{ChorusError, Error};
+use crate::filter::ChorusFilter;
+use crate::globals::GLOBALS;
+use pocket_types::{Event, Pubkey, Time};
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use serde_json::Value;
+use std::time::Duration;
+
+// How often expired cursors are swept away
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A subscription being served that asked for `since_last`
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    pub user: Pubkey,
+    pub fingerprint: [u8; 32],
+
+    /// The `created_at` of the newest event delivered to it so far
+    pub newest: Option<u64>,
+}
+
+impl Cursor {
+    /// Note that the event was delivered to the subscription
+    pub fn delivered(&mut self, event: &Event) {
+        let created_at = event.created_at().as_u64();
+        if self.newest.is_none_or(|newest| newest < created_at) {
+            self.newest = Some(created_at);
+        }
+    }
+
+    fn key(&self) -> Vec<u8> {
+        let mut key = self.user.as_slice().to_vec();
+        key.extend_from_slice(&self.fingerprint);
+        key
+    }
+}
+
+// A filter as JSON, less the fields a resumed subscription may change, with its keys and
+// the values of its lists in order
+fn canonical(filter: &ChorusFilter) -> Result<String, Error> {
+    let mut map = filter.json.clone();
+    for field in ["since", "until", "limit"] {
+        let _ = map.remove(field);
+    }
+    for condition in filter.long_tags.iter() {
+        let _ = map.insert(
+            format!("#{}", condition.name),
+            condition.values.clone().into(),
+        );
+    }
+    if let Some(terms) = &filter.search {
+        let _ = map.insert("search".to_owned(), terms.clone().into());
+    }
+    for value in map.values_mut() {
+        if let Value::Array(values) = value {
+            values.sort_by_cached_key(|v| v.to_string());
+        }
+    }
+    Ok(serde_json::to_string(&map)?)
+}
+
+/// The fingerprint of a subscription's filters, whatever their order
+pub fn fingerprint(filters: &[ChorusFilter]) -> Result<[u8; 32], Error> {
+    let mut canonicals: Vec<String> = Vec::with_capacity(filters.len());
+    for filter in filters.iter() {
+        canonicals.push(canonical(filter)?);
+    }
+    canonicals.sort();
+
+    let mut engine = sha256::Hash::engine();
+    for canonical in canonicals.iter() {
+        engine.input(canonical.as_bytes());
+        engine.input(b"\n");
+    }
+    Ok(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+// The cursor and when it was saved, from a table value
+fn decode(value: &[u8]) -> Option<(u64, u64)> {
+    if value.len() != 16 {
+        return None;
+    }
+    Some((
+        u64::from_be_bytes(value[..8].try_into().unwrap()),
+        u64::from_be_bytes(value[8..].try_into().unwrap()),
+    ))
+}
+
+// When a cursor saved before is expired
+fn expired_before() -> u64 {
+    let days = GLOBALS.config.read().since_last_expiry_days;
+    Time::now()
+        .as_u64()
+        .saturating_sub(days.saturating_mul(86400))
+}
+
+/// Where the subscription left off, if it did and that is not too long ago
+pub fn load(cursor: &Cursor) -> Result<Option<u64>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let table = store
+        .extra_table("since_last_cursors")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "since_last_cursors",
+        )))?;
+    let txn = store.read_txn()?;
+    Ok(match table.get(&txn, &cursor.key())?.and_then(decode) {
+        Some((newest, saved_at)) if saved_at >= expired_before() => Some(newest),
+        _ => None,
+    })
+}
+
+/// Save where the subscription left off (unless an earlier one with the same fingerprint
+/// got further), and forget the user's cursors beyond `max_since_last_cursors`. Nothing is
+/// saved while we hand the store over, or in read-only and maintenance modes.
+pub fn save(cursor: &Cursor) -> Result<(), Error> {
+    if !crate::mode::background_writes() {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let table = store
+        .extra_table("since_last_cursors")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "since_last_cursors",
+        )))?;
+    let (max_cursors, expired_before) = (
+        GLOBALS.config.read().max_since_last_cursors,
+        expired_before(),
+    );
+    let now = Time::now().as_u64();
+    let key = cursor.key();
+
+    // In one write transaction, so concurrent saves cannot move the cursor back
+    let mut txn = store.write_txn()?;
+    let saved = table
+        .get(&txn, &key)?
+        .and_then(decode)
+        .filter(|(_, saved_at)| *saved_at >= expired_before)
+        .map(|(newest, _)| newest);
+    let Some(newest) = saved.max(cursor.newest) else {
+        return Ok(());
+    };
+    let mut value = newest.to_be_bytes().to_vec();
+    value.extend_from_slice(&now.to_be_bytes());
+    table.put(&mut txn, &key, &value)?;
+
+    let mut others: Vec<(u64, Vec<u8>)> = Vec::new();
+    for i in table.prefix_iter(&txn, cursor.user.as_slice())? {
+        let (other, value) = i?;
+        let saved_at = decode(value).map(|(_, saved_at)| saved_at).unwrap_or(0);
+        others.push((saved_at, other.to_vec()));
+    }
+    if others.len() > max_cursors {
+        others.sort();
+        let excess = others.len() - max_cursors;
+        for (_, other) in others.drain(..excess) {
+            let _ = table.delete(&mut txn, &other)?;
+        }
+    }
+
+    txn.commit()?;
+    Ok(())
+}
+
+/// Remove the expired cursors, returning how many there were
+pub fn sweep() -> Result<usize, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let table = store
+        .extra_table("since_last_cursors")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "since_last_cursors",
+        )))?;
+    let expired_before = expired_before();
+
+    let mut txn = store.write_txn()?;
+    let mut expired: Vec<Vec<u8>> = Vec::new();
+    for i in table.iter(&txn)? {
+        let (key, value) = i?;
+        if decode(value).is_none_or(|(_, saved_at)| saved_at < expired_before) {
+            expired.push(key.to_vec());
+        }
+    }
+    for key in expired.iter() {
+        let _ = table.delete(&mut txn, key)?;
+    }
+    txn.commit()?;
+    Ok(expired.len())
+}
+
+/// Sweep expired cursors now and then, until shutdown
+pub async fn run() {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+
+    loop {
+        // Leave the store alone while we hand it over, and in read-only and maintenance modes
+        if crate::mode::background_writes() {
+            match sweep() {
+                Ok(0) => {}
+                Ok(n) => log::info!(target: "Server", "Removed {n} expired since_last cursors"),
+                Err(e) => log::error!(target: "Server", "since_last cursor sweep failed: {e}"),
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(SWEEP_INTERVAL) => {},
+            _ = shutting_down.changed() => {},
+        }
+        if *shutting_down.borrow() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filters(json: &[&str]) -> Vec<ChorusFilter> {
+        let mut buffer = vec![0; 4096];
+        json.iter()
+            .map(|j| {
+                ChorusFilter::from_json_as_is(j.as_bytes(), &mut buffer)
+                    .unwrap()
+                    .2
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let subscription = filters(&[
+            &format!(r#"{{"authors":["{a}","{b}"],"kinds":[1,6]}}"#),
+            r##"{"#title":["x"],"kinds":[30023]}"##,
+        ]);
+        let fingerprint = fingerprint(&subscription).unwrap();
+
+        // The same, whatever the since, until, limit, or order
+        let same = filters(&[
+            r##"{"kinds":[30023],"#title":["x"],"since_last":true,"limit":5}"##,
+            &format!(r#"{{"kinds":[6,1],"authors":["{b}","{a}"],"since":100,"until":200}}"#),
+        ]);
+        assert_eq!(super::fingerprint(&same).unwrap(), fingerprint);
+
+        // But not with other conditions
+        for other in [
+            filters(&[&format!(r#"{{"authors":["{a}"],"kinds":[1,6]}}"#)]),
+            filters(&[
+                &format!(r#"{{"authors":["{a}","{b}"],"kinds":[1,6]}}"#),
+                r##"{"#title":["y"],"kinds":[30023]}"##,
+            ]),
+            filters(&[
+                &format!(r#"{{"authors":["{a}","{b}"],"kinds":[1,6]}}"#),
+                r##"{"#title":["x"],"kinds":[30023]}"##,
+                r#"{"kinds":[0]}"#,
+            ]),
+        ] {
+            assert_ne!(super::fingerprint(&other).unwrap(), fingerprint);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Retention policies: how long, or how many of, each kind of event we keep
+//!
+//! The configured rules (`retention`) are in the form of NIP-11's `retention` field, and
+//! are published there as they are. The first rule whose `kinds` include an event's kind
+//! (a rule without `kinds` covers every kind) decides: events older than its `time` (in
+//! seconds) are removed, as are all but the latest `count` of a kind by each author.
+//!
+//! The latest version of a replaceable or addressable event is always kept, however old
+//! it is. The store is walked a chunk at a time (see `crate::walk`), and events are
+//! removed through `crate::remove_event`, so our indexes stay consistent and live writes
+//! are not held up.
+
+use crate::deletion::Address;
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::walk::Walk;
+use pocket_db::ScreenResult;
+use pocket_types::{Event, Id, Time};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// A kind, or an inclusive range of kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KindSpec {
+    Kind(u16),
+    Range([u16; 2]),
+}
+
+impl KindSpec {
+    pub fn contains(&self, kind: u16) -> bool {
+        match *self {
+            KindSpec::Kind(k) => k == kind,
+            KindSpec::Range([from, to]) => from <= kind && kind <= to,
+        }
+    }
+}
+
+/// A retention rule, as in NIP-11
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionRule {
+    /// The kinds this covers (all of them if empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<KindSpec>,
+
+    /// Seconds to keep these events for (0 to not keep them at all)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+
+    /// How many of a kind to keep, per author
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+}
+
+impl RetentionRule {
+    fn covers(&self, kind: u16) -> bool {
+        self.kinds.is_empty() || self.kinds.iter().any(|k| k.contains(kind))
+    }
+}
+
+/// The rule that applies to `kind`, if any
+pub fn rule_for(rules: &[RetentionRule], kind: u16) -> Option<&RetentionRule> {
+    rules.iter().find(|r| r.covers(kind))
+}
+
+/// The NIP-11 `retention` field
+pub fn nip11_json(rules: &[RetentionRule]) -> Value {
+    if rules.is_empty() {
+        return json!([{ "time": null }]);
+    }
+    serde_json::to_value(rules).unwrap_or_else(|_| json!([]))
+}
+
+// Chooses the events the rules say should go, from all the events given it newest first
+struct Chooser {
+    rules: Vec<RetentionRule>,
+    now: u64,
+    latest_seen: HashSet<Vec<u8>>,
+    counts: HashMap<([u8; 32], u16), u64>,
+}
+
+impl Chooser {
+    fn new(rules: Vec<RetentionRule>, now: u64) -> Chooser {
+        Chooser {
+            rules,
+            now,
+            latest_seen: HashSet::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    // Of the next `events` (newest first), the ids that should go
+    fn choose(&mut self, events: &[&Event]) -> Vec<Id> {
+        let mut chosen = Vec::new();
+
+        for event in events.iter() {
+            let kind = event.kind().as_u16();
+            let Some(rule) = rule_for(&self.rules, kind) else {
+                continue;
+            };
+
+            // The newest version of an address is never removed
+            if let Some(address) = Address::of(event) {
+                if self.latest_seen.insert(address.key()) {
+                    continue;
+                }
+            }
+
+            let author: [u8; 32] = event.pubkey().as_slice().try_into().unwrap();
+            let count = self.counts.entry((author, kind)).or_insert(0);
+            *count += 1;
+
+            let too_old = rule
+                .time
+                .is_some_and(|time| event.created_at().as_u64().saturating_add(time) < self.now);
+            let too_many = rule.count.is_some_and(|max| *count > max);
+            if too_old || too_many {
+                chosen.push(event.id());
+            }
+        }
+        chosen
+    }
+}
+
+// Walk the next chunk of the store, removing what should go. Returns how many were
+// removed, or None once the whole store was walked.
+fn sweep_chunk(walk: &mut Walk, chooser: &mut Chooser) -> Result<Option<usize>, Error> {
+    let screen = |e: &Event| -> ScreenResult {
+        if rule_for(&chooser.rules, e.kind().as_u16()).is_some() {
+            ScreenResult::Match
+        } else {
+            ScreenResult::Mismatch
+        }
+    };
+    let Some(events) = walk.next_chunk(screen)? else {
+        return Ok(None);
+    };
+    let ids = chooser.choose(&events);
+    for id in ids.iter() {
+        crate::remove_event(*id)?;
+    }
+    Ok(Some(ids.len()))
+}
+
+/// Remove every event the retention rules say should go. Returns how many were removed.
+///
+/// The store is walked newest first, a chunk at a time on a blocking thread (see
+/// `crate::walk`), removing what should go of each chunk before the next is read.
+pub async fn sweep() -> Result<usize, Error> {
+    let rules = GLOBALS.config.read().retention.clone();
+    if rules.is_empty() {
+        return Ok(0);
+    }
+
+    let mut chooser = Chooser::new(rules, Time::now().as_u64());
+    let mut walk = Walk::all();
+    let mut removed: usize = 0;
+    loop {
+        // Leave the store alone while we hand it over, and in read-only and maintenance modes
+        if !crate::mode::background_writes() {
+            break;
+        }
+        let (state, result) = tokio::task::spawn_blocking(move || {
+            let result = sweep_chunk(&mut walk, &mut chooser);
+            ((walk, chooser), result)
+        })
+        .await
+        .map_err(|e| ChorusError::General(format!("{e}")).into_err())?;
+        (walk, chooser) = state;
+        match result? {
+            Some(n) => removed += n,
+            None => break,
+        }
+    }
+    Ok(removed)
+}
+
+/// Apply the retention rules every `retention_sweep_seconds`, until shutdown
+pub async fn run() {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+
+    loop {
+        let seconds = GLOBALS.config.read().retention_sweep_seconds;
+        if seconds == 0 {
+            return;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(seconds)) => {},
+            _ = shutting_down.changed() => {},
+        }
+        if *shutting_down.borrow() {
+            return;
+        }
+
+        // Leave the store alone while we hand it over, and in read-only and maintenance modes
+        if !crate::mode::background_writes() {
+            continue;
+        }
+
+        match sweep().await {
+            Ok(0) => {}
+            Ok(n) => log::info!(target: "Server", "Removed {n} events past retention"),
+            Err(e) => log::error!(target: "Server", "Retention sweep failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Rules {
+        retention: Vec<RetentionRule>,
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules: Rules = toml::from_str(
+            r#"
+            [[retention]]
+            kinds = [1, [5, 7]]
+            time = 7776000
+
+            [[retention]]
+            kinds = [10002]
+            count = 5
+
+            [[retention]]
+            kinds = [[20000, 29999]]
+            time = 0
+            "#,
+        )
+        .unwrap();
+        let rules = rules.retention;
+
+        assert_eq!(rule_for(&rules, 6).unwrap().time, Some(7776000));
+        assert_eq!(rule_for(&rules, 10002).unwrap().count, Some(5));
+        assert_eq!(rule_for(&rules, 20001).unwrap().time, Some(0));
+        assert!(rule_for(&rules, 3).is_none());
+
+        assert_eq!(
+            nip11_json(&rules),
+            json!([
+                { "kinds": [1, [5, 7]], "time": 7776000 },
+                { "kinds": [10002], "count": 5 },
+                { "kinds": [[20000, 29999]], "time": 0 }
+            ])
+        );
+        assert_eq!(nip11_json(&[]), json!([{ "time": null }]));
+    }
+}
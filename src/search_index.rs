@@ -0,0 +1,237 @@
+//! Full-text index for NIP-50 search (`enable_search`)
+//!
+//! Event content is split into terms (runs of alphanumeric characters, lowercased) and
+//! each term is indexed against the event. A filter's `search` matches events containing
+//! all of its terms; there is no stemming or ranking, results are newest first like any
+//! other REQ.
+//!
+//! DM and giftwrap content is ciphertext, so those kinds are not indexed.
+
+use crate::config::Config;
+use crate::error::{ChorusError, Error};
+use crate::filter::ChorusFilter;
+use crate::globals::GLOBALS;
+use pocket_db::heed::RwTxn;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Id};
+
+// Longer terms are not worth indexing (and LMDB keys are limited to 511 bytes)
+const MAX_TERM_LEN: usize = 64;
+
+/// Split text into search terms
+pub fn terms(text: &str) -> Vec<String> {
+    let mut terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty() && t.len() <= MAX_TERM_LEN)
+        .map(|t| t.to_lowercase())
+        .collect();
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+/// Whether an event's content contains every one of `terms`
+pub fn event_matches(event: &Event, terms: &[String]) -> bool {
+    let content = String::from_utf8_lossy(event.content());
+    let have = self::terms(&content);
+    terms.iter().all(|t| have.binary_search(t).is_ok())
+}
+
+fn is_searchable(event: &Event) -> bool {
+    let kind = event.kind().as_u16();
+    kind != 4 && kind != 1059
+}
+
+// The index keys for an event
+fn event_keys(event: &Event) -> Vec<Vec<u8>> {
+    if !is_searchable(event) {
+        return vec![];
+    }
+    // Newest first within each term
+    let newness = (u64::MAX - event.created_at().as_u64()).to_be_bytes();
+    let content = String::from_utf8_lossy(event.content());
+    terms(&content)
+        .into_iter()
+        .map(|term| {
+            let mut key = Vec::with_capacity(term.len() + 1 + 8 + 32);
+            key.extend_from_slice(term.as_bytes());
+            key.push(0);
+            key.extend_from_slice(&newness);
+            key.extend_from_slice(event.id().as_slice());
+            key
+        })
+        .collect()
+}
+
+fn index_event_into(store: &Store, txn: &mut RwTxn, event: &Event) -> Result<(), Error> {
+    let table = store
+        .extra_table("search_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "search_index",
+        )))?;
+    for key in event_keys(event) {
+        table.put(txn, &key, b"")?;
+    }
+    Ok(())
+}
+
+/// Index a newly stored event (if search is enabled)
+pub fn index_event(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    if !GLOBALS.config.read().enable_search {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    index_event_into(store, &mut txn, event)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// The index keys of an event that is about to be removed, to pass to `unindex()`
+/// once it is gone
+pub fn keys_for_removal(event: &Event) -> Vec<Vec<u8>> {
+    if !GLOBALS.config.read().enable_search {
+        return vec![];
+    }
+    event_keys(event)
+}
+
+/// Remove index entries (of a removed event)
+pub fn unindex(keys: &[Vec<u8>]) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("search_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "search_index",
+        )))?;
+    let mut txn = store.write_txn()?;
+    for key in keys.iter() {
+        let _ = table.delete(&mut txn, key)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Build the index when search is enabled, drop it when it is disabled
+pub fn migrate(store: &Store, config: &Config) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let meta = store
+        .extra_table("search_index_meta")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "search_index_meta",
+        )))?;
+    let table = store
+        .extra_table("search_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "search_index",
+        )))?;
+
+    let built = {
+        let txn = store.read_txn()?;
+        meta.get(&txn, b"built")?.is_some()
+    };
+    if built == config.enable_search {
+        return Ok(());
+    }
+
+    let mut txn = store.write_txn()?;
+    table.clear(&mut txn)?;
+    let _ = meta.delete(&mut txn, b"built")?;
+    txn.commit()?;
+
+    if config.enable_search {
+        let screen = |e: &Event| -> ScreenResult {
+            if is_searchable(e) {
+                ScreenResult::Match
+            } else {
+                ScreenResult::Mismatch
+            }
+        };
+        let _ = crate::backfill::backfill(store, "search index", screen, |txn, event| {
+            index_event_into(store, txn, event)
+        })?;
+        let mut txn = store.write_txn()?;
+        meta.put(&mut txn, b"built", b"")?;
+        txn.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Find events matching a filter with search terms.
+///
+/// Candidates come from the index entries of the longest term (likely the rarest), newest
+/// first, of which at most `search_max_scanned` are examined. Returns the matching events
+/// (newest first, limited by the filter's limit) and whether any were redacted by the
+/// screen.
+pub fn find_events<F>(
+    filter: &ChorusFilter,
+    terms: &[String],
+    screen: F,
+) -> Result<(Vec<&'static Event>, bool), Error>
+where
+    F: Fn(&Event) -> ScreenResult,
+{
+    let _reading = crate::map_size::reading();
+    let Some(term) = terms.iter().max_by_key(|t| t.len()) else {
+        return Ok((vec![], false));
+    };
+
+    let max_scanned = GLOBALS.config.read().search_max_scanned;
+    let limit = filter.filter.limit() as usize;
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("search_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "search_index",
+        )))?;
+    let txn = store.read_txn()?;
+
+    let mut prefix = term.as_bytes().to_vec();
+    prefix.push(0);
+
+    let mut redacted = false;
+    let mut events: Vec<&'static Event> = Vec::new();
+    for i in table.prefix_iter(&txn, &prefix)?.take(max_scanned) {
+        if events.len() >= limit {
+            break;
+        }
+        let (key, _) = i?;
+        if key.len() != prefix.len() + 8 + 32 {
+            continue;
+        }
+        let id = Id::from_bytes(key[prefix.len() + 8..].try_into().unwrap());
+        let Some(event) = store.get_event_by_id(id)? else {
+            continue;
+        };
+        if !filter.event_matches(event)? {
+            continue;
+        }
+        match screen(event) {
+            ScreenResult::Match => events.push(event),
+            ScreenResult::Redacted => redacted = true,
+            ScreenResult::Mismatch => {}
+        }
+    }
+
+    Ok((events, redacted))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_terms() {
+        assert_eq!(
+            terms("Hello, World! hello\tNOSTR-relay"),
+            vec!["hello", "nostr", "relay", "world"]
+        );
+        assert!(terms("  ,.; ").is_empty());
+    }
+}
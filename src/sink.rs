@@ -0,0 +1,386 @@
+//! Event sink: forwards accepted events to an external endpoint
+//!
+//! Accepted events are appended to a durable outbox table at ingest and a background task
+//! POSTs them in batches (as a JSON array) to `event_sink_url`, removing them from the
+//! outbox only once the endpoint has answered with a 2xx status. Delivery is therefore
+//! at-least-once and survives restarts.
+//!
+//! Ingest never waits on the endpoint. If it is down the outbox grows up to
+//! `event_sink_max_outbox`, after which events are dropped from the sink according to
+//! `event_sink_overflow_drop_oldest` (they are still stored by the relay).
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::rt::{Read, Write};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use pocket_types::Event;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Notify};
+use tokio_rustls::{rustls, TlsConnector};
+use url::Url;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Event sink counters and the wakeup for its delivery task
+#[derive(Debug, Default)]
+pub struct SinkState {
+    next_key: AtomicU64,
+    outbox: AtomicU64,
+    delivered: AtomicU64,
+    failures: AtomicU64,
+    dropped: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    wakeup: Notify,
+}
+
+/// Event sink statistics
+#[derive(Debug, Clone, Serialize)]
+pub struct SinkStats {
+    /// Events waiting to be delivered
+    pub outbox: u64,
+
+    /// Events delivered since startup
+    pub delivered: u64,
+
+    /// Failed delivery attempts since startup
+    pub failures: u64,
+
+    /// Events not delivered because the outbox was full, since startup
+    pub dropped: u64,
+
+    /// Age of the oldest undelivered event, in seconds
+    pub lag_seconds: u64,
+
+    /// Why the last failed delivery attempt failed
+    pub last_error: Option<String>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Pick up where we left off: count the outbox and continue its key sequence
+pub fn init() -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let outbox = store
+        .extra_table("event_sink_outbox")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "event_sink_outbox",
+        )))?;
+    let txn = store.read_txn()?;
+    let next_key = match outbox.last(&txn)? {
+        Some((key, _)) => u64::from_be_bytes(key[..8].try_into().unwrap()) + 1,
+        None => 0,
+    };
+    let state = &GLOBALS.event_sink;
+    state.next_key.store(next_key, Ordering::Relaxed);
+    state.outbox.store(outbox.len(&txn)?, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Queue an accepted event for the sink (if one is configured)
+pub fn enqueue(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let (max_outbox, drop_oldest) = {
+        let config = GLOBALS.config.read();
+        if config.event_sink_url.is_none() {
+            return Ok(());
+        }
+        if crate::ephemeral::is_ephemeral(event.kind()) && !config.event_sink_include_ephemeral {
+            return Ok(());
+        }
+        (
+            config.event_sink_max_outbox,
+            config.event_sink_overflow_drop_oldest,
+        )
+    };
+
+    let state = &GLOBALS.event_sink;
+    let store = GLOBALS.store.get().unwrap();
+    let outbox = store
+        .extra_table("event_sink_outbox")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "event_sink_outbox",
+        )))?;
+    let mut txn = store.write_txn()?;
+
+    let mut len = outbox.len(&txn)?;
+    if len >= max_outbox {
+        state.dropped.fetch_add(1, Ordering::Relaxed);
+        if !drop_oldest || max_outbox == 0 {
+            return Ok(());
+        }
+        let oldest: Option<Vec<u8>> = outbox.first(&txn)?.map(|(key, _)| key.to_owned());
+        if let Some(key) = oldest {
+            let _ = outbox.delete(&mut txn, &key)?;
+            len -= 1;
+        }
+    }
+
+    // [enqueued at (ms, BE)][event json]
+    let mut value = now_ms().to_be_bytes().to_vec();
+    value.extend_from_slice(&event.as_json()?);
+    let key = state.next_key.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+    outbox.put(&mut txn, &key, &value)?;
+    txn.commit()?;
+
+    state.outbox.store(len + 1, Ordering::Relaxed);
+    state.wakeup.notify_one();
+    Ok(())
+}
+
+/// Current event sink statistics
+pub fn stats() -> Result<SinkStats, Error> {
+    let _reading = crate::map_size::reading();
+    let state = &GLOBALS.event_sink;
+    let store = GLOBALS.store.get().unwrap();
+    let outbox = store
+        .extra_table("event_sink_outbox")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "event_sink_outbox",
+        )))?;
+    let txn = store.read_txn()?;
+    let lag_seconds = match outbox.first(&txn)? {
+        Some((_, value)) => {
+            let enqueued = u64::from_be_bytes(value[..8].try_into().unwrap());
+            now_ms().saturating_sub(enqueued) / 1000
+        }
+        None => 0,
+    };
+    Ok(SinkStats {
+        outbox: state.outbox.load(Ordering::Relaxed),
+        delivered: state.delivered.load(Ordering::Relaxed),
+        failures: state.failures.load(Ordering::Relaxed),
+        dropped: state.dropped.load(Ordering::Relaxed),
+        lag_seconds,
+        last_error: state.last_error.lock().clone(),
+    })
+}
+
+// The oldest batch in the outbox: (keys, JSON array of the events)
+fn next_batch(max: usize) -> Result<(Vec<[u8; 8]>, Vec<u8>), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let outbox = store
+        .extra_table("event_sink_outbox")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "event_sink_outbox",
+        )))?;
+    crate::failpoints::hit("sink.next_batch")?;
+    let txn = store.read_txn()?;
+    let mut keys: Vec<[u8; 8]> = Vec::new();
+    let mut body: Vec<u8> = b"[".to_vec();
+    for i in outbox.iter(&txn)?.take(max) {
+        let (key, value) = i?;
+        if !keys.is_empty() {
+            body.push(b',');
+        }
+        keys.push(key[..8].try_into().unwrap());
+        body.extend_from_slice(&value[8..]);
+    }
+    body.push(b']');
+    Ok((keys, body))
+}
+
+fn remove_delivered(keys: &[[u8; 8]]) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let outbox = store
+        .extra_table("event_sink_outbox")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "event_sink_outbox",
+        )))?;
+    crate::failpoints::hit("sink.remove_delivered")?;
+    let mut txn = store.write_txn()?;
+    for key in keys {
+        let _ = outbox.delete(&mut txn, key)?;
+    }
+    let len = outbox.len(&txn)?;
+    txn.commit()?;
+    GLOBALS.event_sink.outbox.store(len, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Deliver the outbox to the event sink, until shutdown
+pub async fn run() {
+    let Some(url) = GLOBALS.config.read().event_sink_url.clone() else {
+        return;
+    };
+    let url = match Url::parse(&url) {
+        Ok(url) => url,
+        Err(e) => {
+            log::error!(target: "Server", "Event sink disabled, bad event_sink_url: {e}");
+            return;
+        }
+    };
+
+    let state = &GLOBALS.event_sink;
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+    let mut backoff = MIN_BACKOFF;
+
+    log::info!(target: "Server", "Delivering events to {url}");
+
+    loop {
+        if *shutting_down.borrow() {
+            return;
+        }
+
+        // Paused while we leave the store alone (while we hand over the new process
+        // delivers the outbox instead)
+        if !crate::mode::background_writes() {
+            tokio::select! {
+                _ = crate::mode::wait_for_writes() => {},
+                _ = shutting_down.changed() => {},
+            }
+            continue;
+        }
+
+        let batch_size = GLOBALS.config.read().event_sink_batch_size.max(1);
+        let (keys, body) = match next_batch(batch_size) {
+            Ok(batch) => batch,
+            Err(e) => {
+                log::error!(
+                    target: "Server",
+                    "Event sink outbox could not be read ({e}), retrying in {}s",
+                    backoff.as_secs()
+                );
+                *state.last_error.lock() = Some(format!("outbox: {e}"));
+                back_off(&mut backoff, &mut shutting_down).await;
+                continue;
+            }
+        };
+
+        if keys.is_empty() {
+            tokio::select! {
+                _ = state.wakeup.notified() => {},
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {},
+                _ = shutting_down.changed() => {},
+            }
+            continue;
+        }
+
+        let error = match tokio::time::timeout(REQUEST_TIMEOUT, post(&url, body)).await {
+            Ok(Ok(status)) if status.is_success() => None,
+            Ok(Ok(status)) => Some(format!("endpoint answered {status}")),
+            Ok(Err(e)) => Some(format!("{e}")),
+            Err(_) => Some("timed out".to_owned()),
+        };
+
+        match error {
+            // Left in the outbox, to be delivered again once we may write (or by the new
+            // process we are handing over to)
+            None if !crate::mode::background_writes() => {}
+            None => {
+                state
+                    .delivered
+                    .fetch_add(keys.len() as u64, Ordering::Relaxed);
+
+                // Still in the outbox, so they are delivered again (at least once)
+                if let Err(e) = remove_delivered(&keys) {
+                    log::error!(
+                        target: "Server",
+                        "Event sink outbox could not be updated ({e}), retrying in {}s",
+                        backoff.as_secs()
+                    );
+                    *state.last_error.lock() = Some(format!("outbox: {e}"));
+                    back_off(&mut backoff, &mut shutting_down).await;
+                    continue;
+                }
+                backoff = MIN_BACKOFF;
+            }
+            Some(e) => {
+                state.failures.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    target: "Server",
+                    "Event sink delivery of {} events failed ({}), retrying in {}s",
+                    keys.len(),
+                    e,
+                    backoff.as_secs()
+                );
+                *state.last_error.lock() = Some(e);
+                back_off(&mut backoff, &mut shutting_down).await;
+            }
+        }
+    }
+}
+
+// Wait out `backoff` (or until shutdown), and double it for next time
+async fn back_off(backoff: &mut Duration, shutting_down: &mut watch::Receiver<bool>) {
+    tokio::select! {
+        _ = tokio::time::sleep(*backoff) => {},
+        _ = shutting_down.changed() => {},
+    }
+    *backoff = (*backoff * 2).min(MAX_BACKOFF);
+}
+
+async fn post(url: &Url, body: Vec<u8>) -> Result<StatusCode, Error> {
+    let host = url
+        .host_str()
+        .ok_or(ChorusError::EventSink("URL has no host".to_owned()).into_err())?
+        .to_owned();
+    let port = url
+        .port_or_known_default()
+        .ok_or(ChorusError::EventSink("URL has no port".to_owned()).into_err())?;
+    let stream = TcpStream::connect((host.as_str(), port)).await?;
+
+    match url.scheme() {
+        "http" => send(TokioIo::new(stream), url, body).await,
+        "https" => {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let tls_config = rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let server_name = rustls_pki_types::ServerName::try_from(host)
+                .map_err(|e| ChorusError::EventSink(format!("{e}")).into_err())?;
+            let stream = TlsConnector::from(Arc::new(tls_config))
+                .connect(server_name, stream)
+                .await?;
+            send(TokioIo::new(stream), url, body).await
+        }
+        scheme => Err(ChorusError::EventSink(format!("Unsupported scheme {scheme}")).into()),
+    }
+}
+
+async fn send<T>(io: T, url: &Url, body: Vec<u8>) -> Result<StatusCode, Error>
+where
+    T: Read + Write + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(io).await?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    let mut path = url.path().to_owned();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+
+    let request = Request::builder()
+        .method("POST")
+        .uri(path)
+        .header("Host", authority)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body)))?;
+    let response = sender.send_request(request).await?;
+    Ok(response.status())
+}
@@ -0,0 +1,349 @@
+//! Optional index over multi-letter tag names
+//!
+//! pocket only indexes single-letter tags. Operators can list multi-letter tag names
+//! (`indexed_tag_names`) that are worth indexing; for those we keep our own table,
+//! built by `migrate()` when the list changes (a chunk of the store at a time, see
+//! `crate::backfill`) and maintained at ingest.
+//!
+//! Entries are removed along with their event (see `crate::remove_event`). Lookups
+//! still skip ids that no longer resolve to an event, in case any are left behind.
+
+use crate::config::Config;
+use crate::error::{ChorusError, Error};
+use crate::filter::{ChorusFilter, LongTagCondition};
+use crate::globals::GLOBALS;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Id};
+
+// LMDB keys are limited to 511 bytes; longer values are indexed by their prefix and
+// we rely on post-filtering to be exact.
+const MAX_VALUE_LEN: usize = 256;
+
+fn key_prefix(name: &[u8], value: &[u8]) -> Vec<u8> {
+    let value = &value[..value.len().min(MAX_VALUE_LEN)];
+    let mut key = Vec::with_capacity(name.len() + value.len() + 2 + 32);
+    key.extend_from_slice(name);
+    key.push(0);
+    key.extend_from_slice(value);
+    key.push(0);
+    key
+}
+
+/// Whether this tag name is index-accelerated
+pub fn is_indexed(name: &str) -> bool {
+    GLOBALS
+        .config
+        .read()
+        .indexed_tag_names
+        .iter()
+        .any(|n| n == name)
+}
+
+// The index keys for an event
+fn event_keys(names: &[String], event: &Event) -> Result<Vec<Vec<u8>>, Error> {
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for mut tag in event.tags()?.iter() {
+        let Some(name) = tag.next() else {
+            continue;
+        };
+        if name.len() < 2 || !names.iter().any(|n| n.as_bytes() == name) {
+            continue;
+        }
+        if let Some(value) = tag.next() {
+            let mut key = key_prefix(name, value);
+            key.extend_from_slice(event.id().as_slice());
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+fn index_event_into(
+    store: &Store,
+    txn: &mut pocket_db::heed::RwTxn,
+    names: &[String],
+    event: &Event,
+) -> Result<(), Error> {
+    let table = store
+        .extra_table("long_tag_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index",
+        )))?;
+    let created_at = event.created_at().as_u64().to_be_bytes();
+    for key in event_keys(names, event)? {
+        crate::failpoints::hit("tag_index.put")?;
+        table.put(txn, &key, &created_at)?;
+    }
+    Ok(())
+}
+
+/// Index a newly stored event
+pub fn index_event(event: &Event) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let names = GLOBALS.config.read().indexed_tag_names.clone();
+    if names.is_empty() {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    index_event_into(store, &mut txn, &names, event)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// The index keys of an event that is about to be removed, to pass to `unindex()`
+/// once it is gone
+pub fn keys_for_removal(event: &Event) -> Result<Vec<Vec<u8>>, Error> {
+    let names = GLOBALS.config.read().indexed_tag_names.clone();
+    event_keys(&names, event)
+}
+
+/// Remove index entries (of a removed event)
+pub fn unindex(keys: &[Vec<u8>]) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("long_tag_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index",
+        )))?;
+    let mut txn = store.write_txn()?;
+    for key in keys.iter() {
+        let _ = table.delete(&mut txn, key)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// (Re)build the index if the configured tag names differ from those it was built for
+pub fn migrate(store: &Store, config: &Config) -> Result<(), Error> {
+    let _reading = crate::map_size::reading();
+    let meta = store
+        .extra_table("long_tag_index_meta")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index_meta",
+        )))?;
+    let table = store
+        .extra_table("long_tag_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index",
+        )))?;
+
+    let mut names = config.indexed_tag_names.clone();
+    names.sort();
+    names.dedup();
+    let names_json = serde_json::to_vec(&names)?;
+
+    {
+        let txn = store.read_txn()?;
+        if meta.get(&txn, b"names")? == Some(names_json.as_slice()) {
+            return Ok(());
+        }
+    }
+
+    // Forgotten first, so that if we are stopped partway it is all built again
+    let mut txn = store.write_txn()?;
+    table.clear(&mut txn)?;
+    let _ = meta.delete(&mut txn, b"names")?;
+    txn.commit()?;
+
+    if !names.is_empty() {
+        let screen = |e: &Event| -> ScreenResult {
+            let has_indexed_tag = match e.tags() {
+                Ok(tags) => tags.iter().any(|mut t| match t.next() {
+                    Some(name) => names.iter().any(|n| n.as_bytes() == name),
+                    None => false,
+                }),
+                Err(_) => false,
+            };
+            if has_indexed_tag {
+                ScreenResult::Match
+            } else {
+                ScreenResult::Mismatch
+            }
+        };
+        let _ = crate::backfill::backfill(
+            store,
+            &format!("tag index for {:?}", names),
+            screen,
+            |txn, event| index_event_into(store, txn, &names, event),
+        )?;
+    }
+
+    let mut txn = store.write_txn()?;
+    meta.put(&mut txn, b"names", &names_json)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Ids of events having a `name` tag with the given value
+pub fn find_ids(name: &str, value: &str) -> Result<Vec<Id>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let table = store
+        .extra_table("long_tag_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index",
+        )))?;
+    let txn = store.read_txn()?;
+    let prefix = key_prefix(name.as_bytes(), value.as_bytes());
+    let mut ids: Vec<Id> = Vec::new();
+    for i in table.prefix_iter(&txn, &prefix)? {
+        let (key, _created_at) = i?;
+        if key.len() >= 32 {
+            ids.push(Id::from_bytes(key[key.len() - 32..].try_into().unwrap()));
+        }
+    }
+    Ok(ids)
+}
+
+/// Find events matching a filter via the index for one of its conditions.
+///
+/// Returns the matching events (newest first, limited by the filter's limit) and whether
+/// any were redacted by the screen.
+pub fn find_events<F>(
+    filter: &ChorusFilter,
+    condition: &LongTagCondition,
+    screen: F,
+) -> Result<(Vec<&'static Event>, bool), Error>
+where
+    F: Fn(&Event) -> ScreenResult,
+{
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+
+    let mut ids: Vec<Id> = Vec::new();
+    for value in condition.values.iter() {
+        ids.extend(find_ids(&condition.name, value)?);
+    }
+    ids.sort();
+    ids.dedup();
+
+    let mut redacted = false;
+    let mut events: Vec<&'static Event> = Vec::new();
+    for id in ids {
+        let Some(event) = store.get_event_by_id(id)? else {
+            continue;
+        };
+        if !filter.event_matches(event)? {
+            continue;
+        }
+        match screen(event) {
+            ScreenResult::Match => events.push(event),
+            ScreenResult::Redacted => redacted = true,
+            ScreenResult::Mismatch => {}
+        }
+    }
+
+    events.sort_by_key(|e| std::cmp::Reverse(e.created_at()));
+    events.truncate(filter.filter.limit() as usize);
+
+    Ok((events, redacted))
+}
+
+/// Check the index's entries against the events in the store.
+///
+/// Returns the number of entries that no longer resolve to an event (harmless, see the
+/// module docs) and a description of each entry pointing at an event that does not have
+/// the tag.
+pub fn verify_entries() -> Result<(usize, Vec<String>), Error> {
+    let _reading = crate::map_size::reading();
+    let names = GLOBALS.config.read().indexed_tag_names.clone();
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("long_tag_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index",
+        )))?;
+    let txn = store.read_txn()?;
+
+    let mut stale: usize = 0;
+    let mut problems: Vec<String> = Vec::new();
+
+    for i in table.iter(&txn)? {
+        let (key, _created_at) = i?;
+        if key.len() < 32 {
+            problems.push(format!("Malformed tag index key {}", hex::encode(key)));
+            continue;
+        }
+        let id = Id::from_bytes(key[key.len() - 32..].try_into().unwrap());
+        match store.get_event_by_id(id)? {
+            None => stale += 1,
+            Some(event) => {
+                if !event_keys(&names, event)?.iter().any(|k| k == key) {
+                    problems.push(format!(
+                        "Tag index entry for {} does not match the event",
+                        id.as_hex_string()
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok((stale, problems))
+}
+
+/// Check that each of `events` has all of its entries in the index.
+///
+/// Returns a description of each event missing entries, and those events.
+pub fn verify_events(events: &[&Event]) -> Result<(Vec<String>, Vec<Id>), Error> {
+    let _reading = crate::map_size::reading();
+    let names = GLOBALS.config.read().indexed_tag_names.clone();
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table("long_tag_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index",
+        )))?;
+    let txn = store.read_txn()?;
+
+    let mut problems: Vec<String> = Vec::new();
+    let mut missing: Vec<Id> = Vec::new();
+
+    for event in events.iter() {
+        for key in event_keys(&names, event)? {
+            if table.get(&txn, &key)?.is_none() {
+                problems.push(format!(
+                    "Event {} is missing from the tag index",
+                    event.id().as_hex_string()
+                ));
+                missing.push(event.id());
+                break;
+            }
+        }
+    }
+
+    Ok((problems, missing))
+}
+
+/// Remove entries that no longer resolve to an event. Returns how many were removed.
+pub fn remove_stale() -> Result<usize, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let _reading = crate::map_size::reading();
+    let table = store
+        .extra_table("long_tag_index")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "long_tag_index",
+        )))?;
+
+    let mut stale: Vec<Vec<u8>> = Vec::new();
+    {
+        let txn = store.read_txn()?;
+        for i in table.iter(&txn)? {
+            let (key, _created_at) = i?;
+            if key.len() < 32 {
+                continue;
+            }
+            let id = Id::from_bytes(key[key.len() - 32..].try_into().unwrap());
+            if store.get_event_by_id(id)?.is_none() {
+                stale.push(key.to_owned());
+            }
+        }
+    }
+
+    unindex(&stale)?;
+    Ok(stale.len())
+}
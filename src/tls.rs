@@ -1,12 +1,38 @@
+//! TLS, with certificates that are picked up again when they are renewed
+//!
+//! The certificate chain and key are loaded into a rustls `ServerConfig` that new
+//! connections are accepted with. `watch()` polls the files' modification times and, when
+//! they change, loads them again and swaps the new `ServerConfig` in; connections already
+//! accepted keep the one they started with. If the renewed files cannot be loaded, that is
+//! logged and the old certificate is served until they can.
+//!
+//! ALPN offers `h2` (if `enable_http2`) and `http/1.1`. Websocket upgrades need HTTP/1.1,
+//! which is what clients offer when opening a websocket, so HTTP/2 is only ever used for
+//! plain HTTP requests (NIP-11, Blossom, etc).
+
 use crate::config::Config;
 use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use parking_lot::{Mutex, RwLock};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use std::fs::File;
 use std::io::BufReader;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio_rustls::{rustls, TlsAcceptor};
 
-pub fn tls_acceptor(config: &Config) -> Result<TlsAcceptor, Error> {
+// The configuration new connections are accepted with
+static CURRENT: RwLock<Option<Arc<rustls::ServerConfig>>> = RwLock::new(None);
+
+// The modification times of the certificate chain and key we last loaded (or tried to)
+static LOADED: Mutex<Option<(SystemTime, SystemTime)>> = Mutex::new(None);
+
+fn modified(path: &str) -> Result<SystemTime, Error> {
+    Ok(std::fs::metadata(path)?.modified()?)
+}
+
+/// Build a rustls server configuration from the configured certificate chain and key
+pub fn server_config(config: &Config) -> Result<rustls::ServerConfig, Error> {
     let cert_file = File::open(&config.certchain_pem_path)?;
     let mut certificates: Vec<CertificateDer<'static>> = Vec::new();
     for maybe_cert in rustls_pemfile::certs(&mut BufReader::new(cert_file)) {
@@ -27,9 +53,81 @@ pub fn tls_acceptor(config: &Config) -> Result<TlsAcceptor, Error> {
         None => return Err(ChorusError::NoPrivateKey.into()),
     };
 
-    let tls_config = rustls::ServerConfig::builder()
+    let mut tls_config = rustls::ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(certificates, key)?;
 
-    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+    // In order of preference
+    if config.enable_http2 {
+        tls_config.alpn_protocols.push(b"h2".to_vec());
+    }
+    tls_config.alpn_protocols.push(b"http/1.1".to_vec());
+
+    Ok(tls_config)
+}
+
+/// Load the certificate chain and key, and accept new connections with them. On error
+/// the configuration already in use (if any) is kept.
+pub fn load(config: &Config) -> Result<(), Error> {
+    // (Read before loading, so that a change made while we load is noticed next time)
+    let mtimes = (
+        modified(&config.certchain_pem_path)?,
+        modified(&config.key_pem_path)?,
+    );
+    *LOADED.lock() = Some(mtimes);
+    let tls_config = server_config(config)?;
+    *CURRENT.write() = Some(Arc::new(tls_config));
+    Ok(())
+}
+
+/// An acceptor for a new connection, or None if TLS is not in use
+pub fn acceptor() -> Option<TlsAcceptor> {
+    CURRENT.read().clone().map(TlsAcceptor::from)
+}
+
+/// Load the certificate chain and key again if either file changed since they were last
+/// loaded. Returns whether they were reloaded.
+pub fn reload_if_changed(config: &Config) -> Result<bool, Error> {
+    let mtimes = (
+        modified(&config.certchain_pem_path)?,
+        modified(&config.key_pem_path)?,
+    );
+    if *LOADED.lock() == Some(mtimes) {
+        return Ok(false);
+    }
+    load(config)?;
+    Ok(true)
+}
+
+/// Watch the certificate chain and key, every `tls_reload_seconds`, until shutdown
+pub async fn watch() {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+
+    loop {
+        let seconds = GLOBALS.config.read().tls_reload_seconds;
+        if seconds == 0 {
+            return;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(seconds)) => {},
+            _ = shutting_down.changed() => {},
+        }
+        if *shutting_down.borrow() {
+            return;
+        }
+
+        let config = GLOBALS.config.read().clone();
+        if !config.tls_in_use() {
+            continue;
+        }
+        match reload_if_changed(&config) {
+            Ok(false) => {}
+            Ok(true) => log::info!(target: "Server", "Reloaded renewed TLS certificates"),
+            Err(e) => log::error!(
+                target: "Server",
+                "TLS certificates changed but could not be loaded, still serving the old ones: {e}"
+            ),
+        }
+    }
 }
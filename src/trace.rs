@@ -0,0 +1,144 @@
+//! Per-connection trace ids, and structured (JSON) logging
+//!
+//! Every connection is served within a `Context` holding a short random trace id and the
+//! client's hashed address, and while a message (an HTTP request, or a REQ, EVENT, etc)
+//! is being handled, what kind of message it is, its subscription id, and when it
+//! started. With `json_logs`, each log line is a JSON object carrying these along with the
+//! message (`ts`, `level`, `target`, `msg`, `trace`, `peer_hash`, `msg_type`, `sub_id` and
+//! `latency_ms`), so the lines about one connection can be picked out of the interleaved
+//! lines of all of them.
+
+use crate::ip::HashedPeer;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+// What is being handled
+struct MessageContext {
+    msg_type: &'static str,
+    sub_id: Option<String>,
+    started: Instant,
+}
+
+/// The logging context of a connection
+pub struct Context {
+    trace: String,
+    peer: HashedPeer,
+    message: RefCell<Option<MessageContext>>,
+}
+
+impl Context {
+    /// A new connection's context, with a new trace id
+    pub fn new(peer: HashedPeer) -> Context {
+        Context {
+            trace: new_trace_id(),
+            peer,
+            message: RefCell::new(None),
+        }
+    }
+
+    /// The current connection's context (for a task of its own), but with `peer` as the
+    /// client's address (which a proxy may have told us)
+    pub fn inherit(peer: HashedPeer) -> Context {
+        let trace = CONTEXT
+            .try_with(|c| c.trace.clone())
+            .unwrap_or_else(|_| new_trace_id());
+        Context {
+            trace,
+            peer,
+            message: RefCell::new(None),
+        }
+    }
+}
+
+/// A short random id
+pub fn new_trace_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:08x}", hasher.finish() as u32)
+}
+
+/// Run `f` within `context`
+pub async fn scope<F: Future>(context: Context, f: F) -> F::Output {
+    CONTEXT.scope(context, f).await
+}
+
+/// Start handling a message of type `msg_type`
+pub fn begin(msg_type: &'static str) {
+    begin_at(msg_type, Instant::now());
+}
+
+/// Start handling a message of type `msg_type`, with latency counted from `started`
+pub fn begin_at(msg_type: &'static str, started: Instant) {
+    let _ = CONTEXT.try_with(|c| {
+        *c.message.borrow_mut() = Some(MessageContext {
+            msg_type,
+            sub_id: None,
+            started,
+        });
+    });
+}
+
+/// Note the subscription id of the message being handled
+pub fn sub_id(sub_id: &str) {
+    let _ = CONTEXT.try_with(|c| {
+        if let Some(message) = c.message.borrow_mut().as_mut() {
+            message.sub_id = Some(sub_id.to_owned());
+        }
+    });
+}
+
+/// Finish handling the message
+pub fn end() {
+    let _ = CONTEXT.try_with(|c| *c.message.borrow_mut() = None);
+}
+
+/// Shorten something (like a filter) for a log line
+pub fn summarize(text: &str) -> &str {
+    const MAX: usize = 256;
+    if text.len() <= MAX {
+        return text;
+    }
+    let mut end = MAX;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Format a log record as a JSON object (for env_logger, with `json_logs`)
+pub fn format_json(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record<'_>,
+) -> std::io::Result<()> {
+    let mut line = Map::new();
+    line.insert("ts".to_owned(), buf.timestamp_millis().to_string().into());
+    line.insert("level".to_owned(), record.level().as_str().into());
+    line.insert("target".to_owned(), record.target().into());
+    let _ = CONTEXT.try_with(|c| {
+        line.insert("trace".to_owned(), c.trace.clone().into());
+        line.insert("peer_hash".to_owned(), c.peer.ip().to_string().into());
+        if let Some(message) = c.message.borrow().as_ref() {
+            line.insert("msg_type".to_owned(), message.msg_type.into());
+            if let Some(sub_id) = &message.sub_id {
+                line.insert("sub_id".to_owned(), sub_id.clone().into());
+            }
+            line.insert(
+                "latency_ms".to_owned(),
+                (message.started.elapsed().as_millis() as u64).into(),
+            );
+        }
+    });
+    line.insert("msg".to_owned(), record.args().to_string().into());
+    writeln!(buf, "{}", Value::Object(line))
+}
@@ -0,0 +1,116 @@
+//! Validation of what clients send, before we act on any of it
+//!
+//! Messages longer than `max_message_length` are refused before they are parsed. A REQ
+//! (or COUNT) is refused as a whole, with `CLOSED` and `invalid:` saying which constraint
+//! it broke, if it has more than `max_filters` filters, if any of a filter's lists (`ids`,
+//! `authors`, `kinds` or a tag condition) has more than `max_filter_values` values, or if
+//! an `ids`, `authors`, `#e` or `#p` value is not 64 lowercase hex characters. Nothing of
+//! it is served, and nothing is truncated.
+
+use crate::config::Config;
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use serde_json::{Map, Value};
+
+// Fields whose values must be event ids or pubkeys
+const HEX_FIELDS: [&str; 4] = ["ids", "authors", "#e", "#p"];
+
+/// Check the length of a message, before parsing it
+pub fn check_message(len: usize) -> Result<(), Error> {
+    let max = GLOBALS.config.read().max_message_length;
+    if len > max {
+        return Err(ChorusError::MessageTooLong(max).into());
+    }
+    Ok(())
+}
+
+/// Check a filter a client sent, as JSON
+pub fn check_filter(filter: &Map<String, Value>) -> Result<(), Error> {
+    check_filter_with(&GLOBALS.config.read(), filter)
+}
+
+fn check_filter_with(config: &Config, filter: &Map<String, Value>) -> Result<(), Error> {
+    for (field, value) in filter.iter() {
+        let Value::Array(values) = value else {
+            continue;
+        };
+        if values.len() > config.max_filter_values {
+            return Err(ChorusError::TooManyFilterValues(
+                field.to_owned(),
+                config.max_filter_values,
+            )
+            .into());
+        }
+        if HEX_FIELDS.contains(&field.as_str()) && !values.iter().all(is_hex_key) {
+            return Err(ChorusError::FilterValueNotHex(field.to_owned()).into());
+        }
+    }
+    Ok(())
+}
+
+// Whether it is an event id or pubkey: 64 lowercase hex characters
+fn is_hex_key(value: &Value) -> bool {
+    value.as_str().is_some_and(|s| {
+        s.len() == 64
+            && s.bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    // Why `filter` is refused, if it is
+    fn refusal(config: &Config, filter: Value) -> Option<String> {
+        check_filter_with(config, filter.as_object().unwrap())
+            .err()
+            .map(|e| format!("{}", e.inner))
+    }
+
+    #[test]
+    fn test_check_filter() {
+        let config = Config {
+            max_filter_values: 3,
+            ..Default::default()
+        };
+        let id = "ab".repeat(32);
+
+        assert_eq!(refusal(&config, json!({})), None);
+        assert_eq!(
+            refusal(&config, json!({ "ids": [id, id, id], "kinds": [1, 2, 3] })),
+            None
+        );
+        assert_eq!(
+            refusal(&config, json!({ "kinds": [1, 2, 3, 4] })),
+            Some("kinds has more than 3 values".to_owned())
+        );
+        assert_eq!(
+            refusal(&config, json!({ "#t": ["a", "b", "c", "d"] })),
+            Some("#t has more than 3 values".to_owned())
+        );
+        assert_eq!(
+            refusal(&config, json!({ "authors": [id, id.to_uppercase()] })),
+            Some("authors values must be 64 lowercase hex characters".to_owned())
+        );
+        assert_eq!(
+            refusal(&config, json!({ "ids": [&id[..63]] })),
+            Some("ids values must be 64 lowercase hex characters".to_owned())
+        );
+        assert_eq!(
+            refusal(&config, json!({ "#e": [format!("{}zz", &id[..62])] })),
+            Some("#e values must be 64 lowercase hex characters".to_owned())
+        );
+        assert_eq!(
+            refusal(&config, json!({ "#p": [1] })),
+            Some("#p values must be 64 lowercase hex characters".to_owned())
+        );
+
+        // Other tags may have any values
+        assert_eq!(
+            refusal(&config, json!({ "#a": ["1:x:y"], "#t": ["AB"] })),
+            None
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Verifying incoming events on a pool of worker threads
+//!
+//! Checking an event's id and schnorr signature is the most expensive thing we do with
+//! it, and done inline it ties up the core running the connection's task while other
+//! cores idle. Instead it runs on blocking threads, at most `verify_workers` at a time,
+//! and the connection task waits for the answer. Since a connection handles one message
+//! at a time, its OKs still go back in the order its events came in.
+//!
+//! Events waiting for a worker are bounded by `verify_queue`; beyond that they are refused
+//! (as the server being busy) rather than queued without bound.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use pocket_types::Event;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Semaphore;
+
+static WORKERS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+// Events waiting for a worker
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+// Counts an event as waiting for as long as it lives
+struct Waiting;
+
+impl Waiting {
+    fn new() -> Option<Waiting> {
+        let bound = GLOBALS.config.read().verify_queue;
+        if WAITING.fetch_add(1, Ordering::Relaxed) >= bound {
+            let _ = WAITING.fetch_sub(1, Ordering::Relaxed);
+            None
+        } else {
+            Some(Waiting)
+        }
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        let _ = WAITING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How many workers verify events: `verify_workers`, or else one per core
+pub fn num_workers() -> usize {
+    match GLOBALS.config.read().verify_workers {
+        0 => std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        n => n,
+    }
+}
+
+/// Verify the event serialized as `json` on a worker
+pub async fn verify(json: Vec<u8>) -> Result<(), Error> {
+    let workers = WORKERS.get_or_init(|| Arc::new(Semaphore::new(num_workers())));
+
+    let Some(waiting) = Waiting::new() else {
+        return Err(ChorusError::ServerBusy.into());
+    };
+    let permit = workers
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|e| ChorusError::General(format!("{e}")).into_err())?;
+    drop(waiting);
+
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        verify_json(&json)
+    })
+    .await
+    .map_err(|e| ChorusError::General(format!("{e}")).into_err())?
+}
+
+/// Verify the event serialized as `json`: that its id is the hash of its contents, and
+/// its signature is valid. This is the work `verify()` does, inline.
+pub fn verify_json(json: &[u8]) -> Result<(), Error> {
+    let mut buffer = vec![0_u8; json.len() + 256];
+    let (_size, event) = Event::from_json(json, &mut buffer)?;
+
+    // Verify the id is the hash of the event first, since it is much cheaper than
+    // verifying the signature and lets us say exactly what went wrong
+    let computed = crate::nostr::compute_event_id(json)?;
+    if computed.as_slice() != event.id().as_slice() {
+        return Err(ChorusError::EventIdMismatch(
+            hex::encode(computed),
+            event.id().as_hex_string(),
+        )
+        .into());
+    }
+
+    // Verify the event is valid (id is hash, signature is valid)
+    if let Err(e) = event.verify() {
+        return Err(ChorusError::EventIsInvalid(format!("{}", e.inner)).into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,146 @@
+//! Walking the stored events matching a filter a chunk at a time, newest first
+//!
+//! Sweeps, exports and backfills over the whole store find no more than `CHUNK` events
+//! at a time (rather than every event at once), each chunk continuing from the
+//! created_at where the last left off. A chunk is cut at the created_at of its last event,
+//! since there may be more events created that second than it held. The next chunk starts
+//! at that second again, skipping the events from it that were walked already.
+
+use crate::error::Error;
+use crate::globals::GLOBALS;
+use pocket_db::{ScreenResult, Store};
+use pocket_types::{Event, Filter, Id};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+
+/// Events per chunk (at most, but for those that share a second with the last)
+pub const CHUNK: usize = 500;
+
+/// A walk over the stored events matching a filter (which may be a scraper), newest
+/// first. Its `limit`, if it has one, applies to the whole walk.
+pub struct Walk {
+    json: Map<String, Value>,
+    remaining: usize,
+
+    // The created_at the next chunk starts at, and the events created then that were
+    // already walked
+    until: Option<u64>,
+    walked_at_until: HashSet<Id>,
+
+    done: bool,
+}
+
+impl Walk {
+    /// Walk the events matching a filter (as JSON)
+    pub fn new(filter_json: &[u8]) -> Result<Walk, Error> {
+        let json: Map<String, Value> = serde_json::from_slice(filter_json)?;
+        let remaining = json
+            .get("limit")
+            .and_then(|v| v.as_u64())
+            .map(|limit| limit as usize)
+            .unwrap_or(usize::MAX);
+        Ok(Walk {
+            json,
+            remaining,
+            until: None,
+            walked_at_until: HashSet::new(),
+            done: false,
+        })
+    }
+
+    /// Walk every stored event
+    pub fn all() -> Walk {
+        Walk {
+            json: Map::new(),
+            remaining: usize::MAX,
+            until: None,
+            walked_at_until: HashSet::new(),
+            done: false,
+        }
+    }
+
+    /// The next chunk of the events `screen` matches, newest first, or None once they
+    /// have all been walked
+    pub fn next_chunk<S>(&mut self, screen: S) -> Result<Option<Vec<&'static Event>>, Error>
+    where
+        S: Fn(&Event) -> ScreenResult,
+    {
+        self.next_chunk_in(GLOBALS.store.get().unwrap(), screen)
+    }
+
+    /// The next chunk from `store` (as when migrating a store that is not yet the relay's)
+    pub fn next_chunk_in<S>(
+        &mut self,
+        store: &Store,
+        screen: S,
+    ) -> Result<Option<Vec<&'static Event>>, Error>
+    where
+        S: Fn(&Event) -> ScreenResult,
+    {
+        if self.done || self.remaining == 0 {
+            return Ok(None);
+        }
+
+        // Enough to get past what was walked already
+        let asked = CHUNK.min(self.remaining) + self.walked_at_until.len();
+        let mut json = self.json.clone();
+        let _ = json.insert("limit".to_owned(), asked.into());
+        if let Some(until) = self.until {
+            if json
+                .get("until")
+                .and_then(|v| v.as_u64())
+                .is_none_or(|u| u > until)
+            {
+                let _ = json.insert("until".to_owned(), until.into());
+            }
+        }
+        let json = serde_json::to_vec(&json)?;
+        let mut buffer = vec![0_u8; json.len() * 2 + 256];
+        let (_incount, _outcount, filter) = Filter::from_json(&json, &mut buffer)?;
+
+        let mut events = {
+            let _reading = crate::map_size::reading();
+            let (events, _redacted) = store.find_events(filter, true, 0, 0, screen)?;
+            events
+        };
+        events.sort_by(|a, b| {
+            b.created_at()
+                .cmp(&a.created_at())
+                .then(a.id().cmp(&b.id()))
+        });
+
+        // With several index ranges the store finds up to the limit in each, so only up
+        // to the `asked`th newest is everything there is
+        let cut = if events.len() >= asked {
+            let cut = events[asked - 1].created_at().as_u64();
+            events.retain(|e| e.created_at().as_u64() >= cut);
+            Some(cut)
+        } else {
+            None
+        };
+
+        events.retain(|e| {
+            Some(e.created_at().as_u64()) != self.until || !self.walked_at_until.contains(&e.id())
+        });
+        events.truncate(self.remaining);
+        self.remaining -= events.len();
+
+        match cut {
+            Some(cut) if !events.is_empty() => {
+                if Some(cut) != self.until {
+                    self.until = Some(cut);
+                    self.walked_at_until.clear();
+                }
+                for event in events.iter() {
+                    if event.created_at().as_u64() == cut {
+                        let _ = self.walked_at_until.insert(event.id());
+                    }
+                }
+            }
+            // That was all of them
+            _ => self.done = true,
+        }
+
+        Ok(Some(events))
+    }
+}
@@ -1,9 +1,10 @@
 use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
 use base64::prelude::*;
 use http::header::AUTHORIZATION;
 use hyper::body::Incoming;
 use hyper::Request;
-use pocket_types::Event;
+use pocket_types::{Event, Pubkey};
 
 fn s_err(s: &str) -> Result<AuthData, Error> {
     Err(ChorusError::BlossomAuthFailure(s.to_owned()).into())
@@ -12,6 +13,7 @@ fn s_err(s: &str) -> Result<AuthData, Error> {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AuthVerb {
     Upload,
+    Media,
     List,
     Delete,
     Mirror,
@@ -19,6 +21,9 @@ pub enum AuthVerb {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AuthData {
+    /// Who authorized the request
+    pub pubkey: Pubkey,
+
     /// If a verb was included, this is it
     pub verb: Option<AuthVerb>,
 
@@ -28,7 +33,7 @@ pub struct AuthData {
 
 pub fn verify_auth(request: &Request<Incoming>) -> Result<AuthData, Error> {
     // Force every other error into a BlossomAuthFailure error
-    match verify_auth_inner(request) {
+    match verify_auth_inner(request).and_then(|ad| check_permitted(ad.pubkey).map(|_| ad)) {
         Ok(ad) => Ok(ad),
         Err(e) => match e.inner {
             ChorusError::BlossomAuthFailure(_) => Err(e),
@@ -37,6 +42,40 @@ pub fn verify_auth(request: &Request<Incoming>) -> Result<AuthData, Error> {
     }
 }
 
+/// Whether `pubkey` may use this Blossom server (upload, mirror, delete and list). Admins
+/// always may. Otherwise those in `blossom_allowed_pubkeys` may, and relay users if
+/// `blossom_relay_users`; if neither is configured, only authorized users may.
+pub fn check_permitted(pubkey: Pubkey) -> Result<(), Error> {
+    let (allowed, relay_users) = {
+        let config = GLOBALS.config.read();
+        if config.blossom_allowed_pubkeys.contains(&pubkey) {
+            return Ok(());
+        }
+        (
+            !config.blossom_allowed_pubkeys.is_empty(),
+            config.blossom_relay_users,
+        )
+    };
+
+    let permitted = if crate::is_admin(pubkey) {
+        true
+    } else if relay_users {
+        crate::is_relay_user(pubkey)
+    } else if allowed {
+        false
+    } else {
+        crate::is_authorized_user(pubkey)
+    };
+    if permitted {
+        Ok(())
+    } else {
+        Err(ChorusError::BlossomAuthFailure(
+            "You are not permitted to use this Blossom server".to_owned(),
+        )
+        .into())
+    }
+}
+
 fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
     // Must have AUTHORIZATION header
     let authz = match request.headers().get(AUTHORIZATION) {
@@ -66,11 +105,6 @@ fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
         return s_err(&format!("Authorization event is invalid: {}", e));
     }
 
-    // Nostr event must be signed by a chorus user
-    if !crate::is_authorized_user(event.pubkey()) {
-        return s_err("You are not an authorized user");
-    }
-
     // Event kind must be 24242
     if event.kind().as_u16() != 24242 {
         return s_err("Authorization event not kind 24242");
@@ -102,6 +136,8 @@ fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
     let verb: Option<AuthVerb> = if let Some(t) = tags.get_value(b"t") {
         if t == b"upload" {
             Some(AuthVerb::Upload)
+        } else if t == b"media" {
+            Some(AuthVerb::Media)
         } else if t == b"list" {
             Some(AuthVerb::List)
         } else if t == b"delete" {
@@ -124,7 +160,11 @@ fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
         None
     };
 
-    Ok(AuthData { verb, hash })
+    Ok(AuthData {
+        pubkey: event.pubkey(),
+        verb,
+        hash,
+    })
 }
 
 // FIXME, expose these from pocket-types
@@ -0,0 +1,244 @@
+//! Fetching a remote blob for BUD-04 mirroring
+//!
+//! The URL comes from the client, so we only fetch http(s) URLs whose host resolves
+//! entirely to public addresses, and we connect to the address we checked rather than
+//! resolving again (so a rebinding name cannot steer us onto a private network).
+//! Redirects are followed a few times, each one checked the same way.
+
+use crate::error::{ChorusError, Error};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::rt::{Read, Write};
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::{rustls, TlsConnector};
+use url::Url;
+
+const MAX_REDIRECTS: usize = 5;
+
+/// Start fetching `url`, returning the body (which errors once it exceeds `max_bytes`)
+/// and the Content-Type the remote server gave, if any.
+pub async fn fetch(
+    url: &str,
+    max_bytes: u64,
+) -> Result<(BoxBody<Bytes, Error>, Option<String>), Error> {
+    let mut url =
+        Url::parse(url).map_err(|e| ChorusError::BlossomMirrorUrl(format!("{e}")).into_err())?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let response = get(&url).await?;
+        let status = response.status();
+
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(
+                    ChorusError::BlossomMirror(format!("{status} without a Location")).into_err(),
+                )?;
+            url = url
+                .join(location)
+                .map_err(|e| ChorusError::BlossomMirror(format!("Bad redirect: {e}")).into_err())?;
+            continue;
+        }
+
+        if !status.is_success() {
+            return Err(
+                ChorusError::BlossomMirror(format!("Remote server responded {status}")).into(),
+            );
+        }
+
+        // Don't bother if they already tell us it is too big
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.parse::<u64>().ok());
+        if content_length.is_some_and(|len| len > max_bytes) {
+            return Err(ChorusError::BlossomTooLarge(max_bytes).into());
+        }
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_owned());
+
+        let body = Limited::new(response.into_body(), max_bytes as usize)
+            .map_err(move |e| {
+                if e.is::<LengthLimitError>() {
+                    ChorusError::BlossomTooLarge(max_bytes).into()
+                } else {
+                    ChorusError::BlossomMirror(format!("{e}")).into()
+                }
+            })
+            .boxed();
+
+        return Ok((body, content_type));
+    }
+
+    Err(ChorusError::BlossomMirror("Too many redirects".to_owned()).into())
+}
+
+async fn get(url: &Url) -> Result<Response<Incoming>, Error> {
+    let scheme = url.scheme();
+    if scheme != "http" && scheme != "https" {
+        return Err(ChorusError::BlossomMirrorUrl(format!("Unsupported scheme {scheme}")).into());
+    }
+    let host = url
+        .host_str()
+        .ok_or(ChorusError::BlossomMirrorUrl("URL has no host".to_owned()).into_err())?
+        .to_owned();
+    let port = url
+        .port_or_known_default()
+        .ok_or(ChorusError::BlossomMirrorUrl("URL has no port".to_owned()).into_err())?;
+
+    let addr = resolve_public(&host, port).await?;
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|e| ChorusError::BlossomMirror(format!("{e}")).into_err())?;
+
+    if scheme == "https" {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let tls_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        // host_str() brackets IPv6 literals, which a server name must not have
+        let server_name =
+            rustls_pki_types::ServerName::try_from(host.trim_matches(['[', ']']).to_owned())
+                .map_err(|e| ChorusError::BlossomMirrorUrl(format!("{e}")).into_err())?;
+        let stream = TlsConnector::from(Arc::new(tls_config))
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| ChorusError::BlossomMirror(format!("{e}")).into_err())?;
+        send(TokioIo::new(stream), url).await
+    } else {
+        send(TokioIo::new(stream), url).await
+    }
+}
+
+async fn send<T>(io: T, url: &Url) -> Result<Response<Incoming>, Error>
+where
+    T: Read + Write + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(io)
+        .await
+        .map_err(|e| ChorusError::BlossomMirror(format!("{e}")).into_err())?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    let mut path = url.path().to_owned();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+
+    let request = Request::builder()
+        .method("GET")
+        .uri(path)
+        .header("Host", authority)
+        .header("User-Agent", "chorus")
+        .body(Empty::<Bytes>::new())?;
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|e| ChorusError::BlossomMirror(format!("{e}")).into_err())?;
+    Ok(response)
+}
+
+// Resolve a host, insisting that every address it resolves to is public
+async fn resolve_public(host: &str, port: u16) -> Result<SocketAddr, Error> {
+    let host = host.trim_matches(['[', ']']);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| ChorusError::BlossomMirror(format!("{host}: {e}")).into_err())?
+        .collect();
+
+    if let Some(addr) = addrs.iter().find(|a| !is_public(a.ip())) {
+        return Err(ChorusError::BlossomMirrorUrl(format!(
+            "{host} resolves to non-public address {}",
+            addr.ip()
+        ))
+        .into());
+    }
+
+    addrs
+        .into_iter()
+        .next()
+        .ok_or(ChorusError::BlossomMirror(format!("{host} did not resolve")).into())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || o[0] == 0 // "this" network
+                || (o[0] == 100 && (o[1] & 0xc0) == 64) // carrier-grade NAT
+                || (o[0] == 192 && o[1] == 0 && o[2] == 0) // IETF protocol assignments
+                || (o[0] == 198 && (o[1] & 0xfe) == 18) // benchmarking
+                || o[0] >= 240) // reserved
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let s = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (s[0] & 0xfe00) == 0xfc00 // unique local
+                || (s[0] & 0xffc0) == 0xfe80 // link local
+                || (s[0] == 0x2001 && s[1] == 0x0db8) // documentation
+                || (s[0] == 0x64 && s[1] == 0xff9b)) // NAT64 (embeds IPv4)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::7f00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "8.8.8.8", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -1,51 +1,76 @@
 use crate::error::{ChorusError, Error};
-use crate::filestore::HashOutput;
+use crate::filestore::{media, HashOutput, Uploading};
 use crate::globals::GLOBALS;
+use crate::ip::HashedPeer;
+use crate::rate_limit::Action;
+use crate::web::router::Route;
 use http::header::{
-    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ALLOW, CONTENT_LENGTH,
-    CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, ORIGIN, WWW_AUTHENTICATE,
+    ACCEPT_RANGES, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+    ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, ORIGIN, RANGE, RETRY_AFTER, WWW_AUTHENTICATE,
 };
 use http::{Method, StatusCode};
 //ACCEPT, AUTHORIZATION, DATE, ETAG, ORIGIN
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty, Full};
+use http_body_util::{BodyExt, Empty, Full, LengthLimitError, Limited};
 use hyper::body::{Bytes, Incoming};
 use hyper::{Request, Response};
+use pocket_types::{Pubkey, Time};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 mod auth;
 use auth::{verify_auth, AuthVerb};
 
-pub async fn handle(request: Request<Incoming>) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
-    match route(request).await {
+mod mirror;
+
+pub async fn handle(
+    peer: HashedPeer,
+    route: Route,
+    request: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let headers = request.headers().clone();
+    match dispatch(peer, route, request).await {
         Ok(response) => Ok(response),
-        Err(e) => match e.inner {
-            ChorusError::SignalNotBlossom => Err(e),
-            _ => error_response(e),
-        },
+        Err(e) => {
+            if matches!(e.inner, ChorusError::BlossomAuthFailure(_)) {
+                super::auth_failed(peer, &headers);
+            }
+            error_response(e)
+        }
     }
 }
 
-pub async fn route(request: Request<Incoming>) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
-    let p = request.uri().path();
-    #[allow(clippy::int_plus_one)]
-    if p.starts_with("/")
-        && p.len() >= 1 + 64
-        && p.chars().skip(1).take(64).all(|c| c.is_ascii_hexdigit())
-    {
-        handle_hash(request).await
-    } else if p == "/upload" {
-        handle_upload(request).await
-    } else if p.starts_with("/list/")
-        && p.len() >= 6 + 64
-        && p.chars().skip(6).take(64).all(|c| c.is_ascii_hexdigit())
-    {
-        handle_list(request).await
-    } else if p == "/mirror" {
-        handle_mirror(request).await
-    } else {
-        Err(ChorusError::SignalNotBlossom.into())
+async fn dispatch(
+    peer: HashedPeer,
+    route: Route,
+    request: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    // Uploads, mirrors and deletes (and BUD-06 asking whether an upload would be
+    // accepted) wait for normal mode
+    let writes = matches!(*request.method(), Method::PUT | Method::DELETE)
+        || (matches!(route, Route::BlossomUpload | Route::BlossomMedia)
+            && *request.method() == Method::HEAD);
+    if writes {
+        // The store belongs to the new process while we hand over
+        if crate::handover::is_handing_over() {
+            return Err(ChorusError::ShuttingDown.into());
+        }
+        crate::mode::check_write()?;
+    }
+
+    match route {
+        Route::BlossomBlob => handle_hash(request).await,
+        Route::BlossomUpload => handle_upload(peer, request, false).await,
+        Route::BlossomMedia => handle_upload(peer, request, true).await,
+        Route::BlossomList => handle_list(request).await,
+        Route::BlossomMirror => handle_mirror(peer, request).await,
+        _ => Ok(Response::builder()
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(CONTENT_LENGTH, "0")
+            .status(StatusCode::NOT_FOUND)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?),
     }
 }
 
@@ -59,7 +84,22 @@ fn error_response(e: Error) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
             response = response.header(WWW_AUTHENTICATE, "Nostr");
             (StatusCode::UNAUTHORIZED, m)
         }
+        ChorusError::BadRequest(_) => (StatusCode::BAD_REQUEST, format!("{e}")),
+        ChorusError::BlossomHashMismatch => (StatusCode::UNPROCESSABLE_ENTITY, format!("{e}")),
+        ChorusError::BlossomMimeType(_) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("{e}")),
+        ChorusError::BlossomMirror(_) => (StatusCode::BAD_GATEWAY, format!("{e}")),
+        ChorusError::BlossomMirrorUrl(_) => (StatusCode::BAD_REQUEST, format!("{e}")),
+        ChorusError::BlossomTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, format!("{e}")),
         ChorusError::FromHex(_) => (StatusCode::BAD_REQUEST, format!("{e}")),
+        ChorusError::RateLimited(_) | ChorusError::RateLimitExceeded => {
+            (StatusCode::TOO_MANY_REQUESTS, format!("{e}"))
+        }
+        ChorusError::TimedOut => (StatusCode::GATEWAY_TIMEOUT, format!("{e}")),
+        ChorusError::ReadOnly => {
+            response = response.header(RETRY_AFTER, crate::mode::RETRY_AFTER_SECONDS);
+            (StatusCode::SERVICE_UNAVAILABLE, format!("{e}"))
+        }
+        ChorusError::ShuttingDown => (StatusCode::SERVICE_UNAVAILABLE, format!("{e}")),
         ChorusError::Io(ref ioerror) => match ioerror.kind() {
             ErrorKind::NotFound => (StatusCode::NOT_FOUND, "Not Found".to_owned()),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}")),
@@ -159,15 +199,77 @@ pub async fn handle_hash(
                     .body(Empty::new().map_err(|e| e.into()).boxed())?);
             }
 
+            let len = metadata.len();
+
+            // The type we recorded, or else sniff it (until it is recorded at startup, see
+            // `backfill_mime_types`)
+            let content_type = match crate::filestore::metadata::get_blob(hash)? {
+                Some(crate::filestore::metadata::BlobMetadata {
+                    mime_type: Some(mime_type),
+                    ..
+                }) => mime_type,
+                _ => GLOBALS
+                    .filestore
+                    .get()
+                    .unwrap()
+                    .sniff_mime_type(hash)
+                    .await?
+                    .unwrap_or("application/octet-stream".to_owned()),
+            };
+
+            // Honor Range (GET only, and ignored like any other we can't parse if it isn't
+            // ASCII)
+            let range = match request.headers().get(RANGE) {
+                Some(range) if matches!(*request.method(), Method::GET) => range
+                    .to_str()
+                    .ok()
+                    .and_then(|range| parse_range(range, len)),
+                _ => None,
+            };
+            match range {
+                Some(Ok((start, end))) => {
+                    let body = GLOBALS
+                        .filestore
+                        .get()
+                        .unwrap()
+                        .retrieve_range(hash, start, end - start + 1)
+                        .await?;
+                    GLOBALS.metrics.blossom_download(end - start + 1);
+                    touch(hash)?;
+                    return Ok(Response::builder()
+                        .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                        .header(ACCEPT_RANGES, "bytes")
+                        .header(CONTENT_LENGTH, format!("{}", end - start + 1))
+                        .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+                        .header(CONTENT_TYPE, content_type)
+                        .header(ETAG, format!("\"{}\"", hash))
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .body(body)?);
+                }
+                Some(Err(())) => {
+                    return Ok(Response::builder()
+                        .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                        .header(CONTENT_LENGTH, "0")
+                        .header(CONTENT_RANGE, format!("bytes */{}", len))
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .body(Empty::new().map_err(|e| e.into()).boxed())?);
+                }
+                None => {}
+            }
+
             // Normal reasponse (HEAD or GET)
             let response = Response::builder()
                 .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-                .header(CONTENT_LENGTH, format!("{}", metadata.len()))
+                .header(ACCEPT_RANGES, "bytes")
+                .header(CONTENT_LENGTH, format!("{}", len))
+                .header(CONTENT_TYPE, content_type)
                 .header(ETAG, format!("\"{}\"", hash))
                 .status(StatusCode::OK);
 
             if matches!(*request.method(), Method::GET) {
                 let body = GLOBALS.filestore.get().unwrap().retrieve(hash).await?;
+                GLOBALS.metrics.blossom_download(len);
+                touch(hash)?;
                 Ok(response.body(body)?)
             } else {
                 Ok(response.body(Empty::new().map_err(|e| e.into()).boxed())?)
@@ -182,7 +284,35 @@ pub async fn handle_hash(
                 .into());
             }
 
-            GLOBALS.filestore.get().unwrap().delete(hash).await?;
+            // Only those who uploaded it may delete it (and admins, who may delete any)
+            let owner = crate::filestore::metadata::get_blob(hash)?
+                .map(|m| m.owners.contains(&auth_data.pubkey.as_hex_string()))
+                .unwrap_or(false);
+            if owner {
+                // Remove it from their list, and remove the blob itself if nobody else
+                // uploaded it
+                if crate::filestore::metadata::remove_owner(hash, auth_data.pubkey)? {
+                    let _ = GLOBALS
+                        .filestore
+                        .get()
+                        .unwrap()
+                        .delete_unreferenced(hash, None)
+                        .await?;
+                }
+            } else if crate::is_admin(auth_data.pubkey) {
+                crate::filestore::metadata::forget_blob(hash)?;
+                let _ = GLOBALS
+                    .filestore
+                    .get()
+                    .unwrap()
+                    .delete_unreferenced(hash, None)
+                    .await?;
+            } else {
+                return Err(ChorusError::BlossomAuthFailure(
+                    "You did not upload this blob".to_string(),
+                )
+                .into());
+            }
             Ok(Response::builder()
                 .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
                 .header(CONTENT_LENGTH, "0")
@@ -197,34 +327,121 @@ pub async fn handle_hash(
     }
 }
 
+/// Handle `/upload`, or if `media`, BUD-05 `/media`: the same, except that we may store
+/// an image as something better suited to serving (see `filestore::media`)
 pub async fn handle_upload(
+    peer: HashedPeer,
     request: Request<Incoming>,
+    media: bool,
 ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
     if matches!(request.method(), &Method::OPTIONS) {
         return options_response(request, "OPTIONS, HEAD, PUT");
     }
 
     let auth_data = verify_auth(&request)?;
-    if auth_data.verb != Some(AuthVerb::Upload) {
+    let authorized = match auth_data.verb {
+        Some(AuthVerb::Upload) => true,
+        Some(AuthVerb::Media) => media,
+        _ => false,
+    };
+    if !authorized {
         return Err(
             ChorusError::BlossomAuthFailure("Upload was not authorized".to_string()).into(),
         );
     }
 
     match *request.method() {
-        Method::HEAD => Ok(Response::builder()
-            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-            .header(CONTENT_LENGTH, "0")
-            .status(StatusCode::NOT_IMPLEMENTED)
-            .body(Empty::new().map_err(|e| e.into()).boxed())?),
+        // BUD-06: would this upload be accepted?
+        Method::HEAD => {
+            let header = |name: &str| -> Option<&str> {
+                request.headers().get(name).and_then(|v| v.to_str().ok())
+            };
+
+            let hash = match header("X-SHA-256") {
+                Some(hex) => HashOutput::from_hex(hex)
+                    .map_err(|_| ChorusError::BadRequest("Invalid X-SHA-256 header").into_err())?,
+                None => return Err(ChorusError::BadRequest("Missing X-SHA-256 header").into()),
+            };
+            if let Some(authorized) = auth_data.hash {
+                if HashOutput::from_bytes(authorized) != hash {
+                    return Err(ChorusError::BlossomAuthFailure(
+                        "X-SHA-256 does not match the authorized hash".to_string(),
+                    )
+                    .into());
+                }
+            }
+
+            // We have it already, so there is nothing for them to upload
+            if GLOBALS
+                .filestore
+                .get()
+                .unwrap()
+                .metadata(hash)
+                .await
+                .is_ok()
+            {
+                return Ok(Response::builder()
+                    .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                    .header(CONTENT_LENGTH, "0")
+                    .status(StatusCode::OK)
+                    .body(Empty::new().map_err(|e| e.into()).boxed())?);
+            }
+
+            let size = match header("X-Content-Length").map(|s| s.parse::<u64>()) {
+                Some(Ok(size)) => size,
+                Some(Err(_)) => {
+                    return Err(ChorusError::BadRequest("Invalid X-Content-Length header").into())
+                }
+                None => {
+                    return Err(ChorusError::BadRequest("Missing X-Content-Length header").into())
+                }
+            };
+            let max_bytes = GLOBALS.config.read().blossom_max_upload_bytes;
+            if size > max_bytes {
+                return Err(ChorusError::BlossomTooLarge(max_bytes).into());
+            }
+
+            if let Some(mime_type) = header("X-Content-Type") {
+                check_mime_type(mime_type)?;
+            }
+
+            Ok(Response::builder()
+                .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .header(CONTENT_LENGTH, "0")
+                .status(StatusCode::OK)
+                .body(Empty::new().map_err(|e| e.into()).boxed())?)
+        }
         Method::PUT => {
+            crate::rate_limit::check(Action::BlossomUpload, peer.ip(), Some(auth_data.pubkey))?;
+
             let expected_hash = auth_data.hash.map(HashOutput::from_bytes);
-            if expected_hash.is_none() {
+            let Some(expected) = expected_hash else {
                 return Err(ChorusError::BlossomAuthFailure(
                     "Put requires an expected hash value x tag in the authorization event"
                         .to_string(),
                 )
                 .into());
+            };
+
+            // (A blob we have a file for from before we kept metadata is not ours to
+            // remove if this upload is refused)
+            let existed = GLOBALS
+                .filestore
+                .get()
+                .unwrap()
+                .metadata(expected)
+                .await
+                .is_ok();
+
+            // Refuse early if they tell us it is too big
+            let max_bytes = GLOBALS.config.read().blossom_max_upload_bytes;
+            let content_length = request
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|s| s.parse::<u64>().ok());
+            if content_length.is_some_and(|len| len > max_bytes) {
+                return Err(ChorusError::BlossomTooLarge(max_bytes).into());
             }
 
             let uri = request.uri().to_owned();
@@ -236,43 +453,83 @@ pub async fn handle_upload(
                 },
                 None => None,
             };
+            if let Some(ref mime_type) = maybe_content_type {
+                check_mime_type(mime_type)?;
+            }
 
-            let (size, hash, maybe_sniffed_mime_string) = GLOBALS
+            let (size, hash, maybe_sniffed_mime_string, uploading) = GLOBALS
                 .filestore
                 .get()
                 .unwrap()
                 .store(
-                    request.into_body().map_err(|e| e.into()).boxed(),
+                    // And abandon it if it grows too big anyway
+                    Limited::new(request.into_body(), max_bytes as usize)
+                        .map_err(move |e| {
+                            if e.is::<LengthLimitError>() {
+                                ChorusError::BlossomTooLarge(max_bytes).into()
+                            } else {
+                                ChorusError::General(format!("{e}")).into()
+                            }
+                        })
+                        .boxed(),
                     expected_hash,
                 )
                 .await?;
 
-            let extension = {
-                let mut mime_string: String = "".to_owned();
-                if let Some(ms) = maybe_content_type {
-                    mime_string = ms.to_owned();
-                } else if let Some(ms) = maybe_sniffed_mime_string {
-                    mime_string = ms.to_owned();
+            let mime_type = maybe_content_type.or(maybe_sniffed_mime_string);
+
+            // They may not have declared it, in which case we check what we sniffed
+            if let Err(e) = check_mime_type(mime_type.as_deref().unwrap_or_default()) {
+                if !existed {
+                    let _ = GLOBALS
+                        .filestore
+                        .get()
+                        .unwrap()
+                        .delete_unreferenced(hash, Some(&uploading))
+                        .await?;
                 }
+                return Err(e);
+            }
 
-                mime2ext::mime2ext(&mime_string).unwrap_or("blob")
-            };
-
-            let uri = {
-                let mut parts = GLOBALS.config.read().uri_parts(uri, true)?;
-                parts.path_and_query = Some(http::uri::PathAndQuery::from_maybe_shared(format!(
-                    "/{}.{}",
-                    hash, extension
-                ))?);
-                http::Uri::from_parts(parts)?
+            let uploaded = pocket_types::Time::now().as_u64();
+            let original = hash;
+            let (hash, size, mime_type, _uploading) = if media {
+                optimize_upload(
+                    hash,
+                    size,
+                    mime_type,
+                    uploading,
+                    auth_data.pubkey,
+                    uploaded,
+                    existed,
+                )
+                .await?
+            } else {
+                (hash, size, mime_type, uploading)
             };
 
-            let blob_descriptor = BlobDescriptor {
-                url: format!("{}", uri),
-                sha256: format!("{}", hash),
+            let dim = dimensions(hash, mime_type.as_deref()).await;
+            crate::filestore::metadata::add_blob(
+                hash,
                 size,
-                uploaded: pocket_types::Time::now().as_u64(),
-            };
+                mime_type.clone(),
+                dim.clone(),
+                auth_data.pubkey,
+                uploaded,
+            )?;
+            GLOBALS.metrics.blossom_upload(size);
+
+            let mut blob_descriptor = BlobDescriptor::new(
+                uri,
+                hash,
+                size,
+                mime_type.as_deref(),
+                dim.as_deref(),
+                uploaded,
+            )?;
+            if hash != original {
+                blob_descriptor.made_from(original);
+            }
 
             let descriptor_json_string = serde_json::to_string(&blob_descriptor)?;
             let body_bytes = descriptor_json_string.into_bytes();
@@ -309,11 +566,53 @@ pub async fn handle_list(
     }
 
     match *request.method() {
-        Method::GET => Ok(Response::builder()
-            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-            .header(CONTENT_LENGTH, "0")
-            .status(StatusCode::NOT_IMPLEMENTED)
-            .body(Empty::new().map_err(|e| e.into()).boxed())?),
+        Method::GET => {
+            let pubkey = {
+                let p = request.uri().path();
+                let pubkey_hex = p.strip_prefix("/list/").unwrap_or_default();
+                Pubkey::read_hex(pubkey_hex.as_bytes())?
+            };
+
+            // BUD-02 since and until (on the uploaded time)
+            let mut since: Option<u64> = None;
+            let mut until: Option<u64> = None;
+            if let Some(query) = request.uri().query() {
+                for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+                    match &*key {
+                        "since" => since = value.parse::<u64>().ok(),
+                        "until" => until = value.parse::<u64>().ok(),
+                        _ => {}
+                    }
+                }
+            }
+
+            let mut descriptors: Vec<BlobDescriptor> = Vec::new();
+            for (hash, metadata, uploaded) in
+                crate::filestore::metadata::list_blobs(pubkey, since, until)?
+            {
+                descriptors.push(BlobDescriptor::new(
+                    request.uri().to_owned(),
+                    hash,
+                    metadata.size,
+                    metadata.mime_type.as_deref(),
+                    metadata.dim.as_deref(),
+                    uploaded,
+                )?);
+            }
+
+            let body_bytes = serde_json::to_vec(&descriptors)?;
+            let len = body_bytes.len();
+            let body = Full::new(Bytes::from(body_bytes))
+                .map_err(|e| e.into())
+                .boxed();
+
+            Ok(Response::builder()
+                .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .header(CONTENT_LENGTH, format!("{}", len))
+                .header(CONTENT_TYPE, "application/json")
+                .status(StatusCode::OK)
+                .body(body)?)
+        }
         _ => Ok(Response::builder()
             .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
             .header(CONTENT_LENGTH, "0")
@@ -323,6 +622,7 @@ pub async fn handle_list(
 }
 
 pub async fn handle_mirror(
+    peer: HashedPeer,
     request: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
     if matches!(request.method(), &Method::OPTIONS) {
@@ -337,11 +637,99 @@ pub async fn handle_mirror(
     }
 
     match *request.method() {
-        Method::PUT => Ok(Response::builder()
-            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-            .header(CONTENT_LENGTH, "0")
-            .status(StatusCode::NOT_IMPLEMENTED)
-            .body(Empty::new().map_err(|e| e.into()).boxed())?),
+        Method::PUT => {
+            crate::rate_limit::check(Action::BlossomUpload, peer.ip(), Some(auth_data.pubkey))?;
+
+            // BUD-04 requires the hash be authorized, we verify the download against it
+            let expected_hash = match auth_data.hash {
+                Some(h) => HashOutput::from_bytes(h),
+                None => {
+                    return Err(ChorusError::BlossomAuthFailure(
+                        "Mirror requires an x tag".to_string(),
+                    )
+                    .into())
+                }
+            };
+
+            let uri = request.uri().to_owned();
+
+            #[derive(Deserialize)]
+            struct MirrorRequest {
+                url: String,
+            }
+            let body = Limited::new(request.into_body(), 65536)
+                .collect()
+                .await
+                .map_err(|e| ChorusError::BlossomMirrorUrl(format!("{e}")).into_err())?
+                .to_bytes();
+            let mirror_request: MirrorRequest = serde_json::from_slice(&body).map_err(|_| {
+                ChorusError::BlossomMirrorUrl("Body must be JSON with a url".to_owned()).into_err()
+            })?;
+
+            let (max_bytes, timeout) = {
+                let config = GLOBALS.config.read();
+                (
+                    config.blossom_mirror_max_bytes,
+                    config.blossom_mirror_timeout_seconds,
+                )
+            };
+
+            let (size, hash, maybe_sniffed_mime_string, maybe_content_type, _uploading) =
+                tokio::time::timeout(Duration::from_secs(timeout), async {
+                    let (body, maybe_content_type) =
+                        mirror::fetch(&mirror_request.url, max_bytes).await?;
+                    let (size, hash, maybe_sniffed_mime_string, uploading) = GLOBALS
+                        .filestore
+                        .get()
+                        .unwrap()
+                        .store(body, Some(expected_hash))
+                        .await?;
+                    Ok::<_, Error>((
+                        size,
+                        hash,
+                        maybe_sniffed_mime_string,
+                        maybe_content_type,
+                        uploading,
+                    ))
+                })
+                .await
+                .map_err(|_| Into::<Error>::into(ChorusError::TimedOut))??;
+
+            let mime_type = maybe_content_type.or(maybe_sniffed_mime_string);
+            let dim = dimensions(hash, mime_type.as_deref()).await;
+            let uploaded = pocket_types::Time::now().as_u64();
+            crate::filestore::metadata::add_blob(
+                hash,
+                size,
+                mime_type.clone(),
+                dim.clone(),
+                auth_data.pubkey,
+                uploaded,
+            )?;
+            GLOBALS.metrics.blossom_upload(size);
+
+            let blob_descriptor = BlobDescriptor::new(
+                uri,
+                hash,
+                size,
+                mime_type.as_deref(),
+                dim.as_deref(),
+                uploaded,
+            )?;
+
+            let body_bytes = serde_json::to_vec(&blob_descriptor)?;
+            let len = body_bytes.len();
+            let body = Full::new(Bytes::from(body_bytes))
+                .map_err(|e| e.into())
+                .boxed();
+
+            Ok(Response::builder()
+                .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .header(CONTENT_LENGTH, format!("{}", len))
+                .header(CONTENT_TYPE, "application/json")
+                .status(StatusCode::OK)
+                .body(body)?)
+        }
         _ => Ok(Response::builder()
             .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
             .header(CONTENT_LENGTH, "0")
@@ -350,11 +738,231 @@ pub async fn handle_mirror(
     }
 }
 
+// Re-encode an image uploaded to /media, returning the blob to describe to them: the
+// re-encoded one, or the upload itself if it is not an image worth re-encoding. The
+// upload is kept as a blob of its own if blossom_media_keep_original (or it already was
+// one, or another upload of it has made it one since), and otherwise removed. The blob
+// returned stays marked as being stored (see `filestore::Uploading`) until its metadata
+// is recorded.
+async fn optimize_upload(
+    hash: HashOutput,
+    size: u64,
+    mime_type: Option<String>,
+    uploading: Uploading,
+    owner: Pubkey,
+    uploaded: u64,
+    existed: bool,
+) -> Result<(HashOutput, u64, Option<String>, Uploading), Error> {
+    if !mime_type.as_deref().is_some_and(media::is_optimizable) {
+        return Ok((hash, size, mime_type, uploading));
+    }
+
+    let filestore = GLOBALS.filestore.get().unwrap();
+    let Some(optimized) = media::optimize(filestore, hash).await? else {
+        return Ok((hash, size, mime_type, uploading));
+    };
+    let body = Full::new(Bytes::from(optimized.bytes))
+        .map_err(|e| e.into())
+        .boxed();
+    let (optimized_size, optimized_hash, _, optimized_uploading) =
+        filestore.store(body, None).await?;
+
+    if GLOBALS.config.read().blossom_media_keep_original {
+        let dim = dimensions(hash, mime_type.as_deref()).await;
+        crate::filestore::metadata::add_blob(hash, size, mime_type, dim, owner, uploaded)?;
+    } else if optimized_hash != hash && !existed {
+        let _ = filestore
+            .delete_unreferenced(hash, Some(&uploading))
+            .await?;
+    }
+
+    Ok((
+        optimized_hash,
+        optimized_size,
+        Some(optimized.mime_type.to_owned()),
+        optimized_uploading,
+    ))
+}
+
+// Parse a Range header value for a blob of `len` bytes into an inclusive (start, end).
+//
+// Only a single byte range is supported; anything else is None (and the Range header is
+// ignored, as RFC 9110 permits). Err means the range is not satisfiable.
+// Note that a blob was just downloaded (for garbage collection), unless we are leaving
+// the store alone (see `mode::background_writes`)
+fn touch(hash: HashOutput) -> Result<(), Error> {
+    if !crate::mode::background_writes() {
+        return Ok(());
+    }
+    crate::filestore::metadata::touch(hash, Time::now().as_u64())
+}
+
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // Suffix: the last N bytes
+        let suffix = last.parse::<u64>().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((len.saturating_sub(suffix), len - 1)));
+    }
+
+    let start = first.parse::<u64>().ok()?;
+    let end = if last.is_empty() {
+        u64::MAX
+    } else {
+        last.parse::<u64>().ok()?
+    };
+    if end < start {
+        return None;
+    }
+    if start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.min(len - 1))))
+}
+
+// The dimensions ("WxH") of an uploaded image. Anything we cannot tell them for, image or
+// not, simply has none.
+async fn dimensions(hash: HashOutput, mime_type: Option<&str>) -> Option<String> {
+    if !mime_type.is_some_and(|m| m.trim().to_lowercase().starts_with("image/")) {
+        return None;
+    }
+    match GLOBALS.filestore.get().unwrap().dimensions(hash).await {
+        Ok(dim) => dim.map(|(width, height)| format!("{width}x{height}")),
+        Err(e) => {
+            log::debug!(target: "Server", "Could not read the dimensions of {hash}: {e}");
+            None
+        }
+    }
+}
+
+// Check a MIME type against blossom_allowed_mime_types
+fn check_mime_type(mime_type: &str) -> Result<(), Error> {
+    let allowed = &GLOBALS.config.read().blossom_allowed_mime_types;
+    if allowed.is_empty() {
+        return Ok(());
+    }
+
+    // Ignore parameters like "; charset=utf-8"
+    let essence = mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    let ok = allowed.iter().any(|a| {
+        let a = a.to_lowercase();
+        match a.strip_suffix("/*") {
+            Some(prefix) => essence.split('/').next() == Some(prefix),
+            None => a == essence,
+        }
+    });
+
+    if ok {
+        Ok(())
+    } else {
+        Err(ChorusError::BlossomMimeType(essence).into())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlobDescriptor {
     pub url: String,
     pub sha256: String,
     pub size: u64,
-    // type: String
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
     pub uploaded: u64,
+
+    /// BUD-08: NIP-94 tags describing the blob, for clients to put in a file metadata
+    /// event
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nip94: Vec<Vec<String>>,
+}
+
+impl BlobDescriptor {
+    /// Describe a blob. `uri` is that of the request, of which only the scheme and
+    /// authority are kept.
+    pub fn new(
+        uri: http::Uri,
+        hash: HashOutput,
+        size: u64,
+        mime_type: Option<&str>,
+        dim: Option<&str>,
+        uploaded: u64,
+    ) -> Result<BlobDescriptor, Error> {
+        let extension = mime2ext::mime2ext(mime_type.unwrap_or_default()).unwrap_or("blob");
+
+        let url = {
+            let mut parts = GLOBALS.config.read().uri_parts(uri, true)?;
+            parts.path_and_query = Some(http::uri::PathAndQuery::from_maybe_shared(format!(
+                "/{}.{}",
+                hash, extension
+            ))?);
+            http::Uri::from_parts(parts)?
+        };
+
+        let url = format!("{}", url);
+        let sha256 = format!("{}", hash);
+
+        let mut nip94 = vec![
+            vec!["url".to_owned(), url.clone()],
+            vec!["x".to_owned(), sha256.clone()],
+            vec!["ox".to_owned(), sha256.clone()],
+            vec!["size".to_owned(), format!("{}", size)],
+        ];
+        if let Some(mime_type) = mime_type {
+            nip94.push(vec!["m".to_owned(), mime_type.to_owned()]);
+        }
+        if let Some(dim) = dim {
+            nip94.push(vec!["dim".to_owned(), dim.to_owned()]);
+        }
+
+        Ok(BlobDescriptor {
+            url,
+            sha256,
+            size,
+            mime_type: mime_type.map(|s| s.to_owned()),
+            uploaded,
+            nip94,
+        })
+    }
+
+    /// Note that the blob was made from another (BUD-05), in the NIP-94 `ox` tag
+    pub fn made_from(&mut self, original: HashOutput) {
+        for tag in self.nip94.iter_mut() {
+            if tag.first().map(|name| name.as_str()) == Some("ox") {
+                tag[1] = format!("{}", original);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(Ok((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=999-999", 1000), Some(Ok((999, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=9-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
 }
@@ -0,0 +1,176 @@
+//! Health check and stats endpoints
+//!
+//! `/health` is for load balancers: 200 only if the store can be read (and the filestore,
+//! if Blossom is on, written to). Anybody may ask, so it never writes to the store, and
+//! what it finds is reused for `HEALTH_CACHE`. `/stats` is a JSON summary for operators,
+//! behind `stats_bearer_token`. Its live figures are ones we keep anyway; the store and
+//! blob figures are cached for `STATS_CACHE` so that frequent polling stays off LMDB.
+
+use crate::error::Error;
+use crate::globals::GLOBALS;
+use crate::ip::HashedPeer;
+use http::Uri;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response, StatusCode};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// How long a health check's outcome is reused for
+const HEALTH_CACHE: Duration = Duration::from_secs(5);
+
+// The last health check's outcome, and when it was made
+static HEALTH: Mutex<Option<(Instant, Result<(), String>)>> = Mutex::new(None);
+
+// How long store and blob figures are reused for
+const STATS_CACHE: Duration = Duration::from_secs(60);
+
+// The store and blob figures, and when we gathered them
+static STORE_STATS: Mutex<Option<(Instant, Value)>> = Mutex::new(None);
+
+/// Serve `/health`
+pub async fn serve_health(peer: HashedPeer) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let (status, body) = match check().await {
+        Ok(()) => (StatusCode::OK, json!({ "status": "ok" })),
+        Err(e) => {
+            log::warn!(target: "Server", "{}: Health check failed: {e}", peer);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({ "status": "error", "error": format!("{e}") }),
+            )
+        }
+    };
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .status(status)
+        .body(
+            Full::new(body.to_string().into())
+                .map_err(|e| e.into())
+                .boxed(),
+        )?)
+}
+
+// Are we healthy? (As of at most `HEALTH_CACHE` ago.)
+async fn check() -> Result<(), String> {
+    let cached = HEALTH.lock().clone();
+    if let Some((at, outcome)) = cached {
+        if at.elapsed() < HEALTH_CACHE {
+            return outcome;
+        }
+    }
+    let outcome = probe().await.map_err(|e| format!("{e}"));
+    *HEALTH.lock() = Some((Instant::now(), outcome.clone()));
+    outcome
+}
+
+// Can we read the store, and write to the filestore? (Not tried while we leave things
+// alone, see `mode::background_writes`.)
+async fn probe() -> Result<(), Error> {
+    {
+        let _reading = crate::map_size::reading();
+        let _txn = GLOBALS.store.get().unwrap().read_txn()?;
+    }
+    if crate::mode::background_writes() {
+        if let Some(filestore) = GLOBALS.filestore.get() {
+            filestore.check_writable().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Serve `/stats` (if enabled, and to those with the token)
+pub async fn serve_stats(
+    peer: HashedPeer,
+    request: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let allowed = {
+        let config = GLOBALS.config.read();
+        let Some(token) = &config.stats_bearer_token else {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Empty::new().map_err(|e| e.into()).boxed())?);
+        };
+        super::bearer_token_matches(request.headers(), token)
+    };
+    if !allowed {
+        log::info!(target: "Client", "{}: Refused /stats", peer);
+        super::auth_failed(peer, request.headers());
+        return Ok(Response::builder()
+            .header("WWW-Authenticate", "Bearer")
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
+    }
+
+    let store = store_stats()?;
+    let body = {
+        let config = GLOBALS.config.read();
+        let url = config
+            .uri_parts(Uri::from_static("wss://authority-will-be-replaced/"), false)
+            .ok()
+            .and_then(|parts| Uri::from_parts(parts).ok())
+            .map(|uri| uri.to_string());
+        json!({
+            "relay": {
+                "url": url,
+                "name": config.name,
+                "pubkey": config.contact_public_key.map(|pk| pk.as_hex_string()),
+                "software": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+                "mode": config.mode.to_string(),
+            },
+            "uptime_seconds": GLOBALS.start_time.elapsed().as_secs(),
+            "connections": GLOBALS.num_connections.load(Ordering::Relaxed),
+            "subscriptions": GLOBALS.metrics.subscriptions.load(Ordering::Relaxed),
+            "store": store["store"],
+            "blobs": store["blobs"],
+            "forwarding": crate::forward::stats(),
+        })
+    };
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .status(StatusCode::OK)
+        .body(
+            Full::new(body.to_string().into())
+                .map_err(|e| e.into())
+                .boxed(),
+        )?)
+}
+
+// The store and blob figures, from the cache if it is fresh enough
+fn store_stats() -> Result<Value, Error> {
+    if let Some((at, stats)) = &*STORE_STATS.lock() {
+        if at.elapsed() < STATS_CACHE {
+            return Ok(stats.clone());
+        }
+    }
+
+    let store = {
+        let _reading = crate::map_size::reading();
+        GLOBALS.store.get().unwrap().stats()?
+    };
+    let (blobs, blob_bytes) = if GLOBALS.filestore.get().is_some() {
+        let all = crate::filestore::metadata::all_blobs()?;
+        (all.len(), all.iter().map(|(_, m)| m.size).sum::<u64>())
+    } else {
+        (0, 0)
+    };
+    let stats = json!({
+        "store": {
+            "events": store.index_stats.i_index_entries,
+            "event_bytes": store.event_bytes,
+            "index_disk_bytes": store.index_stats.disk_usage,
+        },
+        "blobs": {
+            "count": blobs,
+            "bytes": blob_bytes,
+        },
+    });
+    *STORE_STATS.lock() = Some((Instant::now(), stats.clone()));
+    Ok(stats)
+}
@@ -1,6 +1,7 @@
 use crate::error::{ChorusError, Error};
+use crate::filestore::HashOutput;
 use crate::globals::GLOBALS;
-use crate::ip::HashedPeer;
+use crate::ip::{HashedIp, HashedPeer, IpData};
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Full};
 use hyper::body::{Bytes, Incoming};
@@ -32,6 +33,14 @@ struct PubkeyResult {
     reason: Option<String>,
 }
 
+#[derive(Serialize)]
+struct IpBanResult {
+    ip: String,
+    until: u64,
+    bans: u8,
+    score: f32,
+}
+
 fn respond(
     json: serde_json::Value,
     status: StatusCode,
@@ -52,12 +61,14 @@ fn respond(
 }
 
 pub async fn handle(
-    _peer: HashedPeer,
+    peer: HashedPeer,
     request: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let headers = request.headers().clone();
     let (pubkey, command) = match auth::check_auth(request).await {
         Ok((pk, v)) => (pk, v),
         Err(e) => {
+            super::auth_failed(peer, &headers);
             let result = json!({
                 "result": {},
                 "error": format!("{}", e)
@@ -66,43 +77,83 @@ pub async fn handle(
         }
     };
 
-    match handle_inner(pubkey, command) {
-        Ok(Some(value)) => respond(value, StatusCode::OK),
-        Ok(None) => {
-            let result = json!({
+    // The store belongs to the new process while we hand over
+    if crate::handover::is_handing_over() {
+        let result = json!({
+            "result": {},
+            "error": "relay is restarting"
+        });
+        return respond(result, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let (result, status) = response(handle_inner(pubkey, command));
+    respond(result, status)
+}
+
+/// The JSON-RPC response to the outcome of a command, and the HTTP status it goes with
+pub fn response(outcome: Result<Option<Value>, Error>) -> (Value, StatusCode) {
+    match outcome {
+        Ok(Some(value)) => (value, StatusCode::OK),
+        Ok(None) => (
+            json!({
                 "result": {},
-            });
-            respond(result, StatusCode::OK)
-        }
-        Err(e) => {
-            let (result, status) = match e.inner {
-                ChorusError::BadRequest(s) => (
-                    json!({
-                        "result": {},
-                        "error": format!("{}", s)
-                    }),
-                    StatusCode::BAD_REQUEST,
-                ),
-                ChorusError::NotImplemented => (
-                    json!({
-                        "result": {},
-                        "error": "not_implemented"
-                    }),
-                    StatusCode::NOT_IMPLEMENTED,
-                ),
-                _ => (
-                    json!({
-                        "result": {},
-                        "error": format!("{}", e)
-                    }),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ),
-            };
-            respond(result, status)
-        }
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => match e.inner {
+            ChorusError::BadRequest(s) => (
+                json!({
+                    "result": {},
+                    "error": format!("{}", s)
+                }),
+                StatusCode::BAD_REQUEST,
+            ),
+            ChorusError::NotImplemented => (
+                json!({
+                    "result": {},
+                    "error": "not_implemented"
+                }),
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            ChorusError::ReadOnly => (
+                json!({
+                    "result": {},
+                    "error": format!("{}", e)
+                }),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            _ => (
+                json!({
+                    "result": {},
+                    "error": format!("{}", e)
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        },
     }
 }
 
+// The methods that change the store, refused in read-only and maintenance modes
+const WRITE_METHODS: &[&str] = &[
+    "allowevent",
+    "banevent",
+    "clearevent",
+    "removeevent",
+    "allowpubkey",
+    "banpubkey",
+    "clearpubkey",
+    "clearipreputation",
+    "pardonip",
+    "grantmoderator",
+    "revokemoderator",
+    "grantuser",
+    "revokeuser",
+    "grantrole",
+    "revokerole",
+    "pinblob",
+    "unpinblob",
+];
+
 pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Error> {
     let obj = match command.as_object() {
         Some(o) => o,
@@ -117,6 +168,10 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
         None => return Err(ChorusError::BadRequest("Method missing").into()),
     };
 
+    if WRITE_METHODS.contains(&method.as_str()) {
+        crate::mode::check_write()?;
+    }
+
     match &*method {
         "supportedmethods" => Ok(Some(json!({
             "result": [
@@ -133,6 +188,10 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                 "banpubkey",
                 "clearpubkey",
 
+                "clearipreputation",
+                "pardonip",
+                "listipbans",
+
                 "listallowedevents",
                 "listbannedevents",
                 "fetchbannedevents",
@@ -142,6 +201,10 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                 "stats",
                 "numconnections",
                 "uptime",
+                "deliverylag",
+
+                "listrejectedevents",
+                "clearrejectedevents",
 
                 "listadmins",
                 "listmoderators",
@@ -153,6 +216,13 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                 "listrole",
                 "grantrole",
                 "revokerole",
+
+                "pinblob",
+                "unpinblob",
+                "listpinnedblobs",
+
+                "getmode",
+                "setmode",
             ]
         }))),
         "listeventsneedingmoderation" => {
@@ -186,6 +256,7 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
 
             let mut need_moderation: Vec<EventResult> = Vec::new();
 
+            let _reading = crate::map_size::reading();
             let (mut events, _redacted) = GLOBALS
                 .store
                 .get()
@@ -230,7 +301,7 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
         }
         "removeevent" => {
             let id = get_id_param(obj)?;
-            GLOBALS.store.get().unwrap().remove_event(id)?;
+            crate::remove_event(id)?;
             Ok(None)
         }
 
@@ -250,6 +321,36 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
             Ok(None)
         }
 
+        "clearipreputation" => {
+            let ip = get_hashed_ip_param(obj)?;
+            crate::update_ip_data(ip, &IpData::default())?;
+            Ok(None)
+        }
+        "pardonip" => {
+            let ip = get_hashed_ip_param(obj)?;
+            let mut ip_data = crate::get_ip_data(ip)?;
+            ip_data.pardon();
+            crate::update_ip_data(ip, &ip_data)?;
+            Ok(None)
+        }
+        "listipbans" => {
+            let now = pocket_types::Time::now().as_u64();
+            let half_life = GLOBALS.config.read().ip_reputation_half_life_seconds;
+            let bans: Vec<IpBanResult> = crate::dump_ip_data()?
+                .iter()
+                .filter(|(_, data)| data.is_banned())
+                .map(|(ip, data)| IpBanResult {
+                    ip: format!("{ip}"),
+                    until: data.ban_until,
+                    bans: data.reputation.bans_at(now),
+                    score: data.reputation.score_at(now, half_life),
+                })
+                .collect();
+            Ok(Some(json!({
+                "result": bans
+            })))
+        }
+
         "listallowedevents" => {
             let approvals = crate::dump_event_approvals()?;
             let ids: Vec<EventResult> = approvals
@@ -291,6 +392,7 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
         "fetchbannedevents" => {
             let approvals = crate::dump_event_approvals()?;
             let mut results: Vec<FullEventResult> = Vec::new();
+            let _reading = crate::map_size::reading();
             for (id, appr) in approvals.iter() {
                 if !*appr {
                     if let Some(event) = GLOBALS.store.get().unwrap().get_event_by_id(*id)? {
@@ -345,7 +447,10 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
         }
 
         "stats" => {
+            let _reading = crate::map_size::reading();
             let store_stats = GLOBALS.store.get().unwrap().stats()?;
+            let lag = crate::lag::aggregate();
+            let event_sink = crate::sink::stats()?;
             Ok(Some(json!({
                 "result": {
                     "uptime": GLOBALS.start_time.elapsed().as_secs(),
@@ -356,6 +461,10 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                     "num_events": store_stats.index_stats.i_index_entries,
                     "index_disk_usage": store_stats.index_stats.disk_usage,
                     "index_memory_usage": store_stats.index_stats.memory_usage,
+                    "delivery_lag_p50_ms": lag.flushed_p50_ms,
+                    "delivery_lag_p99_ms": lag.flushed_p99_ms,
+                    "event_sink": event_sink,
+                    "forwarding": crate::forward::stats(),
                 }
             })))
         }
@@ -371,6 +480,31 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                 "result": uptime_in_secs,
             })))
         }
+        "deliverylag" => {
+            let connections: Map<String, Value> = GLOBALS
+                .delivery_lag
+                .iter()
+                .map(|entry| (format!("{}", entry.key()), json!(entry.value().gauges)))
+                .collect();
+            Ok(Some(json!({
+                "result": {
+                    "aggregate": crate::lag::aggregate(),
+                    "connections": connections,
+                }
+            })))
+        }
+
+        "listrejectedevents" => {
+            let filter = get_optional_string_param(obj)?;
+            let rejected = crate::rejected::list(filter.as_deref());
+            Ok(Some(json!({
+                "result": rejected
+            })))
+        }
+        "clearrejectedevents" => {
+            crate::rejected::clear();
+            Ok(None)
+        }
 
         "listadmins" => {
             let keys = GLOBALS.config.read().admin_hex_keys.clone();
@@ -569,6 +703,61 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
             }
         }
 
+        "pinblob" => {
+            if !crate::is_admin(pubkey) {
+                Ok(Some(json!({
+                    "result": {},
+                    "error": "Unauthorized: Only admins can pin blobs"
+                })))
+            } else {
+                let hash = get_hash_param(obj)?;
+                crate::filestore::metadata::pin(hash)?;
+                Ok(None)
+            }
+        }
+        "unpinblob" => {
+            if !crate::is_admin(pubkey) {
+                Ok(Some(json!({
+                    "result": {},
+                    "error": "Unauthorized: Only admins can unpin blobs"
+                })))
+            } else {
+                let hash = get_hash_param(obj)?;
+                crate::filestore::metadata::unpin(hash)?;
+                Ok(None)
+            }
+        }
+        "listpinnedblobs" => {
+            let pins: Vec<String> = crate::filestore::metadata::list_pins()?
+                .iter()
+                .map(|hash| format!("{hash}"))
+                .collect();
+            Ok(Some(json!({
+                "result": pins
+            })))
+        }
+
+        "getmode" => Ok(Some(json!({
+            "result": crate::mode::current().to_string(),
+        }))),
+        "setmode" => {
+            if !crate::is_admin(pubkey) {
+                Ok(Some(json!({
+                    "result": {},
+                    "error": "Unauthorized: Only admins can set the mode"
+                })))
+            } else {
+                let mode = get_string_param(obj)?
+                    .parse::<crate::mode::Mode>()
+                    .map_err(|_| {
+                        ChorusError::BadRequest("Mode must be normal, read-only or maintenance")
+                            .into_err()
+                    })?;
+                crate::mode::set(mode);
+                Ok(None)
+            }
+        }
+
         _ => Err(ChorusError::NotImplemented.into()),
     }
 }
@@ -601,6 +790,21 @@ fn get_id_param(obj: &Map<String, Value>) -> Result<Id, Error> {
         .map_err(|_| ChorusError::BadRequest("ID could not be parsed").into_err())
 }
 
+// The hashed IP, as it appears in our logs
+fn get_hashed_ip_param(obj: &Map<String, Value>) -> Result<HashedIp, Error> {
+    let ip = get_string_param(obj)?;
+    if ip.len() != 20 {
+        return Err(ChorusError::BadRequest("Hashed IP could not be parsed").into());
+    }
+    Ok(HashedIp::from_bytes(ip.as_bytes()))
+}
+
+fn get_hash_param(obj: &Map<String, Value>) -> Result<HashOutput, Error> {
+    let hash_text = get_string_param(obj)?;
+    HashOutput::from_hex(&hash_text)
+        .map_err(|_| ChorusError::BadRequest("Hash could not be parsed").into_err())
+}
+
 fn get_string_param(obj: &Map<String, Value>) -> Result<String, Error> {
     Ok(obj
         .get("params")
@@ -613,3 +817,18 @@ fn get_string_param(obj: &Map<String, Value>) -> Result<String, Error> {
         .ok_or(ChorusError::BadRequest("Parameter is not a string as expected").into_err())?
         .to_owned())
 }
+
+fn get_optional_string_param(obj: &Map<String, Value>) -> Result<Option<String>, Error> {
+    let param = match obj.get("params").and_then(|p| p.as_array()) {
+        Some(params) => params.first(),
+        None => None,
+    };
+    match param {
+        None => Ok(None),
+        Some(v) => Ok(Some(
+            v.as_str()
+                .ok_or(ChorusError::BadRequest("Parameter is not a string as expected").into_err())?
+                .to_owned(),
+        )),
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::Error;
+use crate::globals::GLOBALS;
+use crate::ip::HashedPeer;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response, StatusCode};
+use std::sync::atomic::Ordering;
+
+/// Serve Prometheus metrics (if enabled, and if they are allowed to see them)
+pub async fn serve_metrics(
+    peer: HashedPeer,
+    request: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let (enabled, allowed) = {
+        let config = GLOBALS.config.read();
+        let guarded =
+            config.metrics_bearer_token.is_some() || !config.metrics_allowed_ips.is_empty();
+        let token_ok = config
+            .metrics_bearer_token
+            .as_ref()
+            .is_some_and(|token| super::bearer_token_matches(request.headers(), token));
+        let ip_ok = config.metrics_allowed_ips.contains(&peer.ip());
+        (config.enable_metrics, !guarded || token_ok || ip_ok)
+    };
+
+    if !enabled {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
+    }
+    if !allowed {
+        log::info!(target: "Client", "{}: Refused /metrics", peer);
+        super::auth_failed(peer, request.headers());
+        return Ok(Response::builder()
+            .header("WWW-Authenticate", "Bearer")
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
+    }
+
+    let mut out = String::new();
+    GLOBALS.metrics.render(&mut out);
+    render_globals(&mut out)?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .status(StatusCode::OK)
+        .body(Full::new(out.into()).map_err(|e| e.into()).boxed())?)
+}
+
+// Gauges we already keep elsewhere
+fn render_globals(out: &mut String) -> Result<(), Error> {
+    use crate::metrics::header;
+    use std::fmt::Write;
+
+    let stats = {
+        let _reading = crate::map_size::reading();
+        GLOBALS.store.get().unwrap().stats()?
+    };
+    let lag = crate::lag::aggregate();
+
+    for (name, kind, help, value) in [
+        (
+            "chorus_uptime_seconds",
+            "counter",
+            "Seconds since startup",
+            GLOBALS.start_time.elapsed().as_secs() as f64,
+        ),
+        (
+            "chorus_websocket_connections",
+            "gauge",
+            "Open websocket connections",
+            GLOBALS.num_connections.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "chorus_http_requests_in_flight",
+            "gauge",
+            "HTTP requests being handled",
+            GLOBALS.num_http_requests.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "chorus_received_bytes_total",
+            "counter",
+            "Bytes received from clients",
+            GLOBALS.bytes_inbound.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "chorus_sent_bytes_total",
+            "counter",
+            "Bytes sent to clients",
+            GLOBALS.bytes_outbound.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "chorus_store_events",
+            "gauge",
+            "Events in the store",
+            stats.index_stats.i_index_entries as f64,
+        ),
+        (
+            "chorus_store_event_bytes",
+            "gauge",
+            "Bytes of event data in the store",
+            stats.event_bytes as f64,
+        ),
+        (
+            "chorus_store_index_disk_bytes",
+            "gauge",
+            "Bytes of LMDB index on disk",
+            stats.index_stats.disk_usage as f64,
+        ),
+        (
+            "chorus_store_index_memory_bytes",
+            "gauge",
+            "Bytes of LMDB index resident in memory",
+            stats.index_stats.memory_usage as f64,
+        ),
+        (
+            "chorus_delivery_lag_p50_ms",
+            "gauge",
+            "Median live event delivery lag",
+            lag.flushed_p50_ms,
+        ),
+        (
+            "chorus_delivery_lag_p99_ms",
+            "gauge",
+            "99th percentile live event delivery lag",
+            lag.flushed_p99_ms,
+        ),
+    ] {
+        header(out, name, kind, help);
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    Ok(())
+}
@@ -1,15 +1,21 @@
 mod blossom;
+pub mod health;
 mod management;
-mod nip11;
+pub mod metrics;
+pub mod nip11;
+pub mod router;
 
-use crate::error::{ChorusError, Error};
+use crate::error::Error;
 use crate::globals::GLOBALS;
 use crate::ip::HashedPeer;
-use http::Method;
+use bitcoin_hashes::{sha256, Hash};
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, Method};
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::{Bytes, Incoming};
 use hyper::{Request, Response, StatusCode};
+use router::Route;
 
 pub async fn serve_http(
     peer: HashedPeer,
@@ -51,9 +57,26 @@ pub async fn serve_http(
         }
     }
 
+    // Nothing else is served in maintenance mode
+    if crate::mode::current() == crate::mode::Mode::Maintenance {
+        let response = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Content-Type", "text/plain")
+            .header("Retry-After", crate::mode::RETRY_AFTER_SECONDS)
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(
+                Full::new("This relay is down for maintenance. Please try again later.".into())
+                    .map_err(|e| e.into())
+                    .boxed(),
+            )?;
+        return Ok(response);
+    }
+
     let uri = request.uri().to_owned();
 
-    if p == "/privacy-policy" {
+    let route = router::classify(p);
+
+    if route == Route::PrivacyPolicy {
         let config = &*GLOBALS.config.read();
         if let Some(pp) = &config.privacy_policy {
             let response = Response::builder()
@@ -68,7 +91,7 @@ pub async fn serve_http(
         }
     }
 
-    if p == "/terms-of-service" {
+    if route == Route::TermsOfService {
         let config = &*GLOBALS.config.read();
         if let Some(tos) = &config.terms_of_service {
             let response = Response::builder()
@@ -83,16 +106,25 @@ pub async fn serve_http(
         }
     }
 
+    if route == Route::Icon {
+        return nip11::serve_image(peer, "icon").await;
+    }
+    if route == Route::Banner {
+        return nip11::serve_image(peer, "banner").await;
+    }
+
     // Try blossom if enabled
-    if GLOBALS.config.read().blossom_directory.is_some() {
-        match blossom::handle(request).await {
-            Ok(response) => return Ok(response),
-            Err(e) => {
-                if !matches!(e.inner, ChorusError::SignalNotBlossom) {
-                    return Err(e);
-                }
-            }
-        }
+    if route.is_blossom() && GLOBALS.config.read().blossom_directory.is_some() {
+        return blossom::handle(peer, route, request).await;
+    }
+
+    // Reserved paths without a handler do not fall through to the generic response
+    if route == Route::Reserved {
+        log::debug!(target: "Client", "{}: HTTP request for reserved path {}", peer, uri);
+        return Ok(Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::NOT_FOUND)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
     }
 
     log::debug!(target: "Client", "{}: HTTP request for {}", peer, uri);
@@ -131,3 +163,53 @@ pub async fn serve_http(
 
     Ok(response)
 }
+
+// Count a failed authorization (Blossom, NIP-98 or a bearer token) against the IP, as a
+// failed AUTH is on a websocket. Asking without presenting any is not a failure.
+fn auth_failed(peer: HashedPeer, headers: &HeaderMap) {
+    if !headers.contains_key(AUTHORIZATION) || !GLOBALS.config.read().enable_ip_blocking {
+        return;
+    }
+    if let Err(e) = crate::add_ip_violation(peer.ip(), crate::ip::Violation::AuthFailure, 0) {
+        log::error!(target: "Client", "{}: {}", peer, e);
+    }
+}
+
+/// Whether the headers carry `Authorization: Bearer <token>`. Both are hashed and the
+/// hashes compared in full, so how long this takes says nothing about the token.
+pub fn bearer_token_matches(headers: &HeaderMap, token: &str) -> bool {
+    let Some(given) = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    let given = sha256::Hash::hash(given.trim().as_bytes());
+    let token = sha256::Hash::hash(token.as_bytes());
+    bitcoin_hashes::cmp::fixed_time_eq(given.as_byte_array(), token.as_byte_array())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bearer_token_matches() {
+        let mut headers = HeaderMap::new();
+        assert!(!bearer_token_matches(&headers, "secret"));
+
+        for (value, matches) in [
+            ("Bearer secret", true),
+            ("Bearer  secret ", true),
+            ("Bearer secreT", false),
+            ("Bearer secret2", false),
+            ("Bearer ", false),
+            ("Basic secret", false),
+            ("secret", false),
+        ] {
+            let _ = headers.insert(AUTHORIZATION, value.parse().unwrap());
+            assert_eq!(bearer_token_matches(&headers, "secret"), matches, "{value}");
+        }
+    }
+}
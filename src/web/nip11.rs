@@ -3,16 +3,67 @@ use crate::error::Error;
 use crate::globals::GLOBALS;
 use crate::ip::HashedPeer;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::Bytes;
 use hyper::http::uri::Uri;
 use hyper::{Response, StatusCode};
+use pocket_types::Time;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// How often the relay information document is rebuilt
+const RID_REFRESH: Duration = Duration::from_secs(5);
+
+// How long clients may cache the icon and banner
+const IMAGE_MAX_AGE: u64 = 3600;
+
+// Image files we look for in the data directory, and their content types
+const IMAGE_TYPES: [(&str, &str); 5] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("gif", "image/gif"),
+];
+
+/// Fees, in the form of (and published as) the NIP-11 `fees` field
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Fees {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub admission: Vec<Fee>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subscription: Vec<Fee>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub publication: Vec<Fee>,
+}
+
+/// One fee
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fee {
+    pub amount: u64,
+
+    pub unit: String,
+
+    /// Seconds the fee pays for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+
+    /// The kinds of events the fee is for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<u16>,
+}
 
 pub async fn serve_nip11(peer: HashedPeer) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
     log::debug!(target: "Client", "{}: sent NIP-11", peer);
-    let rid = {
-        let config = &*GLOBALS.config.read();
-        GLOBALS.rid.get_or_init(|| build_rid(config))
+    let rid = match &*GLOBALS.rid.read() {
+        Some(rid) => rid.clone(),
+        None => rebuild_rid(),
     };
 
     let response = Response::builder()
@@ -21,28 +72,81 @@ pub async fn serve_nip11(peer: HashedPeer) -> Result<Response<BoxBody<Bytes, Err
         .header("Access-Control-Allow-Methods", "*")
         .header("Content-Type", "application/nostr+json")
         .status(StatusCode::OK)
-        .body(Full::new(rid.clone().into()).map_err(|e| e.into()).boxed())?;
+        .body(Full::new(rid.into()).map_err(|e| e.into()).boxed())?;
     Ok(response)
 }
 
+/// Rebuild the relay information document, returning it
+pub fn rebuild_rid() -> String {
+    let rid = build_rid(&GLOBALS.config.read());
+    *GLOBALS.rid.write() = Some(rid.clone());
+    rid
+}
+
+/// Keep the relay information document up to date (it includes our operational status)
+pub async fn refresh_rid() {
+    let mut interval = tokio::time::interval(RID_REFRESH);
+    loop {
+        let _ = interval.tick().await;
+        let _ = rebuild_rid();
+    }
+}
+
+// How bucketed connection counts are advertised
+fn connections_bucket(num: usize) -> &'static str {
+    match num {
+        0..=9 => "0-9",
+        10..=99 => "10-99",
+        100..=999 => "100-999",
+        _ => "1000+",
+    }
+}
+
+/// The `icon` or `banner` image in the data directory, if there is one, with its content type
+pub fn image_file(config: &Config, name: &str) -> Option<(PathBuf, &'static str)> {
+    IMAGE_TYPES.iter().find_map(|(ext, content_type)| {
+        let path = Path::new(&config.data_directory).join(format!("{name}.{ext}"));
+        path.is_file().then_some((path, *content_type))
+    })
+}
+
+/// Serve `/icon` or `/banner` from the data directory
+pub async fn serve_image(
+    peer: HashedPeer,
+    name: &str,
+) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let file = image_file(&GLOBALS.config.read(), name);
+    let Some((path, content_type)) = file else {
+        return Ok(Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::NOT_FOUND)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
+    };
+    log::debug!(target: "Client", "{}: sent {}", peer, name);
+    let data = tokio::fs::read(&path).await?;
+    Ok(Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Content-Type", content_type)
+        .header("Cache-Control", format!("public, max-age={IMAGE_MAX_AGE}"))
+        .status(StatusCode::OK)
+        .body(Full::new(data.into()).map_err(|e| e.into()).boxed())?)
+}
+
+// A URL of ours, on the http(s) side
+fn our_url(config: &Config, path: &'static str) -> String {
+    match config.uri_parts(Uri::from_static(path), true) {
+        Ok(parts) => match Uri::from_parts(parts) {
+            Ok(uri) => format!("{}", uri),
+            Err(_) => "".to_owned(),
+        },
+        Err(_) => "".to_owned(),
+    }
+}
+
 fn build_rid(config: &Config) -> String {
-    let mut rid: String = String::with_capacity(255);
-
-    const SUPPORTED_NIPS: [u8; 9] = [
-        1,  // nostr
-        4,  // DMs
-        9,  // Event Deletion
-        11, // relay information document
-        40, // Expiration Timestamp
-        42, // AUTH
-        45, // Counting results
-        59, // GiftWrap
-        65, // Relay List Metadata
-    ];
-    const _UNSUPPORTED_NIPS: [u8; 5] = [
+    const _UNSUPPORTED_NIPS: [u8; 4] = [
         26, // Delegated Event Signing
         29, // Relay-based Groups
-        50, // SEARCH
         94, // File Metadata
         96, // HTTP File Storage Integration
     ];
@@ -51,124 +155,129 @@ fn build_rid(config: &Config) -> String {
         39, 44, 46, 47, 48, 49, 51, 52, 53, 56, 57, 58, 72, 75, 78, 84, 89, 90, 92, 98, 99,
     ];
 
-    let s = SUPPORTED_NIPS
-        .iter()
-        .map(|i| format!("{}", i))
-        .collect::<Vec<String>>()
-        .join(",");
-    rid.push_str(&format!("{{\"supported_nips\":[{}],", s));
+    let mut rid = Map::new();
 
-    let software = env!("CARGO_PKG_NAME");
-    rid.push_str("\"software\":\"");
-    rid.push_str(software);
-    rid.push('\"');
-
-    let version = env!("CARGO_PKG_VERSION");
-    rid.push(',');
-    rid.push_str("\"version\":\"");
-    rid.push_str(version);
-    rid.push('\"');
+    // Supported NIPs come from the capability registry (src/capabilities.rs)
+    rid.insert(
+        "supported_nips".to_owned(),
+        json!(crate::capabilities::supported_nips(config)),
+    );
+    rid.insert("software".to_owned(), json!(env!("CARGO_PKG_NAME")));
+    rid.insert("version".to_owned(), json!(env!("CARGO_PKG_VERSION")));
 
     if let Some(name) = &config.name {
-        rid.push(',');
-        rid.push_str("\"name\":\"");
-        rid.push_str(name);
-        rid.push('\"');
+        rid.insert("name".to_owned(), json!(name));
     }
     if let Some(description) = &config.description {
-        rid.push(',');
-        rid.push_str("\"description\":\"");
-        rid.push_str(description);
-        rid.push('\"');
+        rid.insert("description".to_owned(), json!(description));
     }
+
+    // Images: configured URLs, or else ones we serve from the data directory
     if let Some(banner_url) = &config.banner_url {
-        rid.push(',');
-        rid.push_str("\"banner\":\"");
-        rid.push_str(banner_url);
-        rid.push('\"');
+        rid.insert("banner".to_owned(), json!(banner_url));
+    } else if image_file(config, "banner").is_some() {
+        let url = our_url(config, "https://authority-will-be-replaced/banner");
+        rid.insert("banner".to_owned(), json!(url));
     }
     if let Some(icon_url) = &config.icon_url {
-        rid.push(',');
-        rid.push_str("\"icon\":\"");
-        rid.push_str(icon_url);
-        rid.push('\"');
+        rid.insert("icon".to_owned(), json!(icon_url));
+    } else if image_file(config, "icon").is_some() {
+        let url = our_url(config, "https://authority-will-be-replaced/icon");
+        rid.insert("icon".to_owned(), json!(url));
     }
+
     if let Some(pubkey) = &config.contact_public_key {
-        let mut pkh: [u8; 64] = [0; 64];
-        pubkey.write_hex(&mut pkh).unwrap();
-        rid.push(',');
-        rid.push_str("\"pubkey\":\"");
-        rid.push_str(unsafe { std::str::from_utf8_unchecked(pkh.as_slice()) });
-        rid.push('\"');
+        rid.insert("pubkey".to_owned(), json!(pubkey.as_hex_string()));
     }
     if let Some(contact) = &config.contact {
-        rid.push(',');
-        rid.push_str("\"contact\":\"");
-        rid.push_str(contact);
-        rid.push('\"');
+        rid.insert("contact".to_owned(), json!(contact));
     }
     if config.privacy_policy.is_some() {
-        rid.push(',');
-        rid.push_str("\"privacy_policy\":\"");
-        let url = match config.uri_parts(
-            Uri::from_static("https://authority-will-be-replaced/privacy-policy"),
-            true,
-        ) {
-            Ok(parts) => match Uri::from_parts(parts) {
-                Ok(uri) => format!("{}", uri),
-                Err(_) => "".to_owned(),
-            },
-            Err(_) => "".to_owned(),
-        };
-        rid.push_str(&url);
-        rid.push('\"');
+        let url = our_url(config, "https://authority-will-be-replaced/privacy-policy");
+        rid.insert("privacy_policy".to_owned(), json!(url));
     }
     if config.terms_of_service.is_some() {
-        rid.push(',');
-        rid.push_str("\"terms_of_service\":\"");
-        let url = match config.uri_parts(
-            Uri::from_static("https://authority-will-be-replaced/terms-of-service"),
-            true,
-        ) {
-            Ok(parts) => match Uri::from_parts(parts) {
-                Ok(uri) => format!("{}", uri),
-                Err(_) => "".to_owned(),
-            },
-            Err(_) => "".to_owned(),
-        };
-        rid.push_str(&url);
-        rid.push('\"');
+        let url = our_url(
+            config,
+            "https://authority-will-be-replaced/terms-of-service",
+        );
+        rid.insert("terms_of_service".to_owned(), json!(url));
+    }
+    if let Some(posting_policy) = &config.posting_policy {
+        rid.insert("posting_policy".to_owned(), json!(posting_policy));
     }
 
-    // Limitation
-    rid.push(',');
-    rid.push_str("\"limitation\":{");
-    {
-        rid.push_str("\"payment_required\":false,\"auth_required\":false,\"restricted_writes\":true,\"max_message_length\":1048576");
-        rid.push_str(&format!(
-            ",\"max_subscriptions\":{}",
-            config.max_subscriptions
-        ));
+    rid.insert("relay_countries".to_owned(), json!(config.relay_countries));
+    rid.insert("language_tags".to_owned(), json!(config.language_tags));
+    rid.insert("tags".to_owned(), json!(config.tags));
+    rid.insert("payments_url".to_owned(), json!(config.payments_url));
+    rid.insert("fees".to_owned(), json!(config.fees));
+
+    // Limitation: only what we actually enforce
+    let mut limitation = json!({
+        "payment_required": false,
+        // NIP-11: only if AUTH is needed before doing anything at all
+        "auth_required": config.auth_required_for_read && config.auth_required_for_write,
+        "restricted_writes": !config.open_relay || config.auth_required_for_write,
+        "max_message_length": config.max_message_length,
+        "max_subscriptions": config.max_subscriptions,
+        "max_filters": config.max_filters,
+        // A chorus extension
+        "max_filter_values": config.max_filter_values,
+        "max_limit": config.max_limit,
+        "default_limit": config.default_limit,
+        "max_event_tags": config.max_event_tags,
+        "created_at_upper_limit": config.created_at_upper_drift,
+        // Who we accept events from and for (see docs/BEHAVIOR.md)
+        "inbox_outbox": !config.open_relay,
+        "users_from_relay_lists": !config.open_relay && config.users_from_relay_lists,
+        // Read-only and maintenance modes (a chorus extension)
+        "read_only": !config.mode.accepts_writes(),
+    });
+    // In seconds before now, as NIP-11 has it
+    let now = Time::now().as_u64();
+    if let Some(bound) = crate::limits::lower_bound(config, now) {
+        limitation["created_at_lower_limit"] = json!(now.saturating_sub(bound));
+    }
+    if config.blossom_directory.is_some() {
+        limitation["blossom_max_upload_bytes"] = json!(config.blossom_max_upload_bytes);
     }
-    rid.push('}');
+    rid.insert("limitation".to_owned(), limitation);
+
+    // Multi-letter tag names that are index-accelerated (a chorus extension)
+    rid.insert("indexed_tags".to_owned(), json!(config.indexed_tag_names));
+
+    // Operational status (a chorus extension), as of when this document was built
+    let accepting_events = !GLOBALS.handing_over.load(Ordering::Relaxed)
+        && !*GLOBALS.shutting_down.borrow()
+        && config.mode.accepts_writes();
+    rid.insert(
+        "chorus_status".to_owned(),
+        json!({
+            "mode": config.mode.to_string(),
+            "accepting_events": accepting_events,
+            "overloaded": GLOBALS.overloaded.load(Ordering::Relaxed),
+            "greylisting": !config.open_relay,
+            "connections": connections_bucket(GLOBALS.num_connections.load(Ordering::Relaxed)),
+        }),
+    );
 
     // Retention
-    rid.push(',');
-    rid.push_str("\"retention\":[{\"time\": null}]");
+    rid.insert(
+        "retention".to_owned(),
+        crate::retention::nip11_json(&config.retention),
+    );
 
     // Services
-    rid.push(',');
-    rid.push_str("\"services\":{");
-    rid.push_str("\"public\":[\"ephemeral\",\"directory\"]");
-    rid.push(',');
-    rid.push_str("\"private\":[\"outbox\",\"inbox\"]");
-    rid.push(',');
-    rid.push_str("\"paid\":[]");
-    rid.push(',');
-    rid.push_str("\"unavailable\":[\"search\"]");
-    rid.push('}');
-
-    rid.push('}');
+    rid.insert(
+        "services".to_owned(),
+        json!({
+            "public": ["ephemeral", "directory"],
+            "private": ["outbox", "inbox"],
+            "paid": [],
+            "unavailable": if config.enable_search { json!([]) } else { json!(["search"]) },
+        }),
+    );
 
-    rid
+    Value::Object(rid).to_string()
 }
@@ -0,0 +1,222 @@
+/// Path prefixes that belong to relay endpoints (present or future).
+///
+/// A path under one of these prefixes is never treated as a blossom blob, even if the
+/// remainder happens to look like a hash.
+pub const RESERVED_PREFIXES: &[&str] = &[
+    "/.well-known",
+    "/admin",
+    "/metrics",
+    "/health",
+    "/stats",
+    "/icon",
+    "/banner",
+    "/upload",
+    "/list",
+    "/mirror",
+    "/media",
+];
+
+/// Where an HTTP request (that isn't a websocket upgrade, NIP-11 or NIP-86 request)
+/// should be dispatched to.
+///
+/// This is also the place to hang per-route policy (rate limits, auth requirements)
+/// off of, since every HTTP request is classified exactly once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    PrivacyPolicy,
+    TermsOfService,
+    Metrics,
+    Health,
+    Stats,
+    Icon,
+    Banner,
+    BlossomUpload,
+    BlossomMedia,
+    BlossomList,
+    BlossomMirror,
+    BlossomBlob,
+
+    /// A reserved path that has no handler (yet)
+    Reserved,
+
+    /// Anything else
+    Fallback,
+}
+
+impl Route {
+    pub fn is_blossom(&self) -> bool {
+        matches!(
+            *self,
+            Route::BlossomUpload
+                | Route::BlossomMedia
+                | Route::BlossomList
+                | Route::BlossomMirror
+                | Route::BlossomBlob
+        )
+    }
+}
+
+struct RouteEntry {
+    matcher: fn(&str) -> bool,
+    route: Route,
+}
+
+// Ordered: the first matching entry wins. Blob hashes are matched last so that nothing
+// reserved can be shadowed by them.
+const ROUTES: &[RouteEntry] = &[
+    RouteEntry {
+        matcher: |p| p == "/privacy-policy",
+        route: Route::PrivacyPolicy,
+    },
+    RouteEntry {
+        matcher: |p| p == "/terms-of-service",
+        route: Route::TermsOfService,
+    },
+    RouteEntry {
+        matcher: |p| p == "/metrics",
+        route: Route::Metrics,
+    },
+    RouteEntry {
+        matcher: |p| p == "/health",
+        route: Route::Health,
+    },
+    RouteEntry {
+        matcher: |p| p == "/stats",
+        route: Route::Stats,
+    },
+    RouteEntry {
+        matcher: |p| p == "/icon",
+        route: Route::Icon,
+    },
+    RouteEntry {
+        matcher: |p| p == "/banner",
+        route: Route::Banner,
+    },
+    RouteEntry {
+        matcher: |p| p == "/upload",
+        route: Route::BlossomUpload,
+    },
+    RouteEntry {
+        matcher: |p| p == "/media",
+        route: Route::BlossomMedia,
+    },
+    RouteEntry {
+        matcher: |p| match p.strip_prefix("/list/") {
+            Some(rest) => is_hex64(rest),
+            None => false,
+        },
+        route: Route::BlossomList,
+    },
+    RouteEntry {
+        matcher: |p| p == "/mirror",
+        route: Route::BlossomMirror,
+    },
+    RouteEntry {
+        matcher: |p| RESERVED_PREFIXES.iter().any(|r| under_prefix(p, r)),
+        route: Route::Reserved,
+    },
+    RouteEntry {
+        matcher: is_blob_path,
+        route: Route::BlossomBlob,
+    },
+];
+
+/// Classify a request path
+pub fn classify(path: &str) -> Route {
+    for entry in ROUTES {
+        if (entry.matcher)(path) {
+            return entry.route;
+        }
+    }
+    Route::Fallback
+}
+
+// True if `path` is `prefix` itself or something underneath it
+fn under_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn is_hex64(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// `/<64 hex>` optionally followed by a `.ext` file extension, and nothing else
+fn is_blob_path(path: &str) -> bool {
+    let Some(rest) = path.strip_prefix('/') else {
+        return false;
+    };
+    if rest.len() < 64 || !rest.is_char_boundary(64) {
+        return false;
+    }
+    let (hash, ext) = rest.split_at(64);
+    if !is_hex64(hash) {
+        return false;
+    }
+    ext.is_empty() || (ext.starts_with('.') && !ext.contains('/'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "b1674191a88ec5cdd733e4240a81803105dc412d6c6708d53ab94fc248f4f553";
+
+    #[test]
+    fn test_blossom_routes() {
+        assert_eq!(classify(&format!("/{HASH}")), Route::BlossomBlob);
+        assert_eq!(classify(&format!("/{HASH}.png")), Route::BlossomBlob);
+        assert_eq!(classify("/upload"), Route::BlossomUpload);
+        assert_eq!(classify("/media"), Route::BlossomMedia);
+        assert_eq!(classify(&format!("/list/{HASH}")), Route::BlossomList);
+        assert_eq!(classify("/mirror"), Route::BlossomMirror);
+    }
+
+    #[test]
+    fn test_ambiguous_paths() {
+        // Reserved prefixes followed by something hash-like are not blobs
+        for prefix in RESERVED_PREFIXES {
+            let path = format!("{prefix}/{HASH}");
+            let route = classify(&path);
+            assert!(
+                !route.is_blossom() || *prefix == "/list",
+                "{path} -> {route:?}"
+            );
+        }
+        assert_eq!(classify(&format!("/.well-known/{HASH}")), Route::Reserved);
+        assert_eq!(classify(&format!("/media/{HASH}")), Route::Reserved);
+        assert_eq!(classify(&format!("/admin/{HASH}.png")), Route::Reserved);
+
+        // Blob paths with trailing segments or short/long hashes are not blobs
+        assert_eq!(classify(&format!("/{HASH}/extra")), Route::Fallback);
+        assert_eq!(classify(&format!("/{HASH}.png/extra")), Route::Fallback);
+        assert_eq!(classify(&format!("/{HASH}0")), Route::Fallback);
+        assert_eq!(classify(&format!("/{}", &HASH[1..])), Route::Fallback);
+        assert_eq!(classify("/"), Route::Fallback);
+
+        // A list without a valid pubkey is reserved, not a blob
+        assert_eq!(classify("/list/nope"), Route::Reserved);
+        assert_eq!(classify("/list"), Route::Reserved);
+
+        // Prefixes only reserve whole path segments
+        assert_eq!(classify("/administrator"), Route::Fallback);
+        assert_eq!(classify("/uploads"), Route::Fallback);
+        assert_eq!(classify("/health/live"), Route::Reserved);
+
+        // Non-ascii input does not panic
+        assert_eq!(classify(&format!("/a{}", "é".repeat(40))), Route::Fallback);
+    }
+
+    #[test]
+    fn test_static_pages() {
+        assert_eq!(classify("/privacy-policy"), Route::PrivacyPolicy);
+        assert_eq!(classify("/terms-of-service"), Route::TermsOfService);
+        assert_eq!(classify("/metrics"), Route::Metrics);
+        assert_eq!(classify("/health"), Route::Health);
+        assert_eq!(classify("/stats"), Route::Stats);
+        assert_eq!(classify("/icon"), Route::Icon);
+        assert_eq!(classify("/banner"), Route::Banner);
+    }
+}
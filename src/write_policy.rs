@@ -0,0 +1,257 @@
+//! Write policy: deciding, outside of chorus, which events to accept
+//!
+//! Modeled on strfry's write policy plugins. If `write_policy_plugin` is set, chorus runs
+//! that command and keeps it running (restarting it, with backoff, if it exits). Each
+//! incoming EVENT is written to its stdin as a JSON line:
+//!
+//! `{"type":"new","event":{...},"receivedAt":<unix time>,"sourceType":"Client","sourceInfo":<hashed IP>}`
+//!
+//! and the plugin answers each on its stdout with a JSON line
+//!
+//! `{"id":<event id>,"action":"accept"|"reject"|"shadowReject","msg":<message>}`
+//!
+//! Lines are matched up by id, so a plugin may answer out of order. An event without an
+//! answer within `write_policy_timeout_ms` (or while the plugin is not running) is
+//! accepted if `write_policy_fail_open`, and rejected otherwise.
+//!
+//! The `write_policy_deny_pubkeys` and `write_policy_allow_pubkeys` lists are checked
+//! first, and work without a plugin.
+
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::ip::HashedPeer;
+use pocket_types::{Event, Time};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+// How many events may wait for the plugin at once
+const QUEUE: usize = 1024;
+
+// Restarting a plugin that keeps exiting waits this long at first, doubling up to the max
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// What to do with an event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+
+    /// Reject it, with this message
+    Reject(String),
+
+    /// Tell the client it was accepted, but do not store it
+    ShadowReject,
+}
+
+struct Request {
+    id: String,
+    line: String,
+    reply: oneshot::Sender<Verdict>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: String,
+    action: String,
+    #[serde(default)]
+    msg: String,
+}
+
+impl Response {
+    fn verdict(self) -> Verdict {
+        match &*self.action {
+            "accept" => Verdict::Accept,
+            "shadowReject" => Verdict::ShadowReject,
+            _ => Verdict::Reject(self.msg),
+        }
+    }
+}
+
+static QUEUE_SENDER: OnceLock<mpsc::Sender<Request>> = OnceLock::new();
+
+/// Decide what to do with an event from `peer`
+pub async fn check(event: &Event, peer: HashedPeer) -> Result<Verdict, Error> {
+    let (plugin, timeout, fail_open) = {
+        let config = GLOBALS.config.read();
+        if config.write_policy_deny_pubkeys.contains(&event.pubkey()) {
+            return Ok(Verdict::Reject("pubkey is denied".to_owned()));
+        }
+        if config.write_policy_allow_pubkeys.contains(&event.pubkey()) {
+            return Ok(Verdict::Accept);
+        }
+        (
+            config.write_policy_plugin.is_some(),
+            Duration::from_millis(config.write_policy_timeout_ms),
+            config.write_policy_fail_open,
+        )
+    };
+    if !plugin {
+        return Ok(Verdict::Accept);
+    }
+
+    let line = format!(
+        r#"{{"type":"new","event":{},"receivedAt":{},"sourceType":"Client","sourceInfo":{}}}"#,
+        String::from_utf8_lossy(&event.as_json()?),
+        Time::now().as_u64(),
+        serde_json::to_string(&peer.ip().to_string())?,
+    );
+    let (reply, verdict) = oneshot::channel();
+    let request = Request {
+        id: event.id().as_hex_string(),
+        line,
+        reply,
+    };
+
+    let answered = match QUEUE_SENDER.get() {
+        Some(sender) if sender.try_send(request).is_ok() => tokio::time::timeout(timeout, verdict)
+            .await
+            .ok()
+            .and_then(|v| v.ok()),
+        _ => None,
+    };
+    match answered {
+        Some(verdict) => Ok(verdict),
+        None if fail_open => {
+            log::warn!(target: "Server", "Write policy plugin did not answer, accepting {}", event.id().as_hex_string());
+            Ok(Verdict::Accept)
+        }
+        None => Err(ChorusError::WritePolicyUnavailable.into()),
+    }
+}
+
+// Run the plugin until it exits (or we shut down), answering requests
+async fn run_plugin(command: &str, requests: &mut mpsc::Receiver<Request>) -> Result<(), Error> {
+    // The program, then its arguments, separated by whitespace
+    let mut words = command.split_whitespace();
+    let Some(program) = words.next() else {
+        return Err(ChorusError::General("write policy plugin command is empty".to_owned()).into());
+    };
+    let mut child = Command::new(program)
+        .args(words)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+
+    // Lines are written to the plugin from a task of their own, so that a plugin that
+    // stops reading (with its stdin pipe full) does not keep us from reading its answers
+    // or noticing it exit. Requests it has no room for wait no longer than they would for
+    // an answer.
+    let (lines, mut to_write) = mpsc::channel::<String>(QUEUE);
+    let mut writer = tokio::spawn(async move {
+        while let Some(line) = to_write.recv().await {
+            stdin.write_all(line.as_bytes()).await?;
+            stdin.write_all(b"\n").await?;
+            stdin.flush().await?;
+        }
+        Ok::<(), std::io::Error>(())
+    });
+    let result = answer(&mut child, &mut stdout, &lines, &mut writer, requests).await;
+    writer.abort();
+    result
+}
+
+// Answer requests with what the plugin says, until it exits (or we shut down)
+async fn answer(
+    child: &mut Child,
+    stdout: &mut Lines<BufReader<ChildStdout>>,
+    lines: &mpsc::Sender<String>,
+    writer: &mut JoinHandle<Result<(), std::io::Error>>,
+    requests: &mut mpsc::Receiver<Request>,
+) -> Result<(), Error> {
+    let mut shutting_down = GLOBALS.shutting_down.subscribe();
+
+    // Requests written to the plugin, by event id. (Dropping them when the plugin exits
+    // leaves the events to `write_policy_fail_open`.)
+    let mut pending: HashMap<String, oneshot::Sender<Verdict>> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(request) = request else {
+                    return Ok(());
+                };
+                // (Nobody waits for it any more)
+                if request.reply.is_closed() {
+                    continue;
+                }
+                if lines.try_send(request.line).is_err() {
+                    // (Left to time out)
+                    continue;
+                }
+                pending.retain(|_, reply| !reply.is_closed());
+                let _ = pending.insert(request.id, request.reply);
+            }
+            line = stdout.next_line() => {
+                let Some(line) = line? else {
+                    return Err(ChorusError::General("write policy plugin closed its output".to_owned()).into());
+                };
+                match serde_json::from_str::<Response>(&line) {
+                    Ok(response) => {
+                        if let Some(reply) = pending.remove(&response.id) {
+                            let _ = reply.send(response.verdict());
+                        }
+                    }
+                    Err(e) => log::error!(target: "Server", "Write policy plugin said {line:?}: {e}"),
+                }
+            }
+            written = &mut *writer => {
+                let e = match written {
+                    Ok(Ok(())) => "write policy plugin input closed".to_owned(),
+                    Ok(Err(e)) => format!("writing to write policy plugin: {e}"),
+                    Err(e) => format!("{e}"),
+                };
+                return Err(ChorusError::General(e).into());
+            }
+            status = child.wait() => {
+                return Err(ChorusError::General(format!("write policy plugin exited: {}", status?)).into());
+            }
+            _ = shutting_down.changed() => {
+                if *shutting_down.borrow() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Keep the write policy plugin running (if configured), until shutdown
+pub async fn run() {
+    let (sender, mut requests) = mpsc::channel(QUEUE);
+    if QUEUE_SENDER.set(sender).is_err() {
+        return;
+    }
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let Some(command) = GLOBALS.config.read().write_policy_plugin.clone() else {
+            return;
+        };
+
+        let started = Instant::now();
+        log::info!(target: "Server", "Starting write policy plugin {command}");
+        if let Err(e) = run_plugin(&command, &mut requests).await {
+            log::error!(target: "Server", "Write policy plugin failed: {e}");
+        }
+        if *GLOBALS.shutting_down.borrow() {
+            return;
+        }
+
+        // Back off from a plugin that keeps failing, but not from one that ran for a while
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        log::warn!(target: "Server", "Restarting write policy plugin in {}s", backoff.as_secs());
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
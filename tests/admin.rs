@@ -0,0 +1,105 @@
+// Checks management commands sent as nostr events over the websocket, which are applied
+// only by admins and moderators, and only once
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+
+const ADMIN: u8 = 0x42;
+
+fn command(client: &mut Client, secret: u8, content: &str) -> Value {
+    client.send(format!(
+        r#"["EVENT",{}]"#,
+        common::sign_event_as(
+            secret,
+            chorus::admin::ADMIN_COMMAND_KIND,
+            r#"["relay","ws://localhost"]"#,
+            content
+        )
+    ));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    reply
+}
+
+#[test]
+fn test_admin_commands() {
+    let relay = common::start_relay(&format!(
+        "open_relay = true\nadmin_hex_keys = [\"{}\"]\n",
+        common::test_pubkey(ADMIN)
+    ));
+    let mut client = Client::connect(relay.port);
+    let banned = common::test_pubkey(0x17);
+
+    // Only admins and moderators
+    let reply = command(&mut client, 0x17, r#"{"method":"stats","params":[]}"#);
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(
+        reply[3].as_str().unwrap().starts_with("restricted:"),
+        "{reply}"
+    );
+
+    let reply = command(
+        &mut client,
+        ADMIN,
+        &format!(r#"{{"method":"banpubkey","params":["{banned}"]}}"#),
+    );
+    assert_eq!(reply[2], true, "{reply}");
+    let response: Value = serde_json::from_str(reply[3].as_str().unwrap()).unwrap();
+    assert!(response.get("error").is_none(), "{response}");
+
+    // Their events are now refused
+    client.send(format!(
+        r#"["EVENT",{}]"#,
+        common::sign_event(1, "", "hello")
+    ));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(
+        reply[3].as_str().unwrap().starts_with("blocked:"),
+        "{reply}"
+    );
+
+    let reply = command(
+        &mut client,
+        ADMIN,
+        r#"{"method":"listbannedpubkeys","params":[]}"#,
+    );
+    assert_eq!(reply[2], true, "{reply}");
+    assert!(reply[3].as_str().unwrap().contains(&banned), "{reply}");
+
+    let reply = command(&mut client, ADMIN, r#"{"method":"listipbans","params":[]}"#);
+    assert_eq!(reply[2], true, "{reply}");
+    let reply = command(
+        &mut client,
+        ADMIN,
+        r#"{"method":"pardonip","params":["not a hashed ip"]}"#,
+    );
+    assert_eq!(reply[2], false, "{reply}");
+
+    // No command is applied twice, however soon it is replayed
+    let unban = common::sign_event_as(
+        ADMIN,
+        chorus::admin::ADMIN_COMMAND_KIND,
+        r#"["relay","ws://localhost"]"#,
+        &format!(r#"{{"method":"allowpubkey","params":["{banned}"]}}"#),
+    );
+    client.send(format!(r#"["EVENT",{unban}]"#));
+    assert_eq!(client.recv(false)[2], true);
+    client.send(format!(r#"["EVENT",{unban}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], false, "{reply}");
+    assert_eq!(
+        reply[3],
+        "restricted: Authorization failure: command was already applied"
+    );
+
+    // Unknown methods are answered, not stored
+    let reply = command(&mut client, ADMIN, r#"{"method":"nosuchthing"}"#);
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(
+        reply[3].as_str().unwrap().contains("not_implemented"),
+        "{reply}"
+    );
+}
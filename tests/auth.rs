@@ -0,0 +1,127 @@
+// Checks the NIP-42 AUTH policy knobs (auth_required_for_read, auth_required_for_write)
+// and NIP-70 protected events, which depend on AUTH
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+
+// Read the AUTH challenge the relay sends on connect
+fn challenge(client: &mut Client) -> String {
+    let auth = client.recv(true);
+    assert_eq!(auth[0], "AUTH", "{auth}");
+    auth[1].as_str().unwrap().to_owned()
+}
+
+fn authenticate(client: &mut Client, challenge: &str) {
+    let auth = common::sign_event(
+        22242,
+        &format!(r#"["relay","ws://localhost"],["challenge","{challenge}"]"#),
+        "",
+    );
+    client.send(format!(r#"["AUTH",{auth}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], true, "{reply}");
+}
+
+fn is_auth_required(message: &Value) -> bool {
+    message
+        .as_str()
+        .is_some_and(|m| m.starts_with("auth-required:"))
+}
+
+#[test]
+fn test_auth_required_for_read() {
+    let relay = common::start_relay(
+        "open_relay = true\nallow_scraping = true\nauth_required_for_read = true\n",
+    );
+    let mut client = Client::connect(relay.port);
+    let challenge = challenge(&mut client);
+
+    // Writing does not need AUTH
+    let event = common::sign_event(1, "", "hidden until AUTH");
+    let id = serde_json::from_str::<Value>(&event).unwrap()["id"].clone();
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], true, "{reply}");
+
+    // Reading does, and we are reminded of the challenge
+    client.send(r#"["REQ","before",{"kinds":[1]}]"#.to_owned());
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "CLOSED", "{reply}");
+    assert!(is_auth_required(&reply[2]), "{reply}");
+    assert_eq!(client.recv(true)[1], challenge.as_str());
+
+    // Once authenticated, a new REQ sees the event
+    authenticate(&mut client, &challenge);
+    client.send(r#"["REQ","after",{"kinds":[1]}]"#.to_owned());
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "EVENT", "{reply}");
+    assert_eq!(reply[2]["id"], id);
+    assert_eq!(client.recv(false)[0], "EOSE");
+}
+
+#[test]
+fn test_auth_required_for_write() {
+    let relay = common::start_relay(
+        "open_relay = true\nallow_scraping = true\nauth_required_for_write = true\n",
+    );
+    let mut client = Client::connect(relay.port);
+    let challenge = challenge(&mut client);
+
+    // Reading does not need AUTH
+    client.send(r#"["REQ","s",{"kinds":[1]}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EOSE");
+    client.send(r#"["CLOSE","s"]"#.to_owned());
+
+    // Writing does
+    let event = common::sign_event(1, "", "needs AUTH");
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(is_auth_required(&reply[3]), "{reply}");
+
+    authenticate(&mut client, &challenge);
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], true, "{reply}");
+}
+
+#[test]
+fn test_protected_events() {
+    let relay = common::start_relay(
+        "open_relay = true\nallow_scraping = true\nprotected_events_author_only = true\n",
+    );
+    let mut author = Client::connect(relay.port);
+    let challenge = challenge(&mut author);
+
+    // Relayed by somebody not AUTHed as the author (such as another relay): refused
+    let event = common::sign_event(1, r#"["-"]"#, "protected");
+    let id = serde_json::from_str::<Value>(&event).unwrap()["id"].clone();
+    author.send(format!(r#"["EVENT",{event}]"#));
+    let reply = author.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(is_auth_required(&reply[3]), "{reply}");
+
+    // From the author: accepted
+    authenticate(&mut author, &challenge);
+    author.send(format!(r#"["EVENT",{event}]"#));
+    let reply = author.recv(false);
+    assert_eq!(reply[2], true, "{reply}");
+
+    // Only served to the author
+    let mut other = Client::connect(relay.port);
+    other.send(r#"["REQ","s",{"kinds":[1]}]"#.to_owned());
+    let reply = other.recv(false);
+    assert_eq!(reply[0], "CLOSED", "{reply}");
+    assert!(is_auth_required(&reply[2]), "{reply}");
+
+    author.send(r#"["REQ","s",{"kinds":[1]}]"#.to_owned());
+    let reply = author.recv(false);
+    assert_eq!(reply[0], "EVENT", "{reply}");
+    assert_eq!(reply[2]["id"], id);
+    assert_eq!(author.recv(false)[0], "EOSE");
+}
@@ -0,0 +1,88 @@
+// Checks that Blossom garbage collection deletes blobs (and their metadata) per its
+// policy, but leaves alone a blob that is being uploaded again, or that was used after it
+// was chosen
+
+use chorus::config::Config;
+use chorus::filestore::{metadata, FileStore, HashOutput};
+use chorus::globals::GLOBALS;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use pocket_types::{Pubkey, Time};
+
+async fn upload(data: &'static [u8]) -> (HashOutput, chorus::filestore::Uploading) {
+    let body = Full::new(Bytes::from_static(data))
+        .map_err(|never| match never {})
+        .boxed();
+    let (_size, hash, _, uploading) = GLOBALS
+        .filestore
+        .get()
+        .unwrap()
+        .store(body, None)
+        .await
+        .unwrap();
+    (hash, uploading)
+}
+
+async fn on_disk(hash: HashOutput) -> bool {
+    GLOBALS
+        .filestore
+        .get()
+        .unwrap()
+        .metadata(hash)
+        .await
+        .is_ok()
+}
+
+#[tokio::test]
+async fn test_gc_races() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = dir.path().join("blobs");
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        blossom_directory: Some(blobs.to_str().unwrap().to_owned()),
+        blossom_gc_unaccessed_days: 1,
+        blossom_gc_grace_seconds: 0,
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    let filestore = FileStore::new(&blobs, config.blossom_shard_depth)
+        .await
+        .unwrap();
+    let _ = GLOBALS.filestore.set(filestore);
+    *GLOBALS.config.write() = config;
+
+    // Uploaded long ago, and never used since
+    let owner = Pubkey::from_bytes([7; 32]);
+    let long_ago = Time::now().as_u64() - 30 * 86400;
+    let mut hashes = Vec::new();
+    for data in [&b"first blob"[..], &b"second blob"[..]] {
+        let (hash, uploading) = upload(data).await;
+        metadata::add_blob(hash, data.len() as u64, None, None, owner, long_ago).unwrap();
+        drop(uploading);
+        hashes.push(hash);
+    }
+    let (first, second) = (hashes[0], hashes[1]);
+
+    // While one is being uploaded again, only the other goes
+    let (again, uploading) = upload(b"first blob").await;
+    assert_eq!(again, first);
+    assert_eq!(chorus::filestore::gc::sweep().await.unwrap(), (1, 11));
+    assert!(on_disk(first).await);
+    assert!(metadata::get_blob(first).unwrap().is_some());
+    assert!(!on_disk(second).await);
+    assert!(metadata::get_blob(second).unwrap().is_none());
+    drop(uploading);
+
+    // A blob served after it was chosen is kept
+    let filestore = GLOBALS.filestore.get().unwrap();
+    let chosen = metadata::get_blob(first).unwrap().unwrap();
+    metadata::touch(first, Time::now().as_u64()).unwrap();
+    assert!(!filestore.delete_unchanged(first, &chosen).await.unwrap());
+    assert!(on_disk(first).await);
+
+    // Otherwise it goes, metadata and all
+    let chosen = metadata::get_blob(first).unwrap().unwrap();
+    assert!(filestore.delete_unchanged(first, &chosen).await.unwrap());
+    assert!(!on_disk(first).await);
+    assert!(metadata::get_blob(first).unwrap().is_none());
+}
@@ -0,0 +1,572 @@
+// Checks the Blossom endpoints: serving blobs with their types (with MIME types recorded at
+// startup for blobs stored before we kept them) and ranges of them, BUD-06 upload
+// pre-flight checks, who may delete what, BUD-05 media uploads (and which originals they
+// keep), BUD-02 lists of who uploaded what, who may use each verb under each policy, and
+// the upload size limit
+
+mod common;
+
+use base64::prelude::*;
+use bitcoin_hashes::sha256;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x02\0\0\0";
+
+// Make an HTTP request, returning the response headers and body
+fn http(
+    port: u16,
+    method: &str,
+    path: &str,
+    extra_headers: &str,
+    body: &[u8],
+) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "{method} {path} HTTP/1.1\r\nHost: localhost\r\n{extra_headers}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    stream.write_all(body).unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    (
+        String::from_utf8(response[..split].to_vec()).unwrap(),
+        response[split + 4..].to_vec(),
+    )
+}
+
+fn header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers.lines().find_map(|line| {
+        let (n, v) = line.split_once(':')?;
+        n.eq_ignore_ascii_case(name).then(|| v.trim())
+    })
+}
+
+fn hash_hex(data: &[u8]) -> String {
+    let mut engine = sha256::HashEngine::default();
+    engine.write_all(data).unwrap();
+    chorus::filestore::HashOutput::from_engine(engine).to_string()
+}
+
+#[test]
+fn test_mime_type_backfill() {
+    let blobs = tempfile::tempdir().unwrap();
+    let hash = hash_hex(PNG);
+
+    // Stored (flat, as the oldest versions did) before we kept any metadata
+    std::fs::write(blobs.path().join(&hash), PNG).unwrap();
+
+    let relay = common::start_relay(&format!(
+        "blossom_directory = \"{}\"\n",
+        blobs.path().display()
+    ));
+    common::wait_for_log(&relay.log, "recorded 1 MIME types");
+
+    let (headers, body) = http(relay.port, "GET", &format!("/{hash}"), "", b"");
+    assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+    assert_eq!(header(&headers, "content-type"), Some("image/png"));
+    assert_eq!(body, PNG);
+
+    let (headers, _) = http(relay.port, "HEAD", &format!("/{hash}.png"), "", b"");
+    assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+    assert_eq!(header(&headers, "content-type"), Some("image/png"));
+}
+
+#[test]
+fn test_range() {
+    let blobs = tempfile::tempdir().unwrap();
+    let hash = hash_hex(PNG);
+    std::fs::write(blobs.path().join(&hash), PNG).unwrap();
+    let relay = common::start_relay(&format!(
+        "blossom_directory = \"{}\"\n",
+        blobs.path().display()
+    ));
+    let get = |range: &str| http(relay.port, "GET", &format!("/{hash}"), range, b"");
+
+    let (headers, body) = get("Range: bytes=1-3\r\n");
+    assert_eq!(status(&headers), 206, "{headers}");
+    assert_eq!(
+        header(&headers, "content-range"),
+        Some(format!("bytes 1-3/{}", PNG.len()).as_str())
+    );
+    assert_eq!(body, &PNG[1..4]);
+
+    let (headers, _) = get("Range: bytes=1000-\r\n");
+    assert_eq!(status(&headers), 416, "{headers}");
+
+    // Ranges we can't make sense of are ignored, even those that aren't ASCII
+    for range in ["Range: bytes=0-1,3-4\r\n", "Range: bytes=0-1\u{e9}\r\n"] {
+        let (headers, body) = get(range);
+        assert_eq!(status(&headers), 200, "{headers}");
+        assert_eq!(body, PNG);
+    }
+}
+
+// A Blossom authorization header for `verb` on the blob `hash` (if not empty), signed by
+// `secret`
+fn auth(secret: u8, verb: &str, hash: &str) -> String {
+    let expiration = common::now() + 600;
+    let mut tags = format!(r#"["t","{verb}"],["expiration","{expiration}"]"#);
+    if !hash.is_empty() {
+        tags.push_str(&format!(r#",["x","{hash}"]"#));
+    }
+    let event = common::sign_event_as(secret, 24242, &tags, verb);
+    format!("Authorization: Nostr {}\r\n", BASE64_STANDARD.encode(event))
+}
+
+fn status(headers: &str) -> u16 {
+    headers.split(' ').nth(1).unwrap().parse().unwrap()
+}
+
+const UPLOADER: u8 = 0x31;
+const OTHER: u8 = 0x32;
+
+#[test]
+fn test_upload_and_delete() {
+    let blobs = tempfile::tempdir().unwrap();
+
+    // Stored before we kept any metadata, so nobody owns it
+    let legacy: &[u8] = b"\x89PNG\r\n\x1a\nlegacy";
+    let legacy_hash = hash_hex(legacy);
+    std::fs::write(blobs.path().join(&legacy_hash), legacy).unwrap();
+
+    let relay = common::start_relay(&format!(
+        "blossom_directory = \"{}\"\n\
+         blossom_allowed_pubkeys = [\"{}\", \"{}\"]\n\
+         blossom_allowed_mime_types = [\"image/*\"]\n\
+         blossom_max_upload_bytes = 1000\n",
+        blobs.path().display(),
+        common::test_pubkey(UPLOADER),
+        common::test_pubkey(OTHER),
+    ));
+    let hash = hash_hex(PNG);
+
+    // BUD-06: would it be accepted?
+    let preflight = |hash: &str, length: usize, mime_type: &str| {
+        let headers = format!(
+            "{}X-SHA-256: {hash}\r\nX-Content-Length: {length}\r\nX-Content-Type: {mime_type}\r\n",
+            auth(UPLOADER, "upload", hash)
+        );
+        http(relay.port, "HEAD", "/upload", &headers, b"").0
+    };
+    assert_eq!(status(&preflight(&hash, PNG.len(), "image/png")), 200);
+    let headers = preflight(&hash, 5000, "image/png");
+    assert_eq!(status(&headers), 413, "{headers}");
+    assert!(header(&headers, "x-reason").is_some(), "{headers}");
+    assert_eq!(status(&preflight(&hash, PNG.len(), "text/plain")), 415);
+
+    // Uploaded
+    let (headers, body) = http(
+        relay.port,
+        "PUT",
+        "/upload",
+        &format!(
+            "{}Content-Type: image/png\r\n",
+            auth(UPLOADER, "upload", &hash)
+        ),
+        PNG,
+    );
+    assert_eq!(status(&headers), 200, "{headers}");
+    let descriptor: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(descriptor["sha256"], hash);
+
+    // Which we have now, whatever they say it is
+    assert_eq!(status(&preflight(&hash, 5000, "text/plain")), 200);
+
+    // A type we don't take is refused, and not kept
+    let text: &[u8] = b"just some text";
+    let text_hash = hash_hex(text);
+    let (headers, _) = http(
+        relay.port,
+        "PUT",
+        "/upload",
+        &auth(UPLOADER, "upload", &text_hash),
+        text,
+    );
+    assert_eq!(status(&headers), 415, "{headers}");
+    let (headers, _) = http(relay.port, "GET", &format!("/{text_hash}"), "", b"");
+    assert_eq!(status(&headers), 404, "{headers}");
+
+    // Only its uploader may delete it
+    let delete = |secret: u8, hash: &str| {
+        http(
+            relay.port,
+            "DELETE",
+            &format!("/{hash}"),
+            &auth(secret, "delete", hash),
+            b"",
+        )
+        .0
+    };
+    assert_eq!(status(&delete(OTHER, &hash)), 401);
+    assert_eq!(status(&delete(UPLOADER, &hash)), 200);
+    let (headers, _) = http(relay.port, "GET", &format!("/{hash}"), "", b"");
+    assert_eq!(status(&headers), 404, "{headers}");
+    assert_eq!(status(&delete(UPLOADER, &hash)), 404);
+
+    // And a blob nobody uploaded (here) is nobody's to delete
+    assert_eq!(status(&delete(UPLOADER, &legacy_hash)), 401);
+    let (headers, body) = http(relay.port, "GET", &format!("/{legacy_hash}"), "", b"");
+    assert_eq!(status(&headers), 200, "{headers}");
+    assert_eq!(body, legacy);
+}
+
+// A PNG too wide to serve as it is
+fn wide_png() -> Vec<u8> {
+    let image = image::RgbImage::from_fn(3000, 20, |x, y| {
+        image::Rgb([(x % 256) as u8, (y * 12) as u8, ((x + y) % 256) as u8])
+    });
+    let mut png: Vec<u8> = Vec::new();
+    image::DynamicImage::ImageRgb8(image)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+fn put(port: u16, path: &str, secret: u8, verb: &str, data: &[u8]) -> (u16, serde_json::Value) {
+    let (headers, body) = http(
+        port,
+        "PUT",
+        path,
+        &format!(
+            "{}Content-Type: image/png\r\n",
+            auth(secret, verb, &hash_hex(data))
+        ),
+        data,
+    );
+    let descriptor = serde_json::from_slice(&body).unwrap_or_default();
+    (status(&headers), descriptor)
+}
+
+#[test]
+fn test_media() {
+    let blobs = tempfile::tempdir().unwrap();
+    let relay = common::start_relay(&format!(
+        "blossom_directory = \"{}\"\n\
+         blossom_allowed_pubkeys = [\"{}\", \"{}\"]\n",
+        blobs.path().display(),
+        common::test_pubkey(UPLOADER),
+        common::test_pubkey(OTHER),
+    ));
+    let png = wide_png();
+    let original = hash_hex(&png);
+
+    // Re-encoded, and only that is kept
+    let (code, descriptor) = put(relay.port, "/media", UPLOADER, "media", &png);
+    assert_eq!(code, 200, "{descriptor}");
+    assert_eq!(descriptor["type"], "image/jpeg");
+    let optimized = descriptor["sha256"].as_str().unwrap().to_owned();
+    assert_ne!(optimized, original);
+    let (headers, body) = http(relay.port, "GET", &format!("/{optimized}"), "", b"");
+    assert_eq!(status(&headers), 200, "{headers}");
+    assert_eq!(header(&headers, "content-type"), Some("image/jpeg"));
+    assert_eq!(hash_hex(&body), optimized);
+    let (headers, _) = http(relay.port, "GET", &format!("/{original}"), "", b"");
+    assert_eq!(status(&headers), 404, "{headers}");
+
+    // Unless it is a blob of its own, uploaded as it is
+    let (code, descriptor) = put(relay.port, "/upload", OTHER, "upload", &png);
+    assert_eq!(code, 200, "{descriptor}");
+    assert_eq!(descriptor["sha256"], original);
+    let (code, descriptor) = put(relay.port, "/media", UPLOADER, "media", &png);
+    assert_eq!(code, 200, "{descriptor}");
+    assert_eq!(descriptor["sha256"], optimized);
+    let (headers, body) = http(relay.port, "GET", &format!("/{original}"), "", b"");
+    assert_eq!(status(&headers), 200, "{headers}");
+    assert_eq!(body, png);
+}
+
+#[test]
+fn test_list() {
+    let blobs = tempfile::tempdir().unwrap();
+    let mut relay = common::start_relay(&format!(
+        "blossom_directory = \"{}\"\n\
+         blossom_allowed_pubkeys = [\"{}\"]\n",
+        blobs.path().display(),
+        common::test_pubkey(UPLOADER),
+    ));
+    let list = |port: u16, pubkey: &str, query: &str| -> Vec<String> {
+        let (headers, body) = http(
+            port,
+            "GET",
+            &format!("/list/{pubkey}{query}"),
+            &auth(UPLOADER, "list", ""),
+            b"",
+        );
+        assert_eq!(status(&headers), 200, "{headers}");
+        let descriptors: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let mut hashes: Vec<String> = descriptors
+            .iter()
+            .map(|d| d["sha256"].as_str().unwrap().to_owned())
+            .collect();
+        hashes.sort();
+        hashes
+    };
+
+    // Nobody has uploaded anything yet
+    let uploader = common::test_pubkey(UPLOADER);
+    assert!(list(relay.port, &uploader, "").is_empty());
+
+    let first: &[u8] = b"\x89PNG\r\n\x1a\nfirst";
+    let second: &[u8] = b"\x89PNG\r\n\x1a\nsecond";
+    for data in [first, second] {
+        let (code, descriptor) = put(relay.port, "/upload", UPLOADER, "upload", data);
+        assert_eq!(code, 200, "{descriptor}");
+    }
+    let mut both = vec![hash_hex(first), hash_hex(second)];
+    both.sort();
+    assert_eq!(list(relay.port, &uploader, ""), both);
+    assert!(list(relay.port, &common::test_pubkey(OTHER), "").is_empty());
+
+    // By when they were uploaded
+    let now = common::now();
+    assert_eq!(
+        list(relay.port, &uploader, &format!("?since={}", now - 60)),
+        both
+    );
+    assert!(list(relay.port, &uploader, &format!("?since={}", now + 60)).is_empty());
+    assert!(list(relay.port, &uploader, &format!("?until={}", now - 60)).is_empty());
+
+    // Deleted blobs are no longer listed, and the list outlives the relay
+    let (headers, _) = http(
+        relay.port,
+        "DELETE",
+        &format!("/{}", hash_hex(first)),
+        &auth(UPLOADER, "delete", &hash_hex(first)),
+        b"",
+    );
+    assert_eq!(status(&headers), 200, "{headers}");
+    assert_eq!(list(relay.port, &uploader, ""), vec![hash_hex(second)]);
+    relay.restart();
+    assert_eq!(list(relay.port, &uploader, ""), vec![hash_hex(second)]);
+}
+
+const ADMIN: u8 = 0x33;
+
+// Try `verb` as `secret` on the blob `data` (uploading it, or mirroring it from nowhere),
+// returning the response headers
+fn attempt(port: u16, secret: u8, verb: &str, data: &[u8]) -> String {
+    let hash = hash_hex(data);
+    let (method, path, body) = match verb {
+        "upload" | "media" => ("PUT", format!("/{verb}"), data),
+        "list" => (
+            "GET",
+            format!("/list/{}", common::test_pubkey(secret)),
+            &b""[..],
+        ),
+        "mirror" => ("PUT", "/mirror".to_owned(), &b"{}"[..]),
+        "delete" => ("DELETE", format!("/{hash}"), &b""[..]),
+        _ => unreachable!(),
+    };
+    let headers = format!("{}Content-Type: image/png\r\n", auth(secret, verb, &hash));
+    http(port, method, &path, &headers, body).0
+}
+
+fn assert_denied(headers: &str) {
+    assert_eq!(status(headers), 401, "{headers}");
+    assert_eq!(header(headers, "www-authenticate"), Some("Nostr"));
+    assert_eq!(
+        header(headers, "x-reason"),
+        Some("You are not permitted to use this Blossom server")
+    );
+}
+
+#[test]
+fn test_permitted() {
+    let blobs = tempfile::tempdir().unwrap();
+    let relay = common::start_relay(&format!(
+        "blossom_directory = \"{}\"\n\
+         blossom_allowed_pubkeys = [\"{}\"]\n\
+         admin_hex_keys = [\"{}\"]\n",
+        blobs.path().display(),
+        common::test_pubkey(UPLOADER),
+        common::test_pubkey(ADMIN),
+    ));
+    let mine: &[u8] = b"\x89PNG\r\n\x1a\nmine";
+    let theirs: &[u8] = b"\x89PNG\r\n\x1a\ntheirs";
+    let png = wide_png();
+
+    // Every verb is refused to those not allowed, before anything else is looked at
+    for verb in ["upload", "media", "list", "mirror", "delete"] {
+        assert_denied(&attempt(relay.port, OTHER, verb, mine));
+    }
+    let (headers, _) = http(relay.port, "GET", &format!("/{}", hash_hex(mine)), "", b"");
+    assert_eq!(status(&headers), 404, "{headers}");
+
+    // And permitted to those allowed, and to admins
+    for secret in [UPLOADER, ADMIN] {
+        let data = if secret == UPLOADER { mine } else { theirs };
+        assert_eq!(status(&attempt(relay.port, secret, "upload", data)), 200);
+        assert_eq!(status(&attempt(relay.port, secret, "media", &png)), 200);
+        assert_eq!(status(&attempt(relay.port, secret, "list", data)), 200);
+        // (which gets as far as reading what to mirror)
+        assert_eq!(status(&attempt(relay.port, secret, "mirror", data)), 400);
+    }
+
+    // Downloads are public
+    let (headers, body) = http(relay.port, "GET", &format!("/{}", hash_hex(mine)), "", b"");
+    assert_eq!(status(&headers), 200, "{headers}");
+    assert_eq!(body, mine);
+
+    // Someone not allowed may not delete even what is theirs, those allowed may delete only
+    // what they uploaded, and admins may delete anything
+    assert_denied(&attempt(relay.port, OTHER, "delete", theirs));
+    let headers = attempt(relay.port, UPLOADER, "delete", theirs);
+    assert_eq!(status(&headers), 401, "{headers}");
+    assert_eq!(
+        header(&headers, "x-reason"),
+        Some("You did not upload this blob")
+    );
+    assert_eq!(status(&attempt(relay.port, ADMIN, "delete", mine)), 200);
+    let (headers, _) = http(relay.port, "GET", &format!("/{}", hash_hex(mine)), "", b"");
+    assert_eq!(status(&headers), 404, "{headers}");
+    assert_eq!(status(&attempt(relay.port, ADMIN, "delete", theirs)), 200);
+}
+
+#[test]
+fn test_permitted_users() {
+    let config = |blobs: &tempfile::TempDir, relay_users: bool| {
+        format!(
+            "blossom_directory = \"{}\"\n\
+             blossom_relay_users = {relay_users}\n\
+             user_hex_keys = [\"{}\"]\n\
+             admin_hex_keys = [\"{}\"]\n",
+            blobs.path().display(),
+            common::test_pubkey(UPLOADER),
+            common::test_pubkey(ADMIN),
+        )
+    };
+    let data: &[u8] = b"\x89PNG\r\n\x1a\nusers";
+
+    // With no allowlist only authorized users may, and relay users are not that
+    let blobs = tempfile::tempdir().unwrap();
+    let relay = common::start_relay(&config(&blobs, false));
+    for verb in ["upload", "list", "mirror"] {
+        assert_denied(&attempt(relay.port, UPLOADER, verb, data));
+        assert_denied(&attempt(relay.port, OTHER, verb, data));
+    }
+    assert_eq!(status(&attempt(relay.port, ADMIN, "upload", data)), 200);
+    drop(relay);
+
+    // Unless relay users are let in
+    let blobs = tempfile::tempdir().unwrap();
+    let relay = common::start_relay(&config(&blobs, true));
+    assert_eq!(status(&attempt(relay.port, UPLOADER, "upload", data)), 200);
+    assert_eq!(status(&attempt(relay.port, UPLOADER, "list", data)), 200);
+    assert_eq!(status(&attempt(relay.port, UPLOADER, "delete", data)), 200);
+    for verb in ["upload", "list", "mirror", "delete"] {
+        assert_denied(&attempt(relay.port, OTHER, verb, data));
+    }
+}
+
+// Send a PUT whose body is chunked, so has no Content-Length, returning the response headers
+fn put_chunked(port: u16, path: &str, extra_headers: &str, body: &[u8]) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "PUT {path} HTTP/1.1\r\nHost: localhost\r\n{extra_headers}Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    for chunk in body.chunks(100) {
+        let _ = stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes());
+        let _ = stream.write_all(chunk);
+        let _ = stream.write_all(b"\r\n");
+    }
+    let _ = stream.write_all(b"0\r\n\r\n");
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    String::from_utf8(response[..split].to_vec()).unwrap()
+}
+
+#[test]
+fn test_upload_limit() {
+    const LIMIT: usize = 1000;
+    let blobs = tempfile::tempdir().unwrap();
+    let relay = common::start_relay(&format!(
+        "blossom_directory = \"{}\"\n\
+         blossom_allowed_pubkeys = [\"{}\"]\n\
+         blossom_max_upload_bytes = {LIMIT}\n",
+        blobs.path().display(),
+        common::test_pubkey(UPLOADER),
+    ));
+    let blob = |len: usize, fill: u8| {
+        let mut data = PNG.to_vec();
+        data.resize(len, fill);
+        data
+    };
+
+    // Advertised
+    let (_, body) = http(
+        relay.port,
+        "GET",
+        "/",
+        "Accept: application/nostr+json\r\n",
+        b"",
+    );
+    let rid: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(rid["limitation"]["blossom_max_upload_bytes"], LIMIT);
+
+    // Checked before sending
+    let preflight = |len: usize| {
+        let hash = hash_hex(&blob(len, 0));
+        let headers = format!(
+            "{}X-SHA-256: {hash}\r\nX-Content-Length: {len}\r\nX-Content-Type: image/png\r\n",
+            auth(UPLOADER, "upload", &hash)
+        );
+        status(&http(relay.port, "HEAD", "/upload", &headers, b"").0)
+    };
+    assert_eq!(preflight(LIMIT), 200);
+    assert_eq!(preflight(LIMIT + 1), 413);
+
+    // Exactly at the limit is fine, whether or not they say how long it is
+    let (code, descriptor) = put(relay.port, "/upload", UPLOADER, "upload", &blob(LIMIT, 1));
+    assert_eq!(code, 200, "{descriptor}");
+    assert_eq!(descriptor["size"], LIMIT);
+    let data = blob(LIMIT, 2);
+    let upload = |data: &[u8]| {
+        format!(
+            "{}Content-Type: image/png\r\n",
+            auth(UPLOADER, "upload", &hash_hex(data))
+        )
+    };
+    let headers = put_chunked(relay.port, "/upload", &upload(&data), &data);
+    assert_eq!(status(&headers), 200, "{headers}");
+
+    // One byte more is refused, and nothing of it kept
+    let (code, _) = put(
+        relay.port,
+        "/upload",
+        UPLOADER,
+        "upload",
+        &blob(LIMIT + 1, 3),
+    );
+    assert_eq!(code, 413);
+    let data = blob(LIMIT + 1, 4);
+    let headers = put_chunked(relay.port, "/upload", &upload(&data), &data);
+    assert_eq!(status(&headers), 413, "{headers}");
+    for data in [blob(LIMIT + 1, 3), data] {
+        let (headers, _) = http(relay.port, "GET", &format!("/{}", hash_hex(&data)), "", b"");
+        assert_eq!(status(&headers), 404, "{headers}");
+    }
+    let temp = std::fs::read_dir(blobs.path().join("temp"))
+        .unwrap()
+        .count();
+    assert_eq!(temp, 0);
+}
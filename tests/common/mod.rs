@@ -0,0 +1,271 @@
+// Helpers for tests that run the relay binary
+
+#![allow(dead_code)]
+
+use hyper_tungstenite::tungstenite::stream::MaybeTlsStream;
+use hyper_tungstenite::tungstenite::{self, Message, WebSocket};
+use secp256k1::{Keypair, Message as Digest, SECP256K1};
+use serde_json::Value;
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+pub struct Relay {
+    pub child: Child,
+    pub log: Receiver<String>,
+    pub port: u16,
+    pub config_path: std::path::PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A port nothing is listening on (for now)
+pub fn free_port() -> u16 {
+    let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    l.local_addr().unwrap().port()
+}
+
+/// Start the relay on a free port with a fresh data directory. `extra_config` is appended
+/// to a minimal config file.
+pub fn start_relay(extra_config: &str) -> Relay {
+    start_relay_on(free_port(), extra_config)
+}
+
+/// Start the relay on `port` with a fresh data directory. `extra_config` is appended to a
+/// minimal config file (and may configure listeners, as long as one is on `port`).
+pub fn start_relay_on(port: u16, extra_config: &str) -> Relay {
+    let dir = tempfile::tempdir().unwrap();
+
+    let config_path = dir.path().join("config.toml");
+    let config = format!(
+        r#"
+data_directory = "{}"
+ip_address = "127.0.0.1"
+port = {port}
+hostname = "localhost"
+use_tls = false
+enable_ip_blocking = false
+max_connections_per_ip = 50
+server_log_level = "Info"
+{extra_config}
+"#,
+        dir.path().display()
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    let (child, log) = spawn(&config_path, port);
+    Relay {
+        child,
+        log,
+        port,
+        config_path,
+        _dir: dir,
+    }
+}
+
+// Run the relay with `config_path`, collecting its log, and wait until it accepts
+// connections on `port`
+fn spawn(config_path: &std::path::Path, port: u16) -> (Child, Receiver<String>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_chorus"))
+        .arg(config_path)
+        .stderr(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Collect log lines (including those of processes it hands over to, which inherit stderr)
+    let (tx, log) = channel();
+    let stderr = child.stderr.take().unwrap();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    // Wait until it accepts connections
+    let start = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "relay did not start"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    (child, log)
+}
+
+impl Relay {
+    /// Stop the relay and start it again, with the same config and data directory
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let (child, log) = spawn(&self.config_path, self.port);
+        self.child = child;
+        self.log = log;
+    }
+}
+
+pub fn wait_for_log(log: &Receiver<String>, needle: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match log.recv_timeout(remaining) {
+            Ok(line) if line.contains(needle) => return line,
+            Ok(_) => continue,
+            Err(_) => panic!("never logged {needle:?}"),
+        }
+    }
+}
+
+pub fn wait_for_exit(child: &mut Child, within: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < within {
+        if child.try_wait().unwrap().is_some() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+/// A nostr websocket client
+pub struct Client(pub WebSocket<MaybeTlsStream<TcpStream>>);
+
+impl Client {
+    pub fn connect(port: u16) -> Client {
+        let (socket, _response) = tungstenite::connect(format!("ws://127.0.0.1:{port}")).unwrap();
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+        }
+        Client(socket)
+    }
+
+    /// Connect as if through a proxy, which says the client is at `client_ip`
+    pub fn connect_forwarded(port: u16, client_ip: &str) -> Client {
+        use tungstenite::client::IntoClientRequest;
+        let mut request = format!("ws://127.0.0.1:{port}")
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("x-forwarded-for", client_ip.parse().unwrap());
+        let (socket, _response) = tungstenite::connect(request).unwrap();
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+        }
+        Client(socket)
+    }
+
+    /// Connect after sending `preamble` (such as a PROXY protocol header)
+    pub fn connect_after(port: u16, preamble: &[u8]) -> Result<Client, tungstenite::Error> {
+        use std::io::Write;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(preamble).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (socket, _response) = tungstenite::client(
+            format!("ws://127.0.0.1:{port}"),
+            MaybeTlsStream::Plain(stream),
+        )
+        .map_err(|e| match e {
+            tungstenite::HandshakeError::Failure(e) => e,
+            tungstenite::HandshakeError::Interrupted(_) => tungstenite::Error::ConnectionClosed,
+        })?;
+        Ok(Client(socket))
+    }
+
+    pub fn send(&mut self, json: String) {
+        self.0.send(Message::text(json)).unwrap();
+    }
+
+    /// The next nostr message, skipping any AUTH challenge unless that is what we want
+    pub fn recv(&mut self, want_auth: bool) -> Value {
+        loop {
+            if let Message::Text(text) = self.0.read().unwrap() {
+                let value: Value = serde_json::from_str(text.as_str()).unwrap();
+                if want_auth || value[0] != "AUTH" {
+                    return value;
+                }
+            }
+        }
+    }
+}
+
+/// Send `event` and return the OK it is answered with
+pub fn publish(client: &mut Client, event: &str) -> Value {
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    reply
+}
+
+/// The hex id of `event`
+pub fn id_of(event: &str) -> String {
+    serde_json::from_str::<Value>(event).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// The time now, in seconds since the unix epoch
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Sign an event (created now) with a fixed test key (see `sign_event_as`)
+pub fn sign_event(kind: u16, tags: &str, content: &str) -> String {
+    sign_event_as(0x17, kind, tags, content)
+}
+
+/// The hex pubkey of the test key `secret` (every byte of the secret key being `secret`)
+pub fn test_pubkey(secret: u8) -> String {
+    let keypair = Keypair::from_seckey_slice(SECP256K1, &[secret; 32]).unwrap();
+    hex::encode(keypair.x_only_public_key().0.serialize())
+}
+
+/// Sign an event (created now) with the test key `secret`. `tags` is the JSON inside the
+/// tags array; `content` is escaped as needed.
+pub fn sign_event_as(secret: u8, kind: u16, tags: &str, content: &str) -> String {
+    sign_event_at(secret, now(), kind, tags, content)
+}
+
+/// Sign an event created at `created_at` with the test key `secret`
+pub fn sign_event_at(secret: u8, created_at: u64, kind: u16, tags: &str, content: &str) -> String {
+    let keypair = Keypair::from_seckey_slice(SECP256K1, &[secret; 32]).unwrap();
+    let pubkey = hex::encode(keypair.x_only_public_key().0.serialize());
+    let content = serde_json::to_string(content).unwrap();
+    let unsigned = format!(
+        r#"{{"pubkey":"{pubkey}","created_at":{created_at},"kind":{kind},"tags":[{tags}],"content":{content}}}"#
+    );
+    let id = chorus::nostr::compute_event_id(unsigned.as_bytes()).unwrap();
+    let sig = SECP256K1.sign_schnorr_no_aux_rand(&Digest::from_digest(id), &keypair);
+    format!(
+        r#"{{"id":"{}","pubkey":"{pubkey}","created_at":{created_at},"kind":{kind},"tags":[{tags}],"content":{content},"sig":"{}"}}"#,
+        hex::encode(id),
+        hex::encode(sig.serialize())
+    )
+}
@@ -0,0 +1,116 @@
+// Checks the summary logged when a connection closes, and the operational status advertised
+// in the NIP-11 document (rebuilt as it changes)
+
+mod common;
+
+use common::Client;
+use hyper_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use hyper_tungstenite::tungstenite::protocol::CloseFrame;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// The value of `name=` in a summary
+fn field<'a>(line: &'a str, name: &str) -> &'a str {
+    let start = line.find(&format!(" {name}=")).unwrap() + name.len() + 2;
+    line[start..].split(' ').next().unwrap()
+}
+
+#[test]
+fn test_close_summary() {
+    let relay = common::start_relay("open_relay = true\n");
+    let mut client = Client::connect(relay.port);
+
+    for sub in ["a", "b"] {
+        client.send(format!(r#"["REQ","{sub}",{{"kinds":[30000]}}]"#));
+        assert_eq!(client.recv(false)[0], "EOSE");
+    }
+    let event = common::sign_event(1, "", "accepted");
+    assert_eq!(common::publish(&mut client, &event)[2], true);
+    // (a duplicate is answered OK too, but is not counted as accepted)
+    let reply = common::publish(&mut client, &event);
+    assert!(
+        reply[3].as_str().unwrap().starts_with("duplicate:"),
+        "{reply}"
+    );
+    let future = common::now() + 3600;
+    for n in 0..2 {
+        client.send(format!(
+            r#"["EVENT",{}]"#,
+            common::sign_event_at(0x17, future, 1, "", &format!("too new {n}"))
+        ));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], false, "{reply}");
+    }
+
+    client
+        .0
+        .close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        }))
+        .unwrap();
+    while client.0.read().is_ok() {}
+
+    let line = common::wait_for_log(&relay.log, "closed_by=");
+    assert_eq!(field(&line, "frames_in"), "7", "{line}");
+    assert!(
+        field(&line, "frames_out").parse::<u64>().unwrap() >= 7,
+        "{line}"
+    );
+    assert!(
+        field(&line, "bytes_in").parse::<u64>().unwrap() > 0,
+        "{line}"
+    );
+    assert_eq!(field(&line, "events_accepted"), "1", "{line}");
+    assert_eq!(field(&line, "events_duplicate"), "1", "{line}");
+    assert_eq!(field(&line, "events_rejected"), "2", "{line}");
+    assert_eq!(field(&line, "top_rejection"), "\"invalid\"x2", "{line}");
+    assert_eq!(field(&line, "subs_opened"), "2", "{line}");
+    assert_eq!(field(&line, "max_subs"), "2", "{line}");
+    assert_eq!(field(&line, "auth"), "none", "{line}");
+    assert_eq!(field(&line, "close_code"), "1000", "{line}");
+    assert_eq!(field(&line, "close_reason"), "\"bye\"", "{line}");
+    assert_eq!(field(&line, "closed_by"), "client", "{line}");
+}
+
+fn get_status(port: u16) -> Value {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: application/nostr+json\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let rid: Value = serde_json::from_slice(&response[split + 4..]).unwrap();
+    rid["chorus_status"].clone()
+}
+
+#[test]
+fn test_rid_status() {
+    let relay = common::start_relay("open_relay = false\n");
+    let status = get_status(relay.port);
+    assert_eq!(status["mode"], "normal", "{status}");
+    assert_eq!(status["accepting_events"], true, "{status}");
+    assert_eq!(status["overloaded"], false, "{status}");
+    assert_eq!(status["greylisting"], true, "{status}");
+    assert_eq!(status["connections"], "0-9", "{status}");
+
+    // Rebuilt within a few seconds of more connections arriving
+    let clients: Vec<Client> = (0..12).map(|_| Client::connect(relay.port)).collect();
+    let start = Instant::now();
+    while get_status(relay.port)["connections"] != "10-99" {
+        assert!(
+            start.elapsed() < Duration::from_secs(15),
+            "connection count not updated"
+        );
+        std::thread::sleep(Duration::from_millis(250));
+    }
+    drop(clients);
+}
@@ -0,0 +1,201 @@
+// Checks NIP-45 COUNT: every match is counted (not just up to the limit), once, and the
+// count index counts what walking the events would, for whoever asks, including after a
+// request to vanish
+
+mod common;
+
+use chorus::config::Config;
+use chorus::filter::ChorusFilter;
+use chorus::globals::GLOBALS;
+use common::Client;
+use pocket_types::{Event, Id, Pubkey};
+
+const ALICE: u8 = 1;
+const BOB: u8 = 2;
+const CAROL: u8 = 3;
+const DAVE: u8 = 4;
+
+fn count(client: &mut Client, filters: &str) -> u64 {
+    client.send(format!(r#"["COUNT","c",{filters}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "COUNT", "{reply}");
+    reply[2]["count"].as_u64().unwrap()
+}
+
+#[test]
+fn test_count() {
+    let relay = common::start_relay("open_relay = true\nallow_scraping = true\n");
+    let mut client = Client::connect(relay.port);
+
+    for content in ["one", "two", "three"] {
+        client.send(format!(
+            r#"["EVENT",{}]"#,
+            common::sign_event(1, "", content)
+        ));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+    }
+    client.send(format!(r#"["EVENT",{}]"#, common::sign_event(7, "", "+")));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], true, "{reply}");
+
+    assert_eq!(count(&mut client, r#"{"kinds":[1]}"#), 3);
+
+    // The limit does not cap the count
+    assert_eq!(count(&mut client, r#"{"kinds":[1],"limit":1}"#), 3);
+
+    // Events matching several filters are counted once
+    assert_eq!(count(&mut client, r#"{"kinds":[1]},{"kinds":[1,7]}"#), 4);
+
+    assert_eq!(count(&mut client, r#"{"kinds":[30023]}"#), 0);
+}
+
+#[test]
+fn test_count_after_vanish() {
+    let relay = common::start_relay("open_relay = true\nallow_scraping = true\n");
+    let mut client = Client::connect(relay.port);
+    let publish = |client: &mut Client, event: String| {
+        client.send(format!(r#"["EVENT",{event}]"#));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+    };
+
+    publish(&mut client, common::sign_event_as(ALICE, 1, "", "one"));
+    publish(&mut client, common::sign_event_as(ALICE, 1, "", "two"));
+    publish(&mut client, common::sign_event_as(BOB, 1, "", "three"));
+    let by_alice = format!(r#"{{"authors":["{}"]}}"#, common::test_pubkey(ALICE));
+    assert_eq!(count(&mut client, r#"{"kinds":[1]}"#), 3);
+    assert_eq!(count(&mut client, &by_alice), 2);
+
+    // Her events are gone from the count index along with the events
+    publish(
+        &mut client,
+        common::sign_event_as(ALICE, 62, r#"["relay","ALL_RELAYS"]"#, ""),
+    );
+    assert_eq!(count(&mut client, r#"{"kinds":[1]}"#), 1);
+    assert_eq!(count(&mut client, &by_alice), 0);
+}
+
+fn pubkey(secret: u8) -> Pubkey {
+    Pubkey::read_hex(common::test_pubkey(secret).as_bytes()).unwrap()
+}
+
+fn store_as(secret: u8, kind: u16, tags: &str, content: &str) -> Id {
+    let json = common::sign_event_as(secret, kind, tags, content);
+    let mut buffer = vec![0_u8; 4096];
+    let (_size, event) = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    let _ = chorus::store_event(event).unwrap();
+    event.id()
+}
+
+fn count_as(user: Option<Pubkey>, filters: &[String]) -> usize {
+    let filters: Vec<ChorusFilter> = filters
+        .iter()
+        .map(|json| {
+            let mut buffer = vec![0_u8; 4096];
+            ChorusFilter::from_json(json.as_bytes(), &mut buffer)
+                .unwrap()
+                .2
+        })
+        .collect();
+    chorus::count::count(&filters, user).unwrap().0
+}
+
+#[test]
+fn test_count_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        allow_scraping: true,
+        protected_events_author_only: true,
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config;
+    let store = GLOBALS.store.get().unwrap();
+    chorus::count::migrate(store).unwrap();
+
+    // Alice is one of our users, Carol is banned, and Bob and Dave are nobody in particular
+    chorus::add_authorized_user(pubkey(ALICE), false).unwrap();
+    chorus::mark_pubkey_approval(pubkey(CAROL), false).unwrap();
+    let now = common::now();
+    let to_dave = format!(r#"["p","{}"]"#, common::test_pubkey(DAVE));
+
+    let _ = store_as(ALICE, 1, "", "a1");
+    let _ = store_as(ALICE, 1, "", "a2");
+    let _ = store_as(ALICE, 1, r#"["-"]"#, "protected");
+    let _ = store_as(
+        ALICE,
+        1,
+        &format!(r#"["expiration","{}"]"#, now - 10),
+        "expired",
+    );
+    let _ = store_as(ALICE, 7, "", "+");
+    let _ = store_as(BOB, 1, "", "b1");
+    let _ = store_as(BOB, 1, &to_dave, "to dave");
+    let _ = store_as(BOB, 4, &to_dave, "dm");
+    let approved = store_as(BOB, 1, "", "approved");
+    chorus::mark_event_approval(approved, true).unwrap();
+    let deleted = store_as(BOB, 1, "", "deleted");
+    let _ = store_as(
+        BOB,
+        5,
+        &format!(r#"["e","{}"]"#, deleted.as_hex_string()),
+        "",
+    );
+    let _ = store_as(CAROL, 1, "", "banned");
+
+    let (alice, bob) = (common::test_pubkey(ALICE), common::test_pubkey(BOB));
+    let filter_sets: Vec<Vec<String>> = vec![
+        vec![r#"{"kinds":[1]}"#.to_owned()],
+        vec![r#"{"kinds":[1,4,7],"limit":1}"#.to_owned()],
+        vec![format!(r#"{{"authors":["{alice}"]}}"#)],
+        vec![format!(r#"{{"authors":["{bob}"]}}"#)],
+        vec![format!(r#"{{"authors":["{bob}","{bob}"],"kinds":[1]}}"#)],
+        vec![format!(
+            r#"{{"authors":["{alice}","{bob}"],"kinds":[1,5],"since":{},"until":{}}}"#,
+            now - 60,
+            now + 60
+        )],
+        vec![format!(r#"{{"authors":["{alice}"],"until":{}}}"#, now - 60)],
+        vec![
+            r#"{"kinds":[1]}"#.to_owned(),
+            format!(r#"{{"authors":["{bob}"]}}"#),
+        ],
+    ];
+    let users = [
+        None,
+        Some(pubkey(ALICE)),
+        Some(pubkey(BOB)),
+        Some(pubkey(DAVE)),
+    ];
+
+    // From the index
+    let mut counts: Vec<usize> = Vec::new();
+    for filters in filter_sets.iter() {
+        for user in users {
+            counts.push(count_as(user, filters));
+        }
+    }
+    assert_eq!(count_as(None, &filter_sets[0]), 3);
+    assert_eq!(count_as(Some(pubkey(DAVE)), &filter_sets[0]), 4);
+    assert_eq!(count_as(Some(pubkey(ALICE)), &filter_sets[0]), 6);
+
+    // And as if it was never built
+    {
+        let mut txn = store.write_txn().unwrap();
+        store
+            .extra_table("count_meta")
+            .unwrap()
+            .clear(&mut txn)
+            .unwrap();
+        txn.commit().unwrap();
+    }
+    let mut walked: Vec<usize> = Vec::new();
+    for filters in filter_sets.iter() {
+        for user in users {
+            walked.push(count_as(user, filters));
+        }
+    }
+    assert_eq!(counts, walked);
+}
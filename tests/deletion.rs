@@ -0,0 +1,97 @@
+// Checks NIP-09 deletion of addressable events by `a` tag, including a deletion that
+// arrives before (an older version of) the event it deletes
+
+mod common;
+
+use common::Client;
+
+const AUTHOR: u8 = 0x17;
+
+fn article(created_at: u64, d: &str, content: &str) -> String {
+    common::sign_event_at(
+        AUTHOR,
+        created_at,
+        30023,
+        &format!(r#"["d","{d}"]"#),
+        content,
+    )
+}
+
+fn deletion(secret: u8, created_at: u64, d: &str) -> String {
+    let a = format!("30023:{}:{d}", common::test_pubkey(AUTHOR));
+    common::sign_event_at(secret, created_at, 5, &format!(r#"["a","{a}"]"#), "")
+}
+
+// The contents of the articles with d-tag `d`
+fn articles(client: &mut Client, d: &str) -> Vec<String> {
+    client.send(format!(r#"["REQ","q",{{"kinds":[30023],"#d":["{d}"]}}]"#));
+    let mut contents = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => contents.push(message[2]["content"].as_str().unwrap().to_owned()),
+            Some("EOSE") => break,
+            _ => panic!("{message}"),
+        }
+    }
+    client.send(r#"["CLOSE","q"]"#.to_owned());
+    contents
+}
+
+#[test]
+fn test_delete_address() {
+    let relay = common::start_relay("open_relay = true\n");
+    let mut client = Client::connect(relay.port);
+    let now = common::now();
+
+    let reply = common::publish(&mut client, &article(now - 100, "post", "first"));
+    assert_eq!(reply[2], true, "{reply}");
+    assert_eq!(articles(&mut client, "post"), vec!["first"]);
+
+    let reply = common::publish(&mut client, &deletion(AUTHOR, now - 50, "post"));
+    assert_eq!(reply[2], true, "{reply}");
+    assert!(articles(&mut client, "post").is_empty());
+
+    // Versions from before the deletion cannot come back
+    let reply = common::publish(&mut client, &article(now - 60, "post", "second"));
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(
+        reply[3].as_str().unwrap().starts_with("blocked:"),
+        "{reply}"
+    );
+
+    // Later versions are fine
+    let reply = common::publish(&mut client, &article(now, "post", "third"));
+    assert_eq!(reply[2], true, "{reply}");
+    assert_eq!(articles(&mut client, "post"), vec!["third"]);
+}
+
+#[test]
+fn test_deletion_before_event() {
+    let relay = common::start_relay("open_relay = true\n");
+    let mut client = Client::connect(relay.port);
+    let now = common::now();
+
+    let reply = common::publish(&mut client, &deletion(AUTHOR, now - 50, "early"));
+    assert_eq!(reply[2], true, "{reply}");
+
+    let reply = common::publish(&mut client, &article(now - 100, "early", "deleted already"));
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(articles(&mut client, "early").is_empty());
+}
+
+#[test]
+fn test_deletion_by_somebody_else() {
+    let relay = common::start_relay("open_relay = true\n");
+    let mut client = Client::connect(relay.port);
+    let now = common::now();
+
+    let reply = common::publish(&mut client, &article(now - 100, "mine", "still here"));
+    assert_eq!(reply[2], true, "{reply}");
+
+    let _ = common::publish(&mut client, &deletion(0x18, now - 50, "mine"));
+    assert_eq!(articles(&mut client, "mine"), vec!["still here"]);
+
+    let reply = common::publish(&mut client, &article(now - 80, "mine", "newer"));
+    assert_eq!(reply[2], true, "{reply}");
+}
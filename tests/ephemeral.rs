@@ -0,0 +1,41 @@
+// Checks that ephemeral events reach open subscriptions but are never stored
+
+mod common;
+
+use common::Client;
+
+// Publish `event` from one client while another has a matching subscription open, and
+// check the subscriber gets it live but a later REQ does not find it
+fn check_relayed_not_stored(relay: &common::Relay, kind: u16) {
+    let mut subscriber = Client::connect(relay.port);
+    subscriber.send(format!(r#"["REQ","live",{{"kinds":[{kind}]}}]"#));
+    assert_eq!(subscriber.recv(false)[0], "EOSE");
+
+    let event = common::sign_event(kind, "", "here and gone");
+    let mut publisher = Client::connect(relay.port);
+    publisher.send(format!(r#"["EVENT",{event}]"#));
+    let reply = publisher.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], true, "{reply}");
+
+    let live = subscriber.recv(false);
+    assert_eq!(live[0], "EVENT", "{live}");
+    assert_eq!(live[1], "live");
+    assert_eq!(live[2]["id"].as_str().unwrap(), common::id_of(&event));
+
+    publisher.send(format!(r#"["REQ","later",{{"kinds":[{kind}]}}]"#));
+    let reply = publisher.recv(false);
+    assert_eq!(reply[0], "EOSE", "{reply}");
+}
+
+#[test]
+fn test_ephemeral_not_stored() {
+    let relay = common::start_relay("open_relay = true\n");
+    check_relayed_not_stored(&relay, 20001);
+}
+
+#[test]
+fn test_extra_ephemeral_kinds() {
+    let relay = common::start_relay("open_relay = true\nextra_ephemeral_kinds = [7]\n");
+    check_relayed_not_stored(&relay, 7);
+}
@@ -0,0 +1,121 @@
+// Checks NIP-40 expiration: that the index of events by expiration is built for events
+// stored before it existed, that the sweep removes expired events (and only those) from
+// the store and all of its indexes, and that expired events are not served even before
+// they are swept
+
+mod common;
+
+use chorus::config::Config;
+use chorus::globals::GLOBALS;
+use common::Client;
+use pocket_types::{Event, Id};
+use serde_json::Value;
+use std::time::Duration;
+
+// Store an event expiring at `expiration` (if any), returning its id
+fn store_expiring(content: &str, expiration: Option<u64>) -> Id {
+    let tags = match expiration {
+        Some(e) => format!(r#"["t","sweep"],["expiration","{e}"]"#),
+        None => r#"["t","sweep"]"#.to_owned(),
+    };
+    let json = common::sign_event(1, &tags, content);
+    let mut buffer = vec![0_u8; 4096];
+    let (_size, event) = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    let _ = chorus::store_event(event).unwrap();
+    event.id()
+}
+
+fn exported(filter: &str) -> usize {
+    let mut out: Vec<u8> = Vec::new();
+    chorus::jsonl::export(filter.as_bytes(), false, &mut out).unwrap()
+}
+
+#[test]
+fn test_sweep() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config;
+    let store = GLOBALS.store.get().unwrap();
+
+    let expired = store_expiring("expired", Some(common::now() - 10));
+    let later = store_expiring("later", Some(common::now() + 3600));
+    let forever = store_expiring("forever", None);
+
+    // As if stored before the index existed
+    {
+        let mut txn = store.write_txn().unwrap();
+        store
+            .extra_table("expiration_index")
+            .unwrap()
+            .clear(&mut txn)
+            .unwrap();
+        store
+            .extra_table("expiration_index_meta")
+            .unwrap()
+            .clear(&mut txn)
+            .unwrap();
+        txn.commit().unwrap();
+    }
+    assert_eq!(chorus::expiration::sweep().unwrap(), 0);
+    chorus::expiration::migrate(store).unwrap();
+
+    // Only the expired one is removed, from the store and its indexes
+    assert_eq!(chorus::expiration::sweep().unwrap(), 1);
+    assert!(store.get_event_by_id(expired).unwrap().is_none());
+    assert!(store.get_event_by_id(later).unwrap().is_some());
+    assert!(store.get_event_by_id(forever).unwrap().is_some());
+    let author = common::test_pubkey(0x17);
+    assert_eq!(exported("{}"), 2);
+    assert_eq!(exported(r##"{"#t":["sweep"]}"##), 2);
+    assert_eq!(exported(&format!(r#"{{"authors":["{author}"]}}"#)), 2);
+    assert_eq!(
+        exported(&format!(r#"{{"authors":["{author}"],"kinds":[1]}}"#)),
+        2
+    );
+    assert_eq!(
+        exported(&format!(r#"{{"ids":["{}"]}}"#, expired.as_hex_string())),
+        0
+    );
+    let report = chorus::verify_store().unwrap();
+    assert_eq!(report.events, 2);
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+    // And once is enough
+    assert_eq!(chorus::expiration::sweep().unwrap(), 0);
+}
+
+#[test]
+fn test_not_served_once_expired() {
+    // With nothing sweeping
+    let relay = common::start_relay("open_relay = true\nexpiration_sweep_seconds = 0\n");
+    let mut client = Client::connect(relay.port);
+
+    let event = common::sign_event(
+        1,
+        &format!(r#"["expiration","{}"]"#, common::now() + 2),
+        "soon",
+    );
+    let id = common::id_of(&event);
+    client.send(format!(r#"["EVENT",{event}]"#));
+    assert_eq!(client.recv(false)[2], true);
+
+    let mut req = |sub: &str| -> Vec<Value> {
+        client.send(format!(r#"["REQ","{sub}",{{"ids":["{id}"]}}]"#));
+        let mut events = Vec::new();
+        loop {
+            let message = client.recv(false);
+            match message[0].as_str() {
+                Some("EVENT") => events.push(message[2].clone()),
+                Some("EOSE") => return events,
+                _ => panic!("{message}"),
+            }
+        }
+    };
+    assert_eq!(req("before").len(), 1);
+    std::thread::sleep(Duration::from_secs(4));
+    assert!(req("after").is_empty());
+}
@@ -0,0 +1,288 @@
+// Fault injection tests: run randomized workloads against the store while failpoints
+// fire, then check the store is still consistent (and that events whose storing failed
+// left nothing behind in our own indexes).
+//
+// Run with `cargo test --features failpoints`
+
+#![cfg(feature = "failpoints")]
+
+use chorus::config::Config;
+use chorus::failpoints::{self, FailAction};
+use chorus::globals::GLOBALS;
+use pocket_types::{Event, Id};
+use secp256k1::{Keypair, Message, SECP256K1};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+const TITLES: &[&str] = &["alpha", "beta", "gamma", "delta"];
+
+// The store is global, so the scenarios share it and must not run concurrently
+static LOCK: Mutex<()> = Mutex::new(());
+static DATA_DIR: OnceLock<tempfile::TempDir> = OnceLock::new();
+
+fn setup() -> MutexGuard<'static, ()> {
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    failpoints::disarm_all();
+
+    DATA_DIR.get_or_init(|| {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            data_directory: dir.path().to_str().unwrap().to_owned(),
+            indexed_tag_names: vec!["title".to_owned()],
+            ..Default::default()
+        };
+        let store = chorus::setup_store_and_return(&config).unwrap();
+        chorus::tag_index::migrate(&store, &config).unwrap();
+        *GLOBALS.config.write() = config;
+        let _ = GLOBALS.store.set(store);
+        dir
+    });
+
+    guard
+}
+
+// Deterministic pseudo-random generator so failures are reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
+
+// Returns a buffer holding the signed event
+fn make_event(rng: &mut Rng, keypair: &Keypair, titles: &[String]) -> Vec<u8> {
+    let pubkey = hex::encode(keypair.x_only_public_key().0.serialize());
+    let created_at = 1_700_000_000 + rng.next(1_000_000);
+    let content = format!("note {}", rng.next(u64::MAX));
+    let tags = titles
+        .iter()
+        .map(|t| format!(r#"["title","{t}"]"#))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let unsigned = format!(
+        r#"{{"pubkey":"{pubkey}","created_at":{created_at},"kind":1,"tags":[{tags}],"content":"{content}"}}"#
+    );
+    let id = chorus::nostr::compute_event_id(unsigned.as_bytes()).unwrap();
+    let sig = SECP256K1.sign_schnorr_no_aux_rand(&Message::from_digest(id), keypair);
+
+    let json = format!(
+        r#"{{"id":"{}","pubkey":"{pubkey}","created_at":{created_at},"kind":1,"tags":[{tags}],"content":"{content}","sig":"{}"}}"#,
+        hex::encode(id),
+        hex::encode(sig.serialize())
+    );
+
+    let mut buffer = vec![0_u8; 4096];
+    let _ = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    buffer
+}
+
+fn is_stored(id: Id) -> bool {
+    GLOBALS
+        .store
+        .get()
+        .unwrap()
+        .get_event_by_id(id)
+        .unwrap()
+        .is_some()
+}
+
+// Ids the tag index returns for a title that resolve to an event
+fn indexed(title: &str) -> Vec<Id> {
+    let mut ids: Vec<Id> = chorus::tag_index::find_ids("title", title)
+        .unwrap()
+        .into_iter()
+        .filter(|id| is_stored(*id))
+        .collect();
+    ids.sort();
+    ids
+}
+
+// Titles are distinct per workload (seed), since the scenarios share the store
+fn title(rng: &mut Rng, seed: u64) -> String {
+    format!("{}-{seed}", TITLES[rng.next(TITLES.len() as u64) as usize])
+}
+
+#[derive(Default)]
+struct Outcome {
+    stored: usize,
+    store_failures: usize,
+    removed: usize,
+    remove_failures: usize,
+}
+
+// Ingest, query and delete events at random, checking after every operation that what
+// the store serves matches what succeeded
+fn run_workload(seed: u64, operations: usize) -> Outcome {
+    let mut rng = Rng(seed);
+    let keypair = Keypair::from_seckey_slice(SECP256K1, &[0x42; 32]).unwrap();
+    let mut live: HashMap<Id, Vec<String>> = HashMap::new();
+    let mut outcome: Outcome = Default::default();
+
+    for _ in 0..operations {
+        match rng.next(10) {
+            // ingest (with zero, one or two indexed tags)
+            0..=5 => {
+                let titles: Vec<String> = (0..rng.next(3)).map(|_| title(&mut rng, seed)).collect();
+                let bytes = make_event(&mut rng, &keypair, &titles);
+                let event = unsafe { Event::delineate(&bytes).unwrap() };
+                match chorus::store_event(event) {
+                    Ok(_) => {
+                        assert!(is_stored(event.id()));
+                        let _ = live.insert(event.id(), titles);
+                        outcome.stored += 1;
+                    }
+                    Err(_) => {
+                        // A failed store must leave nothing behind to be served
+                        assert!(!is_stored(event.id()));
+                        outcome.store_failures += 1;
+                    }
+                }
+            }
+
+            // query through the tag index
+            6..=7 => {
+                let title = title(&mut rng, seed);
+                let mut expected: Vec<Id> = live
+                    .iter()
+                    .filter(|(_, titles)| titles.contains(&title))
+                    .map(|(id, _)| *id)
+                    .collect();
+                expected.sort();
+                assert_eq!(indexed(&title), expected);
+            }
+
+            // delete
+            _ => {
+                if live.is_empty() {
+                    continue;
+                }
+                let mut ids: Vec<Id> = live.keys().copied().collect();
+                ids.sort();
+                let id = ids[rng.next(ids.len() as u64) as usize];
+                match chorus::remove_event(id) {
+                    Ok(()) => {
+                        assert!(!is_stored(id));
+                        let _ = live.remove(&id);
+                        outcome.removed += 1;
+                    }
+                    Err(_) => {
+                        // Either it was not removed at all, or it was removed but its
+                        // index entries were left behind
+                        if !is_stored(id) {
+                            let _ = live.remove(&id);
+                        }
+                        outcome.remove_failures += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    for id in live.keys() {
+        assert!(is_stored(*id));
+    }
+
+    outcome
+}
+
+fn assert_consistent() -> chorus::VerifyReport {
+    failpoints::disarm_all();
+    let report = chorus::verify_store().unwrap();
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    report
+}
+
+#[test]
+fn test_random_store_failures() {
+    let _guard = setup();
+    failpoints::arm(
+        "store_event",
+        FailAction::Random {
+            seed: 0x0bad_cafe,
+            one_in: 4,
+        },
+    );
+
+    let outcome = run_workload(1, 400);
+    assert!(failpoints::fired("store_event") > 0);
+    assert!(outcome.stored > 0 && outcome.store_failures > 0);
+
+    let _ = assert_consistent();
+}
+
+#[test]
+fn test_failure_between_append_and_index() {
+    let _guard = setup();
+    failpoints::arm("store_event.after_append", FailAction::EveryNth(3));
+
+    let outcome = run_workload(2, 400);
+    assert_eq!(
+        failpoints::fired("store_event.after_append"),
+        outcome.store_failures as u64
+    );
+    assert!(outcome.store_failures > 0);
+
+    // Every event that was appended but not indexed was rolled back
+    let _ = assert_consistent();
+}
+
+#[test]
+fn test_failure_between_indexes() {
+    let _guard = setup();
+    failpoints::arm("store_event.between_indexes", FailAction::EveryNth(2));
+
+    // Events whose tag index entries were written before the failure
+    let keypair = Keypair::from_seckey_slice(SECP256K1, &[0x43; 32]).unwrap();
+    let mut rng = Rng(5);
+    let titles = vec!["between-5".to_owned()];
+    let mut stored: Vec<Id> = Vec::new();
+    for _ in 0..10 {
+        let bytes = make_event(&mut rng, &keypair, &titles);
+        let event = unsafe { Event::delineate(&bytes).unwrap() };
+        match chorus::store_event(event) {
+            Ok(_) => stored.push(event.id()),
+            Err(_) => assert!(!is_stored(event.id())),
+        }
+    }
+    assert_eq!(failpoints::fired("store_event.between_indexes"), 5);
+
+    // Are rolled back without leaving those entries behind
+    let mut found = chorus::tag_index::find_ids("title", "between-5").unwrap();
+    found.sort();
+    stored.sort();
+    assert_eq!(found, stored);
+
+    let _ = assert_consistent();
+}
+
+#[test]
+fn test_index_put_and_remove_failures() {
+    let _guard = setup();
+
+    // Fails part way through indexing events that have two indexed tags
+    failpoints::arm("tag_index.put", FailAction::EveryNth(2));
+    failpoints::arm(
+        "remove_event",
+        FailAction::Random {
+            seed: 0xfeed_f00d,
+            one_in: 3,
+        },
+    );
+    let outcome = run_workload(3, 300);
+    assert!(failpoints::fired("tag_index.put") > 0);
+    assert!(outcome.remove_failures > 0);
+    let _ = assert_consistent();
+
+    // Removals that fail after the event is gone leave stale (but harmless) entries
+    failpoints::arm("remove_event.after_remove", FailAction::Always);
+    let outcome = run_workload(4, 300);
+    assert!(outcome.removed == 0 && outcome.remove_failures > 0);
+    let report = assert_consistent();
+    assert!(report.stale_index_entries > 0);
+}
@@ -0,0 +1,95 @@
+// Checks forwarding to a peer relay (played by the test, which records what it is sent):
+// events are sent in the order they were accepted, even when accepted concurrently, and
+// after the peer or we go away, forwarding resumes without resending or skipping any
+
+mod common;
+
+use common::Client;
+use hyper_tungstenite::tungstenite::{self, Message};
+use serde_json::Value;
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+// Accept one connection and record the ids of the first `count` events it sends,
+// accepting each, then hang up
+fn peer(listener: TcpListener, count: usize, ids: Sender<String>) {
+    let (stream, _) = listener.accept().unwrap();
+    let mut websocket = tungstenite::accept(stream).unwrap();
+    let mut received = 0;
+    while received < count {
+        let Message::Text(text) = websocket.read().unwrap() else {
+            continue;
+        };
+        let message: Value = serde_json::from_str(text.as_str()).unwrap();
+        assert_eq!(message[0], "EVENT", "{message}");
+        let id = message[1]["id"].as_str().unwrap().to_owned();
+        websocket
+            .send(Message::text(format!(r#"["OK","{id}",true,""]"#)))
+            .unwrap();
+        ids.send(id).unwrap();
+        received += 1;
+    }
+    let _ = websocket.close(None);
+    let _ = websocket.flush();
+}
+
+fn start_peer(port: u16, count: usize) -> Receiver<String> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let (tx, rx) = channel();
+    std::thread::spawn(move || peer(listener, count, tx));
+    rx
+}
+
+fn publish_notes(port: u16, secret: u8, count: usize) -> Vec<String> {
+    let mut client = Client::connect(port);
+    let mut ids: Vec<String> = Vec::new();
+    for n in 0..count {
+        let event = common::sign_event_as(secret, 1, "", &format!("note {n}"));
+        let reply = common::publish(&mut client, &event);
+        assert_eq!(reply[2], true, "{reply}");
+        ids.push(common::id_of(&event));
+    }
+    ids
+}
+
+fn received(ids: &Receiver<String>, count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| ids.recv_timeout(Duration::from_secs(30)).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_forward() {
+    let peer_port = common::free_port();
+    let ids = start_peer(peer_port, 40);
+    let mut relay = common::start_relay(&format!(
+        "open_relay = true\nforward_relays = [\"ws://127.0.0.1:{peer_port}\"]\n"
+    ));
+
+    // Four publishers at once, each waiting for its OKs, so each one's events are accepted
+    // in order. Each must arrive once, and in that order.
+    let port = relay.port;
+    let publishers: Vec<_> = (1..=4_u8)
+        .map(|secret| std::thread::spawn(move || publish_notes(port, secret, 10)))
+        .collect();
+    let published: Vec<Vec<String>> = publishers.into_iter().map(|p| p.join().unwrap()).collect();
+    let got = received(&ids, 40);
+    for theirs in published.iter() {
+        let order: Vec<&String> = got.iter().filter(|id| theirs.contains(id)).collect();
+        assert_eq!(order, theirs.iter().collect::<Vec<_>>());
+    }
+
+    // The peer hung up. Events accepted while it is away, and over a restart of ours, are
+    // sent once it is back, and none it already had.
+    let later = publish_notes(relay.port, 5, 5);
+    relay.restart();
+    let ids = start_peer(peer_port, 7);
+    assert_eq!(received(&ids, 5), later);
+    let more = publish_notes(relay.port, 6, 1);
+    assert_eq!(received(&ids, 1), more);
+    assert!(matches!(
+        ids.recv_timeout(Duration::from_secs(1)),
+        Err(RecvTimeoutError::Timeout)
+    ));
+}
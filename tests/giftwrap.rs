@@ -0,0 +1,190 @@
+// Checks that kind 1059 giftwraps are only served (and counted) to their one recipient,
+// and kind 4 DMs to their author and recipients unless private_dms is off
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+
+const WRAPPER: u8 = 9;
+const ALICE: u8 = 1;
+const BOB: u8 = 2;
+const CAROL: u8 = 3;
+
+fn connect_as(port: u16, secret: Option<u8>) -> Client {
+    let mut client = Client::connect(port);
+    let auth = client.recv(true);
+    assert_eq!(auth[0], "AUTH", "{auth}");
+    if let Some(secret) = secret {
+        let event = common::sign_event_as(
+            secret,
+            22242,
+            &format!(r#"["relay","ws://localhost"],["challenge",{}]"#, auth[1]),
+            "",
+        );
+        client.send(format!(r#"["AUTH",{event}]"#));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+    }
+    client
+}
+
+fn p_tag(secret: u8) -> String {
+    format!(r#"["p","{}"]"#, common::test_pubkey(secret))
+}
+
+// The ids served to a REQ, and how it ended
+fn req(client: &mut Client, filter: &str) -> (Vec<String>, Value) {
+    client.send(format!(r#"["REQ","q",{filter}]"#));
+    let mut ids: Vec<String> = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => ids.push(message[2]["id"].as_str().unwrap().to_owned()),
+            _ => {
+                if message[0] == "EOSE" {
+                    client.send(r#"["CLOSE","q"]"#.to_owned());
+                }
+                ids.sort();
+                return (ids, message);
+            }
+        }
+    }
+}
+
+fn count(client: &mut Client, filter: &str) -> Value {
+    client.send(format!(r#"["COUNT","c",{filter}]"#));
+    client.recv(false)
+}
+
+fn is_auth_required(message: &Value) -> bool {
+    message[0] == "CLOSED"
+        && message[2]
+            .as_str()
+            .is_some_and(|m| m.starts_with("auth-required:"))
+}
+
+#[test]
+fn test_giftwrap_visibility() {
+    let relay = common::start_relay("open_relay = true\nallow_scraping = true\n");
+
+    let mut publisher = connect_as(relay.port, None);
+    let event = common::sign_event_as(WRAPPER, 1059, &p_tag(ALICE), "sealed");
+    let reply = common::publish(&mut publisher, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    let to_alice = common::id_of(&event);
+    let event = common::sign_event_as(WRAPPER, 1059, &p_tag(BOB), "sealed");
+    let reply = common::publish(&mut publisher, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    let to_bob = common::id_of(&event);
+    let reply = common::publish(
+        &mut publisher,
+        &common::sign_event_as(
+            WRAPPER,
+            1059,
+            &format!("{},{}", p_tag(ALICE), p_tag(BOB)),
+            "sealed",
+        ),
+    );
+    assert_eq!(reply[2], true, "{reply}");
+    let reply = common::publish(
+        &mut publisher,
+        &common::sign_event_as(WRAPPER, 1059, "", "sealed"),
+    );
+    assert_eq!(reply[2], true, "{reply}");
+    let event = common::sign_event_as(CAROL, 1, "", "hi");
+    let reply = common::publish(&mut publisher, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    let note = common::id_of(&event);
+
+    // Unauthenticated: asking for giftwraps needs AUTH, and other filters leave them out
+    let mut anonymous = connect_as(relay.port, None);
+    let (ids, end) = req(&mut anonymous, r#"{"kinds":[1059]}"#);
+    assert!(ids.is_empty(), "{ids:?}");
+    assert!(is_auth_required(&end), "{end}");
+    assert!(is_auth_required(&count(
+        &mut anonymous,
+        r#"{"kinds":[1059]}"#
+    )));
+    let (ids, _end) = req(&mut anonymous, "{}");
+    assert_eq!(ids, vec![note.clone()]);
+    let reply = count(&mut anonymous, "{}");
+    assert_eq!(reply[2]["count"], 1, "{reply}");
+
+    // Each recipient gets only the giftwrap to them alone, asked for or swept up
+    for (secret, wrap) in [(ALICE, &to_alice), (BOB, &to_bob)] {
+        let mut recipient = connect_as(relay.port, Some(secret));
+        let (ids, end) = req(&mut recipient, r#"{"kinds":[1059]}"#);
+        assert_eq!(ids, vec![wrap.clone()]);
+        assert_eq!(end[0], "EOSE", "{end}");
+        let reply = count(&mut recipient, r#"{"kinds":[1059]}"#);
+        assert_eq!(reply[2]["count"], 1, "{reply}");
+        let (ids, _end) = req(&mut recipient, "{}");
+        let mut expected = vec![wrap.clone(), note.clone()];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    // Not even the author gets them
+    let mut wrapper = connect_as(relay.port, Some(WRAPPER));
+    let (ids, _end) = req(&mut wrapper, r#"{"kinds":[1059]}"#);
+    assert!(ids.is_empty(), "{ids:?}");
+    let reply = count(&mut wrapper, r#"{"kinds":[1059]}"#);
+    assert_eq!(reply[2]["count"], 0, "{reply}");
+
+    // Live giftwraps too
+    let mut alice = connect_as(relay.port, Some(ALICE));
+    let mut bob = connect_as(relay.port, Some(BOB));
+    for (client, wrap) in [(&mut alice, &to_alice), (&mut bob, &to_bob)] {
+        client.send(r#"["REQ","live",{"kinds":[1059]}]"#.to_owned());
+        assert_eq!(client.recv(false)[2]["id"], wrap.as_str());
+        assert_eq!(client.recv(false)[0], "EOSE");
+    }
+    let event = common::sign_event_as(WRAPPER, 1059, &p_tag(BOB), "live");
+    let reply = common::publish(&mut publisher, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    let live_to_bob = common::id_of(&event);
+    let event = common::sign_event_as(WRAPPER, 1059, &p_tag(ALICE), "live");
+    let reply = common::publish(&mut publisher, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    let live_to_alice = common::id_of(&event);
+    assert_eq!(bob.recv(false)[2]["id"], live_to_bob.as_str());
+    assert_eq!(alice.recv(false)[2]["id"], live_to_alice.as_str());
+}
+
+#[test]
+fn test_dm_visibility() {
+    let relay = common::start_relay("open_relay = true\nallow_scraping = true\n");
+    let mut publisher = connect_as(relay.port, None);
+    let event = common::sign_event_as(CAROL, 4, &p_tag(ALICE), "secret");
+    let reply = common::publish(&mut publisher, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    let dm = common::id_of(&event);
+
+    let mut anonymous = connect_as(relay.port, None);
+    let (ids, end) = req(&mut anonymous, r#"{"kinds":[4]}"#);
+    assert!(ids.is_empty(), "{ids:?}");
+    assert!(is_auth_required(&end), "{end}");
+
+    // The recipient and the author may read it, but nobody else
+    for (secret, sees) in [(ALICE, true), (CAROL, true), (BOB, false)] {
+        let mut client = connect_as(relay.port, Some(secret));
+        let (ids, _end) = req(&mut client, r#"{"kinds":[4]}"#);
+        assert_eq!(ids.contains(&dm), sees, "{secret}: {ids:?}");
+        let reply = count(&mut client, r#"{"kinds":[4]}"#);
+        assert_eq!(reply[2]["count"], if sees { 1 } else { 0 }, "{reply}");
+    }
+
+    // Unless DMs are not private
+    let relay =
+        common::start_relay("open_relay = true\nallow_scraping = true\nprivate_dms = false\n");
+    let mut publisher = connect_as(relay.port, None);
+    let event = common::sign_event_as(CAROL, 4, &p_tag(ALICE), "secret");
+    let reply = common::publish(&mut publisher, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    let dm = common::id_of(&event);
+    let mut anonymous = connect_as(relay.port, None);
+    let (ids, end) = req(&mut anonymous, r#"{"kinds":[4]}"#);
+    assert_eq!(ids, vec![dm]);
+    assert_eq!(end[0], "EOSE", "{end}");
+}
@@ -0,0 +1,194 @@
+// Tests for handing the listening socket over to a new process (SIGUSR2)
+
+mod common;
+
+use common::{start_relay, wait_for_exit, wait_for_log, Client};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::time::{Duration, Instant};
+
+// Open a websocket and leave it open: our fake long-lived connection
+fn open_websocket(port: u16) -> TcpStream {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+
+    let mut response: Vec<u8> = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+    stream
+}
+
+// Read frames until a close frame arrives or the socket closes, returning how long it took
+fn wait_for_close(stream: &mut TcpStream) -> Duration {
+    let start = Instant::now();
+    let mut header = [0_u8; 2];
+    loop {
+        if stream.read_exact(&mut header).is_err() {
+            return start.elapsed();
+        }
+        if header[0] & 0x0F == 0x8 {
+            return start.elapsed();
+        }
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut ext = [0_u8; 2];
+                stream.read_exact(&mut ext).unwrap();
+                u16::from_be_bytes(ext) as usize
+            }
+            127 => {
+                let mut ext = [0_u8; 8];
+                stream.read_exact(&mut ext).unwrap();
+                u64::from_be_bytes(ext) as usize
+            }
+            n => n as usize,
+        };
+        let mut payload = vec![0_u8; len];
+        stream.read_exact(&mut payload).unwrap();
+    }
+}
+
+fn signal(pid: u32, sig: libc::c_int) {
+    assert_eq!(unsafe { libc::kill(pid as libc::pid_t, sig) }, 0);
+}
+
+#[test]
+fn test_handover_keeps_accepting_and_drains() {
+    let mut relay = start_relay("handover_grace_seconds = 2");
+    let mut long_lived = open_websocket(relay.port);
+
+    signal(relay.child.id(), libc::SIGUSR2);
+    let line = wait_for_log(&relay.log, "Handed over to pid ");
+    let new_pid: u32 = line
+        .rsplit(' ')
+        .next()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap();
+    assert_ne!(new_pid, relay.child.id());
+
+    // New connections are served (by the new process) while the old one drains
+    let _fresh = open_websocket(relay.port);
+
+    // The long-lived connection is left alone for the grace period, then closed
+    let waited = wait_for_close(&mut long_lived);
+    assert!(waited >= Duration::from_secs(1), "closed after {waited:?}");
+
+    // And the old process exits
+    assert!(wait_for_exit(&mut relay.child, Duration::from_secs(15)));
+
+    // The new process is still serving
+    let _after = open_websocket(relay.port);
+
+    signal(new_pid, libc::SIGTERM);
+}
+
+#[test]
+fn test_failed_handover_keeps_serving() {
+    let mut relay = start_relay("handover_grace_seconds = 2");
+    let _long_lived = open_websocket(relay.port);
+
+    // The new process will fail to load this config and exit before becoming ready
+    std::fs::write(&relay.config_path, "port = \"not a port\"").unwrap();
+
+    signal(relay.child.id(), libc::SIGUSR2);
+    let _ = wait_for_log(&relay.log, "Handover failed");
+
+    // The old process carries on
+    let _fresh = open_websocket(relay.port);
+    assert!(relay.child.try_wait().unwrap().is_none());
+
+    signal(relay.child.id(), libc::SIGTERM);
+    assert!(wait_for_exit(&mut relay.child, Duration::from_secs(15)));
+}
+
+// Play the event sink: answer each POST with a 200, sending on the ids of the events in it
+fn event_sink(listener: TcpListener, ids: Sender<String>) {
+    for stream in listener.incoming() {
+        let mut reader = BufReader::new(stream.unwrap());
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end().to_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0_u8; length];
+        reader.read_exact(&mut body).unwrap();
+        let events: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        for event in events.iter() {
+            ids.send(event["id"].as_str().unwrap().to_owned()).unwrap();
+        }
+        let _ = reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+}
+
+#[test]
+fn test_handover_leaves_the_store_to_the_new_process() {
+    // The event sink is down for now, so what we publish waits in the outbox
+    let sink_port = common::free_port();
+    let mut relay = start_relay(&format!(
+        "open_relay = true\nhandover_grace_seconds = 5\nevent_sink_url = \"http://127.0.0.1:{sink_port}\"\n"
+    ));
+    let mut client = Client::connect(relay.port);
+    let mut published: Vec<String> = Vec::new();
+    for n in 0..3 {
+        let event = common::sign_event_as(1, 1, "", &format!("note {n}"));
+        client.send(format!(r#"["EVENT",{event}]"#));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+        published.push(reply[1].as_str().unwrap().to_owned());
+    }
+
+    signal(relay.child.id(), libc::SIGUSR2);
+    let line = wait_for_log(&relay.log, "Handed over to pid ");
+    let new_pid: u32 = line
+        .rsplit(' ')
+        .next()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap();
+
+    // Once the sink is back, only the new process delivers the outbox (while the old one
+    // drains, it leaves the store alone), so each event arrives once
+    let listener = TcpListener::bind(("127.0.0.1", sink_port)).unwrap();
+    let (tx, ids) = channel();
+    std::thread::spawn(move || event_sink(listener, tx));
+    assert!(wait_for_exit(&mut relay.child, Duration::from_secs(15)));
+    let mut delivered: Vec<String> = (0..published.len())
+        .map(|_| ids.recv_timeout(Duration::from_secs(60)).unwrap())
+        .collect();
+    while let Ok(id) = ids.recv_timeout(Duration::from_secs(3)) {
+        delivered.push(id);
+    }
+    delivered.sort();
+    published.sort();
+    assert_eq!(delivered, published);
+
+    signal(new_pid, libc::SIGTERM);
+}
@@ -0,0 +1,77 @@
+// Checks the /health and /stats endpoints
+
+mod common;
+
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn http_get(port: u16, path: &str, extra_headers: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET {path} HTTP/1.1\r\nHost: localhost\r\n{extra_headers}Connection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (headers, body) = response.split_once("\r\n\r\n").unwrap();
+    (headers.to_owned(), body.to_owned())
+}
+
+#[test]
+fn test_health() {
+    let relay = common::start_relay("");
+
+    let (headers, body) = http_get(relay.port, "/health", "");
+    assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+    let health: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(health["status"], "ok");
+
+    // Not served without a token configured
+    let (headers, _) = http_get(relay.port, "/stats", "");
+    assert!(headers.starts_with("HTTP/1.1 404"), "{headers}");
+}
+
+#[test]
+fn test_health_read_only() {
+    // Healthy, though nothing may be written
+    let relay = common::start_relay("mode = \"read-only\"\n");
+    for _ in 0..3 {
+        let (headers, body) = http_get(relay.port, "/health", "");
+        assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+        let health: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(health["status"], "ok");
+    }
+}
+
+#[test]
+fn test_stats() {
+    let relay = common::start_relay("open_relay = true\nstats_bearer_token = \"sesame\"\n");
+
+    let mut client = common::Client::connect(relay.port);
+    client.send(r#"["REQ","live",{"kinds":[1]}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EOSE");
+
+    let (headers, _) = http_get(relay.port, "/stats", "");
+    assert!(headers.starts_with("HTTP/1.1 401"), "{headers}");
+    let (headers, _) = http_get(relay.port, "/stats", "Authorization: Bearer wrong\r\n");
+    assert!(headers.starts_with("HTTP/1.1 401"), "{headers}");
+
+    let (headers, body) = http_get(relay.port, "/stats", "Authorization: Bearer sesame\r\n");
+    assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+    let stats: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(stats["connections"], 1, "{stats}");
+    assert_eq!(stats["subscriptions"], 1, "{stats}");
+    assert_eq!(stats["relay"]["mode"], "normal", "{stats}");
+    assert!(stats["store"]["events"].is_u64(), "{stats}");
+    assert_eq!(stats["blobs"]["count"], 0, "{stats}");
+}
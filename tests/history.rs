@@ -0,0 +1,139 @@
+// Checks that stored events are served a page at a time without losing or repeating any:
+// across pages that end among events created (or received) in the same second, with each
+// filter's limit applying to it alone. Paging by when events were received with
+// `_received_since` is only for the relay's users.
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+use std::collections::HashSet;
+
+const SECONDS: u64 = 300;
+const START: u64 = 1_700_000_000;
+
+const USER: u8 = 7;
+const STRANGER: u8 = 8;
+
+fn authenticate(client: &mut Client, challenge: &Value, secret: u8) {
+    let auth = common::sign_event_as(
+        secret,
+        22242,
+        &format!(
+            r#"["relay","ws://localhost"],["challenge",{}]"#,
+            challenge[1]
+        ),
+        "",
+    );
+    client.send(format!(r#"["AUTH",{auth}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], true, "{reply}");
+}
+
+// Four events a second, two by each of two authors. Returns (id, created_at, author).
+fn publish_notes(client: &mut Client) -> Vec<(String, u64, u8)> {
+    let mut published = Vec::new();
+    for second in 0..SECONDS {
+        for n in 0..4 {
+            let secret = 1 + (n % 2) as u8;
+            let created_at = START + second;
+            let event = common::sign_event_at(secret, created_at, 1, "", &format!("{second} {n}"));
+            let reply = common::publish(client, &event);
+            assert_eq!(reply[2], true, "{reply}");
+            published.push((common::id_of(&event), created_at, secret));
+        }
+    }
+    published
+}
+
+// The ids and created_ats of the events served for a REQ, up to its EOSE
+fn served(client: &mut Client, req: &str) -> Vec<(String, u64)> {
+    client.send(req.to_owned());
+    let mut events = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => events.push((
+                message[2]["id"].as_str().unwrap().to_owned(),
+                message[2]["created_at"].as_u64().unwrap(),
+            )),
+            Some("EOSE") => return events,
+            _ => panic!("{message}"),
+        }
+    }
+}
+
+#[test]
+fn test_history_pages() {
+    let relay = common::start_relay(&format!(
+        "open_relay = true\n\
+         max_events_per_minute = 0\n\
+         throttling_burst = 104857600\n\
+         throttling_bytes_per_second = 104857600\n\
+         enable_since_seen = true\n\
+         user_hex_keys = [\"{}\"]\n",
+        common::test_pubkey(USER)
+    ));
+    let mut client = Client::connect(relay.port);
+    let challenge = client.recv(true);
+    assert_eq!(challenge[0], "AUTH", "{challenge}");
+    let published = publish_notes(&mut client);
+
+    // The newest 1100 (the newest 275 seconds), and all of the first author's 600, over
+    // several pages of each
+    let events = served(
+        &mut client,
+        &format!(
+            r#"["REQ","both",{{"kinds":[1],"limit":1100}},{{"authors":["{}"],"limit":700}}]"#,
+            common::test_pubkey(1)
+        ),
+    );
+    let expected: HashSet<&String> = published
+        .iter()
+        .filter(|(_, created_at, author)| *created_at >= START + SECONDS - 275 || *author == 1)
+        .map(|(id, _, _)| id)
+        .collect();
+    let ids: HashSet<&String> = events.iter().map(|(id, _)| id).collect();
+    assert_eq!(ids.len(), events.len(), "an event was served twice");
+    assert_eq!(ids, expected);
+    assert!(
+        events.windows(2).all(|w| w[0].1 >= w[1].1),
+        "not newest first"
+    );
+
+    // A limit that ends within a second is kept to
+    let events = served(&mut client, r#"["REQ","some",{"kinds":[1],"limit":1001}]"#);
+    assert_eq!(events.len(), 1001);
+    assert_eq!(events[1000].1, START + SECONDS - 251);
+
+    // In the order they were received, most of them in the same few seconds
+    let events = served(
+        &mut client,
+        r#"["REQ","seen",{"kinds":[1],"since_seen":0,"limit":5000}]"#,
+    );
+    let ids: HashSet<&String> = events.iter().map(|(id, _)| id).collect();
+    assert_eq!(ids.len(), events.len(), "an event was served twice");
+    assert_eq!(ids.len(), published.len());
+
+    // Which the relay's users may also ask for whatever the config, but nobody else
+    let received = r#"["REQ","received",{"kinds":[1],"_received_since":0,"limit":5000}]"#;
+    client.send(received.to_owned());
+    let closed = client.recv(false);
+    assert_eq!(closed[0], "CLOSED", "{closed}");
+    assert!(
+        closed[2].as_str().unwrap().starts_with("auth-required:"),
+        "{closed}"
+    );
+    authenticate(&mut client, &challenge, STRANGER);
+    client.send(received.to_owned());
+    let closed = client.recv(false);
+    assert_eq!(closed[0], "CLOSED", "{closed}");
+    assert!(
+        closed[2].as_str().unwrap().starts_with("restricted:"),
+        "{closed}"
+    );
+    let mut client = Client::connect(relay.port);
+    let challenge = client.recv(true);
+    authenticate(&mut client, &challenge, USER);
+    assert_eq!(served(&mut client, received), events);
+}
@@ -0,0 +1,85 @@
+// Checks the inbox/outbox policy: events by or for our users are accepted, others are
+// not, and a relay list naming us makes its author one of our users (and a later one
+// that leaves us out makes them a stranger again)
+
+mod common;
+
+use common::Client;
+
+const USER: u8 = 0x21;
+const STRANGER: u8 = 0x22;
+
+#[test]
+fn test_inbox_outbox() {
+    let relay = common::start_relay(&format!(
+        "open_relay = false\nusers_from_relay_lists = true\nuser_hex_keys = [\"{}\"]\n",
+        common::test_pubkey(USER)
+    ));
+    let mut client = Client::connect(relay.port);
+    let challenge = client.recv(true);
+    assert_eq!(challenge[0], "AUTH", "{challenge}");
+
+    // A stranger's note is not for anybody here (though they are asked to AUTH, in case
+    // they are one of our authorized users)
+    let note = common::sign_event_as(STRANGER, 1, "", "hello?");
+    let reply = common::publish(&mut client, &note);
+    assert_eq!(reply[2], false);
+    assert_eq!(reply[3], "blocked: not accepted here");
+    assert_eq!(client.recv(true), challenge);
+
+    // They did, and are still nobody here
+    let auth = common::sign_event_as(
+        STRANGER,
+        22242,
+        &format!(
+            r#"["relay","ws://localhost"],["challenge",{}]"#,
+            challenge[1]
+        ),
+        "",
+    );
+    client.send(format!(r#"["AUTH",{auth}]"#));
+    assert_eq!(client.recv(false)[2], true);
+    let reply = common::publish(&mut client, &note);
+    assert_eq!(reply[2], false);
+    assert_eq!(reply[3], "blocked: not accepted here");
+
+    // Unless it tags one of our users (their inbox)
+    let tags = format!(r#"["p","{}"]"#, common::test_pubkey(USER));
+    let reply = common::publish(
+        &mut client,
+        &common::sign_event_as(STRANGER, 1, &tags, "hi"),
+    );
+    assert_eq!(reply[2], true, "{reply}");
+
+    // Our users' own events are accepted (their outbox)
+    let reply = common::publish(&mut client, &common::sign_event_as(USER, 1, "", "mine"));
+    assert_eq!(reply[2], true, "{reply}");
+
+    // A relay list that names us makes the stranger one of our users
+    let reply = common::publish(
+        &mut client,
+        &common::sign_event_as(STRANGER, 10002, r#"["r","ws://localhost/"]"#, ""),
+    );
+    assert_eq!(reply[2], true, "{reply}");
+    let reply = common::publish(
+        &mut client,
+        &common::sign_event_as(STRANGER, 1, "", "moved in"),
+    );
+    assert_eq!(reply[2], true, "{reply}");
+
+    // Until their newer relay list leaves us out
+    let list = common::sign_event_at(
+        STRANGER,
+        common::now() + 1,
+        10002,
+        r#"["r","wss://elsewhere.example/"]"#,
+        "",
+    );
+    let reply = common::publish(&mut client, &list);
+    assert_eq!(reply[2], true, "{reply}");
+    let reply = common::publish(
+        &mut client,
+        &common::sign_event_as(STRANGER, 1, "", "moved out"),
+    );
+    assert_eq!(reply[2], false, "{reply}");
+}
@@ -0,0 +1,74 @@
+// Checks that violations outside of websocket sessions count against an IP's reputation as
+// they happen: a ban for exceeding rate limits is made through it (lasting at least
+// `rate_limit_ban_seconds`), and unix domain socket peers, who share an address, are
+// never banned by it
+
+use chorus::config::Config;
+use chorus::error::ChorusError;
+use chorus::globals::GLOBALS;
+use chorus::ip::{HashedIp, HashedPeer, Violation, UNIX_SOCKET_PEER};
+use chorus::rate_limit::Action;
+use pocket_types::Time;
+
+// The errors of taking a token for an event, `times` times in a row
+fn hit(ip: HashedIp, times: usize) -> Vec<Option<ChorusError>> {
+    (0..times)
+        .map(|_| {
+            chorus::rate_limit::check(Action::Event, ip, None)
+                .err()
+                .map(|e| e.inner)
+        })
+        .collect()
+}
+
+#[test]
+fn test_ip_violations() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        ip_ban_threshold: 3,
+        max_events_per_minute: 1,
+        rate_limit_ban_after: 2,
+        rate_limit_ban_seconds: 600,
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config;
+
+    // Failed authorizations add up to a ban
+    let ip = HashedIp::new("192.0.2.1".parse().unwrap());
+    assert_eq!(
+        chorus::add_ip_violation(ip, Violation::AuthFailure, 0).unwrap(),
+        0
+    );
+    assert_eq!(
+        chorus::add_ip_violation(ip, Violation::AuthFailure, 0).unwrap(),
+        0
+    );
+    assert_eq!(
+        chorus::add_ip_violation(ip, Violation::AuthFailure, 0).unwrap(),
+        60
+    );
+    assert!(chorus::get_ip_data(ip).unwrap().is_banned());
+
+    // Exceeding rate limits bans through the reputation
+    let ip = HashedIp::new("192.0.2.2".parse().unwrap());
+    let errors = hit(ip, 3);
+    assert!(errors[0].is_none());
+    assert!(matches!(errors[1], Some(ChorusError::RateLimited(_))));
+    assert!(matches!(errors[2], Some(ChorusError::RateLimitExceeded)));
+    let ip_data = chorus::get_ip_data(ip).unwrap();
+    assert!(ip_data.ban_until >= Time::now().as_u64() + 599);
+    assert_eq!(ip_data.reputation.score, 0.5);
+
+    // But not for unix domain socket peers
+    let ip = HashedPeer::new(UNIX_SOCKET_PEER).ip();
+    for error in hit(ip, 5).into_iter().skip(1) {
+        assert!(matches!(error, Some(ChorusError::RateLimited(_))));
+    }
+    assert_eq!(
+        chorus::add_ip_violation(ip, Violation::AuthFailure, 600).unwrap(),
+        600
+    );
+    assert!(!chorus::get_ip_data(ip).unwrap().is_banned());
+}
@@ -0,0 +1,52 @@
+// Checks structured logging: with json_logs, lines are JSON objects, and those about a
+// connection carry its trace id throughout
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+
+fn wait_for_json(relay: &common::Relay, needle: &str) -> Value {
+    let line = common::wait_for_log(&relay.log, needle);
+    serde_json::from_str(&line).unwrap_or_else(|_| panic!("not JSON: {line}"))
+}
+
+#[test]
+fn test_json_logs() {
+    let relay =
+        common::start_relay("open_relay = true\njson_logs = true\nclient_log_level = \"Debug\"\n");
+    let mut client = Client::connect(relay.port);
+
+    client.send(r#"["REQ","trace-me",{"kinds":[1],"limit":1}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EOSE");
+    let req = wait_for_json(&relay, r#""sub_id":"trace-me""#);
+    assert_eq!(req["msg_type"], "REQ", "{req}");
+    assert!(
+        req["msg"]
+            .as_str()
+            .unwrap()
+            .contains(r#"{"kinds":[1],"limit":1}"#),
+        "{req}"
+    );
+    assert!(req["latency_ms"].is_u64(), "{req}");
+    assert!(req["peer_hash"].is_string(), "{req}");
+    assert!(req["ts"].is_string(), "{req}");
+    let trace = req["trace"].as_str().unwrap().to_owned();
+
+    // The rest of the connection has the same trace id
+    client.send(r#"["CLOSE","trace-me"]"#.to_owned());
+    let close = wait_for_json(&relay, r#""msg_type":"CLOSE""#);
+    assert_eq!(close["trace"], trace.as_str(), "{close}");
+    assert_eq!(close["sub_id"], "trace-me", "{close}");
+
+    drop(client);
+    let disconnect = wait_for_json(&relay, r#""msg_type":"DISCONNECT""#);
+    assert_eq!(disconnect["trace"], trace.as_str(), "{disconnect}");
+
+    // Another connection has another
+    let mut other = Client::connect(relay.port);
+    other.send(r#"["REQ","other",{"kinds":[1],"limit":1}]"#.to_owned());
+    assert_eq!(other.recv(false)[0], "EOSE");
+    let req = wait_for_json(&relay, r#""sub_id":"other""#);
+    assert_ne!(req["trace"], trace.as_str(), "{req}");
+}
@@ -0,0 +1,48 @@
+// Checks exporting and importing events as line-delimited JSON: counts, filters, and that
+// importing again is harmless
+
+mod common;
+
+use chorus::config::Config;
+use chorus::globals::GLOBALS;
+
+#[test]
+fn test_export_import() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config;
+
+    let note = common::sign_event_as(1, 1, "", "a note");
+    let reaction = common::sign_event_as(2, 7, "", "+");
+    let forged = common::sign_event_as(3, 1, "", "forged").replace("forged", "altered");
+    let input = format!("{note}\n{reaction}\n\n{note}\n{forged}\nnot json\n");
+
+    let report = chorus::jsonl::import(input.as_bytes()).unwrap();
+    assert_eq!(report.accepted, 2);
+    assert_eq!(report.duplicate, 1);
+    assert_eq!(report.invalid, 2);
+    assert_eq!(report.rejected, 0);
+
+    let mut out: Vec<u8> = Vec::new();
+    assert_eq!(chorus::jsonl::export(b"{}", false, &mut out).unwrap(), 2);
+    assert_eq!(out.iter().filter(|b| **b == b'\n').count(), 2);
+
+    let mut out: Vec<u8> = Vec::new();
+    assert_eq!(
+        chorus::jsonl::export(br#"{"kinds":[7]}"#, false, &mut out).unwrap(),
+        1
+    );
+    let exported: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(exported["content"], "+");
+
+    // An export imports again as nothing new
+    let mut out: Vec<u8> = Vec::new();
+    let _ = chorus::jsonl::export(b"{}", false, &mut out).unwrap();
+    let report = chorus::jsonl::import(out.as_slice()).unwrap();
+    assert_eq!(report.accepted, 0);
+    assert_eq!(report.duplicate, 2);
+}
@@ -0,0 +1,100 @@
+// Checks exporting events by when they were first received (since_seen and until_seen),
+// with when that was, that such an export imports again, that when they were received is
+// kept whether or not since_seen is enabled, and that events stored before the index was
+// built count as received when it was
+
+mod common;
+
+use chorus::config::Config;
+use chorus::globals::GLOBALS;
+use serde_json::Value;
+
+fn export(filter: &str) -> Vec<Value> {
+    let mut out: Vec<u8> = Vec::new();
+    let count = chorus::jsonl::export(filter.as_bytes(), true, &mut out).unwrap();
+    let lines: Vec<Value> = out
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), count);
+    lines
+}
+
+#[test]
+fn test_export_seen() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        enable_since_seen: true,
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config;
+
+    // Received in this order, whatever they say about when they were created
+    let start = common::now();
+    let backdated = common::sign_event_at(1, 1_000_000, 1, "", "backdated");
+    let note = common::sign_event_as(2, 1, "", "a note");
+    let input = format!("{backdated}\n{note}\n");
+    let report = chorus::jsonl::import(input.as_bytes()).unwrap();
+    assert_eq!(report.accepted, 2);
+
+    let lines = export(&format!(r#"{{"since_seen":{start}}}"#));
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["event"]["content"], "backdated");
+    assert_eq!(lines[1]["event"]["content"], "a note");
+    for line in lines.iter() {
+        assert!(line["seen_at"].as_u64().unwrap() >= start);
+    }
+
+    // Bounded by until_seen, with or without since_seen
+    assert!(export(&format!(r#"{{"until_seen":{}}}"#, start - 1)).is_empty());
+    let until = common::now() + 1;
+    assert_eq!(
+        export(&format!(
+            r#"{{"since_seen":{start},"until_seen":{until},"kinds":[1]}}"#
+        ))
+        .len(),
+        2
+    );
+
+    // Other filters are served as before, with when they were received too
+    let lines = export("{}");
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().all(|line| line["seen_at"].is_u64()));
+
+    // An export like that imports again as nothing new
+    let mut out: Vec<u8> = Vec::new();
+    let _ = chorus::jsonl::export(b"{}", true, &mut out).unwrap();
+    let report = chorus::jsonl::import(out.as_slice()).unwrap();
+    assert_eq!(report.accepted, 0);
+    assert_eq!(report.duplicate, 2);
+    assert_eq!(report.invalid, 0);
+
+    // Kept with since_seen disabled, for exports by when they were received
+    GLOBALS.config.write().enable_since_seen = false;
+    let lines = export(&format!(r#"{{"_received_since":{start}}}"#));
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["event"]["content"], "backdated");
+    for line in lines.iter() {
+        assert!(line["seen_at"].as_u64().unwrap() >= start, "{line}");
+    }
+
+    // Built for a store from before we kept it, however old the events say they are
+    let store = GLOBALS.store.get().unwrap();
+    {
+        let mut txn = store.write_txn().unwrap();
+        for table in ["first_seen", "first_seen_ids", "first_seen_meta"] {
+            store.extra_table(table).unwrap().clear(&mut txn).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+    let built = common::now();
+    chorus::first_seen::migrate(store).unwrap();
+    let lines = export("{}");
+    assert_eq!(lines.len(), 2);
+    for line in lines.iter() {
+        assert!(line["seen_at"].as_u64().unwrap() >= built, "{line}");
+    }
+}
@@ -0,0 +1,227 @@
+// Checks that chorus serves several listeners, TCP and unix domain socket, each only the
+// services configured for it, and that it will not start if a listener cannot bind: a
+// socket file left behind is replaced, but not one that is still accepted on
+
+mod common;
+
+use common::Client;
+use hyper_tungstenite::tungstenite::{self, Message};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+fn http_get<S: Read + Write>(mut stream: S, accept: &str) -> String {
+    stream
+        .write_all(
+            format!(
+                "GET / HTTP/1.1\r\nHost: localhost\r\nAccept: {accept}\r\nConnection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn tcp(port: u16) -> TcpStream {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+}
+
+fn unix(path: &std::path::Path) -> UnixStream {
+    let stream = UnixStream::connect(path).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+}
+
+#[test]
+fn test_listener_services() {
+    let port = common::free_port();
+    let nip11_port = common::free_port();
+    let socket_dir = tempfile::tempdir().unwrap();
+    let socket_path = socket_dir.path().join("chorus.sock");
+
+    let relay = common::start_relay_on(
+        port,
+        &format!(
+            r#"
+open_relay = true
+
+[[listeners]]
+ip_address = "127.0.0.1"
+port = {port}
+
+[[listeners]]
+ip_address = "127.0.0.1"
+port = {nip11_port}
+services = [ "nip11" ]
+
+[[listeners]]
+unix_path = "{}"
+services = [ "relay" ]
+"#,
+            socket_path.display()
+        ),
+    );
+
+    // The NIP-11 only listener serves NIP-11, but not websockets
+    let response = http_get(tcp(nip11_port), "application/nostr+json");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    match tungstenite::client(format!("ws://127.0.0.1:{nip11_port}"), tcp(nip11_port)) {
+        Err(tungstenite::HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+            assert_eq!(response.status(), 404)
+        }
+        Err(e) => panic!("{e}"),
+        Ok(_) => panic!("websocket served on the NIP-11 only listener"),
+    }
+
+    // The unix domain socket serves websockets, but not NIP-11
+    let response = http_get(unix(&socket_path), "application/nostr+json");
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    let (mut socket, _response) = tungstenite::client("ws://localhost/", unix(&socket_path))
+        .unwrap_or_else(|e| panic!("{e}"));
+    let event = common::sign_event(1, "", "over a unix socket");
+    socket
+        .send(Message::text(format!(r#"["EVENT",{event}]"#)))
+        .unwrap();
+    let reply: Value = loop {
+        if let Message::Text(text) = socket.read().unwrap() {
+            let value: Value = serde_json::from_str(text.as_str()).unwrap();
+            if value[0] != "AUTH" {
+                break value;
+            }
+        }
+    };
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], true, "{reply}");
+
+    // The listener serving everything sees what came in over the unix socket
+    let mut client = Client::connect(relay.port);
+    client.send(r#"["REQ","q",{"kinds":[1]}]"#.to_owned());
+    let message = client.recv(false);
+    assert_eq!(message[0], "EVENT", "{message}");
+    assert_eq!(message[2]["content"], "over a unix socket");
+    let response = http_get(tcp(port), "application/nostr+json");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
+
+#[test]
+fn test_listener_bind_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken_port = taken.local_addr().unwrap().port();
+
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+data_directory = "{}"
+hostname = "localhost"
+
+[[listeners]]
+ip_address = "127.0.0.1"
+port = {}
+
+[[listeners]]
+ip_address = "127.0.0.1"
+port = {taken_port}
+"#,
+            dir.path().display(),
+            common::free_port()
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_chorus"))
+        .arg(&config_path)
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("listener 127.0.0.1:{taken_port}: cannot bind")),
+        "{stderr}"
+    );
+}
+
+fn unix_listener_config(
+    dir: &std::path::Path,
+    socket_path: &std::path::Path,
+) -> std::path::PathBuf {
+    let config_path = dir.join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+data_directory = "{}"
+hostname = "localhost"
+
+[[listeners]]
+unix_path = "{}"
+"#,
+            dir.display(),
+            socket_path.display()
+        ),
+    )
+    .unwrap();
+    config_path
+}
+
+#[test]
+fn test_unix_socket_in_use() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket_path = dir.path().join("chorus.sock");
+    let _taken = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_chorus"))
+        .arg(unix_listener_config(dir.path(), &socket_path))
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is in use"), "{stderr}");
+
+    // Still theirs
+    let _ = unix(&socket_path);
+}
+
+#[test]
+fn test_unix_socket_stale() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket_path = dir.path().join("chorus.sock");
+    drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+    assert!(socket_path.exists());
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_chorus"))
+        .arg(unix_listener_config(dir.path(), &socket_path))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    let response = loop {
+        if let Ok(stream) = UnixStream::connect(&socket_path) {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            break http_get(stream, "application/nostr+json");
+        }
+        assert!(std::time::Instant::now() < deadline, "not listening");
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    let _ = child.kill();
+    let _ = child.wait();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
@@ -0,0 +1,44 @@
+// Checks the offline maintenance tools: that they refuse to run while chorus has the store
+// open, and that chorus_verify finds a healthy store clean
+
+mod common;
+
+use common::Client;
+use std::process::Command;
+
+fn run(tool: &str, relay: &common::Relay, args: &[&str]) -> i32 {
+    Command::new(tool)
+        .arg(&relay.config_path)
+        .args(args)
+        .output()
+        .unwrap()
+        .status
+        .code()
+        .unwrap()
+}
+
+#[test]
+fn test_verify() {
+    let mut relay = common::start_relay("open_relay = true\n");
+
+    let mut client = Client::connect(relay.port);
+    for (kind, tags) in [(1, ""), (1, r#"["t","nostr"]"#), (7, r#"["k","1"]"#)] {
+        let event = common::sign_event(kind, tags, "hello");
+        client.send(format!(r#"["EVENT",{event}]"#));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+    }
+
+    // Not while chorus is running
+    assert_eq!(run(env!("CARGO_BIN_EXE_chorus_verify"), &relay, &[]), 3);
+    assert_eq!(run(env!("CARGO_BIN_EXE_chorus_compress"), &relay, &[]), 3);
+
+    let _ = relay.child.kill();
+    let _ = relay.child.wait();
+
+    assert_eq!(run(env!("CARGO_BIN_EXE_chorus_verify"), &relay, &[]), 0);
+    assert_eq!(
+        run(env!("CARGO_BIN_EXE_chorus_verify"), &relay, &["--repair"]),
+        0
+    );
+}
@@ -0,0 +1,144 @@
+// Checks read-only and maintenance modes: writes refused while reads carry on, and
+// maintenance turning away new connections and HTTP requests
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn http_get(port: u16, accept: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET / HTTP/1.1\r\nHost: localhost\r\nAccept: {accept}\r\nConnection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (headers, body) = response.split_once("\r\n\r\n").unwrap();
+    (headers.to_owned(), body.to_owned())
+}
+
+#[test]
+fn test_read_only() {
+    let relay = common::start_relay("open_relay = true\n");
+    let mut client = Client::connect(relay.port);
+
+    client.send(r#"["REQ","live",{"kinds":[1]}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EOSE");
+
+    // Switch by reloading the config
+    let mut config = std::fs::read_to_string(&relay.config_path).unwrap();
+    config.push_str("mode = \"read-only\"\n");
+    std::fs::write(&relay.config_path, config).unwrap();
+    assert_eq!(
+        unsafe { libc::kill(relay.child.id() as libc::pid_t, libc::SIGHUP) },
+        0
+    );
+    common::wait_for_log(&relay.log, "to read-only mode");
+
+    let event = common::sign_event(1, "", "hello");
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], false, "{reply}");
+    assert_eq!(reply[3], "error: relay is in read-only mode");
+
+    // Reads carry on, and the subscription is still open
+    client.send(r#"["REQ","again",{"kinds":[1]}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EOSE");
+
+    let (_, body) = http_get(relay.port, "application/nostr+json");
+    let rid: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(rid["limitation"]["read_only"], true);
+    assert_eq!(rid["chorus_status"]["mode"], "read-only");
+}
+
+#[test]
+fn test_maintenance() {
+    let relay = common::start_relay("open_relay = true\nmode = \"maintenance\"\n");
+
+    let mut client = Client::connect(relay.port);
+    let notice = client.recv(false);
+    assert_eq!(notice[0], "NOTICE", "{notice}");
+    assert!(notice[1].as_str().unwrap().contains("maintenance"));
+
+    let (headers, _) = http_get(relay.port, "text/html");
+    assert!(headers.starts_with("HTTP/1.1 503"), "{headers}");
+    assert!(headers.to_lowercase().contains("retry-after"));
+
+    // NIP-11 still says what is going on
+    let (headers, body) = http_get(relay.port, "application/nostr+json");
+    assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+    let rid: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(rid["chorus_status"]["mode"], "maintenance");
+}
+
+const ADMIN: u8 = 0x42;
+
+fn command(client: &mut Client, content: &str) -> Value {
+    client.send(format!(
+        r#"["EVENT",{}]"#,
+        common::sign_event_as(
+            ADMIN,
+            chorus::admin::ADMIN_COMMAND_KIND,
+            r#"["relay","ws://localhost"]"#,
+            content
+        )
+    ));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    reply
+}
+
+#[test]
+fn test_read_only_leaves_the_store_alone() {
+    let relay = common::start_relay(&format!(
+        "open_relay = true\nexpiration_sweep_seconds = 1\nadmin_hex_keys = [\"{}\"]\n",
+        common::test_pubkey(ADMIN)
+    ));
+    let mut client = Client::connect(relay.port);
+
+    let expiration = common::now() + 2;
+    let event = common::sign_event(1, &format!(r#"["expiration","{expiration}"]"#), "soon");
+    client.send(format!(r#"["EVENT",{event}]"#));
+    assert_eq!(client.recv(false)[2], true);
+
+    let reply = command(
+        &mut client,
+        r#"{"method":"setmode","params":["read-only"]}"#,
+    );
+    assert_eq!(reply[2], true, "{reply}");
+
+    // Management commands that would change the store are refused
+    let banned = common::test_pubkey(0x33);
+    let ban = format!(r#"{{"method":"banpubkey","params":["{banned}"]}}"#);
+    let reply = command(&mut client, &ban);
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(reply[3].as_str().unwrap().contains("read-only"), "{reply}");
+
+    // And the expired event is not swept, however often the sweeper comes round
+    let deadline = std::time::Instant::now() + Duration::from_secs(4);
+    while let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) {
+        if let Ok(line) = relay.log.recv_timeout(remaining) {
+            assert!(!line.contains("expired events"), "{line}");
+        }
+    }
+
+    // Until we are back to normal
+    let reply = command(&mut client, r#"{"method":"setmode","params":["normal"]}"#);
+    assert_eq!(reply[2], true, "{reply}");
+    common::wait_for_log(&relay.log, "Removed 1 expired events");
+    let reply = command(&mut client, &ban);
+    assert_eq!(reply[2], true, "{reply}");
+}
@@ -0,0 +1,60 @@
+// Checks the bounds on negentropy (NIP-77) sessions
+
+mod common;
+
+use common::Client;
+
+#[test]
+fn test_negentropy_limits() {
+    let relay = common::start_relay(
+        "open_relay = true\nenable_negentropy = true\nmax_subscriptions = 2\nnegentropy_max_items = 2\n",
+    );
+    let mut client = Client::connect(relay.port);
+
+    for content in ["one", "two", "three"] {
+        client.send(format!(
+            r#"["EVENT",{}]"#,
+            common::sign_event(1, "", content)
+        ));
+        assert_eq!(client.recv(false)[2], true);
+    }
+
+    // Too many matching events
+    client.send(r#"["NEG-OPEN","a",{"kinds":[1]},"61"]"#.to_owned());
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "NEG-ERR", "{reply}");
+    assert!(
+        reply[2].as_str().unwrap().starts_with("blocked:"),
+        "{reply}"
+    );
+
+    // However the store looks for them (here over an index range per author and kind)
+    client.send(format!(
+        r#"["NEG-OPEN","a",{{"authors":["{}","{}"],"kinds":[1,7]}},"61"]"#,
+        common::test_pubkey(0x17),
+        common::test_pubkey(0x18)
+    ));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "NEG-ERR", "{reply}");
+    assert!(
+        reply[2].as_str().unwrap().starts_with("blocked:"),
+        "{reply}"
+    );
+
+    // Within bounds
+    client.send(r#"["NEG-OPEN","a",{"kinds":[1],"limit":2},"61"]"#.to_owned());
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "NEG-MSG", "{reply}");
+
+    // A REQ plus the session fill max_subscriptions
+    client.send(r#"["REQ","r",{"kinds":[1],"limit":1}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EVENT");
+    assert_eq!(client.recv(false)[0], "EOSE");
+    client.send(r#"["NEG-OPEN","b",{"kinds":[1],"limit":2},"61"]"#.to_owned());
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "NEG-ERR", "{reply}");
+    assert!(
+        reply[2].as_str().unwrap().starts_with("blocked:"),
+        "{reply}"
+    );
+}
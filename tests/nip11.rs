@@ -0,0 +1,120 @@
+// Checks the NIP-11 relay information document, and the icon and banner served from the
+// data directory
+
+mod common;
+
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+fn http_get(port: u16, path: &str, accept: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET {path} HTTP/1.1\r\nHost: localhost\r\nAccept: {accept}\r\nConnection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let headers = String::from_utf8(response[..split].to_vec()).unwrap();
+    (headers, response[split + 4..].to_vec())
+}
+
+fn get_rid(port: u16) -> Value {
+    let (headers, body) = http_get(port, "/", "application/nostr+json");
+    assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn test_rid_fields() {
+    let relay = common::start_relay(
+        r#"
+name = "The \"best\" relay"
+description = "Quotes \" and backslashes \\ and\nnewlines"
+relay_countries = [ "CA", "US" ]
+language_tags = [ "en" ]
+tags = [ "sfw-only" ]
+posting_policy = "https://example.com/posting-policy"
+max_filters = 3
+
+[fees]
+admission = [ { amount = 1000000, unit = "msats" } ]
+publication = [ { kinds = [4], amount = 100, unit = "msats" } ]
+"#,
+    );
+
+    let rid = get_rid(relay.port);
+    assert_eq!(rid["name"], "The \"best\" relay");
+    assert_eq!(
+        rid["description"],
+        "Quotes \" and backslashes \\ and\nnewlines"
+    );
+    assert_eq!(rid["relay_countries"], serde_json::json!(["CA", "US"]));
+    assert_eq!(rid["language_tags"], serde_json::json!(["en"]));
+    assert_eq!(rid["tags"], serde_json::json!(["sfw-only"]));
+    assert_eq!(rid["posting_policy"], "https://example.com/posting-policy");
+    assert!(rid["payments_url"].is_null(), "{rid}");
+    assert_eq!(
+        rid["fees"],
+        serde_json::json!({
+            "admission": [{ "amount": 1000000, "unit": "msats" }],
+            "publication": [{ "kinds": [4], "amount": 100, "unit": "msats" }],
+        })
+    );
+    assert_eq!(rid["limitation"]["max_filters"], 3);
+    assert_eq!(rid["limitation"]["max_limit"], 5000);
+    assert_eq!(rid["limitation"]["default_limit"], 500);
+    assert!(rid.get("icon").is_none(), "{rid}");
+
+    // The advertised limit is the enforced one
+    let mut client = common::Client::connect(relay.port);
+    client.send(r#"["REQ","three",{"kinds":[1]},{"kinds":[2]},{"kinds":[3]}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EOSE");
+    client.send(
+        r#"["REQ","four",{"kinds":[1]},{"kinds":[2]},{"kinds":[3]},{"kinds":[4]}]"#.to_owned(),
+    );
+    let closed = client.recv(false);
+    assert_eq!(closed[0], "CLOSED", "{closed}");
+    assert_eq!(closed[2], "invalid: Too many filters", "{closed}");
+}
+
+#[test]
+fn test_icon() {
+    let relay = common::start_relay("");
+
+    let (headers, _) = http_get(relay.port, "/icon", "*/*");
+    assert!(headers.starts_with("HTTP/1.1 404"), "{headers}");
+
+    let png = b"\x89PNG\r\n\x1a\nnot really a png".to_vec();
+    let data_directory = relay.config_path.parent().unwrap();
+    std::fs::write(data_directory.join("icon.png"), &png).unwrap();
+
+    let (headers, body) = http_get(relay.port, "/icon", "*/*");
+    assert!(headers.starts_with("HTTP/1.1 200"), "{headers}");
+    let headers = headers.to_lowercase();
+    assert!(headers.contains("content-type: image/png"), "{headers}");
+    assert!(headers.contains("cache-control: public"), "{headers}");
+    assert_eq!(body, png);
+
+    // The document is rebuilt every few seconds, and then points at it
+    let start = Instant::now();
+    loop {
+        let rid = get_rid(relay.port);
+        if let Some(icon) = rid["icon"].as_str() {
+            assert_eq!(icon, format!("http://localhost:{}/icon", relay.port));
+            break;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "{rid}");
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    assert!(get_rid(relay.port).get("banner").is_none());
+}
@@ -0,0 +1,102 @@
+// Checks that the NIPs advertised in NIP-11 match what the relay actually does, across
+// a matrix of configs
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn fetch_supported_nips(port: u16) -> Vec<u64> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Accept: application/nostr+json\r\n\
+              Connection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (_headers, body) = response.split_once("\r\n\r\n").unwrap();
+    let rid: Value = serde_json::from_str(body).unwrap();
+    rid["supported_nips"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|n| n.as_u64().unwrap())
+        .collect()
+}
+
+fn make_event(tags: &str) -> String {
+    let created_at = common::now();
+    // (the tags alone make the events distinct)
+    common::sign_event(1, tags, &format!("probe {created_at}"))
+}
+
+// Whether the relay accepts an event (OK true)
+fn accepts(client: &mut Client, event: String) -> bool {
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    reply[2] == true
+}
+
+// Probe the relay for each NIP whose behavior we can observe, returning (nip, observed)
+fn probe(port: u16) -> Vec<(u64, bool)> {
+    let mut probes: Vec<(u64, bool)> = Vec::new();
+
+    // NIP-42: we are challenged on connect
+    let mut client = Client::connect(port);
+    let first = client.recv(true);
+    probes.push((42, first[0] == "AUTH"));
+
+    // NIP-45: COUNT is answered
+    client.send(r#"["COUNT","c",{"kinds":[1],"limit":1}]"#.to_owned());
+    probes.push((45, client.recv(false)[0] == "COUNT"));
+
+    // NIP-70: a protected event from someone not AUTHed as its author is refused, while
+    // the same event without the '-' tag is accepted
+    let unprotected = accepts(&mut client, make_event(""));
+    let protected = accepts(&mut client, make_event(r#"["-"]"#));
+    probes.push((70, unprotected && !protected));
+
+    // NIP-77: NEG-OPEN gets a NEG-MSG (our version, as we offer a version too high)
+    client.send(r#"["NEG-OPEN","n",{"kinds":[1]},"62"]"#.to_owned());
+    probes.push((77, client.recv(false)[0] == "NEG-MSG"));
+
+    probes
+}
+
+#[test]
+fn test_supported_nips_match_behavior() {
+    for enable_negentropy in [false, true] {
+        for verify_events in [false, true] {
+            let config = format!(
+                "open_relay = true\n\
+                 enable_negentropy = {enable_negentropy}\n\
+                 verify_events = {verify_events}\n"
+            );
+            let relay = common::start_relay(&config);
+
+            let advertised = fetch_supported_nips(relay.port);
+            assert!(advertised.windows(2).all(|w| w[0] < w[1]), "{advertised:?}");
+            assert!(advertised.contains(&1) && advertised.contains(&11));
+
+            for (nip, observed) in probe(relay.port) {
+                assert_eq!(
+                    advertised.contains(&nip),
+                    observed,
+                    "NIP-{nip} advertised={advertised:?} observed={observed} with config:\n{config}"
+                );
+            }
+        }
+    }
+}
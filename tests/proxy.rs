@@ -0,0 +1,87 @@
+// Checks that the client address comes from a trusted proxy's header or PROXY protocol
+// preamble (seen here through per-IP rate limits), and that untrusted peers cannot claim one
+
+mod common;
+
+use common::Client;
+
+fn accepted(client: &mut Client, content: &str) -> bool {
+    client.send(format!(
+        r#"["EVENT",{}]"#,
+        common::sign_event(1, "", content)
+    ));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    reply[2] == true
+}
+
+const LIMITS: &str = "open_relay = true\nmax_events_per_minute = 1\nrate_limit_ban_after = 0\n";
+
+#[test]
+fn test_trusted_proxy_header() {
+    let relay = common::start_relay(&format!("{LIMITS}trusted_proxies = [\"127.0.0.0/8\"]\n"));
+
+    // Each client has its own limit
+    let mut a = Client::connect_forwarded(relay.port, "192.0.2.1");
+    assert!(accepted(&mut a, "a1"));
+    assert!(!accepted(&mut a, "a2"));
+    let mut b = Client::connect_forwarded(relay.port, "198.51.100.1, 127.0.0.1");
+    assert!(accepted(&mut b, "b1"));
+}
+
+#[test]
+fn test_untrusted_proxy_header() {
+    let relay = common::start_relay(&format!("{LIMITS}trusted_proxies = [\"10.0.0.0/8\"]\n"));
+
+    // The header is ignored, so both are limited as 127.0.0.1
+    let mut a = Client::connect_forwarded(relay.port, "192.0.2.1");
+    assert!(accepted(&mut a, "a1"));
+    let mut b = Client::connect_forwarded(relay.port, "198.51.100.1");
+    assert!(!accepted(&mut b, "b1"));
+}
+
+#[test]
+fn test_proxy_protocol() {
+    let relay = common::start_relay(&format!(
+        "{LIMITS}proxy_protocol = true\ntrusted_proxies = [\"127.0.0.0/8\"]\n"
+    ));
+    let preamble = |ip: &str| format!("PROXY TCP4 {ip} 127.0.0.1 40000 {}\r\n", relay.port);
+
+    let mut a = Client::connect_after(relay.port, preamble("192.0.2.1").as_bytes()).unwrap();
+    assert!(accepted(&mut a, "a1"));
+    assert!(!accepted(&mut a, "a2"));
+    let mut b = Client::connect_after(relay.port, preamble("198.51.100.1").as_bytes()).unwrap();
+    assert!(accepted(&mut b, "b1"));
+
+    // Without a preamble we are dropped
+    assert!(Client::connect_after(relay.port, b"").is_err());
+}
+
+#[test]
+fn test_untrusted_proxy_protocol() {
+    let relay = common::start_relay(&format!(
+        "{LIMITS}proxy_protocol = true\ntrusted_proxies = [\"10.0.0.0/8\"]\n"
+    ));
+    let preamble = |ip: &str| format!("PROXY TCP4 {ip} 127.0.0.1 40000 {}\r\n", relay.port);
+
+    // The preamble is read but ignored, so both are limited as 127.0.0.1
+    let mut a = Client::connect_after(relay.port, preamble("192.0.2.1").as_bytes()).unwrap();
+    assert!(accepted(&mut a, "a1"));
+    let mut b = Client::connect_after(relay.port, preamble("198.51.100.1").as_bytes()).unwrap();
+    assert!(!accepted(&mut b, "b1"));
+}
+
+#[test]
+fn test_proxy_protocol_needs_trusted_proxies() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "data_directory = \"{}\"\nproxy_protocol = true\n",
+            dir.path().display()
+        ),
+    )
+    .unwrap();
+    assert!(chorus::load_config(&config_path).is_err());
+}
@@ -0,0 +1,49 @@
+// Checks EVENT and REQ rate limits: over the limit is refused, but the connection stays up
+
+mod common;
+
+use common::Client;
+
+#[test]
+fn test_rate_limits() {
+    let relay = common::start_relay(
+        "open_relay = true\nallow_scraping = true\nmax_events_per_minute = 2\nmax_reqs_per_minute = 1\n",
+    );
+    let mut client = Client::connect(relay.port);
+
+    for (content, accepted) in [("one", true), ("two", true), ("three", false)] {
+        client.send(format!(
+            r#"["EVENT",{}]"#,
+            common::sign_event(1, "", content)
+        ));
+        let reply = client.recv(false);
+        assert_eq!(reply[0], "OK", "{reply}");
+        assert_eq!(reply[2], accepted, "{reply}");
+        if !accepted {
+            assert!(
+                reply[3].as_str().unwrap().starts_with("rate-limited:"),
+                "{reply}"
+            );
+        }
+    }
+
+    client.send(r#"["REQ","a",{"kinds":[1],"limit":1}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EVENT");
+    assert_eq!(client.recv(false)[0], "EOSE");
+
+    client.send(r#"["REQ","b",{"kinds":[1],"limit":1}]"#.to_owned());
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "CLOSED", "{reply}");
+    assert!(
+        reply[2].as_str().unwrap().starts_with("rate-limited:"),
+        "{reply}"
+    );
+
+    // Still connected
+    client.send(r#"["CLOSE","a"]"#.to_owned());
+    client.send(format!(
+        r#"["EVENT",{}]"#,
+        common::sign_event(1, "", "four")
+    ));
+    assert_eq!(client.recv(false)[0], "OK");
+}
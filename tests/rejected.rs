@@ -0,0 +1,153 @@
+// Checks that rejected events are remembered (when so configured) for admins to look up by
+// id or author, without the author or content of sensitive kinds, and that they are never
+// stored, are bounded in number, expire, and can be cleared
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+use std::time::Duration;
+
+const ADMIN: u8 = 0x42;
+const BANNED: u8 = 0x17;
+
+fn command(client: &mut Client, content: &str) -> Value {
+    client.send(format!(
+        r#"["EVENT",{}]"#,
+        common::sign_event_as(
+            ADMIN,
+            chorus::admin::ADMIN_COMMAND_KIND,
+            r#"["relay","ws://localhost"]"#,
+            content
+        )
+    ));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], true, "{reply}");
+    reply
+}
+
+fn list(client: &mut Client, filter: &str) -> Vec<Value> {
+    let params = if filter.is_empty() {
+        "[]".to_owned()
+    } else {
+        format!(r#"["{filter}"]"#)
+    };
+    let reply = command(
+        client,
+        &format!(r#"{{"method":"listrejectedevents","params":{params}}}"#),
+    );
+    let response: Value = serde_json::from_str(reply[3].as_str().unwrap()).unwrap();
+    response["result"].as_array().unwrap().clone()
+}
+
+// Send an event which is refused, returning its id
+fn rejected(client: &mut Client, kind: u16, tags: &str, content: &str) -> String {
+    let event = common::sign_event_as(BANNED, kind, tags, content);
+    let id = common::id_of(&event);
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "OK", "{reply}");
+    assert_eq!(reply[2], false, "{reply}");
+    id
+}
+
+fn start(extra_config: &str) -> (common::Relay, Client) {
+    let relay = common::start_relay(&format!(
+        "open_relay = true\nadmin_hex_keys = [\"{}\"]\n{extra_config}",
+        common::test_pubkey(ADMIN)
+    ));
+    let mut client = Client::connect(relay.port);
+    let _ = command(
+        &mut client,
+        &format!(
+            r#"{{"method":"banpubkey","params":["{}"]}}"#,
+            common::test_pubkey(BANNED)
+        ),
+    );
+    (relay, client)
+}
+
+#[test]
+fn test_rejected_events() {
+    let (_relay, mut client) = start(
+        "keep_rejected_events = true\n\
+         rejected_events_max_count = 3\n\
+         rejected_events_max_seconds = 5\n",
+    );
+    let banned = common::test_pubkey(BANNED);
+
+    // Remembered with why, when and from whom
+    let first = rejected(&mut client, 1, "", "first");
+    let entries = list(&mut client, &first);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["kind"], 1);
+    assert_eq!(entries[0]["pubkey"], banned.as_str());
+    assert!(entries[0]["event"].as_str().unwrap().contains("first"));
+    assert!(
+        entries[0]["reason"]
+            .as_str()
+            .unwrap()
+            .starts_with("blocked:"),
+        "{}",
+        entries[0]
+    );
+    assert!(entries[0]["rejected_at"].as_u64().unwrap() > 0);
+    assert!(!entries[0]["peer"].as_str().unwrap().is_empty());
+
+    // But never stored
+    client.send(format!(r#"["REQ","s",{{"ids":["{first}"]}}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "EOSE", "{reply}");
+
+    // Only the id, kind and reason of sensitive kinds
+    let dm = rejected(
+        &mut client,
+        4,
+        &format!(r#"["p","{}"]"#, common::test_pubkey(ADMIN)),
+        "secret",
+    );
+    let entries = list(&mut client, &dm);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["kind"], 4);
+    assert!(entries[0].get("pubkey").is_none(), "{}", entries[0]);
+    assert!(entries[0].get("event").is_none(), "{}", entries[0]);
+    assert!(!entries[0]["reason"].as_str().unwrap().is_empty());
+
+    // So they are not found by author
+    let entries = list(&mut client, &banned);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["id"], first.as_str());
+
+    // At most so many, dropping the oldest
+    let mut later: Vec<String> = Vec::new();
+    for n in 0..3 {
+        later.push(rejected(&mut client, 1, "", &format!("later {n}")));
+    }
+    let ids: Vec<String> = list(&mut client, "")
+        .iter()
+        .map(|e| e["id"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(ids, later);
+
+    // Cleared on demand
+    let _ = command(
+        &mut client,
+        r#"{"method":"clearrejectedevents","params":[]}"#,
+    );
+    assert!(list(&mut client, "").is_empty());
+
+    // And forgotten in time
+    let _ = rejected(&mut client, 1, "", "expiring");
+    assert_eq!(list(&mut client, "").len(), 1);
+    std::thread::sleep(Duration::from_secs(7));
+    assert!(list(&mut client, "").is_empty());
+}
+
+#[test]
+fn test_rejected_events_off() {
+    let (_relay, mut client) = start("");
+    let id = rejected(&mut client, 1, "", "unremembered");
+    assert!(list(&mut client, &id).is_empty());
+    assert!(list(&mut client, "").is_empty());
+}
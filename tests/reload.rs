@@ -0,0 +1,55 @@
+// Checks reloading the config on SIGHUP: settings that only take effect at startup are
+// kept as they were (and logged as needing a restart), while the rest are applied
+
+mod common;
+
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn get_rid(port: u16) -> Value {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: application/nostr+json\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    serde_json::from_slice(&response[split + 4..]).unwrap()
+}
+
+#[test]
+fn test_startup_settings_kept() {
+    let relay = common::start_relay("name = \"before\"\n");
+
+    let mut config = std::fs::read_to_string(&relay.config_path).unwrap();
+    config = config.replace("name = \"before\"", "name = \"after\"");
+    config.push_str("lmdb_map_size = 1073741824\nenable_http2 = false\nverify_workers = 3\n");
+    std::fs::write(&relay.config_path, config).unwrap();
+    assert_eq!(
+        unsafe { libc::kill(relay.child.id() as libc::pid_t, libc::SIGHUP) },
+        0
+    );
+
+    let line = common::wait_for_log(&relay.log, "require a restart");
+    for name in ["lmdb_map_size", "enable_http2", "verify_workers"] {
+        assert!(line.contains(name), "{line}");
+    }
+    assert!(
+        !line.contains(" name,") && !line.contains(" name "),
+        "{line}"
+    );
+
+    // While the rest took effect
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while get_rid(relay.port)["name"] != "after" {
+        assert!(std::time::Instant::now() < deadline, "name not reloaded");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,85 @@
+// Checks that chorus_verify repairs a store left damaged by failing (as if we crashed)
+// between storing an event and indexing it, and that events it stores again keep when we
+// first received them
+//
+// Run with `cargo test --features failpoints`
+
+#![cfg(feature = "failpoints")]
+
+mod common;
+
+use chorus::config::Config;
+use chorus::failpoints::{self, FailAction};
+use chorus::globals::GLOBALS;
+use pocket_types::Event;
+use std::process::Command;
+
+fn verify(config_path: &std::path::Path, args: &[&str]) -> i32 {
+    Command::new(env!("CARGO_BIN_EXE_chorus_verify"))
+        .arg(config_path)
+        .args(args)
+        .output()
+        .unwrap()
+        .status
+        .code()
+        .unwrap()
+}
+
+#[test]
+fn test_repair() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        indexed_tag_names: vec!["title".to_owned()],
+        ..Default::default()
+    };
+    let store = chorus::setup_store_and_return(&config).unwrap();
+    chorus::tag_index::migrate(&store, &config).unwrap();
+    chorus::first_seen::migrate(&store).unwrap();
+    *GLOBALS.config.write() = config;
+    let _ = GLOBALS.store.set(store);
+
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "data_directory = \"{}\"\nindexed_tag_names = [\"title\"]\n",
+            dir.path().display()
+        ),
+    )
+    .unwrap();
+
+    // Stored and indexed, but its tag index entries are lost since. It was received long
+    // ago.
+    let json = common::sign_event_as(1, 1, r#"[["title","kept"]]"#, "kept");
+    let mut buffer = vec![0_u8; 4096];
+    let (_size, kept) = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    let _ = chorus::store_event(kept).unwrap();
+    chorus::first_seen::forget(kept.id()).unwrap();
+    chorus::first_seen::record_at(kept, 1_000).unwrap();
+    chorus::tag_index::unindex(&chorus::tag_index::keys_for_removal(kept).unwrap()).unwrap();
+
+    // Stored, then indexing it fails and so does removing it again
+    failpoints::arm("store_event.after_append", FailAction::Once);
+    failpoints::arm("store_event.rollback", FailAction::Once);
+    let json = common::sign_event_as(2, 1, r#"[["title","lost"]]"#, "lost");
+    let mut buffer = vec![0_u8; 4096];
+    let (_size, lost) = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    assert!(chorus::store_event(lost).is_err());
+    assert_eq!(failpoints::fired("store_event.rollback"), 1);
+    failpoints::disarm_all();
+
+    let report = chorus::verify_store().unwrap();
+    assert_eq!(report.unindexed.len(), 2, "{:?}", report.problems);
+    assert!(report.unindexed.contains(&kept.id()));
+    assert!(report.unindexed.contains(&lost.id()));
+    assert_eq!(report.mismatched_entries, 0, "{:?}", report.problems);
+
+    // Found, then repaired, then clean
+    assert_eq!(verify(&config_path, &[]), 2);
+    assert_eq!(verify(&config_path, &["--repair"]), 1);
+    assert_eq!(verify(&config_path, &[]), 0);
+
+    assert_eq!(chorus::first_seen::seen_at(kept.id()).unwrap(), Some(1_000));
+    assert!(chorus::first_seen::seen_at(lost.id()).unwrap().is_some());
+}
@@ -0,0 +1,65 @@
+// Checks that only the latest version of replaceable and addressable events is kept
+
+mod common;
+
+use common::Client;
+
+const AUTHOR: u8 = 0x31;
+
+// The ids of the events matching `filter`
+fn query(client: &mut Client, filter: &str) -> Vec<String> {
+    client.send(format!(r#"["REQ","q",{filter}]"#));
+    let mut ids = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => ids.push(message[2]["id"].as_str().unwrap().to_owned()),
+            Some("EOSE") => break,
+            _ => panic!("{message}"),
+        }
+    }
+    client.send(r#"["CLOSE","q"]"#.to_owned());
+    ids
+}
+
+#[test]
+fn test_latest_version_only() {
+    let relay = common::start_relay("open_relay = true\n");
+    let mut client = Client::connect(relay.port);
+    let now = common::now();
+    let author = common::test_pubkey(AUTHOR);
+
+    // Addressable: per d-tag
+    let old = common::sign_event_at(AUTHOR, now - 20, 30023, r#"["d","post"]"#, "draft");
+    let new = common::sign_event_at(AUTHOR, now - 10, 30023, r#"["d","post"]"#, "final");
+    let other = common::sign_event_at(AUTHOR, now - 20, 30023, r#"["d","other"]"#, "other");
+    assert_eq!(common::publish(&mut client, &old)[2], true);
+    assert_eq!(common::publish(&mut client, &new)[2], true);
+    assert_eq!(common::publish(&mut client, &other)[2], true);
+    let filter = format!(r#"{{"kinds":[30023],"authors":["{author}"]}}"#);
+    let mut ids = query(&mut client, &filter);
+    ids.sort();
+    let mut expected = vec![common::id_of(&new), common::id_of(&other)];
+    expected.sort();
+    assert_eq!(ids, expected);
+
+    // An older version arriving later is not stored
+    let stale = common::sign_event_at(AUTHOR, now - 15, 30023, r#"["d","post"]"#, "stale");
+    let reply = common::publish(&mut client, &stale);
+    assert_eq!(reply[2], true, "{reply}");
+    assert!(
+        reply[3].as_str().unwrap().starts_with("duplicate:"),
+        "{reply}"
+    );
+    let filter = format!(r#"{{"kinds":[30023],"authors":["{author}"],"#d":["post"]}}"#);
+    assert_eq!(query(&mut client, &filter), vec![common::id_of(&new)]);
+
+    // Replaceable: per kind, with ties going to the lowest id
+    let a = common::sign_event_at(AUTHOR, now, 10002, r#"["r","wss://a.example/"]"#, "");
+    let b = common::sign_event_at(AUTHOR, now, 10002, r#"["r","wss://b.example/"]"#, "");
+    assert_eq!(common::publish(&mut client, &a)[2], true);
+    assert_eq!(common::publish(&mut client, &b)[2], true);
+    let lowest = std::cmp::min(common::id_of(&a), common::id_of(&b));
+    let filter = format!(r#"{{"kinds":[10002],"authors":["{author}"]}}"#);
+    assert_eq!(query(&mut client, &filter), vec![lowest]);
+}
@@ -0,0 +1,201 @@
+// Checks that each REQ filter is served its newest matching events, no more than its limit
+// of them, and that live events are not limited
+
+mod common;
+
+use common::Client;
+
+const BASE: u64 = 1_700_000_000;
+const KINDS: [u16; 3] = [1, 7, 42];
+const AUTHORS: [u8; 4] = [1, 2, 3, 4];
+
+struct Stored {
+    created_at: u64,
+    kind: u16,
+    author: u8,
+    id: String,
+}
+
+// 1000 events a second apart, with kinds and authors interleaved, sent out of order
+fn populate(client: &mut Client) -> Vec<Stored> {
+    let mut stored: Vec<Stored> = Vec::new();
+    for n in 0..1000_u64 {
+        let i = (n * 7) % 1000;
+        let kind = KINDS[i as usize % KINDS.len()];
+        let author = AUTHORS[i as usize % AUTHORS.len()];
+        let event = common::sign_event_at(author, BASE + i, kind, "", &format!("note {i}"));
+        let id = common::id_of(&event);
+        client.send(format!(r#"["EVENT",{event}]"#));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+        stored.push(Stored {
+            created_at: BASE + i,
+            kind,
+            author,
+            id,
+        });
+    }
+    stored
+}
+
+// The ids a REQ gets before EOSE, in the order sent
+fn req(client: &mut Client, filters: &str) -> Vec<String> {
+    client.send(format!(r#"["REQ","q",{filters}]"#));
+    let mut ids: Vec<String> = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => ids.push(message[2]["id"].as_str().unwrap().to_owned()),
+            Some("EOSE") => break,
+            _ => panic!("{message}"),
+        }
+    }
+    client.send(r#"["CLOSE","q"]"#.to_owned());
+    ids
+}
+
+// The newest `limit` events matching
+fn newest(stored: &[Stored], limit: usize, matches: impl Fn(&Stored) -> bool) -> Vec<&Stored> {
+    let mut found: Vec<&Stored> = stored.iter().filter(|e| matches(e)).collect();
+    found.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+    found.truncate(limit);
+    found
+}
+
+// What a REQ of several filters should get: each filter's own newest, newest first
+fn expect(mut found: Vec<&Stored>) -> Vec<String> {
+    found.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+    found.dedup_by_key(|e| e.created_at);
+    found.iter().map(|e| e.id.clone()).collect()
+}
+
+#[test]
+fn test_limits() {
+    let relay = common::start_relay(
+        "open_relay = true\n\
+         allow_scraping = true\n\
+         max_events_per_minute = 0\n\
+         default_limit = 50\n\
+         max_limit = 300\n",
+    );
+    let mut client = Client::connect(relay.port);
+    let stored = populate(&mut client);
+
+    // The newest overall
+    assert_eq!(
+        req(&mut client, r#"{"limit":20}"#),
+        expect(newest(&stored, 20, |_| true))
+    );
+
+    // With until, since and both
+    assert_eq!(
+        req(
+            &mut client,
+            &format!(r#"{{"kinds":[7],"until":{},"limit":20}}"#, BASE + 500)
+        ),
+        expect(newest(&stored, 20, |e| e.kind == 7 && e.created_at <= BASE + 500))
+    );
+    assert_eq!(
+        req(
+            &mut client,
+            &format!(r#"{{"kinds":[1],"since":{},"limit":20}}"#, BASE + 970)
+        ),
+        expect(newest(&stored, 20, |e| e.kind == 1 && e.created_at >= BASE + 970))
+    );
+    assert_eq!(
+        req(
+            &mut client,
+            &format!(
+                r#"{{"authors":["{}"],"since":{},"until":{},"limit":30}}"#,
+                common::test_pubkey(2),
+                BASE + 100,
+                BASE + 400
+            )
+        ),
+        expect(newest(&stored, 30, |e| e.author == 2
+            && e.created_at >= BASE + 100
+            && e.created_at <= BASE + 400))
+    );
+
+    // Several authors and kinds, each their own index range
+    assert_eq!(
+        req(
+            &mut client,
+            &format!(
+                r#"{{"authors":["{}","{}"],"kinds":[1,42],"limit":25}}"#,
+                common::test_pubkey(1),
+                common::test_pubkey(3)
+            )
+        ),
+        expect(newest(&stored, 25, |e| [1, 3].contains(&e.author)
+            && [1, 42].contains(&e.kind)))
+    );
+
+    // No limit gets default_limit, and more than max_limit gets max_limit
+    assert_eq!(
+        req(&mut client, r#"{"kinds":[42]}"#),
+        expect(newest(&stored, 50, |e| e.kind == 42))
+    );
+    assert_eq!(
+        req(&mut client, r#"{"limit":1000}"#),
+        expect(newest(&stored, 300, |_| true))
+    );
+
+    // The limit is per filter, including filters that are merged into one scan
+    let mut found = newest(&stored, 5, |e| e.kind == 1);
+    found.extend(newest(&stored, 10, |e| e.kind == 7));
+    assert_eq!(
+        req(
+            &mut client,
+            r#"{"kinds":[1],"limit":5},{"kinds":[7],"limit":10}"#
+        ),
+        expect(found)
+    );
+    let mut found = newest(&stored, 3, |e| e.author == 1);
+    found.extend(newest(&stored, 40, |e| e.author == 2));
+    found.extend(newest(&stored, 200, |e| {
+        e.kind == 7 && e.created_at < BASE + 600
+    }));
+    assert_eq!(
+        req(
+            &mut client,
+            &format!(
+                r#"{{"authors":["{}"],"limit":3}},{{"authors":["{}"],"limit":40}},{{"kinds":[7],"until":{},"limit":200}}"#,
+                common::test_pubkey(1),
+                common::test_pubkey(2),
+                BASE + 599
+            )
+        ),
+        expect(found)
+    );
+}
+
+#[test]
+fn test_live_events_ignore_limit() {
+    let relay = common::start_relay("open_relay = true\nmax_events_per_minute = 0\n");
+
+    let mut publisher = Client::connect(relay.port);
+    for n in 0..5 {
+        let event = common::sign_event_at(1, BASE + n, 1, "", "old");
+        publisher.send(format!(r#"["EVENT",{event}]"#));
+        assert_eq!(publisher.recv(false)[2], true);
+    }
+
+    let mut subscriber = Client::connect(relay.port);
+    subscriber.send(r#"["REQ","live",{"kinds":[1],"limit":2}]"#.to_owned());
+    for _ in 0..2 {
+        assert_eq!(subscriber.recv(false)[0], "EVENT");
+    }
+    assert_eq!(subscriber.recv(false)[0], "EOSE");
+
+    for n in 0..5 {
+        let event = common::sign_event(1, "", &format!("new {n}"));
+        publisher.send(format!(r#"["EVENT",{event}]"#));
+        assert_eq!(publisher.recv(false)[2], true);
+    }
+    for n in 0..5 {
+        let message = subscriber.recv(false);
+        assert_eq!(message[0], "EVENT", "{message}");
+        assert_eq!(message[2]["content"], format!("new {n}"));
+    }
+}
@@ -0,0 +1,178 @@
+// Checks that with enable_since_last a filter with "since_last" is served from where the
+// same subscription of the same user left off, that subscriptions with the same filters
+// share that (which never moves back), and that it is ignored when it cannot apply
+
+mod common;
+
+use common::Client;
+use std::time::{Duration, Instant};
+
+const WRITER: u8 = 9;
+const ALICE: u8 = 1;
+const BOB: u8 = 2;
+
+const FILTER: &str = r#"{"kinds":[1],"since_last":true}"#;
+
+fn connect_as(port: u16, secret: Option<u8>) -> Client {
+    let mut client = Client::connect(port);
+    let auth = client.recv(true);
+    assert_eq!(auth[0], "AUTH", "{auth}");
+    if let Some(secret) = secret {
+        let event = common::sign_event_as(
+            secret,
+            22242,
+            &format!(r#"["relay","ws://localhost"],["challenge",{}]"#, auth[1]),
+            "",
+        );
+        client.send(format!(r#"["AUTH",{event}]"#));
+        let reply = client.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+    }
+    client
+}
+
+fn note(client: &mut Client, created_at: u64) -> String {
+    let event = common::sign_event_at(WRITER, created_at, 1, "", &format!("at {created_at}"));
+    let reply = common::publish(client, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    common::id_of(&event)
+}
+
+// The ids of the stored events served to a REQ, which is left open
+fn req(client: &mut Client, subid: &str, filter: &str) -> Vec<String> {
+    client.send(format!(r#"["REQ","{subid}",{filter}]"#));
+    let mut ids: Vec<String> = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => ids.push(message[2]["id"].as_str().unwrap().to_owned()),
+            Some("EOSE") => return ids,
+            _ => panic!("{message}"),
+        }
+    }
+}
+
+// Close a subscription, waiting until the relay has handled that
+fn close(client: &mut Client, subid: &str) {
+    client.send(format!(r#"["CLOSE","{subid}"]"#));
+    client.send(r#"["COUNT","sync",{"kinds":[0]}]"#.to_owned());
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "COUNT", "{reply}");
+}
+
+fn live(client: &mut Client) -> String {
+    let message = client.recv(false);
+    assert_eq!(message[0], "EVENT", "{message}");
+    message[2]["id"].as_str().unwrap().to_owned()
+}
+
+// What a fresh subscription of Alice's is served, once the relay has seen the connections
+// dropped before it go (and saved where their subscriptions left off)
+fn served_after_disconnect(port: u16, expected: &[String]) {
+    let start = Instant::now();
+    loop {
+        let mut client = connect_as(port, Some(ALICE));
+        let ids = req(&mut client, "after", FILTER);
+        if ids == expected || start.elapsed() > Duration::from_secs(10) {
+            assert_eq!(ids, expected);
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn test_since_last() {
+    let relay = common::start_relay("open_relay = true\nenable_since_last = true\n");
+    let now = common::now();
+    let mut publisher = connect_as(relay.port, None);
+    let first = note(&mut publisher, now - 300);
+    let second = note(&mut publisher, now - 200);
+    let third = note(&mut publisher, now - 100);
+
+    let mut alice = connect_as(relay.port, Some(ALICE));
+    assert_eq!(
+        req(&mut alice, "a", FILTER),
+        vec![third.clone(), second.clone(), first.clone()]
+    );
+    close(&mut alice, "a");
+
+    // It carries on from the newest event delivered (again, in case others share its
+    // second), on any connection and under any subscription id
+    let mut alice = connect_as(relay.port, Some(ALICE));
+    assert_eq!(req(&mut alice, "b", FILTER), vec![third.clone()]);
+
+    // Live events move it on, and disconnecting saves that
+    let fourth = note(&mut publisher, now - 50);
+    assert_eq!(live(&mut alice), fourth);
+    drop(alice);
+    served_after_disconnect(relay.port, &[fourth.clone()]);
+
+    // Other filters, other users, and unauthenticated REQs get the lot
+    let all = vec![fourth.clone(), third.clone(), second.clone(), first.clone()];
+    let mut alice = connect_as(relay.port, Some(ALICE));
+    assert_eq!(req(&mut alice, "c", r#"{"kinds":[1]}"#), all);
+    let other = format!(
+        r#"{{"kinds":[1],"authors":["{}"],"since_last":true}}"#,
+        common::test_pubkey(WRITER)
+    );
+    assert_eq!(req(&mut alice, "d", &other), all);
+    let mut bob = connect_as(relay.port, Some(BOB));
+    assert_eq!(req(&mut bob, "a", FILTER), all);
+    let mut anonymous = connect_as(relay.port, None);
+    assert_eq!(req(&mut anonymous, "a", FILTER), all);
+
+    // Unless it is disabled
+    let relay = common::start_relay("open_relay = true\n");
+    let mut publisher = connect_as(relay.port, None);
+    let first = note(&mut publisher, now - 300);
+    let mut alice = connect_as(relay.port, Some(ALICE));
+    assert_eq!(req(&mut alice, "a", FILTER), vec![first.clone()]);
+    close(&mut alice, "a");
+    assert_eq!(req(&mut alice, "a", FILTER), vec![first]);
+}
+
+#[test]
+fn test_since_last_shared() {
+    let relay = common::start_relay("open_relay = true\nenable_since_last = true\n");
+    let now = common::now();
+    let mut publisher = connect_as(relay.port, None);
+    let first = note(&mut publisher, now - 300);
+    let second = note(&mut publisher, now - 200);
+    let third = note(&mut publisher, now - 100);
+
+    // Two subscriptions with the same filters, the second opened after the newest event
+    // the first got is deleted, so it gets no further
+    let mut early = connect_as(relay.port, Some(ALICE));
+    assert_eq!(
+        req(&mut early, "x", FILTER),
+        vec![third.clone(), second.clone(), first.clone()]
+    );
+    let deletion = common::sign_event_as(WRITER, 5, &format!(r#"["e","{third}"]"#), "");
+    let reply = common::publish(&mut publisher, &deletion);
+    assert_eq!(reply[2], true, "{reply}");
+    let mut late = connect_as(relay.port, Some(ALICE));
+    assert_eq!(
+        req(&mut late, "x", FILTER),
+        vec![second.clone(), first.clone()]
+    );
+
+    // The one that got less closing last does not move their cursor back
+    close(&mut early, "x");
+    close(&mut late, "x");
+    let mut alice = connect_as(relay.port, Some(ALICE));
+    assert!(req(&mut alice, "y", FILTER).is_empty());
+
+    // Both get live events, and both disconnecting at once leaves it at the newest
+    let mut other = connect_as(relay.port, Some(ALICE));
+    assert!(req(&mut other, "y", FILTER).is_empty());
+    let fourth = note(&mut publisher, now - 50);
+    let fifth = note(&mut publisher, now - 75);
+    for client in [&mut alice, &mut other] {
+        assert_eq!(live(client), fourth);
+        assert_eq!(live(client), fifth);
+    }
+    drop(alice);
+    drop(other);
+    served_after_disconnect(relay.port, &[fourth]);
+}
@@ -0,0 +1,100 @@
+// Checks that retention rules remove events by age and by count, never remove the latest
+// version of a replaceable event, and are published in NIP-11
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const CONFIG: &str = r#"
+open_relay = true
+allow_scraping = true
+retention_sweep_seconds = 1
+
+[[retention]]
+kinds = [1, 0]
+time = 3600
+
+[[retention]]
+kinds = [[7, 8]]
+count = 2
+"#;
+
+fn contents(client: &mut Client, filter: &str) -> Vec<String> {
+    client.send(format!(r#"["REQ","q",{filter}]"#));
+    let mut contents = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => contents.push(message[2]["content"].as_str().unwrap().to_owned()),
+            Some("EOSE") => break,
+            _ => panic!("{message}"),
+        }
+    }
+    client.send(r#"["CLOSE","q"]"#.to_owned());
+    contents.sort();
+    contents
+}
+
+fn fetch_retention(port: u16) -> Value {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Accept: application/nostr+json\r\n\
+              Connection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response).unwrap();
+    let (_headers, body) = response.split_once("\r\n\r\n").unwrap();
+    let rid: Value = serde_json::from_str(body).unwrap();
+    rid["retention"].clone()
+}
+
+#[test]
+fn test_retention() {
+    let relay = common::start_relay(CONFIG);
+    let mut client = Client::connect(relay.port);
+    let now = common::now();
+
+    let mut events = vec![
+        common::sign_event_at(1, now - 7200, 1, "", "old"),
+        common::sign_event_at(1, now - 60, 1, "", "new"),
+        common::sign_event_at(1, now - 7200, 0, "", "profile"),
+    ];
+    for (i, content) in ["r1", "r2", "r3"].iter().enumerate() {
+        events.push(common::sign_event_at(
+            1,
+            now - 100 + i as u64,
+            7,
+            "",
+            content,
+        ));
+    }
+    events.push(common::sign_event_at(2, now - 100, 7, "", "other"));
+    for event in &events {
+        let reply = common::publish(&mut client, event);
+        assert_eq!(reply[2], true, "{reply}");
+    }
+
+    std::thread::sleep(Duration::from_secs(3));
+
+    assert_eq!(contents(&mut client, r#"{"kinds":[1]}"#), vec!["new"]);
+    // However old, the latest version of a replaceable event stays
+    assert_eq!(contents(&mut client, r#"{"kinds":[0]}"#), vec!["profile"]);
+    // Counted per author
+    assert_eq!(
+        contents(&mut client, r#"{"kinds":[7]}"#),
+        vec!["other", "r2", "r3"]
+    );
+
+    assert_eq!(
+        fetch_retention(relay.port),
+        serde_json::json!([{"kinds":[1,0],"time":3600},{"kinds":[[7,8]],"count":2}])
+    );
+}
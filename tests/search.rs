@@ -0,0 +1,160 @@
+// Checks NIP-50 search: events stored before search was enabled are indexed when it is,
+// new ones as they arrive; every term must match, case-insensitively, along with the rest
+// of the filter; DMs are never searched; and no search examines more than
+// `search_max_scanned` candidates
+
+mod common;
+
+use common::Client;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const ALICE: u8 = 1;
+const BOB: u8 = 2;
+
+fn post(client: &mut Client, secret: u8, kind: u16, tags: &str, content: &str) {
+    let event = common::sign_event_as(secret, kind, tags, content);
+    let reply = common::publish(client, &event);
+    assert_eq!(reply[2], true, "{reply}");
+}
+
+// The contents of the events matching `filter`
+fn search(client: &mut Client, filter: &str) -> Vec<String> {
+    client.send(format!(r#"["REQ","search",{filter}]"#));
+    let mut contents = Vec::new();
+    loop {
+        let message = client.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => contents.push(message[2]["content"].as_str().unwrap().to_owned()),
+            Some("EOSE") => break,
+            _ => panic!("{message}"),
+        }
+    }
+    client.send(r#"["CLOSE","search"]"#.to_owned());
+    contents.sort();
+    contents
+}
+
+fn get_rid(port: u16) -> Value {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: application/nostr+json\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response: Vec<u8> = Vec::new();
+    let _ = stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    serde_json::from_slice(&response[split + 4..]).unwrap()
+}
+
+#[test]
+fn test_search() {
+    let mut relay =
+        common::start_relay("open_relay = true\nenable_search = false\nsearch_max_scanned = 5\n");
+    let rid = get_rid(relay.port);
+    assert!(!rid["supported_nips"]
+        .as_array()
+        .unwrap()
+        .contains(&Value::from(50)));
+
+    // Stored before search was enabled
+    let mut client = Client::connect(relay.port);
+    post(&mut client, ALICE, 1, "", "Hello Nostr world");
+    post(&mut client, BOB, 1, "", "hello there");
+    post(&mut client, BOB, 7, "", "hello");
+    let to_bob = format!(r#"["p","{}"]"#, common::test_pubkey(BOB));
+    post(&mut client, ALICE, 4, &to_bob, "hello secret");
+    for n in 0..8 {
+        post(&mut client, ALICE, 1, "", &format!("common {n}"));
+    }
+    drop(client);
+
+    let config = std::fs::read_to_string(&relay.config_path).unwrap();
+    std::fs::write(
+        &relay.config_path,
+        config.replace("enable_search = false", "enable_search = true"),
+    )
+    .unwrap();
+    relay.restart();
+    let rid = get_rid(relay.port);
+    assert!(rid["supported_nips"]
+        .as_array()
+        .unwrap()
+        .contains(&Value::from(50)));
+    assert_eq!(rid["services"]["unavailable"], serde_json::json!([]));
+
+    // As the recipient of the DM, which would be served if it were found
+    let mut client = Client::connect(relay.port);
+    let challenge = client.recv(true);
+    assert_eq!(challenge[0], "AUTH", "{challenge}");
+    let auth = common::sign_event_as(
+        BOB,
+        22242,
+        &format!(
+            r#"["relay","ws://localhost"],["challenge",{}]"#,
+            challenge[1]
+        ),
+        "",
+    );
+    client.send(format!(r#"["AUTH",{auth}]"#));
+    assert_eq!(client.recv(false)[2], true);
+
+    // Every term, in any case
+    assert_eq!(
+        search(&mut client, r#"{"search":"hello"}"#),
+        vec!["Hello Nostr world", "hello", "hello there"]
+    );
+    assert_eq!(
+        search(&mut client, r#"{"search":"HELLO, nostr!"}"#),
+        vec!["Hello Nostr world"]
+    );
+    assert!(search(&mut client, r#"{"search":"hello absent"}"#).is_empty());
+    assert!(search(&mut client, r#"{"search":"secret"}"#).is_empty());
+
+    // Along with the rest of the filter
+    let bob = common::test_pubkey(BOB);
+    assert_eq!(
+        search(
+            &mut client,
+            &format!(r#"{{"search":"hello","authors":["{bob}"]}}"#)
+        ),
+        vec!["hello", "hello there"]
+    );
+    assert_eq!(
+        search(&mut client, r#"{"search":"hello","kinds":[7]}"#),
+        vec!["hello"]
+    );
+    let now = common::now();
+    assert!(search(
+        &mut client,
+        &format!(r#"{{"search":"hello","since":{}}}"#, now + 60)
+    )
+    .is_empty());
+    assert_eq!(
+        search(
+            &mut client,
+            &format!(r#"{{"search":"hello","until":{}}}"#, now + 60)
+        )
+        .len(),
+        3
+    );
+
+    // Examining no more than so many
+    assert_eq!(
+        search(&mut client, r#"{"search":"common","limit":100}"#).len(),
+        5
+    );
+
+    // And new events are found too
+    post(&mut client, BOB, 1, "", "something fresh");
+    assert_eq!(
+        search(&mut client, r#"{"search":"fresh"}"#),
+        vec!["something fresh"]
+    );
+}
@@ -0,0 +1,120 @@
+// Checks that the event sink keeps delivering when its outbox cannot be read or updated for
+// a while: it backs off and tries again (delivering a batch again if it could not remove
+// it), rather than giving up until the relay is restarted
+//
+// Run with `cargo test --features failpoints`
+
+#![cfg(feature = "failpoints")]
+
+mod common;
+
+use chorus::config::Config;
+use chorus::failpoints::{self, FailAction};
+use chorus::globals::GLOBALS;
+use pocket_types::Event;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+// An endpoint that answers every POST with 200, passing on the contents of each event
+async fn endpoint(listener: TcpListener, posted: mpsc::UnboundedSender<Vec<String>>) {
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let posted = posted.clone();
+        let _ = tokio::spawn(async move {
+            let mut request: Vec<u8> = Vec::new();
+            let mut buffer = [0_u8; 4096];
+            let body = loop {
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert!(n > 0, "request cut short");
+                request.extend_from_slice(&buffer[..n]);
+                let Some(split) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    continue;
+                };
+                let headers = String::from_utf8_lossy(&request[..split]).to_lowercase();
+                let length: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .unwrap()
+                    .trim()
+                    .parse()
+                    .unwrap();
+                if request.len() >= split + 4 + length {
+                    break request[split + 4..split + 4 + length].to_vec();
+                }
+            };
+            let events: Vec<Value> = serde_json::from_slice(&body).unwrap();
+            let contents = events
+                .iter()
+                .map(|e| e["content"].as_str().unwrap().to_owned())
+                .collect();
+            posted.send(contents).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+        });
+    }
+}
+
+fn enqueue(content: &str) {
+    let json = common::sign_event(1, "", content);
+    let mut buffer = vec![0_u8; 4096];
+    let (_size, event) = Event::from_json(json.as_bytes(), &mut buffer).unwrap();
+    chorus::sink::enqueue(event).unwrap();
+}
+
+async fn next_post(posted: &mut mpsc::UnboundedReceiver<Vec<String>>) -> Vec<String> {
+    tokio::time::timeout(Duration::from_secs(10), posted.recv())
+        .await
+        .expect("nothing was delivered")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_sink_outbox_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, mut posted) = mpsc::unbounded_channel();
+    let _ = tokio::spawn(endpoint(listener, tx));
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        event_sink_url: Some(format!("http://127.0.0.1:{port}/events")),
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config;
+    chorus::sink::init().unwrap();
+
+    // The outbox cannot be read at first
+    failpoints::arm("sink.next_batch", FailAction::Once);
+    enqueue("first");
+    let sink = tokio::spawn(chorus::sink::run());
+    assert_eq!(next_post(&mut posted).await, vec!["first"]);
+    assert_eq!(failpoints::fired("sink.next_batch"), 1);
+    let stats = chorus::sink::stats().unwrap();
+    assert!(stats.last_error.unwrap().starts_with("outbox:"));
+
+    // Then a delivered batch cannot be removed from it, so it is delivered again
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(chorus::sink::stats().unwrap().outbox, 0);
+    failpoints::arm("sink.remove_delivered", FailAction::Once);
+    enqueue("second");
+    assert_eq!(next_post(&mut posted).await, vec!["second"]);
+    assert_eq!(next_post(&mut posted).await, vec!["second"]);
+    assert_eq!(failpoints::fired("sink.remove_delivered"), 1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(chorus::sink::stats().unwrap().outbox, 0);
+
+    // Still running
+    assert!(!sink.is_finished());
+    let _ = GLOBALS.shutting_down.send(true);
+    tokio::time::timeout(Duration::from_secs(5), sink)
+        .await
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,80 @@
+// Checks that a client which stops reading has its live subscription closed with
+// "error: slow reader", rather than the relay queueing everything for it, and without
+// holding up the events for anybody else
+
+mod common;
+
+use common::Client;
+use std::time::{Duration, Instant};
+
+const EVENTS: usize = 800;
+
+#[test]
+fn test_slow_reader() {
+    let relay = common::start_relay(
+        "open_relay = true\n\
+         max_events_per_minute = 0\n\
+         throttling_burst = 104857600\n\
+         throttling_bytes_per_second = 104857600\n\
+         max_inflight_bytes = 65536\n",
+    );
+
+    let mut slow = Client::connect(relay.port);
+    slow.send(r#"["REQ","live",{"kinds":[1]}]"#.to_owned());
+    let reply = slow.recv(false);
+    assert_eq!(reply[0], "EOSE", "{reply}");
+
+    // Another client reads everything as it comes
+    let mut fast = Client::connect(relay.port);
+    fast.send(r#"["REQ","live",{"kinds":[1]}]"#.to_owned());
+    let reply = fast.recv(false);
+    assert_eq!(reply[0], "EOSE", "{reply}");
+    let reader = std::thread::spawn(move || {
+        for _ in 0..EVENTS {
+            let message = fast.recv(false);
+            assert_eq!(message[0], "EVENT", "{message}");
+        }
+    });
+
+    // Far more than the socket buffers hold (about 40 MB), while the slow client reads
+    // nothing. Nobody waits on it.
+    let mut publisher = Client::connect(relay.port);
+    let padding = "x".repeat(50_000);
+    for n in 0..EVENTS {
+        let event = common::sign_event(1, "", &format!("{n} {padding}"));
+        let sent = Instant::now();
+        publisher.send(format!(r#"["EVENT",{event}]"#));
+        let reply = publisher.recv(false);
+        assert_eq!(reply[2], true, "{reply}");
+        assert!(
+            sent.elapsed() < Duration::from_secs(1),
+            "event {n} was held up"
+        );
+    }
+    reader.join().unwrap();
+
+    // They get what was in flight, then the subscription is closed
+    let mut received = 0;
+    loop {
+        let message = slow.recv(false);
+        match message[0].as_str() {
+            Some("EVENT") => received += 1,
+            Some("CLOSED") => {
+                assert_eq!(message[1], "live");
+                assert_eq!(message[2], "error: slow reader");
+                break;
+            }
+            _ => panic!("{message}"),
+        }
+    }
+    // What was in flight is bounded (by max_inflight_bytes and the socket buffers)
+    assert!(
+        received < EVENTS / 2,
+        "received {received} of {EVENTS} events"
+    );
+
+    // The connection itself is fine
+    slow.send(r#"["REQ","again",{"kinds":[1],"limit":1}]"#.to_owned());
+    let reply = slow.recv(false);
+    assert_eq!(reply[0], "EVENT", "{reply}");
+}
@@ -0,0 +1,79 @@
+// Checks that the index over multi-letter tag names is built for the events already stored
+// when a name is added (over more of them than a chunk of the store holds), and dropped
+// when it is no longer wanted
+
+mod common;
+
+use chorus::config::Config;
+use chorus::globals::GLOBALS;
+use pocket_db::ScreenResult;
+use pocket_types::Event;
+
+const EVENTS: usize = 1200;
+
+#[test]
+fn test_tag_index_migration() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config.clone();
+    let store = GLOBALS.store.get().unwrap();
+
+    // Stored before the tag name was indexed, every third with a title
+    let now = common::now();
+    let mut input = String::new();
+    for n in 0..EVENTS {
+        let tags = if n % 3 == 0 {
+            format!(r#"["title","t{}"]"#, n % 7)
+        } else {
+            String::new()
+        };
+        let event = common::sign_event_at(1, now - 300 + (n / 4) as u64, 1, &tags, &format!("{n}"));
+        input.push_str(&event);
+        input.push('\n');
+    }
+    let report = chorus::jsonl::import(input.as_bytes()).unwrap();
+    assert_eq!(report.accepted, EVENTS);
+    let titled = |value: usize| (0..EVENTS).filter(|n| n % 3 == 0 && n % 7 == value).count();
+
+    let indexed = Config {
+        indexed_tag_names: vec!["title".to_owned()],
+        ..config.clone()
+    };
+    chorus::tag_index::migrate(store, &indexed).unwrap();
+    *GLOBALS.config.write() = indexed.clone();
+    for value in 0..7 {
+        let ids = chorus::tag_index::find_ids("title", &format!("t{value}")).unwrap();
+        assert_eq!(ids.len(), titled(value));
+    }
+
+    // Which is consistent with the events
+    let mut events: Vec<&Event> = Vec::new();
+    let mut walk = chorus::walk::Walk::all();
+    while let Some(chunk) = walk.next_chunk(|_| ScreenResult::Match).unwrap() {
+        events.extend(chunk);
+    }
+    assert_eq!(events.len(), EVENTS);
+    let (stale, problems) = chorus::tag_index::verify_entries().unwrap();
+    assert_eq!(stale, 0);
+    assert!(problems.is_empty(), "{problems:?}");
+    let (problems, missing) = chorus::tag_index::verify_events(&events).unwrap();
+    assert!(missing.is_empty(), "{problems:?}");
+
+    // Nothing to do for the same names
+    chorus::tag_index::migrate(store, &indexed).unwrap();
+    assert_eq!(
+        chorus::tag_index::find_ids("title", "t0").unwrap().len(),
+        titled(0)
+    );
+
+    // And dropped
+    chorus::tag_index::migrate(store, &config).unwrap();
+    *GLOBALS.config.write() = config;
+    assert!(chorus::tag_index::find_ids("title", "t0")
+        .unwrap()
+        .is_empty());
+}
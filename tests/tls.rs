@@ -0,0 +1,161 @@
+// Checks that renewed TLS certificates are picked up when their files change (and that
+// ones that cannot be loaded leave the old ones in use), and that HTTP/2 is offered by
+// ALPN, with connections that stop sending requests told to go away
+
+mod common;
+
+use chorus::config::Config;
+use http_body_util::Empty;
+use hyper::body::Bytes;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls_pki_types::ServerName;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{rustls, TlsConnector};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+// Copy a file into place, modified at `stamp` seconds past the epoch
+fn install(from: &[u8], to: &Path, stamp: u64) {
+    std::fs::write(to, from).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(to)
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(stamp))
+        .unwrap();
+}
+
+fn client_config(trusted: &str, alpn: &[u8]) -> rustls::ClientConfig {
+    let pem = std::fs::read(fixture(trusted)).unwrap();
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let mut config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    config
+}
+
+// Whether a client trusting only the certificate `trusted` gets through a handshake with
+// a connection accepted now
+async fn trusts(trusted: &str) -> bool {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = chorus::tls::acceptor().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let _ = acceptor.accept(stream).await;
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let connected = TlsConnector::from(Arc::new(client_config(trusted, b"http/1.1")))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .is_ok();
+    server.await.unwrap();
+    connected
+}
+
+#[tokio::test]
+async fn test_reload_if_changed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    let config = Config {
+        certchain_pem_path: cert.to_str().unwrap().to_owned(),
+        key_pem_path: key.to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    let first = (
+        std::fs::read(fixture("first.cert.pem")).unwrap(),
+        std::fs::read(fixture("first.key.pem")).unwrap(),
+    );
+    let second = (
+        std::fs::read(fixture("second.cert.pem")).unwrap(),
+        std::fs::read(fixture("second.key.pem")).unwrap(),
+    );
+
+    install(&first.0, &cert, 1000);
+    install(&first.1, &key, 1000);
+    chorus::tls::load(&config).unwrap();
+    assert!(trusts("first.cert.pem").await);
+    assert!(!trusts("second.cert.pem").await);
+    assert!(!chorus::tls::reload_if_changed(&config).unwrap());
+
+    // Renewed
+    install(&second.0, &cert, 2000);
+    install(&second.1, &key, 2000);
+    assert!(chorus::tls::reload_if_changed(&config).unwrap());
+    assert!(trusts("second.cert.pem").await);
+    assert!(!trusts("first.cert.pem").await);
+
+    // Broken, so the renewed one is still served (and not loaded again until it changes)
+    install(b"not a certificate", &cert, 3000);
+    assert!(chorus::tls::reload_if_changed(&config).is_err());
+    assert!(trusts("second.cert.pem").await);
+    assert!(!chorus::tls::reload_if_changed(&config).unwrap());
+
+    // And mended
+    install(&first.0, &cert, 4000);
+    install(&first.1, &key, 4000);
+    assert!(chorus::tls::reload_if_changed(&config).unwrap());
+    assert!(trusts("first.cert.pem").await);
+}
+
+#[tokio::test]
+async fn test_http2() {
+    let port = common::free_port();
+    let _relay = common::start_relay_on(
+        port,
+        &format!(
+            r#"
+certchain_pem_path = "{}"
+key_pem_path = "{}"
+enable_http2 = true
+
+[[listeners]]
+ip_address = "127.0.0.1"
+port = {port}
+tls = true
+"#,
+            fixture("first.cert.pem").display(),
+            fixture("first.key.pem").display(),
+        ),
+    );
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(client_config("first.cert.pem", b"h2")))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let (mut sender, connection) = hyper::client::conn::http2::handshake::<_, _, Empty<Bytes>>(
+        TokioExecutor::new(),
+        TokioIo::new(stream),
+    )
+    .await
+    .unwrap();
+    let connection = tokio::spawn(connection);
+
+    let request = hyper::Request::builder()
+        .uri(format!("https://localhost:{port}/"))
+        .header("accept", "application/nostr+json")
+        .body(Empty::new())
+        .unwrap();
+    assert_eq!(sender.send_request(request).await.unwrap().status(), 200);
+    let idle = Instant::now();
+
+    // Then, with no more requests, it is told to go away
+    let closed = tokio::time::timeout(Duration::from_secs(15), connection).await;
+    assert!(closed.is_ok(), "HTTP/2 connection left open");
+    assert!(idle.elapsed() >= Duration::from_secs(4));
+}
@@ -0,0 +1,145 @@
+// Checks that malformed or oversized messages and REQs are refused as a whole, with the
+// machine-readable prefixes clients rely on
+
+mod common;
+
+use common::Client;
+use hyper_tungstenite::tungstenite::Message;
+use serde_json::Value;
+
+const CONFIG: &str = "open_relay = true\n\
+                      allow_scraping = true\n\
+                      max_message_length = 1000\n\
+                      max_filters = 3\n\
+                      max_filter_values = 5\n";
+
+// What a REQ with these filters gets first
+fn first_reply(port: u16, filters: &str) -> Value {
+    let mut client = Client::connect(port);
+    client.send(format!(r#"["REQ","v",{filters}]"#));
+    client.recv(false)
+}
+
+fn assert_closed(reply: Value, reason: &str) {
+    assert_eq!(reply[0], "CLOSED", "{reply}");
+    assert_eq!(reply[1], "v", "{reply}");
+    assert_eq!(reply[2], format!("invalid: {reason}"), "{reply}");
+}
+
+#[test]
+fn test_filter_validation() {
+    let relay = common::start_relay(CONFIG);
+    let mut client = Client::connect(relay.port);
+    let event = common::sign_event(1, "", "hello");
+    client.send(format!(r#"["EVENT",{event}]"#));
+    assert_eq!(client.recv(false)[2], true);
+
+    let id = "ab".repeat(32);
+    let ids = |n: usize| format!(r#"["{}"]"#, vec![id.as_str(); n].join(r#"",""#));
+
+    // Within the limits
+    let reply = first_reply(relay.port, &format!(r#"{{"ids":{}}}"#, ids(5)));
+    assert_eq!(reply[0], "EOSE", "{reply}");
+
+    // Too many filters, or values
+    assert_closed(
+        first_reply(relay.port, r#"{"kinds":[1]},{},{},{}"#),
+        "Too many filters",
+    );
+    assert_closed(
+        first_reply(relay.port, &format!(r#"{{"ids":{}}}"#, ids(6))),
+        "ids has more than 5 values",
+    );
+    assert_closed(
+        first_reply(relay.port, r#"{"kinds":[1,2,3,4,5,6]}"#),
+        "kinds has more than 5 values",
+    );
+    assert_closed(
+        first_reply(relay.port, r##"{"#t":["a","b","c","d","e","f"]}"##),
+        "#t has more than 5 values",
+    );
+
+    // Not hex, not 64 characters, or not lowercase
+    assert_closed(
+        first_reply(relay.port, r#"{"ids":["xyz"]}"#),
+        "ids values must be 64 lowercase hex characters",
+    );
+    assert_closed(
+        first_reply(relay.port, &format!(r#"{{"authors":["{}"]}}"#, &id[..62])),
+        "authors values must be 64 lowercase hex characters",
+    );
+    assert_closed(
+        first_reply(
+            relay.port,
+            &format!(r##"{{"#p":["{}"]}}"##, id.to_uppercase()),
+        ),
+        "#p values must be 64 lowercase hex characters",
+    );
+    assert_closed(
+        first_reply(relay.port, r##"{"#e":["note1abc"]}"##),
+        "#e values must be 64 lowercase hex characters",
+    );
+
+    // A valid filter is not served when another is invalid
+    assert_closed(
+        first_reply(relay.port, r#"{"kinds":[1]},{"ids":["xyz"]}"#),
+        "ids values must be 64 lowercase hex characters",
+    );
+}
+
+#[test]
+fn test_message_validation() {
+    let relay = common::start_relay(CONFIG);
+
+    // Too long, but the connection carries on
+    let mut client = Client::connect(relay.port);
+    let padding = " ".repeat(1000);
+    client.send(format!(r#"["REQ","v",{{"kinds":[1]}}{padding}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[0], "NOTICE", "{reply}");
+    assert_eq!(reply[1], "invalid: message longer than 1000 bytes");
+    client.send(r#"["REQ","v",{"kinds":[1]}]"#.to_owned());
+    assert_eq!(client.recv(false)[0], "EOSE");
+
+    // Far too long, and the connection is closed
+    let mut client = Client::connect(relay.port);
+    let _ = client.0.send(Message::text(" ".repeat(3000)));
+    loop {
+        match client.0.read() {
+            Ok(Message::Text(text)) => assert!(!text.contains("EOSE"), "{text}"),
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    // Not a nostr message at all
+    let mut client = Client::connect(relay.port);
+    for garbage in ["hello", "{}", "[1,2]", "["] {
+        client.send(garbage.to_owned());
+        let reply = client.recv(false);
+        assert_eq!(reply[0], "NOTICE", "{reply}");
+        assert_eq!(
+            reply[1], "invalid: not a JSON array starting with a command",
+            "{reply}"
+        );
+    }
+}
+
+#[test]
+fn test_limits_advertised() {
+    let relay = common::start_relay(CONFIG);
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", relay.port)).unwrap();
+    use std::io::{Read, Write};
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: application/nostr+json\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response).unwrap();
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    let rid: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(rid["limitation"]["max_message_length"], 1000);
+    assert_eq!(rid["limitation"]["max_filters"], 3);
+    assert_eq!(rid["limitation"]["max_filter_values"], 5);
+}
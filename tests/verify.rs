@@ -0,0 +1,116 @@
+// Checks verifying incoming events on worker threads: that events waiting for a worker are
+// bounded (beyond which they are refused as the server being busy), that events we already
+// have are not verified at all, and that a connection's OKs come back in the order its
+// events were sent
+
+mod common;
+
+use chorus::config::Config;
+use chorus::error::ChorusError;
+use chorus::globals::GLOBALS;
+use common::Client;
+use serde_json::Value;
+
+// The event with its signature spoiled
+fn spoiled(event: &str) -> String {
+    let mut value: Value = serde_json::from_str(event).unwrap();
+    let sig = value["sig"].as_str().unwrap();
+    let last = if sig.ends_with('0') { '1' } else { '0' };
+    value["sig"] = Value::from(format!("{}{last}", &sig[..sig.len() - 1]));
+    value.to_string()
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_verify_queue() {
+    *GLOBALS.config.write() = Config {
+        verify_workers: 1,
+        verify_queue: 2,
+        ..Default::default()
+    };
+
+    // Big enough to keep the worker busy while the rest arrive
+    let content = "x".repeat(256 * 1024);
+    let tasks: Vec<_> = (0..30)
+        .map(|n| {
+            let json = common::sign_event(1, "", &format!("{n} {content}")).into_bytes();
+            tokio::spawn(chorus::verify::verify(json))
+        })
+        .collect();
+    let (mut verified, mut busy) = (0, 0);
+    for task in tasks {
+        match task.await.unwrap() {
+            Ok(()) => verified += 1,
+            Err(e) if matches!(e.inner, ChorusError::ServerBusy) => busy += 1,
+            Err(e) => panic!("{e}"),
+        }
+    }
+    assert!(verified >= 3, "{verified} verified");
+    assert!(busy > 0, "none refused");
+
+    // Which are only refused while the queue is full, and only for being busy
+    let event = common::sign_event(1, "", "later");
+    assert!(chorus::verify::verify(event.clone().into_bytes())
+        .await
+        .is_ok());
+    let e = chorus::verify::verify(spoiled(&event).into_bytes())
+        .await
+        .unwrap_err();
+    assert!(matches!(e.inner, ChorusError::EventIsInvalid(_)), "{e}");
+}
+
+#[test]
+fn test_oks_in_order() {
+    let relay = common::start_relay("open_relay = true\nverify_workers = 4\n");
+    let mut client = Client::connect(relay.port);
+
+    // Sent without waiting for OKs, some to be refused
+    let mut sent: Vec<(String, bool)> = Vec::new();
+    for n in 0..40 {
+        let event = common::sign_event(1, "", &format!("event {n}"));
+        let (event, accepted) = if n % 7 == 3 {
+            (spoiled(&event), false)
+        } else {
+            (event, true)
+        };
+        sent.push((common::id_of(&event), accepted));
+        client.send(format!(r#"["EVENT",{event}]"#));
+    }
+    for (id, accepted) in sent {
+        let reply = client.recv(false);
+        assert_eq!(reply[0], "OK", "{reply}");
+        assert_eq!(reply[1], id.as_str(), "{reply}");
+        assert_eq!(reply[2], accepted, "{reply}");
+    }
+}
+
+#[test]
+fn test_server_busy() {
+    let mut relay = common::start_relay("open_relay = true\n");
+    let mut client = Client::connect(relay.port);
+    let stored = common::sign_event(1, "", "stored");
+    client.send(format!(r#"["EVENT",{stored}]"#));
+    assert_eq!(client.recv(false)[2], true);
+    drop(client);
+
+    // With no room to wait for verification
+    let mut config = std::fs::read_to_string(&relay.config_path).unwrap();
+    config.push_str("verify_queue = 0\n");
+    std::fs::write(&relay.config_path, config).unwrap();
+    relay.restart();
+    let mut client = Client::connect(relay.port);
+
+    let event = common::sign_event(1, "", "new");
+    client.send(format!(r#"["EVENT",{event}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], false, "{reply}");
+    assert_eq!(reply[3], "rate-limited: server busy", "{reply}");
+
+    // But what we already have needs no verifying
+    client.send(format!(r#"["EVENT",{stored}]"#));
+    let reply = client.recv(false);
+    assert_eq!(reply[2], true, "{reply}");
+    assert!(
+        reply[3].as_str().unwrap().starts_with("duplicate:"),
+        "{reply}"
+    );
+}
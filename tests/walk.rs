@@ -0,0 +1,89 @@
+// Checks walking the whole store a chunk at a time (for exports and retention sweeps) over
+// more events than a chunk holds, several created in each second
+
+mod common;
+
+use chorus::config::Config;
+use chorus::globals::GLOBALS;
+use chorus::retention::{KindSpec, RetentionRule};
+use serde_json::Value;
+use std::collections::HashSet;
+
+const EVENTS: usize = 1200;
+
+fn export(filter: &str) -> Vec<Value> {
+    let mut out: Vec<u8> = Vec::new();
+    let count = chorus::jsonl::export(filter.as_bytes(), false, &mut out).unwrap();
+    let lines: Vec<Value> = out
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), count);
+
+    let ids: HashSet<&str> = lines.iter().map(|e| e["id"].as_str().unwrap()).collect();
+    assert_eq!(ids.len(), lines.len(), "an event was exported twice");
+    assert!(
+        lines
+            .windows(2)
+            .all(|w| w[0]["created_at"].as_u64() >= w[1]["created_at"].as_u64()),
+        "not newest first"
+    );
+    lines
+}
+
+#[tokio::test]
+async fn test_walk() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_directory: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    chorus::setup_store(&config).unwrap();
+    *GLOBALS.config.write() = config;
+
+    // Three authors, four events a second, over the last five minutes
+    let now = common::now();
+    let mut events: Vec<Value> = Vec::new();
+    let mut input = String::new();
+    for n in 0..EVENTS {
+        let created_at = now - 300 + (n / 4) as u64;
+        let event = common::sign_event_at(1 + (n % 3) as u8, created_at, 1, "", &format!("{n}"));
+        events.push(serde_json::from_str(&event).unwrap());
+        input.push_str(&event);
+        input.push('\n');
+    }
+    let report = chorus::jsonl::import(input.as_bytes()).unwrap();
+    assert_eq!(report.accepted, EVENTS);
+
+    assert_eq!(export("{}").len(), EVENTS);
+
+    // With an index range for each author, and a limit that ends within a second
+    let (first, second) = (common::test_pubkey(1), common::test_pubkey(2));
+    let lines = export(&format!(
+        r#"{{"authors":["{first}","{second}"],"limit":601}}"#
+    ));
+    assert_eq!(lines.len(), 601);
+    let oldest = lines[600]["created_at"].as_u64().unwrap();
+    let exported: HashSet<&str> = lines.iter().map(|e| e["id"].as_str().unwrap()).collect();
+    for event in events.iter() {
+        let author = event["pubkey"].as_str().unwrap();
+        if (author == first || author == second) && event["created_at"].as_u64().unwrap() > oldest {
+            assert!(exported.contains(event["id"].as_str().unwrap()));
+        }
+    }
+
+    // Each author's newest 100 are kept
+    GLOBALS.config.write().retention = vec![RetentionRule {
+        kinds: vec![KindSpec::Kind(1)],
+        time: None,
+        count: Some(100),
+    }];
+    assert_eq!(chorus::retention::sweep().await.unwrap(), EVENTS - 300);
+    let lines = export("{}");
+    assert_eq!(lines.len(), 300);
+    for secret in 1..=3 {
+        let pubkey = common::test_pubkey(secret);
+        assert_eq!(lines.iter().filter(|e| e["pubkey"] == pubkey).count(), 100);
+    }
+}
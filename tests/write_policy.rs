@@ -0,0 +1,116 @@
+// Checks the write policy: a plugin's verdicts, the builtin deny list, and failing closed
+// when the plugin is not there to ask
+
+mod common;
+
+use common::Client;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const PLUGIN: &str = r#"#!/bin/sh
+while read -r line; do
+  id=$(printf '%s' "$line" | sed 's/.*"id":"\([0-9a-f]*\)".*/\1/')
+  case "$line" in
+    *'"content":"spam'*) echo "{\"id\":\"$id\",\"action\":\"reject\",\"msg\":\"no spam\"}" ;;
+    *'"content":"shadow'*) echo "{\"id\":\"$id\",\"action\":\"shadowReject\"}" ;;
+    *) echo "{\"id\":\"$id\",\"action\":\"accept\"}" ;;
+  esac
+done
+"#;
+
+fn write_script(dir: &Path, script: &str) -> String {
+    let path = dir.join("plugin.sh");
+    std::fs::write(&path, script).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path.display().to_string()
+}
+
+fn stored(client: &mut Client, event: &str) -> bool {
+    let id = common::id_of(event);
+    client.send(format!(r#"["REQ","q",{{"ids":["{id}"]}}]"#));
+    let found = client.recv(false)[0] == "EVENT";
+    client.send(r#"["CLOSE","q"]"#.to_owned());
+    found
+}
+
+#[test]
+fn test_plugin() {
+    let dir = tempfile::tempdir().unwrap();
+    let plugin = write_script(dir.path(), PLUGIN);
+    let relay = common::start_relay(&format!(
+        "open_relay = true\nwrite_policy_plugin = \"{plugin}\"\nwrite_policy_deny_pubkeys = [\"{}\"]\n",
+        common::test_pubkey(0x42)
+    ));
+    let mut client = Client::connect(relay.port);
+
+    let event = common::sign_event(1, "", "hello");
+    let reply = common::publish(&mut client, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    assert!(stored(&mut client, &event));
+
+    let event = common::sign_event(1, "", "spam spam spam");
+    let reply = common::publish(&mut client, &event);
+    assert_eq!(reply[2], false, "{reply}");
+    assert_eq!(reply[3], "blocked: no spam");
+    assert!(!stored(&mut client, &event));
+
+    // Looks accepted, but is not stored
+    let event = common::sign_event(1, "", "shadow");
+    let reply = common::publish(&mut client, &event);
+    assert_eq!(reply[2], true, "{reply}");
+    assert!(!stored(&mut client, &event));
+
+    // The deny list does not need the plugin
+    let event = common::sign_event_as(0x42, 1, "", "hello");
+    let reply = common::publish(&mut client, &event);
+    assert_eq!(reply[2], false, "{reply}");
+    assert_eq!(reply[3], "blocked: pubkey is denied");
+}
+
+#[test]
+fn test_fail_closed() {
+    let dir = tempfile::tempdir().unwrap();
+    let plugin = write_script(dir.path(), "#!/bin/sh\nexit 1\n");
+    let relay = common::start_relay(&format!(
+        "open_relay = true\nwrite_policy_plugin = \"{plugin}\"\nwrite_policy_timeout_ms = 200\n"
+    ));
+    let mut client = Client::connect(relay.port);
+
+    let reply = common::publish(&mut client, &common::sign_event(1, "", "hello"));
+    assert_eq!(reply[2], false, "{reply}");
+    assert!(
+        reply[3]
+            .as_str()
+            .unwrap()
+            .starts_with("error: write policy unavailable"),
+        "{reply}"
+    );
+}
+
+#[test]
+fn test_plugin_arguments() {
+    let dir = tempfile::tempdir().unwrap();
+    let plugin = write_script(
+        dir.path(),
+        r#"#!/bin/sh
+while read -r line; do
+  id=$(printf '%s' "$line" | sed 's/.*"id":"\([0-9a-f]*\)".*/\1/')
+  case "$line" in
+    *"\"content\":\"$1\""*) echo "{\"id\":\"$id\",\"action\":\"reject\",\"msg\":\"no $1\"}" ;;
+    *) echo "{\"id\":\"$id\",\"action\":\"accept\"}" ;;
+  esac
+done
+"#,
+    );
+    let relay = common::start_relay(&format!(
+        "open_relay = true\nwrite_policy_plugin = \"{plugin}  eggs\"\n"
+    ));
+    let mut client = Client::connect(relay.port);
+
+    let reply = common::publish(&mut client, &common::sign_event(1, "", "ham"));
+    assert_eq!(reply[2], true, "{reply}");
+
+    let reply = common::publish(&mut client, &common::sign_event(1, "", "eggs"));
+    assert_eq!(reply[2], false, "{reply}");
+    assert_eq!(reply[3], "blocked: no eggs");
+}